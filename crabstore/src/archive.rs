@@ -0,0 +1,138 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::Path,
+};
+
+use rkyv::{
+    de::deserializers::SharedDeserializeMap, validation::validators::DefaultValidator, AlignedVec,
+    Archive, CheckBytes, Deserialize,
+};
+
+#[cfg(test)]
+use rkyv::{ser::serializers::AllocSerializer, Serialize};
+
+use crate::error::CrabError;
+
+const MAGIC: u64 = u64::from_le_bytes(*b"CRABSUM1");
+
+// checksum followed by MAGIC, appended to every archive we write
+pub(crate) const TRAILER_SIZE: usize = 16;
+
+pub(crate) fn corrupt(path: &Path, reason: impl ToString) -> CrabError {
+    CrabError::CorruptFile {
+        path: path.into(),
+        reason: reason.to_string(),
+    }
+}
+
+pub(crate) fn unavailable(path: &Path, err: io::Error) -> CrabError {
+    CrabError::StorageUnavailable(format!("{}: {err}", path.display()))
+}
+
+/*
+    FNV-1a, enough to catch torn writes and flipped bits
+*/
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+pub(crate) fn seal(bytes: &[u8]) -> [u8; TRAILER_SIZE] {
+    let mut trailer = [0; TRAILER_SIZE];
+    trailer[..8].copy_from_slice(&checksum(bytes).to_le_bytes());
+    trailer[8..].copy_from_slice(&MAGIC.to_le_bytes());
+    trailer
+}
+
+/*
+    Checks the trailer and returns the archive it covers, copied out so it is suitably aligned
+*/
+pub(crate) fn unseal(path: &Path, bytes: &[u8]) -> Result<AlignedVec, CrabError> {
+    if bytes.len() < TRAILER_SIZE {
+        return Err(corrupt(path, format!("only {} bytes long", bytes.len())));
+    }
+
+    let (payload, trailer) = bytes.split_at(bytes.len() - TRAILER_SIZE);
+
+    if trailer[8..] != MAGIC.to_le_bytes() {
+        return Err(corrupt(
+            path,
+            "missing trailer, file is truncated or not ours",
+        ));
+    }
+
+    if trailer[..8] != checksum(payload).to_le_bytes() {
+        return Err(corrupt(path, "checksum mismatch"));
+    }
+
+    let mut aligned = AlignedVec::with_capacity(payload.len());
+    aligned.extend_from_slice(payload);
+    Ok(aligned)
+}
+
+/*
+    Validates the archive before touching it, a bad file is an error rather than undefined behavior
+*/
+pub(crate) fn from_bytes<T>(path: &Path, bytes: &[u8]) -> Result<T, CrabError>
+where
+    T: Archive,
+    T::Archived: for<'a> CheckBytes<DefaultValidator<'a>> + Deserialize<T, SharedDeserializeMap>,
+{
+    let payload = unseal(path, bytes)?;
+
+    rkyv::check_archived_root::<T>(&payload)
+        .map_err(|err| corrupt(path, err))?
+        .deserialize(&mut SharedDeserializeMap::new())
+        .map_err(|err| corrupt(path, err))
+}
+
+pub(crate) fn read_file(path: &Path) -> Result<Vec<u8>, CrabError> {
+    let mut bytes = Vec::new();
+
+    File::options()
+        .read(true)
+        .open(path)
+        .and_then(|mut file| file.read_to_end(&mut bytes))
+        .map_err(|err| corrupt(path, err))?;
+
+    Ok(bytes)
+}
+
+/*
+    Serialized archive with its trailer, the way files were written before format.rs.
+    Only tests still produce these, to check the legacy readers
+*/
+#[cfg(test)]
+pub(crate) fn to_bytes<T: Serialize<AllocSerializer<4096>>>(value: &T) -> Vec<u8> {
+    let mut bytes = rkyv::to_bytes::<T, 4096>(value)
+        .expect("Unable to serialize archive")
+        .into_vec();
+
+    let trailer = seal(&bytes);
+    bytes.extend_from_slice(&trailer);
+    bytes
+}
+
+/*
+    Written beside the target and renamed over it, so a reader never sees half a file
+*/
+pub(crate) fn write_file(path: &Path, bytes: &[u8]) -> Result<(), CrabError> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+
+    File::options()
+        .write(true)
+        .truncate(true)
+        .create(true)
+        .open(&temp_path)
+        .and_then(|mut file| file.write_all(bytes).and_then(|_| file.flush()))
+        .and_then(|_| fs::rename(&temp_path, path))
+        .map_err(|err| unavailable(path, err))
+}
+
+#[cfg(test)]
+pub(crate) fn persist<T: Serialize<AllocSerializer<4096>>>(path: &Path, value: &T) {
+    write_file(path, &to_bytes(value)).unwrap();
+}
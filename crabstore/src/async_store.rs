@@ -0,0 +1,254 @@
+use std::{
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+    thread,
+};
+
+use parking_lot::Mutex;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+use crate::{
+    config::CrabConfig,
+    crabstore::CrabStore,
+    error::CrabError,
+    record::Record,
+    table::{Table, UpdateOp},
+    transaction::{QueryResult, Transaction, TransactionError},
+};
+
+// Everything the futures below move onto the pool has to be able to go there
+const _: fn() = || {
+    fn send_sync<T: Send + Sync>() {}
+    fn send<T: Send>() {}
+
+    send_sync::<Table>();
+    send_sync::<CrabStore>();
+    send::<Transaction>();
+    send::<Record>();
+    send::<CrabError>();
+};
+
+struct Slot<T> {
+    result: Option<thread::Result<T>>,
+    waker: Option<Waker>,
+}
+
+/*
+    Resolves to the result of a closure run on the store's pool. Nothing blocks
+    the thread polling it, so an async runtime's workers stay free while the
+    query waits on the bufferpool or the disk. A panic in the closure is
+    resumed in whoever awaits it.
+*/
+pub struct Blocking<T> {
+    slot: Arc<Mutex<Slot<T>>>,
+}
+
+impl<T> Future for Blocking<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let mut slot = self.slot.lock();
+
+        match slot.result.take() {
+            Some(Ok(result)) => Poll::Ready(result),
+            Some(Err(panic)) => panic::resume_unwind(panic),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+fn spawn<T, F>(pool: &ThreadPool, work: F) -> Blocking<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let slot = Arc::new(Mutex::new(Slot {
+        result: None,
+        waker: None,
+    }));
+    let done = Arc::clone(&slot);
+
+    pool.spawn(move || {
+        // Caught so the pool thread survives, the awaiting task panics instead
+        let result = panic::catch_unwind(AssertUnwindSafe(work));
+
+        let waker = {
+            let mut slot = done.lock();
+            slot.result = Some(result);
+            slot.waker.take()
+        };
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    });
+
+    Blocking { slot }
+}
+
+/*
+    CrabStore for async code: every call runs on a dedicated pool of
+    config.async_threads threads and returns a future. Tables and transactions
+    are handed over by value, so nothing borrowed has to outlive an await.
+*/
+#[derive(Clone)]
+pub struct AsyncCrabStore {
+    store: Arc<Mutex<CrabStore>>,
+    pool: Arc<ThreadPool>,
+}
+
+impl AsyncCrabStore {
+    pub fn new(directory: PathBuf) -> Self {
+        AsyncCrabStore::with_config(directory, CrabConfig::default())
+    }
+
+    pub fn with_config(directory: PathBuf, config: CrabConfig) -> Self {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(config.async_threads)
+            .thread_name(|i| format!("crabstore-async-{i}"))
+            .build()
+            .expect("Failed to start the async query pool");
+
+        AsyncCrabStore {
+            store: Arc::new(Mutex::new(CrabStore::with_config(directory, config))),
+            pool: Arc::new(pool),
+        }
+    }
+
+    fn run_store<T, F>(&self, work: F) -> Blocking<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut CrabStore) -> T + Send + 'static,
+    {
+        let store = Arc::clone(&self.store);
+        spawn(&self.pool, move || work(&mut store.lock()))
+    }
+
+    fn wrap(&self, table: Arc<Table>) -> AsyncTable {
+        AsyncTable {
+            table,
+            pool: Arc::clone(&self.pool),
+        }
+    }
+
+    pub fn open(&self) -> Blocking<Result<(), CrabError>> {
+        self.run_store(|store| store.open())
+    }
+
+    pub fn close(&self) -> Blocking<()> {
+        self.run_store(|store| store.close())
+    }
+
+    pub fn checkpoint(&self) -> Blocking<()> {
+        self.run_store(|store| store.checkpoint())
+    }
+
+    pub async fn create_table(
+        &self,
+        name: String,
+        num_columns: usize,
+        key_index: usize,
+    ) -> AsyncTable {
+        let table = self
+            .run_store(move |store| store.create_table(&name, num_columns, key_index))
+            .await;
+
+        self.wrap(table)
+    }
+
+    pub async fn get_table(&self, name: String) -> AsyncTable {
+        let table = self.run_store(move |store| store.get_table(&name)).await;
+        self.wrap(table)
+    }
+
+    /*
+        Runs the transaction and hands it back with what Transaction::run returned,
+        so an aborted one can be run again
+    */
+    pub fn run(
+        &self,
+        mut transaction: Transaction,
+    ) -> Blocking<(Result<Vec<QueryResult>, TransactionError>, Transaction)> {
+        spawn(&self.pool, move || (transaction.run(), transaction))
+    }
+}
+
+/*
+    A table of an AsyncCrabStore, cheap to clone into as many tasks as need it
+*/
+#[derive(Clone)]
+pub struct AsyncTable {
+    table: Arc<Table>,
+    pool: Arc<ThreadPool>,
+}
+
+impl AsyncTable {
+    /*
+        The underlying table, for building Transactions and for anything cheap enough
+        to call directly.
+    */
+    pub fn table(&self) -> &Arc<Table> {
+        &self.table
+    }
+
+    fn run_table<T, F>(&self, work: F) -> Blocking<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Table) -> T + Send + 'static,
+    {
+        let table = Arc::clone(&self.table);
+        spawn(&self.pool, move || work(&table))
+    }
+
+    pub fn select_query(
+        &self,
+        search_value: u64,
+        column_index: usize,
+        included_columns: Vec<usize>,
+    ) -> Blocking<Result<Vec<Record>, CrabError>> {
+        self.run_table(move |table| {
+            table.select_query(search_value, column_index, &included_columns, None)
+        })
+    }
+
+    pub fn insert_query(&self, values: Vec<u64>) -> Blocking<Result<(), CrabError>> {
+        self.run_table(move |table| table.insert_query(&values, None))
+    }
+
+    pub fn update_query(
+        &self,
+        key: u64,
+        values: Vec<Option<u64>>,
+    ) -> Blocking<Result<bool, CrabError>> {
+        self.run_table(move |table| table.update_query(key, &values, None))
+    }
+
+    pub fn update_range(
+        &self,
+        start_key: u64,
+        end_key: u64,
+        updates: Vec<Option<UpdateOp>>,
+    ) -> Blocking<Result<usize, CrabError>> {
+        self.run_table(move |table| table.update_range(start_key, end_key, &updates, None))
+    }
+
+    pub fn delete_query(&self, key: u64) -> Blocking<Result<bool, CrabError>> {
+        self.run_table(move |table| table.delete_query(key, None))
+    }
+
+    pub fn sum_query(
+        &self,
+        start_range: u64,
+        end_range: u64,
+        column_index: usize,
+    ) -> Blocking<Result<u64, CrabError>> {
+        self.run_table(move |table| table.sum_query(start_range, end_range, column_index, None))
+    }
+}
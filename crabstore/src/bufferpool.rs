@@ -1,21 +1,44 @@
 use std::{
     borrow::Borrow,
+    cell::Cell,
     hash::BuildHasherDefault,
+    io,
+    ops::Deref,
     sync::{
-        atomic::{self, Ordering},
-        Arc, RwLock,
+        atomic::{self, AtomicUsize, Ordering},
+        mpsc::{channel, RecvTimeoutError, Sender},
+        Arc, OnceLock, RwLock, RwLockWriteGuard,
     },
-    time::Duration,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
+use parking_lot::Mutex;
 use rustc_hash::{FxHashMap, FxHasher};
 
-use crate::{disk_manager::DiskManager, page::PhysicalPage};
+use crate::{
+    disk_manager::DiskManager,
+    error::CrabError,
+    eviction::{Eviction, EvictionPolicy},
+    page::PhysicalPage,
+    PAGE_SLOTS,
+};
+
+/*
+    How often the background flusher writes dirty pages back unless
+    CrabConfig::flush_interval says otherwise.
+*/
+pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+// How long BufferPool::pin_page waits for a frame to be unpinned
+const PIN_WAIT_TIMEOUT: Duration = Duration::from_secs(1);
 
 #[derive(Debug)]
 pub struct BufferPoolFrame {
     page_id: atomic::AtomicUsize,
     dirty: atomic::AtomicBool,
+    // PageGuards on the frame, it's only evicted once there are none
+    pins: atomic::AtomicUsize,
     page: RwLock<PhysicalPage>,
 }
 
@@ -24,20 +47,28 @@ impl BufferPoolFrame {
         BufferPoolFrame {
             page_id: (!0).into(),
             dirty: false.into(),
+            pins: 0.into(),
             page: RwLock::new(PhysicalPage::default()),
         }
     }
-    pub fn flush(&self, disk: &DiskManager) {
+    /*
+        Writes the page back and forgets it. It's forgotten even if the write
+        fails, the caller records the failure and nothing reads the file again.
+    */
+    pub fn flush(&self, disk: &DiskManager, sync: bool) -> io::Result<()> {
         let page = self
             .page
             .write()
             .expect("Failed to acquire lock, lock poisoning?");
 
-        disk.write_page(self.page_id.load(Ordering::Relaxed), &page.page);
-        disk.flush();
+        let written = disk
+            .write_page(self.page_id.load(Ordering::Relaxed), &page.page)
+            .and_then(|_| if sync { disk.flush() } else { Ok(()) });
 
         self.dirty.store(false, Ordering::Relaxed);
         self.page_id.store(!0, Ordering::Relaxed);
+
+        written
     }
 
     pub fn mark_dirty(&self) {
@@ -48,6 +79,10 @@ impl BufferPoolFrame {
         self.page_id.load(Ordering::Relaxed)
     }
 
+    pub fn pin_count(&self) -> usize {
+        self.pins.load(Ordering::Relaxed)
+    }
+
     pub fn slot(&self, slot: usize) -> u64 {
         let page = self
             .page
@@ -58,152 +93,749 @@ impl BufferPoolFrame {
     }
 
     pub fn write_slot(&self, slot: usize, value: u64) {
-        self.mark_dirty();
         let mut page = self
             .page
             .write()
             .expect("Couldn't lock physical page, poisoned?");
+        // Only under the lock, so a flush that clears it can't miss this write
+        self.mark_dirty();
 
         page.write_slot(slot, value);
     }
 
+    /*
+        Writes consecutive slots starting at first_slot under a single lock
+    */
+    pub fn write_slots(&self, first_slot: usize, values: impl IntoIterator<Item = u64>) {
+        let mut page = self
+            .page
+            .write()
+            .expect("Couldn't lock physical page, poisoned?");
+        self.mark_dirty();
+
+        for (slot, value) in (first_slot..).zip(values) {
+            page.write_slot(slot, value);
+        }
+    }
+
+    pub fn fill(&self, value: u64) {
+        let mut page = self
+            .page
+            .write()
+            .expect("Couldn't lock physical page, poisoned?");
+        self.mark_dirty();
+
+        for slot in 0..PAGE_SLOTS {
+            page.write_slot(slot, value);
+        }
+    }
+
     pub fn raw(&self) -> &RwLock<PhysicalPage> {
         &self.page
     }
 }
+/*
+    A frame pinned in the bufferpool, which keeps its page cached until every guard on
+    it is dropped.
+*/
+#[derive(Debug)]
+pub struct PageGuard(Arc<BufferPoolFrame>);
+
+impl PageGuard {
+    fn pin(frame: &Arc<BufferPoolFrame>) -> Self {
+        frame.pins.fetch_add(1, Ordering::Relaxed);
+        PageGuard(Arc::clone(frame))
+    }
+}
+
+impl Clone for PageGuard {
+    fn clone(&self) -> Self {
+        PageGuard::pin(&self.0)
+    }
+}
+
+impl Deref for PageGuard {
+    type Target = BufferPoolFrame;
+
+    fn deref(&self) -> &BufferPoolFrame {
+        &self.0
+    }
+}
+
+impl Drop for PageGuard {
+    fn drop(&mut self) {
+        self.0.pins.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BufferPoolStats {
+    pub requests: usize,
+    pub hits: usize,
+    pub misses: usize,
+    // Cached pages pushed out to make room, by misses and new pages
+    pub evictions: usize,
+    // Dirty pages written back, whether evicted, flushed or invalidated
+    pub dirty_writes: usize,
+    // Frames pinned right now, reset_stats leaves this alone
+    pub pinned: usize,
+    // Pages read in by BufferPool::prefetch before anything asked for them
+    pub prefetched: usize,
+    // Cached pages dropped without being written back, see BufferPool::discard_pages
+    pub discarded: usize,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BudgetPolicy {
+    /*
+        Pages past the budget are still fetched, but through the scan hint
+        path so they are the first to go instead of the working set
+    */
+    #[default]
+    Degrade,
+    /*
+        The query stops at the next page boundary and fails with BudgetExceeded
+    */
+    Fail,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QueryBudget {
+    // Page requests the query may make, hits included
+    pub max_fetches: usize,
+    pub policy: BudgetPolicy,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueryUsage {
+    pub fetches: usize,
+    pub exceeded: bool,
+}
+
+thread_local! {
+    // Budgeted query running on this thread, fetches made here are charged to it
+    static CURRENT_QUERY: Cell<Option<u64>> = const { Cell::new(None) };
+    // See BufferPool::scan_thread
+    static SCAN_THREAD: Cell<bool> = const { Cell::new(false) };
+}
+
+#[derive(Debug, Default)]
+struct PoolStats {
+    requests: AtomicUsize,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    evictions: AtomicUsize,
+    dirty_writes: AtomicUsize,
+    prefetched: AtomicUsize,
+    discarded: AtomicUsize,
+}
+
+/*
+    Which frame holds each cached page, and what the eviction policy knows
+    about them, changed together
+*/
+#[derive(Debug)]
+struct PageTable {
+    pages: FxHashMap<usize, usize>,
+    policy: Box<dyn EvictionPolicy>,
+}
+
+#[derive(Debug, Default)]
+struct Budgets {
+    running: FxHashMap<u64, (QueryBudget, QueryUsage)>,
+    next_query_id: u64,
+}
+
+/*
+    Hits only read-lock the page table, long enough to pin the frame, and
+    then go through the frame's own latch. Anything that moves a page in or
+    out of a frame (misses, new pages, invalidation, flushes) holds the
+    replacer, so a page being written back can't be read from the file
+    again until the write is done.
+*/
 #[derive(Debug)]
 pub struct BufferPool {
     disk: Arc<DiskManager>,
     size: usize,
-    page_frame_map: FxHashMap<usize, usize>,
+    page_table: parking_lot::RwLock<PageTable>,
     frames: Vec<Arc<BufferPoolFrame>>,
-    clock_refs: Vec<bool>,
-    clock_hand: usize,
+    replacer: Mutex<()>,
+    stats: PoolStats,
+    budgets: Mutex<Budgets>,
+    // The first IO error on the file, shared with the table so its queries fail from then on
+    failure: Arc<OnceLock<String>>,
+    // Started by the first prefetch, see BufferPool::spawn_prefetcher
+    prefetcher: OnceLock<Sender<Vec<usize>>>,
 }
 
 impl BufferPool {
     pub fn new(disk: Arc<DiskManager>, size: usize) -> Self {
-        let mut frames = Vec::with_capacity(size);
-        let page_frame_map =
-            FxHashMap::with_capacity_and_hasher(size, BuildHasherDefault::<FxHasher>::default());
-        let mut clock_refs = Vec::with_capacity(size);
+        BufferPool::with_policy(disk, size, Eviction::default().policy(size))
+    }
 
-        for _ in 0..size {
-            frames.push(Arc::new(BufferPoolFrame::new()));
-            clock_refs.push(false);
-        }
+    /*
+        A pool evicting by policy, which must track size frames
+    */
+    pub fn with_policy(
+        disk: Arc<DiskManager>,
+        size: usize,
+        policy: Box<dyn EvictionPolicy>,
+    ) -> Self {
+        let pages =
+            FxHashMap::with_capacity_and_hasher(size, BuildHasherDefault::<FxHasher>::default());
 
         BufferPool {
             disk,
             size,
-            page_frame_map,
-            frames,
-            clock_refs,
-            clock_hand: 0,
-        }
-    }
-
-    fn find_evict_victim(&mut self) -> usize {
-        let evict_start_time = std::time::Instant::now();
-        let victim = loop {
-            if self.clock_refs[self.clock_hand]
-                || Arc::strong_count(&self.frames[self.clock_hand]) > 1
-            {
-                self.clock_refs[self.clock_hand] = false;
-                self.clock_hand = (self.clock_hand + 1) % self.size;
-                if Duration::from_secs(1) < evict_start_time.elapsed() {
-                    panic!("Evicting a page took more than 1 second! Buffer pool is too small!");
+            page_table: parking_lot::RwLock::new(PageTable { pages, policy }),
+            frames: (0..size)
+                .map(|_| Arc::new(BufferPoolFrame::new()))
+                .collect(),
+            replacer: Mutex::new(()),
+            stats: PoolStats::default(),
+            budgets: Mutex::default(),
+            failure: Arc::default(),
+            prefetcher: OnceLock::new(),
+        }
+    }
+
+    /*
+        Starts the thread writing back the pool's dirty pages every interval, see
+        flush_dirty. Sending on the sender changes the interval, dropping it
+        stops the thread.
+    */
+    pub(crate) fn spawn_flusher(
+        bufferpool: &Arc<BufferPool>,
+        interval: Duration,
+    ) -> (JoinHandle<()>, Sender<Duration>) {
+        let (send, recv) = channel();
+        let bufferpool = Arc::clone(bufferpool);
+        let handle = thread::spawn(move || {
+            let mut interval = interval;
+            loop {
+                match recv.recv_timeout(interval) {
+                    Ok(new_interval) => interval = new_interval,
+                    Err(RecvTimeoutError::Timeout) => {
+                        bufferpool.flush_dirty();
+                    }
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            }
+        });
+
+        (handle, send)
+    }
+
+    /*
+        The thread behind prefetch. It only holds the pool while loading, so
+        dropping the pool drops the sender, which stops it.
+    */
+    fn spawn_prefetcher(bufferpool: &Arc<BufferPool>) -> Sender<Vec<usize>> {
+        let (send, recv) = channel::<Vec<usize>>();
+        let bufferpool = Arc::downgrade(bufferpool);
+
+        thread::spawn(move || {
+            for page_ids in recv {
+                let Some(bufferpool) = bufferpool.upgrade() else {
+                    return;
+                };
+                for page_id in page_ids {
+                    bufferpool.load_ahead(page_id);
                 }
-                continue;
             }
+        });
 
-            break self.clock_hand;
+        send
+    }
+
+    pub(crate) fn failure(&self) -> Arc<OnceLock<String>> {
+        Arc::clone(&self.failure)
+    }
+
+    /*
+        Only the first failure is kept, later ones are usually caused by it
+    */
+    fn record_failure(&self, page_id: usize, result: io::Result<()>) {
+        if let Err(err) = result {
+            let _ = self.failure.set(format!("page {page_id}: {err}"));
+        }
+    }
+
+    pub fn stats(&self) -> BufferPoolStats {
+        BufferPoolStats {
+            requests: self.stats.requests.load(Ordering::Relaxed),
+            hits: self.stats.hits.load(Ordering::Relaxed),
+            misses: self.stats.misses.load(Ordering::Relaxed),
+            evictions: self.stats.evictions.load(Ordering::Relaxed),
+            dirty_writes: self.stats.dirty_writes.load(Ordering::Relaxed),
+            prefetched: self.stats.prefetched.load(Ordering::Relaxed),
+            discarded: self.stats.discarded.load(Ordering::Relaxed),
+            pinned: self
+                .frames
+                .iter()
+                .filter(|frame| frame.pin_count() > 0)
+                .count(),
+        }
+    }
+
+    pub fn reset_stats(&self) {
+        self.stats.requests.store(0, Ordering::Relaxed);
+        self.stats.hits.store(0, Ordering::Relaxed);
+        self.stats.misses.store(0, Ordering::Relaxed);
+        self.stats.evictions.store(0, Ordering::Relaxed);
+        self.stats.dirty_writes.store(0, Ordering::Relaxed);
+        self.stats.prefetched.store(0, Ordering::Relaxed);
+        self.stats.discarded.store(0, Ordering::Relaxed);
+    }
+
+    /*
+        Charges page requests on this thread to a new budgeted query until end_budget.
+        Returns the query id and whichever query was running before, to be restored.
+    */
+    pub(crate) fn begin_budget(&self, budget: QueryBudget) -> (u64, Option<u64>) {
+        let mut budgets = self.budgets.lock();
+        let id = budgets.next_query_id;
+        budgets.next_query_id += 1;
+
+        budgets.running.insert(id, (budget, QueryUsage::default()));
+
+        (id, CURRENT_QUERY.with(|query| query.replace(Some(id))))
+    }
+
+    pub(crate) fn end_budget(&self, id: u64, previous: Option<u64>) -> QueryUsage {
+        CURRENT_QUERY.with(|query| query.set(previous));
+
+        self.budgets
+            .lock()
+            .running
+            .remove(&id)
+            .map(|(_, usage)| usage)
+            .unwrap_or_default()
+    }
+
+    /*
+        Whether the query on this thread ran out of budget and should stop scanning
+    */
+    pub(crate) fn budget_exhausted(&self) -> bool {
+        CURRENT_QUERY.with(Cell::get).is_some_and(|id| {
+            self.budgets
+                .lock()
+                .running
+                .get(&id)
+                .is_some_and(|(budget, usage)| {
+                    usage.exceeded && budget.policy == BudgetPolicy::Fail
+                })
+        })
+    }
+
+    /*
+        Returns false once the current query is over a degrading budget
+    */
+    fn charge_query(&self) -> bool {
+        // Queries without a budget don't touch the budgets' lock
+        let Some(id) = CURRENT_QUERY.with(Cell::get) else {
+            return true;
         };
+        let mut budgets = self.budgets.lock();
+        let Some((budget, usage)) = budgets.running.get_mut(&id) else {
+            return true;
+        };
+
+        usage.fetches += 1;
+        usage.exceeded |= usage.fetches > budget.max_fetches;
+
+        !(usage.exceeded && budget.policy == BudgetPolicy::Degrade)
+    }
+
+    /*
+        Swaps the eviction policy, which starts out knowing nothing about the
+        pages already cached
+    */
+    pub fn set_policy(&self, policy: Box<dyn EvictionPolicy>) {
+        let _replacing = self.replacer.lock();
+        self.page_table.write().policy = policy;
+    }
+
+    /*
+        Marks every page this thread fetches from now on as a scan access,
+        for threads that only ever pass through pages, like the merge thread
+    */
+    pub(crate) fn scan_thread() {
+        SCAN_THREAD.with(|scan| scan.set(true));
+    }
+
+    /*
+        Maps page_id to an evicted frame and returns it pinned and write locked,
+        after writing back the page it held if that was dirty. The caller
+        holds the replacer until then.
+    */
+    fn claim_frame(
+        &self,
+        page_id: usize,
+        scan: bool,
+    ) -> Result<(PageGuard, RwLockWriteGuard<'_, PhysicalPage>), CrabError> {
+        let mut page_table = self.page_table.write();
+        // Nothing can pin a frame between its pin count being checked and it being handed out
+        let victim = page_table
+            .policy
+            .pick_victim(&|frame| self.frames[frame].pin_count() == 0)
+            .ok_or(CrabError::BufferPoolFull)?;
+        let frame = &self.frames[victim];
+        let pinned = PageGuard::pin(frame);
+
+        let page = frame
+            .page
+            .write()
+            .expect("Failed to acquire RwLock, poisoned?");
+        let old_page_id = frame.page_id.swap(page_id, Ordering::Relaxed);
+
+        page_table.pages.remove(&old_page_id);
+        page_table
+            .pages
+            .try_insert(page_id, victim)
+            .expect("Tried to re-map existing page in bufferpool");
+        page_table.policy.record_load(victim, scan);
+        drop(page_table);
+
+        if old_page_id != !0 {
+            self.stats.evictions.fetch_add(1, Ordering::Relaxed);
+        }
 
-        self.clock_hand = (self.clock_hand + 1) % self.size;
+        // Invalidated frames have no page to go back to
+        if frame.dirty.swap(false, Ordering::Relaxed) && old_page_id != !0 {
+            let written = self.disk.write_page(old_page_id, &page.page);
+            self.stats.dirty_writes.fetch_add(1, Ordering::Relaxed);
+            self.record_failure(old_page_id, written.map(|_| ()));
+        }
 
-        victim
+        Ok((pinned, page))
     }
 
-    pub fn flush_all(&mut self) {
+    /*
+        Writes back every dirty page nothing holds, in page order, and syncs the
+        file once after all of them. The pages are forgotten like with
+        BufferPoolFrame::flush, written or not.
+    */
+    pub fn flush_all(&self) {
+        let _replacing = self.replacer.lock();
+        // Held throughout so nothing pins a frame that is being forgotten
+        let mut page_table = self.page_table.write();
+
+        for page_id in self.write_dirty(true) {
+            if let Some(frame_id) = page_table.pages.remove(&page_id) {
+                self.frames[frame_id].page_id.store(!0, Ordering::Relaxed);
+                page_table.policy.forget(frame_id);
+            }
+        }
+    }
+
+    /*
+        What the background flusher does every interval: writes back the dirty
+        pages nothing holds but keeps them cached, clean, so evicting them later
+        costs nothing. Returns how many were written.
+    */
+    pub fn flush_dirty(&self) -> usize {
+        // After a failure nothing reads the file again, see record_failure
+        if self.failure.get().is_some() {
+            return 0;
+        }
+
+        let _replacing = self.replacer.lock();
+        self.write_dirty(false).len()
+    }
+
+    /*
+        Cached pages changed since they were last written back.
+    */
+    pub fn dirty_count(&self) -> usize {
+        self.frames
+            .iter()
+            .filter(|frame| frame.dirty.load(Ordering::Relaxed) && frame.get_page_id() != !0)
+            .count()
+    }
+
+    /*
+        Writes the dirty unpinned pages in one batch and clears their dirty bits
+        while still holding their write locks. Returns their page ids. Frames
+        pinned meanwhile are fine, writers mark a frame dirty only once they
+        hold its write lock.
+    */
+    fn write_dirty(&self, sync: bool) -> Vec<usize> {
+        let mut dirty = self
+            .frames
+            .iter()
+            .filter(|frame| {
+                frame.dirty.load(Ordering::Relaxed)
+                    && frame.get_page_id() != !0
+                    && frame.pin_count() == 0
+            })
+            .map(|frame| {
+                let page = frame
+                    .page
+                    .write()
+                    .expect("Failed to acquire lock, lock poisoning?");
+                (frame, page)
+            })
+            .collect::<Vec<_>>();
+        dirty.sort_unstable_by_key(|(frame, _)| frame.get_page_id());
+
+        let pages = dirty
+            .iter()
+            .map(|(frame, page)| (frame.get_page_id(), &page.page))
+            .collect::<Vec<_>>();
+        let written =
+            self.disk
+                .write_pages(&pages)
+                .and_then(|_| if sync { self.disk.flush() } else { Ok(()) });
+        self.stats
+            .dirty_writes
+            .fetch_add(pages.len(), Ordering::Relaxed);
+        drop(pages);
+
+        if let Err(err) = written {
+            let _ = self.failure.set(err.to_string());
+        }
+
+        dirty
+            .into_iter()
+            .map(|(frame, _page)| {
+                frame.dirty.store(false, Ordering::Relaxed);
+                frame.get_page_id()
+            })
+            .collect()
+    }
+
+    /*
+        Forgets every cached page without writing it back, for read-only tables
+        whose file is changed underneath them. Pinned frames are left alone.
+    */
+    pub(crate) fn discard_all(&self) {
+        let _replacing = self.replacer.lock();
+        let mut page_table = self.page_table.write();
+
         for i in 0..self.size {
-            if self.frames[i].dirty.load(Ordering::Relaxed)
-                && Arc::strong_count(&self.frames[i]) < 2
-            {
-                self.frames[i].flush(self.disk.borrow());
-                self.page_frame_map
-                    .remove(&self.frames[i].page_id.load(Ordering::Relaxed));
+            if self.frames[i].pin_count() == 0 {
+                let page_id = self.frames[i].page_id.swap(!0, Ordering::Relaxed);
+                self.frames[i].dirty.store(false, Ordering::Relaxed);
+                page_table.pages.remove(&page_id);
+                page_table.policy.forget(i);
             }
         }
-        self.disk.flush();
     }
 
-    fn evict(&mut self, victim: usize) {
-        let frame = &self.frames[victim];
+    /*
+        Forgets a page that no longer belongs to anything, e.g. a base page copy
+        a merge replaced, writing it back first only if flush is set. A frame
+        still pinned keeps its contents for whoever holds it, but is detached
+        from the page so nothing it gets written ever reaches the disk.
+    */
+    pub fn invalidate(&self, page_id: usize, flush: bool) {
+        let _replacing = self.replacer.lock();
+        let mut page_table = self.page_table.write();
+        let Some(frame_id) = page_table.pages.remove(&page_id) else {
+            return;
+        };
+        let frame = &self.frames[frame_id];
+
+        if flush && frame.dirty.load(Ordering::Relaxed) {
+            self.stats.dirty_writes.fetch_add(1, Ordering::Relaxed);
+            self.record_failure(page_id, frame.flush(self.disk.borrow(), false));
+        } else {
+            self.stats.discarded.fetch_add(1, Ordering::Relaxed);
+        }
 
-        self.page_frame_map
-            .remove(&frame.page_id.load(Ordering::Relaxed));
+        self.detach(&mut page_table, frame_id);
+    }
 
-        if frame.dirty.load(Ordering::Relaxed) {
-            frame.flush(self.disk.borrow());
+    /*
+        invalidate without writing back, for many pages at once, like the
+        column pages of the base pages a merge replaced. Frees their frames
+        for the copies instead of leaving them until the clock gets to them.
+    */
+    pub fn discard_pages(&self, page_ids: &[usize]) {
+        let _replacing = self.replacer.lock();
+        let mut page_table = self.page_table.write();
+
+        for page_id in page_ids {
+            if let Some(frame_id) = page_table.pages.remove(page_id) {
+                self.stats.discarded.fetch_add(1, Ordering::Relaxed);
+                self.detach(&mut page_table, frame_id);
+            }
         }
+    }
 
-        frame.dirty.store(false, Ordering::Relaxed);
+    /*
+        Takes a frame already removed from the page table off its page, for good
+    */
+    fn detach(&self, page_table: &mut PageTable, frame_id: usize) {
+        let frame = &self.frames[frame_id];
 
         frame.page_id.store(!0, Ordering::Relaxed);
+        frame.dirty.store(false, Ordering::Relaxed);
+        page_table.policy.forget(frame_id);
+    }
+
+    /*
+        Reads pages into the pool on a helper thread, ahead of a scan or merge
+        that knows it needs them next, so its own fetches hit. Cached pages are
+        skipped, and a page that would need a pinned frame isn't read at all.
+    */
+    pub fn prefetch(self: &Arc<Self>, page_ids: &[usize]) {
+        let page_ids = page_ids
+            .iter()
+            .copied()
+            .filter(|page_id| *page_id != !0 && !self.is_page_mapped(*page_id))
+            .collect::<Vec<_>>();
+
+        if !page_ids.is_empty() {
+            let _ = self
+                .prefetcher
+                .get_or_init(|| BufferPool::spawn_prefetcher(self))
+                .send(page_ids);
+        }
+    }
+
+    /*
+        Loads a page like a scan's miss would, unless it's cached by now or
+        nothing owns it. A freed page could be handed out again while a stale
+        copy of it was still cached, merges free pages before invalidating
+        them so checking under the replacer is enough.
+    */
+    fn load_ahead(&self, page_id: usize) {
+        if self.failure.get().is_some() {
+            return;
+        }
+
+        let replacing = self.replacer.lock();
+        if self.is_page_mapped(page_id) || self.disk.is_free(page_id) {
+            return;
+        }
+
+        let Ok((_frame, mut page)) = self.claim_frame(page_id, true) else {
+            return;
+        };
+        self.stats.prefetched.fetch_add(1, Ordering::Relaxed);
+        drop(replacing);
+
+        self.read_into(page_id, &mut page);
+    }
+
+    /*
+        Frames in the pool, so pages it can keep cached.
+    */
+    pub fn size(&self) -> usize {
+        self.size
     }
 
     pub fn is_page_mapped(&self, page_id: usize) -> bool {
-        self.page_frame_map.contains_key(&page_id)
+        self.page_table.read().pages.contains_key(&page_id)
     }
 
-    pub fn new_page(&mut self) -> Arc<BufferPoolFrame> {
+    /*
+        A fresh disk page, unless every frame is pinned
+    */
+    pub fn new_page(&self) -> Result<PageGuard, CrabError> {
+        let _replacing = self.replacer.lock();
         let new_page_id = self.disk.reserve_page();
 
-        let victim = self.find_evict_victim();
+        match self.claim_frame(new_page_id, false) {
+            Ok((frame, _page)) => Ok(frame),
+            Err(err) => {
+                self.disk.free_page(new_page_id);
+                Err(err)
+            }
+        }
+    }
 
-        self.evict(victim);
+    /*
+        Fails with BufferPoolFull rather than waiting when the page isn't
+        cached and every frame is pinned
+    */
+    pub fn get_page(&self, page_id: usize) -> Result<PageGuard, CrabError> {
+        self.fetch_page(page_id, true)
+    }
 
-        let frame = Arc::clone(&self.frames[victim]);
+    /*
+        Scan hint: a page read once by a sequential scan doesn't get its
+        reference bit set, so it's the next thing the clock evicts instead
+        of pushing out the working set.
+    */
+    pub fn get_page_for_scan(&self, page_id: usize) -> Result<PageGuard, CrabError> {
+        self.fetch_page(page_id, false)
+    }
 
-        frame.page_id.store(new_page_id, Ordering::Relaxed);
-        self.page_frame_map.insert(new_page_id, victim);
+    /*
+        For queries and merges, which have nothing to fall back on without the
+        page: while every frame is pinned, waits for other threads to unpin
+        one. A thread that pinned them all itself would wait forever, so it
+        panics after PIN_WAIT_TIMEOUT instead.
+    */
+    pub(crate) fn pin_page(&self, page_id: usize, scan: bool) -> PageGuard {
+        let started = Instant::now();
+
+        loop {
+            match self.fetch_page(page_id, !scan) {
+                Ok(frame) => return frame,
+                Err(_) if started.elapsed() < PIN_WAIT_TIMEOUT => thread::yield_now(),
+                Err(_) => panic!(
+                    "Every bufferpool frame stayed pinned for {PIN_WAIT_TIMEOUT:?}! Buffer pool is too small!"
+                ),
+            }
+        }
+    }
+
+    /*
+        The frame holding page_id, pinned before the page table is unlocked
+    */
+    fn cached_frame(&self, page_id: usize, scan: bool) -> Option<PageGuard> {
+        let page_table = self.page_table.read();
+        let frame_id = *page_table.pages.get(&page_id)?;
 
-        frame
+        self.stats.hits.fetch_add(1, Ordering::Relaxed);
+        page_table.policy.record_access(frame_id, scan);
+
+        Some(PageGuard::pin(&self.frames[frame_id]))
     }
 
-    pub fn get_page(&mut self, page_id: usize) -> Arc<BufferPoolFrame> {
+    fn fetch_page(&self, page_id: usize, reference: bool) -> Result<PageGuard, CrabError> {
         if page_id == !0 {
             panic!("Tried to load invalid page");
         }
-        if let Some(frame_id) = self.page_frame_map.get(&page_id) {
-            self.clock_refs[*frame_id] = true;
-            let frame = &self.frames[*frame_id];
-            return Arc::clone(frame);
-        }
 
-        let victim = self.find_evict_victim();
-        self.evict(victim);
+        let scan = !(self.charge_query() && reference) || SCAN_THREAD.with(Cell::get);
 
-        let frame = Arc::clone(&self.frames[victim]);
+        self.stats.requests.fetch_add(1, Ordering::Relaxed);
 
-        frame.page_id.store(page_id, Ordering::Relaxed);
+        if let Some(frame) = self.cached_frame(page_id, scan) {
+            return Ok(frame);
+        }
 
-        let mut page = frame
-            .page
-            .write()
-            .expect("Failed to acquire RwLock, poisoned?");
+        let replacing = self.replacer.lock();
+
+        // Another miss may have loaded it while this one waited for the replacer
+        if let Some(frame) = self.cached_frame(page_id, scan) {
+            return Ok(frame);
+        }
 
-        self.disk.read_page(page_id, &mut page.page);
+        let (frame, mut page) = self.claim_frame(page_id, scan)?;
+        self.stats.misses.fetch_add(1, Ordering::Relaxed);
 
-        self.clock_refs[victim] = true;
+        // Hits on the page wait on its write lock until it's read
+        drop(replacing);
 
+        self.read_into(page_id, &mut page);
         drop(page);
 
-        self.page_frame_map
-            .try_insert(page_id, victim)
-            .expect("Tried to re-map existing page in bufferpool");
+        Ok(frame)
+    }
 
-        frame
+    /*
+        A page that can't be read comes back zeroed, the failure fails the next query
+    */
+    fn read_into(&self, page_id: usize, page: &mut PhysicalPage) {
+        if let Err(err) = self.disk.read_page(page_id, &mut page.page) {
+            page.page.fill(0);
+            self.record_failure(page_id, Err(err));
+        }
     }
 }
@@ -0,0 +1,127 @@
+use std::time::Duration;
+
+use crate::{
+    eviction::Eviction, format::PageLayout, merge::MergeConfig, BUFFERPOOL_SIZE, MERGE_THREADS,
+    MERGE_THRESHOLD, PAGE_RANGE_COUNT,
+};
+
+#[derive(Clone, Debug, Default)]
+pub struct CrabConfig {
+    /*
+        Refuse to open a table whose header and directories disagree
+        instead of repairing them from what the data pages say
+    */
+    pub strict: bool,
+    /*
+        Open the directory alongside a writer in another CrabStore. Tables reject
+        writes, and see what the writer last checkpointed until refreshed
+    */
+    pub read_only: bool,
+    /*
+        Threads AsyncCrabStore runs queries on, with the async feature. 0 starts
+        one per CPU
+    */
+    pub async_threads: usize,
+    /*
+        How tables created from here on place their column pages, see PageLayout.
+        Tables opened from disk keep the layout their header records
+    */
+    pub page_layout: PageLayout,
+    /*
+        Base pages of a page range given their disk pages together, on the first
+        insert into any of them. 0 allocates a whole range at once, and so do
+        tables whose ranges the batch doesn't divide. Smaller batches keep small
+        tables' files small, larger ones keep a range's pages closer together on
+        disk
+    */
+    pub base_page_batch: usize,
+    /*
+        Bytes a table file grows by once its pages run out, so it grows in a few
+        large steps rather than a page at a time. 0 uses DEFAULT_EXTENT_SIZE, 4
+        MiB. Closing a table gives back whatever it never used
+    */
+    pub extent_size: usize,
+    /*
+        How often each table writes its dirty cached pages back in the
+        background, so closing has less left to write. Zero uses
+        DEFAULT_FLUSH_INTERVAL, a second. Only checkpoints make the pages
+        readable after a crash
+    */
+    pub flush_interval: Duration,
+    /*
+        Which pages each table's bufferpool evicts first, see Eviction. LruK
+        keeps the pages queries keep coming back to cached through merges
+        and scans, which Clock lets push them out
+    */
+    pub eviction: Eviction,
+    /*
+        Frames in each table's bufferpool, so pages it keeps cached. 0 uses
+        256. Queries and merges pin a few frames at a time each, a pool too
+        small for all of them makes them wait
+    */
+    pub bufferpool_frames: usize,
+    /*
+        Base pages in each page range of tables created from here on, which
+        share tail pages and are merged together. 0 uses 16. Recorded in the
+        header, tables opened from disk keep theirs
+    */
+    pub page_range_pages: usize,
+    /*
+        Tail pages a page range fills before the merge thread merges it. 0
+        uses 4. Lower merges sooner, so reads follow fewer tail records, at
+        the cost of more merging
+    */
+    pub merge_threshold: usize,
+    /*
+        Leaves each table's ranges to Table::force_merge, nothing is merged
+        in the background. See Table::set_merge_config
+    */
+    pub manual_merge: bool,
+    /*
+        Merged tail pages each range keeps for version queries, the rest are
+        reclaimed at checkpoints. None keeps them all
+    */
+    pub retained_tail_pages: Option<usize>,
+    /*
+        Threads a CrabStore merges all its tables' ranges on, started as
+        tables open. 0 uses 2. A table is always merged by the same one
+    */
+    pub merge_threads: usize,
+}
+
+/*
+    The settings a table is built with, 0 replaced by its default
+*/
+impl CrabConfig {
+    pub(crate) fn frames(&self) -> usize {
+        match self.bufferpool_frames {
+            0 => BUFFERPOOL_SIZE,
+            frames => frames,
+        }
+    }
+
+    pub(crate) fn range_pages(&self) -> usize {
+        match self.page_range_pages {
+            0 => PAGE_RANGE_COUNT,
+            pages => pages,
+        }
+    }
+
+    pub(crate) fn merge_threads(&self) -> usize {
+        match self.merge_threads {
+            0 => MERGE_THREADS,
+            threads => threads,
+        }
+    }
+
+    pub(crate) fn merge_config(&self) -> MergeConfig {
+        MergeConfig {
+            tail_pages_per_merge: match self.merge_threshold {
+                0 => MERGE_THRESHOLD,
+                pages => pages,
+            },
+            enabled: !self.manual_merge,
+            retained_tail_pages: self.retained_tail_pages,
+        }
+    }
+}
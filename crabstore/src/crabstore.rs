@@ -1,74 +1,59 @@
 use std::{
     collections::HashMap,
-    fs::{self, File},
-    io::{self, BufWriter, Read, Seek, Write},
+    fs,
     path::{Path, PathBuf},
     sync::Arc,
+    time::{Duration, Instant},
 };
 
-use rkyv::ser::{
-    serializers::{AllocScratch, CompositeSerializer, SharedSerializeMap, WriteSerializer},
-    Serializer,
+use crate::{
+    archive,
+    config::CrabConfig,
+    error::CrabError,
+    eviction::Eviction,
+    format::{TableHeaderPage, TableList},
+    merge::MergeScheduler,
+    schema::ColumnType,
+    table::{CompactReport, Table},
 };
 
-use crate::table::Table;
+/*
+    Everything a table keeps on disk, see CrabStore::table_files
+*/
+// written into a compaction's staging directory once all its files are complete
+const COMPACTION_MARKER: &str = "COMPLETE";
+
+#[derive(Clone, Debug)]
+pub struct TableFiles {
+    pub db_file: PathBuf,
+    pub pd_file: PathBuf,
+    pub id_file: PathBuf,
+    pub rd_file: PathBuf,
+    pub st_file: PathBuf,
+    // Copy of the table file's header, see TableHeaderPage::write
+    pub hd_file: PathBuf,
+}
 
 #[derive(Clone, Default)]
 pub struct CrabStore {
     pub directory: PathBuf,
+    pub config: CrabConfig,
     tables: HashMap<String, Arc<Table>>,
+    // Merges every table opened here, see CrabConfig::merge_threads
+    merge_scheduler: Arc<MergeScheduler>,
 }
 
 impl CrabStore {
-    pub fn load_table_index(file: &Path) -> Vec<String> {
-        let crab_file = File::options().read(true).open(file);
-
-        if crab_file.is_err() && crab_file.as_ref().unwrap_err().kind() == io::ErrorKind::NotFound {
-            File::create(file).expect("Failed to find database file");
-
-            return Vec::new();
+    pub fn load_table_index(file: &Path) -> Result<Vec<String>, CrabError> {
+        if !file.exists() {
+            return Ok(Vec::new());
         }
 
-        let mut crab_file = crab_file.unwrap();
-
-        crab_file.rewind().unwrap();
-
-        let mut crab_bytes = Vec::new();
-        crab_file.read_to_end(&mut crab_bytes).unwrap();
-
-        unsafe {
-            rkyv::from_bytes_unchecked::<Vec<String>>(&crab_bytes)
-                .expect("Failed to deserialize database file")
-        }
+        Ok(TableList::decode(file, &archive::read_file(file)?)?.names)
     }
 
-    pub fn persist_table_index(file: &Path, table_names: Vec<String>) {
-        let mut crab_file = File::options()
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(file)
-            .expect("Failed to open database file");
-
-        crab_file.rewind().unwrap();
-
-        let mut bufwriter = BufWriter::new(crab_file);
-
-        bufwriter.rewind().unwrap();
-
-        let mut serializer = CompositeSerializer::new(
-            WriteSerializer::new(bufwriter),
-            AllocScratch::default(),
-            SharedSerializeMap::new(),
-        );
-
-        serializer
-            .serialize_value(&table_names)
-            .expect("Unable to serialize table names");
-
-        let (buf, _, _) = serializer.into_components();
-
-        buf.into_inner().flush().unwrap();
+    pub fn persist_table_index(file: &Path, table_names: Vec<String>) -> Result<(), CrabError> {
+        archive::write_file(file, &TableList { names: table_names }.encode())
     }
 
     pub fn database_filename(directory: &Path) -> PathBuf {
@@ -102,66 +87,430 @@ impl CrabStore {
 
         directory.join(Path::new(&rd_file))
     }
+
+    pub fn stats_filename(directory: &Path, table: &str) -> PathBuf {
+        let mut st_file = table.to_string();
+        st_file.push_str("_st.CRAB");
+
+        directory.join(Path::new(&st_file))
+    }
+
+    pub fn header_filename(directory: &Path, table: &str) -> PathBuf {
+        let mut hd_file = table.to_string();
+        hd_file.push_str("_hd.CRAB");
+
+        directory.join(Path::new(&hd_file))
+    }
+
+    /*
+        Where compact_table stages a table's rewritten files before swapping them in
+    */
+    pub fn compact_dirname(directory: &Path, table: &str) -> PathBuf {
+        let mut compact_dir = table.to_string();
+        compact_dir.push_str("_compact");
+
+        directory.join(Path::new(&compact_dir))
+    }
+
+    pub fn table_files(directory: &Path, table: &str) -> TableFiles {
+        TableFiles {
+            db_file: CrabStore::table_filename(directory, table),
+            pd_file: CrabStore::page_dir_filename(directory, table),
+            id_file: CrabStore::index_filename(directory, table),
+            rd_file: CrabStore::range_filename(directory, table),
+            st_file: CrabStore::stats_filename(directory, table),
+            hd_file: CrabStore::header_filename(directory, table),
+        }
+    }
 }
 
 impl CrabStore {
     pub fn new(directory: PathBuf) -> Self {
+        CrabStore::with_config(directory, CrabConfig::default())
+    }
+
+    pub fn with_config(directory: PathBuf, config: CrabConfig) -> Self {
         CrabStore {
             directory,
+            merge_scheduler: Arc::new(MergeScheduler::new(config.merge_threads())),
+            config,
             tables: HashMap::new(),
         }
     }
 
     pub fn create_table(&mut self, name: &str, num_columns: usize, key_index: usize) -> Arc<Table> {
-        let table = Arc::new(Table::new(
+        let table = Arc::new(Table::with_scheduler(
             name.to_string(),
             num_columns,
             key_index,
-            &CrabStore::table_filename(&self.directory, name),
-            &CrabStore::page_dir_filename(&self.directory, name),
-            &CrabStore::index_filename(&self.directory, name),
-            &CrabStore::range_filename(&self.directory, name),
+            &CrabStore::table_files(&self.directory, name),
+            &self.config,
+            &self.merge_scheduler,
         ));
+        self.configure(&table);
+        table.ensure_primary_index();
         self.tables.insert(name.to_string(), Arc::clone(&table));
         table
     }
 
+    /*
+        create_table with a name for each column, which the table then answers to
+        in Table::column_index and the *_named queries. Names must be unique.
+    */
+    pub fn create_table_named(
+        &mut self,
+        name: &str,
+        column_names: Vec<String>,
+        key_index: usize,
+    ) -> Result<Arc<Table>, CrabError> {
+        let column_types = vec![ColumnType::Unsigned; column_names.len()];
+        self.create_table_typed(name, column_types, column_names, key_index)
+    }
+
+    /*
+        create_table with a type for each column, and names as in create_table_named
+        unless column_names is empty
+    */
+    pub fn create_table_typed(
+        &mut self,
+        name: &str,
+        column_types: Vec<ColumnType>,
+        column_names: Vec<String>,
+        key_index: usize,
+    ) -> Result<Arc<Table>, CrabError> {
+        self.create_table_schema(name, column_types, column_names, key_index, false)
+    }
+
+    /*
+        create_table_typed for a table whose columns but the primary key can hold
+        NULLs, see Table::insert_query_nullable. Its pages carry a NULL mask for
+        every record, so each logical page takes one column page more.
+    */
+    pub fn create_table_nullable(
+        &mut self,
+        name: &str,
+        column_types: Vec<ColumnType>,
+        column_names: Vec<String>,
+        key_index: usize,
+    ) -> Result<Arc<Table>, CrabError> {
+        self.create_table_schema(name, column_types, column_names, key_index, true)
+    }
+
+    fn create_table_schema(
+        &mut self,
+        name: &str,
+        column_types: Vec<ColumnType>,
+        column_names: Vec<String>,
+        key_index: usize,
+        nullable: bool,
+    ) -> Result<Arc<Table>, CrabError> {
+        let num_columns = column_types.len();
+
+        if num_columns == 0 || num_columns > u64::BITS as usize {
+            return Err(CrabError::InvalidSchema(format!(
+                "{num_columns} columns, a table has 1 to {}",
+                u64::BITS
+            )));
+        }
+
+        if key_index >= num_columns {
+            return Err(CrabError::InvalidSchema(format!(
+                "primary key {key_index} out of range"
+            )));
+        }
+
+        if !column_names.is_empty() && column_names.len() != num_columns {
+            return Err(CrabError::InvalidSchema(format!(
+                "{} names for {num_columns} columns",
+                column_names.len()
+            )));
+        }
+
+        for (column, name) in column_names.iter().enumerate() {
+            if column_names[..column].contains(name) {
+                return Err(CrabError::InvalidSchema(format!("duplicate column {name}")));
+            }
+        }
+
+        if !TableHeaderPage::names_fit(&column_names) {
+            return Err(CrabError::InvalidSchema(
+                "too long to fit in the table header".into(),
+            ));
+        }
+
+        let table = Arc::new(
+            Table::with_scheduler(
+                name.to_string(),
+                num_columns,
+                key_index,
+                &CrabStore::table_files(&self.directory, name),
+                &self.config,
+                &self.merge_scheduler,
+            )
+            .with_column_names(column_names)
+            .with_column_types(&column_types)
+            .with_nullable(nullable),
+        );
+        self.configure(&table);
+        table.ensure_primary_index();
+        self.tables.insert(name.to_string(), Arc::clone(&table));
+        Ok(table)
+    }
+
+    /*
+        Forgets the table, once the merges already requested for it are done
+    */
     pub fn drop_table(&mut self, name: &str) -> bool {
-        self.tables.remove(name);
+        if let Some(table) = self.tables.remove(name) {
+            table.close_merges();
+        }
         true
     }
 
+    /*
+        The threads merging this store's tables.
+    */
+    pub fn merge_scheduler(&self) -> &MergeScheduler {
+        &self.merge_scheduler
+    }
+
     pub fn get_table(&self, name: &str) -> Arc<Table> {
         Arc::clone(self.tables.get(name).expect("Table not found"))
     }
 
-    pub fn open(&mut self) {
-        fs::create_dir_all(&self.directory).expect("Failed to create database directories.");
+    fn load_table(&self, name: &str) -> Result<Arc<Table>, CrabError> {
+        let table = Arc::new(Table::load_with_scheduler(
+            name,
+            &CrabStore::table_files(&self.directory, name),
+            &self.config,
+            &self.merge_scheduler,
+        )?);
+        self.configure(&table);
+        table.ensure_primary_index();
+        Ok(table)
+    }
+
+    /*
+        Settings from the config that aren't recorded in a table's files
+    */
+    fn configure(&self, table: &Table) {
+        if self.config.base_page_batch != 0 {
+            // The table's ranges may be sized differently, e.g. when it was created with another config
+            let batch = match self.config.base_page_batch {
+                batch if table.page_range_pages().is_multiple_of(batch) => batch,
+                _ => table.page_range_pages(),
+            };
+            table.set_base_page_batch(batch);
+        }
+        if self.config.extent_size != 0 {
+            table.set_extent_size(self.config.extent_size);
+        }
+        if !self.config.flush_interval.is_zero() {
+            table.set_flush_interval(self.config.flush_interval);
+        }
+        if self.config.eviction != Eviction::default() {
+            table.set_eviction(self.config.eviction);
+        }
+    }
+
+    /*
+        Moves a finished compaction's files over the table's own. The marker is only
+        written once every staged file is complete, so a crash before it leaves the
+        old files in place and one after it is finished on the next open.
+    */
+    fn finish_compaction(directory: &Path, name: &str) {
+        let compact_dir = CrabStore::compact_dirname(directory, name);
+
+        if !compact_dir.exists() {
+            return;
+        }
+
+        if compact_dir.join(COMPACTION_MARKER).exists() {
+            let staged = CrabStore::table_files(&compact_dir, name);
+            let live = CrabStore::table_files(directory, name);
+
+            for (from, to) in [
+                (staged.pd_file, live.pd_file),
+                (staged.id_file, live.id_file),
+                (staged.rd_file, live.rd_file),
+                (staged.st_file, live.st_file),
+                (staged.hd_file, live.hd_file),
+                (staged.db_file, live.db_file),
+            ] {
+                // Already moved before a crash part way through
+                if from.exists() {
+                    fs::rename(from, to).expect("Failed to swap in compacted table file");
+                }
+            }
+        }
+
+        fs::remove_dir_all(compact_dir).expect("Failed to remove compaction directory");
+    }
+
+    /*
+        Rewrites a table densely into fresh files and swaps them in, see Table::compact_into.
+        The table is closed meanwhile, so handles to it from before fail with TableClosed
+        and the table has to be fetched again with get_table.
+    */
+    pub fn compact_table(&mut self, name: &str) -> Result<CompactReport, CrabError> {
+        if self.config.read_only {
+            return Err(CrabError::ReadOnly);
+        }
+
+        let table = self.get_table(name);
+        if !table.get_lock_manager().is_idle() {
+            return Err(CrabError::TableInUse);
+        }
+
+        // Closing drains running queries and stops the merge thread before the copy
+        table.persist()?;
+
+        let compact_dir = CrabStore::compact_dirname(&self.directory, name);
+        let compacted = table.compact_into(&compact_dir).and_then(|report| {
+            archive::write_file(&compact_dir.join(COMPACTION_MARKER), &[])?;
+            Ok(report)
+        });
+        CrabStore::finish_compaction(&self.directory, name);
+
+        self.tables.insert(name.to_string(), self.load_table(name)?);
+
+        compacted
+    }
+
+    fn open_result(mut errors: Vec<CrabError>) -> Result<(), CrabError> {
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.pop().unwrap()),
+            _ => Err(CrabError::OpenFailed(errors)),
+        }
+    }
+
+    pub fn open(&mut self) -> Result<(), CrabError> {
+        if !self.config.read_only {
+            fs::create_dir_all(&self.directory).expect("Failed to create database directories.");
+        }
 
         let table_names =
-            CrabStore::load_table_index(&CrabStore::database_filename(&self.directory));
+            CrabStore::load_table_index(&CrabStore::database_filename(&self.directory))?;
+
+        // Keep going past a bad table so the error covers everything that needs fixing
+        let mut errors = Vec::new();
 
         for name in table_names.iter() {
-            self.tables.insert(
-                name.to_string(),
-                Arc::new(Table::load(
-                    name,
-                    &CrabStore::table_filename(&self.directory, name),
-                    &CrabStore::page_dir_filename(&self.directory, name),
-                    &CrabStore::index_filename(&self.directory, name),
-                    &CrabStore::range_filename(&self.directory, name),
-                )),
-            );
+            if !self.config.read_only {
+                CrabStore::finish_compaction(&self.directory, name);
+            }
+
+            match self.load_table(name) {
+                Ok(table) => {
+                    self.tables.insert(name.to_string(), table);
+                }
+                Err(err) => errors.push(err),
+            }
         }
+
+        CrabStore::open_result(errors)
     }
 
-    pub fn close(&mut self) {
-        let table_names = self.tables.keys().cloned().collect::<Vec<String>>();
+    /*
+        Makes everything written so far visible to read-only stores on the same
+        directory, without closing anything. Tables go before the table list so
+        a reader never finds a table whose files aren't there yet. A table whose
+        storage is gone is reported and skipped, its queries fail on their own.
+    */
+    pub fn checkpoint(&self) {
+        assert!(
+            !self.config.read_only,
+            "Read-only stores have nothing to checkpoint"
+        );
+
+        for (name, table) in self.tables.iter() {
+            if let Err(err) = table.checkpoint() {
+                eprintln!("Failed to checkpoint table {name}: {err}");
+            }
+        }
+
+        if let Err(err) = CrabStore::persist_table_index(
+            &CrabStore::database_filename(&self.directory),
+            self.tables.keys().cloned().collect(),
+        ) {
+            eprintln!("Failed to write the table list: {err}");
+        }
+    }
 
-        CrabStore::persist_table_index(&CrabStore::database_filename(&self.directory), table_names);
+    /*
+        Table::quiesce for every table, all within the one timeout
+    */
+    pub fn quiesce_all(&self, timeout: Duration) -> Result<(), CrabError> {
+        let deadline = Instant::now() + timeout;
 
         for table in self.tables.values() {
-            table.persist();
+            table.quiesce(deadline.saturating_duration_since(Instant::now()))?;
+        }
+
+        Ok(())
+    }
+
+    /*
+        Catches a read-only store up with the writer's last checkpoint. Tables
+        already handed out are refreshed in place, new ones are opened and
+        dropped ones forgotten.
+
+        Reads see the last checkpoint: rows inserted since are hidden. Pages the
+        writer flushes in between are read as they are, so a row updated since
+        may already show that update, or its last merged version if the update
+        is only partly on disk. A CorruptFile error means the writer was
+        checkpointing at that moment and refresh can be retried.
+    */
+    pub fn refresh(&mut self) -> Result<(), CrabError> {
+        assert!(
+            self.config.read_only,
+            "Only read-only stores can be refreshed"
+        );
+
+        let table_names =
+            CrabStore::load_table_index(&CrabStore::database_filename(&self.directory))?;
+
+        self.tables.retain(|name, _| table_names.contains(name));
+
+        let mut errors = Vec::new();
+
+        for name in table_names.iter() {
+            let refreshed = match self.tables.get(name) {
+                Some(table) => table.refresh(&CrabStore::table_files(&self.directory, name)),
+                None => self.load_table(name).map(|table| {
+                    self.tables.insert(name.to_string(), table);
+                }),
+            };
+
+            if let Err(err) = refreshed {
+                errors.push(err);
+            }
+        }
+
+        CrabStore::open_result(errors)
+    }
+
+    /*
+        Closes every table even if some can't be written, say because the
+        directory is gone, and reports the ones that couldn't
+    */
+    pub fn close(&mut self) {
+        if !self.config.read_only {
+            let table_names = self.tables.keys().cloned().collect::<Vec<String>>();
+
+            if let Err(err) = CrabStore::persist_table_index(
+                &CrabStore::database_filename(&self.directory),
+                table_names,
+            ) {
+                eprintln!("Failed to write the table list: {err}");
+            }
+        }
+
+        for (name, table) in self.tables.iter() {
+            if let Err(err) = table.persist() {
+                eprintln!("Failed to persist table {name}: {err}");
+            }
         }
 
         self.tables.clear();
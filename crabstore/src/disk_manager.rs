@@ -1,78 +1,306 @@
 use std::{
+    collections::BTreeSet,
     fs::*,
-    io::{self, Write},
+    io,
     path::Path,
     sync::atomic::{AtomicUsize, Ordering},
 };
 
-#[cfg(target_os = "linux")]
+#[cfg(unix)]
 use std::os::unix::prelude::FileExt;
 
-#[cfg(target_os = "windows")]
+#[cfg(windows)]
 use std::os::windows::prelude::FileExt;
 
 use parking_lot::Mutex;
 
 use crate::PAGE_SIZE;
+
+/*
+    How much a table file grows by at a time unless CrabConfig::extent_size says
+    otherwise.
+*/
+pub const DEFAULT_EXTENT_SIZE: usize = 4 << 20;
+
 #[derive(Debug)]
 pub struct DiskManager {
     file: Mutex<File>,
     next_free_page: AtomicUsize,
+    free_pages: Mutex<FreePages>,
+    // Pages the file has room for, written or not, see DiskManager::allocate
+    allocated_pages: AtomicUsize,
+    extent_pages: AtomicUsize,
+}
+
+/*
+    Pages given back with free_page. Until the next checkpoint the files on disk
+    may still reference a freed page, so it waits in freed rather than being
+    handed out again, see DiskManager::reuse_freed.
+*/
+#[derive(Debug, Default)]
+struct FreePages {
+    reusable: BTreeSet<usize>,
+    freed: Vec<usize>,
 }
 
 impl DiskManager {
     pub fn new(file_path: &Path) -> Result<Self, io::Error> {
-        Ok(DiskManager {
-            file: Mutex::new(
-                OpenOptions::new()
-                    .read(true)
-                    .write(true)
-                    .create(true)
-                    .open(file_path)?,
-            ),
+        DiskManager::with_extent_size(file_path, DEFAULT_EXTENT_SIZE)
+    }
+
+    /*
+        Grows the file extent_size bytes at a time, rounded up to whole pages
+    */
+    pub fn with_extent_size(file_path: &Path, extent_size: usize) -> Result<Self, io::Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            // Reopening a table keeps its pages
+            .truncate(false)
+            .open(file_path)?;
+        DiskManager::from_file(file, extent_size)
+    }
+
+    /*
+        For readers sharing the file with a writer, any write through this fails
+    */
+    pub fn open_read_only(file_path: &Path) -> Result<Self, io::Error> {
+        let file = OpenOptions::new().read(true).open(file_path)?;
+        DiskManager::from_file(file, DEFAULT_EXTENT_SIZE)
+    }
+
+    fn from_file(file: File, extent_size: usize) -> Result<Self, io::Error> {
+        let allocated_pages = (file.metadata()?.len() as usize).div_ceil(PAGE_SIZE);
+
+        let disk = DiskManager {
+            file: Mutex::new(file),
             next_free_page: 1.into(),
-        })
+            free_pages: Default::default(),
+            allocated_pages: allocated_pages.into(),
+            extent_pages: 1.into(),
+        };
+        disk.set_extent_size(extent_size);
+        Ok(disk)
     }
 
-    pub fn flush(&self) {
-        let mut file = self.file.lock();
-        file.flush().expect("Failed to flush file to disk");
+    pub fn set_extent_size(&self, extent_size: usize) {
+        self.extent_pages
+            .store(extent_size.div_ceil(PAGE_SIZE).max(1), Ordering::Relaxed);
     }
 
-    #[cfg(target_os = "windows")]
-    pub fn read_page(&self, page_id: usize, page: &mut [u8; PAGE_SIZE]) -> usize {
+    /*
+        Makes room in the file for every page below end, growing it to the next
+        whole extent so a run of inserts doesn't extend it a page at a time.
+        Never shrinks it, and a file that can't grow is extended by the writes
+        past its end instead.
+    */
+    fn allocate(&self, end: usize) {
+        if end <= self.allocated_pages.load(Ordering::Relaxed) {
+            return;
+        }
+
         let file = self.file.lock();
-        file.seek_read(page, (page_id * PAGE_SIZE) as u64)
-            .expect("Failed to read page")
+        let extent = self.extent_pages.load(Ordering::Relaxed);
+        let grown = end.div_ceil(extent) * extent;
+
+        let Ok(metadata) = file.metadata() else {
+            return;
+        };
+        let len = metadata.len() as usize;
+        if len >= grown * PAGE_SIZE || file.set_len((grown * PAGE_SIZE) as u64).is_ok() {
+            self.allocated_pages
+                .fetch_max(grown.max(len.div_ceil(PAGE_SIZE)), Ordering::Relaxed);
+        }
     }
 
-    #[cfg(target_os = "linux")]
-    pub fn read_page(&self, page_id: usize, page: &mut [u8; PAGE_SIZE]) -> usize {
+    /*
+        Gives back the room allocate made past the last reserved page, so a closed
+        table's file is no bigger than its pages
+    */
+    pub fn trim(&self) -> io::Result<()> {
         let file = self.file.lock();
-        file.read_at(page, (page_id * PAGE_SIZE) as u64)
-            .expect("Failed to read page")
+        let end = self.next_free_page.load(Ordering::Relaxed);
+
+        if file.metadata()?.len() > (end * PAGE_SIZE) as u64 {
+            file.set_len((end * PAGE_SIZE) as u64)?;
+        }
+        self.allocated_pages.store(end, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /*
+        Pages the file has room for, including ones allocated ahead but never written
+    */
+    pub fn allocated_pages(&self) -> usize {
+        self.allocated_pages.load(Ordering::Relaxed)
+    }
+
+    /*
+        Makes everything written so far durable. Writes don't sync on their own,
+        so a batch of them is synced once, after the last
+    */
+    pub fn flush(&self) -> io::Result<()> {
+        self.file.lock().sync_data()
     }
 
-    #[cfg(target_os = "windows")]
-    pub fn write_page(&self, page_id: usize, page: &[u8; PAGE_SIZE]) -> usize {
+    /*
+        Reads until the page is full or the file ends, and zeroes the rest the way
+        a page reserved but never written reads. Returns how many bytes came from
+        the file.
+    */
+    pub fn read_page(&self, page_id: usize, page: &mut [u8; PAGE_SIZE]) -> io::Result<usize> {
         let file = self.file.lock();
-        file.seek_write(page, (page_id * PAGE_SIZE) as u64)
-            .expect("Failed to write page")
+        let offset = (page_id * PAGE_SIZE) as u64;
+
+        let mut read = 0;
+        while read < PAGE_SIZE {
+            match read_at(&file, &mut page[read..], offset + read as u64) {
+                Ok(0) => break,
+                Ok(bytes) => read += bytes,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        page[read..].fill(0);
+        Ok(read)
     }
 
-    #[cfg(target_os = "linux")]
-    pub fn write_page(&self, page_id: usize, page: &[u8; PAGE_SIZE]) -> usize {
+    /*
+        Writes the whole page or fails
+    */
+    pub fn write_page(&self, page_id: usize, page: &[u8; PAGE_SIZE]) -> io::Result<usize> {
         let file = self.file.lock();
-        file.write_at(page, (page_id * PAGE_SIZE) as u64)
-            .expect("Failed to write page")
+        let offset = (page_id * PAGE_SIZE) as u64;
+
+        let mut written = 0;
+        while written < PAGE_SIZE {
+            match write_at(&file, &page[written..], offset + written as u64) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(bytes) => written += bytes,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(written)
+    }
+
+    /*
+        Writes every page, stopping at the first that fails, for the caller to flush once after
+    */
+    pub fn write_pages(&self, pages: &[(usize, &[u8; PAGE_SIZE])]) -> io::Result<()> {
+        for (page_id, page) in pages {
+            self.write_page(*page_id, page)
+                .map_err(|err| io::Error::new(err.kind(), format!("page {page_id}: {err}")))?;
+        }
+        Ok(())
     }
 
     pub fn reserve_page(&self) -> usize {
-        self.next_free_page.fetch_add(1, Ordering::Relaxed)
+        self.reserve_range(1)
     }
 
+    /*
+        The first of pages consecutive pages, reused ones if enough of them lie
+        together. Reused pages are zeroed first, as a page past the end of the
+        file would read, and one that can't be is never handed out again.
+    */
     pub fn reserve_range(&self, pages: usize) -> usize {
-        self.next_free_page.fetch_add(pages, Ordering::Relaxed)
+        let mut free_pages = self.free_pages.lock();
+
+        if let Some(start) = free_pages.take_run(pages) {
+            let zeroed = (start..start + pages).all(|page_id| {
+                self.write_page(page_id, &[0; PAGE_SIZE])
+                    .is_ok_and(|written| written == PAGE_SIZE)
+            });
+            if zeroed {
+                return start;
+            }
+        }
+
+        let start = self.next_free_page.fetch_add(pages, Ordering::Relaxed);
+        self.allocate(start + pages);
+        start
+    }
+
+    /*
+        For a page nothing references any more, e.g. a base page a merge replaced.
+        It must not be mapped in the bufferpool any more, see BufferPool::discard_pages,
+        which comes after this so a prefetch can't read it back in between.
+    */
+    pub fn free_page(&self, page_id: usize) {
+        self.free_pages.lock().freed.push(page_id);
+    }
+
+    pub fn is_free(&self, page_id: usize) -> bool {
+        let free_pages = self.free_pages.lock();
+        free_pages.reusable.contains(&page_id) || free_pages.freed.contains(&page_id)
+    }
+
+    pub fn free_range(&self, start: usize, count: usize) {
+        self.free_pages.lock().freed.extend(start..start + count);
+    }
+
+    /*
+        Called once a checkpoint has written files that no longer reference the
+        pages freed before it
+    */
+    pub fn reuse_freed(&self) {
+        let free_pages = &mut *self.free_pages.lock();
+        free_pages.reusable.extend(free_pages.freed.drain(..));
+    }
+
+    /*
+        Every free page as runs of (first page, count), lowest first
+    */
+    pub fn free_runs(&self) -> Vec<(usize, usize)> {
+        let free_pages = self.free_pages.lock();
+        let mut pages = free_pages
+            .reusable
+            .iter()
+            .chain(&free_pages.freed)
+            .copied()
+            .collect::<Vec<_>>();
+        pages.sort_unstable();
+
+        let mut runs: Vec<(usize, usize)> = Vec::new();
+        for page_id in pages {
+            match runs.last_mut() {
+                Some((start, count)) if *start + *count == page_id => *count += 1,
+                _ => runs.push((page_id, 1)),
+            }
+        }
+        runs
+    }
+
+    pub fn set_free_runs(&self, runs: &[(usize, usize)]) {
+        *self.free_pages.lock() = FreePages {
+            reusable: runs
+                .iter()
+                .flat_map(|(start, count)| *start..*start + *count)
+                .collect(),
+            freed: Vec::new(),
+        };
+    }
+
+    /*
+        Takes the pages keep rejects off the free list, returning how many there were
+    */
+    pub fn retain_free(&self, mut keep: impl FnMut(usize) -> bool) -> usize {
+        let free_pages = &mut *self.free_pages.lock();
+        let before = free_pages.reusable.len() + free_pages.freed.len();
+
+        free_pages.reusable.retain(|page_id| keep(*page_id));
+        free_pages.freed.retain(|page_id| keep(*page_id));
+
+        before - free_pages.reusable.len() - free_pages.freed.len()
+    }
+
+    pub fn free_page_count(&self) -> usize {
+        let free_pages = self.free_pages.lock();
+        free_pages.reusable.len() + free_pages.freed.len()
     }
 
     pub fn free_page_pointer(&self) -> usize {
@@ -83,3 +311,63 @@ impl DiskManager {
         self.next_free_page.store(ptr, Ordering::Relaxed)
     }
 }
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    file.read_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    file.seek_read(buf, offset)
+}
+
+#[cfg(unix)]
+fn write_at(file: &File, buf: &[u8], offset: u64) -> io::Result<usize> {
+    file.write_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn write_at(file: &File, buf: &[u8], offset: u64) -> io::Result<usize> {
+    file.seek_write(buf, offset)
+}
+
+impl FreePages {
+    /*
+        Removes count consecutive reusable pages and returns the first of them.
+        They come from the shortest run long enough, so a merge's copies go back
+        where the originals were and longer runs are kept for longer reservations.
+    */
+    fn take_run(&mut self, count: usize) -> Option<usize> {
+        let mut best: Option<(usize, usize)> = None;
+        let mut run: Option<(usize, usize)> = None;
+
+        let pages = self.reusable.iter().copied().map(Some).chain([None]);
+        for page_id in pages {
+            match (run, page_id) {
+                (Some((start, len)), Some(page_id)) if start + len == page_id => {
+                    run = Some((start, len + 1));
+                    continue;
+                }
+                _ => {}
+            }
+
+            if let Some((start, len)) = run {
+                let shorter = match best {
+                    Some((_, best_len)) => len < best_len,
+                    None => true,
+                };
+                if len >= count && shorter {
+                    best = Some((start, len));
+                }
+            }
+            run = page_id.map(|page_id| (page_id, 1));
+        }
+
+        let (start, _) = best?;
+        for page_id in start..start + count {
+            self.reusable.remove(&page_id);
+        }
+        Some(start)
+    }
+}
@@ -0,0 +1,152 @@
+use std::{fmt, path::PathBuf};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CrabError {
+    /*
+        The table was persisted for shutdown, no further queries are accepted
+    */
+    TableClosed,
+    /*
+        Strict open found the table header and directories disagreeing
+    */
+    InconsistentTable(String),
+    /*
+        Writes are rejected until the table is unfrozen
+    */
+    TableFrozen,
+    /*
+        A file failed validation while loading, nothing was read from it
+    */
+    CorruptFile { path: PathBuf, reason: String },
+    /*
+        Open reports every table that failed to load instead of only the first
+    */
+    OpenFailed(Vec<CrabError>),
+    /*
+        The query made more page requests than its QueryContext allowed
+    */
+    BudgetExceeded { fetches: usize, max_fetches: usize },
+    /*
+        The table was opened read-only and cannot be written
+    */
+    ReadOnly,
+    /*
+        An ordered query needs an index on the column, it won't sort a scan instead
+    */
+    NotIndexed(usize),
+    /*
+        Transactions still hold locks on the table's rows, it can't be compacted under them
+    */
+    TableInUse,
+    /*
+        The table's files can't be read or written any more, e.g. because its
+        directory was deleted. Every query after the first failure gets this
+    */
+    StorageUnavailable(String),
+    /*
+        Table::quiesce gave up waiting on background work
+    */
+    QuiesceTimeout,
+    /*
+        Column names a table can't be created with: duplicates, the wrong number
+        of them, or more than its header has room for
+    */
+    InvalidSchema(String),
+    /*
+        No column of the table has this name
+    */
+    UnknownColumn(String),
+    /*
+        A sum doesn't fit the type of the column summed
+    */
+    SumOverflow(usize),
+    /*
+        A NULL written to the primary key, or to a table created without
+        room for NULLs, see CrabStore::create_table_nullable
+    */
+    NotNullable(usize),
+    /*
+        A query as of a timestamp on a table written before versions were
+        timestamped, see Table::select_as_of_query
+    */
+    NoTimestamps,
+    /*
+        A query run on its own in an open transaction aborted and was undone,
+        see Transaction::select. The transaction itself stays open
+    */
+    TransactionAborted { retryable: bool },
+    /*
+        Every bufferpool frame is pinned, so there is none to read another page into
+    */
+    BufferPoolFull,
+    /*
+        A merge of the range panicked, see Table::merge_status. The table
+        still reads and writes, but the range's base pages may stay unmerged
+    */
+    MergeFailed { range_id: usize, reason: String },
+    /*
+        The version asked for was in tail pages a checkpoint reclaimed, see
+        MergeConfig::retained_tail_pages
+    */
+    VersionUnavailable,
+    /*
+        Rows already share the value, so the column can't have a unique index,
+        see Table::build_unique_index
+    */
+    DuplicateValue { column: usize, value: u64 },
+}
+
+impl fmt::Display for CrabError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CrabError::TableClosed => write!(f, "Table has been closed"),
+            CrabError::InconsistentTable(reason) => {
+                write!(f, "Table metadata is inconsistent: {reason}")
+            }
+            CrabError::TableFrozen => write!(f, "Table is frozen"),
+            CrabError::CorruptFile { path, reason } => {
+                write!(f, "{} is corrupt: {reason}", path.display())
+            }
+            CrabError::OpenFailed(errors) => {
+                write!(f, "Failed to open {} tables:", errors.len())?;
+                for error in errors {
+                    write!(f, "\n  {error}")?;
+                }
+                Ok(())
+            }
+            CrabError::BudgetExceeded {
+                fetches,
+                max_fetches,
+            } => write!(
+                f,
+                "Query made {fetches} page requests, over its budget of {max_fetches}"
+            ),
+            CrabError::ReadOnly => write!(f, "Table is opened read-only"),
+            CrabError::NotIndexed(column) => write!(f, "Column {column} has no index to order by"),
+            CrabError::TableInUse => write!(f, "Table has rows locked by running transactions"),
+            CrabError::StorageUnavailable(reason) => {
+                write!(f, "Table storage is unavailable: {reason}")
+            }
+            CrabError::QuiesceTimeout => write!(f, "Timed out waiting for background work"),
+            CrabError::InvalidSchema(reason) => write!(f, "Invalid column names: {reason}"),
+            CrabError::UnknownColumn(name) => write!(f, "No column named {name}"),
+            CrabError::SumOverflow(column) => write!(f, "Sum of column {column} overflows"),
+            CrabError::NotNullable(column) => write!(f, "Column {column} can't be NULL"),
+            CrabError::NoTimestamps => write!(f, "Table has no version timestamps"),
+            CrabError::TransactionAborted { retryable: true } => {
+                write!(f, "Query aborted on a lock conflict, it can be tried again")
+            }
+            CrabError::TransactionAborted { retryable: false } => write!(f, "Query aborted"),
+            CrabError::BufferPoolFull => write!(f, "Every bufferpool frame is pinned"),
+            CrabError::MergeFailed { range_id, reason } => {
+                write!(f, "Merge of page range {range_id} failed: {reason}")
+            }
+            CrabError::VersionUnavailable => write!(f, "Version no longer available"),
+            CrabError::DuplicateValue { column, value } => {
+                write!(f, "Column {column} holds {value} in more than one row")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CrabError {}
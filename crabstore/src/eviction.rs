@@ -0,0 +1,174 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+/*
+    Decides which frame a BufferPool reads the next page into. Hits are recorded from
+    many threads at once, through &self. Loads, forgotten frames and victims are
+    handled one at a time, while the bufferpool's page table is write locked.
+*/
+pub trait EvictionPolicy: Send + Sync + fmt::Debug {
+    /*
+        A page cached in frame was fetched again. Scan accesses, e.g. by a sequential
+        scan or the merge thread, shouldn't make the page look hot.
+    */
+    fn record_access(&self, frame: usize, scan: bool);
+
+    /*
+        A page was just read into frame.
+    */
+    fn record_load(&mut self, frame: usize, scan: bool);
+
+    /*
+        The frame's page was dropped, so the frame is the best victim there is.
+    */
+    fn forget(&mut self, frame: usize);
+
+    /*
+        The frame to evict next, out of those evictable says can go. None once every
+        frame is pinned.
+    */
+    fn pick_victim(&mut self, evictable: &dyn Fn(usize) -> bool) -> Option<usize>;
+}
+
+/*
+    Replacement policies a CrabConfig can pick for its tables' bufferpools.
+*/
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Eviction {
+    // See Clock.
+    #[default]
+    Clock,
+    // See LruK.
+    LruK,
+}
+
+impl Eviction {
+    pub fn policy(self, frames: usize) -> Box<dyn EvictionPolicy> {
+        match self {
+            Eviction::Clock => Box::new(Clock::new(frames)),
+            Eviction::LruK => Box::new(LruK::new(frames)),
+        }
+    }
+}
+
+/*
+    Second chance: a frame hit since the hand last passed it is skipped once.
+*/
+#[derive(Debug)]
+pub struct Clock {
+    refs: Vec<AtomicBool>,
+    hand: usize,
+}
+
+impl Clock {
+    pub fn new(frames: usize) -> Self {
+        Clock {
+            refs: (0..frames).map(|_| AtomicBool::new(false)).collect(),
+            hand: 0,
+        }
+    }
+}
+
+impl EvictionPolicy for Clock {
+    fn record_access(&self, frame: usize, scan: bool) {
+        if !scan {
+            self.refs[frame].store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn record_load(&mut self, frame: usize, scan: bool) {
+        self.refs[frame].store(!scan, Ordering::Relaxed);
+    }
+
+    fn forget(&mut self, frame: usize) {
+        self.refs[frame].store(false, Ordering::Relaxed);
+    }
+
+    /*
+        Two sweeps clear every reference bit, a frame still not evictable
+        after them is pinned, and so is every other
+    */
+    fn pick_victim(&mut self, evictable: &dyn Fn(usize) -> bool) -> Option<usize> {
+        let frames = self.refs.len();
+
+        for _ in 0..2 * frames {
+            let frame = self.hand;
+            self.hand = (self.hand + 1) % frames;
+
+            if !self.refs[frame].swap(false, Ordering::Relaxed) && evictable(frame) {
+                return Some(frame);
+            }
+        }
+
+        None
+    }
+}
+
+/*
+    LRU-K with K = 2: evicts the frame whose second to last access is oldest. Pages
+    accessed only once since they were loaded go first, oldest first, so a scan
+    passing through only ever evicts its own pages.
+*/
+#[derive(Debug)]
+pub struct LruK {
+    // Each frame's last two non-scan accesses, latest first. 0 is never
+    history: Vec<[AtomicU64; 2]>,
+    clock: AtomicU64,
+}
+
+impl LruK {
+    pub fn new(frames: usize) -> Self {
+        LruK {
+            history: (0..frames)
+                .map(|_| [AtomicU64::new(0), AtomicU64::new(0)])
+                .collect(),
+            clock: AtomicU64::new(0),
+        }
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+impl EvictionPolicy for LruK {
+    fn record_access(&self, frame: usize, scan: bool) {
+        if scan {
+            return;
+        }
+
+        let [last, second_last] = &self.history[frame];
+        second_last.store(last.swap(self.tick(), Ordering::Relaxed), Ordering::Relaxed);
+    }
+
+    /*
+        Loading counts as the first access either way, so a scanned page
+        still has an age to be evicted by
+    */
+    fn record_load(&mut self, frame: usize, _scan: bool) {
+        let now = self.tick();
+        let [last, second_last] = &self.history[frame];
+        last.store(now, Ordering::Relaxed);
+        second_last.store(0, Ordering::Relaxed);
+    }
+
+    fn forget(&mut self, frame: usize) {
+        for access in &self.history[frame] {
+            access.store(0, Ordering::Relaxed);
+        }
+    }
+
+    fn pick_victim(&mut self, evictable: &dyn Fn(usize) -> bool) -> Option<usize> {
+        (0..self.history.len())
+            .filter(|frame| evictable(*frame))
+            .min_by_key(|frame| {
+                let [last, second_last] = &self.history[*frame];
+                (
+                    second_last.load(Ordering::Relaxed),
+                    last.load(Ordering::Relaxed),
+                )
+            })
+    }
+}
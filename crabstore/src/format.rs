@@ -0,0 +1,1164 @@
+/*
+    Every on-disk layout lives here. Each file starts with its own magic and the
+    format version that wrote it, followed by a little-endian payload written
+    field by field and the checksum trailer from archive. Readers accept every
+    version up to FORMAT_VERSION, so older databases keep opening; files without
+    a magic predate versioning and go through the legacy readers below.
+
+    Changing a layout means bumping FORMAT_VERSION, decoding the old version
+    alongside the new one, and regenerating the fixture with write_fixture
+    (see the fixture test in tests/format_test.rs).
+
+    Column pages are raw slots rather than encoded files. From version 2 they
+    are little-endian too, and the table header says so; before that they were
+    in the byte order of whichever machine wrote them, see PageOrder. From
+    version 3 the header also records how new column pages are placed, see
+    PageLayout. Version 4 headers end with the column names, if the table has
+    them, so they are the one header of variable size. Version 5 adds which
+    columns are signed, see ColumnType, and version 6 whether the table's pages
+    carry a column of NULL masks, see CrabStore::create_table_nullable. Version
+    7 adds whether they carry a column of version timestamps, and the clock
+    those are taken from, see Table::current_timestamp. Version 8 adds the runs
+    of pages merges gave back, see DiskManager::free_page, and version 9 the
+    page size and how many base pages make up a page range, see
+    CrabConfig::page_range_pages. Version 10 adds to each page range the
+    newest of its tail pages a checkpoint reclaimed, see
+    MergeConfig::retained_tail_pages, and version 11 which indexes are
+    unique, see Table::build_unique_index. Version 12 index files end with
+    the composite indexes, see Table::build_composite_index.
+
+    There is no write-ahead log yet; its record layout belongs here too once
+    one exists.
+*/
+
+use std::{io, path::Path};
+
+use crate::{
+    archive,
+    config::CrabConfig,
+    crabstore::CrabStore,
+    disk_manager::DiskManager,
+    error::CrabError,
+    page::PhysicalPage,
+    schema::{ColumnType, SignedColumns},
+    transaction::{Query, Transaction},
+    PAGE_RANGE_COUNT, PAGE_SIZE, PAGE_SLOTS, RID_INVALID,
+};
+
+pub const FORMAT_VERSION: u32 = 12;
+
+const TABLE_LIST_MAGIC: [u8; 8] = *b"CRABTBL\0";
+const HEADER_MAGIC: [u8; 8] = *b"CRABHDR\0";
+const PAGE_DIRECTORY_MAGIC: [u8; 8] = *b"CRABPDR\0";
+const RANGE_DIRECTORY_MAGIC: [u8; 8] = *b"CRABRNG\0";
+const INDEX_MAGIC: [u8; 8] = *b"CRABIDX\0";
+const STATS_MAGIC: [u8; 8] = *b"CRABSTA\0";
+
+// magic, version and a reserved word
+const PREAMBLE_SIZE: usize = 16;
+
+// The only page order version 2 headers record
+const PAGE_ORDER_LITTLE_ENDIAN: u64 = 1;
+
+const PAGE_LAYOUT_INTERLEAVED: u64 = 0;
+const PAGE_LAYOUT_COLUMNAR: u64 = 1;
+
+/*
+    Table names in crab_dt.CRAB
+*/
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TableList {
+    pub names: Vec<String>,
+}
+
+/*
+    Byte order of the slots in a table file's column pages
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageOrder {
+    LittleEndian,
+    // Written before version 2, in the writing machine's order, see written_big_endian
+    WriterNative,
+}
+
+/*
+    Where a table puts the column pages it allocates in its file. The page
+    directory maps every page explicitly, so this only decides what gets
+    allocated next, never how existing pages are found.
+*/
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PageLayout {
+    // Every column of a logical page together, one page after the other
+    #[default]
+    Interleaved,
+    /*
+        Each column of a page range together, metadata columns first, so a
+        scan of a few columns reads runs of consecutive pages
+    */
+    Columnar,
+}
+
+impl PageLayout {
+    /*
+        Page id of one column of the page-th of pages logical pages, whose
+        columns were reserved together starting at reserved
+    */
+    pub(crate) fn place(
+        self,
+        reserved: usize,
+        columns: usize,
+        pages: usize,
+        page: usize,
+        column: usize,
+    ) -> usize {
+        match self {
+            PageLayout::Interleaved => reserved + page * columns + column,
+            PageLayout::Columnar => reserved + column * pages + page,
+        }
+    }
+}
+
+/*
+    Page 0 of each table file
+*/
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TableHeaderPage {
+    pub(crate) num_columns: usize,
+    pub(crate) primary_key_index: usize,
+    pub(crate) next_free_page: usize,
+    pub(crate) next_rid: u64,
+    pub(crate) next_tid: u64,
+    // bit i set if column i has an index
+    pub(crate) indexed_columns: u64,
+    // bit i set if column i's index is unique, none before version 11
+    pub(crate) unique_columns: u64,
+    pub(crate) page_order: PageOrder,
+    pub(crate) page_layout: PageLayout,
+    // Empty for a table created without names
+    pub(crate) column_names: Vec<String>,
+    pub(crate) signed_columns: SignedColumns,
+    // Tables written before version 6 never are
+    pub(crate) nullable: bool,
+    // Tables written before version 7 never have them
+    pub(crate) timestamps: bool,
+    // Last timestamp handed out
+    pub(crate) clock: u64,
+    // Disk pages nothing uses, as (first page, count), see DiskManager::free_runs
+    pub(crate) free_runs: Vec<(usize, usize)>,
+    // Bytes per page, a table only opens in a build with the same PAGE_SIZE
+    pub(crate) page_size: usize,
+    // Base pages per page range, PAGE_RANGE_COUNT before version 9
+    pub(crate) range_pages: usize,
+}
+
+/*
+    Logical page -> column pages on disk, grouped the way the directory shards them
+*/
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PageDirectorySnapshot {
+    pub shards: Vec<Vec<(usize, Vec<usize>)>>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RangeEntry {
+    pub next_tid: u64,
+    pub current_tail_page: usize,
+    pub merged_until: usize,
+    // RID_INVALID if none, and before version 10
+    pub reclaimed_from: usize,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RangeDirectorySnapshot {
+    pub ranges: Vec<RangeEntry>,
+}
+
+/*
+    Per column, None if unindexed, otherwise each value with the base RIDs holding it.
+    Then each composite index's columns, with the values of those columns and the
+    base RIDs holding them, none before version 12.
+*/
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IndexSnapshot {
+    pub columns: Vec<Option<ColumnEntries>>,
+    pub composites: Vec<(Vec<usize>, CompositeEntries)>,
+}
+
+// Values of an indexed column, with the base RIDs holding them
+pub type ColumnEntries = Vec<(u64, Vec<u64>)>;
+
+// Values of a composite index's columns, with the base RIDs holding them
+pub type CompositeEntries = Vec<(Vec<u64>, Vec<u64>)>;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ColumnSketchEntry {
+    pub registers: Vec<u8>,
+    pub min: u64,
+    pub max: u64,
+}
+
+/*
+    Column statistics, rewritten with the header at every checkpoint
+*/
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ColumnStatsSnapshot {
+    pub rows: u64,
+    pub columns: Vec<ColumnSketchEntry>,
+}
+
+struct Encoder {
+    bytes: Vec<u8>,
+}
+
+impl Encoder {
+    fn new(magic: [u8; 8]) -> Self {
+        let mut bytes = magic.to_vec();
+        bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        Encoder { bytes }
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn usize(&mut self, value: usize) {
+        self.u64(value as u64);
+    }
+
+    fn bytes(&mut self, value: &[u8]) {
+        self.usize(value.len());
+        self.bytes.extend_from_slice(value);
+    }
+
+    fn str(&mut self, value: &str) {
+        self.bytes(value.as_bytes());
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let trailer = archive::seal(&self.bytes);
+        self.bytes.extend_from_slice(&trailer);
+        self.bytes
+    }
+}
+
+fn check_version(path: &Path, version: u32) -> Result<(), CrabError> {
+    if version == 0 || version > FORMAT_VERSION {
+        return Err(archive::corrupt(
+            path,
+            format!("format version {version}, this build reads up to {FORMAT_VERSION}"),
+        ));
+    }
+
+    Ok(())
+}
+
+struct Decoder<'a> {
+    path: &'a Path,
+    version: u32,
+    bytes: Vec<u8>,
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    /*
+        None if the file doesn't start with magic, i.e. was written before versioning
+    */
+    fn open(path: &'a Path, magic: [u8; 8], bytes: &[u8]) -> Result<Option<Self>, CrabError> {
+        if !bytes.starts_with(&magic) {
+            return Ok(None);
+        }
+
+        let bytes = archive::unseal(path, bytes)?.to_vec();
+
+        if bytes.len() < PREAMBLE_SIZE {
+            return Err(archive::corrupt(path, "truncated preamble"));
+        }
+
+        let version = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+        check_version(path, version)?;
+
+        Ok(Some(Decoder {
+            path,
+            version,
+            bytes,
+            pos: PREAMBLE_SIZE,
+        }))
+    }
+
+    fn u64(&mut self) -> Result<u64, CrabError> {
+        let Some(value) = self.bytes.get(self.pos..self.pos + 8) else {
+            return Err(archive::corrupt(self.path, "truncated payload"));
+        };
+
+        self.pos += 8;
+        Ok(u64::from_le_bytes(value.try_into().unwrap()))
+    }
+
+    fn usize(&mut self) -> Result<usize, CrabError> {
+        Ok(self.u64()? as usize)
+    }
+
+    /*
+        A length prefix, checked against what is left so a bad one can't trigger a huge allocation
+    */
+    fn len(&mut self, min_item_size: usize) -> Result<usize, CrabError> {
+        let len = self.usize()?;
+
+        if len.saturating_mul(min_item_size) > self.bytes.len() - self.pos {
+            return Err(archive::corrupt(
+                self.path,
+                format!("length {len} runs past the end of the file"),
+            ));
+        }
+
+        Ok(len)
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, CrabError> {
+        let len = self.len(1)?;
+        let value = self.bytes[self.pos..self.pos + len].to_vec();
+
+        self.pos += len;
+        Ok(value)
+    }
+
+    fn str(&mut self) -> Result<String, CrabError> {
+        String::from_utf8(self.bytes()?).map_err(|err| archive::corrupt(self.path, err))
+    }
+
+    fn finish<T>(self, value: T) -> Result<T, CrabError> {
+        if self.pos != self.bytes.len() {
+            return Err(archive::corrupt(self.path, "trailing bytes after payload"));
+        }
+
+        Ok(value)
+    }
+}
+
+impl TableList {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new(TABLE_LIST_MAGIC);
+        encoder.usize(self.names.len());
+        for name in &self.names {
+            encoder.str(name);
+        }
+        encoder.finish()
+    }
+
+    pub(crate) fn decode(path: &Path, bytes: &[u8]) -> Result<Self, CrabError> {
+        let Some(mut decoder) = Decoder::open(path, TABLE_LIST_MAGIC, bytes)? else {
+            return legacy::table_list(path, bytes);
+        };
+
+        let names = (0..decoder.len(8)?)
+            .map(|_| decoder.str())
+            .collect::<Result<_, _>>()?;
+
+        decoder.finish(TableList { names })
+    }
+}
+
+impl TableHeaderPage {
+    /*
+        Version 2 added the page order, version 3 the page layout, version 4 the
+        byte size of the column names that follow the fixed fields, version 5 the
+        signed columns, version 6 whether the table is nullable, version 7
+        whether it has timestamps and its clock, version 8 how many free page
+        runs follow the names, and version 9 the page size and pages per range.
+        A size past the end of the page is cut short there, and fails the
+        checksum instead.
+    */
+    fn encoded_size(page: &[u8; PAGE_SIZE]) -> usize {
+        let version = u32::from_le_bytes(page[8..12].try_into().unwrap());
+        let fields = match version {
+            ..=1 => 6,
+            2 => 7,
+            3 => 8,
+            4 => 9,
+            5 => 10,
+            6 => 11,
+            7 => 13,
+            8 => 14,
+            9 | 10 => 16,
+            _ => 17,
+        };
+        let mut size = PREAMBLE_SIZE + fields * 8 + archive::TRAILER_SIZE;
+
+        if version >= 4 {
+            let names_at = PREAMBLE_SIZE + 8 * 8;
+            let names_size = u64::from_le_bytes(page[names_at..names_at + 8].try_into().unwrap());
+            size = size.saturating_add(names_size as usize);
+        }
+
+        if version >= 8 {
+            let runs_at = PREAMBLE_SIZE + 13 * 8;
+            let runs = u64::from_le_bytes(page[runs_at..runs_at + 8].try_into().unwrap());
+            size = size.saturating_add((runs as usize).saturating_mul(16));
+        }
+
+        size.min(PAGE_SIZE)
+    }
+
+    /*
+        Bytes the column names take in the header, their count included
+    */
+    fn names_size(column_names: &[String]) -> usize {
+        8 + column_names
+            .iter()
+            .map(|name| 8 + name.len())
+            .sum::<usize>()
+    }
+
+    /*
+        Whether a header with these column names still fits in its page
+    */
+    pub(crate) fn names_fit(column_names: &[String]) -> bool {
+        PREAMBLE_SIZE + 17 * 8 + Self::names_size(column_names) + archive::TRAILER_SIZE <= PAGE_SIZE
+    }
+
+    pub(crate) fn encode(&self) -> [u8; PAGE_SIZE] {
+        let mut encoder = Encoder::new(HEADER_MAGIC);
+        encoder.usize(self.num_columns);
+        encoder.usize(self.primary_key_index);
+        encoder.usize(self.next_free_page);
+        encoder.u64(self.next_rid);
+        encoder.u64(self.next_tid);
+        encoder.u64(self.indexed_columns);
+        // Whatever order the pages were read in, they are written back little-endian
+        encoder.u64(PAGE_ORDER_LITTLE_ENDIAN);
+        encoder.u64(match self.page_layout {
+            PageLayout::Interleaved => PAGE_LAYOUT_INTERLEAVED,
+            PageLayout::Columnar => PAGE_LAYOUT_COLUMNAR,
+        });
+        encoder.usize(Self::names_size(&self.column_names));
+        encoder.u64(self.signed_columns.0);
+        encoder.u64(self.nullable as u64);
+        encoder.u64(self.timestamps as u64);
+        encoder.u64(self.clock);
+
+        // Whatever runs don't fit after the names are left unused for good
+        let room = PAGE_SIZE
+            - PREAMBLE_SIZE
+            - 17 * 8
+            - Self::names_size(&self.column_names)
+            - archive::TRAILER_SIZE;
+        let free_runs = &self.free_runs[..self.free_runs.len().min(room / 16)];
+        encoder.usize(free_runs.len());
+        encoder.usize(self.page_size);
+        encoder.usize(self.range_pages);
+        encoder.u64(self.unique_columns);
+
+        encoder.usize(self.column_names.len());
+        for name in &self.column_names {
+            encoder.str(name);
+        }
+        for (start, count) in free_runs {
+            encoder.usize(*start);
+            encoder.usize(*count);
+        }
+
+        let bytes = encoder.finish();
+        let mut page = [0; PAGE_SIZE];
+        page[..bytes.len()].copy_from_slice(&bytes);
+        page
+    }
+
+    pub(crate) fn decode(path: &Path, page: &[u8; PAGE_SIZE]) -> Result<Self, CrabError> {
+        // The trailer follows the payload, so how much to unseal depends on the version
+        let header = match Decoder::open(path, HEADER_MAGIC, &page[..Self::encoded_size(page)])? {
+            Some(mut decoder) => {
+                let mut header = TableHeaderPage {
+                    num_columns: decoder.usize()?,
+                    primary_key_index: decoder.usize()?,
+                    next_free_page: decoder.usize()?,
+                    next_rid: decoder.u64()?,
+                    next_tid: decoder.u64()?,
+                    indexed_columns: decoder.u64()?,
+                    unique_columns: 0,
+                    page_order: PageOrder::WriterNative,
+                    page_layout: PageLayout::Interleaved,
+                    column_names: Vec::new(),
+                    signed_columns: SignedColumns::default(),
+                    nullable: false,
+                    timestamps: false,
+                    clock: 0,
+                    free_runs: Vec::new(),
+                    page_size: PAGE_SIZE,
+                    range_pages: PAGE_RANGE_COUNT,
+                };
+
+                if decoder.version >= 2 {
+                    match decoder.u64()? {
+                        PAGE_ORDER_LITTLE_ENDIAN => header.page_order = PageOrder::LittleEndian,
+                        order => {
+                            return Err(archive::corrupt(path, format!("bad page order {order}")))
+                        }
+                    }
+                }
+
+                if decoder.version >= 3 {
+                    header.page_layout = match decoder.u64()? {
+                        PAGE_LAYOUT_INTERLEAVED => PageLayout::Interleaved,
+                        PAGE_LAYOUT_COLUMNAR => PageLayout::Columnar,
+                        layout => {
+                            return Err(archive::corrupt(path, format!("bad page layout {layout}")))
+                        }
+                    };
+                }
+
+                if decoder.version >= 4 {
+                    // Only needed to find the trailer, see encoded_size
+                    decoder.usize()?;
+                    if decoder.version >= 5 {
+                        header.signed_columns = SignedColumns(decoder.u64()?);
+                    }
+                    if decoder.version >= 6 {
+                        header.nullable = match decoder.u64()? {
+                            0 => false,
+                            1 => true,
+                            nullable => {
+                                return Err(archive::corrupt(
+                                    path,
+                                    format!("bad nullable flag {nullable}"),
+                                ))
+                            }
+                        };
+                    }
+                    if decoder.version >= 7 {
+                        header.timestamps = match decoder.u64()? {
+                            0 => false,
+                            1 => true,
+                            timestamps => {
+                                return Err(archive::corrupt(
+                                    path,
+                                    format!("bad timestamps flag {timestamps}"),
+                                ))
+                            }
+                        };
+                        header.clock = decoder.u64()?;
+                    }
+                    let free_runs = match decoder.version {
+                        8.. => decoder.len(16)?,
+                        _ => 0,
+                    };
+                    if decoder.version >= 9 {
+                        header.page_size = decoder.usize()?;
+                        header.range_pages = decoder.usize()?;
+                    }
+                    if decoder.version >= 11 {
+                        header.unique_columns = decoder.u64()?;
+                    }
+                    header.column_names = (0..decoder.len(8)?)
+                        .map(|_| decoder.str())
+                        .collect::<Result<_, _>>()?;
+                    header.free_runs = (0..free_runs)
+                        .map(|_| Ok((decoder.usize()?, decoder.usize()?)))
+                        .collect::<Result<_, CrabError>>()?;
+                }
+
+                decoder.finish(header)?
+            }
+            None => legacy::table_header(path, page)?,
+        };
+
+        if header.num_columns == 0 || header.num_columns > u64::BITS as usize {
+            return Err(archive::corrupt(
+                path,
+                format!("header declares {} columns", header.num_columns),
+            ));
+        }
+
+        if header.page_size != PAGE_SIZE {
+            return Err(archive::corrupt(
+                path,
+                format!(
+                    "written with {} byte pages, not {PAGE_SIZE}",
+                    header.page_size
+                ),
+            ));
+        }
+
+        if header.range_pages == 0 {
+            return Err(archive::corrupt(path, "page ranges of 0 pages"));
+        }
+
+        if header.primary_key_index >= header.num_columns {
+            return Err(archive::corrupt(
+                path,
+                format!("primary key {} out of range", header.primary_key_index),
+            ));
+        }
+
+        // Page 0 is the header's own
+        let past_end = |(start, count): &(usize, usize)| !matches!(start.checked_add(*count), Some(end) if *start != 0 && end <= header.next_free_page);
+        if let Some((start, count)) = header.free_runs.iter().find(|run| past_end(run)) {
+            return Err(archive::corrupt(
+                path,
+                format!(
+                    "free pages {start}..{} outside the file's {} pages",
+                    start.saturating_add(*count),
+                    header.next_free_page
+                ),
+            ));
+        }
+
+        if !header.column_names.is_empty() && header.column_names.len() != header.num_columns {
+            return Err(archive::corrupt(
+                path,
+                format!(
+                    "{} column names for {} columns",
+                    header.column_names.len(),
+                    header.num_columns
+                ),
+            ));
+        }
+
+        Ok(header)
+    }
+
+    /*
+        Page 0 of the table file at path, or the copy at copy_path if page 0 was
+        torn while being rewritten. A torn write leaves the page its full size, so
+        a file cut shorter than that has lost more than its header and is corrupt
+        whatever the copy says. Without a good copy the error is page 0's.
+    */
+    pub(crate) fn read(
+        disk: &DiskManager,
+        path: &Path,
+        copy_path: &Path,
+    ) -> Result<Self, CrabError> {
+        let mut page = PhysicalPage::default();
+        let read = disk
+            .read_page(0, &mut page.page)
+            .map_err(|err| archive::unavailable(path, err))?;
+
+        let err = match TableHeaderPage::decode(path, &page.page) {
+            Ok(header) => return Ok(header),
+            Err(err) if read < PAGE_SIZE => return Err(err),
+            Err(err) => err,
+        };
+
+        let copy = match std::fs::read(copy_path) {
+            Ok(bytes) => bytes,
+            Err(_) => return Err(err),
+        };
+        match copy.as_slice().try_into() {
+            Ok(copy) => TableHeaderPage::decode(copy_path, copy).map_err(|_| err),
+            Err(_) => Err(err),
+        }
+    }
+
+    /*
+        The copy goes first, renamed into place whole, so whichever of the two a
+        crash interrupts the other still holds a good header
+    */
+    pub(crate) fn write(&self, disk: &DiskManager, copy_path: &Path) -> io::Result<()> {
+        let page = self.encode();
+        archive::write_file(copy_path, &page).map_err(|err| io::Error::other(err.to_string()))?;
+        disk.write_page(0, &page)?;
+        disk.flush()
+    }
+}
+
+/*
+    Whether a table file written before version 2 came from a big-endian machine.
+    base_rid_page is the base RID column of logical page 0, if there is one. Every
+    slot ever written there holds its own RID, deletes included, so slot 1 reads
+    as 1 in the order it was written and as 1 byte-swapped in the other. With
+    fewer than two rows there is no telling, and the pages are taken to be
+    little-endian like most machines'.
+*/
+pub(crate) fn written_big_endian(
+    disk: &DiskManager,
+    base_rid_page: Option<usize>,
+) -> io::Result<bool> {
+    let Some(base_rid_page) = base_rid_page else {
+        return Ok(false);
+    };
+
+    let mut page = PhysicalPage::default();
+    disk.read_page(base_rid_page, &mut page.page)?;
+    Ok(page.slot(1) == 1u64.swap_bytes())
+}
+
+/*
+    Byte-swaps every slot of every page after the header, in place. Pages that
+    were reserved but never written are left alone.
+*/
+pub(crate) fn swap_page_order(disk: &DiskManager, next_free_page: usize) -> io::Result<()> {
+    let mut page = PhysicalPage::default();
+
+    for page_id in 1..next_free_page {
+        if disk.read_page(page_id, &mut page.page)? < PAGE_SIZE {
+            continue;
+        }
+
+        for slot in 0..PAGE_SLOTS {
+            page.write_slot(slot, page.slot(slot).swap_bytes());
+        }
+        disk.write_page(page_id, &page.page)?;
+    }
+
+    disk.flush()
+}
+
+impl PageDirectorySnapshot {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new(PAGE_DIRECTORY_MAGIC);
+        encoder.usize(self.shards.len());
+
+        for shard in &self.shards {
+            encoder.usize(shard.len());
+
+            for (page, cols) in shard {
+                encoder.usize(*page);
+                encoder.usize(cols.len());
+                cols.iter().for_each(|col| encoder.usize(*col));
+            }
+        }
+
+        encoder.finish()
+    }
+
+    pub(crate) fn decode(path: &Path, bytes: &[u8]) -> Result<Self, CrabError> {
+        let Some(mut decoder) = Decoder::open(path, PAGE_DIRECTORY_MAGIC, bytes)? else {
+            return legacy::page_directory(path, bytes);
+        };
+
+        let mut shards = Vec::new();
+
+        for _ in 0..decoder.len(8)? {
+            let mut shard = Vec::new();
+
+            for _ in 0..decoder.len(16)? {
+                let page = decoder.usize()?;
+                let cols = (0..decoder.len(8)?)
+                    .map(|_| decoder.usize())
+                    .collect::<Result<_, _>>()?;
+
+                shard.push((page, cols));
+            }
+
+            shards.push(shard);
+        }
+
+        decoder.finish(PageDirectorySnapshot { shards })
+    }
+}
+
+impl RangeDirectorySnapshot {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new(RANGE_DIRECTORY_MAGIC);
+        encoder.usize(self.ranges.len());
+
+        for range in &self.ranges {
+            encoder.u64(range.next_tid);
+            encoder.usize(range.current_tail_page);
+            encoder.usize(range.merged_until);
+            encoder.usize(range.reclaimed_from);
+        }
+
+        encoder.finish()
+    }
+
+    pub(crate) fn decode(path: &Path, bytes: &[u8]) -> Result<Self, CrabError> {
+        let Some(mut decoder) = Decoder::open(path, RANGE_DIRECTORY_MAGIC, bytes)? else {
+            return legacy::range_directory(path, bytes);
+        };
+
+        let ranges = (0..decoder.len(24)?)
+            .map(|_| {
+                Ok(RangeEntry {
+                    next_tid: decoder.u64()?,
+                    current_tail_page: decoder.usize()?,
+                    merged_until: decoder.usize()?,
+                    reclaimed_from: match decoder.version {
+                        ..=9 => RID_INVALID as usize,
+                        _ => decoder.usize()?,
+                    },
+                })
+            })
+            .collect::<Result<_, CrabError>>()?;
+
+        decoder.finish(RangeDirectorySnapshot { ranges })
+    }
+}
+
+impl IndexSnapshot {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new(INDEX_MAGIC);
+        encoder.usize(self.columns.len());
+
+        for column in &self.columns {
+            let Some(entries) = column else {
+                encoder.u64(0);
+                continue;
+            };
+
+            encoder.u64(1);
+            encoder.usize(entries.len());
+
+            for (value, rids) in entries {
+                encoder.u64(*value);
+                encoder.usize(rids.len());
+                rids.iter().for_each(|rid| encoder.u64(*rid));
+            }
+        }
+
+        encoder.usize(self.composites.len());
+        for (columns, entries) in &self.composites {
+            encoder.usize(columns.len());
+            columns.iter().for_each(|column| encoder.usize(*column));
+            encoder.usize(entries.len());
+
+            for (values, rids) in entries {
+                values.iter().for_each(|value| encoder.u64(*value));
+                encoder.usize(rids.len());
+                rids.iter().for_each(|rid| encoder.u64(*rid));
+            }
+        }
+
+        encoder.finish()
+    }
+
+    pub(crate) fn decode(path: &Path, bytes: &[u8]) -> Result<Self, CrabError> {
+        let Some(mut decoder) = Decoder::open(path, INDEX_MAGIC, bytes)? else {
+            return legacy::index(path, bytes);
+        };
+
+        let mut columns = Vec::new();
+
+        for _ in 0..decoder.len(8)? {
+            let column = match decoder.u64()? {
+                0 => None,
+                1 => {
+                    let mut entries = Vec::new();
+
+                    for _ in 0..decoder.len(16)? {
+                        let value = decoder.u64()?;
+                        let rids = (0..decoder.len(8)?)
+                            .map(|_| decoder.u64())
+                            .collect::<Result<_, _>>()?;
+
+                        entries.push((value, rids));
+                    }
+
+                    Some(entries)
+                }
+                tag => {
+                    return Err(archive::corrupt(
+                        path,
+                        format!("bad index column tag {tag}"),
+                    ));
+                }
+            };
+
+            columns.push(column);
+        }
+
+        let mut composites = Vec::new();
+        if decoder.version >= 12 {
+            for _ in 0..decoder.len(16)? {
+                let columns = (0..decoder.len(8)?)
+                    .map(|_| decoder.usize())
+                    .collect::<Result<Vec<_>, _>>()?;
+                let mut entries = Vec::new();
+
+                for _ in 0..decoder.len(8 * (columns.len() + 1))? {
+                    let values = (0..columns.len())
+                        .map(|_| decoder.u64())
+                        .collect::<Result<_, _>>()?;
+                    let rids = (0..decoder.len(8)?)
+                        .map(|_| decoder.u64())
+                        .collect::<Result<_, _>>()?;
+
+                    entries.push((values, rids));
+                }
+
+                composites.push((columns, entries));
+            }
+        }
+
+        decoder.finish(IndexSnapshot {
+            columns,
+            composites,
+        })
+    }
+}
+
+impl ColumnStatsSnapshot {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new(STATS_MAGIC);
+        encoder.u64(self.rows);
+        encoder.usize(self.columns.len());
+
+        for column in &self.columns {
+            encoder.bytes(&column.registers);
+            encoder.u64(column.min);
+            encoder.u64(column.max);
+        }
+
+        encoder.finish()
+    }
+
+    /*
+        Statistics came after versioning, so there is no legacy layout
+    */
+    pub(crate) fn decode(path: &Path, bytes: &[u8]) -> Result<Self, CrabError> {
+        let Some(mut decoder) = Decoder::open(path, STATS_MAGIC, bytes)? else {
+            return Err(archive::corrupt(path, "not a statistics file"));
+        };
+
+        let rows = decoder.u64()?;
+        let columns = (0..decoder.len(24)?)
+            .map(|_| {
+                Ok(ColumnSketchEntry {
+                    registers: decoder.bytes()?,
+                    min: decoder.u64()?,
+                    max: decoder.u64()?,
+                })
+            })
+            .collect::<Result<_, CrabError>>()?;
+
+        decoder.finish(ColumnStatsSnapshot { rows, columns })
+    }
+}
+
+/*
+    Readers for the files written before FORMAT_VERSION 1, which were rkyv
+    archives of the in-memory types followed by the archive trailer. The types
+    here mirror those in-memory types so the archived layouts match. rkyv
+    archives in the host's byte order, so these only read on little-endian
+    machines, the only ones such files were ever written on.
+*/
+mod legacy {
+    use std::{
+        collections::BTreeMap,
+        mem::size_of,
+        path::Path,
+        sync::{
+            atomic::{AtomicU64, AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use bytecheck::CheckBytes;
+    use rkyv::{Archive, Deserialize, Serialize};
+    use rustc_hash::FxHashMap;
+
+    use super::{
+        IndexSnapshot, PageDirectorySnapshot, PageLayout, PageOrder, RangeDirectorySnapshot,
+        RangeEntry, SignedColumns, TableHeaderPage, TableList,
+    };
+    use crate::{archive, error::CrabError, PAGE_RANGE_COUNT, PAGE_SIZE, RID_INVALID};
+
+    // Sharded page directories briefly had their own unversioned layout
+    const SHARDED_MAGIC: [u8; 8] = *b"CRABPDS1";
+
+    #[derive(Archive, Serialize, Deserialize)]
+    #[archive_attr(derive(CheckBytes))]
+    pub(super) struct Header {
+        num_columns: usize,
+        primary_key_index: usize,
+        next_free_page: usize,
+        next_rid: u64,
+        next_tid: u64,
+        indexed_columns: u64,
+    }
+
+    #[derive(Archive, Serialize, Deserialize)]
+    #[archive_attr(derive(CheckBytes))]
+    pub(super) struct Range {
+        next_tid: AtomicU64,
+        current_tail_page: AtomicUsize,
+        merged_until: AtomicUsize,
+    }
+
+    #[derive(Archive, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+    #[archive_attr(derive(CheckBytes))]
+    pub(super) struct Rid(u64);
+
+    pub(super) fn table_list(path: &Path, bytes: &[u8]) -> Result<TableList, CrabError> {
+        Ok(TableList {
+            names: archive::from_bytes(path, bytes)?,
+        })
+    }
+
+    pub(super) fn table_header(
+        path: &Path,
+        page: &[u8; PAGE_SIZE],
+    ) -> Result<TableHeaderPage, CrabError> {
+        let size = size_of::<<Header as Archive>::Archived>();
+        let header: Header = archive::from_bytes(path, &page[..size + archive::TRAILER_SIZE])?;
+
+        Ok(TableHeaderPage {
+            num_columns: header.num_columns,
+            primary_key_index: header.primary_key_index,
+            next_free_page: header.next_free_page,
+            next_rid: header.next_rid,
+            next_tid: header.next_tid,
+            indexed_columns: header.indexed_columns,
+            unique_columns: 0,
+            page_order: PageOrder::WriterNative,
+            page_layout: PageLayout::Interleaved,
+            column_names: Vec::new(),
+            signed_columns: SignedColumns::default(),
+            nullable: false,
+            timestamps: false,
+            clock: 0,
+            free_runs: Vec::new(),
+            page_size: PAGE_SIZE,
+            range_pages: PAGE_RANGE_COUNT,
+        })
+    }
+
+    type Shard = FxHashMap<usize, Arc<[usize]>>;
+
+    fn shard_entries(shard: Shard) -> Vec<(usize, Vec<usize>)> {
+        shard
+            .into_iter()
+            .map(|(page, cols)| (page, cols.to_vec()))
+            .collect()
+    }
+
+    pub(super) fn page_directory(
+        path: &Path,
+        bytes: &[u8],
+    ) -> Result<PageDirectorySnapshot, CrabError> {
+        if !bytes.starts_with(&SHARDED_MAGIC) {
+            let single: Shard = archive::from_bytes(path, bytes)?;
+
+            return Ok(PageDirectorySnapshot {
+                shards: vec![shard_entries(single)],
+            });
+        }
+
+        // Shard count, then each shard's length and sealed archive
+        let mut rest = &bytes[SHARDED_MAGIC.len()..];
+        let read_u64 = |rest: &mut &[u8]| -> Result<u64, CrabError> {
+            if rest.len() < 8 {
+                return Err(archive::corrupt(path, "truncated shard header"));
+            }
+
+            let (value, tail) = rest.split_at(8);
+            *rest = tail;
+            Ok(u64::from_le_bytes(value.try_into().unwrap()))
+        };
+
+        let count = read_u64(&mut rest)?;
+        let mut shards = Vec::new();
+
+        for _ in 0..count {
+            let len = read_u64(&mut rest)? as usize;
+
+            if rest.len() < len {
+                return Err(archive::corrupt(path, "truncated shard"));
+            }
+
+            let (shard, tail) = rest.split_at(len);
+            shards.push(shard_entries(archive::from_bytes(path, shard)?));
+            rest = tail;
+        }
+
+        if !rest.is_empty() {
+            return Err(archive::corrupt(path, "trailing bytes after last shard"));
+        }
+
+        Ok(PageDirectorySnapshot { shards })
+    }
+
+    pub(super) fn range_directory(
+        path: &Path,
+        bytes: &[u8],
+    ) -> Result<RangeDirectorySnapshot, CrabError> {
+        let ranges: Vec<Range> = archive::from_bytes(path, bytes)?;
+
+        Ok(RangeDirectorySnapshot {
+            ranges: ranges
+                .into_iter()
+                .map(|range| RangeEntry {
+                    next_tid: range.next_tid.load(Ordering::Relaxed),
+                    current_tail_page: range.current_tail_page.load(Ordering::Relaxed),
+                    merged_until: range.merged_until.load(Ordering::Relaxed),
+                    reclaimed_from: RID_INVALID as usize,
+                })
+                .collect(),
+        })
+    }
+
+    pub(super) fn index(path: &Path, bytes: &[u8]) -> Result<IndexSnapshot, CrabError> {
+        let columns: Vec<Option<BTreeMap<u64, Vec<Rid>>>> = archive::from_bytes(path, bytes)?;
+
+        Ok(IndexSnapshot {
+            columns: columns
+                .into_iter()
+                .map(|column| {
+                    column.map(|map| {
+                        map.into_iter()
+                            .map(|(value, rids)| (value, rids.into_iter().map(|r| r.0).collect()))
+                            .collect()
+                    })
+                })
+                .collect(),
+            composites: Vec::new(),
+        })
+    }
+}
+
+/*
+    Builds the small canonical database the compatibility test opens: one table
+    with a secondary index and a unique one, inserts spanning two base pages,
+    updates, a delete and a couple of NULLs. Base pages are allocated one at a
+    time, so the file only holds the two the rows use rather than a whole range.
+*/
+pub fn write_fixture(dir: &Path) {
+    let mut crabstore = CrabStore::with_config(
+        dir.into(),
+        CrabConfig {
+            base_page_batch: 1,
+            ..Default::default()
+        },
+    );
+    crabstore.open().expect("Failed to open fixture directory");
+
+    let table = crabstore
+        .create_table_nullable(
+            "Fixture",
+            vec![
+                ColumnType::Unsigned,
+                ColumnType::Unsigned,
+                ColumnType::Signed,
+            ],
+            vec!["key".into(), "grade".into(), "score".into()],
+            0,
+        )
+        .unwrap();
+    table.build_index(1);
+    table.build_unique_index(2).unwrap();
+    table.build_composite_index(&[1, 2]);
+
+    for key in 0..600 {
+        table.insert_query(&[key, key % 7, key * 3], None).unwrap();
+    }
+
+    for key in (0..600).step_by(5) {
+        table
+            .update_query(key, &[None, None, Some(key * 3 + 1)], None)
+            .unwrap();
+    }
+
+    table
+        .update_query_nullable(3, &[None, Some(None), None], None)
+        .unwrap();
+    table
+        .insert_query_nullable(&[Some(600), Some(1), None], None)
+        .unwrap();
+
+    let mut transaction = Transaction::new();
+    transaction.add_query(Query::Update(42, Box::new([None, Some(100), None])), &table);
+    transaction.add_query(Query::Delete(599), &table);
+    assert!(transaction.run().is_ok());
+
+    crabstore.close();
+}
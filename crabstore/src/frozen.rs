@@ -0,0 +1,208 @@
+use std::{
+    ops::{RangeBounds, RangeInclusive},
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use crate::{
+    error::CrabError, index::Index, record::Record, rid::RID, schema::SignedColumns, table::Table,
+    CLOSE_DRAIN_TIMEOUT_MS,
+};
+
+/*
+    Immutable copy of the latest version of every record, read without
+    touching the page directory, the bufferpool or any frame latches.
+*/
+pub(crate) struct FrozenTable {
+    primary_key_index: usize,
+    // Indexed by base RID, None for deleted records
+    rows: Vec<Option<Record>>,
+    index: Index,
+    signed: SignedColumns,
+}
+
+impl FrozenTable {
+    fn build(table: &Table) -> Self {
+        let live = table.live_rids();
+        let latest = table
+            .data
+            .materialize_latest(&live, &vec![1; table.columns()]);
+
+        let mut rows = Vec::new();
+        rows.resize_with(table.next_rid(), Default::default);
+
+        for (rid, record) in live.into_iter().zip(latest) {
+            rows[rid.raw() as usize] = Some(record);
+        }
+
+        FrozenTable {
+            primary_key_index: table.primary_key(),
+            rows,
+            index: table.index_snapshot(),
+            signed: table.signed_columns(),
+        }
+    }
+
+    fn live_rows(&self, rids: Vec<RID>) -> Vec<&Record> {
+        rids.into_iter()
+            .filter_map(|rid| self.rows.get(rid.raw() as usize)?.as_ref())
+            .collect()
+    }
+
+    pub(crate) fn select_rids(&self, search_value: u64, column_index: usize) -> Vec<RID> {
+        match self.index.get_from_index(column_index, search_value) {
+            Some(rids) => rids
+                .into_iter()
+                .filter(|rid| matches!(self.rows.get(rid.raw() as usize), Some(Some(_))))
+                .collect(),
+            None => self
+                .rows
+                .iter()
+                .enumerate()
+                .filter(|(_, row)| {
+                    row.as_ref()
+                        .is_some_and(|record| record.value(column_index) == Some(search_value))
+                })
+                .map(|(rid, _)| RID::from(rid as u64))
+                .collect(),
+        }
+    }
+
+    pub(crate) fn materialize(&self, rids: &[RID], included_columns: &[usize]) -> Vec<Record> {
+        self.live_rows(rids.to_vec())
+            .into_iter()
+            .map(|record| record.clone().project(included_columns))
+            .collect()
+    }
+
+    pub(crate) fn select(
+        &self,
+        search_value: u64,
+        column_index: usize,
+        included_columns: &[usize],
+    ) -> Vec<Record> {
+        self.materialize(
+            &self.select_rids(search_value, column_index),
+            included_columns,
+        )
+    }
+
+    /*
+        At least the rows holding every filter's value, the caller checks them
+    */
+    pub(crate) fn select_multi(
+        &self,
+        filters: &[(usize, u64)],
+        included_columns: &[usize],
+    ) -> Vec<Record> {
+        let rids = match (self.index.find_composite(filters), filters.first()) {
+            (Some(rids), _) => rids,
+            (None, Some((column_index, search_value))) => {
+                self.select_rids(*search_value, *column_index)
+            }
+            (None, None) => (0..self.rows.len() as u64).map(RID::from).collect(),
+        };
+
+        self.materialize(&rids, included_columns)
+    }
+
+    pub(crate) fn select_range(
+        &self,
+        range: impl RangeBounds<u64>,
+        column_index: usize,
+        included_columns: &[usize],
+    ) -> Vec<Record> {
+        let rids = match self
+            .index
+            .range_from_index(column_index, (range.start_bound(), range.end_bound()))
+        {
+            Some(rids) => rids,
+            None => self
+                .rows
+                .iter()
+                .enumerate()
+                .filter(|(_, row)| {
+                    row.as_ref().is_some_and(|record| {
+                        record
+                            .value(column_index)
+                            .is_some_and(|value| self.signed.in_range(column_index, &range, value))
+                    })
+                })
+                .map(|(rid, _)| RID::from(rid as u64))
+                .collect(),
+        };
+
+        self.materialize(&rids, included_columns)
+    }
+
+    pub(crate) fn values(&self, range: RangeInclusive<u64>, column_index: usize) -> Vec<u64> {
+        let key = self.primary_key_index;
+
+        let rows = match self.index.range_from_index(key, range.clone()) {
+            Some(rids) => self.live_rows(rids),
+            None => self
+                .rows
+                .iter()
+                .flatten()
+                .filter(|record| self.signed.in_range(key, &range, record.columns[key]))
+                .collect(),
+        };
+
+        rows.iter()
+            .filter_map(|record| record.value(column_index))
+            .collect()
+    }
+}
+
+impl Table {
+    /*
+        Stops accepting writes and serves reads from an immutable snapshot.
+        Queries still running get CLOSE_DRAIN_TIMEOUT_MS to finish, the merges
+        already queued are finished and the pages written back, then the
+        snapshot materializes the latest version of every record itself. A
+        transaction still holding locks could roll back writes the snapshot
+        would keep, so freezing fails with TableInUse while there is one, or
+        while queries are still running when the drain gives up, and leaves
+        the table unfrozen.
+    */
+    pub fn freeze(&self) -> Result<(), CrabError> {
+        if self.frozen.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        let quiesced = if self.drain_queries() {
+            self.quiesce(Duration::from_millis(CLOSE_DRAIN_TIMEOUT_MS))
+        } else {
+            Err(CrabError::TableInUse)
+        };
+
+        let ready = quiesced.and_then(|_| {
+            if self.get_lock_manager().is_idle() {
+                Ok(())
+            } else {
+                Err(CrabError::TableInUse)
+            }
+        });
+
+        if let Err(err) = ready {
+            self.frozen.store(false, Ordering::SeqCst);
+            return Err(err);
+        }
+
+        *self.frozen_snapshot.write() = Some(Arc::new(FrozenTable::build(self)));
+        Ok(())
+    }
+
+    pub fn unfreeze(&self) {
+        *self.frozen_snapshot.write() = None;
+        self.frozen.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.frozen.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn frozen(&self) -> Option<Arc<FrozenTable>> {
+        self.frozen_snapshot.read().clone()
+    }
+}
@@ -1,26 +1,70 @@
-use crate::rid::RID;
-use core::fmt;
-use rkyv::{
-    de::deserializers::SharedDeserializeMap,
-    ser::{
-        serializers::{AllocScratch, CompositeSerializer, SharedSerializeMap, WriteSerializer},
-        Serializer,
-    },
-    Deserialize,
-};
-use std::{
-    collections::BTreeMap,
-    io::{BufWriter, Read, Write},
-    ops::RangeBounds,
-    path::PathBuf,
+use crate::{
+    archive, error::CrabError, format::IndexSnapshot, record, rid::RID, schema::SignedColumns,
+    transaction::IndexMutation,
 };
-use std::{fs::File, path::Path};
+use core::fmt;
+use std::path::Path;
+use std::{collections::BTreeMap, ops::RangeBounds, path::PathBuf};
 
 #[derive(Clone, Debug, Default)]
 //change to BTreeMap when we need to implement ranges
 pub struct Index {
     path: PathBuf,
+    // Keyed by SignedColumns::sort_key, every method takes and returns the values themselves
     indices: Vec<Option<BTreeMap<u64, Vec<RID>>>>,
+    signed: SignedColumns,
+    // Bit i set if column i's index is unique, which the table enforces, see Table::build_unique_index
+    unique: u64,
+    composites: Vec<CompositeIndex>,
+}
+
+/*
+    Values of the indexed columns in order, each as its SignedColumns::sort_key,
+    so keys sharing a prefix of columns are next to each other
+*/
+type CompositeKey = Vec<u64>;
+
+/*
+    An index on several columns at once, see Table::build_composite_index. Rows
+    with a NULL in any of its columns have no entry.
+*/
+#[derive(Clone, Debug, Default)]
+struct CompositeIndex {
+    columns: Vec<usize>,
+    map: BTreeMap<CompositeKey, Vec<RID>>,
+}
+
+/*
+    A write to a row, which Index::apply brings every index of its table up to date
+    with. Values are the row's columns in order, with nulls masking the NULL ones,
+    which have no index entries.
+*/
+#[derive(Clone, Copy, Debug)]
+pub enum RecordChange<'a> {
+    Insert {
+        rid: RID,
+        values: &'a [u64],
+        nulls: u64,
+    },
+    /*
+        Only the columns set in the schema encoding changed. The rest of old isn't
+        read, those of new are for composite indexes over changed and unchanged
+        columns alike.
+    */
+    Update {
+        rid: RID,
+        schema_encoding: u64,
+        old: &'a [u64],
+        old_nulls: u64,
+        new: &'a [u64],
+        new_nulls: u64,
+    },
+    // Values of the row's latest version
+    Delete {
+        rid: RID,
+        values: &'a [u64],
+        nulls: u64,
+    },
 }
 
 impl fmt::Display for Index {
@@ -30,6 +74,7 @@ impl fmt::Display for Index {
             match v {
                 Some(v) => {
                     for (key, value) in v.iter() {
+                        let key = self.signed.sort_key(i, *key);
                         write!(f, "Key: {} | Value: {:?}\n", key, value)?;
                     }
                 }
@@ -52,56 +97,302 @@ impl Index {
         Index {
             path: path.into(),
             indices,
+            signed: SignedColumns::default(),
+            unique: 0,
+            composites: Vec::new(),
         }
     }
 
-    pub fn load(path: &Path) -> Self {
-        let mut id_file = File::options()
-            .read(true)
-            .open(path)
-            .expect("Unable to open index file");
+    pub fn load(path: &Path, num_columns: usize) -> Result<Self, CrabError> {
+        // Declared indexes are rebuilt by the table if the index file is gone
+        if !path.exists() {
+            let mut indices = Vec::with_capacity(num_columns);
+            indices.resize_with(num_columns, Default::default);
 
-        let mut id_bytes = Vec::new();
+            return Ok(Index {
+                path: path.into(),
+                indices,
+                signed: SignedColumns::default(),
+                unique: 0,
+                composites: Vec::new(),
+            });
+        }
 
-        id_file
-            .read_to_end(&mut id_bytes)
-            .expect("Unable to read index file");
+        let snapshot = IndexSnapshot::decode(path, &archive::read_file(path)?)?;
 
-        let archived =
-            unsafe { rkyv::archived_root::<Vec<Option<BTreeMap<u64, Vec<RID>>>>>(&id_bytes) };
+        let indices: Vec<Option<BTreeMap<u64, Vec<RID>>>> = snapshot
+            .columns
+            .into_iter()
+            .map(|column| {
+                column.map(|entries| {
+                    entries
+                        .into_iter()
+                        .map(|(value, rids)| (value, rids.into_iter().map(RID::from).collect()))
+                        .collect()
+                })
+            })
+            .collect();
 
-        Index {
+        if indices.len() != num_columns {
+            return Err(archive::corrupt(
+                path,
+                format!("{} indexes for {num_columns} columns", indices.len()),
+            ));
+        }
+
+        let mut composites = Vec::with_capacity(snapshot.composites.len());
+        for (columns, entries) in snapshot.composites {
+            if columns.iter().any(|column| *column >= num_columns) {
+                return Err(archive::corrupt(
+                    path,
+                    format!("composite index on {columns:?} for {num_columns} columns"),
+                ));
+            }
+
+            composites.push(CompositeIndex {
+                columns,
+                map: entries
+                    .into_iter()
+                    .map(|(key, rids)| (key, rids.into_iter().map(RID::from).collect()))
+                    .collect(),
+            });
+        }
+
+        Ok(Index {
             path: path.into(),
-            indices: archived
-                .deserialize(&mut SharedDeserializeMap::new())
-                .expect("Failed to deserialize page directory"),
+            indices,
+            signed: SignedColumns::default(),
+            unique: 0,
+            composites,
+        })
+    }
+
+    /*
+        The index of a table with signed columns, whose keys those columns order
+        as signed. Index files hold the values themselves, so one loaded from
+        disk is rekeyed here.
+    */
+    pub(crate) fn with_signed(mut self, signed: SignedColumns) -> Self {
+        let old = self.signed;
+        for (column, index) in self.indices.iter_mut().enumerate() {
+            if let Some(map) = index {
+                if signed.is_signed(column) != old.is_signed(column) {
+                    *map = std::mem::take(map)
+                        .into_iter()
+                        .map(|(key, rids)| {
+                            (signed.sort_key(column, old.sort_key(column, key)), rids)
+                        })
+                        .collect();
+                }
+            }
         }
+
+        for composite in self.composites.iter_mut() {
+            let columns = &composite.columns;
+            if columns
+                .iter()
+                .all(|column| signed.is_signed(*column) == old.is_signed(*column))
+            {
+                continue;
+            }
+
+            composite.map = std::mem::take(&mut composite.map)
+                .into_iter()
+                .map(|(key, rids)| {
+                    let key = columns
+                        .iter()
+                        .zip(key)
+                        .map(|(column, key)| signed.sort_key(*column, old.sort_key(*column, key)))
+                        .collect();
+                    (key, rids)
+                })
+                .collect();
+        }
+
+        self.signed = signed;
+        self
     }
 
-    pub fn persist(&self) {
-        let id_file = File::options()
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(&self.path)
-            .expect("Unable to open index file");
+    pub fn persist(&self) -> Result<(), CrabError> {
+        let snapshot = IndexSnapshot {
+            columns: self
+                .indices
+                .iter()
+                .enumerate()
+                .map(|(column, index)| {
+                    index.as_ref().map(|map| {
+                        map.iter()
+                            .map(|(key, rids)| {
+                                (
+                                    self.signed.sort_key(column, *key),
+                                    rids.iter().map(|rid| rid.raw()).collect(),
+                                )
+                            })
+                            .collect()
+                    })
+                })
+                .collect(),
+            composites: self
+                .composites
+                .iter()
+                .map(|composite| {
+                    let entries = composite
+                        .map
+                        .iter()
+                        .map(|(key, rids)| {
+                            (
+                                self.composite_values(&composite.columns, key),
+                                rids.iter().map(|rid| rid.raw()).collect(),
+                            )
+                        })
+                        .collect();
+                    (composite.columns.clone(), entries)
+                })
+                .collect(),
+        };
 
-        let mut serializer = CompositeSerializer::new(
-            WriteSerializer::new(BufWriter::new(id_file)),
-            AllocScratch::default(),
-            SharedSerializeMap::new(),
-        );
+        archive::write_file(&self.path, &snapshot.encode())
+    }
 
-        serializer
-            .serialize_value(&self.indices)
-            .expect("Unable to serialize indexes");
+    /*
+        Adds the row's entries to every index for an insert, takes them out for a
+        delete, and moves them to the new values of the indexed columns an update
+        changed.
+    */
+    pub fn apply(&mut self, change: &RecordChange) {
+        self.apply_logged(change, |_| {});
+    }
 
-        let (buf, _, _) = serializer.into_components();
+    /*
+        apply, passing every entry an update adds or removes to log, so a rollback
+        can undo it. Rolled back inserts and deletes are undone from the row
+        itself, see TableData::undo_insert
+    */
+    pub(crate) fn apply_logged(
+        &mut self,
+        change: &RecordChange,
+        mut log: impl FnMut(IndexMutation),
+    ) {
+        for column in 0..self.indices.len() {
+            if self.indices[column].is_none() {
+                continue;
+            }
 
-        buf.into_inner().flush().expect("Failed to flush indices");
+            match *change {
+                RecordChange::Insert { rid, values, nulls } => {
+                    if !record::is_null(nulls, column) {
+                        self.update_index(column, values[column], rid);
+                    }
+                }
+                RecordChange::Update {
+                    rid,
+                    schema_encoding,
+                    old,
+                    old_nulls,
+                    new,
+                    new_nulls,
+                } => {
+                    if schema_encoding >> column & 1 == 0 {
+                        continue;
+                    }
+
+                    let (old_value, old_null) = (old[column], record::is_null(old_nulls, column));
+                    let (value, null) = (new[column], record::is_null(new_nulls, column));
+                    if (old_value, old_null) == (value, null) {
+                        continue;
+                    }
+
+                    if !null {
+                        log(IndexMutation::Add { rid, value, column });
+                        self.update_index(column, value, rid);
+                    }
+                    if !old_null {
+                        log(IndexMutation::Remove {
+                            rid,
+                            old_value,
+                            column,
+                        });
+                        self.remove_index(column, old_value, rid);
+                    }
+                }
+                RecordChange::Delete { rid, values, nulls } => {
+                    if !record::is_null(nulls, column) {
+                        self.remove_index(column, values[column], rid);
+                    }
+                }
+            }
+        }
+
+        for i in 0..self.composites.len() {
+            let columns = self.composites[i].columns.clone();
+            let key = |values: &[u64], nulls: u64| {
+                (!columns.iter().any(|column| record::is_null(nulls, *column)))
+                    .then(|| columns.iter().map(|column| values[*column]).collect())
+            };
+
+            let (rid, old, new): (RID, Option<Vec<u64>>, Option<Vec<u64>>) = match *change {
+                RecordChange::Insert { rid, values, nulls } => (rid, None, key(values, nulls)),
+                RecordChange::Update {
+                    rid,
+                    schema_encoding,
+                    old,
+                    old_nulls,
+                    new,
+                    new_nulls,
+                } => {
+                    if !columns
+                        .iter()
+                        .any(|column| schema_encoding >> column & 1 != 0)
+                    {
+                        continue;
+                    }
+
+                    // Columns the update left alone are only given in new
+                    let unchanged = (0..new.len())
+                        .map(|column| match schema_encoding >> column & 1 {
+                            0 => new[column],
+                            _ => old[column],
+                        })
+                        .collect::<Vec<u64>>();
+                    let unchanged_nulls =
+                        new_nulls & !schema_encoding | old_nulls & schema_encoding;
+
+                    let old = key(&unchanged, unchanged_nulls);
+                    let new = key(new, new_nulls);
+                    if old == new {
+                        continue;
+                    }
+
+                    if let Some(values) = &new {
+                        log(IndexMutation::AddComposite {
+                            rid,
+                            columns: columns.clone(),
+                            values: values.clone(),
+                        });
+                    }
+                    if let Some(old_values) = &old {
+                        log(IndexMutation::RemoveComposite {
+                            rid,
+                            columns: columns.clone(),
+                            old_values: old_values.clone(),
+                        });
+                    }
+                    (rid, old, new)
+                }
+                RecordChange::Delete { rid, values, nulls } => (rid, key(values, nulls), None),
+            };
+
+            if let Some(values) = new {
+                self.update_composite(&columns, &values, rid);
+            }
+            if let Some(old_values) = old {
+                self.remove_composite(&columns, &old_values, rid);
+            }
+        }
     }
 
     pub fn update_index(&mut self, column_number: usize, value: u64, rid: RID) {
+        let value = self.signed.sort_key(column_number, value);
         if let Some(ref mut index) = self.indices[column_number] {
             if let Some(ref mut rids) = index.get_mut(&value) {
                 rids.push(rid);
@@ -114,6 +405,7 @@ impl Index {
     }
 
     pub fn remove_index(&mut self, column_number: usize, value: u64, rid: RID) {
+        let value = self.signed.sort_key(column_number, value);
         if let Some(ref mut index) = self.indices[column_number] {
             if let Some(ref mut rids) = index.get_mut(&value) {
                 rids.retain(|x| x.raw() != rid.raw());
@@ -121,7 +413,25 @@ impl Index {
         }
     }
 
+    pub fn contains(&self, column_number: usize, value: u64, rid: RID) -> bool {
+        let value = self.signed.sort_key(column_number, value);
+        self.indices[column_number]
+            .as_ref()
+            .and_then(|map| map.get(&value))
+            .is_some_and(|rids| rids.contains(&rid))
+    }
+
+    /*
+        Number of RIDs in a column's index, None if the column is not indexed
+    */
+    pub fn entry_count(&self, column_number: usize) -> Option<usize> {
+        self.indices[column_number]
+            .as_ref()
+            .map(|map| map.values().map(Vec::len).sum())
+    }
+
     pub fn get_from_index(&self, column_number: usize, value: u64) -> Option<Vec<RID>> {
+        let value = self.signed.sort_key(column_number, value);
         self.indices[column_number]
             .as_ref()
             .map(|map| match map.get(&value) {
@@ -135,6 +445,7 @@ impl Index {
         column_number: usize,
         range: impl RangeBounds<u64>,
     ) -> Option<Vec<RID>> {
+        let range = self.signed.key_range(column_number, &range);
         self.indices[column_number].as_ref().map(|map| {
             map.range(range)
                 .flat_map(|item| item.1.clone())
@@ -142,11 +453,261 @@ impl Index {
         })
     }
 
+    /*
+        Each value in range with the RIDs holding it, lowest value first
+    */
+    pub fn range_entries(
+        &self,
+        column_number: usize,
+        range: impl RangeBounds<u64>,
+    ) -> Option<impl Iterator<Item = (u64, &[RID])> + '_> {
+        let range = self.signed.key_range(column_number, &range);
+        let signed = self.signed;
+        self.indices[column_number].as_ref().map(move |map| {
+            map.range(range)
+                .map(move |(key, rids)| (signed.sort_key(column_number, *key), rids.as_slice()))
+        })
+    }
+
+    /*
+        Each value in range with how many RIDs hold it, lowest value first
+    */
+    pub fn range_counts(
+        &self,
+        column_number: usize,
+        range: impl RangeBounds<u64>,
+    ) -> Option<impl Iterator<Item = (u64, usize)> + '_> {
+        let range = self.signed.key_range(column_number, &range);
+        let signed = self.signed;
+        self.indices[column_number].as_ref().map(move |map| {
+            map.range(range)
+                .map(move |(key, rids)| (signed.sort_key(column_number, *key), rids.len()))
+        })
+    }
+
+    /*
+        RIDs from the highest value in range down, newest first among equal values.
+        Lazy, so a caller that only wants the first few never walks the rest.
+    */
+    pub fn range_rev(
+        &self,
+        column_number: usize,
+        range: impl RangeBounds<u64>,
+    ) -> Option<impl Iterator<Item = RID> + '_> {
+        let range = self.signed.key_range(column_number, &range);
+        self.indices[column_number].as_ref().map(|map| {
+            map.range(range)
+                .rev()
+                .flat_map(|item| item.1.iter().rev().copied())
+        })
+    }
+
+    /*
+        Every value with its RIDs, lowest value first
+    */
+    pub fn entries(&self, column_number: usize) -> Option<impl Iterator<Item = (u64, RID)> + '_> {
+        let signed = self.signed;
+        self.indices[column_number].as_ref().map(move |map| {
+            map.iter().flat_map(move |(key, rids)| {
+                let value = signed.sort_key(column_number, *key);
+                rids.iter().map(move |rid| (value, *rid))
+            })
+        })
+    }
+
+    pub fn is_indexed(&self, column_number: usize) -> bool {
+        self.indices.get(column_number).is_some_and(Option::is_some)
+    }
+
+    /*
+        The lstore lookups: RIDs holding value, or a value in begin..=end, in the
+        column. Empty if the column isn't indexed, or isn't one of the table's.
+    */
+    pub fn locate(&self, column_number: usize, value: u64) -> Vec<RID> {
+        match self.is_indexed(column_number) {
+            true => self
+                .get_from_index(column_number, value)
+                .unwrap_or_default(),
+            false => Vec::new(),
+        }
+    }
+
+    pub fn locate_range(&self, begin: u64, end: u64, column_number: usize) -> Vec<RID> {
+        // A BTreeMap range that starts past its end panics
+        if !self.is_indexed(column_number)
+            || self.signed.sort_key(column_number, begin) > self.signed.sort_key(column_number, end)
+        {
+            return Vec::new();
+        }
+
+        self.range_from_index(column_number, begin..=end)
+            .unwrap_or_default()
+    }
+
+    /*
+        Distinct values with at least one RID in the column's index, None if the
+        column isn't indexed
+    */
+    pub fn cardinality(&self, column_number: usize) -> Option<usize> {
+        self.indices
+            .get(column_number)?
+            .as_ref()
+            .map(|map| map.values().filter(|rids| !rids.is_empty()).count())
+    }
+
+    /*
+        entry_count, but None rather than a panic for a column the table doesn't have
+    */
+    pub fn len(&self, column_number: usize) -> Option<usize> {
+        match self.is_indexed(column_number) {
+            true => self.entry_count(column_number),
+            false => None,
+        }
+    }
+
+    pub fn indexed_columns(&self) -> Vec<usize> {
+        self.indices
+            .iter()
+            .enumerate()
+            .filter(|(_, index)| index.is_some())
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /*
+        An empty index on the column, replacing any it had, unique or not
+    */
     pub fn create_index(&mut self, column_number: usize) {
         self.indices[column_number] = Some(BTreeMap::new());
+        self.unique &= !(1 << column_number);
     }
 
     pub fn drop_index(&mut self, column_number: usize) {
         self.indices[column_number] = None;
+        self.unique &= !(1 << column_number);
+    }
+
+    /*
+        Only marks the column's index, keeping its values apart is up to the table
+    */
+    pub fn set_unique(&mut self, column_number: usize) {
+        if self.is_indexed(column_number) {
+            self.unique |= 1 << column_number;
+        }
+    }
+
+    pub fn is_unique(&self, column_number: usize) -> bool {
+        self.unique >> column_number & 1 != 0
+    }
+
+    /*
+        Bit i set if column i's index is unique, as the table header records them
+    */
+    pub(crate) fn unique_mask(&self) -> u64 {
+        self.unique
+    }
+
+    /*
+        An empty index on the columns in this order, replacing any there was on them
+    */
+    pub fn create_composite_index(&mut self, columns: &[usize]) {
+        self.drop_composite_index(columns);
+        self.composites.push(CompositeIndex {
+            columns: columns.to_vec(),
+            map: BTreeMap::new(),
+        });
+    }
+
+    pub fn drop_composite_index(&mut self, columns: &[usize]) {
+        self.composites
+            .retain(|composite| composite.columns != columns);
+    }
+
+    pub fn composite_indexes(&self) -> Vec<Vec<usize>> {
+        self.composites
+            .iter()
+            .map(|composite| composite.columns.clone())
+            .collect()
+    }
+
+    fn composite_key(&self, columns: &[usize], values: &[u64]) -> CompositeKey {
+        columns
+            .iter()
+            .zip(values)
+            .map(|(column, value)| self.signed.sort_key(*column, *value))
+            .collect()
+    }
+
+    // sort_key is its own inverse
+    fn composite_values(&self, columns: &[usize], key: &[u64]) -> Vec<u64> {
+        self.composite_key(columns, key)
+    }
+
+    /*
+        Adds rid under values, those of the composite index's columns in its order
+    */
+    pub fn update_composite(&mut self, columns: &[usize], values: &[u64], rid: RID) {
+        let key = self.composite_key(columns, values);
+        if let Some(composite) = self.composites.iter_mut().find(|c| c.columns == columns) {
+            composite.map.entry(key).or_default().push(rid);
+        }
+    }
+
+    pub fn remove_composite(&mut self, columns: &[usize], values: &[u64], rid: RID) {
+        let key = self.composite_key(columns, values);
+        if let Some(composite) = self.composites.iter_mut().find(|c| c.columns == columns) {
+            if let Some(rids) = composite.map.get_mut(&key) {
+                rids.retain(|x| x.raw() != rid.raw());
+            }
+        }
+    }
+
+    /*
+        RIDs of the rows holding the filters' values, from the composite index
+        whose leading columns the filters cover most of. Filters on its later
+        columns, or on columns it doesn't have, aren't checked here. None if no
+        composite index starts with a filtered column.
+    */
+    pub fn find_composite(&self, filters: &[(usize, u64)]) -> Option<Vec<RID>> {
+        let filtered = |column: &usize| {
+            filters
+                .iter()
+                .find(|(filtered, _)| filtered == column)
+                .map(|(_, value)| *value)
+        };
+
+        let (composite, prefix) = self
+            .composites
+            .iter()
+            .map(|composite| {
+                let prefix = composite
+                    .columns
+                    .iter()
+                    .map_while(filtered)
+                    .collect::<Vec<u64>>();
+                (composite, prefix)
+            })
+            .filter(|(_, prefix)| !prefix.is_empty())
+            .max_by_key(|(_, prefix)| prefix.len())?;
+
+        let prefix = self.composite_key(&composite.columns, &prefix);
+        Some(
+            composite
+                .map
+                .range(prefix.clone()..)
+                .take_while(|(key, _)| key.starts_with(&prefix))
+                .flat_map(|(_, rids)| rids.iter().copied())
+                .collect(),
+        )
+    }
+
+    /*
+        Number of RIDs in the composite index on columns, None if there is none
+    */
+    pub fn composite_entry_count(&self, columns: &[usize]) -> Option<usize> {
+        self.composites
+            .iter()
+            .find(|composite| composite.columns == columns)
+            .map(|composite| composite.map.values().map(Vec::len).sum())
     }
 }
@@ -0,0 +1,125 @@
+use std::fmt;
+
+use crate::{
+    page::Page, rid::RID, table::Table, INSPECT_SLOT_LIMIT, METADATA_BASE_RID,
+    METADATA_INDIRECTION, METADATA_PAGE_HEADER, METADATA_RID, METADATA_SCHEMA_ENCODING, PAGE_SLOTS,
+};
+
+/*
+    Raw contents of one logical page, for debugging
+*/
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PageInspection {
+    pub page: usize,
+    pub is_tail: bool,
+    // Physical page holding each column, metadata columns first
+    pub columns: Vec<usize>,
+    // TPS of a base page, the previous tail page of a tail page
+    pub header: u64,
+    // The first slots of every column, as many as asked for up to INSPECT_SLOT_LIMIT
+    pub slots: Vec<Vec<u64>>,
+}
+
+/*
+    Where a RID lives and the metadata stored for it
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RidInspection {
+    pub rid: RID,
+    pub page: usize,
+    pub slot: usize,
+    pub indirection: u64,
+    // RID_INVALID once the record is deleted
+    pub stored_rid: u64,
+    pub base_rid: u64,
+    pub schema_encoding: u64,
+}
+
+impl Table {
+    /*
+        None if the page directory has no such page. Reads go through the
+        bufferpool's scan path, so inspecting a page neither dirties nor keeps
+        its frames.
+    */
+    pub fn inspect_page(&self, logical_page: usize, dump_slots: usize) -> Option<PageInspection> {
+        let columns = self.data.page_dir.get_page(logical_page)?;
+        let page = Page::new(columns.clone());
+
+        let bufferpool = self.get_bufferpool();
+        let bp = &*bufferpool;
+
+        let header = page.get_column_for_scan(bp, METADATA_PAGE_HEADER).slot(0);
+        let dump_slots = dump_slots.min(INSPECT_SLOT_LIMIT).min(PAGE_SLOTS);
+        let slots = (0..columns.len())
+            .filter(|_| dump_slots > 0)
+            .map(|column| {
+                let frame = page.get_column_for_scan(bp, column);
+                (0..dump_slots).map(|slot| frame.slot(slot)).collect()
+            })
+            .collect();
+
+        Some(PageInspection {
+            page: logical_page,
+            is_tail: RID::is_tail_page(logical_page),
+            columns: columns.to_vec(),
+            header,
+            slots,
+        })
+    }
+
+    /*
+        None if the page directory has no page for the RID
+    */
+    pub fn inspect_rid(&self, rid: RID) -> Option<RidInspection> {
+        let page = Page::new(self.data.page_dir.get(rid)?);
+
+        let bufferpool = self.get_bufferpool();
+        let bp = &*bufferpool;
+        let metadata = |column| page.get_column_for_scan(bp, column).slot(rid.slot());
+
+        Some(RidInspection {
+            rid,
+            page: rid.page(),
+            slot: rid.slot(),
+            indirection: metadata(METADATA_INDIRECTION),
+            stored_rid: metadata(METADATA_RID),
+            base_rid: metadata(METADATA_BASE_RID),
+            schema_encoding: metadata(METADATA_SCHEMA_ENCODING),
+        })
+    }
+}
+
+impl fmt::Display for PageInspection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = if self.is_tail { "Tail" } else { "Base" };
+        writeln!(f, "[{kind} page {}] header {}", self.page, self.header)?;
+
+        for (column, physical) in self.columns.iter().enumerate() {
+            write!(f, "  Column {column} -> page {physical}")?;
+
+            if let Some(slots) = self.slots.get(column).filter(|slots| !slots.is_empty()) {
+                write!(f, " {slots:?}")?;
+            }
+
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for RidInspection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "RID {} at page {} slot {}: indirection {} rid {} base {} schema {:b}",
+            self.rid.raw(),
+            self.page,
+            self.slot,
+            self.indirection,
+            self.stored_rid,
+            self.base_rid,
+            self.schema_encoding
+        )
+    }
+}
@@ -0,0 +1,96 @@
+use std::{ops::Deref, vec};
+
+use crate::{error::CrabError, record::Record, rid::RID, table::Table, ITER_CHUNK_ROWS};
+
+/*
+    Latest values of every row in primary key order, read ITER_CHUNK_ROWS rows at
+    a time. The keys are a snapshot taken when the iteration starts, rows deleted
+    since are skipped and rows inserted since are not seen. Holds anything that
+    derefs to the table, so bindings can keep it alive with an Arc.
+*/
+pub struct OrderedRecords<T: Deref<Target = Table>> {
+    table: T,
+    keys: vec::IntoIter<(u64, RID)>,
+    included_columns: Vec<usize>,
+    chunk: vec::IntoIter<Record>,
+    failed: bool,
+}
+
+impl<T: Deref<Target = Table>> OrderedRecords<T> {
+    pub fn new(table: T, included_columns: &[usize]) -> Result<Self, CrabError> {
+        let keys = table.key_snapshot()?;
+
+        Ok(OrderedRecords {
+            table,
+            keys: keys.into_iter(),
+            included_columns: included_columns.to_vec(),
+            chunk: Vec::new().into_iter(),
+            failed: false,
+        })
+    }
+}
+
+impl<T: Deref<Target = Table>> Iterator for OrderedRecords<T> {
+    type Item = Result<Record, CrabError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.failed {
+            if let Some(record) = self.chunk.next() {
+                return Some(Ok(record));
+            }
+
+            if self.keys.len() == 0 {
+                return None;
+            }
+
+            let key_column = self.table.primary_key();
+            let rids = self
+                .keys
+                .by_ref()
+                .take(ITER_CHUNK_ROWS)
+                .filter(|(key, rid)| self.table.holds_value(*rid, key_column, *key))
+                .map(|(_, rid)| rid)
+                .collect::<Vec<RID>>();
+
+            match self.table.materialize(&rids, &self.included_columns) {
+                Ok(records) => self.chunk = records.into_iter(),
+                Err(err) => {
+                    self.failed = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+
+        None
+    }
+}
+
+impl Table {
+    /*
+        Primary keys of the live rows in ascending order. The keys are a snapshot of
+        the key index taken by this call, so only they are held in memory. Keys whose
+        rows are deleted before the iterator reaches them are skipped.
+    */
+    pub fn iter_keys(&self) -> Result<impl Iterator<Item = u64> + '_, CrabError> {
+        let key_column = self.primary_key();
+
+        Ok(self
+            .key_snapshot()?
+            .into_iter()
+            .filter(move |(key, rid)| self.holds_value(*rid, key_column, *key))
+            .map(|(key, _)| key))
+    }
+
+    /*
+        Latest values of the columns flagged in included_columns for every live row,
+        in primary key order. Like Table::iter_keys, but rows are read in batches
+        through Table::materialize, so each batch fails on its own, e.g. once the
+        table is closed.
+    */
+    pub fn iter_records_ordered(
+        &self,
+        included_columns: &[usize],
+    ) -> Result<OrderedRecords<&Table>, CrabError> {
+        OrderedRecords::new(self, included_columns)
+    }
+}
@@ -8,6 +8,7 @@ use std::mem::size_of;
 
 const PAGE_SIZE: usize = 4096;
 const PAGE_SLOTS: usize = PAGE_SIZE / size_of::<i64>();
+// base pages in a page range, unless CrabConfig::page_range_pages says otherwise
 const PAGE_RANGE_COUNT: usize = 16;
 const PAGE_RANGE_SIZE: usize = PAGE_SIZE * PAGE_RANGE_COUNT;
 const RANGE_PAGE_COUNT: usize = PAGE_RANGE_SIZE / PAGE_SIZE;
@@ -20,8 +21,8 @@ const METADATA_BASE_RID: usize = 2;
 const NUM_STATIC_COLUMNS: usize = 3;
 
 const METADATA_PAGE_HEADER: usize = 3;
-//const METADATA_TIMESTAMP: usize = 4;
 const METADATA_SCHEMA_ENCODING: usize = 4;
+// Version timestamps go after a table's own columns instead, see TableData::timestamp_column
 // 0xFF...FF
 const RID_INVALID: u64 = !0;
 
@@ -29,31 +30,113 @@ const RID_INVALID: u64 = !0;
 // allow for shared bufferpool with merge thread
 const BUFFERPOOL_SIZE: usize = 256;
 
+// filled tail pages that get a page range merged, unless its MergeConfig says otherwise
+const MERGE_THRESHOLD: usize = 4;
+
+// threads a CrabStore's MergeScheduler merges its tables on, unless CrabConfig says otherwise
+const MERGE_THREADS: usize = 2;
+
+// page directory lock shards, page ranges are spread across them
+const PAGE_DIRECTORY_SHARDS: usize = 64;
+
+// lock manager shards, records are spread across them by RID
+const LOCK_MANAGER_SHARDS: usize = 64;
+
+// longest an older transaction waits on younger lock holders under wait-die before giving up
+const WAIT_DIE_TIMEOUT_MS: u64 = 1000;
+
+// keys within the same block of this many share a scheduler affinity
+const AFFINITY_KEY_RANGE: u64 = 1024;
+
+// past this many rows a mismatched primary key index is rebuilt on a background thread
+const BACKGROUND_INDEX_REBUILD_ROWS: usize = 1 << 16;
+
+// how long persist waits for in-flight queries before closing anyway
+const CLOSE_DRAIN_TIMEOUT_MS: u64 = 5000;
+
+// queries slower than this land in the table's slow query log
+const SLOW_QUERY_THRESHOLD_MS: u64 = 10;
+// how many of the most recent slow queries each table keeps
+const SLOW_QUERY_LOG_SIZE: usize = 64;
+
+// most slots per column Table::inspect_page dumps
+const INSPECT_SLOT_LIMIT: usize = 64;
+
+// rows Table::iter_records_ordered materializes at a time
+const ITER_CHUNK_ROWS: usize = PAGE_SLOTS;
+
+// most index entries per row in range for which aggregates read another column's index over its pages
+const INDEX_ONLY_FANOUT: usize = 8;
+
+mod archive;
+#[cfg(feature = "async")]
+pub mod async_store;
 pub mod bufferpool;
+pub mod config;
 pub mod crabstore;
 pub mod disk_manager;
+pub mod error;
+pub mod eviction;
+pub mod format;
+mod frozen;
 pub mod index;
+pub mod inspect;
+pub mod key_order;
 pub mod lock_manager;
-mod merge;
+pub mod merge;
 pub mod page;
 mod page_directory;
+pub mod prelude;
 mod range_directory;
+pub mod range_dump;
 pub mod record;
 pub mod rid;
+pub mod scan;
+pub mod schema;
+pub mod stats;
 pub mod table;
+mod table_data;
 pub mod transaction;
+pub mod transaction_scheduler;
 pub mod transaction_worker;
 
 #[cfg(test)]
 mod tests {
-    use crate::crabstore::CrabStore;
+    use crate::{
+        archive,
+        config::CrabConfig,
+        crabstore::CrabStore,
+        disk_manager::DiskManager,
+        error::CrabError,
+        format::TableHeaderPage,
+        merge::{tail_base_rids, MergeConfig},
+        page::Page,
+        page_directory::PageDirectory,
+        range_directory::RangeDirectory,
+        table::Table,
+    };
+    use crate::{
+        METADATA_BASE_RID, METADATA_PAGE_HEADER, NUM_METADATA_COLUMNS, PAGE_RANGE_COUNT, PAGE_SIZE,
+        PAGE_SLOTS, RID_INVALID,
+    };
+    use rustc_hash::FxHashMap;
+    use std::{
+        fs,
+        iter::successors,
+        path::Path,
+        sync::{atomic::Ordering, Arc},
+        time::Duration,
+    };
     use tempfile::tempdir;
 
+    // What a corruption test calls it, and what it does to the file's bytes
+    type Corruption = (&'static str, fn(&mut Vec<u8>));
+
     #[test]
     fn open_close_db() {
         let dir = tempdir().expect("Failed to get temp directory");
         let mut db = CrabStore::new(dir.path().into());
-        db.open();
+        db.open().unwrap();
         db.close();
     }
 
@@ -61,7 +144,7 @@ mod tests {
     fn create_table() {
         let dir = tempdir().expect("Failed to get temp directory");
         let mut db = CrabStore::new(dir.path().into());
-        db.open();
+        db.open().unwrap();
         db.create_table("test_table", 2, 0);
         db.close();
     }
@@ -71,14 +154,14 @@ mod tests {
         let dir = tempdir().expect("Failed to get temp directory");
 
         let mut db = CrabStore::new(dir.path().into());
-        db.open();
+        db.open().unwrap();
 
         db.create_table("test_table", 2, 0);
         db.get_table("test_table");
 
         db.close();
 
-        db.open();
+        db.open().unwrap();
 
         db.get_table("test_table");
         assert_eq!(db.get_table("test_table").columns(), 2);
@@ -91,19 +174,582 @@ mod tests {
         let dir = tempdir().expect("Failed to get temp directory");
 
         let mut db = CrabStore::new(dir.path().into());
-        db.open();
+        db.open().unwrap();
         let table1 = db.create_table("test_table", 2, 0);
         let table2 = db.get_table("test_table");
-        table1.insert_query(&[1, 2], None);
-        table2.insert_query(&[3, 4], None);
+        table1.insert_query(&[1, 2], None).unwrap();
+        table2.insert_query(&[3, 4], None).unwrap();
         assert_eq!(
-            table1.select_query(1, 0, &[1, 1], None),
-            table2.select_query(1, 0, &[1, 1], None)
+            table1.select_query(1, 0, &[1, 1], None).unwrap(),
+            table2.select_query(1, 0, &[1, 1], None).unwrap()
         );
         assert_eq!(
-            table1.select_query(2, 0, &[1, 1], None),
-            table2.select_query(2, 0, &[1, 1], None)
+            table1.select_query(2, 0, &[1, 1], None).unwrap(),
+            table2.select_query(2, 0, &[1, 1], None).unwrap()
         );
         db.close();
     }
+
+    const CONSISTENCY_RECORDS: u64 = 600;
+
+    fn populate_grades(dir: &Path) {
+        let mut db = CrabStore::new(dir.into());
+        db.open().unwrap();
+        let grades = db.create_table("Grades", 2, 0);
+
+        for key in 0..CONSISTENCY_RECORDS {
+            grades.insert_query(&[key, key], None).unwrap();
+        }
+
+        // Enough updates to fill the first tail page of the range
+        for key in 0..CONSISTENCY_RECORDS {
+            grades
+                .update_query(key, &[None, Some(key + 1)], None)
+                .unwrap();
+        }
+
+        db.close();
+    }
+
+    fn tamper_header(dir: &Path, tamper: impl Fn(&mut TableHeaderPage)) {
+        let path = CrabStore::table_filename(dir, "Grades");
+        let copy_path = CrabStore::header_filename(dir, "Grades");
+        let disk = DiskManager::new(&path).unwrap();
+        let mut header = TableHeaderPage::read(&disk, &path, &copy_path).unwrap();
+        tamper(&mut header);
+        header.write(&disk, &copy_path).unwrap();
+    }
+
+    fn assert_open_strict_fails(dir: &Path) {
+        let mut db = CrabStore::with_config(
+            dir.into(),
+            CrabConfig {
+                strict: true,
+                ..Default::default()
+            },
+        );
+        assert!(matches!(db.open(), Err(CrabError::InconsistentTable(_))));
+    }
+
+    /*
+        Repaired tables must keep old rows intact while allocating new rids and tids
+    */
+    fn assert_repaired(dir: &Path) {
+        let mut db = CrabStore::new(dir.into());
+        db.open().unwrap();
+        let grades = db.get_table("Grades");
+
+        grades
+            .insert_query(&[CONSISTENCY_RECORDS, CONSISTENCY_RECORDS], None)
+            .unwrap();
+
+        for key in 0..CONSISTENCY_RECORDS {
+            grades
+                .update_query(key, &[None, Some(key + 2)], None)
+                .unwrap();
+        }
+
+        for key in 0..CONSISTENCY_RECORDS {
+            let selected = grades.select_query(key, 0, &[1, 1], None).unwrap();
+            assert_eq!(selected[0].columns, [key, key + 2]);
+        }
+
+        let selected = grades
+            .select_query(CONSISTENCY_RECORDS, 0, &[1, 1], None)
+            .unwrap();
+        assert_eq!(
+            selected[0].columns,
+            [CONSISTENCY_RECORDS, CONSISTENCY_RECORDS]
+        );
+
+        db.close();
+    }
+
+    #[test]
+    fn load_repairs_stale_next_rid() {
+        let dir = tempdir().expect("Failed to get temp directory");
+        populate_grades(dir.path());
+        tamper_header(dir.path(), |header| header.next_rid = 10);
+
+        assert_open_strict_fails(dir.path());
+        assert_repaired(dir.path());
+    }
+
+    #[test]
+    fn load_repairs_stale_next_tid() {
+        let dir = tempdir().expect("Failed to get temp directory");
+        populate_grades(dir.path());
+        tamper_header(dir.path(), |header| header.next_tid = !0 - 1);
+
+        assert_open_strict_fails(dir.path());
+        assert_repaired(dir.path());
+    }
+
+    #[test]
+    fn load_repairs_stale_free_page_pointer() {
+        let dir = tempdir().expect("Failed to get temp directory");
+        populate_grades(dir.path());
+        tamper_header(dir.path(), |header| header.next_free_page = 1);
+
+        assert_open_strict_fails(dir.path());
+        assert_repaired(dir.path());
+    }
+
+    #[test]
+    fn load_rejects_other_page_size() {
+        let dir = tempdir().expect("Failed to get temp directory");
+        populate_grades(dir.path());
+        tamper_header(dir.path(), |header| header.page_size = 2 * PAGE_SIZE);
+
+        let mut db = CrabStore::new(dir.path().into());
+        assert!(matches!(db.open(), Err(CrabError::CorruptFile { .. })));
+    }
+
+    #[test]
+    fn load_repairs_missing_range_tail() {
+        let dir = tempdir().expect("Failed to get temp directory");
+        populate_grades(dir.path());
+
+        let range_dir =
+            RangeDirectory::load(&CrabStore::range_filename(dir.path(), "Grades")).unwrap();
+        let range = range_dir.get(0);
+        let tail = range.current_tail_page.load(Ordering::Relaxed);
+        range.current_tail_page.store(tail - 5, Ordering::Relaxed);
+        range_dir.persist().unwrap();
+
+        assert_open_strict_fails(dir.path());
+
+        let mut db = CrabStore::new(dir.path().into());
+        db.open().unwrap();
+        db.close();
+
+        let range_dir =
+            RangeDirectory::load(&CrabStore::range_filename(dir.path(), "Grades")).unwrap();
+        let page_dir = PageDirectory::load(
+            &CrabStore::page_dir_filename(dir.path(), "Grades"),
+            PAGE_RANGE_COUNT,
+        )
+        .unwrap();
+        let tail = range_dir.get(0).current_tail_page.load(Ordering::Relaxed);
+        assert!(page_dir.get_page(tail).is_some());
+
+        assert_repaired(dir.path());
+    }
+
+    #[test]
+    fn load_repairs_unreadable_tps() {
+        let dir = tempdir().expect("Failed to get temp directory");
+        populate_grades(dir.path());
+
+        let page_dir = PageDirectory::load(
+            &CrabStore::page_dir_filename(dir.path(), "Grades"),
+            PAGE_RANGE_COUNT,
+        )
+        .unwrap();
+        let tps_page = page_dir.get_page(0).unwrap()[METADATA_PAGE_HEADER];
+
+        // What a header page that never made it to disk reads as
+        corrupt_file(&CrabStore::table_filename(dir.path(), "Grades"), |bytes| {
+            bytes[tps_page * PAGE_SIZE..][..8].fill(0);
+        });
+
+        assert_open_strict_fails(dir.path());
+        assert_repaired(dir.path());
+    }
+
+    #[test]
+    fn page_directory_reads_single_map_format() {
+        let dir = tempdir().expect("Failed to get temp directory");
+        populate_grades(dir.path());
+
+        let path = CrabStore::page_dir_filename(dir.path(), "Grades");
+        let mut pages = PageDirectory::load(&path, PAGE_RANGE_COUNT)
+            .unwrap()
+            .pages()
+            .collect::<Vec<_>>();
+        pages.sort_by_key(|x| x.0);
+
+        // Written the way directories were before they were sharded
+        let single: FxHashMap<usize, Arc<[usize]>> = pages.iter().cloned().collect();
+        archive::persist(&path, &single);
+
+        let mut reloaded = PageDirectory::load(&path, PAGE_RANGE_COUNT)
+            .unwrap()
+            .pages()
+            .collect::<Vec<_>>();
+        reloaded.sort_by_key(|x| x.0);
+        assert_eq!(reloaded, pages);
+
+        let mut db = CrabStore::new(dir.path().into());
+        db.open().unwrap();
+        let grades = db.get_table("Grades");
+        for key in 0..CONSISTENCY_RECORDS {
+            let record = grades.select_query(key, 0, &[1, 1], None).unwrap();
+            assert_eq!(record[0].columns, [key, key + 1]);
+        }
+        db.close();
+
+        // Persisting again switches to the sharded layout, which reads back the same
+        let mut sharded = PageDirectory::load(&path, PAGE_RANGE_COUNT)
+            .unwrap()
+            .pages()
+            .collect::<Vec<_>>();
+        sharded.sort_by_key(|x| x.0);
+        assert_eq!(sharded, pages);
+    }
+
+    #[test]
+    fn load_consistent_table_strict() {
+        let dir = tempdir().expect("Failed to get temp directory");
+        populate_grades(dir.path());
+
+        let mut db = CrabStore::with_config(
+            dir.path().into(),
+            CrabConfig {
+                strict: true,
+                ..Default::default()
+            },
+        );
+        db.open().unwrap();
+        db.close();
+    }
+
+    fn copy_database(from: &Path, to: &Path) {
+        for entry in fs::read_dir(from).unwrap() {
+            let entry = entry.unwrap();
+            fs::copy(entry.path(), to.join(entry.file_name())).unwrap();
+        }
+    }
+
+    fn corrupt_file(path: &Path, corrupt: impl Fn(&mut Vec<u8>)) {
+        let mut bytes = fs::read(path).unwrap();
+        corrupt(&mut bytes);
+        fs::write(path, bytes).unwrap();
+    }
+
+    #[test]
+    fn load_rejects_corrupt_files() {
+        let original = tempdir().expect("Failed to get temp directory");
+        populate_grades(original.path());
+
+        let files = [
+            CrabStore::database_filename(original.path()),
+            CrabStore::page_dir_filename(original.path(), "Grades"),
+            CrabStore::range_filename(original.path(), "Grades"),
+            CrabStore::index_filename(original.path(), "Grades"),
+        ];
+
+        let corruptions: [Corruption; 3] = [
+            ("truncated", |bytes| bytes.truncate(bytes.len() / 2)),
+            ("zero-filled", |bytes| bytes.fill(0)),
+            ("bit-flipped", |bytes| {
+                let middle = bytes.len() / 2;
+                bytes[middle] ^= 0b100;
+            }),
+        ];
+
+        // The table header only occupies the start of the table file's first page. Its copy
+        // gets the same treatment, see load_falls_back_to_header_copy for when only one is bad
+        let header_corruptions: [Corruption; 3] = [
+            ("truncated", |bytes| bytes.truncate(20)),
+            ("zero-filled", |bytes| bytes[..PAGE_SIZE].fill(0)),
+            ("bit-flipped", |bytes| bytes[3] ^= 0b100),
+        ];
+
+        let cases = files
+            .iter()
+            .flat_map(|file| corruptions.iter().map(move |c| (file.clone(), *c)))
+            .chain(
+                header_corruptions
+                    .iter()
+                    .map(|c| (CrabStore::table_filename(original.path(), "Grades"), *c)),
+            );
+
+        for (file, (kind, corrupt)) in cases {
+            let dir = tempdir().expect("Failed to get temp directory");
+            copy_database(original.path(), dir.path());
+
+            let path = dir.path().join(file.file_name().unwrap());
+            corrupt_file(&path, corrupt);
+            if path == CrabStore::table_filename(dir.path(), "Grades") {
+                corrupt_file(&CrabStore::header_filename(dir.path(), "Grades"), corrupt);
+            }
+
+            let mut db = CrabStore::new(dir.path().into());
+            match db.open() {
+                Err(CrabError::CorruptFile { path: reported, .. }) => {
+                    assert_eq!(reported, path, "{kind} {path:?}")
+                }
+                other => panic!("{kind} {path:?} opened with {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn load_falls_back_to_header_copy() {
+        let original = tempdir().expect("Failed to get temp directory");
+        populate_grades(original.path());
+
+        // As if the last checkpoint tore page 0 part way through rewriting it
+        let zero_filled: fn(&mut Vec<u8>) = |bytes| bytes[..PAGE_SIZE].fill(0);
+        let bit_flipped: fn(&mut Vec<u8>) = |bytes| bytes[3] ^= 0b100;
+
+        for (kind, corrupt) in [("zero-filled", zero_filled), ("bit-flipped", bit_flipped)] {
+            let dir = tempdir().expect("Failed to get temp directory");
+            copy_database(original.path(), dir.path());
+            corrupt_file(&CrabStore::table_filename(dir.path(), "Grades"), corrupt);
+
+            let mut db = CrabStore::new(dir.path().into());
+            db.open().unwrap_or_else(|err| panic!("{kind}: {err}"));
+            let grades = db.get_table("Grades");
+            for key in [0, CONSISTENCY_RECORDS - 1] {
+                assert_eq!(
+                    grades.select_query(key, 0, &[1, 1], None).unwrap()[0].columns,
+                    [key, key + 1],
+                    "{kind}"
+                );
+            }
+            db.close();
+
+            // Closing wrote page 0 back whole
+            let path = CrabStore::table_filename(dir.path(), "Grades");
+            fs::remove_file(CrabStore::header_filename(dir.path(), "Grades")).unwrap();
+            let disk = DiskManager::new(&path).unwrap();
+            assert!(TableHeaderPage::read(&disk, &path, Path::new("")).is_ok());
+        }
+
+        // Without a copy to fall back to, the header is reported as it was
+        let dir = tempdir().expect("Failed to get temp directory");
+        copy_database(original.path(), dir.path());
+        let path = CrabStore::table_filename(dir.path(), "Grades");
+        corrupt_file(&path, bit_flipped);
+        fs::remove_file(CrabStore::header_filename(dir.path(), "Grades")).unwrap();
+
+        let mut db = CrabStore::new(dir.path().into());
+        match db.open() {
+            Err(CrabError::CorruptFile { path: reported, .. }) => assert_eq!(reported, path),
+            other => panic!("opened with {other:?}"),
+        }
+    }
+
+    #[test]
+    fn open_reports_every_corrupt_table() {
+        let dir = tempdir().expect("Failed to get temp directory");
+
+        let mut db = CrabStore::new(dir.path().into());
+        db.open().unwrap();
+        for name in ["First", "Second", "Third"] {
+            db.create_table(name, 2, 0)
+                .insert_query(&[1, 2], None)
+                .unwrap();
+        }
+        db.close();
+
+        for name in ["First", "Third"] {
+            corrupt_file(&CrabStore::range_filename(dir.path(), name), |bytes| {
+                bytes.truncate(3)
+            });
+        }
+
+        let err = db.open().unwrap_err();
+        let CrabError::OpenFailed(errors) = &err else {
+            panic!("expected every failure to be reported, got {err:?}");
+        };
+
+        assert_eq!(errors.len(), 2);
+        assert!(err.to_string().contains("First_rd.CRAB"));
+        assert!(err.to_string().contains("Third_rd.CRAB"));
+        assert_eq!(
+            db.get_table("Second")
+                .select_query(1, 0, &[1, 1], None)
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    /*
+        Tail pages of range 0, from the current one back to its first
+    */
+    fn tail_chain(table: &Table) -> Vec<usize> {
+        let bp = table.get_bufferpool();
+        let current = table
+            .data
+            .range_dir
+            .lock()
+            .get(0)
+            .current_tail_page
+            .load(Ordering::SeqCst);
+
+        successors(Some(current), |page_id| {
+            let last_tail = Page::new(table.data.page_dir.get_page(*page_id)?).read_last_tail(&bp);
+            (last_tail != RID_INVALID).then_some(last_tail as usize)
+        })
+        .collect()
+    }
+
+    #[test]
+    fn unmerged_range_walks_its_whole_tail_chain() {
+        let dir = tempdir().unwrap();
+        let mut db = CrabStore::with_config(
+            dir.path().into(),
+            CrabConfig {
+                manual_merge: true,
+                ..CrabConfig::default()
+            },
+        );
+        db.open().unwrap();
+        let table = db.create_table("chain", 2, 0);
+
+        for i in 0..16 {
+            table.insert_query(&[i, 0], None).unwrap();
+        }
+        for i in 0..512 * 3 + 1 {
+            table.update_query(i % 16, &[None, Some(i)], None).unwrap();
+        }
+
+        // Numbered down from the top of the TID space, far past the 0 merged_until starts at
+        let chain = tail_chain(&table);
+        assert_eq!(chain.len(), 4);
+        assert!(chain.iter().all(|page_id| *page_id > u32::MAX as usize));
+        let merged_until = || {
+            let ranges = table.data.range_dir.lock();
+            ranges.get(0).merged_until.load(Ordering::SeqCst)
+        };
+        assert_eq!(merged_until(), 0);
+
+        let bp = table.get_bufferpool();
+        let records =
+            |stop_at| tail_base_rids(&table.data.page_dir, &bp, chain[1], stop_at).count();
+        assert_eq!(records(0), 3 * PAGE_SLOTS);
+        assert_eq!(records(chain[3]), 2 * PAGE_SLOTS);
+
+        // Merging everything filled moves merged_until to the newest full page
+        assert!(table.force_merge(0).unwrap());
+        assert_eq!(merged_until(), chain[1]);
+        assert_eq!(records(chain[1]), 0);
+        assert!(!table.force_merge(0).unwrap());
+
+        db.close();
+    }
+
+    /*
+        Points every record written to the current tail page so far at a
+        base page that doesn't exist, which the merge of the page trips on
+    */
+    fn corrupt_current_tail_page(table: &Table) {
+        let bp = table.get_bufferpool();
+        let current = Page::new(table.data.page_dir.get_page(tail_chain(table)[0]).unwrap());
+        let base_rids = current.get_column(&bp, METADATA_BASE_RID);
+
+        for slot in 0..PAGE_SLOTS {
+            if base_rids.slot(slot) != RID_INVALID {
+                base_rids.write_slot(slot, (1000 * PAGE_SLOTS) as u64);
+            }
+        }
+    }
+
+    #[test]
+    fn failed_merges_are_recorded() {
+        let dir = tempdir().unwrap();
+        let mut db = CrabStore::with_config(
+            dir.path().into(),
+            CrabConfig {
+                manual_merge: true,
+                ..CrabConfig::default()
+            },
+        );
+        db.open().unwrap();
+        let table = db.create_table("failing", 2, 0);
+
+        for i in 0..16 {
+            table.insert_query(&[i, 0], None).unwrap();
+        }
+        let mut updates = 0;
+        let mut update = |count| {
+            for _ in 0..count {
+                table
+                    .update_query(updates % 16, &[None, Some(updates)], None)
+                    .unwrap();
+                updates += 1;
+            }
+        };
+
+        // The merge thread's merge of the second tail page panics, the next one still runs
+        update(PAGE_SLOTS as u64 + 1);
+        corrupt_current_tail_page(&table);
+        // So the first page's merge request is passed over before merges are on
+        table.quiesce(Duration::from_secs(10)).unwrap();
+        table.set_merge_config(MergeConfig {
+            tail_pages_per_merge: 1,
+            ..MergeConfig::default()
+        });
+        update(PAGE_SLOTS as u64);
+        table.quiesce(Duration::from_secs(10)).unwrap();
+
+        let err = table.merge_status().expect("merge didn't fail");
+        assert_eq!(err.range_id, 0);
+        assert!(err.reason.contains("non-existent page id"), "{err:?}");
+        assert_eq!(table.merge_stats().merges, 0);
+
+        update(PAGE_SLOTS as u64);
+        table.quiesce(Duration::from_secs(10)).unwrap();
+        assert_eq!(table.merge_stats().merges, 1);
+
+        // A forced merge reports it to the caller
+        table.set_merge_config(MergeConfig {
+            enabled: false,
+            ..table.merge_config()
+        });
+        corrupt_current_tail_page(&table);
+        update(PAGE_SLOTS as u64);
+        assert!(matches!(
+            table.force_merge(0),
+            Err(CrabError::MergeFailed { range_id: 0, .. })
+        ));
+
+        // Reads go through the indirection column, past whatever wasn't merged
+        for key in 0..16 {
+            let latest = table.select_query(key, 0, &[1, 1], None).unwrap();
+            let last_update = (0..updates).rev().find(|i| i % 16 == key).unwrap();
+            assert_eq!(latest[0].columns, [key, last_update]);
+        }
+
+        assert!(matches!(
+            table.persist(),
+            Err(CrabError::MergeFailed { range_id: 0, .. })
+        ));
+        db.close();
+    }
+    #[test]
+    fn table_data_undoes_writes_on_its_own() {
+        let dir = tempdir().unwrap();
+        let mut db = CrabStore::new(dir.path().into());
+        db.open().unwrap();
+        let table = db.create_table("undo", 2, 0);
+
+        table.insert_query(&[1, 10], None).unwrap();
+        table.insert_query(&[2, 20], None).unwrap();
+        let rid = table.locate(0, 2)[0];
+
+        // What a rolled back transaction reaches, with no query in between
+        let data = Arc::clone(&table.data);
+        data.undo_write(NUM_METADATA_COLUMNS + 1, rid, 21);
+        assert_eq!(data.materialize_latest(&[rid], &[1, 1])[0].columns, [2, 21]);
+        assert_eq!(
+            table.select_query(2, 0, &[1, 1], None).unwrap()[0].columns,
+            [2, 21]
+        );
+
+        data.undo_insert(rid);
+        assert!(!data.is_live(rid));
+        assert!(table.select_query(2, 0, &[1, 1], None).unwrap().is_empty());
+        assert!(table.locate(0, 2).is_empty());
+        assert_eq!(
+            table.select_query(1, 0, &[1, 1], None).unwrap()[0].columns,
+            [1, 10]
+        );
+
+        db.close();
+    }
 }
@@ -1,12 +1,17 @@
-use std::{collections::HashMap, hash::BuildHasherDefault};
+use std::{
+    collections::HashMap,
+    hash::BuildHasherDefault,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
 
 use parking_lot::{
-    lock_api::{RawRwLock, RawRwLockFair, RawRwLockRecursive, RawRwLockUpgrade},
-    Mutex, RwLock,
+    lock_api::{RawRwLock as _, RawRwLockFair},
+    Condvar, Mutex, RawRwLock,
 };
 use rustc_hash::{FxHashMap, FxHasher};
 
-use crate::rid::RID;
+use crate::{rid::RID, LOCK_MANAGER_SHARDS};
 
 #[derive(Copy, PartialEq, Clone, Eq, Debug)]
 pub enum LockType {
@@ -14,19 +19,130 @@ pub enum LockType {
     Exclusive,
 }
 
+/*
+    What a transaction does when a lock it needs is held by someone else
+*/
+#[derive(Copy, PartialEq, Clone, Eq, Debug, Default)]
+pub enum ConcurrencyPolicy {
+    // Abort (retryably) straight away
+    #[default]
+    NoWait,
+    // Keep retrying the lock, abort once the timeout passes
+    WaitWithTimeout(Duration),
+    // Older transactions wait for younger holders, younger ones abort
+    WaitDie,
+}
+
 pub struct LockHandle {
     pub rid: RID,
     pub lock_type: LockType,
+    // Timestamp of the transaction holding this lock
+    pub owner: u64,
+    // LockManager::id of the manager the lock is in, as every table has its own
+    pub manager: usize,
+}
+
+struct RecordLock {
+    lock: RawRwLock,
+    holders: Vec<u64>,
+    // Oldest transaction waiting for the lock under wait-die, u64::MAX if none
+    oldest_waiter: u64,
 }
 
-impl LockHandle {
-    fn new(rid: RID, lock_type: LockType) -> Self {
-        LockHandle { rid, lock_type }
+impl Default for RecordLock {
+    fn default() -> Self {
+        Self {
+            lock: RawRwLock::INIT,
+            holders: Vec::new(),
+            oldest_waiter: u64::MAX,
+        }
     }
 }
 
+impl RecordLock {
+    fn is_unused(&self) -> bool {
+        self.holders.is_empty() && self.oldest_waiter == u64::MAX
+    }
+
+    fn try_lock(&mut self, lock_type: LockType, owner: u64) -> Result<(), u64> {
+        let locked = match lock_type {
+            LockType::Shared => self.lock.try_lock_shared(),
+            LockType::Exclusive => self.lock.try_lock_exclusive(),
+        };
+
+        if !locked {
+            return Err(self.oldest_other(owner));
+        }
+
+        self.holders.push(owner);
+        Ok(())
+    }
+
+    // On failure the owner keeps its shared lock
+    fn try_upgrade(&self, owner: u64) -> Result<(), u64> {
+        let locked = unsafe {
+            self.lock.unlock_shared();
+            let locked = self.lock.try_lock_exclusive();
+
+            // Nobody else can take the lock while we hold its shard, so this can't fail
+            if !locked {
+                assert!(self.lock.try_lock_shared());
+            }
+
+            locked
+        };
+
+        match locked {
+            true => Ok(()),
+            false => Err(self.oldest_other(owner)),
+        }
+    }
+
+    // Oldest transaction holding the lock besides the requester, u64::MAX if none
+    fn oldest_other(&self, owner: u64) -> u64 {
+        self.holders
+            .iter()
+            .copied()
+            .filter(|holder| *holder != owner)
+            .min()
+            .unwrap_or(u64::MAX)
+    }
+}
+
+type Shard = FxHashMap<RID, RecordLock>;
+
+/*
+    A shard's records, and where wait-die waiters on any of them sleep until
+    one is unlocked
+*/
+struct ShardLocks {
+    records: Mutex<Shard>,
+    released: Condvar,
+}
+
+/*
+    Drops the record's entry once nobody holds or waits on it. Everyone reaches
+    an entry through its shard's mutex, which the caller holds, so nobody can be
+    about to lock it
+*/
+fn remove_if_unused(shard: &mut Shard, rid: RID) {
+    if shard.get(&rid).is_some_and(RecordLock::is_unused) {
+        shard.remove(&rid);
+    }
+}
+
+/*
+    Every lock is taken and released under its shard's mutex, so nobody parks on
+    a record lock itself. Waiting means retrying, either from the transaction, or
+    in LockManager::lock under wait-die after sleeping until a lock in the shard
+    is released. Records are spread across the shards by RID, so transactions on
+    different rows rarely share a mutex. A record only has an entry while someone
+    holds or waits on its lock.
+*/
 pub struct LockManager {
-    locks: Mutex<FxHashMap<RID, RwLock<()>>>,
+    id: usize,
+    shards: Box<[ShardLocks]>,
+    policy: Mutex<ConcurrencyPolicy>,
 }
 
 impl Default for LockManager {
@@ -35,66 +151,241 @@ impl Default for LockManager {
     }
 }
 
+// Where LockManager ids come from
+static NEXT_LOCK_MANAGER_ID: AtomicUsize = AtomicUsize::new(0);
+
 impl LockManager {
     pub fn new() -> Self {
         Self {
-            locks: Mutex::new(FxHashMap::with_capacity_and_hasher(
-                4096,
-                BuildHasherDefault::<FxHasher>::default(),
-            )),
+            id: NEXT_LOCK_MANAGER_ID.fetch_add(1, Ordering::Relaxed),
+            shards: (0..LOCK_MANAGER_SHARDS)
+                .map(|_| ShardLocks {
+                    records: Mutex::new(FxHashMap::with_capacity_and_hasher(
+                        4096 / LOCK_MANAGER_SHARDS,
+                        BuildHasherDefault::<FxHasher>::default(),
+                    )),
+                    released: Condvar::new(),
+                })
+                .collect(),
+            policy: Mutex::new(ConcurrencyPolicy::default()),
         }
     }
 
-    pub fn upgrade_shared(&self, handle: &mut LockHandle) -> bool {
-        let guard = self.locks.lock();
-        let lock = guard.get(&handle.rid).unwrap();
-        let locked = unsafe {
-            lock.raw().unlock_shared();
-            lock.raw().try_lock_exclusive()
-        };
+    /*
+        Tells this manager's locks apart from other tables' on the same RIDs, no
+        two managers have the same one
+    */
+    pub fn id(&self) -> usize {
+        self.id
+    }
 
-        if locked {
-            handle.lock_type = LockType::Exclusive;
+    /*
+        Releasing or upgrading another manager's lock would unlock a record lock
+        nobody holds here
+    */
+    fn check_owns(&self, handle: &LockHandle) {
+        assert_eq!(
+            handle.manager, self.id,
+            "Lock on {:?} belongs to another lock manager",
+            handle.rid
+        );
+    }
+
+    fn handle(&self, rid: RID, lock_type: LockType, owner: u64) -> LockHandle {
+        LockHandle {
+            rid,
+            lock_type,
+            owner,
+            manager: self.id,
         }
+    }
 
-        locked
+    // Base RIDs are handed out in order, so neighbouring rows land in different shards
+    fn shard(&self, rid: RID) -> &ShardLocks {
+        &self.shards[rid.raw() as usize % self.shards.len()]
     }
 
-    pub fn try_lock(&self, rid: RID, lock_type: LockType) -> Option<LockHandle> {
-        let mut guard = self.locks.lock();
-        let lock = guard.entry(rid).or_insert(RwLock::new(()));
+    pub fn policy(&self) -> ConcurrencyPolicy {
+        *self.policy.lock()
+    }
 
-        unsafe {
-            match lock_type {
-                LockType::Shared => {
-                    if !lock.raw().try_lock_shared() {
-                        None
-                    } else {
-                        Some(LockHandle::new(rid, lock_type))
-                    }
+    pub fn set_policy(&self, policy: ConcurrencyPolicy) {
+        *self.policy.lock() = policy;
+    }
+
+    /*
+        On failure the handle keeps its shared lock, and the oldest other holder is returned
+    */
+    pub fn upgrade_shared(&self, handle: &mut LockHandle) -> Result<(), u64> {
+        self.check_owns(handle);
+        let guard = self.shard(handle.rid).records.lock();
+        guard.get(&handle.rid).unwrap().try_upgrade(handle.owner)?;
+
+        handle.lock_type = LockType::Exclusive;
+        Ok(())
+    }
+
+    /*
+        On failure returns the timestamp of the oldest transaction holding the lock
+    */
+    pub fn try_lock(&self, rid: RID, lock_type: LockType, owner: u64) -> Result<LockHandle, u64> {
+        let mut guard = self.shard(rid).records.lock();
+        if let Err(oldest_holder) = guard.entry(rid).or_default().try_lock(lock_type, owner) {
+            remove_if_unused(&mut guard, rid);
+            return Err(oldest_holder);
+        }
+
+        Ok(self.handle(rid, lock_type, owner))
+    }
+
+    /*
+        try_lock under wait-die: while the lock is held, an owner older than every
+        other holder sleeps until a lock in its shard is released and retries, until
+        the timeout passes, and a younger one gives up straight away. Only older
+        transactions ever wait on younger ones, so no two can end up waiting on each
+        other.
+
+        A waiting owner also keeps the lock from younger ones, even when they could
+        share it. Otherwise a younger reader that keeps dying and coming back could
+        hold on to it for good, and an older one waiting to upgrade would never get in
+    */
+    pub fn lock(
+        &self,
+        rid: RID,
+        lock_type: LockType,
+        owner: u64,
+        timeout: Duration,
+    ) -> Result<LockHandle, u64> {
+        self.wait_die(rid, owner, timeout, |entry| {
+            entry.try_lock(lock_type, owner)
+        })?;
+
+        Ok(self.handle(rid, lock_type, owner))
+    }
+
+    /*
+        upgrade_shared under wait-die, see LockManager::lock
+    */
+    pub fn upgrade(&self, handle: &mut LockHandle, timeout: Duration) -> Result<(), u64> {
+        self.check_owns(handle);
+        let owner = handle.owner;
+        self.wait_die(handle.rid, owner, timeout, |entry| entry.try_upgrade(owner))?;
+
+        handle.lock_type = LockType::Exclusive;
+        Ok(())
+    }
+
+    fn wait_die(
+        &self,
+        rid: RID,
+        owner: u64,
+        timeout: Duration,
+        mut attempt: impl FnMut(&mut RecordLock) -> Result<(), u64>,
+    ) -> Result<(), u64> {
+        let deadline = Instant::now() + timeout;
+        let shard = self.shard(rid);
+        let mut guard = shard.records.lock();
+
+        loop {
+            let entry = guard.entry(rid).or_default();
+
+            let result = match entry.oldest_waiter {
+                waiter if waiter < owner => Err(waiter),
+                _ => attempt(entry),
+            };
+
+            match result {
+                Err(oldest) if owner < oldest && Instant::now() < deadline => {
+                    entry.oldest_waiter = owner;
+                    shard.released.wait_until(&mut guard, deadline);
                 }
-                LockType::Exclusive => {
-                    if !lock.raw().try_lock_exclusive() {
-                        None
-                    } else {
-                        Some(LockHandle::new(rid, lock_type))
+                result => {
+                    if entry.oldest_waiter == owner {
+                        entry.oldest_waiter = u64::MAX;
                     }
+                    remove_if_unused(&mut guard, rid);
+                    return result;
                 }
             }
         }
     }
 
+    /*
+        Whether any transaction holds a lock on the record right now
+    */
+    pub fn is_locked(&self, rid: RID) -> bool {
+        self.shard(rid)
+            .records
+            .lock()
+            .get(&rid)
+            .is_some_and(|entry| !entry.holders.is_empty())
+    }
+
+    /*
+        Whether a transaction holds the record's lock exclusively, as one that wrote
+        to it does until it commits or rolls back
+    */
+    pub fn is_locked_exclusive(&self, rid: RID) -> bool {
+        self.shard(rid)
+            .records
+            .lock()
+            .get(&rid)
+            .is_some_and(|entry| entry.lock.is_locked_exclusive())
+    }
+
+    /*
+        Records someone holds or waits on a lock of
+    */
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.records.lock().len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /*
+        Whether no transaction holds a lock on any record
+    */
+    pub fn is_idle(&self) -> bool {
+        self.shards.iter().all(|shard| {
+            shard
+                .records
+                .lock()
+                .values()
+                .all(|entry| entry.holders.is_empty())
+        })
+    }
+
     pub fn unlock(&self, lock_handle: &LockHandle) {
-        let guard = self.locks.lock();
-        let lock = guard
-            .get(&lock_handle.rid)
+        self.check_owns(lock_handle);
+        let shard = self.shard(lock_handle.rid);
+        let mut guard = shard.records.lock();
+        let entry = guard
+            .get_mut(&lock_handle.rid)
             .expect("Invalid unlock requested from Lock Manager");
 
+        if let Some(pos) = entry
+            .holders
+            .iter()
+            .position(|holder| *holder == lock_handle.owner)
+        {
+            entry.holders.swap_remove(pos);
+        }
+
         unsafe {
             match lock_handle.lock_type {
-                LockType::Shared => lock.raw().unlock_shared_fair(),
-                LockType::Exclusive => lock.raw().unlock_exclusive_fair(),
+                LockType::Shared => entry.lock.unlock_shared_fair(),
+                LockType::Exclusive => entry.lock.unlock_exclusive_fair(),
             }
         }
+
+        remove_if_unused(&mut guard, lock_handle.rid);
+
+        // Waiters on any of the shard's records share the condvar
+        shard.released.notify_all();
     }
 }
@@ -1,207 +1,855 @@
 use std::{
     hash::BuildHasherDefault,
+    iter::successors,
+    panic::{self, AssertUnwindSafe},
     sync::{
-        atomic::Ordering,
-        mpsc::{channel, Sender},
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{channel, Receiver, Sender},
         Arc,
     },
     thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
 use parking_lot::{Mutex, RwLock};
 use rustc_hash::{FxHashMap, FxHashSet, FxHasher};
 
 use crate::{
-    bufferpool::BufferPool, disk_manager::DiskManager, page::Page, page_directory::PageDirectory,
-    range_directory::RangeDirectory, rid::RID, table::Table, METADATA_BASE_RID,
-    METADATA_INDIRECTION, METADATA_RID, NUM_METADATA_COLUMNS, NUM_STATIC_COLUMNS, PAGE_RANGE_COUNT,
-    PAGE_SLOTS, RID_INVALID,
+    bufferpool::BufferPool,
+    disk_manager::DiskManager,
+    error::CrabError,
+    format::PageLayout,
+    index::{Index, RecordChange},
+    lock_manager::LockManager,
+    page::Page,
+    page_directory::PageDirectory,
+    range_directory::RangeDirectory,
+    rid::RID,
+    MERGE_THREADS, MERGE_THRESHOLD, METADATA_BASE_RID, METADATA_INDIRECTION, METADATA_RID,
+    METADATA_SCHEMA_ENCODING, NUM_METADATA_COLUMNS, NUM_STATIC_COLUMNS, PAGE_SLOTS, RID_INVALID,
 };
 
-impl Table {
-    pub fn spawn_merge_thread(
-        page_directory: &Arc<RwLock<PageDirectory>>,
-        range_directory: &Arc<Mutex<RangeDirectory>>,
-        disk_manager: &Arc<DiskManager>,
-        main_bufferpool: &Arc<Mutex<BufferPool>>,
-        num_columns: usize,
-    ) -> (JoinHandle<()>, Sender<usize>) {
-        let page_dir_clone = Arc::clone(page_directory);
-        let disk_manager_clone = Arc::clone(disk_manager);
-        let range_dir_clone = Arc::clone(range_directory);
-        let main_bp_clone = Arc::clone(main_bufferpool);
-        let (send, recv) = channel();
-        let handle = thread::spawn(move || {
-            let num_columns = num_columns;
-            let main_bufferpool = main_bp_clone;
-            let page_dir = page_dir_clone;
-            let range_dir = range_dir_clone;
-            let disk = disk_manager_clone;
-            let recv = recv;
-            let mut seen: FxHashSet<u64> = FxHashSet::with_capacity_and_hasher(
-                PAGE_SLOTS * PAGE_RANGE_COUNT,
-                BuildHasherDefault::<FxHasher>::default(),
-            );
-            let mut merged: FxHashMap<usize, Arc<[usize]>> = FxHashMap::with_capacity_and_hasher(
-                PAGE_SLOTS * PAGE_RANGE_COUNT,
-                BuildHasherDefault::<FxHasher>::default(),
-            );
-            let mut rangecounts: FxHashMap<usize, usize> = FxHashMap::with_capacity_and_hasher(
-                PAGE_SLOTS * PAGE_RANGE_COUNT,
-                BuildHasherDefault::<FxHasher>::default(),
-            );
-
-            loop {
-                let merge_range = loop {
-                    let range_update = recv.recv();
+/*
+    Work done merging a table since it opened or the last Table::reset_stats.
+*/
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MergeStats {
+    pub merges: usize,
+    // Tail records folded into base pages, older versions of the same row skipped
+    pub tail_records: usize,
+    pub base_pages: usize,
+    // Deleted rows cleared out of merged base pages, see compact_deleted
+    pub compacted_rows: usize,
+    // Copies of base pages plus the tail values folded into them
+    pub slots_written: usize,
+    pub total_time: Duration,
+    // Merged tail pages checkpoints gave back, see MergeConfig::retained_tail_pages
+    pub reclaimed_tail_pages: usize,
+}
 
-                    if range_update.is_err() {
-                        return;
-                    }
+/*
+    One finished merge, passed to the hook set with Table::on_merge_complete.
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MergeReport {
+    pub range_id: usize,
+    // Distinct base rows the merged tail pages updated
+    pub tail_records: usize,
+    pub base_pages: usize,
+    pub compacted_rows: usize,
+    pub slots_written: usize,
+    pub time: Duration,
+}
 
-                    let range_update: usize = range_update.unwrap();
+/*
+    Called on whichever thread ran the merge, with the merge lock still held
+*/
+pub(crate) type MergeHook = Box<dyn Fn(&MergeReport) + Send>;
+
+/*
+    A merge that panicked partway, see Table::merge_status.
+*/
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MergeError {
+    pub range_id: usize,
+    pub reason: String,
+}
 
-                    *rangecounts.entry(range_update).or_default() += 1;
+impl From<MergeError> for CrabError {
+    fn from(err: MergeError) -> Self {
+        CrabError::MergeFailed {
+            range_id: err.range_id,
+            reason: err.reason,
+        }
+    }
+}
 
-                    if *rangecounts.get(&range_update).unwrap() >= 4 {
-                        *rangecounts.get_mut(&range_update).unwrap() = 0;
-                        break range_update;
-                    }
-                };
+/*
+    When a table's ranges are merged, see Table::set_merge_config.
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MergeConfig {
+    // Filled tail pages a range waits for before the merge thread merges it.
+    pub tail_pages_per_merge: usize,
+    // Off, ranges are only merged by Table::force_merge.
+    pub enabled: bool,
+    // Merged tail pages each range keeps, newest first, for version queries.
+    // Checkpoints reclaim the rest, after which versions only they held fail with
+    // CrabError::VersionUnavailable. None keeps every tail page.
+    pub retained_tail_pages: Option<usize>,
+}
 
-                //println!("Merge request received for range {merge_range}");
+impl Default for MergeConfig {
+    fn default() -> Self {
+        MergeConfig {
+            tail_pages_per_merge: MERGE_THRESHOLD,
+            enabled: true,
+            retained_tail_pages: None,
+        }
+    }
+}
 
-                let range_dir = range_dir.lock();
-                let range = range_dir.get(merge_range);
-                let merge_from = range.current_tail_page.load(Ordering::SeqCst);
+/*
+    Everything the merge thread shares with its table
+*/
+pub(crate) struct MergeTarget {
+    pub page_dir: Arc<PageDirectory>,
+    pub range_dir: Arc<Mutex<RangeDirectory>>,
+    pub disk: Arc<DiskManager>,
+    pub bufferpool: Arc<BufferPool>,
+    pub index: Arc<RwLock<Index>>,
+    pub lock_manager: Arc<LockManager>,
+    pub checkpoint_lock: Arc<RwLock<()>>,
+    pub stats: Arc<Mutex<MergeStats>>,
+    pub num_columns: usize,
+    pub page_layout: PageLayout,
+    pub range_pages: usize,
+    pub config: Arc<Mutex<MergeConfig>>,
+    // Held for each merge, so a forced one never runs alongside the thread's
+    pub merge_lock: Arc<Mutex<()>>,
+    // See Table::base_writes
+    pub base_writes: Arc<RwLock<()>>,
+    // The last merge that failed, see Table::merge_status
+    pub status: Arc<Mutex<Option<MergeError>>>,
+    // See Table::on_merge_complete
+    pub hook: Arc<Mutex<Option<MergeHook>>>,
+}
 
-                let last_page = Page::new(
-                    page_dir
-                        .read()
-                        .get_page(merge_from)
-                        .expect("Bad page ID for Page Range encountered in merge"),
-                )
-                .read_last_tail(&mut main_bufferpool.lock())
-                    as usize;
+/*
+    What a scheduler's worker is sent, with the id of the table it's for. A
+    table's requests all go to the same worker, which handles them in order
+    and finishes a merge before reading the next.
+*/
+pub(crate) enum MergeRequest {
+    // Sent before anything else for the table
+    Register(Box<MergeTarget>),
+    // Another tail page of the range filled up
+    Range(usize),
+    // Answered once everything sent before it is done, see Table::quiesce
+    Quiesce(Sender<()>),
+    // The table's merges stop, answered once the ones sent before are done
+    Deregister(Option<Sender<()>>),
+}
 
-                let merge_stop_at = range.merged_until.load(Ordering::SeqCst);
+// A worker thread, and where its requests are sent with their table's id
+type MergeWorker = (JoinHandle<()>, Sender<(usize, MergeRequest)>);
+
+/*
+    The threads that merge the ranges of every table a CrabStore opens, see
+    CrabConfig::merge_threads. Each table is merged by one of them, so a table's
+    merges run one at a time while other tables' run alongside.
+*/
+pub struct MergeScheduler {
+    threads: usize,
+    next_table_id: AtomicUsize,
+    // Started as tables are registered, the nth table goes to worker n % threads
+    workers: Mutex<Vec<MergeWorker>>,
+}
 
-                range.merged_until.store(last_page, Ordering::SeqCst);
+impl MergeScheduler {
+    pub fn new(threads: usize) -> Self {
+        MergeScheduler {
+            threads: threads.max(1),
+            next_table_id: AtomicUsize::new(0),
+            workers: Mutex::new(Vec::new()),
+        }
+    }
 
-                drop(range_dir);
+    /*
+        Workers started so far, at most the threads it was made with.
+    */
+    pub fn running_threads(&self) -> usize {
+        self.workers.lock().len()
+    }
 
-                let mut tail_page_id = last_page;
+    pub(crate) fn register(self: &Arc<Self>, target: MergeTarget) -> MergeRegistration {
+        let table_id = self.next_table_id.fetch_add(1, Ordering::Relaxed);
+        let worker = table_id % self.threads;
 
-                while tail_page_id > merge_stop_at && tail_page_id != RID_INVALID as usize {
-                    let tail_page = Page::new(
-                        page_dir
-                            .read()
-                            .get_page(tail_page_id)
-                            .expect("Bad page ID for Page Range encountered in merge"),
-                    );
+        let send = {
+            let mut workers = self.workers.lock();
+            while workers.len() <= worker {
+                workers.push(MergeScheduler::spawn_worker());
+            }
+            workers[worker].1.clone()
+        };
+        send.send((table_id, MergeRequest::Register(Box::new(target))))
+            .expect("Unable to register table with merge worker");
+
+        MergeRegistration {
+            table_id,
+            send,
+            closed: false,
+            _scheduler: Arc::clone(self),
+        }
+    }
 
-                    for tail_slot in (0..PAGE_SLOTS).rev() {
-                        let base_rid = tail_page
-                            .get_column(&mut main_bufferpool.lock(), METADATA_BASE_RID)
-                            .slot(tail_slot);
+    fn spawn_worker() -> MergeWorker {
+        let (send, recv) = channel::<(usize, MergeRequest)>();
+        let handle = thread::spawn(move || {
+            // A merge reads each page it touches once, it shouldn't push the queries' pages out
+            BufferPool::scan_thread();
 
-                        assert!(base_rid != RID_INVALID);
+            let mut targets: FxHashMap<usize, MergeTarget> = FxHashMap::default();
+            let mut rangecounts: FxHashMap<(usize, usize), usize> = FxHashMap::default();
 
-                        if seen.contains(&base_rid) {
-                            continue;
+            while let Ok((table_id, request)) = recv.recv() {
+                let range_update = match request {
+                    MergeRequest::Register(target) => {
+                        targets.insert(table_id, *target);
+                        continue;
+                    }
+                    MergeRequest::Range(range_update) => range_update,
+                    MergeRequest::Quiesce(done) => {
+                        // The quiescer may have given up waiting already
+                        let _ = done.send(());
+                        continue;
+                    }
+                    MergeRequest::Deregister(done) => {
+                        // Lets go of the table's bufferpool and files
+                        targets.remove(&table_id);
+                        rangecounts.retain(|&(table, _), _| table != table_id);
+                        if let Some(done) = done {
+                            let _ = done.send(());
                         }
+                        continue;
+                    }
+                };
 
-                        seen.insert(base_rid);
+                let Some(target) = targets.get(&table_id) else {
+                    continue;
+                };
 
-                        let base_page_id = RID(base_rid).page();
+                let config = *target.config.lock();
+                if !config.enabled {
+                    continue;
+                }
 
-                        let merged_page = Page::new(Arc::clone(
-                            merged.entry(base_page_id).or_insert_with(|| {
-                                let mut new_page_dir_entry =
-                                    Arc::new_uninit_slice(NUM_METADATA_COLUMNS + num_columns);
+                let count = rangecounts.entry((table_id, range_update)).or_default();
+                *count += 1;
 
-                                let page_dir = page_dir.read();
+                if *count >= config.tail_pages_per_merge {
+                    *count = 0;
+                    // Recorded in status, the worker carries on with the next request
+                    let _ = target.run_merge(range_update);
+                }
+            }
+        });
 
-                                let base_cols = page_dir
-                                    .get_page(base_page_id)
-                                    .expect("Merge thread tried to access a non-existent page id");
+        (handle, send)
+    }
+}
 
-                                drop(page_dir);
+impl Default for MergeScheduler {
+    fn default() -> Self {
+        MergeScheduler::new(MERGE_THREADS)
+    }
+}
 
-                                let new_page = Arc::get_mut(&mut new_page_dir_entry).unwrap();
-                                new_page[METADATA_INDIRECTION]
-                                    .write(base_cols[METADATA_INDIRECTION]);
-                                new_page[METADATA_BASE_RID].write(base_cols[METADATA_BASE_RID]);
-                                new_page[METADATA_RID].write(base_cols[METADATA_RID]);
+/*
+    Workers stop once every table's registration is gone, which each holds
+    the scheduler for
+*/
+impl Drop for MergeScheduler {
+    fn drop(&mut self) {
+        for (handle, send) in self.workers.get_mut().drain(..) {
+            drop(send);
+            // A merge hook holding the last table lets go of it on the worker itself
+            if handle.thread().id() != thread::current().id() {
+                handle.join().expect("Failed to join merge worker");
+            }
+        }
+    }
+}
 
-                                let mut new_column_ids = disk.reserve_range(
-                                    NUM_METADATA_COLUMNS - NUM_STATIC_COLUMNS + num_columns,
-                                );
+/*
+    A table's place with its scheduler's worker. Dropped without close, as a
+    table never persisted is, the worker lets go of the table once it gets to
+    the request rather than being waited for
+*/
+pub(crate) struct MergeRegistration {
+    table_id: usize,
+    send: Sender<(usize, MergeRequest)>,
+    closed: bool,
+    _scheduler: Arc<MergeScheduler>,
+}
 
-                                for i in NUM_STATIC_COLUMNS..(NUM_METADATA_COLUMNS + num_columns) {
-                                    new_page[i].write(new_column_ids);
-                                    new_column_ids += 1;
-                                }
+impl MergeRegistration {
+    pub(crate) fn submit(&self, range_id: usize) {
+        self.send
+            .send((self.table_id, MergeRequest::Range(range_id)))
+            .expect("Unable to send range id to merge worker");
+    }
 
-                                let new_page_dir_entry =
-                                    unsafe { new_page_dir_entry.assume_init() };
+    pub(crate) fn quiesce(&self) -> Option<Receiver<()>> {
+        let (done, merged) = channel();
+        self.send
+            .send((self.table_id, MergeRequest::Quiesce(done)))
+            .ok()?;
+        Some(merged)
+    }
 
-                                let bp = &mut main_bufferpool.lock();
-                                for i in NUM_STATIC_COLUMNS..(NUM_METADATA_COLUMNS + num_columns) {
-                                    let page = bp.get_page(base_cols[i]);
-                                    let page_copy = bp.get_page(new_page_dir_entry[i]);
+    /*
+        Waits for the merges already requested, after which none are
+    */
+    pub(crate) fn close(mut self) {
+        let (done, closed) = channel();
+        if self
+            .send
+            .send((self.table_id, MergeRequest::Deregister(Some(done))))
+            .is_ok()
+        {
+            let _ = closed.recv();
+        }
+        self.closed = true;
+    }
+}
 
-                                    let page = page
-                                        .raw()
-                                        .read()
-                                        .expect("Failed to acquire merge page lock");
-                                    let mut page_copy = page_copy
-                                        .raw()
-                                        .write()
-                                        .expect("Failed to acquire merge page lock");
+impl Drop for MergeRegistration {
+    fn drop(&mut self) {
+        if !self.closed {
+            let _ = self
+                .send
+                .send((self.table_id, MergeRequest::Deregister(None)));
+        }
+    }
+}
 
-                                    // println!("{:?}", page.page);
+impl MergeTarget {
+    /*
+        Merges the range once any other merge is done. A panic partway is
+        caught and recorded in status rather than taking the merge thread,
+        or the table's caller, down with it. The base pages stay as they
+        were, but the tail pages the merge got to count as merged, their
+        records are still read through the indirection column.
+    */
+    pub(crate) fn run_merge(&self, merge_range: usize) -> Result<bool, MergeError> {
+        let _merging = self.merge_lock.lock();
+
+        panic::catch_unwind(AssertUnwindSafe(|| self.merge_range(merge_range))).map_err(|payload| {
+            let reason = payload
+                .downcast_ref::<&str>()
+                .map(|reason| reason.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "merge panicked".into());
+
+            let err = MergeError {
+                range_id: merge_range,
+                reason,
+            };
+            *self.status.lock() = Some(err.clone());
+            err
+        })
+    }
 
-                                    page_copy.page.clone_from_slice(&page.page);
-                                }
+    /*
+        Folds the range's filled tail pages into copies of its base pages, unless
+        a write to them may still be rolled back. Returns whether anything was
+        merged. Only run through run_merge, so one merge runs at a time.
+    */
+    fn merge_range(&self, merge_range: usize) -> bool {
+        let MergeTarget {
+            page_dir,
+            range_dir,
+            disk,
+            bufferpool: main_bufferpool,
+            index,
+            lock_manager,
+            checkpoint_lock,
+            stats: merge_stats,
+            hook,
+            base_writes,
+            ..
+        } = self;
+        let (num_columns, page_layout, range_pages) =
+            (self.num_columns, self.page_layout, self.range_pages);
+
+        let mut seen: FxHashSet<u64> = FxHashSet::with_capacity_and_hasher(
+            PAGE_SLOTS * range_pages,
+            BuildHasherDefault::<FxHasher>::default(),
+        );
+        let mut merged: FxHashMap<usize, Arc<[usize]>> = FxHashMap::with_capacity_and_hasher(
+            PAGE_SLOTS * range_pages,
+            BuildHasherDefault::<FxHasher>::default(),
+        );
+        // Slots of each copied page with no row yet when it was copied
+        let mut unpublished: FxHashMap<usize, Vec<usize>> = FxHashMap::default();
+
+        /*
+            A checkpoint must not flush a range halfway through being merged.
+            Recursive, as Table::force_merge holds it already
+        */
+        let _checkpoint = checkpoint_lock.read_recursive();
+        let started = Instant::now();
+        let mut tail_records = 0;
+
+        let ranges = range_dir.lock();
+        let range = ranges.get(merge_range);
+        let merge_from = range.current_tail_page.load(Ordering::SeqCst);
+
+        let tail_columns = page_dir
+            .get_page(merge_from)
+            .expect("Bad page ID for Page Range encountered in merge");
+
+        // Column pages per logical page, the same for all of them, NULL masks included if any
+        let columns = tail_columns.len();
+
+        let last_page = Page::new(tail_columns).read_last_tail(main_bufferpool) as usize;
+
+        let merge_stop_at = range.merged_until.load(Ordering::SeqCst);
+
+        drop(ranges);
+
+        // Nothing filled up since the last merge
+        if last_page == merge_stop_at || last_page == RID_INVALID as usize {
+            return false;
+        }
+
+        /*
+            A row a running transaction wrote to may still be rolled back,
+            which would leave its values merged into the base page with
+            nothing pointing at them, so the range waits for a later merge.
+            So it does for a slot update_range took along with others but
+            hasn't written yet. Writes from here on only land in newer tail
+            pages.
+        */
+        let writing =
+            tail_base_rids(page_dir, main_bufferpool, last_page, merge_stop_at).any(|base_rid| {
+                base_rid == RID_INVALID || lock_manager.is_locked_exclusive(RID(base_rid))
+            });
+        if writing {
+            return false;
+        }
+
+        range_dir
+            .lock()
+            .get(merge_range)
+            .merged_until
+            .store(last_page, Ordering::SeqCst);
+
+        let mut tail_page_id = last_page;
+
+        // Everything past the static columns is copied, and all but the page header merged
+        let merged_columns = columns - NUM_STATIC_COLUMNS;
+
+        // Columnar copies of the range's base pages share one reservation, made on first use
+        let mut reserved_range = None;
+
+        while tail_page_id != merge_stop_at && tail_page_id != RID_INVALID as usize {
+            let tail_page = Page::new(
+                page_dir
+                    .get_page(tail_page_id)
+                    .expect("Bad page ID for Page Range encountered in merge"),
+            );
+            let next_tail_page_id = tail_page.read_last_tail(main_bufferpool) as usize;
 
-                                new_page_dir_entry
-                            }),
-                        ));
+            // Reads the next tail page in while this one is merged
+            if next_tail_page_id != merge_stop_at {
+                if let Some(next_tail_page) = page_dir.get_page(next_tail_page_id) {
+                    main_bufferpool.prefetch(&next_tail_page);
+                }
+            }
 
-                        let bp = &mut main_bufferpool.lock();
-                        let tid = tail_page.get_column(bp, METADATA_RID).slot(tail_slot);
+            for tail_slot in (0..PAGE_SLOTS).rev() {
+                let base_rid = tail_page
+                    .get_column(main_bufferpool, METADATA_BASE_RID)
+                    .slot(tail_slot);
+
+                // Rolled back or deleted, the row's latest version is an older one if any
+                let tid = tail_page
+                    .get_column(main_bufferpool, METADATA_RID)
+                    .slot(tail_slot);
+                if tid == RID_INVALID || seen.contains(&base_rid) {
+                    continue;
+                }
 
-                        if merged_page.read_page_tps(bp) > tid && tid != 0 {
-                            merged_page.write_page_tps(bp, tid);
+                /*
+                    Never written, which the check above rules out for the pages
+                    merged, or zeroed by a failed read. Either way there's no row
+                    to fold it into, and page 0 mustn't get it instead
+                */
+                if base_rid == RID_INVALID || tid == 0 {
+                    continue;
+                }
+
+                seen.insert(base_rid);
+                tail_records += 1;
+
+                let base_page_id = RID(base_rid).page();
+
+                let merged_page = Page::new(Arc::clone(merged.entry(base_page_id).or_insert_with(
+                    || {
+                        let mut new_page_dir_entry = Arc::new_uninit_slice(columns);
+
+                        let base_cols = page_dir
+                            .get_page(base_page_id)
+                            .expect("Merge thread tried to access a non-existent page id");
+
+                        let new_page = Arc::get_mut(&mut new_page_dir_entry).unwrap();
+                        new_page[METADATA_INDIRECTION].write(base_cols[METADATA_INDIRECTION]);
+                        new_page[METADATA_BASE_RID].write(base_cols[METADATA_BASE_RID]);
+                        new_page[METADATA_RID].write(base_cols[METADATA_RID]);
+
+                        let (reserved, pages, page) = match page_layout {
+                            PageLayout::Interleaved => (disk.reserve_range(merged_columns), 1, 0),
+                            PageLayout::Columnar => {
+                                assert_eq!(base_page_id / range_pages, merge_range);
+
+                                let reserved = *reserved_range.get_or_insert_with(|| {
+                                    disk.reserve_range(merged_columns * range_pages)
+                                });
+                                (reserved, range_pages, base_page_id % range_pages)
+                            }
+                        };
+
+                        for (i, column) in
+                            new_page[NUM_STATIC_COLUMNS..columns].iter_mut().enumerate()
+                        {
+                            column.write(page_layout.place(
+                                reserved,
+                                merged_columns,
+                                pages,
+                                page,
+                                i,
+                            ));
                         }
 
-                        for i in (NUM_STATIC_COLUMNS + 1)..(NUM_METADATA_COLUMNS + num_columns) {
-                            let updated_value = tail_page.get_column(bp, i).slot(tail_slot);
-                            merged_page
-                                .get_column(bp, i)
-                                .write_slot(RID(base_rid).slot(), updated_value);
+                        let new_page_dir_entry = unsafe { new_page_dir_entry.assume_init() };
+
+                        /*
+                            An insert publishes its RID last, so every row seen here
+                            is in the copy below. The others may still be written
+                            to the original, see the swap
+                        */
+                        let bp = main_bufferpool;
+                        let rids = Page::new(Arc::clone(&base_cols)).get_column(bp, METADATA_RID);
+                        unpublished.insert(
+                            base_page_id,
+                            (0..PAGE_SLOTS)
+                                .filter(|slot| rids.slot(*slot) == RID_INVALID)
+                                .collect(),
+                        );
+                        drop(rids);
+
+                        for i in NUM_STATIC_COLUMNS..columns {
+                            let page = bp.pin_page(base_cols[i], false);
+                            let page_copy = bp.pin_page(new_page_dir_entry[i], false);
+
+                            let page = page
+                                .raw()
+                                .read()
+                                .expect("Failed to acquire merge page lock");
+                            let mut page_copy = page_copy
+                                .raw()
+                                .write()
+                                .expect("Failed to acquire merge page lock");
+
+                            page_copy.page.clone_from_slice(&page.page);
                         }
-                    }
 
-                    tail_page_id = tail_page.read_last_tail(&mut main_bufferpool.lock()) as usize;
+                        new_page_dir_entry
+                    },
+                )));
+
+                let bp = main_bufferpool;
+
+                if merged_page.read_page_tps(bp) > tid && tid != 0 {
+                    merged_page.write_page_tps(bp, tid);
                 }
 
-                //main_bufferpool.lock().flush_all();
+                for i in (NUM_STATIC_COLUMNS + 1)..columns {
+                    let updated_value = tail_page.get_column(bp, i).slot(tail_slot);
+                    merged_page
+                        .get_column(bp, i)
+                        .write_slot(RID(base_rid).slot(), updated_value);
+                }
+            }
 
-                let mut page_dir = page_dir.write();
+            tail_page_id = next_tail_page_id;
+        }
+
+        //main_bufferpool.lock().flush_all();
+
+        /*
+            Inserts wait from here until the copies are swapped in, and the
+            rows they made since a page was copied are brought over first.
+            None of those has a tail record this merge folded in, unless it
+            was updated as soon as it was inserted, and then the copy already
+            has its latest version
+        */
+        let base_writes = base_writes.write();
+        let bp = main_bufferpool;
+        for (base_page_id, column_pages) in &merged {
+            let Some(original) = page_dir.get_page(*base_page_id) else {
+                continue;
+            };
+            let (original, copy) = (Page::new(original), Page::new(Arc::clone(column_pages)));
+            let rids = original.get_column(bp, METADATA_RID);
+            for slot in &unpublished[base_page_id] {
+                let rid = rids.slot(*slot);
+                if rid == RID_INVALID || seen.contains(&rid) {
+                    continue;
+                }
 
-                for pair in &merged {
-                    page_dir.replace_page(*pair.0, pair.1);
+                for i in (NUM_STATIC_COLUMNS + 1)..columns {
+                    let value = original.get_column(bp, i).slot(*slot);
+                    copy.get_column(bp, i).write_slot(*slot, value);
                 }
+            }
+        }
+
+        let mut compacted = 0;
+        for (base_page_id, column_pages) in &merged {
+            let (freed, newly) = compact_deleted(
+                *base_page_id,
+                &Page::new(Arc::clone(column_pages)),
+                page_dir,
+                main_bufferpool,
+                lock_manager,
+                num_columns,
+            );
 
-                drop(page_dir);
+            // Deletes drop their own entries, this clears any an older index file kept
+            let mut index = index.write();
+            for (rid, values) in freed.iter() {
+                index.apply(&RecordChange::Delete {
+                    rid: *rid,
+                    values,
+                    nulls: 0,
+                });
+            }
+            compacted += newly;
+        }
+
+        /*
+            The copies are the base pages from here on. Whatever is still
+            cached of the originals would only ever be written back over
+            pages nothing reads again, so it's dropped instead, once the
+            queries that looked them up before the swap let go of them.
+            Their disk pages are free to reuse after the next checkpoint.
+        */
+        let mut originals = Vec::with_capacity(merged.len());
+        for pair in &merged {
+            originals.extend(page_dir.replace_page(*pair.0, pair.1));
+        }
+        drop(base_writes);
+
+        for original in originals {
+            while Arc::strong_count(&original) > 1 {
+                thread::sleep(Duration::from_millis(1));
+            }
 
-                merged.clear();
-                seen.clear();
+            let superseded = &original[NUM_STATIC_COLUMNS..columns];
+            for page_id in superseded {
+                disk.free_page(*page_id);
             }
-        });
+            main_bufferpool.discard_pages(superseded);
+        }
+
+        let report = MergeReport {
+            range_id: merge_range,
+            tail_records,
+            base_pages: merged.len(),
+            compacted_rows: compacted,
+            slots_written: merged.len() * merged_columns * PAGE_SLOTS
+                + tail_records * (merged_columns - 1),
+            time: started.elapsed(),
+        };
+
+        {
+            let mut stats = merge_stats.lock();
+            stats.merges += 1;
+            stats.tail_records += report.tail_records;
+            stats.base_pages += report.base_pages;
+            stats.compacted_rows += report.compacted_rows;
+            stats.slots_written += report.slots_written;
+            stats.total_time += report.time;
+        }
+
+        if let Some(hook) = hook.lock().as_ref() {
+            hook(&report);
+        }
+
+        true
+    }
 
-        (handle, send)
+    /*
+        Gives back the merged tail pages of every range past the newest retained
+        ones: out of the page directory, onto the disk free list and out of the
+        bufferpool. Only run by a checkpoint, which holds off queries and merges
+        alike, so nothing is reading them. Returns how many were reclaimed.
+    */
+    pub(crate) fn reclaim_tail_pages(&self, retained: usize) -> usize {
+        let bp = &*self.bufferpool;
+        let ranges = self.range_dir.lock();
+
+        let mut reclaimed = 0;
+        for range in ranges.ranges() {
+            let merged_until = range.merged_until.load(Ordering::SeqCst);
+            let reclaimed_from = range.reclaimed_from.load(Ordering::SeqCst);
+
+            // Newest first, down to the pages an earlier checkpoint reclaimed
+            let merged = successors(Some(merged_until), |page_id| {
+                let tail_page = Page::new(self.page_dir.get_page(*page_id)?);
+                Some(tail_page.read_last_tail(bp) as usize)
+            })
+            .take_while(|page_id| *page_id != 0 && *page_id < reclaimed_from)
+            .collect::<Vec<_>>();
+
+            let Some(&newest) = merged.get(retained) else {
+                continue;
+            };
+
+            // A rolled back delete writes its RIDs back into every tail record of the row
+            if tail_base_rids(&self.page_dir, bp, newest, reclaimed_from)
+                .any(|base_rid| self.lock_manager.is_locked_exclusive(RID(base_rid)))
+            {
+                continue;
+            }
+
+            range.reclaimed_from.store(newest, Ordering::SeqCst);
+
+            for page_id in &merged[retained..] {
+                let Some(column_pages) = self.page_dir.remove_page(*page_id) else {
+                    continue;
+                };
+
+                for column_page in column_pages.iter() {
+                    self.disk.free_page(*column_page);
+                }
+                bp.discard_pages(&column_pages);
+                reclaimed += 1;
+            }
+        }
+
+        self.stats.lock().reclaimed_tail_pages += reclaimed;
+        reclaimed
+    }
+}
+
+/*
+    Base RIDs of every tail record in the tail pages from last_page back to, but
+    not including, stop_at
+*/
+pub(crate) fn tail_base_rids<'a>(
+    page_dir: &'a PageDirectory,
+    bp: &'a BufferPool,
+    last_page: usize,
+    stop_at: usize,
+) -> impl Iterator<Item = u64> + 'a {
+    let pages = std::iter::successors(Some(last_page), move |page_id| {
+        let tail_page = Page::new(page_dir.get_page(*page_id)?);
+        Some(tail_page.read_last_tail(bp) as usize)
+    });
+
+    pages
+        .take_while(move |page_id| *page_id != stop_at && *page_id != RID_INVALID as usize)
+        .flat_map(move |page_id| {
+            let tail_page = Page::new(page_dir.get_page(page_id).unwrap());
+            let base_rids = tail_page.get_column(bp, METADATA_BASE_RID);
+            (0..PAGE_SLOTS).map(move |slot| base_rids.slot(slot))
+        })
+}
+
+/*
+    Clears the deleted rows out of a merged copy of a base page and returns each
+    newly cleared one's RID with the values of its latest version, so the caller
+    can drop their index entries. The slot stays where it is, marked free by its
+    invalid RID, so no other row's RID changes. A row whose delete is still held
+    by a running transaction is left for a later merge, since a rollback brings
+    it back.
+
+    Cleared slots get an invalid schema encoding, which tells a later merge they
+    are done unless a late tail record of the row was folded in over them since.
+    The page header keeps the count of cleared slots.
+*/
+fn compact_deleted(
+    base_page_id: usize,
+    page: &Page,
+    page_dir: &PageDirectory,
+    bp: &BufferPool,
+    lock_manager: &LockManager,
+    num_columns: usize,
+) -> (Vec<(RID, Vec<u64>)>, usize) {
+    let rids = page.get_column(bp, METADATA_RID);
+    let base_rids = page.get_column(bp, METADATA_BASE_RID);
+    let indirection = page.get_column(bp, METADATA_INDIRECTION);
+    let schema_encoding = page.get_column(bp, METADATA_SCHEMA_ENCODING);
+
+    let mut freed = Vec::new();
+    let mut cleared: usize = 0;
+
+    for slot in 0..PAGE_SLOTS {
+        let rid = RID::base(base_page_id, slot);
+
+        // Never written slots have no base RID, deleted ones keep their own
+        if rids.slot(slot) != RID_INVALID || base_rids.slot(slot) != rid.raw() {
+            continue;
+        }
+
+        if schema_encoding.slot(slot) == RID_INVALID {
+            cleared += 1;
+            continue;
+        }
+
+        if lock_manager.is_locked(rid) {
+            continue;
+        }
+
+        /*
+            A latest version gone with a reclaimed tail page had its index
+            entries dropped by the delete itself, the base record's will do
+        */
+        let latest = RID(indirection.slot(slot));
+        let tail_page = (!latest.is_invalid() && latest != rid)
+            .then(|| page_dir.get(latest))
+            .flatten()
+            .map(Page::new);
+        let (latest_page, latest_slot) = match &tail_page {
+            Some(tail_page) => (tail_page, latest.slot()),
+            None => (page, slot),
+        };
+
+        let values = (0..num_columns)
+            .map(|i| {
+                latest_page
+                    .get_column(bp, NUM_METADATA_COLUMNS + i)
+                    .slot(latest_slot)
+            })
+            .collect::<Vec<u64>>();
+
+        for i in 0..num_columns {
+            page.get_column(bp, NUM_METADATA_COLUMNS + i)
+                .write_slot(slot, 0);
+        }
+        schema_encoding.write_slot(slot, RID_INVALID);
+
+        cleared += 1;
+        freed.push((rid, values));
     }
+
+    let newly = cleared.saturating_sub(page.read_freed_slots(bp) as usize);
+    page.write_freed_slots(bp, cleared as u64);
+
+    (freed, newly)
 }
@@ -1,9 +1,7 @@
-use rkyv::{Archive, Deserialize, Serialize};
-
 use crate::{
-    bufferpool::{BufferPool, BufferPoolFrame},
+    bufferpool::{BufferPool, PageGuard},
     rid::RID,
-    METADATA_PAGE_HEADER, PAGE_SLOTS,
+    METADATA_PAGE_HEADER, PAGE_SLOTS, RID_INVALID,
 };
 use std::{
     fmt::Display,
@@ -38,9 +36,10 @@ impl Default for PhysicalPage {
     }
 }
 
+// Slots are little-endian on every machine, see format::PageOrder
 impl PhysicalPage {
     pub fn slot(&self, index: usize) -> u64 {
-        u64::from_ne_bytes(
+        u64::from_le_bytes(
             self.page[size_of::<u64>() * index..size_of::<u64>() * (index + 1)]
                 .try_into()
                 .unwrap(),
@@ -49,7 +48,7 @@ impl PhysicalPage {
 
     pub fn write_slot(&mut self, index: usize, value: u64) {
         self.page[size_of::<u64>() * index..size_of::<u64>() * (index + 1)]
-            .copy_from_slice(u64::to_ne_bytes(value).as_slice())
+            .copy_from_slice(u64::to_le_bytes(value).as_slice())
     }
 }
 
@@ -77,53 +76,80 @@ impl Page {
         self.0[index]
     }
 
-    pub fn read_metadata(&self, bp: &mut BufferPool) -> u64 {
-        bp.get_page(self.0[METADATA_PAGE_HEADER]).slot(0)
+    pub fn read_metadata(&self, bp: &BufferPool) -> u64 {
+        bp.pin_page(self.0[METADATA_PAGE_HEADER], false).slot(0)
     }
 
-    pub fn write_metadata(&self, bp: &mut BufferPool, val: u64) {
-        bp.get_page(self.0[METADATA_PAGE_HEADER]).write_slot(0, val);
+    pub fn write_metadata(&self, bp: &BufferPool, val: u64) {
+        bp.pin_page(self.0[METADATA_PAGE_HEADER], false)
+            .write_slot(0, val);
     }
 
-    pub fn write_page_tps(&self, bp: &mut BufferPool, val: u64) {
+    pub fn write_page_tps(&self, bp: &BufferPool, val: u64) {
         self.write_metadata(bp, val);
     }
 
-    pub fn write_last_tail(&self, bp: &mut BufferPool, val: u64) {
+    pub fn write_last_tail(&self, bp: &BufferPool, val: u64) {
         self.write_metadata(bp, val);
     }
 
-    pub fn read_page_tps(&self, bp: &mut BufferPool) -> u64 {
+    pub fn read_page_tps(&self, bp: &BufferPool) -> u64 {
         self.read_metadata(bp)
     }
 
-    pub fn read_last_tail(&self, bp: &mut BufferPool) -> u64 {
+    pub fn read_last_tail(&self, bp: &BufferPool) -> u64 {
         self.read_metadata(bp)
     }
 
+    /*
+        Base pages only: how many deleted rows merges have compacted out of this
+        page, kept in the header beside the TPS. Headers start out filled with
+        RID_INVALID, which reads as none.
+    */
+    pub fn read_freed_slots(&self, bp: &BufferPool) -> u64 {
+        match bp.pin_page(self.0[METADATA_PAGE_HEADER], false).slot(1) {
+            RID_INVALID => 0,
+            freed => freed,
+        }
+    }
+
+    pub fn write_freed_slots(&self, bp: &BufferPool, freed: u64) {
+        bp.pin_page(self.0[METADATA_PAGE_HEADER], false)
+            .write_slot(1, freed);
+    }
+
     #[inline(always)]
-    pub fn get_column(&self, bp: &mut BufferPool, index: usize) -> Arc<BufferPoolFrame> {
-        bp.get_page(self.0[index])
+    pub fn get_column(&self, bp: &BufferPool, index: usize) -> PageGuard {
+        bp.pin_page(self.0[index], false)
+    }
+    /*
+        Same frame through the bufferpool's scan hint, for reads that touch a page once
+    */
+    pub fn get_column_for_scan(&self, bp: &BufferPool, index: usize) -> PageGuard {
+        bp.pin_page(self.0[index], true)
     }
-    pub fn get_column_mut(&self, bp: &mut BufferPool, index: usize) -> Arc<BufferPoolFrame> {
-        bp.get_page(self.0[index])
+
+    pub fn get_column_mut(&self, bp: &BufferPool, index: usize) -> PageGuard {
+        bp.pin_page(self.0[index], false)
     }
     #[inline(always)]
-    pub fn slot(&self, bp: &mut BufferPool, column: usize, rid: RID) -> u64 {
+    pub fn slot(&self, bp: &BufferPool, column: usize, rid: RID) -> u64 {
         self.get_column(bp, column).slot(rid.slot())
     }
 
     #[inline(always)]
-    pub fn write_slot(&mut self, bp: &mut BufferPool, column: usize, rid: RID, value: u64) {
+    pub fn write_slot(&mut self, bp: &BufferPool, column: usize, rid: RID, value: u64) {
         self.get_column(bp, column).write_slot(rid.slot(), value);
     }
 }
 
-#[derive(Archive, Serialize, Deserialize, Debug)]
+#[derive(Debug)]
 pub struct PageRange {
     pub next_tid: AtomicU64,
     pub current_tail_page: AtomicUsize,
     pub merged_until: AtomicUsize,
+    // Newest tail page reclaimed at a checkpoint, it and every older one are gone
+    pub reclaimed_from: AtomicUsize,
 }
 
 impl PageRange {
@@ -132,6 +158,7 @@ impl PageRange {
             next_tid: next_tid.into(),
             current_tail_page: current_tail_page.into(),
             merged_until: 0.into(),
+            reclaimed_from: (RID_INVALID as usize).into(),
         }
     }
 
@@ -1,140 +1,188 @@
 use std::{
-    fs::File,
     hash::BuildHasherDefault,
-    io::{BufWriter, Read, Write},
     path::{Path, PathBuf},
     sync::Arc,
 };
 
-use rkyv::{
-    de::deserializers::SharedDeserializeMap,
-    ser::{
-        serializers::{AllocScratch, CompositeSerializer, SharedSerializeMap, WriteSerializer},
-        Serializer,
-    },
-    Deserialize,
-};
+use parking_lot::RwLock;
 use rustc_hash::{FxHashMap, FxHasher};
 
-use crate::rid::RID;
+use crate::{
+    archive, error::CrabError, format::PageDirectorySnapshot, rid::RID, PAGE_DIRECTORY_SHARDS,
+};
+
+type Shard = FxHashMap<usize, Arc<[usize]>>;
+
+/*
+    Logical page -> column pages on disk. Split into independently locked shards
+    by page range, so allocating in one range doesn't stall lookups in another;
+    all pages of a range share a shard.
+*/
 #[derive(Debug)]
 pub struct PageDirectory {
     path: PathBuf,
-    directory: FxHashMap<usize, Arc<[usize]>>,
+    shards: Box<[RwLock<Shard>]>,
+    range_pages: usize,
 }
 
 impl PageDirectory {
+    fn shard(&self, page: usize) -> &RwLock<Shard> {
+        &self.shards[(page / self.range_pages) % self.shards.len()]
+    }
+
     #[inline(always)]
     pub fn get(&self, rid: RID) -> Option<Arc<[usize]>> {
         self.get_page(rid.page())
     }
 
     pub fn get_page(&self, page: usize) -> Option<Arc<[usize]>> {
-        self.directory.get(&page).map(Arc::clone)
+        self.shard(page).read().get(&page).map(Arc::clone)
     }
 
-    pub fn set(&mut self, rid: RID, page_ids: &[Option<usize>]) {
-        if let Some(current_vals) = self.directory.get_mut(&rid.page()) {
-            let mut cols_clone = Arc::<[usize]>::new_uninit_slice(current_vals.len());
-
-            for (i, potential_page) in page_ids.into_iter().enumerate() {
-                if let Some(new_page) = potential_page {
-                    Arc::get_mut(&mut cols_clone).unwrap()[i].write(*new_page);
-                } else {
-                    Arc::get_mut(&mut cols_clone).unwrap()[i].write(current_vals[i]);
-                }
-            }
+    /*
+        Snapshot of every mapping, shard by shard
+    */
+    pub fn pages(&self) -> impl Iterator<Item = (usize, Arc<[usize]>)> {
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .read()
+                    .iter()
+                    .map(|(page, cols)| (*page, Arc::clone(cols)))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
 
-            self.directory
-                .insert(rid.page(), unsafe { cols_clone.assume_init() });
+    pub fn new_page(&self, page_num: usize, column_page_ids: Arc<[usize]>) {
+        self.shard(page_num)
+            .write()
+            .try_insert(page_num, column_page_ids)
+            .expect("Tried to allocate new page with existing page number");
+    }
 
-            return;
+    /*
+        Looks up the page holding rid, calling allocate to create it, and any
+        other pages of its range allocated along with it, if it is missing.
+        A range's pages share a shard, so racing callers allocate once and the
+        rest wait on the shard, whichever pages of the range they insert into.
+    */
+    pub fn get_or_allocate(
+        &self,
+        rid: RID,
+        allocate: impl FnOnce() -> Vec<(usize, Arc<[usize]>)>,
+    ) -> Arc<[usize]> {
+        if let Some(cols) = self.get(rid) {
+            return cols;
         }
 
-        let mut entry = Arc::<[usize]>::new_uninit_slice(page_ids.len());
+        let mut shard = self.shard(rid.page()).write();
+
+        // Check again since unlocking read and acquiring write are not atomic
+        if shard.get(&rid.page()).is_none() {
+            for (page_num, cols) in allocate() {
+                assert_eq!(page_num / self.range_pages, rid.page() / self.range_pages);
 
-        for (i, x) in page_ids.iter().enumerate() {
-            Arc::get_mut(&mut entry).unwrap()[i]
-                .write(x.expect("Must provide all columns of page dir entry if new"));
+                shard
+                    .try_insert(page_num, cols)
+                    .expect("Tried to allocate new page with existing page number");
+            }
         }
 
-        self.directory
-            .insert(rid.page(), unsafe { entry.assume_init() });
-    }
-
-    pub fn new_page(&mut self, page_num: usize, column_page_ids: Arc<[usize]>) {
-        self.directory
-            .try_insert(page_num, Arc::clone(&column_page_ids))
-            .expect("Tried to allocate new page with existing page number");
+        shard
+            .get(&rid.page())
+            .map(Arc::clone)
+            .expect("Allocated new pages but no mapping in directory")
     }
 
     pub fn replace_page(
-        &mut self,
+        &self,
         page_num: usize,
         replacement: &Arc<[usize]>,
     ) -> Option<Arc<[usize]>> {
-        self.directory.insert(page_num, Arc::clone(replacement))
+        self.shard(page_num)
+            .write()
+            .insert(page_num, Arc::clone(replacement))
     }
 
-    pub fn new(path: &Path) -> Self {
-        if !path.exists() {
-            File::create(path).unwrap();
-        }
+    pub fn remove_page(&self, page_num: usize) -> Option<Arc<[usize]>> {
+        self.shard(page_num).write().remove(&page_num)
+    }
 
+    /*
+        For a table whose page ranges have range_pages pages each
+    */
+    pub fn new(path: &Path, range_pages: usize) -> Self {
         PageDirectory {
             path: path.into(),
-            directory: FxHashMap::with_capacity_and_hasher(
-                80000,
-                BuildHasherDefault::<FxHasher>::default(),
-            ),
+            range_pages,
+            shards: (0..PAGE_DIRECTORY_SHARDS)
+                .map(|_| {
+                    RwLock::new(FxHashMap::with_capacity_and_hasher(
+                        80000 / PAGE_DIRECTORY_SHARDS,
+                        BuildHasherDefault::<FxHasher>::default(),
+                    ))
+                })
+                .collect(),
         }
     }
 
-    pub fn load(path: &Path) -> Self {
+    /*
+        Entries are redistributed on load, so the shard count on disk doesn't have to match ours
+    */
+    pub fn load(path: &Path, range_pages: usize) -> Result<Self, CrabError> {
+        let page_dir = PageDirectory::new(path, range_pages);
+
         if !path.exists() {
-            return PageDirectory::new(path);
+            return Ok(page_dir);
         }
 
-        let mut pd_file = File::options().read(true).open(path).unwrap();
-        let mut pd_bytes = Vec::new();
+        let snapshot = PageDirectorySnapshot::decode(path, &archive::read_file(path)?)?;
 
-        pd_file
-            .read_to_end(&mut pd_bytes)
-            .expect("Unable to read page directory file");
+        for (page_num, cols) in snapshot.shards.into_iter().flatten() {
+            if page_dir.get_page(page_num).is_some() {
+                return Err(archive::corrupt(
+                    path,
+                    format!("page {page_num} appears in two shards"),
+                ));
+            }
 
-        let archived = unsafe { rkyv::archived_root::<FxHashMap<usize, Arc<[usize]>>>(&pd_bytes) };
+            page_dir.new_page(page_num, cols.into());
+        }
 
-        let directory = archived
-            .deserialize(&mut SharedDeserializeMap::new())
-            .expect("Failed to deserialize page directory");
+        Ok(page_dir)
+    }
 
-        PageDirectory {
-            path: path.into(),
-            directory,
+    /*
+        Swaps in the mappings of a freshly loaded directory, shard by shard
+    */
+    pub fn replace_with(&self, loaded: PageDirectory) {
+        for (shard, loaded) in self.shards.iter().zip(loaded.shards.into_vec()) {
+            *shard.write() = loaded.into_inner();
         }
     }
 
-    pub fn persist(&self) {
-        let pd_file = File::options()
-            .write(true)
-            .truncate(true)
-            .open(self.path.clone())
-            .expect("Unable to open page directory file");
-
-        let mut serializer = CompositeSerializer::new(
-            WriteSerializer::new(BufWriter::new(pd_file)),
-            AllocScratch::default(),
-            SharedSerializeMap::new(),
-        );
-
-        serializer
-            .serialize_value(&self.directory)
-            .expect("Unable to serialize page directory");
-
-        let (buf, _, _) = serializer.into_components();
-
-        buf.into_inner()
-            .flush()
-            .expect("Failed to flush page directory");
+    pub fn persist(&self) -> Result<(), CrabError> {
+        let snapshot = PageDirectorySnapshot {
+            shards: self
+                .shards
+                .iter()
+                .map(|shard| {
+                    let mut entries = shard
+                        .read()
+                        .iter()
+                        .map(|(page, cols)| (*page, cols.to_vec()))
+                        .collect::<Vec<_>>();
+
+                    // Same directory, same bytes
+                    entries.sort_unstable_by_key(|x| x.0);
+                    entries
+                })
+                .collect(),
+        };
+
+        archive::write_file(&self.path, &snapshot.encode())
     }
 }
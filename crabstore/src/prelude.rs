@@ -0,0 +1,41 @@
+//! Everything needed to embed a CrabStore database.
+//!
+//! ```
+//! use crabcore::prelude::*;
+//!
+//! let dir = tempfile::tempdir().unwrap();
+//!
+//! let mut db = CrabStore::new(dir.path().into());
+//! db.open()?;
+//!
+//! let grades = db.create_table("Grades", 3, 0);
+//! grades.insert_query(&[1, 90, 85], None)?;
+//!
+//! let mut transaction = Transaction::new();
+//! transaction.add_query(Query::Update(1, Box::new([None, Some(95), None])), &grades);
+//! assert!(transaction.run().is_ok());
+//!
+//! let records: Vec<Record> = grades.select_query(1, 0, &[1, 1, 1], None)?;
+//! assert_eq!(records[0].columns, [1, 95, 85]);
+//! assert_eq!(grades.name(), "Grades");
+//!
+//! db.close();
+//! assert!(grades.is_closed());
+//! # Ok::<(), CrabError>(())
+//! ```
+
+pub use crate::{
+    bufferpool::{BudgetPolicy, QueryBudget},
+    crabstore::CrabStore,
+    error::CrabError,
+    lock_manager::ConcurrencyPolicy,
+    record::Record,
+    rid::RID,
+    table::{QueryContext, ResultOrder, Table, UpdateOp, UpsertResult},
+    transaction::{
+        AbortKind, ConstraintPolicy, Query, QueryResult, QueryStatus, SavepointId, Transaction,
+        TransactionError,
+    },
+    transaction_scheduler::{TransactionScheduler, WorkerStats},
+    transaction_worker::{RetryPolicy, TransactionWorker},
+};
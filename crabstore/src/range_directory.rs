@@ -1,18 +1,15 @@
-use crate::{page::PageRange, rid::RID};
-use rkyv::{
-    de::deserializers::SharedDeserializeMap,
-    ser::{
-        serializers::{AllocScratch, CompositeSerializer, SharedSerializeMap, WriteSerializer},
-        Serializer,
-    },
-    Deserialize,
+use crate::{
+    archive,
+    error::CrabError,
+    format::{RangeDirectorySnapshot, RangeEntry},
+    page::PageRange,
 };
 
 use std::{
-    fs::File,
-    io::{BufWriter, Read, Write},
     path::{Path, PathBuf},
+    sync::atomic::Ordering,
 };
+
 #[derive(Debug)]
 pub struct RangeDirectory {
     path: PathBuf,
@@ -24,8 +21,8 @@ impl RangeDirectory {
         &self.directory[range]
     }
 
-    pub fn next_tid(&self, range: usize) -> RID {
-        self.directory[range].next_tid()
+    pub fn ranges(&self) -> impl Iterator<Item = &PageRange> {
+        self.directory.iter()
     }
 
     pub fn next_range_id(&self) -> usize {
@@ -48,47 +45,38 @@ impl RangeDirectory {
         }
     }
 
-    pub fn load(path: &Path) -> Self {
-        let mut rd_file = File::options().read(true).open(path).unwrap();
-        let mut rd_bytes = Vec::new();
-
-        rd_file
-            .read_to_end(&mut rd_bytes)
-            .expect("Unable to read page directory file");
-
-        let archived = unsafe { rkyv::archived_root::<Vec<PageRange>>(&rd_bytes) };
-        let directory = archived
-            .deserialize(&mut SharedDeserializeMap::new())
-            .expect("Failed to deserialize page directory");
+    pub fn load(path: &Path) -> Result<Self, CrabError> {
+        let snapshot = RangeDirectorySnapshot::decode(path, &archive::read_file(path)?)?;
 
-        RangeDirectory {
+        Ok(RangeDirectory {
             path: path.into(),
-            directory,
-        }
+            directory: snapshot
+                .ranges
+                .into_iter()
+                .map(|range| PageRange {
+                    next_tid: range.next_tid.into(),
+                    current_tail_page: range.current_tail_page.into(),
+                    merged_until: range.merged_until.into(),
+                    reclaimed_from: range.reclaimed_from.into(),
+                })
+                .collect(),
+        })
     }
 
-    pub fn persist(&self) {
-        let rd_file = File::options()
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(self.path.clone())
-            .expect("Unable to open range directory file");
-
-        let mut serializer = CompositeSerializer::new(
-            WriteSerializer::new(BufWriter::new(rd_file)),
-            AllocScratch::default(),
-            SharedSerializeMap::new(),
-        );
-
-        serializer
-            .serialize_value(&self.directory)
-            .expect("Unable to serialize range directory");
-
-        let (buf, _, _) = serializer.into_components();
-
-        buf.into_inner()
-            .flush()
-            .expect("Failed to flush range directory");
+    pub fn persist(&self) -> Result<(), CrabError> {
+        let snapshot = RangeDirectorySnapshot {
+            ranges: self
+                .directory
+                .iter()
+                .map(|range| RangeEntry {
+                    next_tid: range.next_tid.load(Ordering::SeqCst),
+                    current_tail_page: range.current_tail_page.load(Ordering::SeqCst),
+                    merged_until: range.merged_until.load(Ordering::SeqCst),
+                    reclaimed_from: range.reclaimed_from.load(Ordering::SeqCst),
+                })
+                .collect(),
+        };
+
+        archive::write_file(&self.path, &snapshot.encode())
     }
 }
@@ -0,0 +1,178 @@
+use std::{fmt, sync::atomic::Ordering};
+
+use crate::{
+    page::Page, rid::RID, table::Table, METADATA_BASE_RID, METADATA_PAGE_HEADER, METADATA_RID,
+    METADATA_SCHEMA_ENCODING, NUM_METADATA_COLUMNS, PAGE_SLOTS, RID_INVALID,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TailRecord {
+    pub tid: RID,
+    pub base_rid: RID,
+    pub schema_encoding: u64,
+    pub columns: Vec<u64>,
+    // 0 if the table doesn't record timestamps
+    pub timestamp: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TailPageDump {
+    pub page: usize,
+    pub records: Vec<TailRecord>,
+}
+
+/*
+    Snapshot of one page range's tail lineage and merge state, for debugging merges
+*/
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RangeDump {
+    pub range_id: usize,
+    // Oldest tail page first
+    pub tail_pages: Vec<TailPageDump>,
+    // (base page, TPS) for every base page allocated in the range
+    pub tps: Vec<(usize, u64)>,
+    // Newest tail page folded in by the merge thread, 0 if never merged
+    pub merged_until: usize,
+    // Newest tail page a checkpoint reclaimed, the ones left start after it
+    pub reclaimed_from: Option<usize>,
+}
+
+impl Table {
+    fn dump_tail_page(&self, page_id: usize, next_tid: u64) -> Option<TailPageDump> {
+        let page = Page::new(self.data.page_dir.get_page(page_id)?);
+        let bufferpool = self.get_bufferpool();
+        let bp = &*bufferpool;
+
+        let frames: Vec<_> = (0..self.total_columns())
+            .map(|i| bp.pin_page(page.read_col(i), true))
+            .collect();
+
+        // The timestamp column comes last, when there is one
+        let timestamp_column = self.has_timestamps().then(|| self.data.timestamp_column());
+        let columns_end = timestamp_column.unwrap_or(frames.len());
+
+        let mut records = Vec::new();
+
+        for slot in 0..PAGE_SLOTS {
+            let tid = RID::tail(page_id, slot).raw();
+
+            // Slots below next_tid were never handed out, ones whose RID doesn't match were never written
+            if tid <= next_tid || frames[METADATA_RID].slot(slot) != tid {
+                continue;
+            }
+
+            records.push(TailRecord {
+                tid: tid.into(),
+                base_rid: frames[METADATA_BASE_RID].slot(slot).into(),
+                schema_encoding: frames[METADATA_SCHEMA_ENCODING].slot(slot),
+                columns: frames[NUM_METADATA_COLUMNS..columns_end]
+                    .iter()
+                    .map(|frame| frame.slot(slot))
+                    .collect(),
+                timestamp: timestamp_column.map_or(0, |column| frames[column].slot(slot)),
+            });
+        }
+
+        Some(TailPageDump {
+            page: page_id,
+            records,
+        })
+    }
+
+    /*
+        None if the range hasn't been allocated. Reads through the bufferpool's
+        scan path so dumping a range doesn't evict the pages queries are
+        actually using.
+    */
+    pub fn dump_range(&self, range_id: usize) -> Option<RangeDump> {
+        let range_dir = self.data.range_dir.lock();
+
+        if range_id >= range_dir.next_range_id() {
+            return None;
+        }
+
+        let range = range_dir.get(range_id);
+        let current_tail = range.current_tail_page.load(Ordering::SeqCst);
+        let next_tid = range.next_tid.load(Ordering::SeqCst);
+        let merged_until = range.merged_until.load(Ordering::SeqCst);
+        let reclaimed_from = range.reclaimed_from.load(Ordering::SeqCst);
+
+        drop(range_dir);
+
+        let mut tail_pages = Vec::new();
+        let mut tail_page_id = current_tail;
+
+        while tail_page_id != RID_INVALID as usize {
+            let Some(dump) = self.dump_tail_page(tail_page_id, next_tid) else {
+                break;
+            };
+
+            tail_pages.push(dump);
+
+            let page = Page::new(self.data.page_dir.get_page(tail_page_id).unwrap());
+            tail_page_id = self
+                .get_bufferpool()
+                .pin_page(page.read_col(METADATA_PAGE_HEADER), true)
+                .slot(0) as usize;
+        }
+
+        tail_pages.reverse();
+
+        let tps = (range_id * self.data.range_pages..(range_id + 1) * self.data.range_pages)
+            .filter_map(|page_id| {
+                let page = Page::new(self.data.page_dir.get_page(page_id)?);
+                let tps = self
+                    .get_bufferpool()
+                    .pin_page(page.read_col(METADATA_PAGE_HEADER), true)
+                    .slot(0);
+
+                Some((page_id, tps))
+            })
+            .collect();
+
+        Some(RangeDump {
+            range_id,
+            tail_pages,
+            tps,
+            merged_until,
+            reclaimed_from: (reclaimed_from != RID_INVALID as usize).then_some(reclaimed_from),
+        })
+    }
+}
+
+impl fmt::Display for RangeDump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "[Range {}]", self.range_id)?;
+        writeln!(f, "Merged until: tail page {}", self.merged_until)?;
+        if let Some(reclaimed_from) = self.reclaimed_from {
+            writeln!(f, "Reclaimed from: tail page {reclaimed_from}")?;
+        }
+
+        for (page, tps) in &self.tps {
+            writeln!(f, "Base page {page}: TPS {tps}")?;
+        }
+
+        for tail_page in &self.tail_pages {
+            writeln!(
+                f,
+                "Tail page {} ({} records)",
+                tail_page.page,
+                tail_page.records.len()
+            )?;
+
+            for record in &tail_page.records {
+                writeln!(
+                    f,
+                    "  TID {} -> base {} schema {:b} at {} {:?}",
+                    record.tid.raw(),
+                    record.base_rid.raw(),
+                    record.schema_encoding,
+                    record.timestamp,
+                    record.columns
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
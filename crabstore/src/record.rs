@@ -1,11 +1,69 @@
+/*
+    Whether bit position of a NULL mask is set. Masks have a bit per column,
+    and only tables of at most 64 columns have any NULLs.
+*/
+pub(crate) fn is_null(nulls: u64, position: usize) -> bool {
+    position < u64::BITS as usize && nulls >> position & 1 != 0
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Record {
     pub rid: u64,
     pub columns: Vec<u64>,
+    // Bit i set if columns[i] is NULL, which reads as 0 there
+    pub nulls: u64,
+    // When this version was written, 0 if its table didn't record it, see Table::current_timestamp
+    pub timestamp: u64,
 }
 
 impl Record {
     pub fn new(rid: u64, columns: Vec<u64>) -> Self {
-        Record { rid, columns }
+        Record {
+            rid,
+            columns,
+            nulls: 0,
+            timestamp: 0,
+        }
+    }
+
+    pub fn is_null(&self, position: usize) -> bool {
+        is_null(self.nulls, position)
+    }
+
+    /*
+        The value at position of the returned columns, None if it is NULL.
+    */
+    pub fn value(&self, position: usize) -> Option<u64> {
+        (!self.is_null(position)).then(|| self.columns[position])
+    }
+
+    /*
+        Every returned column, NULLs as None.
+    */
+    pub fn values(&self) -> Vec<Option<u64>> {
+        (0..self.columns.len())
+            .map(|position| self.value(position))
+            .collect()
+    }
+
+    /*
+        Keeps the columns flagged in keep, the nulls moving along with them
+    */
+    pub(crate) fn project(mut self, keep: &[usize]) -> Self {
+        let mut nulls = 0;
+        let mut kept = 0;
+
+        let flags = keep.iter().take(self.columns.len()).enumerate();
+        for (position, _) in flags.filter(|(_, keep)| **keep != 0) {
+            if self.is_null(position) {
+                nulls |= 1 << kept;
+            }
+            self.columns.swap(kept, position);
+            kept += 1;
+        }
+
+        self.columns.truncate(kept);
+        self.nulls = nulls;
+        self
     }
 }
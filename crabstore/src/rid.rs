@@ -1,24 +1,39 @@
-use rkyv::{Archive, Deserialize, Serialize};
+use crate::PAGE_SLOTS;
 
-use crate::PAGE_RANGE_COUNT;
+/*
+    A RID is its page's number followed by its slot, so both come from PAGE_SLOTS,
+    which has to be a power of two
+*/
+const SLOT_BITS: u32 = PAGE_SLOTS.trailing_zeros();
+const SLOT_MASK: usize = PAGE_SLOTS - 1;
+const _: () = assert!(PAGE_SLOTS.is_power_of_two());
 
-#[derive(
-    Archive,
-    Serialize,
-    Deserialize,
-    Clone,
-    Copy,
-    Debug,
-    Default,
-    Eq,
-    PartialEq,
-    Ord,
-    PartialOrd,
-    Hash,
-)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct RID(pub u64);
 
 impl RID {
+    /*
+        The RID of a slot in a base page
+    */
+    pub fn base(page: usize, slot: usize) -> RID {
+        RID((page * PAGE_SLOTS + slot) as u64)
+    }
+
+    /*
+        The RID of a slot in a tail page. Tail RIDs count down from the end of the
+        page, so slot 0 is the highest RID in it.
+    */
+    pub fn tail(page: usize, slot: usize) -> RID {
+        RID((page * PAGE_SLOTS + (PAGE_SLOTS - 1 - slot)) as u64 - 1)
+    }
+
+    /*
+        Whether a logical page ID belongs to a tail page
+    */
+    pub fn is_tail_page(page: usize) -> bool {
+        RID::base(page, 0).is_tail()
+    }
+
     /*
         MSB set, then tail since tail grows downwards from 64 bit max
     */
@@ -45,19 +60,22 @@ impl RID {
         We untail because we want the offset from the start of the page
     */
     pub fn slot(&self) -> usize {
-        self.untail() & 0b111111111
+        self.untail() & SLOT_MASK
     }
 
     pub fn page(&self) -> usize {
         if self.is_tail() {
-            ((self.0 + 1) >> 9) as usize
+            ((self.0 + 1) >> SLOT_BITS) as usize
         } else {
-            (self.0 >> 9) as usize
+            (self.0 >> SLOT_BITS) as usize
         }
     }
 
-    pub fn page_range(&self) -> usize {
-        self.page() / PAGE_RANGE_COUNT
+    /*
+        The page range of a base RID, in a table whose ranges have range_pages pages
+    */
+    pub fn page_range(&self, range_pages: usize) -> usize {
+        self.page() / range_pages
     }
 
     pub fn raw(&self) -> u64 {
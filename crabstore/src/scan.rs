@@ -0,0 +1,72 @@
+use std::{ops::Deref, vec};
+
+use crate::{error::CrabError, record::Record, table::Table, PAGE_SLOTS};
+
+/*
+    Latest values of every live row in RID order, read a base page at a time.
+    The end is the next RID when the scan starts, so rows inserted since are not
+    seen, and rows deleted before the scan reaches their page are skipped. Holds
+    anything that derefs to the table, so bindings can keep it alive with an Arc.
+*/
+pub struct TableScan<T: Deref<Target = Table>> {
+    table: T,
+    next_page: u64,
+    end: u64,
+    page: vec::IntoIter<Record>,
+    failed: bool,
+}
+
+impl<T: Deref<Target = Table>> TableScan<T> {
+    pub fn new(table: T) -> Self {
+        let end = table.next_rid() as u64;
+
+        TableScan {
+            table,
+            next_page: 0,
+            end,
+            page: Vec::new().into_iter(),
+            failed: false,
+        }
+    }
+}
+
+impl<T: Deref<Target = Table>> Iterator for TableScan<T> {
+    type Item = Result<Record, CrabError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.failed {
+            if let Some(record) = self.page.next() {
+                return Some(Ok(record));
+            }
+
+            if self.next_page >= self.end {
+                return None;
+            }
+
+            let page_end = self.end.min(self.next_page + PAGE_SLOTS as u64);
+            match self.table.scan_page(self.next_page..page_end) {
+                Ok(records) => self.page = records.into_iter(),
+                Err(err) => {
+                    self.failed = true;
+                    return Some(Err(err));
+                }
+            }
+
+            self.next_page = page_end;
+        }
+
+        None
+    }
+}
+
+impl Table {
+    /*
+        Latest values of every column of every live row, in RID order. Rows are read a
+        base page at a time as the iterator is consumed, so only one page of records
+        is held in memory. Rows inserted after this call are not seen. Each page fails
+        on its own, e.g. once the table is closed.
+    */
+    pub fn scan(&self) -> TableScan<&Table> {
+        TableScan::new(self)
+    }
+}
@@ -0,0 +1,111 @@
+use std::ops::{Bound, RangeBounds};
+
+const SIGN_BIT: u64 = 1 << 63;
+
+/*
+    How a column's slots are read. Every value is passed around as a u64 either
+    way, a signed column's being the two's complement bits of an i64
+*/
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColumnType {
+    #[default]
+    Unsigned,
+    Signed,
+}
+
+/*
+    Bit i set if column i is signed, as recorded in the table header. Anything
+    that compares or adds values goes through here so a signed column's negative
+    values order below zero instead of above every positive one.
+*/
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct SignedColumns(pub(crate) u64);
+
+impl SignedColumns {
+    pub(crate) fn from_types(column_types: &[ColumnType]) -> Self {
+        SignedColumns(
+            column_types
+                .iter()
+                .enumerate()
+                .filter(|(_, column_type)| **column_type == ColumnType::Signed)
+                .fold(0, |mask, (column, _)| mask | 1 << column),
+        )
+    }
+
+    pub(crate) fn is_signed(self, column: usize) -> bool {
+        self.0 & (1 << column) != 0
+    }
+
+    pub(crate) fn column_type(self, column: usize) -> ColumnType {
+        match self.is_signed(column) {
+            true => ColumnType::Signed,
+            false => ColumnType::Unsigned,
+        }
+    }
+
+    /*
+        A u64 that orders the way the column's values do. Flipping the sign bit
+        is its own inverse, so this also turns a key back into its value
+    */
+    pub(crate) fn sort_key(self, column: usize, value: u64) -> u64 {
+        match self.is_signed(column) {
+            true => value ^ SIGN_BIT,
+            false => value,
+        }
+    }
+
+    /*
+        A range of column values as the range of their sort keys. One that ends
+        before it starts comes back as an empty range BTreeMap::range accepts,
+        rather than one it panics on.
+    */
+    pub(crate) fn key_range(
+        self,
+        column: usize,
+        range: &impl RangeBounds<u64>,
+    ) -> (Bound<u64>, Bound<u64>) {
+        let key = |bound: Bound<&u64>| bound.map(|value| self.sort_key(column, *value));
+        let (start, end) = (key(range.start_bound()), key(range.end_bound()));
+
+        let inverted = match (start, end) {
+            (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+            (
+                Bound::Included(start) | Bound::Excluded(start),
+                Bound::Included(end) | Bound::Excluded(end),
+            ) => start > end,
+            _ => false,
+        };
+
+        match inverted {
+            true => (Bound::Excluded(0), Bound::Included(0)),
+            false => (start, end),
+        }
+    }
+
+    pub(crate) fn in_range(self, column: usize, range: &impl RangeBounds<u64>, value: u64) -> bool {
+        self.key_range(column, range)
+            .contains(&self.sort_key(column, value))
+    }
+
+    pub(crate) fn widen(self, column: usize, value: u64) -> i128 {
+        match self.is_signed(column) {
+            true => value as i64 as i128,
+            false => value as i128,
+        }
+    }
+
+    /*
+        Sum of a column's values, None if it doesn't fit the column's type
+    */
+    pub(crate) fn sum(self, column: usize, values: impl IntoIterator<Item = u64>) -> Option<u64> {
+        let sum = values
+            .into_iter()
+            .map(|value| self.widen(column, value))
+            .sum::<i128>();
+
+        match self.is_signed(column) {
+            true => i64::try_from(sum).ok().map(|sum| sum as u64),
+            false => u64::try_from(sum).ok(),
+        }
+    }
+}
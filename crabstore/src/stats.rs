@@ -0,0 +1,226 @@
+use std::path::{Path, PathBuf};
+
+use crate::{
+    archive,
+    error::CrabError,
+    format::{ColumnSketchEntry, ColumnStatsSnapshot},
+};
+
+// 2^10 registers per column, about 3% standard error on distinct counts
+const HLL_PRECISION: u32 = 10;
+const HLL_REGISTERS: usize = 1 << HLL_PRECISION;
+
+/*
+    Estimated shape of a column's values, as of the last analyze and the writes since.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ColumnStats {
+    pub distinct: u64,
+    pub min: Option<u64>,
+    pub max: Option<u64>,
+    // Columns aren't nullable yet, so always 0
+    pub null_fraction: f64,
+}
+
+/*
+    splitmix64's finalizer, so runs of similar values spread over the registers
+*/
+fn mix(value: u64) -> u64 {
+    let mut x = value.wrapping_add(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+/*
+    HyperLogLog registers plus the range of a column. Values are only ever
+    added, so updates and deletes leave it an overestimate until analyze.
+*/
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct ColumnSketch {
+    registers: Vec<u8>,
+    min: u64,
+    max: u64,
+}
+
+impl ColumnSketch {
+    fn new() -> Self {
+        ColumnSketch {
+            registers: vec![0; HLL_REGISTERS],
+            min: u64::MAX,
+            max: 0,
+        }
+    }
+
+    fn add(&mut self, value: u64) {
+        let hash = mix(value);
+        let register = (hash >> (u64::BITS - HLL_PRECISION)) as usize;
+
+        // The sentinel bit caps the rank at the bits left after the register index
+        let rank = ((hash << HLL_PRECISION) | (1 << (HLL_PRECISION - 1))).leading_zeros() + 1;
+
+        self.registers[register] = self.registers[register].max(rank as u8);
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.registers.iter().all(|register| *register == 0)
+    }
+
+    fn distinct(&self) -> u64 {
+        let registers = HLL_REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / registers);
+
+        let sum = self
+            .registers
+            .iter()
+            .map(|register| 2f64.powi(-(*register as i32)))
+            .sum::<f64>();
+        let estimate = alpha * registers * registers / sum;
+
+        // Linear counting is more accurate while most registers are still empty
+        let empty = self.registers.iter().filter(|x| **x == 0).count();
+        if estimate <= 2.5 * registers && empty > 0 {
+            return (registers * (registers / empty as f64).ln()).round() as u64;
+        }
+
+        estimate.round() as u64
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct TableStats {
+    path: PathBuf,
+    // Live rows, as far as the writes since the last analyze tell
+    rows: u64,
+    columns: Vec<ColumnSketch>,
+}
+
+impl TableStats {
+    pub fn new(path: &Path, num_columns: usize) -> Self {
+        TableStats {
+            path: path.into(),
+            rows: 0,
+            columns: vec![ColumnSketch::new(); num_columns],
+        }
+    }
+
+    /*
+        Empty statistics persisted to the same place, for analyze to fill
+    */
+    pub fn cleared(&self) -> Self {
+        TableStats::new(&self.path, self.columns.len())
+    }
+
+    pub fn record_insert(&mut self, values: &[u64]) {
+        self.rows += 1;
+
+        for (column, value) in self.columns.iter_mut().zip(values) {
+            column.add(*value);
+        }
+    }
+
+    pub fn record_update(&mut self, values: &[Option<u64>]) {
+        for (column, value) in self.columns.iter_mut().zip(values) {
+            if let Some(value) = value {
+                column.add(*value);
+            }
+        }
+    }
+
+    pub fn record_delete(&mut self) {
+        self.rows = self.rows.saturating_sub(1);
+    }
+
+    pub fn column(&self, column: usize) -> ColumnStats {
+        let sketch = &self.columns[column];
+
+        if sketch.is_empty() {
+            return ColumnStats {
+                distinct: 0,
+                min: None,
+                max: None,
+                null_fraction: 0.0,
+            };
+        }
+
+        ColumnStats {
+            distinct: sketch.distinct().clamp(1, self.rows.max(1)),
+            min: Some(sketch.min),
+            max: Some(sketch.max),
+            null_fraction: 0.0,
+        }
+    }
+
+    /*
+        Rows expected to hold value, assuming values are spread evenly
+    */
+    pub fn estimate_rows(&self, column: usize, value: u64) -> f64 {
+        let stats = self.column(column);
+
+        match (stats.min, stats.max) {
+            (Some(min), Some(max)) if (min..=max).contains(&value) => {
+                self.rows as f64 / stats.distinct as f64
+            }
+            _ => 0.0,
+        }
+    }
+
+    /*
+        None if the table predates statistics
+    */
+    pub fn load(path: &Path, num_columns: usize) -> Result<Option<Self>, CrabError> {
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let snapshot = ColumnStatsSnapshot::decode(path, &archive::read_file(path)?)?;
+
+        if snapshot.columns.len() != num_columns
+            || snapshot
+                .columns
+                .iter()
+                .any(|column| column.registers.len() != HLL_REGISTERS)
+        {
+            return Err(archive::corrupt(
+                path,
+                format!(
+                    "statistics for {} columns don't fit a table with {num_columns}",
+                    snapshot.columns.len()
+                ),
+            ));
+        }
+
+        Ok(Some(TableStats {
+            path: path.into(),
+            rows: snapshot.rows,
+            columns: snapshot
+                .columns
+                .into_iter()
+                .map(|column| ColumnSketch {
+                    registers: column.registers,
+                    min: column.min,
+                    max: column.max,
+                })
+                .collect(),
+        }))
+    }
+
+    pub fn persist(&self) -> Result<(), CrabError> {
+        let snapshot = ColumnStatsSnapshot {
+            rows: self.rows,
+            columns: self
+                .columns
+                .iter()
+                .map(|column| ColumnSketchEntry {
+                    registers: column.registers.clone(),
+                    min: column.min,
+                    max: column.max,
+                })
+                .collect(),
+        };
+
+        archive::write_file(&self.path, &snapshot.encode())
+    }
+}
@@ -1,921 +1,4551 @@
 use crate::{
-    bufferpool::{BufferPool, BufferPoolFrame},
+    archive,
+    bufferpool::{BudgetPolicy, BufferPool, BufferPoolStats, QueryBudget, DEFAULT_FLUSH_INTERVAL},
+    config::CrabConfig,
+    crabstore::{CrabStore, TableFiles},
     disk_manager::DiskManager,
-    lock_manager::{LockManager, LockType},
-    page::PhysicalPage,
+    error::CrabError,
+    eviction::Eviction,
+    format::{self, PageOrder, TableHeaderPage},
+    frozen::FrozenTable,
+    lock_manager::{ConcurrencyPolicy, LockManager, LockType},
+    merge::{
+        MergeConfig, MergeError, MergeHook, MergeRegistration, MergeReport, MergeScheduler,
+        MergeStats, MergeTarget,
+    },
     range_directory::RangeDirectory,
     record::Record,
     rid::RID,
-    transaction::{IndexMutation, Transaction},
-    BUFFERPOOL_SIZE, METADATA_BASE_RID, METADATA_PAGE_HEADER, NUM_STATIC_COLUMNS, PAGE_RANGE_COUNT,
-    PAGE_SIZE, PAGE_SLOTS,
+    schema::{ColumnType, SignedColumns},
+    stats::{ColumnStats, TableStats},
+    table_data::TableData,
+    transaction::{AbortKind, QueryStatus, Transaction},
+    BACKGROUND_INDEX_REBUILD_ROWS, CLOSE_DRAIN_TIMEOUT_MS, INDEX_ONLY_FANOUT, METADATA_BASE_RID,
+    METADATA_PAGE_HEADER, PAGE_SIZE, PAGE_SLOTS, SLOW_QUERY_LOG_SIZE, SLOW_QUERY_THRESHOLD_MS,
+};
+use crate::{
+    index::{Index, RecordChange},
+    RID_INVALID,
 };
-use crate::{index::Index, RID_INVALID};
 use crate::{
-    page::{Page, PageRange},
+    page::{Page, PhysicalPage},
     page_directory::PageDirectory,
 };
 use crate::{
     record, METADATA_INDIRECTION, METADATA_RID, METADATA_SCHEMA_ENCODING, NUM_METADATA_COLUMNS,
 };
-use parking_lot::{lock_api::RawMutex, Mutex, RwLock};
-use rkyv::{
-    ser::{serializers::BufferSerializer, Serializer},
-    with::Lock,
-    Archive, Deserialize, Serialize,
-};
+use parking_lot::{Mutex, RwLock, RwLockReadGuard};
 use rustc_hash::{FxHashMap, FxHashSet, FxHasher};
 use std::{
     borrow::BorrowMut,
-    mem::size_of,
-    path::Path,
     sync::{
-        atomic::{AtomicU64, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, OnceLock,
     },
+    time::{Duration, Instant},
 };
 use std::{
-    fmt,
-    ops::{RangeBounds, RangeInclusive},
+    collections::{BTreeMap, VecDeque},
+    fmt, fs, iter, mem,
+    ops::{Range, RangeBounds, RangeInclusive},
+    path::{Path, PathBuf},
 };
 use std::{
     hash::BuildHasherDefault,
-    sync::mpsc::{channel, Sender},
+    sync::mpsc::{RecvTimeoutError, Sender},
     thread::{self, JoinHandle},
 };
 
-#[derive(Archive, Deserialize, Serialize, Clone, Debug)]
-pub struct TableHeaderPage {
-    num_columns: usize,
-    primary_key_index: usize,
-    next_free_page: usize,
-    next_rid: u64,
-    next_tid: u64,
-}
-
 pub struct Table {
     name: String,
-    num_columns: usize,
+    // Pages, directories, bufferpool, RID counters and indexes, see TableData
+    pub(crate) data: Arc<TableData>,
+    // Empty unless the table was created with names, see CrabStore::create_table_named
+    column_names: Vec<String>,
+    signed: SignedColumns,
+    // Last timestamp handed out, see Table::current_timestamp
+    clock: AtomicU64,
     primary_key_index: usize,
-    pub index: RwLock<Index>,
-    next_rid: AtomicU64,
-    next_tid: AtomicU64,
-    page_dir: Arc<RwLock<PageDirectory>>,
-    range_dir: Arc<Mutex<RangeDirectory>>,
-    bufferpool: Arc<Mutex<BufferPool>>,
     lock_manager: Arc<LockManager>,
-    disk: Arc<DiskManager>,
-    merge_thread_handle: Mutex<Option<(JoinHandle<()>, Sender<usize>)>>,
+    // Where TableHeaderPage::write keeps its copy of the header
+    header_file: PathBuf,
+    // The bufferpool's, plus failures writing the directory files, see Table::storage_error
+    storage_failure: Arc<OnceLock<String>>,
+    // Taken when the table closes, see MergeScheduler
+    merge_registration: Mutex<Option<MergeRegistration>>,
+    // Writes dirty pages back between checkpoints, see BufferPool::spawn_flusher
+    flusher_handle: Mutex<Option<(JoinHandle<()>, Sender<Duration>)>>,
+    closed: AtomicBool,
+    active_queries: AtomicUsize,
+    pub(crate) frozen: AtomicBool,
+    pub(crate) frozen_snapshot: RwLock<Option<Arc<FrozenTable>>>,
+    // Set while ensure_primary_index refills the key index, lookups scan until it clears
+    rebuilding_primary: AtomicBool,
+    // Queries and merges hold this shared, checkpoint and refresh take it exclusively
+    checkpoint_lock: Arc<RwLock<()>>,
+    stats: Mutex<TableStats>,
+    merge_stats: Arc<Mutex<MergeStats>>,
+    merge_config: Arc<Mutex<MergeConfig>>,
+    // Taken by every merge, so a forced one waits for the merge thread's
+    merge_lock: Arc<Mutex<()>>,
+    merge_status: Arc<Mutex<Option<MergeError>>>,
+    merge_hook: Arc<Mutex<Option<MergeHook>>>,
+    // Writes by queries, Table::write_stats adds the merges'
+    write_stats: Mutex<WriteStats>,
+    /*
+        (column, value) of inserts between their duplicate check and their index
+        update, for the primary key and unique columns
+    */
+    inserting_keys: Mutex<FxHashSet<(usize, u64)>>,
+    slow_queries: Mutex<VecDeque<SlowQuery>>,
+    // Microseconds
+    slow_query_threshold: AtomicU64,
+}
+
+/*
+    Per-query limits, applied to everything run inside Table::with_context
+*/
+#[derive(Clone, Copy, Debug, Default)]
+pub struct QueryContext {
+    pub budget: Option<QueryBudget>,
+}
+
+/*
+    What Table::upsert_query did with its row
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpsertResult {
+    Inserted,
+    Updated,
+}
+
+/*
+    How Table::update_range changes a column of every row in the range
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpdateOp {
+    Set(u64),
+    // Wraps on overflow like the rest of the column arithmetic
+    Add(u64),
+}
+
+impl UpdateOp {
+    fn apply(self, value: u64) -> u64 {
+        match self {
+            UpdateOp::Set(new) => new,
+            UpdateOp::Add(delta) => value.wrapping_add(delta),
+        }
+    }
+}
+
+/*
+    Order of the records a select returns. Unordered follows the index's posting
+    list or the scan, and may change across a merge or an index build
+*/
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ResultOrder {
+    #[default]
+    Unordered,
+    ByPrimaryKey,
+    // Base RIDs survive merges but not Table::compact_into
+    ByRid,
+}
+
+impl ResultOrder {
+    fn sort(self, records: &mut [Record], key_position: usize, sort_key: impl Fn(u64) -> u64) {
+        match self {
+            ResultOrder::Unordered => {}
+            ResultOrder::ByPrimaryKey => records.sort_unstable_by_key(|record| {
+                (sort_key(record.columns[key_position]), record.rid)
+            }),
+            ResultOrder::ByRid => records.sort_unstable_by_key(|record| record.rid),
+        }
+    }
+}
+
+/*
+    Held for the duration of a public query so persist can wait for it to finish,
+    and a checkpoint never sees it half done
+*/
+struct QueryGuard<'a> {
+    table: &'a Table,
+    query: &'static str,
+    started: Instant,
+    _checkpoint: RwLockReadGuard<'a, ()>,
+}
+
+impl Drop for QueryGuard<'_> {
+    fn drop(&mut self) {
+        let duration = self.started.elapsed();
+
+        if duration.as_micros() as u64 >= self.table.slow_query_threshold.load(Ordering::Relaxed) {
+            let mut slow_queries = self.table.slow_queries.lock();
+
+            if slow_queries.len() == SLOW_QUERY_LOG_SIZE {
+                slow_queries.pop_front();
+            }
+            slow_queries.push_back(SlowQuery {
+                query: self.query,
+                duration,
+            });
+        }
+
+        self.table.active_queries.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/*
+    Claims a primary key, or a value of a unique column, for one insert or one
+    update moving a row to it, so a concurrent write of the same value can't
+    pass the duplicate check before the first one reaches the index
+*/
+struct KeyReservation<'a> {
+    table: &'a Table,
+    column: usize,
+    value: u64,
+}
+
+impl Drop for KeyReservation<'_> {
+    fn drop(&mut self) {
+        self.table
+            .inserting_keys
+            .lock()
+            .remove(&(self.column, self.value));
+    }
+}
+
+/*
+    A query that took at least the table's slow query threshold.
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SlowQuery {
+    // Name of the Table method, e.g. "select_query"
+    pub query: &'static str,
+    pub duration: Duration,
+}
+
+/*
+    How a lookup on a column finds its rows
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessPath {
+    Index,
+    Scan,
+    // Answered by the frozen snapshot, see Table::freeze
+    Frozen,
+}
+
+/*
+    Occupancy of a table's base pages.
+*/
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StorageStats {
+    pub base_pages: usize,
+    pub live_rows: usize,
+    pub deleted_rows: usize,
+    // Deleted rows whose values and index entries a merge has already cleared
+    pub compacted_rows: usize,
+    // Disk pages reserved in the table file, header included, whether or not written yet
+    pub file_pages: usize,
+    // Of those, the ones merges gave back for reuse
+    pub free_pages: usize,
+}
+
+/*
+    Slots written to pages for the rows changed since the table opened or the last
+    Table::reset_stats.
+*/
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WriteStats {
+    // Rows inserted, updated or deleted
+    pub rows_modified: usize,
+    // Metadata slots included, and the base pages merges copied
+    pub slots_written: usize,
+}
+
+impl WriteStats {
+    /*
+        Slots written per row modified, 0 until a row is.
+    */
+    pub fn amplification(&self) -> f64 {
+        if self.rows_modified == 0 {
+            return 0.0;
+        }
+
+        self.slots_written as f64 / self.rows_modified as f64
+    }
+}
+
+/*
+    What Table::compact_into copied, with the table file's size in pages before and
+    after.
+*/
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CompactReport {
+    pub rows: usize,
+    pub old_pages: usize,
+    pub new_pages: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QueryPlan {
+    pub access: AccessPath,
+    // Exact through an index, otherwise from the column statistics
+    pub estimated_rows: f64,
 }
 
 impl Table {
-    pub fn new(
+    pub fn new(name: String, num_columns: usize, key_index: usize, files: &TableFiles) -> Table {
+        Table::with_config(name, num_columns, key_index, files, &CrabConfig::default())
+    }
+
+    /*
+        A new table with the bufferpool, page layout, page ranges and merge
+        threshold config asks for. The layout and page ranges are for good:
+        they are recorded in its header. It's merged on a thread of its own,
+        tables a CrabStore opens share its scheduler's
+    */
+    pub fn with_config(
+        name: String,
+        num_columns: usize,
+        key_index: usize,
+        files: &TableFiles,
+        config: &CrabConfig,
+    ) -> Table {
+        let scheduler = Arc::new(MergeScheduler::new(1));
+        Table::with_scheduler(name, num_columns, key_index, files, config, &scheduler)
+    }
+
+    pub(crate) fn with_scheduler(
         name: String,
         num_columns: usize,
         key_index: usize,
-        db_file: &Path,
-        pd_file: &Path,
-        id_file: &Path,
-        rd_file: &Path,
+        files: &TableFiles,
+        config: &CrabConfig,
+        scheduler: &Arc<MergeScheduler>,
     ) -> Table {
-        let page_dir = Arc::new(RwLock::new(PageDirectory::new(pd_file)));
+        let page_layout = config.page_layout;
+        let range_pages = config.range_pages();
+        let TableFiles {
+            db_file,
+            pd_file,
+            id_file,
+            rd_file,
+            st_file,
+            hd_file,
+        } = files;
+
+        let page_dir = Arc::new(PageDirectory::new(pd_file, range_pages));
         let range_dir = Arc::new(Mutex::new(RangeDirectory::new(rd_file)));
 
         let disk = Arc::new(DiskManager::new(db_file).unwrap());
-        let bufferpool = Arc::new(Mutex::new(BufferPool::new(
-            Arc::clone(&disk),
-            BUFFERPOOL_SIZE,
-        )));
-        let merge_thread_handle =
-            Table::spawn_merge_thread(&page_dir, &range_dir, &disk, &bufferpool, num_columns);
+        let bufferpool = Arc::new(BufferPool::new(Arc::clone(&disk), config.frames()));
+        let storage_failure = bufferpool.failure();
+        let index = Arc::new(RwLock::new(Index::new(key_index, num_columns, id_file)));
+        let lock_manager = Arc::new(LockManager::new());
+        let checkpoint_lock = Arc::new(RwLock::new(()));
+        let merge_stats = Arc::new(Mutex::new(MergeStats::default()));
+        let merge_config = Arc::new(Mutex::new(config.merge_config()));
+        let merge_lock = Arc::new(Mutex::new(()));
+        let base_writes = Arc::new(RwLock::new(()));
+        let merge_status = Arc::new(Mutex::new(None));
+        let merge_hook = Arc::new(Mutex::new(None));
+        let merge_registration = scheduler.register(MergeTarget {
+            page_dir: Arc::clone(&page_dir),
+            range_dir: Arc::clone(&range_dir),
+            disk: Arc::clone(&disk),
+            bufferpool: Arc::clone(&bufferpool),
+            index: Arc::clone(&index),
+            lock_manager: Arc::clone(&lock_manager),
+            checkpoint_lock: Arc::clone(&checkpoint_lock),
+            stats: Arc::clone(&merge_stats),
+            num_columns,
+            page_layout,
+            range_pages,
+            config: Arc::clone(&merge_config),
+            merge_lock: Arc::clone(&merge_lock),
+            base_writes: Arc::clone(&base_writes),
+            status: Arc::clone(&merge_status),
+            hook: Arc::clone(&merge_hook),
+        });
+        let flusher_handle = BufferPool::spawn_flusher(&bufferpool, DEFAULT_FLUSH_INTERVAL);
 
         Table {
             name,
-            num_columns,
+            data: Arc::new(TableData {
+                num_columns,
+                nullable: false,
+                timestamps: true,
+                read_only: false,
+                index,
+                next_rid: 0.into(),
+                next_tid: (!0 - 1).into(),
+                page_dir,
+                range_dir,
+                bufferpool,
+                disk,
+                page_layout,
+                range_pages,
+                base_page_batch: range_pages.into(),
+                tail_block: Mutex::new(None),
+                base_writes,
+            }),
+            column_names: Vec::new(),
+            signed: SignedColumns::default(),
+            clock: 0.into(),
             primary_key_index: key_index,
-            index: RwLock::new(Index::new(key_index, num_columns, id_file)),
-            next_rid: 0.into(),
-            next_tid: (!0 - 1).into(),
-            page_dir,
-            range_dir,
-            disk,
-            bufferpool,
-            merge_thread_handle: Mutex::new(Some(merge_thread_handle)),
-            lock_manager: Arc::new(LockManager::new()),
+            header_file: hd_file.clone(),
+            storage_failure,
+            merge_registration: Mutex::new(Some(merge_registration)),
+            flusher_handle: Mutex::new(Some(flusher_handle)),
+            lock_manager,
+            closed: false.into(),
+            active_queries: 0.into(),
+            frozen: false.into(),
+            frozen_snapshot: RwLock::new(None),
+            rebuilding_primary: false.into(),
+            checkpoint_lock,
+            stats: Mutex::new(TableStats::new(st_file, num_columns)),
+            merge_stats,
+            merge_config,
+            merge_lock,
+            merge_status,
+            merge_hook,
+            write_stats: Mutex::new(WriteStats::default()),
+            inserting_keys: Mutex::new(FxHashSet::default()),
+            slow_queries: Mutex::new(VecDeque::with_capacity(SLOW_QUERY_LOG_SIZE)),
+            slow_query_threshold: (SLOW_QUERY_THRESHOLD_MS * 1000).into(),
         }
     }
 
-    pub fn load(
+    /*
+        Names the columns of a table that has none yet, in column order. Checked
+        by CrabStore::create_table_named, which is the only way names get here
+    */
+    pub(crate) fn with_column_names(mut self, column_names: Vec<String>) -> Table {
+        self.column_names = column_names;
+        self
+    }
+
+    /*
+        Makes columns of a new table signed, before anything is inserted into it
+    */
+    pub(crate) fn with_column_types(mut self, column_types: &[ColumnType]) -> Table {
+        self.signed = SignedColumns::from_types(column_types);
+        let index = mem::take(&mut *self.data.index.write());
+        *self.data.index.write() = index.with_signed(self.signed);
+        self
+    }
+
+    /*
+        Gives every record of a new table a mask of its NULL columns, in a column
+        of its own after the table's columns. Set before anything is inserted
+    */
+    pub(crate) fn with_nullable(mut self, nullable: bool) -> Table {
+        Arc::get_mut(&mut self.data)
+            .expect("nothing shares a table's data before it is built")
+            .nullable = nullable;
+        self
+    }
+
+    pub fn load(name: &str, files: &TableFiles, config: &CrabConfig) -> Result<Self, CrabError> {
+        let scheduler = Arc::new(MergeScheduler::new(1));
+        Table::load_with_scheduler(name, files, config, &scheduler)
+    }
+
+    pub(crate) fn load_with_scheduler(
         name: &str,
-        db_file: &Path,
-        pd_file: &Path,
-        id_file: &Path,
-        rd_file: &Path,
-    ) -> Self {
-        let disk = Arc::new(DiskManager::new(db_file).expect("Failed to open table file"));
+        files: &TableFiles,
+        config: &CrabConfig,
+        scheduler: &Arc<MergeScheduler>,
+    ) -> Result<Self, CrabError> {
+        let TableFiles {
+            db_file,
+            pd_file,
+            id_file,
+            rd_file,
+            st_file,
+            hd_file,
+        } = files;
+
+        let disk = Arc::new(
+            if config.read_only {
+                DiskManager::open_read_only(db_file)
+            } else {
+                DiskManager::new(db_file)
+            }
+            .map_err(|err| archive::unavailable(db_file, err))?,
+        );
 
-        let mut page = PhysicalPage::default();
+        let header = TableHeaderPage::read(&disk, db_file, hd_file)?;
 
-        disk.read_page(0, &mut page.page);
+        let index = Arc::new(RwLock::new(
+            Index::load(id_file, header.num_columns)?.with_signed(header.signed_columns),
+        ));
+        let page_dir = Arc::new(PageDirectory::load(pd_file, header.range_pages)?);
+        let range_dir = Arc::new(Mutex::new(RangeDirectory::load(rd_file)?));
+        let stats = TableStats::load(st_file, header.num_columns)?;
 
-        let header = unsafe {
-            rkyv::from_bytes_unchecked::<TableHeaderPage>(
-                &page.page[0..size_of::<<TableHeaderPage as Archive>::Archived>()],
+        // Converted once, before anything reads a page through the bufferpool
+        let big_endian = header.page_order == PageOrder::WriterNative
+            && format::written_big_endian(
+                &disk,
+                page_dir.get_page(0).map(|cols| cols[METADATA_BASE_RID]),
             )
-            .expect("Failed to deserialize table header")
-        };
+            .map_err(|err| archive::unavailable(db_file, err))?;
+
+        if big_endian {
+            if config.read_only {
+                return Err(CrabError::CorruptFile {
+                    path: db_file.clone(),
+                    reason: "pages written big-endian, open it writable once to convert them"
+                        .into(),
+                });
+            }
 
-        disk.set_free_page_pointer(header.next_free_page);
+            format::swap_page_order(&disk, header.next_free_page)
+                .and_then(|_| {
+                    TableHeaderPage {
+                        page_order: PageOrder::LittleEndian,
+                        ..header.clone()
+                    }
+                    .write(&disk, hd_file)
+                })
+                .map_err(|err| archive::unavailable(db_file, err))?;
+        }
+        let analyze = stats.is_none();
 
-        let index = RwLock::new(Index::load(id_file));
-        let page_dir = Arc::new(RwLock::new(PageDirectory::load(pd_file)));
-        let range_dir = Arc::new(Mutex::new(RangeDirectory::load(rd_file)));
-        let bufferpool = Arc::new(Mutex::new(BufferPool::new(
-            Arc::clone(&disk),
-            BUFFERPOOL_SIZE,
-        )));
-
-        let merge_thread_handle = Table::spawn_merge_thread(
-            &page_dir,
-            &range_dir,
-            &disk,
-            &bufferpool,
-            header.num_columns,
-        );
+        disk.set_free_page_pointer(header.next_free_page);
+        disk.set_free_runs(&header.free_runs);
+        let bufferpool = Arc::new(BufferPool::new(Arc::clone(&disk), config.frames()));
+        let storage_failure = bufferpool.failure();
+
+        // Merging rewrites pages, which a reader must leave to the writer
+        let lock_manager = Arc::new(LockManager::new());
+        let checkpoint_lock = Arc::new(RwLock::new(()));
+        let merge_stats = Arc::new(Mutex::new(MergeStats::default()));
+        let merge_config = Arc::new(Mutex::new(config.merge_config()));
+        let merge_lock = Arc::new(Mutex::new(()));
+        let base_writes = Arc::new(RwLock::new(()));
+        let merge_status = Arc::new(Mutex::new(None));
+        let merge_hook = Arc::new(Mutex::new(None));
+        let merge_registration = (!config.read_only).then(|| {
+            scheduler.register(MergeTarget {
+                page_dir: Arc::clone(&page_dir),
+                range_dir: Arc::clone(&range_dir),
+                disk: Arc::clone(&disk),
+                bufferpool: Arc::clone(&bufferpool),
+                index: Arc::clone(&index),
+                lock_manager: Arc::clone(&lock_manager),
+                checkpoint_lock: Arc::clone(&checkpoint_lock),
+                stats: Arc::clone(&merge_stats),
+                num_columns: header.num_columns,
+                page_layout: header.page_layout,
+                range_pages: header.range_pages,
+                config: Arc::clone(&merge_config),
+                merge_lock: Arc::clone(&merge_lock),
+                base_writes: Arc::clone(&base_writes),
+                status: Arc::clone(&merge_status),
+                hook: Arc::clone(&merge_hook),
+            })
+        });
+        let flusher_handle = (!config.read_only)
+            .then(|| BufferPool::spawn_flusher(&bufferpool, DEFAULT_FLUSH_INTERVAL));
 
-        Table {
+        let table = Table {
             name: name.into(),
-            num_columns: header.num_columns,
+            data: Arc::new(TableData {
+                num_columns: header.num_columns,
+                nullable: header.nullable,
+                timestamps: header.timestamps,
+                read_only: config.read_only,
+                index,
+                next_rid: header.next_rid.into(),
+                next_tid: header.next_tid.into(),
+                page_dir,
+                range_dir,
+                bufferpool,
+                disk,
+                page_layout: header.page_layout,
+                range_pages: header.range_pages,
+                base_page_batch: header.range_pages.into(),
+                tail_block: Mutex::new(None),
+                base_writes,
+            }),
+            column_names: header.column_names.clone(),
+            signed: header.signed_columns,
+            clock: header.clock.into(),
             primary_key_index: header.primary_key_index,
-            index,
-            page_dir,
-            range_dir,
-            disk,
-            bufferpool,
-            next_rid: header.next_rid.into(),
-            next_tid: header.next_tid.into(),
-            merge_thread_handle: Mutex::new(Some(merge_thread_handle)),
-            lock_manager: Arc::new(LockManager::new()),
-        }
-    }
-
-    pub fn persist(&self) {
-        let merge_thread_handle =
-            std::mem::replace(&mut *self.merge_thread_handle.lock(), None).unwrap();
-
-        drop(merge_thread_handle.1);
-        merge_thread_handle
-            .0
-            .join()
-            .expect("Failed to join merge thread");
-
-        let header = TableHeaderPage {
-            num_columns: self.num_columns,
-            primary_key_index: self.primary_key_index,
-            next_rid: self.next_rid.load(Ordering::Relaxed),
-            next_tid: self.next_tid.load(Ordering::Relaxed),
-            next_free_page: self.disk.free_page_pointer(),
+            header_file: hd_file.clone(),
+            storage_failure,
+            merge_registration: Mutex::new(merge_registration),
+            flusher_handle: Mutex::new(flusher_handle),
+            lock_manager,
+            closed: false.into(),
+            active_queries: 0.into(),
+            frozen: false.into(),
+            frozen_snapshot: RwLock::new(None),
+            rebuilding_primary: false.into(),
+            checkpoint_lock,
+            stats: Mutex::new(
+                stats.unwrap_or_else(|| TableStats::new(st_file, header.num_columns)),
+            ),
+            merge_stats,
+            merge_config,
+            merge_lock,
+            merge_status,
+            merge_hook,
+            write_stats: Mutex::new(WriteStats::default()),
+            inserting_keys: Mutex::new(FxHashSet::default()),
+            slow_queries: Mutex::new(VecDeque::with_capacity(SLOW_QUERY_LOG_SIZE)),
+            slow_query_threshold: (SLOW_QUERY_THRESHOLD_MS * 1000).into(),
         };
 
-        let mut page = [0; PAGE_SIZE];
-        let mut serializer = BufferSerializer::new(&mut page);
+        // A running writer is always ahead of its last checkpoint, that's no reason to repair
+        if !config.read_only {
+            table.check_consistency(config.strict)?;
+        }
 
-        serializer
-            .serialize_value(&header)
-            .expect("Unable to serialize table header");
+        for column in 0..table.data.num_columns {
+            if header.indexed_columns & (1 << column) != 0
+                && !table.data.index.read().is_indexed(column)
+            {
+                table.build_index(column);
+            }
+            if header.unique_columns & (1 << column) != 0 {
+                table.data.index.write().set_unique(column);
+            }
+        }
 
-        self.disk.write_page(0, &page);
-        self.disk.flush();
+        // Written before statistics existed
+        if analyze {
+            table.analyze()?;
+        }
 
-        self.bufferpool.lock().flush_all();
+        Ok(table)
+    }
 
-        let page_dir = self.page_dir.write();
-        page_dir.persist();
+    /*
+        persist writes the header and each directory separately, so a crash in between
+        leaves them disagreeing. Data pages are the most trustworthy evidence we have,
+        repair the header and range directory from them unless asked to be strict.
+    */
+    fn check_consistency(&self, strict: bool) -> Result<(), CrabError> {
+        let mismatch = |reason: String| {
+            if strict {
+                Err(CrabError::InconsistentTable(format!(
+                    "{}: {reason}",
+                    self.name
+                )))
+            } else {
+                Ok(())
+            }
+        };
 
-        let range_dir = self.range_dir.lock();
-        range_dir.persist();
+        // Every column page the directory references, the TPS pages included, must
+        // lie below the free page pointer or it will be handed out again
+        let free_page = self.data.disk.free_page_pointer();
+        if let Some((page, column, disk_page)) = self
+            .data
+            .page_dir
+            .pages()
+            .flat_map(|(page, cols)| {
+                (0..cols.len())
+                    .map(|c| (page, c, cols[c]))
+                    .collect::<Vec<_>>()
+            })
+            .max_by_key(|x| x.2)
+        {
+            if disk_page >= free_page {
+                mismatch(format!(
+                    "page {page} column {column} uses disk page {disk_page} past free page pointer {free_page}"
+                ))?;
+                self.data.disk.set_free_page_pointer(disk_page + 1);
+            }
+        }
 
-        let index = self.index.write();
-        index.persist();
-    }
+        // A directory written after the header may already have reused pages the header lists as free
+        let used = self
+            .data
+            .page_dir
+            .pages()
+            .flat_map(|(_, cols)| cols.to_vec())
+            .collect::<FxHashSet<usize>>();
+        let reused = self.data.disk.retain_free(|page| !used.contains(&page));
+        if reused != 0 {
+            mismatch(format!("{reused} disk pages in use are on the free list"))?;
+        }
 
-    pub fn next_tid(&self, range_id: usize) -> RID {
-        let mut range_dir = self.range_dir.lock();
+        let mut base_pages = self
+            .data
+            .page_dir
+            .pages()
+            .filter(|(page, _)| !RID::is_tail_page(*page))
+            .map(|(page, cols)| (page, Page::new(cols)))
+            .collect::<Vec<(usize, Page)>>();
+
+        let tail_pages = self
+            .data
+            .page_dir
+            .pages()
+            .filter(|(page, _)| RID::is_tail_page(*page))
+            .map(|(page, cols)| (page, Page::new(cols)))
+            .collect::<Vec<(usize, Page)>>();
+
+        // A base page's TPS is RID_INVALID until it's merged and a tail RID after, anything
+        // else, or a header page the file can't give back whole, can't be trusted
+        for (page, cols) in &base_pages {
+            let disk_page = cols.read_col(METADATA_PAGE_HEADER);
+            let mut header = PhysicalPage::default();
+            let tps = match self.data.disk.read_page(disk_page, &mut header.page) {
+                Ok(PAGE_SIZE) => Some(header.slot(0)),
+                _ => None,
+            };
+
+            if tps.is_some_and(|tps| tps == RID_INVALID || RID(tps).is_tail()) {
+                continue;
+            }
 
-        if range_id >= range_dir.next_range_id() {
-            println!("range id {}, {}", range_id, range_dir.next_range_id());
+            mismatch(match tps {
+                Some(tps) => format!("base page {page} has TPS {tps}, which isn't a tail RID"),
+                None => format!("base page {page} TPS in disk page {disk_page} can't be read"),
+            })?;
 
-            assert!(range_id == range_dir.next_range_id());
+            // Assuming nothing was merged is always safe, reads follow the tail records instead
+            cols.write_page_tps(&self.data.bufferpool, RID_INVALID);
+        }
 
-            let new_page = self.allocate_tail_page();
+        // A written base slot keeps its own RID in the base RID column even once deleted
+        base_pages.sort_by_key(|x| x.0);
+        let written_rid = base_pages.iter().rev().find_map(|(page, cols)| {
+            let rids = cols.get_column(&self.data.bufferpool, METADATA_RID);
+            let base_rids = cols.get_column(&self.data.bufferpool, METADATA_BASE_RID);
+            (0..PAGE_SLOTS).rev().find_map(|slot| {
+                let rid = RID::base(*page, slot).raw();
+                (rids.slot(slot) == rid || base_rids.slot(slot) == rid).then_some(rid)
+            })
+        });
+
+        if let Some(rid) = written_rid {
+            if self.data.next_rid.load(Ordering::Relaxed) <= rid {
+                mismatch(format!(
+                    "next rid {} is not past written rid {rid}",
+                    self.data.next_rid.load(Ordering::Relaxed)
+                ))?;
+                self.data.next_rid.store(rid + 1, Ordering::Relaxed);
+            }
+        }
 
-            self.get_page_by_id(new_page.current_tail_page.load(Ordering::Relaxed))
-                .write_last_tail(&mut self.bufferpool.lock(), RID_INVALID);
+        let range_tails = self
+            .data
+            .range_dir
+            .lock()
+            .ranges()
+            .map(|range| range.current_tail_page.load(Ordering::Relaxed))
+            .collect::<Vec<usize>>();
 
-            range_dir.allocate_range(new_page);
+        // Tail pages are handed out downwards, the next one must sit below all of them
+        let lowest_tail = tail_pages
+            .iter()
+            .map(|x| x.0)
+            .chain(
+                range_tails
+                    .iter()
+                    .copied()
+                    .filter(|x| RID::is_tail_page(*x)),
+            )
+            .min();
+
+        if let Some(lowest_tail) = lowest_tail {
+            if RID::from(self.data.next_tid.load(Ordering::Relaxed)).page() >= lowest_tail {
+                mismatch(format!(
+                    "next tid {} overlaps allocated tail page {lowest_tail}",
+                    self.data.next_tid.load(Ordering::Relaxed)
+                ))?;
+                self.data
+                    .next_tid
+                    .store(RID::tail(lowest_tail - 1, 0).raw(), Ordering::Relaxed);
+            }
         }
 
-        let range = range_dir.get(range_id);
-        if range.tail_is_full() {
-            let last_tail_page = range.current_tail_page.load(Ordering::Relaxed);
-            let new_tail = self.allocate_tail_page();
+        for (range_id, tail) in range_tails.into_iter().enumerate() {
+            if self.data.page_dir.get_page(tail).is_some() {
+                continue;
+            }
 
-            self.get_page_by_id(new_tail.current_tail_page.load(Ordering::Relaxed))
-                .write_last_tail(&mut self.bufferpool.lock(), last_tail_page as u64);
+            mismatch(format!(
+                "range {range_id} tail page {tail} is missing from the page directory"
+            ))?;
 
-            range_dir.new_range_tail(range_id, new_tail);
+            // The first slot written in a tail page tells us which range owns it
+            let last_tail = tail_pages
+                .iter()
+                .filter(|(_, cols)| {
+                    RID(cols
+                        .get_column(&self.data.bufferpool, METADATA_BASE_RID)
+                        .slot(0))
+                    .page_range(self.data.range_pages)
+                        == range_id
+                })
+                .map(|x| x.0 as u64)
+                .min()
+                .unwrap_or(RID_INVALID);
 
-            let merge_thread_handle = self.merge_thread_handle.lock();
-            merge_thread_handle
-                .as_ref()
-                .expect("No merge handle")
-                .1
-                .send(range_id)
-                .expect("Unable to send range id to merge channel");
+            let new_tail = self.data.allocate_tail_page();
+
+            self.data
+                .get_page_by_id(new_tail.current_tail_page.load(Ordering::Relaxed))
+                .write_last_tail(&self.data.bufferpool, last_tail);
+
+            self.data
+                .range_dir
+                .lock()
+                .new_range_tail(range_id, new_tail);
         }
 
-        range_dir.get(range_id).next_tid()
+        Ok(())
     }
 
-    pub fn allocate_tail_page(&self) -> PageRange {
-        let next_tid: RID = self
-            .next_tid
-            .fetch_sub(PAGE_SLOTS as u64, Ordering::Relaxed)
-            .into();
+    /*
+        Persisting closes the table. Queries started after this point fail with
+        TableClosed, queries already running get a bounded amount of time to finish.
+        Fails with StorageUnavailable if the table's files couldn't be written, in
+        which case the table is closed all the same, and with MergeFailed after
+        writing them if a merge failed since the table opened.
+    */
+    pub fn persist(&self) -> Result<(), CrabError> {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
 
-        let tail_reserve_start = self.disk.reserve_range(self.total_columns());
-        let mut column_pages = Arc::<[usize]>::new_uninit_slice(self.total_columns());
+        self.drain_queries();
 
-        for (i, x) in (tail_reserve_start..(tail_reserve_start + self.total_columns())).enumerate()
-        {
-            Arc::get_mut(&mut column_pages).unwrap()[i].write(x);
-        }
+        self.close_merges();
 
-        let column_pages = unsafe { column_pages.assume_init() };
+        // Stopped before the checkpoint so the last pages are written by flush_all alone
+        let flusher_handle = self.flusher_handle.lock().take();
+        if let Some((handle, flusher_send)) = flusher_handle {
+            drop(flusher_send);
+            handle.join().expect("Failed to join flusher thread");
+        }
 
-        let mut page_dir = self.page_dir.write();
+        if self.data.read_only {
+            return Ok(());
+        }
 
-        page_dir.new_page(next_tid.page(), column_pages);
+        // Writing the rest after a failure would only leave mismatched files behind
+        if let Some(err) = self.storage_error() {
+            return Err(err);
+        }
+        self.write_checkpoint()?;
 
-        drop(page_dir);
+        // A file left longer reads the same, its extra pages are zeros past the last reserved one
+        let _ = self.data.disk.trim();
 
-        PageRange::new(next_tid.raw(), next_tid.page())
+        // Everything is written all the same, what a failed merge left is still readable
+        match self.merge_status() {
+            Some(err) => Err(err.into()),
+            None => Ok(()),
+        }
     }
 
-    #[inline(always)]
-    pub fn get_page(&self, rid: RID) -> Page {
-        Page::new(self.page_dir.read().get(rid).expect("Page get fail"))
+    /*
+        Waits for the merges already requested, none are made after. Persisting
+        and CrabStore::drop_table stop them
+    */
+    pub(crate) fn close_merges(&self) {
+        let merge_registration = self.merge_registration.lock().take();
+        if let Some(merge_registration) = merge_registration {
+            merge_registration.close();
+        }
     }
 
-    #[inline(always)]
-    fn get_page_by_id(&self, id: usize) -> Page {
-        Page::new(self.page_dir.read().get_page(id).expect("Page get fail"))
-    }
+    /*
+        Writes everything a reader needs to see the table as it is now, without closing it.
+        Queries and merges are held off meanwhile, so no record is caught half written.
+    */
+    pub fn checkpoint(&self) -> Result<(), CrabError> {
+        if self.data.read_only || self.is_closed() {
+            return Ok(());
+        }
 
-    pub fn get_bufferpool(&self) -> Arc<Mutex<BufferPool>> {
-        Arc::clone(&self.bufferpool)
-    }
+        let _checkpoint = self.checkpoint_lock.write();
 
-    pub fn get_lock_manager(&self) -> Arc<LockManager> {
-        Arc::clone(&self.lock_manager)
+        if let Some(err) = self.storage_error() {
+            return Err(err);
+        }
+        self.write_checkpoint()
     }
 
-    fn find_row(&self, column_index: usize, value: u64) -> Option<RID> {
-        match self.index.read().get_from_index(column_index, value) {
-            Some(vals) => vals
-                .iter()
-                .find(|x| {
-                    self.get_page(**x)
-                        .get_column(self.bufferpool.lock().borrow_mut(), METADATA_RID)
-                        .slot(x.slot())
-                        != RID_INVALID
-                })
-                .copied(),
-            None => {
-                let mut rid: RID = 0.into();
+    /*
+        Waits for the background work queued before the call: the merges already
+        requested and a primary index rebuild, then writes dirty pages back. Work
+        that writers still running queue meanwhile is left alone, so this returns
+        even if they never stop. Fails with QuiesceTimeout once timeout is up.
+    */
+    pub fn quiesce(&self, timeout: Duration) -> Result<(), CrabError> {
+        if self.is_closed() {
+            return Ok(());
+        }
 
-                let next_rid = self.next_rid.load(Ordering::Relaxed);
+        let deadline = Instant::now() + timeout;
 
-                while rid.raw() < next_rid {
-                    let page = self.get_page(rid);
+        let merged = self
+            .merge_registration
+            .lock()
+            .as_ref()
+            .and_then(MergeRegistration::quiesce);
 
-                    if page
-                        .get_column(self.bufferpool.lock().borrow_mut(), METADATA_RID)
-                        .slot(rid.slot())
-                        == RID_INVALID
-                    {
-                        rid = rid.next();
-                        continue;
-                    }
+        // Disconnected means the table closed meanwhile, which stopped the merges
+        if let Some(merged) = merged {
+            if let Err(RecvTimeoutError::Timeout) =
+                merged.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            {
+                return Err(CrabError::QuiesceTimeout);
+            }
+        }
 
-                    drop(page);
+        while self.rebuilding_primary.load(Ordering::SeqCst) {
+            if Instant::now() >= deadline {
+                return Err(CrabError::QuiesceTimeout);
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
 
-                    let latest_rid = self.get_latest(rid);
-                    let latest_page = self.get_page(latest_rid);
+        if self.data.read_only {
+            return Ok(());
+        }
 
-                    if latest_page
-                        .get_column(
-                            self.bufferpool.lock().borrow_mut(),
-                            NUM_METADATA_COLUMNS + column_index,
-                        )
-                        .slot(latest_rid.slot())
-                        == value
-                    {
-                        return Some(rid);
-                    }
-                }
+        // Like a checkpoint, so no record is flushed half written
+        let Some(_checkpoint) = self.checkpoint_lock.try_write_until(deadline) else {
+            return Err(CrabError::QuiesceTimeout);
+        };
+        self.data.bufferpool.flush_all();
 
-                None
-            }
+        match self.storage_error() {
+            Some(err) => Err(err),
+            None => Ok(()),
         }
     }
 
-    fn find_rows(&self, column_index: usize, value: u64) -> Vec<RID> {
-        match self.index.read().get_from_index(column_index, value) {
-            Some(vals) => vals
-                .into_iter()
-                .filter(|x| {
-                    self.get_page(*x)
-                        .get_column(self.bufferpool.lock().borrow_mut(), METADATA_RID)
-                        .slot(x.slot())
-                        != RID_INVALID
-                })
-                .collect(),
-            None => {
-                let mut rid: RID = 0.into();
-                let mut rids = Vec::new();
-                let next_rid = self.next_rid.load(Ordering::Relaxed);
+    /*
+        Data pages go first and the header last, so whichever files a reader
+        picks up after the header describe at least the rows the header counts.
+        Stops at the first file that can't be written and remembers why. Merged
+        tail pages past the ones MergeConfig retains are reclaimed first.
+    */
+    fn write_checkpoint(&self) -> Result<(), CrabError> {
+        if let Some(retained) = self.merge_config().retained_tail_pages {
+            self.merge_target().reclaim_tail_pages(retained);
+        }
 
-                while rid.raw() < next_rid {
-                    let page = self.get_page(rid);
+        let written = self.write_files();
 
-                    if page
-                        .get_column(self.bufferpool.lock().borrow_mut(), METADATA_RID)
-                        .slot(rid.slot())
-                        == RID_INVALID
-                    {
-                        rid = rid.next();
-                        continue;
-                    }
+        if let Err(CrabError::StorageUnavailable(reason)) = &written {
+            let _ = self.storage_failure.set(reason.clone());
+        }
 
-                    let latest_rid = self.get_latest(rid);
+        written
+    }
 
-                    if self
-                        .get_page(latest_rid)
-                        .get_column(
-                            self.bufferpool.lock().borrow_mut(),
-                            NUM_METADATA_COLUMNS + column_index,
-                        )
-                        .slot(latest_rid.slot())
-                        == value
-                    {
-                        rids.push(rid);
-                    }
+    fn write_files(&self) -> Result<(), CrabError> {
+        self.data.bufferpool.flush_all();
+        if let Some(err) = self.storage_error() {
+            return Err(err);
+        }
 
-                    rid = rid.next();
-                }
+        self.data.index.read().persist()?;
+        self.data.range_dir.lock().persist()?;
+        self.data.page_dir.persist()?;
+        self.stats.lock().persist()?;
 
-                rids
-            }
+        TableHeaderPage {
+            num_columns: self.data.num_columns,
+            primary_key_index: self.primary_key_index,
+            next_rid: self.data.next_rid.load(Ordering::Relaxed),
+            next_tid: self.data.next_tid.load(Ordering::Relaxed),
+            next_free_page: self.data.disk.free_page_pointer(),
+            indexed_columns: self
+                .data
+                .index
+                .read()
+                .indexed_columns()
+                .iter()
+                .fold(0, |mask, column| mask | 1 << column),
+            page_order: PageOrder::LittleEndian,
+            page_layout: self.data.page_layout,
+            column_names: self.column_names.clone(),
+            signed_columns: self.signed,
+            nullable: self.data.nullable,
+            timestamps: self.data.timestamps,
+            clock: self.clock.load(Ordering::SeqCst),
+            free_runs: self.data.disk.free_runs(),
+            page_size: PAGE_SIZE,
+            range_pages: self.data.range_pages,
+            unique_columns: self.data.index.read().unique_mask(),
         }
+        .write(&self.data.disk, &self.header_file)
+        .map_err(|err| CrabError::StorageUnavailable(format!("header: {err}")))?;
+
+        // Nothing written above references the pages freed since the last checkpoint
+        self.data.disk.reuse_freed();
+        Ok(())
     }
 
-    fn find_rows_range(
-        &self,
-        column_index: usize,
-        range: impl RangeBounds<u64> + Clone,
-    ) -> Vec<RID> {
-        match self
-            .index
-            .read()
-            .range_from_index(column_index, range.clone())
-        {
-            Some(vals) => vals,
-            None => {
-                let mut rids: Vec<RID> = Vec::new();
-                let mut rid: RID = 0.into();
-                let next_rid = self.next_rid.load(Ordering::Relaxed);
+    /*
+        Set once reading or writing one of the table's files has failed. Pages
+        read since may have come back zeroed, so nothing the table returns or
+        writes from then on can be trusted.
+    */
+    fn storage_error(&self) -> Option<CrabError> {
+        self.storage_failure
+            .get()
+            .map(|reason| CrabError::StorageUnavailable(reason.clone()))
+    }
 
-                while rid.raw() < next_rid {
-                    let key = self
-                        .get_page(rid)
-                        .get_column(
-                            self.bufferpool.lock().borrow_mut(),
-                            NUM_METADATA_COLUMNS + self.primary_key_index,
-                        )
-                        .slot(rid.slot());
+    /*
+        Catches a read-only table up with the writer's last checkpoint, reading
+        its files in the opposite order they are written. Fails with CorruptFile
+        if the writer was rewriting the header just then, try again.
+    */
+    pub fn refresh(&self, files: &TableFiles) -> Result<(), CrabError> {
+        assert!(
+            self.data.read_only,
+            "Only read-only tables can be refreshed"
+        );
 
-                    if range.contains(&key) {
-                        rids.push(rid);
-                    }
+        let TableFiles {
+            db_file,
+            pd_file,
+            id_file,
+            rd_file,
+            st_file,
+            hd_file,
+        } = files;
+
+        let _checkpoint = self.checkpoint_lock.write();
+
+        let header = TableHeaderPage::read(&self.data.disk, db_file, hd_file)?;
+        let page_dir = PageDirectory::load(pd_file, self.data.range_pages)?;
+        let range_dir = RangeDirectory::load(rd_file)?;
+        let index = Index::load(id_file, self.data.num_columns)?.with_signed(self.signed);
+        let stats = TableStats::load(st_file, self.data.num_columns)?;
+
+        self.data.page_dir.replace_with(page_dir);
+        *self.data.range_dir.lock() = range_dir;
+        *self.data.index.write() = index;
+        if let Some(stats) = stats {
+            *self.stats.lock() = stats;
+        }
+        self.data.next_rid.store(header.next_rid, Ordering::Relaxed);
+        self.data.next_tid.store(header.next_tid, Ordering::Relaxed);
+        self.clock.store(header.clock, Ordering::Relaxed);
+        self.data.disk.set_free_page_pointer(header.next_free_page);
 
-                    rid = rid.next();
-                }
+        // Cached pages may predate what the writer has flushed since
+        self.data.bufferpool.discard_all();
 
-                rids
-            }
-        }
+        Ok(())
     }
 
-    pub fn is_latest(&self, rid: RID) -> bool {
-        let mut bp = self.bufferpool.lock();
-        self.get_page(rid).read_page_tps(bp.borrow_mut())
-            <= self
-                .get_page(rid)
-                .get_column(bp.borrow_mut(), METADATA_INDIRECTION)
-                .slot(rid.slot())
+    pub(crate) fn next_tid(&self, range_id: usize) -> RID {
+        let mut range_dir = self.data.range_dir.lock();
+        self.take_tid(&mut range_dir, range_id)
     }
 
-    pub fn get_latest(&self, rid: RID) -> RID {
-        let page = self.get_page(rid);
+    /*
+        Tail RIDs for count updates to the same page range, taking the range
+        directory lock once rather than per record
+    */
+    fn next_tids(&self, range_id: usize, count: usize) -> Vec<RID> {
+        let mut range_dir = self.data.range_dir.lock();
+        (0..count)
+            .map(|_| self.take_tid(&mut range_dir, range_id))
+            .collect()
+    }
 
-        let mut bp = self.bufferpool.lock();
+    fn take_tid(&self, range_dir: &mut RangeDirectory, range_id: usize) -> RID {
+        // Concurrent updates can reach a later range before an earlier one got its first tail
+        while range_id >= range_dir.next_range_id() {
+            let new_page = self.data.allocate_tail_page();
 
-        let indir = page
-            .get_column(bp.borrow_mut(), METADATA_INDIRECTION)
-            .slot(rid.slot());
+            self.data
+                .get_page_by_id(new_page.current_tail_page.load(Ordering::Relaxed))
+                .write_last_tail(&self.data.bufferpool, RID_INVALID);
 
-        if indir == RID_INVALID || page.read_page_tps(bp.borrow_mut()) <= indir {
-            rid
-        } else {
-            indir.into()
+            range_dir.allocate_range(new_page);
         }
-    }
 
-    pub fn get_latest_with_bp(&self, bp: &mut BufferPool, rid: RID) -> RID {
-        let page = self.get_page(rid);
+        let range = range_dir.get(range_id);
+        if range.tail_is_full() {
+            let last_tail_page = range.current_tail_page.load(Ordering::Relaxed);
+            let new_tail = self.data.allocate_tail_page();
 
-        let indir = page
-            .get_column(bp.borrow_mut(), METADATA_INDIRECTION)
-            .slot(rid.slot());
+            self.data
+                .get_page_by_id(new_tail.current_tail_page.load(Ordering::Relaxed))
+                .write_last_tail(&self.data.bufferpool, last_tail_page as u64);
 
-        if indir == RID_INVALID || page.read_page_tps(bp.borrow_mut()) <= indir {
-            rid
-        } else {
-            indir.into()
-        }
-    }
+            range_dir.new_range_tail(range_id, new_tail);
 
-    pub fn merge_values(&self, base_rid: RID, columns: &[Option<u64>]) -> Vec<u64> {
-        let rid = self.get_latest(base_rid);
-        let page = self.get_page(rid);
+            // The table's merges already stopped if a query outlived the close drain
+            if let Some(merge_registration) = self.merge_registration.lock().as_ref() {
+                merge_registration.submit(range_id);
+            }
+        }
 
-        let mut bp = self.bufferpool.lock();
-        columns
-            .iter()
-            .enumerate()
-            .map(|(i, x)| match x {
-                None => page
-                    .get_column(&mut bp, NUM_METADATA_COLUMNS + i)
-                    .slot(rid.slot()),
-                Some(val) => *val,
-            })
-            .collect()
+        range_dir.get(range_id).next_tid()
     }
 
-    pub fn total_columns(&self) -> usize {
-        NUM_METADATA_COLUMNS + self.num_columns
-    }
+    /*
+        Writes a full tail record for base_rid, stamped with a new timestamp,
+        publishing its RID last. values is the row as stored_row gives it, NULL
+        mask and all. The caller points the base record at it afterwards.
+    */
+    fn write_tail(
+        &self,
+        bp: &BufferPool,
+        tail_rid: RID,
+        base_rid: RID,
+        old_latest_rid: RID,
+        values: &[u64],
+        schema_encoding: u64,
+    ) {
+        let tail_page = self.data.get_page(tail_rid);
 
-    pub fn columns(&self) -> usize {
-        self.num_columns
+        tail_page
+            .get_column(bp, METADATA_BASE_RID)
+            .write_slot(tail_rid.slot(), base_rid.raw());
+
+        tail_page.get_column(bp, METADATA_INDIRECTION).write_slot(
+            tail_rid.slot(),
+            if old_latest_rid.is_invalid() {
+                base_rid.raw()
+            } else {
+                old_latest_rid.raw()
+            },
+        );
+
+        for (i, val) in values.iter().enumerate() {
+            tail_page
+                .get_column(bp, NUM_METADATA_COLUMNS + i)
+                .write_slot(tail_rid.slot(), *val);
+        }
+
+        tail_page
+            .get_column(bp, METADATA_SCHEMA_ENCODING)
+            .write_slot(tail_rid.slot(), schema_encoding);
+
+        if self.data.timestamps {
+            tail_page
+                .get_column(bp, self.data.timestamp_column())
+                .write_slot(tail_rid.slot(), self.tick(1));
+        }
+
+        tail_page
+            .get_column(bp, METADATA_RID)
+            .write_slot(tail_rid.slot(), tail_rid.raw());
+    }
+
+    /*
+        Counts rows changed by a query and the slots written for them. An insert
+        or a tail record writes every column but the page header.
+    */
+    fn record_writes(&self, rows: usize, slots: usize) {
+        let mut stats = self.write_stats.lock();
+        stats.rows_modified += rows;
+        stats.slots_written += slots;
+    }
+
+    /*
+        Whether the table has been persisted for shutdown and stopped accepting
+        queries.
+    */
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /*
+        Runs queries against this table under the limits in context. A Fail budget
+        turns the query's result into BudgetExceeded once it goes over, whatever
+        the query managed to return.
+    */
+    pub fn with_context<R>(
+        &self,
+        context: &QueryContext,
+        query: impl FnOnce(&Table) -> Result<R, CrabError>,
+    ) -> Result<R, CrabError> {
+        let Some(budget) = context.budget else {
+            return query(self);
+        };
+
+        let (id, previous) = self.data.bufferpool.begin_budget(budget);
+        let result = query(self);
+        let usage = self.data.bufferpool.end_budget(id, previous);
+
+        if usage.exceeded && budget.policy == BudgetPolicy::Fail {
+            return Err(CrabError::BudgetExceeded {
+                fetches: usage.fetches,
+                max_fetches: budget.max_fetches,
+            });
+        }
+
+        result
+    }
+
+    /*
+        Waits a bounded amount of time for running queries to finish, and
+        says whether every one did within CLOSE_DRAIN_TIMEOUT_MS
+    */
+    pub(crate) fn drain_queries(&self) -> bool {
+        let drain_start = Instant::now();
+        while self.active_queries.load(Ordering::SeqCst) != 0 {
+            if drain_start.elapsed() >= Duration::from_millis(CLOSE_DRAIN_TIMEOUT_MS) {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        true
+    }
+
+    fn begin_query(&self, query: &'static str) -> Result<QueryGuard<'_>, CrabError> {
+        // Count first, then check, so persist either sees us or we see the flag
+        self.active_queries.fetch_add(1, Ordering::SeqCst);
+
+        // Recursive so a query nested in another can't queue behind a waiting checkpoint
+        let guard = QueryGuard {
+            table: self,
+            query,
+            started: Instant::now(),
+            _checkpoint: self.checkpoint_lock.read_recursive(),
+        };
+
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(CrabError::TableClosed);
+        }
+
+        if let Some(err) = self.storage_error() {
+            return Err(err);
+        }
+
+        Ok(guard)
+    }
+
+    fn begin_write(&self, query: &'static str) -> Result<QueryGuard<'_>, CrabError> {
+        if self.data.read_only {
+            return Err(CrabError::ReadOnly);
+        }
+
+        let guard = self.begin_query(query)?;
+
+        if self.frozen.load(Ordering::SeqCst) {
+            return Err(CrabError::TableFrozen);
+        }
+
+        Ok(guard)
+    }
+
+    /*
+        Shared handle to the bufferpool backing this table's pages.
+    */
+    pub fn get_bufferpool(&self) -> Arc<BufferPool> {
+        Arc::clone(&self.data.bufferpool)
+    }
+
+    /*
+        Shared handle to the lock manager transactions use for this table's records.
+    */
+    pub fn get_lock_manager(&self) -> Arc<LockManager> {
+        Arc::clone(&self.lock_manager)
+    }
+
+    /*
+        How transactions react to contended record locks in this table, unless they
+        override it.
+    */
+    pub fn concurrency_policy(&self) -> ConcurrencyPolicy {
+        self.lock_manager.policy()
+    }
+
+    pub fn set_concurrency_policy(&self, policy: ConcurrencyPolicy) {
+        self.lock_manager.set_policy(policy);
+    }
+
+    /*
+        How many base pages of a page range get their disk pages at once, see
+        CrabConfig::base_page_batch. Only affects pages not allocated yet.
+    */
+    pub fn set_base_page_batch(&self, pages: usize) {
+        assert!(
+            pages > 0 && self.data.range_pages.is_multiple_of(pages),
+            "Base pages are allocated in batches dividing a page range, not {pages}"
+        );
+
+        self.data.base_page_batch.store(pages, Ordering::Relaxed);
+    }
+
+    /*
+        Bytes the table file grows by once its pages run out, see
+        CrabConfig::extent_size.
+    */
+    pub fn set_extent_size(&self, bytes: usize) {
+        self.data.disk.set_extent_size(bytes);
+    }
+
+    /*
+        How often dirty pages are written back between checkpoints, see
+        CrabConfig::flush_interval.
+    */
+    pub fn set_flush_interval(&self, interval: Duration) {
+        assert!(!interval.is_zero(), "Flush interval must not be zero");
+
+        if let Some((_, flusher_send)) = self.flusher_handle.lock().as_ref() {
+            let _ = flusher_send.send(interval);
+        }
+    }
+
+    /*
+        Which pages the table's bufferpool evicts first, see CrabConfig::eviction.
+        Whatever the old policy knew about the cached pages is dropped.
+    */
+    pub fn set_eviction(&self, eviction: Eviction) {
+        self.data
+            .bufferpool
+            .set_policy(eviction.policy(self.data.bufferpool.size()));
+    }
+
+    /*
+        Base pages per page range, see CrabConfig::page_range_pages.
+    */
+    pub fn page_range_pages(&self) -> usize {
+        self.data.range_pages
+    }
+
+    /*
+        Copy of the indexes, leaving out the key index while it is only partly rebuilt
+    */
+    pub(crate) fn index_snapshot(&self) -> Index {
+        let mut index = self.data.index.read().clone();
+
+        if self.rebuilding_primary.load(Ordering::SeqCst) {
+            index.drop_index(self.primary_key_index);
+        }
+
+        index
+    }
+
+    /*
+        Index entries can run ahead of or lag behind the data, so a row found
+        through the index only counts if its latest version still holds the value
+    */
+    pub(crate) fn holds_value(&self, rid: RID, column_index: usize, value: u64) -> bool {
+        // A reader's index can be from a later checkpoint than its header
+        if rid.raw() >= self.data.next_rid.load(Ordering::Relaxed) {
+            return false;
+        }
+
+        if self
+            .data
+            .get_page(rid)
+            .get_column(&self.data.bufferpool, METADATA_RID)
+            .slot(rid.slot())
+            == RID_INVALID
+        {
+            return false;
+        }
+
+        self.record_holds(self.data.get_latest(rid), column_index, value)
+    }
+
+    /*
+        Whether the base or tail record rid holds value in column_index, which a
+        NULL never does
+    */
+    fn record_holds(&self, rid: RID, column_index: usize, value: u64) -> bool {
+        self.data
+            .get_page(rid)
+            .get_column(&self.data.bufferpool, NUM_METADATA_COLUMNS + column_index)
+            .slot(rid.slot())
+            == value
+            && !record::is_null(self.data.read_nulls(rid), column_index)
+    }
+
+    /*
+        The index for a column, unless it is missing or still being rebuilt
+    */
+    pub(crate) fn usable_index(&self, column_index: usize) -> Option<RwLockReadGuard<'_, Index>> {
+        let index = self.data.index.read();
+
+        if !index.is_indexed(column_index)
+            || (column_index == self.primary_key_index
+                && self.rebuilding_primary.load(Ordering::SeqCst))
+        {
+            return None;
+        }
+
+        Some(index)
+    }
+
+    fn find_row(&self, column_index: usize, value: u64) -> Option<RID> {
+        match self
+            .usable_index(column_index)
+            .and_then(|index| index.get_from_index(column_index, value))
+        {
+            Some(vals) => vals
+                .iter()
+                .find(|x| self.holds_value(**x, column_index, value))
+                .copied(),
+            None => {
+                let next_rid = self.data.next_rid.load(Ordering::Relaxed);
+
+                // The RID advances in the loop header, so skipping a deleted row can't stall the scan
+                (0..next_rid).map(RID::from).find(|rid| {
+                    let page = self.data.get_page(*rid);
+
+                    if page
+                        .get_column(&self.data.bufferpool, METADATA_RID)
+                        .slot(rid.slot())
+                        == RID_INVALID
+                    {
+                        return false;
+                    }
+
+                    drop(page);
+
+                    self.record_holds(self.data.get_latest(*rid), column_index, value)
+                })
+            }
+        }
+    }
+
+    pub(crate) fn next_rid(&self) -> usize {
+        self.data.next_rid.load(Ordering::Relaxed) as usize
+    }
+
+    /*
+        Every base RID that has not been deleted, reading the RID column once per page
+    */
+    pub(crate) fn live_rids(&self) -> Vec<RID> {
+        let next_rid = self.data.next_rid.load(Ordering::Relaxed);
+        let mut rids = Vec::with_capacity(next_rid as usize);
+
+        let mut page_start = 0;
+        while page_start < next_rid {
+            let rid_column = self
+                .data
+                .get_page(page_start.into())
+                .get_column(&self.data.bufferpool, METADATA_RID);
+
+            let page_end = next_rid.min(page_start + PAGE_SLOTS as u64);
+            rids.extend(
+                (page_start..page_end)
+                    .map(RID::from)
+                    .filter(|rid| rid_column.slot(rid.slot()) != RID_INVALID),
+            );
+
+            page_start = page_end;
+        }
+
+        rids
+    }
+
+    /*
+        Latest versions of the live rows among rids, one base page's worth, for
+        TableScan. Frames are fetched once for the page and as a scan, so a full
+        table read doesn't push the hot pages out of the bufferpool.
+    */
+    pub(crate) fn scan_page(&self, rids: Range<u64>) -> Result<Vec<Record>, CrabError> {
+        let _guard = self.begin_query("scan")?;
+
+        let page = self.data.get_page(rids.start.into());
+        let rid_column = TableData::fetch_column(&self.data.bufferpool, &page, METADATA_RID, true);
+
+        let live = rids
+            .map(RID::from)
+            .filter(|rid| rid_column.slot(rid.slot()) != RID_INVALID)
+            .collect::<Vec<RID>>();
+
+        Ok(self.data.read_versions(
+            &self.data.get_latest_batch(&live, true),
+            &vec![1; self.data.num_columns],
+            true,
+        ))
+    }
+
+    /*
+        Primary keys and their base RIDs in key order, from the key index when it
+        can be used and otherwise by sorting the keys of a scan
+    */
+    pub(crate) fn key_snapshot(&self) -> Result<Vec<(u64, RID)>, CrabError> {
+        let _guard = self.begin_query("key_snapshot")?;
+        let key_column = self.primary_key();
+
+        if let Some(index) = self.usable_index(key_column) {
+            return Ok(index
+                .entries(key_column)
+                .expect("usable index missing its column")
+                .collect());
+        }
+
+        let rids = self.live_rids();
+        let mut key_only = vec![0; self.columns()];
+        key_only[key_column] = 1;
+
+        let mut keys = self
+            .data
+            .materialize_latest(&rids, &key_only)
+            .into_iter()
+            .zip(rids)
+            .map(|(record, rid)| (record.columns[0], rid))
+            .collect::<Vec<(u64, RID)>>();
+        keys.sort_unstable_by_key(|(key, rid)| (self.signed.sort_key(key_column, *key), *rid));
+
+        Ok(keys)
+    }
+
+    /*
+        Live rows holding value, stopping once limit of them have been found
+    */
+    fn find_rows(&self, column_index: usize, value: u64, limit: Option<usize>) -> Vec<RID> {
+        let limit = limit.unwrap_or(usize::MAX);
+
+        match self
+            .usable_index(column_index)
+            .and_then(|index| index.get_from_index(column_index, value))
+        {
+            Some(vals) => vals
+                .into_iter()
+                .filter(|x| self.holds_value(*x, column_index, value))
+                .take(limit)
+                .collect(),
+            None => {
+                let mut rid: RID = 0.into();
+                let mut rids = Vec::new();
+                let next_rid = self.data.next_rid.load(Ordering::Relaxed);
+
+                while rid.raw() < next_rid && rids.len() < limit {
+                    if rid.slot() == 0 && self.data.bufferpool.budget_exhausted() {
+                        break;
+                    }
+
+                    let page = self.data.get_page(rid);
+
+                    if page
+                        .get_column(&self.data.bufferpool, METADATA_RID)
+                        .slot(rid.slot())
+                        == RID_INVALID
+                    {
+                        rid = rid.next();
+                        continue;
+                    }
+
+                    if self.record_holds(self.data.get_latest(rid), column_index, value) {
+                        rids.push(rid);
+                    }
+
+                    rid = rid.next();
+                }
+
+                rids
+            }
+        }
+    }
+
+    fn find_rows_range(
+        &self,
+        column_index: usize,
+        range: impl RangeBounds<u64> + Clone,
+    ) -> Vec<RID> {
+        match self
+            .usable_index(column_index)
+            .and_then(|index| index.range_from_index(column_index, range.clone()))
+        {
+            Some(vals) => vals.into_iter().filter(|x| self.data.is_live(*x)).collect(),
+            None => {
+                let mut rids: Vec<RID> = Vec::new();
+                let mut rid: RID = 0.into();
+                let next_rid = self.data.next_rid.load(Ordering::Relaxed);
+
+                while rid.raw() < next_rid {
+                    if rid.slot() == 0 {
+                        if self.data.bufferpool.budget_exhausted() {
+                            break;
+                        }
+                        self.prefetch_next_page(rid, next_rid);
+                    }
+
+                    // The RID is published last, check it before trusting the key
+                    let published = self
+                        .data
+                        .get_page(rid)
+                        .get_column(&self.data.bufferpool, METADATA_RID)
+                        .slot(rid.slot())
+                        != RID_INVALID;
+
+                    if published
+                        && self.signed.in_range(
+                            self.primary_key_index,
+                            &range,
+                            self.data
+                                .get_page(rid)
+                                .get_column(
+                                    &self.data.bufferpool,
+                                    NUM_METADATA_COLUMNS + self.primary_key_index,
+                                )
+                                .slot(rid.slot()),
+                        )
+                    {
+                        rids.push(rid);
+                    }
+
+                    rid = rid.next();
+                }
+
+                rids
+            }
+        }
+    }
+
+    /*
+        Starts reading in the column pages a scan of the primary key reads on the
+        logical page after rid's, if rows were inserted there yet
+    */
+    fn prefetch_next_page(&self, rid: RID, next_rid: u64) {
+        let next_page = rid.page() + 1;
+        if RID::base(next_page, 0).raw() >= next_rid {
+            return;
+        }
+
+        if let Some(columns) = self.data.page_dir.get_page(next_page) {
+            self.data.bufferpool.prefetch(&[
+                columns[METADATA_RID],
+                columns[NUM_METADATA_COLUMNS + self.primary_key_index],
+            ]);
+        }
+    }
+
+    /*
+        Share-locks every row a transactional read found, or aborts it as the
+        concurrency policy says and returns false. Rows are found before they
+        are locked, so one may have been deleted by a transaction that held it
+        in between; those are dropped once the locks are held, and nothing can
+        change the rest until the transaction ends.
+    */
+    fn lock_shared(&self, transaction: &mut Transaction, rids: &mut Vec<RID>) -> bool {
+        for rid in rids.iter() {
+            if !transaction.try_lock_with_abort(&self.lock_manager, *rid, LockType::Shared) {
+                return false;
+            }
+        }
+
+        rids.retain(|rid| self.data.is_live(*rid));
+        true
+    }
+
+    pub fn is_latest(&self, rid: RID) -> bool {
+        self.data.is_latest(rid)
+    }
+
+    pub fn get_latest(&self, rid: RID) -> RID {
+        self.data.get_latest(rid)
+    }
+
+    pub(crate) fn merge_values(&self, base_rid: RID, columns: &[Option<u64>]) -> Vec<u64> {
+        let rid = self.data.get_latest(base_rid);
+        let page = self.data.get_page(rid);
+
+        let bp = &self.data.bufferpool;
+        columns
+            .iter()
+            .enumerate()
+            .map(|(i, x)| match x {
+                None => page
+                    .get_column(bp, NUM_METADATA_COLUMNS + i)
+                    .slot(rid.slot()),
+                Some(val) => *val,
+            })
+            .collect()
+    }
+
+    /*
+        The first of count new timestamps, which are consecutive. The clock only
+        counts writes, so timestamps order versions but say nothing of wall time
+    */
+    fn tick(&self, count: usize) -> u64 {
+        self.clock.fetch_add(count as u64, Ordering::SeqCst) + 1
+    }
+
+    /*
+        A row's values as its record stores them, which for a nullable table
+        ends with the row's NULL mask. What write_tail writes, and what
+        merge_values reads when asked for one column more than the table has.
+    */
+    fn stored_row(&self, values: &[u64], nulls: u64) -> Vec<u64> {
+        let mut row = values.to_vec();
+        if self.data.nullable {
+            row.push(nulls);
+        }
+        row
+    }
+
+    /*
+        The NULL mask at the end of a stored row, see stored_row
+    */
+    fn stored_nulls(&self, row: &[u64]) -> u64 {
+        match self.data.nullable {
+            true => row[self.data.num_columns],
+            false => 0,
+        }
+    }
+
+    /*
+        Mask of the columns flagged as NULL, failing if one of them can't be
+    */
+    fn null_mask(&self, nulls: impl Iterator<Item = bool>) -> Result<u64, CrabError> {
+        let mut mask = 0;
+
+        for (column, _) in nulls.enumerate().filter(|(_, null)| *null) {
+            if !self.data.nullable || column == self.primary_key_index {
+                return Err(CrabError::NotNullable(column));
+            }
+            mask |= 1 << column;
+        }
+
+        Ok(mask)
+    }
+
+    /*
+        Timestamp of the table's latest write. Every version written since has a later
+        one, so Table::select_as_of_query with this reads the table as it is now.
+        Timestamps count writes rather than time, and carry on across reopens and
+        compaction.
+    */
+    pub fn current_timestamp(&self) -> u64 {
+        self.clock.load(Ordering::SeqCst)
+    }
+
+    /*
+        Whether the table records when its versions were written, which every table
+        created since version timestamps existed does.
+    */
+    pub fn has_timestamps(&self) -> bool {
+        self.data.timestamps
+    }
+
+    /*
+        Name the table was created with.
+    */
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /*
+        Number of columns including the metadata columns stored alongside user data.
+    */
+    pub fn total_columns(&self) -> usize {
+        self.data.total_columns()
+    }
+
+    /*
+        Number of user data columns.
+    */
+    pub fn columns(&self) -> usize {
+        self.data.num_columns
+    }
+
+    /*
+        Columns that currently have an index, the primary key included.
+    */
+    pub fn indexed_columns(&self) -> Vec<usize> {
+        self.data.index.read().indexed_columns()
+    }
+
+    /*
+        RIDs the column's index has under value, see Index::locate. Empty if the
+        column isn't indexed.
+    */
+    pub fn locate(&self, column_index: usize, value: u64) -> Vec<RID> {
+        self.data.index.read().locate(column_index, value)
+    }
+
+    /*
+        RIDs the column's index has under the values in begin..=end. Empty if the
+        column isn't indexed.
+    */
+    pub fn locate_range(&self, begin: u64, end: u64, column_index: usize) -> Vec<RID> {
+        self.data
+            .index
+            .read()
+            .locate_range(begin, end, column_index)
+    }
+
+    /*
+        Distinct values the column's index holds, None if the column isn't indexed.
+    */
+    pub fn cardinality(&self, column_index: usize) -> Option<usize> {
+        self.data.index.read().cardinality(column_index)
+    }
+
+    /*
+        Entries in the column's index, one per row with a value, None if the column
+        isn't indexed.
+    */
+    pub fn index_len(&self, column_index: usize) -> Option<usize> {
+        self.data.index.read().len(column_index)
+    }
+
+    pub(crate) fn signed_columns(&self) -> SignedColumns {
+        self.signed
+    }
+
+    /*
+        Index of the primary key column.
+    */
+    pub fn primary_key(&self) -> usize {
+        self.primary_key_index
+    }
+
+    /*
+        Names of the user data columns in order, empty if the table was created
+        without them.
+    */
+    pub fn column_names(&self) -> &[String] {
+        &self.column_names
+    }
+
+    /*
+        How each user data column's values are read, all ColumnType::Unsigned unless
+        the table was created with CrabStore::create_table_typed.
+    */
+    pub fn column_types(&self) -> Vec<ColumnType> {
+        (0..self.data.num_columns)
+            .map(|column| self.signed.column_type(column))
+            .collect()
+    }
+
+    /*
+        Whether columns other than the primary key can hold NULLs, which only tables
+        created with CrabStore::create_table_nullable can.
+    */
+    pub fn is_nullable(&self) -> bool {
+        self.data.nullable
+    }
+
+    /*
+        Index of the column called name, None if there is no such column or no names
+        at all.
+    */
+    pub fn column_index(&self, name: &str) -> Option<usize> {
+        self.column_names.iter().position(|column| column == name)
+    }
+
+    fn named_column(&self, name: &str) -> Result<usize, CrabError> {
+        self.column_index(name)
+            .ok_or_else(|| CrabError::UnknownColumn(name.into()))
+    }
+
+    /*
+        Estimated statistics of each column, kept up by writes and recomputed by
+        Table::analyze.
+    */
+    pub fn column_stats(&self) -> Vec<ColumnStats> {
+        let stats = self.stats.lock();
+        (0..self.data.num_columns)
+            .map(|column| stats.column(column))
+            .collect()
+    }
+
+    /*
+        How a lookup on column_index would find its rows right now.
+    */
+    pub fn access_path(&self, column_index: usize) -> AccessPath {
+        if self.frozen.load(Ordering::SeqCst) {
+            AccessPath::Frozen
+        } else if self.usable_index(column_index).is_some() {
+            AccessPath::Index
+        } else {
+            AccessPath::Scan
+        }
+    }
+
+    /*
+        How select_query(search_value, column_index, ..) would run, without running
+        it.
+    */
+    pub fn explain(&self, column_index: usize, search_value: u64) -> QueryPlan {
+        QueryPlan {
+            access: self.access_path(column_index),
+            estimated_rows: self.estimate_rows(column_index, search_value),
+        }
+    }
+
+    /*
+        Page requests served by this table's bufferpool, and the evictions and
+        write-backs they caused, since it opened or the last Table::reset_stats.
+    */
+    pub fn bufferpool_stats(&self) -> BufferPoolStats {
+        self.data.bufferpool.stats()
+    }
+
+    /*
+        Work done by the merge thread since the table opened or the last
+        Table::reset_stats.
+    */
+    pub fn merge_stats(&self) -> MergeStats {
+        *self.merge_stats.lock()
+    }
+
+    /*
+        When the merge thread merges this table's ranges.
+    */
+    pub fn merge_config(&self) -> MergeConfig {
+        *self.merge_config.lock()
+    }
+
+    /*
+        Applies to tail pages filled from here on. Ranges already past a lowered
+        threshold are merged once their next tail page fills.
+    */
+    pub fn set_merge_config(&self, config: MergeConfig) {
+        *self.merge_config.lock() = MergeConfig {
+            tail_pages_per_merge: config.tail_pages_per_merge.max(1),
+            ..config
+        };
+    }
+
+    /*
+        Merges the range's filled tail pages now, whatever the MergeConfig says, after
+        any merge already running. Ok(false) if the range has none since its last
+        merge, or a transaction still holds a row they update, the same as a merge
+        from the merge thread would. Fails with CrabError::MergeFailed if the merge
+        panicked.
+    */
+    pub fn force_merge(&self, range_id: usize) -> Result<bool, CrabError> {
+        let _query = self.begin_write("force_merge")?;
+
+        if range_id >= self.data.range_dir.lock().next_range_id() {
+            return Ok(false);
+        }
+
+        Ok(self.merge_target().run_merge(range_id)?)
+    }
+
+    /*
+        The last merge that failed, by the merge thread or Table::force_merge, if any
+        did since the table opened.
+    */
+    pub fn merge_status(&self) -> Option<MergeError> {
+        self.merge_status.lock().clone()
+    }
+
+    /*
+        Calls hook after each merge from here on, by the merge thread or
+        Table::force_merge, replacing any hook set before. It runs before the next
+        merge can start, so should return quickly, and must not set another hook.
+    */
+    pub fn on_merge_complete(&self, hook: Box<dyn Fn(&MergeReport) + Send>) {
+        *self.merge_hook.lock() = Some(hook);
+    }
+
+    /*
+        Table::force_merge on every range, returns how many were merged.
+    */
+    pub fn force_merge_all(&self) -> Result<usize, CrabError> {
+        let ranges = self.data.range_dir.lock().next_range_id();
+
+        let mut merged = 0;
+        for range_id in 0..ranges {
+            if self.force_merge(range_id)? {
+                merged += 1;
+            }
+        }
+
+        Ok(merged)
+    }
+
+    fn merge_target(&self) -> MergeTarget {
+        MergeTarget {
+            page_dir: Arc::clone(&self.data.page_dir),
+            range_dir: Arc::clone(&self.data.range_dir),
+            disk: Arc::clone(&self.data.disk),
+            bufferpool: Arc::clone(&self.data.bufferpool),
+            index: Arc::clone(&self.data.index),
+            lock_manager: Arc::clone(&self.lock_manager),
+            checkpoint_lock: Arc::clone(&self.checkpoint_lock),
+            stats: Arc::clone(&self.merge_stats),
+            num_columns: self.data.num_columns,
+            page_layout: self.data.page_layout,
+            range_pages: self.data.range_pages,
+            config: Arc::clone(&self.merge_config),
+            merge_lock: Arc::clone(&self.merge_lock),
+            base_writes: Arc::clone(&self.data.base_writes),
+            status: Arc::clone(&self.merge_status),
+            hook: Arc::clone(&self.merge_hook),
+        }
+    }
+
+    /*
+        Writes by queries and merges, see WriteStats::amplification.
+    */
+    pub fn write_stats(&self) -> WriteStats {
+        let mut stats = *self.write_stats.lock();
+        stats.slots_written += self.merge_stats.lock().slots_written;
+        stats
+    }
+
+    /*
+        (chain length, rows) for every length some live row's unmerged tail records
+        reach, shortest first. A row not updated since its last merge has a chain of
+        0. Only reads metadata columns, through the scan hint.
+    */
+    pub fn chain_histogram(&self) -> Result<Vec<(usize, usize)>, CrabError> {
+        let _guard = self.begin_query("chain_histogram")?;
+
+        let mut histogram: BTreeMap<usize, usize> = BTreeMap::new();
+        let next_rid = self.data.next_rid.load(Ordering::Relaxed);
+
+        let mut page_start = 0;
+        while page_start < next_rid {
+            let page_end = next_rid.min(page_start + PAGE_SLOTS as u64);
+            let page = self.data.get_page(page_start.into());
+            let bp = &self.data.bufferpool;
+
+            let rids = page.get_column_for_scan(bp, METADATA_RID);
+            let indirection = page.get_column_for_scan(bp, METADATA_INDIRECTION);
+            let tps = page.get_column_for_scan(bp, METADATA_PAGE_HEADER).slot(0);
+
+            for rid in (page_start..page_end).map(RID::from) {
+                if rids.slot(rid.slot()) == RID_INVALID {
+                    continue;
+                }
+
+                // Tail RIDs count down, so the ones a merge hasn't reached are below the TPS
+                let mut length = 0;
+                let mut tail = RID::from(indirection.slot(rid.slot()));
+                while tail.is_tail()
+                    && !tail.is_invalid()
+                    && tail.raw() < tps
+                    && self.data.tail_visible(tail)
+                {
+                    length += 1;
+                    tail = self
+                        .data
+                        .get_page(tail)
+                        .get_column_for_scan(&self.data.bufferpool, METADATA_INDIRECTION)
+                        .slot(tail.slot())
+                        .into();
+                }
+
+                *histogram.entry(length).or_default() += 1;
+            }
+
+            page_start = page_end;
+        }
+
+        Ok(histogram.into_iter().collect())
+    }
+
+    /*
+        Counts the live, deleted and compacted slots of every base page. Reads one
+        column page per base page through the scan hint, so it is cheap but not free.
+    */
+    pub fn storage_stats(&self) -> Result<StorageStats, CrabError> {
+        let _guard = self.begin_query("storage_stats")?;
+
+        let mut stats = StorageStats::default();
+        let next_rid = self.data.next_rid.load(Ordering::Relaxed);
+
+        let mut page_start = 0;
+        while page_start < next_rid {
+            let page_end = next_rid.min(page_start + PAGE_SLOTS as u64);
+            let page = self.data.get_page(page_start.into());
+            let bp = &self.data.bufferpool;
+
+            let rids = page.get_column_for_scan(bp, METADATA_RID);
+            let live = (page_start..page_end)
+                .filter(|rid| rids.slot(RID::from(*rid).slot()) != RID_INVALID)
+                .count();
+
+            stats.base_pages += 1;
+            stats.live_rows += live;
+            stats.deleted_rows += (page_end - page_start) as usize - live;
+            stats.compacted_rows += page.read_freed_slots(bp) as usize;
+
+            page_start = page_end;
+        }
+        stats.file_pages = self.data.disk.free_page_pointer();
+        stats.free_pages = self.data.disk.free_page_count();
+
+        Ok(stats)
+    }
+
+    /*
+        Writes the live rows densely into a fresh copy of this table under
+        new_path_base. Rows keep their order but get new RIDs, and only their latest
+        versions are copied, so deleted slots and tail records are left behind. Fails
+        with CrabError::TableInUse while transactions hold locks on the table, see
+        CrabStore::compact_table to swap the copy in.
+    */
+    pub fn compact_into(&self, new_path_base: &Path) -> Result<CompactReport, CrabError> {
+        // Queries, merges and checkpoints all wait until the copy is done
+        let _checkpoint = self.checkpoint_lock.write();
+
+        if !self.lock_manager.is_idle() {
+            return Err(CrabError::TableInUse);
+        }
+
+        fs::create_dir_all(new_path_base).expect("Failed to create compaction directory");
+
+        let compacted = Table::with_config(
+            self.name.clone(),
+            self.data.num_columns,
+            self.primary_key_index,
+            &CrabStore::table_files(new_path_base, &self.name),
+            &CrabConfig {
+                page_layout: self.data.page_layout,
+                page_range_pages: self.data.range_pages,
+                ..CrabConfig::default()
+            },
+        )
+        .with_column_names(self.column_names.clone())
+        .with_column_types(&self.column_types())
+        .with_nullable(self.data.nullable);
+        compacted.set_base_page_batch(self.data.base_page_batch.load(Ordering::Relaxed));
+        for column in self.data.index.read().indexed_columns() {
+            if !compacted.data.index.read().is_indexed(column) {
+                compacted.build_index(column);
+            }
+        }
+        for column in self.unique_columns() {
+            compacted.data.index.write().set_unique(column);
+        }
+        for columns in self.composite_indexes() {
+            compacted.build_composite_index(&columns);
+        }
+
+        /*
+            Each row keeps the timestamp of its latest version, and the copy's
+            clock carries on from this one's
+        */
+        let all_columns = vec![1; self.data.num_columns];
+        let live = self.live_rids();
+        for rids in live.chunks(PAGE_SLOTS) {
+            for record in self.data.materialize_latest(rids, &all_columns) {
+                compacted.write_row(&record.columns, record.nulls, record.timestamp, None);
+            }
+        }
+        compacted
+            .clock
+            .store(self.clock.load(Ordering::SeqCst), Ordering::SeqCst);
+
+        compacted.persist()?;
+
+        Ok(CompactReport {
+            rows: live.len(),
+            old_pages: self.data.disk.free_page_pointer(),
+            new_pages: compacted.data.disk.free_page_pointer(),
+        })
+    }
+
+    /*
+        The most recent queries that took at least the slow query threshold, oldest
+        first.
+    */
+    pub fn slow_queries(&self) -> Vec<SlowQuery> {
+        self.slow_queries.lock().iter().copied().collect()
+    }
+
+    /*
+        Queries taking at least threshold are kept in Table::slow_queries.
+    */
+    pub fn set_slow_query_threshold(&self, threshold: Duration) {
+        self.slow_query_threshold
+            .store(threshold.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /*
+        Clears the bufferpool and merge counters and the slow query log. Column
+        statistics describe the data rather than the workload, so they stay.
+    */
+    pub fn reset_stats(&self) {
+        self.data.bufferpool.reset_stats();
+        *self.merge_stats.lock() = MergeStats::default();
+        *self.write_stats.lock() = WriteStats::default();
+        self.slow_queries.lock().clear();
+    }
+
+    /*
+        Base RIDs of the live rows whose column_index currently holds search_value.
+        Pair with Table::materialize when only some of the matches need their values
+        read.
+    */
+    pub fn select_rids(
+        &self,
+        search_value: u64,
+        column_index: usize,
+    ) -> Result<Vec<RID>, CrabError> {
+        let _guard = self.begin_query("select_rids")?;
+
+        if let Some(frozen) = self.frozen() {
+            return Ok(frozen.select_rids(search_value, column_index));
+        }
+
+        Ok(self.find_rows(column_index, search_value, None))
+    }
+
+    /*
+        Latest values of the columns flagged in included_columns for each base RID, in
+        order. Column frames are fetched once per logical page rather than once per
+        row.
+    */
+    pub fn materialize(
+        &self,
+        rids: &[RID],
+        included_columns: &[usize],
+    ) -> Result<Vec<Record>, CrabError> {
+        let _guard = self.begin_query("materialize")?;
+
+        if let Some(frozen) = self.frozen() {
+            return Ok(frozen.materialize(rids, included_columns));
+        }
+
+        Ok(self.data.materialize_latest(rids, included_columns))
+    }
+
+    /*
+        Latest values of the columns flagged in included_columns for one base RID,
+        such as one from Table::select_rids, without looking anything up by key. None
+        for a RID that has been deleted since, or isn't a base RID.
+    */
+    pub fn select_by_rid(
+        &self,
+        rid: RID,
+        included_columns: &[usize],
+    ) -> Result<Option<Record>, CrabError> {
+        let _guard = self.begin_query("select_by_rid")?;
+
+        if let Some(frozen) = self.frozen() {
+            return Ok(frozen.materialize(&[rid], included_columns).pop());
+        }
+
+        if rid.is_tail() || !self.data.is_live(rid) {
+            return Ok(None);
+        }
+
+        Ok(self.data.materialize_latest(&[rid], included_columns).pop())
+    }
+
+    /*
+        Latest values of the live rows whose column_index holds search_value,
+        unordered. Table::select_query_limit returns them in a stable ResultOrder
+        instead.
+    */
+    pub fn select_query(
+        &self,
+        search_value: u64,
+        column_index: usize,
+        included_columns: &[usize],
+        transaction: Option<&mut Transaction>,
+    ) -> Result<Vec<Record>, CrabError> {
+        self.select_query_limit(
+            search_value,
+            column_index,
+            included_columns,
+            ResultOrder::Unordered,
+            None,
+            transaction,
+        )
+    }
+
+    /*
+        Table::select_query by column names, returning the included columns in table
+        order. Fails with CrabError::UnknownColumn if a name isn't one of the table's.
+    */
+    pub fn select_query_named(
+        &self,
+        search_value: u64,
+        column: &str,
+        included: &[&str],
+        transaction: Option<&mut Transaction>,
+    ) -> Result<Vec<Record>, CrabError> {
+        let column_index = self.named_column(column)?;
+
+        let mut included_columns = vec![0; self.data.num_columns];
+        for name in included {
+            included_columns[self.named_column(name)?] = 1;
+        }
+
+        self.select_query(search_value, column_index, &included_columns, transaction)
+    }
+
+    /*
+        Table::select_query returning at most limit records, in order. Unordered,
+        finding the rows stops once limit match, so the rest are never read or locked.
+        Any other order finds and locks every match to return the first limit.
+    */
+    pub fn select_query_limit(
+        &self,
+        search_value: u64,
+        column_index: usize,
+        included_columns: &[usize],
+        order: ResultOrder,
+        limit: Option<usize>,
+        mut transaction: Option<&mut Transaction>,
+    ) -> Result<Vec<Record>, CrabError> {
+        let _guard = self.begin_query("select_query")?;
+
+        // The row may have been updated since find_rows, so check the version actually read
+        let mut mask = included_columns.to_vec();
+        mask.resize(mask.len().max(column_index + 1), 0);
+        mask[column_index] = 1;
+        if order == ResultOrder::ByPrimaryKey {
+            mask.resize(mask.len().max(self.primary_key_index + 1), 0);
+            mask[self.primary_key_index] = 1;
+        }
+        let position = |column: usize| mask[..column].iter().filter(|x| **x != 0).count();
+
+        let mut records = match self.frozen() {
+            Some(frozen) => frozen.select(search_value, column_index, &mask),
+            None => {
+                let find_limit = match order {
+                    ResultOrder::Unordered => limit,
+                    _ => None,
+                };
+                let mut vals: Vec<RID> = self.find_rows(column_index, search_value, find_limit);
+
+                if let Some(t) = transaction.borrow_mut() {
+                    if !self.lock_shared(t, &mut vals) {
+                        return Ok(Vec::new());
+                    }
+                }
+
+                self.overlay_own_writes(
+                    transaction.as_deref(),
+                    &vals,
+                    self.data.materialize_latest(&vals, &mask),
+                    &mask,
+                    |row| row[column_index] == search_value,
+                )
+            }
+        };
+
+        let search_position = position(column_index);
+        records.retain(|record| record.columns[search_position] == search_value);
+        order.sort(&mut records, position(self.primary_key_index), |key| {
+            self.signed.sort_key(self.primary_key_index, key)
+        });
+        records.truncate(limit.unwrap_or(usize::MAX));
+
+        Ok(Table::project(records, &mask, included_columns))
+    }
+
+    /*
+        Latest values of the live rows holding the value of every (column, value) in
+        filters, unordered. With no filters, every live row. Finds the rows through
+        the composite index whose leading columns the filters cover most of, see
+        Table::build_composite_index. Failing that it takes the rows every indexed
+        filtered column agrees on, and only scans the table if none of them is
+        indexed.
+    */
+    pub fn select_multi_query(
+        &self,
+        filters: &[(usize, u64)],
+        included_columns: &[usize],
+        mut transaction: Option<&mut Transaction>,
+    ) -> Result<Vec<Record>, CrabError> {
+        let _guard = self.begin_query("select_multi_query")?;
+
+        // Rows may have been updated since they were found, so check the versions actually read
+        let mut mask = included_columns.to_vec();
+        for (column, _) in filters {
+            mask.resize(mask.len().max(column + 1), 0);
+            mask[*column] = 1;
+        }
+        let position = |column: usize| mask[..column].iter().filter(|x| **x != 0).count();
+
+        let mut records = match self.frozen() {
+            Some(frozen) => frozen.select_multi(filters, &mask),
+            None => {
+                let mut vals = self.find_rows_multi(filters);
+
+                if let Some(t) = transaction.borrow_mut() {
+                    if !self.lock_shared(t, &mut vals) {
+                        return Ok(Vec::new());
+                    }
+                }
+
+                self.overlay_own_writes(
+                    transaction.as_deref(),
+                    &vals,
+                    self.data.materialize_latest(&vals, &mask),
+                    &mask,
+                    |row| filters.iter().all(|(column, value)| row[*column] == *value),
+                )
+            }
+        };
+
+        let positions = filters
+            .iter()
+            .map(|(column, value)| (position(*column), *value))
+            .collect::<Vec<_>>();
+        records.retain(|record| {
+            positions
+                .iter()
+                .all(|(position, value)| record.value(*position) == Some(*value))
+        });
+
+        Ok(Table::project(records, &mask, included_columns))
+    }
+
+    /*
+        Live rows that may hold every filter's value, for select_multi_query to
+        check: those a composite index has under their leading values, those
+        the indexes of the filtered columns all have, or failing both those
+        holding the first filter's value
+    */
+    fn find_rows_multi(&self, filters: &[(usize, u64)]) -> Vec<RID> {
+        let Some(&(column_index, search_value)) = filters.first() else {
+            return self.live_rids();
+        };
+
+        let composite = self.data.index.read().find_composite(filters);
+        if let Some(rids) = composite {
+            return rids
+                .into_iter()
+                .filter(|rid| self.data.is_live(*rid))
+                .collect();
+        }
+
+        let mut found: Option<Vec<RID>> = None;
+        for (column, value) in filters {
+            let Some(rids) = self
+                .usable_index(*column)
+                .and_then(|index| index.get_from_index(*column, *value))
+            else {
+                continue;
+            };
+
+            found = Some(match found {
+                None => rids,
+                Some(found) => {
+                    let rids = rids.into_iter().collect::<FxHashSet<RID>>();
+                    found.into_iter().filter(|rid| rids.contains(rid)).collect()
+                }
+            });
+        }
+
+        match found {
+            Some(rids) => rids
+                .into_iter()
+                .filter(|rid| self.data.is_live(*rid))
+                .collect(),
+            None => self.find_rows(column_index, search_value, None),
+        }
+    }
+
+    /*
+        Drops the columns that are in mask but not in included_columns again, the
+        ones a select only read to filter and sort by
+    */
+    fn project(records: Vec<Record>, mask: &[usize], included_columns: &[usize]) -> Vec<Record> {
+        let projected = mask
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| **x != 0)
+            .map(|(column, _)| included_columns.get(column).is_some_and(|x| *x != 0) as usize)
+            .collect::<Vec<usize>>();
+
+        if !projected.contains(&0) {
+            return records;
+        }
+
+        records
+            .into_iter()
+            .map(|record| record.project(&projected))
+            .collect()
+    }
+
+    /*
+        Latest values of the live rows whose column_index lies in start..=end,
+        ascending by that column and then by primary key, so equal values come back in
+        the same order every time. With a limit, only the first that many are
+        returned, and rows past the last value they hold are neither locked nor read.
+        Through an index the lookup stops there too.
+    */
+    pub fn select_range_query(
+        &self,
+        start: u64,
+        end: u64,
+        column_index: usize,
+        included_columns: &[usize],
+        limit: Option<usize>,
+        transaction: Option<&mut Transaction>,
+    ) -> Result<Vec<Record>, CrabError> {
+        self.select_range(
+            start..=end,
+            column_index,
+            included_columns,
+            limit,
+            transaction,
+        )
+    }
+
+    fn select_range(
+        &self,
+        range: impl RangeBounds<u64> + Clone,
+        column_index: usize,
+        included_columns: &[usize],
+        limit: Option<usize>,
+        mut transaction: Option<&mut Transaction>,
+    ) -> Result<Vec<Record>, CrabError> {
+        let _guard = self.begin_query("select_range_query")?;
+
+        let mut mask = included_columns.to_vec();
+        mask.resize(mask.len().max(self.data.num_columns), 0);
+        mask[column_index] = 1;
+        mask[self.primary_key_index] = 1;
+        let position = |column: usize| mask[..column].iter().filter(|x| **x != 0).count();
+
+        let mut records = match self.frozen() {
+            Some(frozen) => frozen.select_range(range.clone(), column_index, &mask),
+            None => {
+                let mut rids = self.find_rows_between(column_index, range.clone(), limit);
+
+                if let Some(t) = transaction.borrow_mut() {
+                    if !self.lock_shared(t, &mut rids) {
+                        return Ok(Vec::new());
+                    }
+                }
+
+                self.overlay_own_writes(
+                    transaction.as_deref(),
+                    &rids,
+                    self.data.materialize_latest(&rids, &mask),
+                    &mask,
+                    |row| {
+                        self.signed
+                            .in_range(column_index, &range, row[column_index])
+                    },
+                )
+            }
+        };
+
+        let (search_position, key_position) =
+            (position(column_index), position(self.primary_key_index));
+        records.retain(|record| {
+            record
+                .value(search_position)
+                .is_some_and(|value| self.signed.in_range(column_index, &range, value))
+        });
+        records.sort_unstable_by_key(|record| {
+            (
+                self.signed
+                    .sort_key(column_index, record.columns[search_position]),
+                self.signed
+                    .sort_key(self.primary_key_index, record.columns[key_position]),
+            )
+        });
+        records.truncate(limit.unwrap_or(usize::MAX));
+
+        Ok(Table::project(records, &mask, included_columns))
+    }
+
+    /*
+        Live rows whose latest version has column_index in range, through the index if
+        it has one. Unlike find_rows_range, the scan reads the latest version of every
+        row, not the base record's key.
+
+        With a limit, rows are taken lowest value first until there are that many, and
+        then the rest holding the last value taken, which the primary key orders. The
+        index is read no further than that, a scan still reads the column of every row.
+    */
+    fn find_rows_between(
+        &self,
+        column_index: usize,
+        range: impl RangeBounds<u64> + Clone,
+        limit: Option<usize>,
+    ) -> Vec<RID> {
+        let limit = limit.unwrap_or(usize::MAX);
+
+        if let Some(index) = self.usable_index(column_index) {
+            if let Some(entries) = index.range_entries(column_index, range.clone()) {
+                let mut rids = Vec::new();
+
+                // A row updated to another value is still listed under its old one
+                for (value, holding) in entries {
+                    if rids.len() >= limit {
+                        break;
+                    }
+                    rids.extend(
+                        holding
+                            .iter()
+                            .filter(|rid| self.holds_value(**rid, column_index, value)),
+                    );
+                }
+
+                return rids;
+            }
+        }
+
+        let mut column = vec![0; self.data.num_columns];
+        column[column_index] = 1;
+
+        let live = self.live_rids();
+        let latest = self.data.materialize_latest(&live, &column);
+
+        let mut matching = live
+            .into_iter()
+            .zip(latest)
+            .filter_map(|(rid, record)| {
+                let value = record.value(0)?;
+                self.signed
+                    .in_range(column_index, &range, value)
+                    .then(|| (self.signed.sort_key(column_index, value), rid))
+            })
+            .collect::<Vec<(u64, RID)>>();
+
+        if limit < matching.len() {
+            matching.select_nth_unstable_by_key(limit, |(key, _)| *key);
+            let last = matching[..limit].iter().map(|(key, _)| *key).max();
+            matching.retain(|(key, _)| Some(*key) <= last);
+        }
+
+        matching.into_iter().map(|(_, rid)| rid).collect()
+    }
+
+    /*
+        How many live rows hold search_value in column_index, counting no further than
+        bound. Asking for n + 1 tells whether more than n rows match without finding
+        them all.
+    */
+    pub fn select_count_bounded(
+        &self,
+        search_value: u64,
+        column_index: usize,
+        bound: usize,
+    ) -> Result<usize, CrabError> {
+        let _guard = self.begin_query("select_count_bounded")?;
+
+        if let Some(frozen) = self.frozen() {
+            return Ok(frozen
+                .select_rids(search_value, column_index)
+                .len()
+                .min(bound));
+        }
+
+        Ok(self
+            .find_rows(column_index, search_value, Some(bound))
+            .len())
+    }
+
+    /*
+        How many live rows hold search_value in column_index. Finds the rows like
+        Table::select_query but reads no column other than the one searched, and
+        builds no records.
+    */
+    pub fn count_query(&self, search_value: u64, column_index: usize) -> Result<usize, CrabError> {
+        self.select_count_bounded(search_value, column_index, usize::MAX)
+    }
+
+    /*
+        Rows expected to hold value, exact when the column has a usable index
+    */
+    fn estimate_rows(&self, column_index: usize, value: u64) -> f64 {
+        match self
+            .usable_index(column_index)
+            .and_then(|index| index.get_from_index(column_index, value))
+        {
+            Some(rids) => rids.len() as f64,
+            None => self.stats.lock().estimate_rows(column_index, value),
+        }
+    }
+
+    /*
+        The (column, value) predicates in the order Table::select_where evaluates
+        them, those expected to match the fewest rows first.
+    */
+    pub fn plan_predicates(&self, predicates: &[(usize, u64)]) -> Vec<(usize, u64)> {
+        let mut planned = predicates
+            .iter()
+            .map(|predicate| (self.estimate_rows(predicate.0, predicate.1), *predicate))
+            .collect::<Vec<_>>();
+
+        planned.sort_by(|a, b| a.0.total_cmp(&b.0));
+        planned.into_iter().map(|x| x.1).collect()
+    }
+
+    /*
+        Latest values of the live rows matching every (column, value) predicate. The
+        most selective predicate finds the candidates and the rest filter them, which
+        makes the order of an unordered result depend on the plan too.
+    */
+    pub fn select_where(
+        &self,
+        predicates: &[(usize, u64)],
+        included_columns: &[usize],
+        order: ResultOrder,
+        mut transaction: Option<&mut Transaction>,
+    ) -> Result<Vec<Record>, CrabError> {
+        assert!(!predicates.is_empty(), "select_where needs a predicate");
+
+        let _guard = self.begin_query("select_where")?;
+
+        let planned = self.plan_predicates(predicates);
+        let (column_index, search_value) = planned[0];
+        let all_columns = vec![1; self.data.num_columns];
+
+        let records = match self.frozen() {
+            Some(frozen) => frozen.materialize(
+                &frozen.select_rids(search_value, column_index),
+                &all_columns,
+            ),
+            None => {
+                let mut rids = self.find_rows(column_index, search_value, None);
+
+                if let Some(t) = transaction.borrow_mut() {
+                    if !self.lock_shared(t, &mut rids) {
+                        return Ok(Vec::new());
+                    }
+                }
+
+                self.overlay_own_writes(
+                    transaction.as_deref(),
+                    &rids,
+                    self.data.materialize_latest(&rids, &all_columns),
+                    &all_columns,
+                    |row| row[column_index] == search_value,
+                )
+            }
+        };
+
+        // Also rechecks the first predicate, the row may have changed since find_rows
+        let mut records = records
+            .into_iter()
+            .filter(|record| {
+                planned
+                    .iter()
+                    .all(|(column, value)| record.value(*column) == Some(*value))
+            })
+            .collect::<Vec<Record>>();
+        order.sort(&mut records, self.primary_key_index, |key| {
+            self.signed.sort_key(self.primary_key_index, key)
+        });
+
+        Ok(records
+            .into_iter()
+            .map(|record| record.project(included_columns))
+            .collect())
+    }
+
+    /*
+        Brings records read for rids, projected by included_columns, in line with
+        what the transaction itself wrote: rows it deleted are dropped, rows it
+        wrote take the values it wrote, and rows it wrote that the read never
+        reached are added when they match. So a transaction reads its own writes
+        whether or not they have reached the pages yet.
+    */
+    fn overlay_own_writes(
+        &self,
+        transaction: Option<&Transaction>,
+        rids: &[RID],
+        records: Vec<Record>,
+        included_columns: &[usize],
+        matches: impl Fn(&[u64]) -> bool,
+    ) -> Vec<Record> {
+        let Some(own_writes) = transaction.and_then(|t| t.own_writes(&self.name)) else {
+            return records;
+        };
+
+        let project = |values: &[u64]| {
+            values
+                .iter()
+                .zip(included_columns)
+                .filter(|(_, included)| **included != 0)
+                .map(|(value, _)| *value)
+                .collect::<Vec<u64>>()
+        };
+
+        let mut overlaid = Vec::with_capacity(records.len());
+        for (rid, mut record) in rids.iter().zip(records) {
+            match own_writes.get(rid) {
+                None => overlaid.push(record),
+                Some(Some(values)) if matches(values) => {
+                    record.columns = project(values);
+                    overlaid.push(record);
+                }
+                Some(_) => {}
+            }
+        }
+
+        let read = rids.iter().collect::<FxHashSet<&RID>>();
+        let mut unread = own_writes
+            .iter()
+            .filter_map(|(rid, values)| Some((rid, values.as_ref()?)))
+            .filter(|(rid, values)| !read.contains(rid) && matches(values))
+            .map(|(rid, values)| Record::new(rid.raw(), project(values)))
+            .collect::<Vec<Record>>();
+        unread.sort_unstable();
+        overlaid.extend(unread);
+
+        overlaid
+    }
+
+    fn reserve_key(&self, key: u64) -> Option<KeyReservation<'_>> {
+        self.reserve_value(self.primary_key_index, key)
+    }
+
+    fn reserve_value(&self, column: usize, value: u64) -> Option<KeyReservation<'_>> {
+        self.inserting_keys
+            .lock()
+            .insert((column, value))
+            .then(|| KeyReservation {
+                table: self,
+                column,
+                value,
+            })
+    }
+
+    // Whether any column written has a unique index
+    fn writes_unique(&self, written: impl Iterator<Item = bool>) -> bool {
+        let unique = self.data.index.read().unique_mask();
+        written
+            .enumerate()
+            .any(|(column, written)| written && unique & (1 << column) != 0)
+    }
+
+    /*
+        Reserves the values written of the table's unique columns, for a write
+        that found its primary key free. Turns the write away if another row
+        (other than row, the one being updated) holds one of them, or another
+        write of one is still running, which aborts a transaction the way a
+        primary key would.
+    */
+    fn claim_unique(
+        &self,
+        values: &[u64],
+        written: u64,
+        row: Option<RID>,
+        transaction: &mut Option<&mut Transaction>,
+    ) -> Option<Vec<KeyReservation<'_>>> {
+        let unique = self.data.index.read().unique_mask() & written;
+        let mut reservations = Vec::new();
+
+        for column in (0..self.data.num_columns).filter(|column| unique & (1 << column) != 0) {
+            let value = values[column];
+
+            let Some(reservation) = self.reserve_value(column, value) else {
+                if let Some(t) = transaction.borrow_mut() {
+                    t.set_aborted(AbortKind::KeyInUse(value));
+                }
+                return None;
+            };
+            reservations.push(reservation);
+
+            if self
+                .find_row(column, value)
+                .is_some_and(|holder| Some(holder) != row)
+            {
+                if let Some(t) = transaction.borrow_mut() {
+                    t.constraint_violated();
+                }
+                return None;
+            }
+        }
+
+        Some(reservations)
+    }
+
+    pub fn insert_query(
+        &self,
+        values: &[u64],
+        transaction: Option<&mut Transaction>,
+    ) -> Result<(), CrabError> {
+        self.insert_row("insert_query", values, 0, transaction)
+    }
+
+    /*
+        Table::insert_query with None for a NULL, which only a table created with
+        CrabStore::create_table_nullable can hold, and never in its primary key. Fails
+        with CrabError::NotNullable otherwise, inserting nothing.
+    */
+    pub fn insert_query_nullable(
+        &self,
+        values: &[Option<u64>],
+        transaction: Option<&mut Transaction>,
+    ) -> Result<(), CrabError> {
+        let nulls = self.null_mask(values.iter().map(Option::is_none))?;
+        let values = values
+            .iter()
+            .map(|value| value.unwrap_or(0))
+            .collect::<Vec<u64>>();
+
+        self.insert_row("insert_query_nullable", &values, nulls, transaction)
+    }
+
+    /*
+        Inserts a row whose columns in nulls are NULL, holding 0 in values
+    */
+    fn insert_row(
+        &self,
+        query: &'static str,
+        values: &[u64],
+        nulls: u64,
+        mut transaction: Option<&mut Transaction>,
+    ) -> Result<(), CrabError> {
+        let _guard = self.begin_write(query)?;
+
+        let key = values[self.primary_key_index];
+
+        /*
+            Another insert of the key is still running. It may yet be rolled back,
+            so a transaction retries rather than treating this as a duplicate.
+        */
+        let Some(_reservation) = self.reserve_key(key) else {
+            if let Some(t) = transaction.borrow_mut() {
+                t.set_aborted(AbortKind::KeyInUse(key));
+            }
+            return Ok(());
+        };
+
+        if self.find_row(self.primary_key_index, key).is_some() {
+            if let Some(t) = transaction.borrow_mut() {
+                t.constraint_violated();
+            }
+            return Ok(());
+        }
+
+        // NULLs don't collide
+        let Some(_unique) = self.claim_unique(values, !nulls, None, &mut transaction) else {
+            return Ok(());
+        };
+
+        self.write_row(values, nulls, self.tick(1), transaction);
+
+        Ok(())
+    }
+
+    /*
+        Writes values as a new base record and indexes it, for a caller holding
+        the reservation of its key that found no live row with it
+    */
+    fn write_row(
+        &self,
+        values: &[u64],
+        nulls: u64,
+        timestamp: u64,
+        mut transaction: Option<&mut Transaction>,
+    ) {
+        let rid: RID = self.data.next_rid.fetch_add(1, Ordering::Relaxed).into();
+
+        if let Some(t) = transaction.borrow_mut() {
+            if !t.try_lock_with_abort(&self.lock_manager, rid, LockType::Exclusive) {
+                return;
+            }
+        }
+
+        // Until the RID is written, so a merge swapping in its copy of the page waits for the row
+        let base_write = self.data.base_writes.read();
+        let page = self.data.base_page(rid);
+
+        if let Some(t) = transaction.borrow_mut() {
+            t.log_insert(rid);
+        }
+
+        page.get_column(&self.data.bufferpool, METADATA_INDIRECTION)
+            .write_slot(rid.slot(), RID_INVALID);
+
+        // Survives deletion, so scans can tell a deleted row from a never written slot
+        page.get_column(&self.data.bufferpool, METADATA_BASE_RID)
+            .write_slot(rid.slot(), rid.raw());
+
+        page.get_column(&self.data.bufferpool, METADATA_SCHEMA_ENCODING)
+            .write_slot(rid.slot(), 0);
+
+        for (i, val) in values.iter().enumerate() {
+            page.get_column(&self.data.bufferpool, NUM_METADATA_COLUMNS + i)
+                .write_slot(rid.slot(), *val);
+        }
+
+        if self.data.nullable {
+            page.get_column(&self.data.bufferpool, self.data.nulls_column())
+                .write_slot(rid.slot(), nulls);
+        }
+
+        if self.data.timestamps {
+            page.get_column(&self.data.bufferpool, self.data.timestamp_column())
+                .write_slot(rid.slot(), timestamp);
+        }
+
+        /*
+            Scans treat the slot as live once its RID is set, so that goes last.
+            Every slot write takes its frame's lock, so a reader that sees the RID
+            through that lock also sees the columns written before it.
+        */
+        page.get_column(&self.data.bufferpool, METADATA_RID)
+            .write_slot(rid.slot(), rid.raw());
+        drop(base_write);
+
+        self.data
+            .index
+            .write()
+            .apply(&RecordChange::Insert { rid, values, nulls });
+
+        if let Some(t) = transaction {
+            t.record_own_write(&self.name, rid, Some(values.into()));
+        }
+
+        self.stats.lock().record_insert(values);
+        self.record_writes(1, self.data.total_columns() - 1);
+    }
+
+    /*
+        Inserts values if no live row has their primary key, and otherwise updates
+        that row to them, with one lookup of the key. A row that was deleted is
+        inserted again as a new one. Returns None only when transaction aborted, as it
+        does when another write of the key is running, or when the row would repeat a
+        value of a column with a unique index (see Table::build_unique_index). A plain
+        upsert waits out another write of the key instead.
+    */
+    pub fn upsert_query(
+        &self,
+        values: &[u64],
+        mut transaction: Option<&mut Transaction>,
+    ) -> Result<Option<UpsertResult>, CrabError> {
+        let _guard = self.begin_write("upsert_query")?;
+
+        let key = values[self.primary_key_index];
+
+        /*
+            Held until the row is written, so no insert of the key gets in between
+            the lookup and the write. A transaction may hold locks the other write
+            is waiting on, so it aborts rather than waiting too.
+        */
+        let _reservation = loop {
+            if let Some(reservation) = self.reserve_key(key) {
+                break reservation;
+            }
+
+            if let Some(t) = transaction.borrow_mut() {
+                t.set_aborted(AbortKind::KeyInUse(key));
+                return Ok(None);
+            }
+            thread::yield_now();
+        };
+
+        let aborted = |transaction: &Option<&mut Transaction>| {
+            transaction.as_ref().is_some_and(|t| {
+                matches!(
+                    t.get_status(),
+                    QueryStatus::AbortedRetryable | QueryStatus::AbortedNotRetryable
+                )
+            })
+        };
+
+        if let Some(rid) = self.find_row(self.primary_key_index, key) {
+            let mut update = values.iter().copied().map(Some).collect::<Vec<_>>();
+            update[self.primary_key_index] = None;
+
+            if self.update_row(Some(rid), &update, 0, transaction.as_deref_mut())? {
+                return Ok(Some(UpsertResult::Updated));
+            }
+            if aborted(&transaction) {
+                return Ok(None);
+            }
+
+            // Deleted by the transaction that held its lock, which frees the key
+        }
+
+        let Some(_unique) = self.claim_unique(values, u64::MAX, None, &mut transaction) else {
+            return Ok(None);
+        };
+
+        self.write_row(values, 0, self.tick(1), transaction.as_deref_mut());
+
+        match aborted(&transaction) {
+            true => Ok(None),
+            false => Ok(Some(UpsertResult::Inserted)),
+        }
+    }
+
+    /*
+        Inserts many rows outside any transaction, paying once per batch for what
+        insert_query pays per row: the RIDs come from a single contiguous block,
+        every column page is fetched and locked once for all the rows landing in
+        it, and the index takes one write lock. Says for each row whether it went
+        in; one whose key is already in the table, earlier in the batch or being
+        inserted by another query at that moment is left out.
+    */
+    pub fn insert_many_query(&self, rows: &[Vec<u64>]) -> Result<Vec<bool>, CrabError> {
+        let _guard = self.begin_write("insert_many_query")?;
+
+        // Held until the rows are indexed, which also turns away repeats within the batch
+        let mut reservations = Vec::new();
+        let mut accepted = Vec::with_capacity(rows.len());
+
+        for values in rows {
+            let key = values[self.primary_key_index];
+
+            let reservation = self
+                .reserve_key(key)
+                .filter(|_| self.find_row(self.primary_key_index, key).is_none());
+            let unique = reservation
+                .as_ref()
+                .and_then(|_| self.claim_unique(values, u64::MAX, None, &mut None));
+
+            accepted.push(unique.is_some());
+            if let Some(unique) = unique {
+                reservations.extend(reservation);
+                reservations.extend(unique);
+            }
+        }
+
+        let inserted = rows
+            .iter()
+            .zip(&accepted)
+            .filter(|(_, accepted)| **accepted)
+            .map(|(values, _)| values.as_slice())
+            .collect::<Vec<&[u64]>>();
+
+        let first_rid = self
+            .data
+            .next_rid
+            .fetch_add(inserted.len() as u64, Ordering::Relaxed);
+        let first_timestamp = self.tick(inserted.len());
+
+        let mut written = 0;
+        while written < inserted.len() {
+            let rid: RID = (first_rid + written as u64).into();
+            let count = (PAGE_SLOTS - rid.slot()).min(inserted.len() - written);
+            let rows = &inserted[written..written + count];
+            let rids = rid.raw()..rid.raw() + count as u64;
+
+            let base_write = self.data.base_writes.read();
+            let page = self.data.base_page(rid);
+            let bp = &self.data.bufferpool;
+
+            page.get_column(bp, METADATA_INDIRECTION)
+                .write_slots(rid.slot(), iter::repeat_n(RID_INVALID, count));
+            page.get_column(bp, METADATA_BASE_RID)
+                .write_slots(rid.slot(), rids.clone());
+            page.get_column(bp, METADATA_SCHEMA_ENCODING)
+                .write_slots(rid.slot(), iter::repeat_n(0, count));
+
+            for column in 0..self.data.num_columns {
+                page.get_column(bp, NUM_METADATA_COLUMNS + column)
+                    .write_slots(rid.slot(), rows.iter().map(|values| values[column]));
+            }
+
+            if self.data.nullable {
+                page.get_column(bp, self.data.nulls_column())
+                    .write_slots(rid.slot(), iter::repeat_n(0, count));
+            }
+
+            if self.data.timestamps {
+                let timestamps = first_timestamp + written as u64..;
+                page.get_column(bp, self.data.timestamp_column())
+                    .write_slots(rid.slot(), timestamps.take(count));
+            }
+
+            // Last, as in insert_query, so a scan never sees a row half written
+            page.get_column(bp, METADATA_RID)
+                .write_slots(rid.slot(), rids);
+            drop(base_write);
+
+            written += count;
+        }
+
+        let mut index = self.data.index.write();
+        for (rid, values) in (first_rid..).map(RID::from).zip(&inserted) {
+            index.apply(&RecordChange::Insert {
+                rid,
+                values,
+                nulls: 0,
+            });
+        }
+        drop(index);
+        drop(reservations);
+
+        let mut stats = self.stats.lock();
+        for values in &inserted {
+            stats.record_insert(values);
+        }
+        drop(stats);
+        self.record_writes(
+            inserted.len(),
+            inserted.len() * (self.data.total_columns() - 1),
+        );
+
+        Ok(accepted)
+    }
+
+    pub fn sum_query(
+        &self,
+        start_range: u64,
+        end_range: u64,
+        column_index: usize,
+        transaction: Option<&mut Transaction>,
+    ) -> Result<u64, CrabError> {
+        let values = self.aggregated_values(
+            "sum_query",
+            start_range..=end_range,
+            column_index,
+            transaction,
+        )?;
+
+        self.sum_column(column_index, values)
+    }
+
+    /*
+        What the sum queries return: for a signed column the sum's two's complement
+        bits, and an error rather than a wrapped sum when it doesn't fit the column
+    */
+    fn sum_column(&self, column_index: usize, values: Vec<u64>) -> Result<u64, CrabError> {
+        self.signed
+            .sum(column_index, values)
+            .ok_or(CrabError::SumOverflow(column_index))
+    }
+
+    /*
+        Smallest latest value of a column over a key range, None for a range with no rows
+    */
+    pub fn min_query(
+        &self,
+        start_range: u64,
+        end_range: u64,
+        column_index: usize,
+        transaction: Option<&mut Transaction>,
+    ) -> Result<Option<u64>, CrabError> {
+        Ok(self
+            .aggregated_values(
+                "min_query",
+                start_range..=end_range,
+                column_index,
+                transaction,
+            )?
+            .into_iter()
+            .min_by_key(|value| self.signed.sort_key(column_index, *value)))
+    }
+
+    pub fn max_query(
+        &self,
+        start_range: u64,
+        end_range: u64,
+        column_index: usize,
+        transaction: Option<&mut Transaction>,
+    ) -> Result<Option<u64>, CrabError> {
+        Ok(self
+            .aggregated_values(
+                "max_query",
+                start_range..=end_range,
+                column_index,
+                transaction,
+            )?
+            .into_iter()
+            .max_by_key(|value| self.signed.sort_key(column_index, *value)))
+    }
+
+    pub fn avg_query(
+        &self,
+        start_range: u64,
+        end_range: u64,
+        column_index: usize,
+        transaction: Option<&mut Transaction>,
+    ) -> Result<Option<f64>, CrabError> {
+        let values = self.aggregated_values(
+            "avg_query",
+            start_range..=end_range,
+            column_index,
+            transaction,
+        )?;
+
+        if values.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(
+            values
+                .iter()
+                .map(|value| self.signed.widen(column_index, *value) as f64)
+                .sum::<f64>()
+                / values.len() as f64,
+        ))
+    }
+
+    /*
+        The latest value of a column for every row whose key is in range, NULLs
+        left out, what the aggregate queries reduce. A transaction that has to abort gets no values.
+    */
+    fn aggregated_values(
+        &self,
+        query: &'static str,
+        keys: RangeInclusive<u64>,
+        column_index: usize,
+        mut transaction: Option<&mut Transaction>,
+    ) -> Result<Vec<u64>, CrabError> {
+        let _guard = self.begin_query(query)?;
+
+        if let Some(frozen) = self.frozen() {
+            return Ok(frozen.values(keys, column_index));
+        }
+
+        // A transaction has to lock the rows, so it reads them
+        if transaction.is_none() {
+            if let Some(values) = self.index_only_values(&keys, column_index) {
+                return Ok(values);
+            }
+        }
+
+        let mut range = self.find_rows_range(self.primary_key_index, keys.clone());
+
+        if let Some(t) = transaction.borrow_mut() {
+            if !self.lock_shared(t, &mut range) {
+                return Ok(Vec::new());
+            }
+        }
+
+        let mut aggregated_column = vec![0; self.data.num_columns];
+        aggregated_column[column_index] = 1;
+
+        Ok(self
+            .overlay_own_writes(
+                transaction.as_deref(),
+                &range,
+                self.data.materialize_latest(&range, &aggregated_column),
+                &aggregated_column,
+                |row| {
+                    self.signed
+                        .in_range(self.primary_key_index, &keys, row[self.primary_key_index])
+                },
+            )
+            .iter()
+            .filter_map(|record| record.value(0))
+            .collect())
+    }
+
+    /*
+        aggregated_values from the indexes alone, without reading a page. Writers
+        keep each entry at its row's latest value, see Index::apply, so these are
+        the values a page walk would read. For the key, the range's entries are
+        the values themselves. For another indexed column, its entries for the
+        range's rows are, so long as its index isn't so much bigger than the
+        range that reading the rows costs less. None if the indexes can't say.
+    */
+    fn index_only_values(
+        &self,
+        keys: &RangeInclusive<u64>,
+        column_index: usize,
+    ) -> Option<Vec<u64>> {
+        let key = self.primary_key_index;
+        let index = self.usable_index(key)?;
+
+        if column_index == key {
+            return Some(
+                index
+                    .range_counts(key, keys.clone())?
+                    .flat_map(|(value, count)| iter::repeat_n(value, count))
+                    .collect(),
+            );
+        }
+
+        if !index.is_indexed(column_index) {
+            return None;
+        }
+
+        let rids = index
+            .range_from_index(key, keys.clone())?
+            .into_iter()
+            .collect::<FxHashSet<RID>>();
+        if index.entry_count(column_index)? > rids.len().saturating_mul(INDEX_ONLY_FANOUT) {
+            return None;
+        }
+
+        // NULLs have no entries, and aren't aggregated either
+        let values = index
+            .entries(column_index)?
+            .filter(|(_, rid)| rids.contains(rid))
+            .map(|(value, _)| value)
+            .collect();
+        Some(values)
+    }
+
+    /*
+        The n live rows with the highest values in column_index, highest first. Walks
+        the column's index down from the top and stops after n rows, so it fails with
+        CrabError::NotIndexed rather than sorting a scan of an unindexed column.
+    */
+    pub fn last_n_by(
+        &self,
+        column_index: usize,
+        n: usize,
+        included_columns: &[usize],
+        mut transaction: Option<&mut Transaction>,
+    ) -> Result<Vec<Record>, CrabError> {
+        let _guard = self.begin_query("last_n_by")?;
+
+        let mut rids: Vec<RID> = {
+            let index = self
+                .usable_index(column_index)
+                .ok_or(CrabError::NotIndexed(column_index))?;
+
+            index
+                .range_rev(column_index, ..)
+                .expect("usable index missing its column")
+                .filter(|rid| self.data.is_live(*rid))
+                .take(n)
+                .collect()
+        };
+
+        if let Some(t) = transaction.borrow_mut() {
+            if !self.lock_shared(t, &mut rids) {
+                return Ok(Vec::new());
+            }
+        }
+
+        Ok(self.data.materialize_latest(&rids, included_columns))
+    }
+
+    /*
+        Selects the base version of the row with this key, ignoring updates.
+        Merges write the consolidated values back into base pages, so once a
+        row's page range has been merged this returns the values as of that
+        merge rather than as inserted.
+    */
+    pub fn select_original(
+        &self,
+        key: u64,
+        included_columns: &[usize],
+    ) -> Result<Vec<Record>, CrabError> {
+        let _guard = self.begin_query("select_original")?;
+
+        let rids = self.find_rows(self.primary_key_index, key, None);
+        Ok(self.data.read_versions(&rids, included_columns, false))
+    }
+
+    /*
+        Like select_query, but reads each row as it was relative_version updates
+        before its latest: 0 is the latest, -1 the version before and so on, down
+        to the base record once the row runs out of updates. Positive versions
+        read the latest. The tail records are followed whether or not they have
+        been merged, so merges don't cut the history short, but the base record
+        itself reads as of its last merge, like select_original.
+    */
+    pub fn select_version_query(
+        &self,
+        search_value: u64,
+        column_index: usize,
+        included_columns: &[usize],
+        relative_version: i64,
+    ) -> Result<Vec<Record>, CrabError> {
+        let _guard = self.begin_query("select_version_query")?;
+
+        let back = relative_version.min(0).unsigned_abs();
+        let versions = self
+            .find_rows(column_index, search_value, None)
+            .into_iter()
+            .map(|rid| self.version_of(rid, back))
+            .collect::<Result<Vec<RID>, CrabError>>()?;
+
+        Ok(self.data.read_versions(&versions, included_columns, false))
+    }
+
+    /*
+        The record holding base_rid's version back updates before its latest.
+        Unlike get_latest this ignores the TPS, the chain of tail records stays
+        intact after a merge, up to the tail pages a checkpoint reclaimed.
+        Fails with VersionUnavailable if the version was in one of those.
+    */
+    fn version_of(&self, base_rid: RID, back: u64) -> Result<RID, CrabError> {
+        let indirection = |rid: RID| -> RID {
+            self.data
+                .get_page(rid)
+                .get_column(&self.data.bufferpool, METADATA_INDIRECTION)
+                .slot(rid.slot())
+                .into()
+        };
+
+        let mut version = indirection(base_rid);
+        if version.is_invalid() || !self.data.tail_visible(version) {
+            return Ok(base_rid);
+        }
+
+        // Reclaimed tail pages were merged, so the base record holds the latest version
+        if self.tail_reclaimed(base_rid, version) {
+            return match back {
+                0 => Ok(base_rid),
+                _ => Err(CrabError::VersionUnavailable),
+            };
+        }
+
+        // The oldest tail record points back at the base record
+        for _ in 0..back {
+            if version == base_rid {
+                break;
+            }
+            version = indirection(version);
+
+            if version != base_rid && self.tail_reclaimed(base_rid, version) {
+                return Err(CrabError::VersionUnavailable);
+            }
+        }
+
+        Ok(version)
+    }
+
+    /*
+        Whether tail, a record of base_rid's row, went with a tail page a
+        checkpoint reclaimed
+    */
+    fn tail_reclaimed(&self, base_rid: RID, tail: RID) -> bool {
+        let ranges = self.data.range_dir.lock();
+        let range = ranges.get(base_rid.page_range(self.data.range_pages));
+
+        tail.page() >= range.reclaimed_from.load(Ordering::SeqCst)
+    }
+
+    /*
+        Like Table::select_query, but reads each row as of timestamp: its newest
+        version written at or before then, see Table::current_timestamp. Rows are
+        matched on their latest values, and rows with no version that old are left
+        out. A merge writes the newest version it folds in over the base record, so
+        from then on the row as first inserted is gone and timestamps before that
+        version leave the row out. Fails with CrabError::NoTimestamps on a table
+        written before versions were timestamped, until it is compacted, and with
+        CrabError::VersionUnavailable if a row's version as of then was in a tail page
+        a checkpoint reclaimed.
+    */
+    pub fn select_as_of_query(
+        &self,
+        search_value: u64,
+        column_index: usize,
+        included_columns: &[usize],
+        timestamp: u64,
+    ) -> Result<Vec<Record>, CrabError> {
+        let _guard = self.begin_query("select_as_of_query")?;
+
+        if !self.data.timestamps {
+            return Err(CrabError::NoTimestamps);
+        }
+
+        let versions = self
+            .find_rows(column_index, search_value, None)
+            .into_iter()
+            .map(|rid| self.version_as_of(rid, timestamp))
+            .collect::<Result<Vec<Option<RID>>, CrabError>>()?
+            .into_iter()
+            .flatten()
+            .collect::<Vec<RID>>();
+
+        Ok(self.data.read_versions(&versions, included_columns, false))
+    }
+
+    /*
+        The newest record of base_rid written at or before timestamp, walking
+        back from its latest tail record the way version_of does, and failing
+        the same way once the versions left to look at were reclaimed
+    */
+    fn version_as_of(&self, base_rid: RID, timestamp: u64) -> Result<Option<RID>, CrabError> {
+        let read = |rid: RID, column: usize| {
+            self.data
+                .get_page(rid)
+                .get_column(&self.data.bufferpool, column)
+                .slot(rid.slot())
+        };
+
+        let mut version: RID = read(base_rid, METADATA_INDIRECTION).into();
+        if version.is_invalid() || !self.data.tail_visible(version) {
+            version = base_rid;
+        }
+
+        // Merged, so the base record holds the latest version, but not the ones before
+        let reclaimed = version != base_rid && self.tail_reclaimed(base_rid, version);
+        if reclaimed {
+            version = base_rid;
+        }
+
+        loop {
+            if read(version, self.data.timestamp_column()) <= timestamp {
+                return Ok(Some(version));
+            }
+
+            // The oldest tail record points back at the base record
+            if version == base_rid {
+                return if reclaimed {
+                    Err(CrabError::VersionUnavailable)
+                } else {
+                    Ok(None)
+                };
+            }
+            version = read(version, METADATA_INDIRECTION).into();
+
+            if version != base_rid && self.tail_reclaimed(base_rid, version) {
+                return Err(CrabError::VersionUnavailable);
+            }
+        }
+    }
+
+    /*
+        Sums a column over the rows with keys in start..=end as they were relative_version
+        updates back, see select_version_query. Tail records hold whole rows, so a version
+        that didn't touch the column still reads the value it had then.
+    */
+    pub fn sum_version_query(
+        &self,
+        start_range: u64,
+        end_range: u64,
+        column_index: usize,
+        relative_version: i64,
+    ) -> Result<u64, CrabError> {
+        let _guard = self.begin_query("sum_version_query")?;
+
+        let back = relative_version.min(0).unsigned_abs();
+        let versions = self
+            .find_rows_range(
+                self.primary_key_index,
+                RangeInclusive::new(start_range, end_range),
+            )
+            .into_iter()
+            .map(|rid| self.version_of(rid, back))
+            .collect::<Result<Vec<RID>, CrabError>>()?;
+
+        let mut summed_column = vec![0; self.data.num_columns];
+        summed_column[column_index] = 1;
+
+        let values = self
+            .data
+            .read_versions(&versions, &summed_column, false)
+            .iter()
+            .filter_map(|record| record.value(0))
+            .collect();
+
+        self.sum_column(column_index, values)
+    }
+
+    /*
+        Sums a column over the base versions of the rows with keys in start..=end,
+        with the same caveat about merged ranges as select_original.
+    */
+    pub fn sum_original(
+        &self,
+        start_range: u64,
+        end_range: u64,
+        column_index: usize,
+    ) -> Result<u64, CrabError> {
+        let _guard = self.begin_query("sum_original")?;
+
+        let rids = self.find_rows_range(
+            self.primary_key_index,
+            RangeInclusive::new(start_range, end_range),
+        );
+
+        let mut summed_column = vec![0; self.data.num_columns];
+        summed_column[column_index] = 1;
+
+        let values = self
+            .data
+            .read_versions(&rids, &summed_column, false)
+            .iter()
+            .filter_map(|record| record.value(0))
+            .collect();
+
+        self.sum_column(column_index, values)
+    }
+
+    pub fn update_query(
+        &self,
+        key: u64,
+        values: &[Option<u64>],
+        transaction: Option<&mut Transaction>,
+    ) -> Result<bool, CrabError> {
+        let _guard = self.begin_write("update_query")?;
+
+        self.update_row(
+            self.find_row(self.primary_key_index, key),
+            values,
+            0,
+            transaction,
+        )
+    }
+
+    /*
+        Table::update_query that can also set columns to NULL: Some(None) does, and
+        None leaves a column as it is. Fails with CrabError::NotNullable as
+        Table::insert_query_nullable does, updating nothing.
+    */
+    pub fn update_query_nullable(
+        &self,
+        key: u64,
+        values: &[Option<Option<u64>>],
+        transaction: Option<&mut Transaction>,
+    ) -> Result<bool, CrabError> {
+        let set_null = self.null_mask(values.iter().map(|value| *value == Some(None)))?;
+        let values = values
+            .iter()
+            .map(|value| value.flatten())
+            .collect::<Vec<Option<u64>>>();
+
+        let _guard = self.begin_write("update_query_nullable")?;
+
+        self.update_row(
+            self.find_row(self.primary_key_index, key),
+            &values,
+            set_null,
+            transaction,
+        )
+    }
+
+    /*
+        Table::update_query setting the named columns, leaving the rest as they are.
+        Fails with CrabError::UnknownColumn if a name isn't one of the table's.
+    */
+    pub fn update_query_named(
+        &self,
+        key: u64,
+        values: &[(&str, u64)],
+        transaction: Option<&mut Transaction>,
+    ) -> Result<bool, CrabError> {
+        let mut columns = vec![None; self.data.num_columns];
+        for (name, value) in values {
+            columns[self.named_column(name)?] = Some(*value);
+        }
+
+        self.update_query(key, &columns, transaction)
+    }
+
+    /*
+        update_query for a caller that already holds the row's base RID, say from
+        select_rids, skipping the key lookup. A RID that was deleted since, or is
+        a tail RID, updates nothing.
+    */
+    pub fn update_by_rid(
+        &self,
+        rid: RID,
+        values: &[Option<u64>],
+        transaction: Option<&mut Transaction>,
+    ) -> Result<bool, CrabError> {
+        let _guard = self.begin_write("update_by_rid")?;
+
+        self.update_row(
+            Some(rid).filter(|rid| !rid.is_tail() && self.data.is_live(*rid)),
+            values,
+            0,
+            transaction,
+        )
     }
 
-    pub fn primary_key(&self) -> usize {
-        self.primary_key_index
+    /*
+        Writes a tail record of row with values in place of its latest ones, and
+        the columns in set_null NULL. A column given a value is no longer NULL.
+    */
+    fn update_row(
+        &self,
+        row: Option<RID>,
+        values: &[Option<u64>],
+        set_null: u64,
+        mut transaction: Option<&mut Transaction>,
+    ) -> Result<bool, CrabError> {
+        // Moving a row to a new key claims it the way an insert of that key would
+        let _reservation = match values[self.primary_key_index] {
+            Some(pk) => {
+                let Some(reservation) = self.reserve_key(pk) else {
+                    if let Some(t) = transaction.borrow_mut() {
+                        t.set_aborted(AbortKind::KeyInUse(pk));
+                    }
+                    return Ok(false);
+                };
+
+                if self.find_row(self.primary_key_index, pk).is_some() {
+                    if let Some(t) = transaction.borrow_mut() {
+                        t.constraint_violated();
+                    }
+                    return Ok(false);
+                }
+
+                Some(reservation)
+            }
+            None => None,
+        };
+
+        if row.is_none() {
+            return Ok(false);
+        }
+
+        let base_rid = row.unwrap();
+
+        if let Some(t) = transaction.borrow_mut() {
+            if !t.try_lock_with_abort(&self.lock_manager, base_rid, LockType::Exclusive) {
+                return Ok(false);
+            }
+
+            // Deleted by the transaction that held the lock before
+            if !self.data.is_live(base_rid) {
+                return Ok(false);
+            }
+        }
+
+        let written = values
+            .iter()
+            .enumerate()
+            .filter(|(_, v)| v.is_some())
+            .fold(0, |encoding, (i, _)| encoding | (1 << i));
+        let new_values = values
+            .iter()
+            .map(|value| value.unwrap_or(0))
+            .collect::<Vec<u64>>();
+
+        // Held until the new values reach the index, like the key's reservation
+        let Some(_unique) = self.claim_unique(&new_values, written, row, &mut transaction) else {
+            return Ok(false);
+        };
+
+        let base_page = self.data.get_page(base_rid);
+        let mut updated_values = self.merge_values(base_rid, values);
+
+        let old_latest_rid: RID = self
+            .data
+            .get_page(base_rid)
+            .get_column(&self.data.bufferpool, METADATA_INDIRECTION)
+            .slot(base_rid.slot())
+            .into();
+
+        let base_latest = self.data.get_latest(base_rid);
+        let old_nulls = self.data.read_nulls(base_latest);
+
+        let tail_rid = self.next_tid(base_rid.page_range(self.data.range_pages));
+
+        // Setting a column NULL changes it like any other value
+        let schema_encoding = written | set_null;
+        let nulls = old_nulls & !written | set_null;
+        for (i, value) in updated_values.iter_mut().enumerate() {
+            if record::is_null(set_null, i) {
+                *value = 0;
+            }
+        }
+
+        /*
+            The new version is written in full before the base record points at it,
+            and indexes only learn about it once it is reachable, so a reader going
+            through either never sees a half-written tail record.
+        */
+        self.write_tail(
+            &self.data.bufferpool,
+            tail_rid,
+            base_rid,
+            old_latest_rid,
+            &self.stored_row(&updated_values, nulls),
+            schema_encoding,
+        );
+
+        if let Some(t) = transaction.borrow_mut() {
+            t.log_write(METADATA_INDIRECTION, base_rid, old_latest_rid.raw());
+            t.log_write(METADATA_RID, tail_rid, RID_INVALID);
+        }
+
+        base_page
+            .get_column(&self.data.bufferpool, METADATA_INDIRECTION)
+            .write_slot(base_rid.slot(), tail_rid.raw());
+
+        // Tail records carry every column, so the previous latest version has the old values
+        let old_values = (0..self.data.num_columns)
+            .map(|i| match schema_encoding >> i & 1 {
+                0 => 0,
+                _ => self
+                    .data
+                    .get_page(base_latest)
+                    .get_column(&self.data.bufferpool, NUM_METADATA_COLUMNS + i)
+                    .slot(base_latest.slot()),
+            })
+            .collect::<Vec<u64>>();
+
+        self.data.index.write().apply_logged(
+            &RecordChange::Update {
+                rid: base_rid,
+                schema_encoding,
+                old: &old_values,
+                old_nulls,
+                new: &updated_values,
+                new_nulls: nulls,
+            },
+            |mutation| {
+                if let Some(t) = transaction.borrow_mut() {
+                    t.log_index_write(mutation);
+                }
+            },
+        );
+
+        if let Some(t) = transaction {
+            t.record_own_write(&self.name, base_rid, Some(updated_values.into()));
+        }
+
+        self.stats.lock().record_update(values);
+        // The tail record and the base record's indirection
+        self.record_writes(1, self.data.total_columns());
+
+        Ok(true)
     }
 
-    pub fn select_query(
+    /*
+        Applies the same change to every row with a key in start_key..=end_key
+        and returns how many rows were updated. Rows are resolved once, tail
+        records are allocated a page range at a time, and the index is updated
+        a column at a time under one lock. Under a transaction the rows are
+        locked in key order, so overlapping range updates queue up behind each
+        other rather than deadlocking. The key itself can't be changed, since
+        every row in the range would end up with the same one, and for the same
+        reason neither can a column with a unique index.
+    */
+    pub fn update_range(
         &self,
-        search_value: u64,
-        column_index: usize,
-        included_columns: &[usize],
+        start_key: u64,
+        end_key: u64,
+        updates: &[Option<UpdateOp>],
         mut transaction: Option<&mut Transaction>,
-    ) -> Vec<Record> {
-        let vals: Vec<RID> = self.find_rows(column_index, search_value);
+    ) -> Result<usize, CrabError> {
+        let _guard = self.begin_write("update_range")?;
+
+        if updates[self.primary_key_index].is_some()
+            || self.writes_unique(updates.iter().map(Option::is_some))
+        {
+            if let Some(t) = transaction.borrow_mut() {
+                t.constraint_violated();
+            }
+            return Ok(0);
+        }
+
+        let range = start_key..=end_key;
+
+        // Scans match on the base record's key, so go by the latest one
+        let mut rows: Vec<(u64, RID)> = self
+            .find_rows_range(self.primary_key_index, range.clone())
+            .into_iter()
+            .map(|rid| {
+                let latest = self.data.get_latest(rid);
+                let key = self
+                    .data
+                    .get_page(latest)
+                    .get_column(
+                        &self.data.bufferpool,
+                        NUM_METADATA_COLUMNS + self.primary_key_index,
+                    )
+                    .slot(latest.slot());
+                (key, rid)
+            })
+            .filter(|(key, _)| range.contains(key))
+            .collect();
+        rows.sort_unstable();
 
         if let Some(t) = transaction.borrow_mut() {
-            for rid in vals.iter() {
-                if !t.try_lock_with_abort(&self.lock_manager, *rid, LockType::Shared) {
-                    return Vec::new();
+            for (_, rid) in rows.iter() {
+                if !t.try_lock_with_abort(&self.lock_manager, *rid, LockType::Exclusive) {
+                    return Ok(0);
                 }
             }
         }
 
-        vals.into_iter()
-            .map(|rid| {
-                let rid = self.get_latest(rid);
-                let page = self.get_page(rid);
+        // A nullable table's rows are read and written with their NULL masks, see stored_row
+        let unchanged = vec![None; self.data.num_columns + self.data.nullable as usize];
+        let set = updates
+            .iter()
+            .enumerate()
+            .filter(|(_, op)| matches!(op, Some(UpdateOp::Set(_))))
+            .fold(0, |mask, (i, _)| mask | (1 << i));
 
-                let result_cols = included_columns
+        // (base RID, its indirection, latest values, updated values) per row
+        let updated: Vec<(RID, RID, Vec<u64>, Vec<u64>)> = rows
+            .iter()
+            .map(|(_, base_rid)| {
+                let old_latest_rid: RID = self
+                    .data
+                    .get_page(*base_rid)
+                    .get_column(&self.data.bufferpool, METADATA_INDIRECTION)
+                    .slot(base_rid.slot())
+                    .into();
+
+                let old_values = self.merge_values(*base_rid, &unchanged);
+                let nulls = self.stored_nulls(&old_values);
+                let new_values = old_values
                     .iter()
+                    .zip(updates)
                     .enumerate()
-                    .filter_map(|(i, x)| {
-                        if *x != 0 {
-                            Some(
-                                page.get_column(
-                                    self.bufferpool.lock().borrow_mut(),
-                                    NUM_METADATA_COLUMNS + i,
-                                )
-                                .slot(rid.slot()),
-                            )
-                        } else {
-                            None
-                        }
+                    .map(|(i, (value, op))| match op {
+                        // NULL plus anything is still NULL
+                        Some(UpdateOp::Add(_)) if record::is_null(nulls, i) => *value,
+                        _ => op.map_or(*value, |op| op.apply(*value)),
                     })
                     .collect::<Vec<u64>>();
 
-                Record {
-                    rid: rid.raw(),
-                    columns: result_cols,
-                }
+                let new_values = self.stored_row(&new_values, nulls & !set);
+                (*base_rid, old_latest_rid, old_values, new_values)
             })
-            .collect()
-    }
+            .collect();
 
-    pub fn insert_query(&self, values: &[u64], mut transaction: Option<&mut Transaction>) {
-        if self
-            .find_row(self.primary_key_index, values[self.primary_key_index])
-            .is_some()
-        {
-            if let Some(t) = transaction.borrow_mut() {
-                t.set_aborted(false);
-            }
-            return;
+        let schema_encoding = updates
+            .iter()
+            .enumerate()
+            .filter(|(_, op)| op.is_some())
+            .fold(0, |encoding, (i, _)| encoding | (1 << i));
+
+        let mut by_range: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for (row, (base_rid, ..)) in updated.iter().enumerate() {
+            by_range
+                .entry(base_rid.page_range(self.data.range_pages))
+                .or_default()
+                .push(row);
         }
 
-        let rid: RID = self.next_rid.fetch_add(1, Ordering::Relaxed).into();
-
-        if let Some(t) = transaction.borrow_mut() {
-            if !t.try_lock_with_abort(&self.lock_manager, rid, LockType::Exclusive) {
-                return;
-            }
-        }
+        // Same ordering as update_query, each tail record is complete before its base points at it
+        for (range_id, rows) in by_range {
+            let tail_rids = self.next_tids(range_id, rows.len());
+            let bp = &self.data.bufferpool;
 
-        let page_dir = self.page_dir.read();
+            for (row, tail_rid) in rows.into_iter().zip(tail_rids) {
+                let (base_rid, old_latest_rid, _, new_values) = &updated[row];
 
-        let page: Arc<[usize]> = match page_dir.get(rid) {
-            None => {
-                drop(page_dir);
-                let mut page_dir = self.page_dir.write();
-                // Check again since unlocking read and acquiring write are not atomic
-                if page_dir.get(rid).is_none() {
-                    let reserve_count = self.total_columns() * PAGE_RANGE_COUNT;
-                    let reserved = self.disk.reserve_range(reserve_count);
-
-                    for i in 0..PAGE_RANGE_COUNT {
-                        let page_id = (rid.page_range() * PAGE_RANGE_COUNT) + i;
-                        let mut column_pages =
-                            Arc::<[usize]>::new_uninit_slice(self.total_columns());
-
-                        let start_offset = reserved + (i * self.total_columns());
-
-                        for (i, x) in
-                            (start_offset..(start_offset + self.total_columns())).enumerate()
-                        {
-                            Arc::get_mut(&mut column_pages).unwrap()[i].write(x);
-                        }
+                self.write_tail(
+                    bp,
+                    tail_rid,
+                    *base_rid,
+                    *old_latest_rid,
+                    new_values,
+                    schema_encoding,
+                );
 
-                        let column_pages = unsafe { column_pages.assume_init() };
+                if let Some(t) = transaction.borrow_mut() {
+                    t.log_write(METADATA_INDIRECTION, *base_rid, old_latest_rid.raw());
+                    t.log_write(METADATA_RID, tail_rid, RID_INVALID);
+                }
 
-                        self.bufferpool
-                            .lock()
-                            .get_page(column_pages[METADATA_PAGE_HEADER])
-                            .write_slot(0, RID_INVALID);
+                self.data
+                    .get_page(*base_rid)
+                    .get_column(bp, METADATA_INDIRECTION)
+                    .write_slot(base_rid.slot(), tail_rid.raw());
+            }
+        }
 
-                        page_dir.new_page(page_id, column_pages);
+        let mut index = self.data.index.write();
+        for (base_rid, _, old_values, new_values) in updated.iter() {
+            index.apply_logged(
+                &RecordChange::Update {
+                    rid: *base_rid,
+                    schema_encoding,
+                    old: old_values,
+                    old_nulls: self.stored_nulls(old_values),
+                    new: new_values,
+                    new_nulls: self.stored_nulls(new_values),
+                },
+                |mutation| {
+                    if let Some(t) = transaction.borrow_mut() {
+                        t.log_index_write(mutation);
                     }
-                }
-
-                page_dir
-                    .get(rid)
-                    .expect("Allocated new pages but no mapping in directory")
+                },
+            );
+        }
+        drop(index);
+
+        if let Some(t) = transaction {
+            for (base_rid, _, _, new_values) in updated.iter() {
+                t.record_own_write(
+                    &self.name,
+                    *base_rid,
+                    Some(new_values[..self.data.num_columns].into()),
+                );
             }
-            Some(cols) => cols,
-        };
+        }
 
-        if let Some(t) = transaction.borrow_mut() {
-            t.log_write(METADATA_RID, rid, RID_INVALID);
+        let mut stats = self.stats.lock();
+        for (.., new_values) in updated.iter() {
+            let values: Vec<Option<u64>> = updates
+                .iter()
+                .zip(new_values)
+                .map(|(op, value)| op.map(|_| *value))
+                .collect();
+            stats.record_update(&values);
         }
+        drop(stats);
+        self.record_writes(updated.len(), updated.len() * self.data.total_columns());
 
-        let page = Page::new(page);
-        page.get_column(self.bufferpool.lock().borrow_mut(), METADATA_INDIRECTION)
-            .write_slot(rid.slot(), RID_INVALID);
+        Ok(updated.len())
+    }
 
-        page.get_column(self.bufferpool.lock().borrow_mut(), METADATA_RID)
-            .write_slot(rid.slot(), rid.raw());
+    pub fn delete_query(
+        &self,
+        key: u64,
+        transaction: Option<&mut Transaction>,
+    ) -> Result<bool, CrabError> {
+        Ok(self
+            .delete_with("delete_query", key, transaction)?
+            .is_some())
+    }
 
-        page.get_column(
-            self.bufferpool.lock().borrow_mut(),
-            METADATA_SCHEMA_ENCODING,
-        )
-        .write_slot(rid.slot(), 0);
+    /*
+        Like delete_query, but hands back every column of the row's latest version
+        as it was read under the delete's own lock, for callers that keep their own
+        undo or redo log and can't afford a separate select racing other writers
+    */
+    pub fn delete_returning_query(
+        &self,
+        key: u64,
+        transaction: Option<&mut Transaction>,
+    ) -> Result<Option<Record>, CrabError> {
+        self.delete_with("delete_returning_query", key, transaction)
+    }
 
-        for (i, val) in values.iter().enumerate() {
-            page.get_column(
-                self.bufferpool.lock().borrow_mut(),
-                NUM_METADATA_COLUMNS + i,
-            )
-            .write_slot(rid.slot(), *val);
-        }
+    fn delete_with(
+        &self,
+        query: &'static str,
+        key: u64,
+        transaction: Option<&mut Transaction>,
+    ) -> Result<Option<Record>, CrabError> {
+        let _guard = self.begin_write(query)?;
 
-        let mut index = self.index.write();
-        for i in 0..self.num_columns {
-            if let Some(t) = transaction.borrow_mut() {
-                t.log_index_write(IndexMutation::Add {
-                    rid,
-                    value: values[i],
-                    column: i,
-                });
-            }
+        Ok(self.delete_row(self.find_row(self.primary_key_index, key), transaction))
+    }
 
-            index.update_index(i, values[i], rid);
-        }
+    /*
+        Deletes every row whose latest version holds value in column_index, going
+        through the column's index if it has one and scanning otherwise. Returns
+        how many rows were deleted.
+    */
+    pub fn delete_where_query(
+        &self,
+        column_index: usize,
+        value: u64,
+        mut transaction: Option<&mut Transaction>,
+    ) -> Result<usize, CrabError> {
+        let _guard = self.begin_write("delete_where_query")?;
+
+        let Some(rows) = self.lock_matching(column_index, value, transaction.as_deref_mut()) else {
+            return Ok(0);
+        };
+
+        Ok(rows
+            .into_iter()
+            .filter(|rid| {
+                self.delete_row(Some(*rid), transaction.as_deref_mut())
+                    .is_some()
+            })
+            .count())
     }
 
-    pub fn sum_query(
+    /*
+        update_query for every row whose latest version holds value in column_index.
+        Setting the key would give every one of them the same key, so that is a key
+        constraint violation like in update_range, as is setting a unique column.
+        Returns how many rows were updated.
+    */
+    pub fn update_where_query(
         &self,
-        start_range: u64,
-        end_range: u64,
         column_index: usize,
+        value: u64,
+        values: &[Option<u64>],
         mut transaction: Option<&mut Transaction>,
-    ) -> u64 {
-        let range = self.find_rows_range(column_index, RangeInclusive::new(start_range, end_range));
+    ) -> Result<usize, CrabError> {
+        let _guard = self.begin_write("update_where_query")?;
 
-        if let Some(t) = transaction.borrow_mut() {
-            for rid in range.iter() {
-                if !t.try_lock_with_abort(&self.lock_manager, *rid, LockType::Shared) {
-                    return 0;
-                }
+        if values[self.primary_key_index].is_some()
+            || self.writes_unique(values.iter().map(Option::is_some))
+        {
+            if let Some(t) = transaction.borrow_mut() {
+                t.constraint_violated();
             }
+            return Ok(0);
         }
 
-        let mut sum: u64 = 0;
-        for rid in range.iter() {
-            let latest = self.get_latest_with_bp(&mut self.bufferpool.lock(), *rid);
-            sum += self
-                .get_page(latest)
-                .get_column(
-                    &mut self.bufferpool.lock(),
-                    NUM_METADATA_COLUMNS + column_index,
-                )
-                .slot(latest.slot());
+        let Some(rows) = self.lock_matching(column_index, value, transaction.as_deref_mut()) else {
+            return Ok(0);
+        };
+
+        let mut updated = 0;
+        for rid in rows {
+            if self.update_row(Some(rid), values, 0, transaction.as_deref_mut())? {
+                updated += 1;
+            }
         }
 
-        sum
+        Ok(updated)
     }
 
-    pub fn update_query(
+    /*
+        The rows a bulk write by value applies to, locked in RID order for a transaction,
+        or None if it had to abort. Rows found are checked again once locked, since
+        another writer may have deleted them or changed the value in the meantime.
+    */
+    fn lock_matching(
         &self,
-        key: u64,
-        values: &[Option<u64>],
+        column_index: usize,
+        value: u64,
         mut transaction: Option<&mut Transaction>,
-    ) -> bool {
-        let row = self.find_row(self.primary_key_index, key);
+    ) -> Option<Vec<RID>> {
+        let mut rows = self.find_rows(column_index, value, None);
+        rows.sort_unstable();
 
-        if let Some(pk) = values[self.primary_key_index] {
-            if self.find_row(self.primary_key_index, pk).is_some() {
-                if let Some(t) = transaction.borrow_mut() {
-                    t.set_aborted(false);
+        if let Some(t) = transaction.borrow_mut() {
+            for rid in rows.iter() {
+                if !t.try_lock_with_abort(&self.lock_manager, *rid, LockType::Exclusive) {
+                    return None;
                 }
-                return false;
             }
         }
 
-        if row.is_none() {
-            return false;
-        }
+        rows.retain(|rid| self.data.is_live(*rid) && self.holds_value(*rid, column_index, value));
 
-        let base_rid = row.unwrap();
+        Some(rows)
+    }
+
+    fn delete_row(
+        &self,
+        row: Option<RID>,
+        mut transaction: Option<&mut Transaction>,
+    ) -> Option<Record> {
+        let row = row?;
 
         if let Some(t) = transaction.borrow_mut() {
-            if !t.try_lock_with_abort(&self.lock_manager, base_rid, LockType::Exclusive) {
-                return false;
+            if !t.try_lock_with_abort(&self.lock_manager, row, LockType::Exclusive) {
+                return None;
             }
         }
 
-        let base_page = self.get_page(base_rid);
-        let updated_values = self.merge_values(base_rid, values);
+        // Read before the RIDs go, so it's the version a select just before would have seen
+        let latest = self.data.get_latest(row);
+        let deleted = self
+            .data
+            .read_versions(&[latest], &vec![1; self.data.num_columns], false)
+            .pop()
+            .expect("latest version of a live row");
 
-        let old_latest_rid: RID = self
-            .get_page(base_rid)
-            .get_column(self.bufferpool.lock().borrow_mut(), METADATA_INDIRECTION)
-            .slot(base_rid.slot())
+        let mut next_tail: RID = self
+            .data
+            .get_page(row)
+            .get_column(&self.data.bufferpool, METADATA_INDIRECTION)
+            .slot(row.slot())
             .into();
 
-        let base_latest = self.get_latest(base_rid);
-        let old_schema_encoding = self
-            .get_page(base_latest)
-            .get_column(
-                self.bufferpool.lock().borrow_mut(),
-                METADATA_SCHEMA_ENCODING,
-            )
-            .slot(base_latest.slot());
+        // Records in reclaimed tail pages are gone already
+        let mut chain = Vec::new();
+        while next_tail.raw() != RID_INVALID
+            && next_tail.raw() != row.raw()
+            && !self.tail_reclaimed(row, next_tail)
+        {
+            let tail_page = self.data.get_page(next_tail);
+            let bp = &self.data.bufferpool;
 
-        let tail_rid = self.next_tid(base_rid.page_range());
-        let tail_page = self.get_page(tail_rid);
+            let next = tail_page
+                .get_column(bp, METADATA_INDIRECTION)
+                .slot(next_tail.slot());
+            tail_page
+                .get_column(bp, METADATA_RID)
+                .write_slot(next_tail.slot(), RID_INVALID);
 
-        tail_page
-            .get_column(self.bufferpool.lock().borrow_mut(), METADATA_BASE_RID)
-            .write_slot(tail_rid.slot(), base_rid.raw());
+            chain.push(next_tail);
+            next_tail = next.into();
+        }
 
-        tail_page
-            .get_column(self.bufferpool.lock().borrow_mut(), METADATA_INDIRECTION)
-            .write_slot(
-                tail_rid.slot(),
-                if old_latest_rid.is_invalid() {
-                    base_rid.raw()
-                } else {
-                    old_latest_rid.raw()
-                },
-            );
+        self.data
+            .get_page(row)
+            .get_column(&self.data.bufferpool, METADATA_RID)
+            .write_slot(row.slot(), RID_INVALID);
 
-        tail_page
-            .get_column(self.bufferpool.lock().borrow_mut(), METADATA_RID)
-            .write_slot(tail_rid.slot(), tail_rid.raw());
+        /*
+            Every index drops the row under the values of its latest version, which
+            a rollback puts back. is_live still filters entries a reader found just
+            before this, and merges clear whatever an older build left behind.
+        */
+        self.data.index.write().apply(&RecordChange::Delete {
+            rid: row,
+            values: &deleted.columns,
+            nulls: deleted.nulls,
+        });
+
+        let invalidated = chain.len() + 1;
+        if let Some(t) = transaction {
+            t.log_delete(row, chain);
+            t.record_own_write(&self.name, row, None);
+        }
 
-        //print!("Update vals: {:?}\n", columns);
+        self.stats.lock().record_delete();
+        self.record_writes(1, invalidated);
 
-        for (i, val) in updated_values.iter().enumerate() {
-            tail_page
-                .get_column(
-                    self.bufferpool.lock().borrow_mut(),
-                    NUM_METADATA_COLUMNS + i,
-                )
-                .write_slot(tail_rid.slot(), *val);
+        Some(deleted)
+    }
+
+    /*
+        Recomputes the column statistics from the latest version of every live row.
+        Runs alongside other queries, reading through the scan hint so it doesn't
+        push out their pages; writes that land meanwhile may be missed until the next.
+    */
+    pub fn analyze(&self) -> Result<(), CrabError> {
+        let _guard = self.begin_query("analyze")?;
+
+        let mut stats = self.stats.lock().cleared();
+        let all_columns = vec![1; self.data.num_columns];
+        let next_rid = self.data.next_rid.load(Ordering::Relaxed);
+
+        let mut page_start = 0;
+        while page_start < next_rid {
+            let page_end = next_rid.min(page_start + PAGE_SLOTS as u64);
+
+            let rid_column = self
+                .data
+                .get_page(page_start.into())
+                .get_column_for_scan(&self.data.bufferpool, METADATA_RID);
+
+            let live = (page_start..page_end)
+                .map(RID::from)
+                .filter(|rid| rid_column.slot(rid.slot()) != RID_INVALID)
+                .collect::<Vec<RID>>();
+
+            let latest = self.data.get_latest_batch(&live, true);
+            for record in self.data.read_versions(&latest, &all_columns, true) {
+                stats.record_insert(&record.columns);
+            }
 
-            //print!("Base Page: {:?}\n",&base_page.get_column(crate::NUM_METADATA_COLUMNS + i).page[0..50],);
-            //print!("Tail Page: {:?}\n",&page.get_column(crate::NUM_METADATA_COLUMNS + i).page[0..50]);
+            page_start = page_end;
         }
 
-        let mut schema_encoding: u64 = 0;
+        *self.stats.lock() = stats;
 
-        for (i, v) in values.iter().enumerate() {
-            if !v.is_none() {
-                schema_encoding |= 1 << i;
-                let mut index = self.index.write();
+        Ok(())
+    }
 
-                if let Some(t) = transaction.borrow_mut() {
-                    t.log_index_write(IndexMutation::Add {
-                        rid: base_rid,
-                        value: v.unwrap(),
-                        column: i,
-                    });
-                }
+    pub fn build_index(&self, column_num: usize) {
+        let mut index = self.data.index.write();
+        self.fill_index(&mut index, column_num);
+    }
 
-                index.update_index(i, v.unwrap(), base_rid);
-                if (old_schema_encoding & (1 << i)) == 1 || old_latest_rid.is_invalid() {
-                    let val = base_page
-                        .get_column(
-                            self.bufferpool.lock().borrow_mut(),
-                            NUM_METADATA_COLUMNS + i,
-                        )
-                        .slot(base_rid.slot());
+    /*
+        Table::build_index on a column that may then hold each value in only one row.
+        Inserts and updates that would repeat a value are turned away like a duplicate
+        primary key, as are Table::update_range and Table::update_where_query setting
+        the column. NULLs never collide, and deleting a row frees its value. Fails
+        with CrabError::DuplicateValue if rows already share a value, leaving the
+        column as it was, or with CrabError::TableInUse while transactions hold locks
+        on the table, since rolling one back could bring a duplicate back.
+    */
+    pub fn build_unique_index(&self, column_num: usize) -> Result<(), CrabError> {
+        // No write gets between the check and the index becoming unique
+        let _checkpoint = self.checkpoint_lock.write();
+
+        if !self.lock_manager.is_idle() {
+            return Err(CrabError::TableInUse);
+        }
 
-                    if let Some(t) = transaction.borrow_mut() {
-                        t.log_index_write(IndexMutation::Remove {
-                            rid: base_rid,
-                            old_value: val,
-                            column: i,
-                        });
-                    }
+        let mut index = self.data.index.write();
+        let was_indexed = index.is_indexed(column_num);
+        self.fill_index(&mut index, column_num);
+
+        // Entries come in value order, so a repeat is next to the first one
+        let duplicate = {
+            let mut values = index.entries(column_num).unwrap().map(|(value, _)| value);
+            let mut previous = values.next();
+            values.find(|value| {
+                let repeated = previous == Some(*value);
+                previous = Some(*value);
+                repeated
+            })
+        };
 
-                    index.remove_index(i, val, base_rid);
-                } else if !old_latest_rid.is_invalid() && (old_schema_encoding & (1 << i)) == 1 {
-                    let mut index = self.index.write();
-                    let val = self
-                        .get_page(old_latest_rid)
-                        .get_column(
-                            self.bufferpool.lock().borrow_mut(),
-                            NUM_METADATA_COLUMNS + i,
-                        )
-                        .slot(old_latest_rid.slot());
+        if let Some(value) = duplicate {
+            if !was_indexed {
+                index.drop_index(column_num);
+            }
+            return Err(CrabError::DuplicateValue {
+                column: column_num,
+                value,
+            });
+        }
 
-                    if let Some(t) = transaction.borrow_mut() {
-                        t.log_index_write(IndexMutation::Remove {
-                            rid: base_rid,
-                            old_value: val,
-                            column: i,
-                        });
-                    }
+        index.set_unique(column_num);
+        Ok(())
+    }
 
-                    index.remove_index(i, val, base_rid);
-                }
+    /*
+        Columns with a unique index, see Table::build_unique_index.
+    */
+    pub fn unique_columns(&self) -> Vec<usize> {
+        let index = self.data.index.read();
+        (0..self.data.num_columns)
+            .filter(|column| index.is_unique(*column))
+            .collect()
+    }
+
+    /*
+        Indexes column_num afresh from the latest version of every live row. A
+        unique index stays unique, its rows can't have come to share a value.
+    */
+    fn fill_index(&self, index: &mut Index, column_num: usize) {
+        let unique = index.is_unique(column_num);
+        index.create_index(column_num);
+        let max_rid = self.data.next_rid.load(Ordering::Relaxed);
+        for rid in (0..max_rid).map(RID::from) {
+            if self
+                .data
+                .get_page(rid)
+                .get_column(&self.data.bufferpool, METADATA_RID)
+                .slot(rid.slot())
+                == RID_INVALID
+            {
+                continue;
             }
-        }
 
-        tail_page
-            .get_column(
-                self.bufferpool.lock().borrow_mut(),
-                METADATA_SCHEMA_ENCODING,
-            )
-            .write_slot(base_rid.slot(), schema_encoding);
+            // NULLs aren't indexed
+            let latest = self.data.get_latest(rid);
+            if record::is_null(self.data.read_nulls(latest), column_num) {
+                continue;
+            }
 
-        //print!("Update called\n");
+            index.update_index(
+                column_num,
+                self.data
+                    .get_page(latest)
+                    .get_column(&self.data.bufferpool, NUM_METADATA_COLUMNS + column_num)
+                    .slot(latest.slot()),
+                rid,
+            );
+        }
 
-        if let Some(t) = transaction.borrow_mut() {
-            t.log_write(METADATA_INDIRECTION, base_rid, old_latest_rid.raw());
-            t.log_write(METADATA_RID, tail_rid, RID_INVALID);
+        if unique {
+            index.set_unique(column_num);
         }
+    }
 
-        base_page
-            .get_column(self.bufferpool.lock().borrow_mut(), METADATA_INDIRECTION)
-            .write_slot(base_rid.slot(), tail_rid.raw());
+    /*
+        Makes sure the primary key index covers every live row, rebuilding it if its
+        entry count disagrees with the row count. Large tables are rebuilt on a
+        background thread, whose handle is returned; queries keep working meanwhile
+        by scanning the key column until the rebuild is done.
+    */
+    pub fn ensure_primary_index(self: &Arc<Self>) -> Option<JoinHandle<()>> {
+        let key = self.primary_key_index;
+        let rows = self.live_rids().len();
 
-        true
-    }
+        {
+            let mut index = self.data.index.write();
+            let entries = index.entry_count(key);
 
-    pub fn delete_query(&self, key: u64, mut transaction: Option<&mut Transaction>) -> bool {
-        let row = self.find_row(self.primary_key_index, key);
+            if entries == Some(rows) {
+                return None;
+            }
 
-        if row.is_none() {
-            return false;
-        }
+            eprintln!(
+                "Table {}: primary key index has {} entries for {rows} rows, rebuilding",
+                self.name,
+                entries.unwrap_or(0)
+            );
 
-        let row = row.unwrap();
+            // Writers maintain the fresh index from here on, the rebuild fills in the rest
+            self.rebuilding_primary.store(true, Ordering::SeqCst);
+            index.create_index(key);
+        }
 
-        if let Some(t) = transaction.borrow_mut() {
-            if !t.try_lock_with_abort(&self.lock_manager, row, LockType::Exclusive) {
-                return false;
-            }
+        if rows <= BACKGROUND_INDEX_REBUILD_ROWS {
+            self.rebuild_primary_index();
+            return None;
         }
 
-        let mut next_tail: RID = self
-            .get_page(row)
-            .get_column(self.bufferpool.lock().borrow_mut(), METADATA_INDIRECTION)
-            .slot(row.slot())
-            .into();
+        let table = Arc::clone(self);
+        Some(thread::spawn(move || table.rebuild_primary_index()))
+    }
 
-        while next_tail.raw() != RID_INVALID && next_tail.raw() != row.raw() {
-            let next = self
-                .get_page(next_tail)
-                .get_column(self.bufferpool.lock().borrow_mut(), METADATA_INDIRECTION)
-                .slot(next_tail.slot());
+    fn rebuild_primary_index(&self) {
+        let key = self.primary_key_index;
 
-            if let Some(t) = transaction.borrow_mut() {
-                t.log_write(METADATA_INDIRECTION, next_tail, next);
+        for rid in self.live_rids() {
+            if self.closed.load(Ordering::SeqCst) {
+                break;
             }
 
-            self.get_page(next_tail)
-                .get_column(self.bufferpool.lock().borrow_mut(), METADATA_RID)
-                .write_slot(next_tail.slot(), RID_INVALID);
-
-            next_tail = next.into();
-        }
+            let latest = self.data.get_latest(rid);
+            let value = self
+                .data
+                .get_page(latest)
+                .get_column(&self.data.bufferpool, NUM_METADATA_COLUMNS + key)
+                .slot(latest.slot());
 
-        if let Some(t) = transaction.borrow_mut() {
-            t.log_write(METADATA_RID, row, row.raw());
+            /*
+                A concurrent update may already have indexed the row, or may change the
+                key right after we read it. The stale entry that leaves is harmless,
+                lookups re-check the value.
+            */
+            let mut index = self.data.index.write();
+            if !index.contains(key, value, rid) {
+                index.update_index(key, value, rid);
+            }
         }
 
-        self.get_page(row)
-            .get_column(self.bufferpool.lock().borrow_mut(), METADATA_RID)
-            .write_slot(row.slot(), RID_INVALID);
+        self.rebuilding_primary.store(false, Ordering::SeqCst);
+    }
 
-        true
+    pub fn drop_index(&self, column_num: usize) {
+        self.data.index.write().drop_index(column_num);
     }
 
-    pub fn build_index(&self, column_num: usize) {
-        let mut index = self.index.write();
-        index.create_index(column_num);
-        let mut rid: RID = 0.into();
-        let max_rid = self.next_rid.load(Ordering::Relaxed);
-        while rid.raw() < max_rid {
+    /*
+        Indexes the rows by the values of columns together, in that order, replacing
+        any index there was on the same columns. Table::select_multi_query looks rows
+        up through it when its filters cover the first of these columns, and the more
+        of the ones after that the fewer rows it reads. Rows with a NULL in any of the
+        columns are left out of it.
+    */
+    pub fn build_composite_index(&self, columns: &[usize]) {
+        let mut index = self.data.index.write();
+        index.create_composite_index(columns);
+
+        let max_rid = self.data.next_rid.load(Ordering::Relaxed);
+        for rid in (0..max_rid).map(RID::from) {
             if self
+                .data
                 .get_page(rid)
-                .get_column(self.bufferpool.lock().borrow_mut(), METADATA_RID)
+                .get_column(&self.data.bufferpool, METADATA_RID)
                 .slot(rid.slot())
                 == RID_INVALID
             {
                 continue;
             }
 
-            let latest = self.get_latest(rid);
-            index.update_index(
-                column_num,
-                self.get_page(latest)
-                    .get_column(
-                        self.bufferpool.lock().borrow_mut(),
-                        NUM_METADATA_COLUMNS + column_num,
-                    )
-                    .slot(latest.slot()),
-                rid,
-            );
-            rid = rid.next();
+            let latest = self.data.get_latest(rid);
+            let nulls = self.data.read_nulls(latest);
+            if columns.iter().any(|column| record::is_null(nulls, *column)) {
+                continue;
+            }
+
+            let page = self.data.get_page(latest);
+            let values = columns
+                .iter()
+                .map(|column| {
+                    page.get_column(&self.data.bufferpool, NUM_METADATA_COLUMNS + column)
+                        .slot(latest.slot())
+                })
+                .collect::<Vec<u64>>();
+
+            index.update_composite(columns, &values, rid);
         }
     }
 
-    pub fn drop_index(&self, column_num: usize) {
-        self.index.write().drop_index(column_num);
+    pub fn drop_composite_index(&self, columns: &[usize]) {
+        self.data.index.write().drop_composite_index(columns);
+    }
+
+    /*
+        The columns of each composite index, see Table::build_composite_index.
+    */
+    pub fn composite_indexes(&self) -> Vec<Vec<usize>> {
+        self.data.index.read().composite_indexes()
     }
 }
 
 impl fmt::Display for Table {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "[Table \"{}\"]", self.name)?;
-        writeln!(f, "{} Columns: ", self.num_columns)?;
+        writeln!(f, "{} Columns: ", self.data.num_columns)?;
         writeln!(f, "PK: {}", self.primary_key_index)?;
-        writeln!(f, "Current RID: {}", self.next_rid.load(Ordering::Relaxed))?;
-        writeln!(f, "Current TID: {}", self.next_rid.load(Ordering::Relaxed))?;
+        writeln!(
+            f,
+            "Current RID: {}",
+            self.data.next_rid.load(Ordering::Relaxed)
+        )?;
+        writeln!(
+            f,
+            "Current TID: {}",
+            self.data.next_rid.load(Ordering::Relaxed)
+        )?;
         writeln!(f)
     }
 }
@@ -0,0 +1,449 @@
+use crate::{
+    bufferpool::{BufferPool, PageGuard},
+    disk_manager::DiskManager,
+    format::PageLayout,
+    index::{Index, RecordChange},
+    page::{Page, PageRange},
+    page_directory::PageDirectory,
+    range_directory::RangeDirectory,
+    record::{self, Record},
+    rid::RID,
+    transaction::IndexMutation,
+    METADATA_BASE_RID, METADATA_INDIRECTION, METADATA_PAGE_HEADER, METADATA_RID,
+    NUM_METADATA_COLUMNS, PAGE_SLOTS, RID_INVALID,
+};
+use parking_lot::{Mutex, RwLock};
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc,
+};
+
+/*
+    What a table stores and how: its pages and the directories finding them, the
+    bufferpool and disk behind them, where the next base and tail RIDs come from,
+    and the indexes. Reads and writes records with no notion of queries, locks or
+    transactions, which Table layers on top. Rolling back a transaction only needs
+    the undo methods here.
+*/
+pub(crate) struct TableData {
+    pub(crate) num_columns: usize,
+    // Records carry a NULL mask after their columns, see Table::with_nullable
+    pub(crate) nullable: bool,
+    // Records carry when each version was written, last of all, see TableData::timestamp_column
+    pub(crate) timestamps: bool,
+    // Opened beside a writer, see CrabStore::refresh
+    pub(crate) read_only: bool,
+    pub(crate) index: Arc<RwLock<Index>>,
+    pub(crate) next_rid: AtomicU64,
+    pub(crate) next_tid: AtomicU64,
+    pub(crate) page_dir: Arc<PageDirectory>,
+    pub(crate) range_dir: Arc<Mutex<RangeDirectory>>,
+    pub(crate) bufferpool: Arc<BufferPool>,
+    pub(crate) disk: Arc<DiskManager>,
+    pub(crate) page_layout: PageLayout,
+    // Base pages per page range, recorded in the header
+    pub(crate) range_pages: usize,
+    // Base pages given disk pages together when the first of them is inserted into
+    pub(crate) base_page_batch: AtomicUsize,
+    // Columnar layout only: first page of the tail pages reserved together, and how many are used
+    pub(crate) tail_block: Mutex<Option<(usize, usize)>>,
+    // Inserts hold this shared while writing a base page, a merge exclusively while swapping its copies in
+    pub(crate) base_writes: Arc<RwLock<()>>,
+}
+
+impl TableData {
+    pub(crate) fn allocate_tail_page(&self) -> PageRange {
+        let next_tid: RID = self
+            .next_tid
+            .fetch_sub(PAGE_SLOTS as u64, Ordering::Relaxed)
+            .into();
+
+        // Columnar tail pages are reserved a page range's worth at a time, so each column is a run
+        let (reserved, page, pages) = match self.page_layout {
+            PageLayout::Interleaved => (self.disk.reserve_range(self.total_columns()), 0, 1),
+            PageLayout::Columnar => {
+                let mut block = self.tail_block.lock();
+                let (reserved, used) = match *block {
+                    Some((reserved, used)) if used < self.range_pages => (reserved, used),
+                    _ => (
+                        self.disk
+                            .reserve_range(self.total_columns() * self.range_pages),
+                        0,
+                    ),
+                };
+                *block = Some((reserved, used + 1));
+
+                (reserved, used, self.range_pages)
+            }
+        };
+
+        let column_pages = self.place_columns(reserved, pages, page);
+
+        self.init_metadata(&column_pages);
+
+        self.page_dir.new_page(next_tid.page(), column_pages);
+
+        PageRange::new(next_tid.raw(), next_tid.page())
+    }
+
+    /*
+        Column pages of the page-th of pages logical pages reserved together
+    */
+    fn place_columns(&self, reserved: usize, pages: usize, page: usize) -> Arc<[usize]> {
+        (0..self.total_columns())
+            .map(|column| {
+                self.page_layout
+                    .place(reserved, self.total_columns(), pages, page, column)
+            })
+            .collect()
+    }
+
+    /*
+        Fresh pages read back as zeros, which alias RID 0. Mark every slot
+        as unoccupied up front so scans never mistake an empty slot for a row.
+    */
+    fn init_metadata(&self, column_pages: &[usize]) {
+        let bp = &self.bufferpool;
+
+        for column in [
+            METADATA_INDIRECTION,
+            METADATA_RID,
+            METADATA_BASE_RID,
+            METADATA_PAGE_HEADER,
+        ] {
+            bp.pin_page(column_pages[column], false).fill(RID_INVALID);
+        }
+    }
+
+    /*
+        Rollback of a write an aborted transaction logged, so undoing it
+        doesn't need to know how records are laid out
+    */
+    pub(crate) fn undo_write(&self, column: usize, rid: RID, original_value: u64) {
+        self.get_page(rid)
+            .get_column(&self.bufferpool, column)
+            .write_slot(rid.slot(), original_value);
+    }
+
+    /*
+        Takes a rolled back insert out entirely: its index entries go, and the
+        slot loses its base RID along with its RID, so merges take it for a
+        never written slot rather than a deleted row to compact
+    */
+    pub(crate) fn undo_insert(&self, rid: RID) {
+        let inserted = self
+            .read_versions(&[rid], &vec![1; self.num_columns], false)
+            .pop()
+            .expect("inserted row to roll back");
+
+        self.index.write().apply(&RecordChange::Delete {
+            rid,
+            values: &inserted.columns,
+            nulls: inserted.nulls,
+        });
+
+        self.undo_write(METADATA_RID, rid, RID_INVALID);
+        self.undo_write(METADATA_BASE_RID, rid, RID_INVALID);
+    }
+
+    /*
+        Brings back a row whose delete was rolled back, with the tail records it
+        had, and indexes it again under the values of its latest version
+    */
+    pub(crate) fn undo_delete(&self, base: RID, chain: &[RID]) {
+        for tail in chain {
+            self.undo_write(METADATA_RID, *tail, tail.raw());
+        }
+        self.undo_write(METADATA_RID, base, base.raw());
+
+        let latest = self.get_latest(base);
+        let restored = self
+            .read_versions(&[latest], &vec![1; self.num_columns], false)
+            .pop()
+            .expect("latest version of a restored row");
+
+        self.index.write().apply(&RecordChange::Insert {
+            rid: base,
+            values: &restored.columns,
+            nulls: restored.nulls,
+        });
+    }
+
+    pub(crate) fn undo_index_write(&self, mutation: IndexMutation) {
+        let mut index = self.index.write();
+
+        match mutation {
+            IndexMutation::Add { rid, value, column } => index.remove_index(column, value, rid),
+            IndexMutation::Remove {
+                rid,
+                old_value,
+                column,
+            } => index.update_index(column, old_value, rid),
+            IndexMutation::AddComposite {
+                rid,
+                columns,
+                values,
+            } => index.remove_composite(&columns, &values, rid),
+            IndexMutation::RemoveComposite {
+                rid,
+                columns,
+                old_values,
+            } => index.update_composite(&columns, &old_values, rid),
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn get_page(&self, rid: RID) -> Page {
+        Page::new(self.page_dir.get(rid).expect("Page get fail"))
+    }
+
+    #[inline(always)]
+    pub(crate) fn get_page_by_id(&self, id: usize) -> Page {
+        Page::new(self.page_dir.get_page(id).expect("Page get fail"))
+    }
+
+    /*
+        Whether an index entry still points at a row: published, not deleted, and
+        for a read-only table, not past what the last checkpoint covered
+    */
+    pub(crate) fn is_live(&self, rid: RID) -> bool {
+        rid.raw() < self.next_rid.load(Ordering::Relaxed)
+            && self
+                .get_page(rid)
+                .get_column(&self.bufferpool, METADATA_RID)
+                .slot(rid.slot())
+                != RID_INVALID
+    }
+
+    pub(crate) fn is_latest(&self, rid: RID) -> bool {
+        let bp = &self.bufferpool;
+        self.get_page(rid).read_page_tps(bp)
+            <= self
+                .get_page(rid)
+                .get_column(bp, METADATA_INDIRECTION)
+                .slot(rid.slot())
+    }
+
+    pub(crate) fn get_latest(&self, rid: RID) -> RID {
+        let page = self.get_page(rid);
+
+        let bp = &self.bufferpool;
+
+        let indir = page.get_column(bp, METADATA_INDIRECTION).slot(rid.slot());
+
+        if indir == RID_INVALID || page.read_page_tps(bp) <= indir {
+            return rid;
+        }
+
+        if self.tail_visible(indir.into()) {
+            indir.into()
+        } else {
+            rid
+        }
+    }
+
+    /*
+        A reader's base pages can point at tail records the writer added after the
+        checkpoint, whose page it may not know or which may only be partly on disk.
+        Until the next refresh such rows read as their last merged version.
+    */
+    pub(crate) fn tail_visible(&self, tail: RID) -> bool {
+        !self.read_only
+            || self.page_dir.get(tail).is_some_and(|cols| {
+                Page::new(cols)
+                    .get_column(&self.bufferpool, METADATA_RID)
+                    .slot(tail.slot())
+                    == tail.raw()
+            })
+    }
+
+    /*
+        Page column holding the NULL masks of a nullable table, after its own columns
+    */
+    pub(crate) fn nulls_column(&self) -> usize {
+        NUM_METADATA_COLUMNS + self.num_columns
+    }
+
+    /*
+        Page column holding when each version was written, after the NULL masks
+        if the table has them. Only tables created since version timestamps
+        existed have it.
+    */
+    pub(crate) fn timestamp_column(&self) -> usize {
+        NUM_METADATA_COLUMNS + self.num_columns + self.nullable as usize
+    }
+
+    /*
+        NULL mask of a base or tail record, bit i set if its column i is NULL
+    */
+    pub(crate) fn read_nulls(&self, rid: RID) -> u64 {
+        if !self.nullable {
+            return 0;
+        }
+
+        self.get_page(rid)
+            .get_column(&self.bufferpool, self.nulls_column())
+            .slot(rid.slot())
+    }
+
+    /*
+        Columns of a page, the metadata columns stored alongside the table's included
+    */
+    pub(crate) fn total_columns(&self) -> usize {
+        NUM_METADATA_COLUMNS + self.num_columns + self.nullable as usize + self.timestamps as usize
+    }
+
+    /*
+        Groups positions in rids by the logical page they live on,
+        so each page directory entry and column frame is fetched once per page
+    */
+    fn page_groups(rids: &[RID]) -> Vec<Vec<usize>> {
+        let mut order = (0..rids.len()).collect::<Vec<usize>>();
+        order.sort_by_key(|i| rids[*i].page());
+
+        let mut groups: Vec<Vec<usize>> = Vec::new();
+        for i in order {
+            match groups.last_mut() {
+                Some(group) if rids[group[0]].page() == rids[i].page() => group.push(i),
+                _ => groups.push(vec![i]),
+            }
+        }
+
+        groups
+    }
+
+    /*
+        Column frame of a page, through the scan hint when scan is set
+    */
+    pub(crate) fn fetch_column(
+        bp: &BufferPool,
+        page: &Page,
+        column: usize,
+        scan: bool,
+    ) -> PageGuard {
+        if scan {
+            page.get_column_for_scan(bp, column)
+        } else {
+            page.get_column(bp, column)
+        }
+    }
+
+    pub(crate) fn get_latest_batch(&self, rids: &[RID], scan: bool) -> Vec<RID> {
+        let mut latest = rids.to_vec();
+
+        for group in TableData::page_groups(rids) {
+            let page = self.get_page(rids[group[0]]);
+            let bp = &self.bufferpool;
+            let indirection = TableData::fetch_column(bp, &page, METADATA_INDIRECTION, scan);
+            let tps = TableData::fetch_column(bp, &page, METADATA_PAGE_HEADER, scan).slot(0);
+
+            for i in group {
+                let indir = indirection.slot(rids[i].slot());
+                if indir != RID_INVALID && tps > indir && self.tail_visible(indir.into()) {
+                    latest[i] = indir.into();
+                }
+            }
+        }
+
+        latest
+    }
+
+    /*
+        Reads the projected columns of the latest version of each base record.
+    */
+    pub(crate) fn materialize_latest(
+        &self,
+        rids: &[RID],
+        included_columns: &[usize],
+    ) -> Vec<Record> {
+        self.read_versions(&self.get_latest_batch(rids, false), included_columns, false)
+    }
+
+    /*
+        Reads the projected columns of exactly the given base or tail records.
+        Only the frames of projected columns are fetched, once per logical page.
+    */
+    pub(crate) fn read_versions(
+        &self,
+        latest: &[RID],
+        included_columns: &[usize],
+        scan: bool,
+    ) -> Vec<Record> {
+        let projected = included_columns
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| **x != 0)
+            .map(|(i, _)| NUM_METADATA_COLUMNS + i)
+            .collect::<Vec<usize>>();
+
+        let mut records = latest
+            .iter()
+            .map(|rid| Record::new(rid.raw(), Vec::with_capacity(projected.len())))
+            .collect::<Vec<Record>>();
+
+        for group in TableData::page_groups(latest) {
+            let page = self.get_page(latest[group[0]]);
+            let bp = &self.bufferpool;
+            let frames = projected
+                .iter()
+                .map(|column| TableData::fetch_column(bp, &page, *column, scan))
+                .collect::<Vec<PageGuard>>();
+            let nulls = self
+                .nullable
+                .then(|| TableData::fetch_column(bp, &page, self.nulls_column(), scan));
+            let timestamps = self
+                .timestamps
+                .then(|| TableData::fetch_column(bp, &page, self.timestamp_column(), scan));
+
+            for i in group {
+                records[i].columns = frames
+                    .iter()
+                    .map(|frame| frame.slot(latest[i].slot()))
+                    .collect();
+
+                // The record's mask has a bit per column, the result one per projected column
+                if let Some(nulls) = &nulls {
+                    let mask = nulls.slot(latest[i].slot());
+                    records[i].nulls = projected
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, column)| {
+                            record::is_null(mask, **column - NUM_METADATA_COLUMNS)
+                        })
+                        .fold(0, |nulls, (position, _)| nulls | 1 << position);
+                }
+
+                if let Some(timestamps) = &timestamps {
+                    records[i].timestamp = timestamps.slot(latest[i].slot());
+                }
+            }
+        }
+
+        records
+    }
+
+    /*
+        The base page a newly handed out RID goes in, allocating its batch of pages
+        if it's the first RID to land there
+    */
+    pub(crate) fn base_page(&self, rid: RID) -> Page {
+        Page::new(self.page_dir.get_or_allocate(rid, || {
+            // Batches are aligned, so the batch holding this page has none allocated yet
+            let batch = self.base_page_batch.load(Ordering::Relaxed);
+            let first_page = rid.page() - rid.page() % batch;
+            let reserved = self.disk.reserve_range(self.total_columns() * batch);
+
+            (0..batch)
+                .map(|i| {
+                    let page_id = first_page + i;
+                    let column_pages = self.place_columns(reserved, batch, i);
+
+                    // Also sets the page's TPS to RID_INVALID
+                    self.init_metadata(&column_pages);
+
+                    (page_id, column_pages)
+                })
+                .collect()
+        }))
+    }
+}
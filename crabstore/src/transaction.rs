@@ -1,17 +1,32 @@
 use std::{
-    borrow::{Borrow, BorrowMut},
+    borrow::Borrow,
     cell::RefCell,
-    sync::Arc,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-use rustc_hash::FxHashSet;
+use rustc_hash::FxHashMap;
 
 use crate::{
-    lock_manager::{LockHandle, LockManager, LockType},
+    error::CrabError,
+    lock_manager::{ConcurrencyPolicy, LockHandle, LockManager, LockType},
+    record::Record,
     rid::RID,
-    table::Table,
+    table::{Table, UpdateOp, UpsertResult},
+    AFFINITY_KEY_RANGE, WAIT_DIE_TIMEOUT_MS,
 };
 
+// Lower is older, WaitDie lets older transactions wait on younger ones
+static NEXT_TIMESTAMP: AtomicU64 = AtomicU64::new(0);
+
+// Times a query that lost a lock is run again on its own before its whole transaction aborts
+const QUERY_RETRIES: usize = 3;
+
 #[derive(Clone, Debug)]
 struct RecordMutation {
     pub modified_entry: RID,
@@ -32,11 +47,35 @@ pub enum IndexMutation {
         old_value: u64,
         column: usize,
     },
+    // Values of a composite index's columns, in its order
+    AddComposite {
+        rid: RID,
+        columns: Vec<usize>,
+        values: Vec<u64>,
+    },
+    RemoveComposite {
+        rid: RID,
+        columns: Vec<usize>,
+        old_values: Vec<u64>,
+    },
 }
 
 enum Mutation {
     Index(IndexMutation),
     Record(RecordMutation),
+    // A base record written by an insert, undone by taking it out entirely
+    InsertedRecord(RID),
+    // A deleted row with the tail records of its chain, all of which get their RIDs back
+    DeletedRecord { base: RID, chain: Vec<RID> },
+}
+
+/*
+    Entry of an OwnWrites map as it was before a write replaced it
+*/
+struct OwnWriteUndo {
+    table: String,
+    rid: RID,
+    previous: Option<Option<Box<[u64]>>>,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Copy)]
@@ -47,30 +86,127 @@ pub enum QueryStatus {
     AbortedNotRetryable,
 }
 
+/*
+    What a transaction does when one of its queries would break a key constraint
+*/
+#[derive(Clone, Debug, PartialEq, Eq, Copy, Default)]
+pub enum ConstraintPolicy {
+    #[default]
+    Abort,
+    Skip,
+}
+
 #[derive(Clone)]
 pub enum Query {
     Select(u64, usize, Box<[usize]>),
     Sum(u64, u64, usize),
     Insert(Box<[u64]>),
     Update(u64, Box<[Option<u64>]>),
+    Upsert(Box<[u64]>),
+    UpdateRange(u64, u64, Box<[Option<UpdateOp>]>),
     Delete(u64),
 }
 
+/*
+    What one query of a committed transaction returned. Sum also carries how many
+    rows an UpdateRange changed, and Bool whether an Update or Delete found its
+    row, an Upsert inserted a new one, or an Insert wasn't skipped.
+*/
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum QueryResult {
+    Records(Vec<Record>),
+    Sum(u64),
+    Bool(bool),
+}
+
+/*
+    Why a query aborted. Only lock conflicts and keys another insert is still
+    claiming can go away by running the transaction again
+*/
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AbortKind {
+    // Another transaction holds a lock on the record
+    LockConflict(RID),
+    // Another transaction's write of the key hasn't committed or rolled back yet
+    KeyInUse(u64),
+    // A write would have given two rows the same key, see ConstraintPolicy
+    DuplicateKey,
+    QueryFailed(CrabError),
+}
+
+impl AbortKind {
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, AbortKind::LockConflict(_) | AbortKind::KeyInUse(_))
+    }
+}
+
+impl fmt::Display for AbortKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AbortKind::LockConflict(rid) => write!(f, "record {} is locked", rid.raw()),
+            AbortKind::KeyInUse(key) => write!(f, "key {key} is being written"),
+            AbortKind::DuplicateKey => write!(f, "duplicate key"),
+            AbortKind::QueryFailed(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+/*
+    The query a transaction aborted on, by its position among the queries
+    added, and why
+*/
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TransactionError {
+    pub query_index: usize,
+    pub table_name: String,
+    pub kind: AbortKind,
+}
+
+impl TransactionError {
+    pub fn is_retryable(&self) -> bool {
+        self.kind.is_retryable()
+    }
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Query {} on table {} aborted: {}",
+            self.query_index, self.table_name, self.kind
+        )
+    }
+}
+
+impl std::error::Error for TransactionError {}
+
 #[derive(Clone)]
 struct ExecutedQuery {
     pub num_locks: usize,
     pub num_muts: usize,
+    pub num_own_writes: usize,
 }
 
 impl ExecutedQuery {
-    fn new(num_locks: usize, num_muts: usize) -> Self {
+    fn new(num_locks: usize, num_muts: usize, num_own_writes: usize) -> Self {
         ExecutedQuery {
             num_locks,
             num_muts,
+            num_own_writes,
         }
     }
 }
 
+/*
+    Point of a running transaction that Transaction::rollback_to goes back to.
+    Only valid until the transaction commits or aborts, which starts a new epoch
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SavepointId {
+    epoch: u64,
+    queries: usize,
+}
+
 pub struct Transaction {
     query_log: Vec<ExecutedQuery>,
     queries: Vec<(Query, Arc<Table>)>,
@@ -78,11 +214,37 @@ pub struct Transaction {
     locks_acquired: Vec<LockHandle>,
     current_writes: usize,
     current_locks: usize,
+    current_own_writes: usize,
     current_status: QueryStatus,
+    on_constraint_violation: ConstraintPolicy,
+    skipped: Vec<usize>,
+    results: Vec<QueryResult>,
+    touched: Vec<(String, Option<u64>)>,
+    // Kept across retries so a transaction that keeps dying eventually becomes the oldest
+    timestamp: u64,
+    concurrency_policy: Option<ConcurrencyPolicy>,
+    own_writes: FxHashMap<String, OwnWrites>,
+    own_write_log: Vec<OwnWriteUndo>,
+    // Bumped on every commit and abort, so older savepoints can't be used
+    epoch: u64,
+    retries: usize,
+    // Why the running query aborted, until run_next_query turns it into last_error
+    abort_reason: Option<AbortKind>,
+    last_error: Option<TransactionError>,
 }
 
+/*
+    Latest values of each base RID a transaction wrote in one table, None once
+    it deleted the row
+*/
+pub(crate) type OwnWrites = FxHashMap<RID, Option<Box<[u64]>>>;
+
 impl Transaction {
     pub fn new() -> Self {
+        Transaction::with_constraint_policy(ConstraintPolicy::default())
+    }
+
+    pub fn with_constraint_policy(on_constraint_violation: ConstraintPolicy) -> Self {
         Transaction {
             query_log: Vec::new(),
             queries: Vec::new(),
@@ -90,81 +252,363 @@ impl Transaction {
             locks_acquired: Vec::new(),
             current_writes: 0,
             current_locks: 0,
+            current_own_writes: 0,
             current_status: QueryStatus::Idle,
+            on_constraint_violation,
+            skipped: Vec::new(),
+            results: Vec::new(),
+            touched: Vec::new(),
+            timestamp: NEXT_TIMESTAMP.fetch_add(1, Ordering::Relaxed),
+            concurrency_policy: None,
+            own_writes: FxHashMap::default(),
+            own_write_log: Vec::new(),
+            epoch: 0,
+            retries: 0,
+            abort_reason: None,
+            last_error: None,
         }
     }
 
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /*
+        Times run was called again after aborting retryably
+    */
+    pub fn retries(&self) -> usize {
+        self.retries
+    }
+
+    /*
+        Overrides the policy of every table this transaction locks records in
+    */
+    pub fn set_concurrency_policy(&mut self, policy: ConcurrencyPolicy) {
+        self.concurrency_policy = Some(policy);
+    }
+
     pub fn add_query(&mut self, query: Query, table: &Arc<Table>) {
+        let key = match &query {
+            Query::Select(key, column, _) if *column == table.primary_key() => Some(*key),
+            Query::Select(..) | Query::Sum(..) | Query::UpdateRange(..) => None,
+            Query::Insert(values) | Query::Upsert(values) => Some(values[table.primary_key()]),
+            Query::Update(key, _) | Query::Delete(key) => Some(*key),
+        };
+
+        let tag = (
+            table.name().to_string(),
+            key.map(|x| x / AFFINITY_KEY_RANGE),
+        );
+        if !self.touched.contains(&tag) {
+            self.touched.push(tag);
+        }
+
         self.queries.push((query, table.clone()));
     }
 
-    pub fn run(&mut self) -> bool {
+    /*
+        Tables touched by this transaction, with the primary key range where known
+    */
+    pub fn touched(&self) -> &[(String, Option<u64>)] {
+        &self.touched
+    }
+
+    pub fn query_count(&self) -> usize {
+        self.queries.len()
+    }
+
+    /*
+        Runs every query added and not yet run, then commits, returning what each
+        query of the transaction returned, see Transaction::results. On the first
+        query that aborts, everything is rolled back and the error says which query it
+        was and why.
+    */
+    pub fn run(&mut self) -> Result<Vec<QueryResult>, TransactionError> {
+        if self.current_status == QueryStatus::AbortedRetryable {
+            self.retries += 1;
+        }
+
         self.write_log.reserve(self.queries.len());
         self.locks_acquired.reserve(self.queries.len() * 2);
         self.current_status = QueryStatus::Executing;
 
-        for query in self.queries.clone().iter() {
-            self.current_locks = 0;
-            self.current_writes = 0;
+        // Queries already run with Transaction::execute keep what they did
+        self.skipped
+            .retain(|position| *position < self.query_log.len());
+        self.results.truncate(self.query_log.len());
 
-            match &query.0 {
-                Query::Select(search_val, col_idx, selected) => {
-                    query
-                        .1
-                        .select_query(*search_val, *col_idx, selected, Some(self));
+        while self.query_log.len() < self.queries.len() {
+            if !self.run_next_query() {
+                self.rollback();
+                return Err(self.last_error.clone().expect("aborted query's error"));
+            }
+        }
 
-                    self.query_log
-                        .push(ExecutedQuery::new(self.current_locks, self.current_writes));
-                }
-                Query::Sum(start, end, val) => {
-                    query.1.sum_query(*start, *end, *val, Some(self));
+        self.release_committed();
+        Ok(self.results.clone())
+    }
 
-                    self.query_log
-                        .push(ExecutedQuery::new(self.current_locks, self.current_writes));
-                }
-                Query::Insert(vals) => {
-                    query.1.insert_query(vals, Some(self));
+    /*
+        Runs query right away as part of this transaction, along with any added before
+        it that haven't run, and leaves the transaction open. Transaction::run commits
+        it. Returns false if a query aborted. Only that query is undone and dropped,
+        with any added after it, so the transaction can carry on, roll back to a
+        savepoint, or try the query again when Transaction::get_status says it is
+        retryable.
+    */
+    pub fn execute(&mut self, query: Query, table: &Arc<Table>) -> bool {
+        self.add_query(query, table);
+        self.current_status = QueryStatus::Executing;
+        self.results.truncate(self.query_log.len());
 
-                    self.query_log
-                        .push(ExecutedQuery::new(self.current_locks, self.current_writes));
-                }
-                Query::Update(key, vals) => {
-                    query.1.update_query(*key, vals, Some(self));
+        while self.query_log.len() < self.queries.len() {
+            let position = self.query_log.len();
 
-                    self.query_log
-                        .push(ExecutedQuery::new(self.current_locks, self.current_writes));
-                }
-                Query::Delete(key) => {
-                    query.1.delete_query(*key, Some(self));
+            if !self.run_next_query() {
+                self.undo_queries(position);
+                self.queries.truncate(position);
+                return false;
+            }
+        }
 
-                    self.query_log
-                        .push(ExecutedQuery::new(self.current_locks, self.current_writes));
-                }
+        true
+    }
+
+    /*
+        A transaction to run queries in one at a time, each deciding on the results of
+        those before it, with Transaction::select, Transaction::update and the like,
+        then Transaction::commit or Transaction::abort. The same as Transaction::new.
+    */
+    pub fn begin() -> Self {
+        Transaction::new()
+    }
+
+    /*
+        Runs any queries added and not yet run, then commits everything the
+        transaction did. The same as Transaction::run, so an error if it aborted and
+        was rolled back.
+    */
+    pub fn commit(&mut self) -> Result<Vec<QueryResult>, TransactionError> {
+        self.run()
+    }
+
+    /*
+        Undoes everything the transaction did and drops any queries waiting to run,
+        releasing all of its locks.
+    */
+    pub fn abort(&mut self) {
+        self.rollback();
+        self.queries.clear();
+        self.current_status = QueryStatus::AbortedNotRetryable;
+    }
+
+    /*
+        Like Table::select_query, run right away with Transaction::execute. Fails with
+        CrabError::TransactionAborted if the query aborted, which undoes only it, and
+        Transaction::last_error says why.
+    */
+    pub fn select(
+        &mut self,
+        table: &Arc<Table>,
+        search_key: u64,
+        search_key_index: usize,
+        projected_columns_index: &[usize],
+    ) -> Result<Vec<Record>, CrabError> {
+        let query = Query::Select(search_key, search_key_index, projected_columns_index.into());
+        let QueryResult::Records(records) = self.execute_returning(query, table)? else {
+            unreachable!("select returns records");
+        };
+        Ok(records)
+    }
+
+    /*
+        Like Table::sum_query, run right away, see Transaction::select.
+    */
+    pub fn sum(
+        &mut self,
+        table: &Arc<Table>,
+        start_range: u64,
+        end_range: u64,
+        aggregate_column_index: usize,
+    ) -> Result<u64, CrabError> {
+        let query = Query::Sum(start_range, end_range, aggregate_column_index);
+        let QueryResult::Sum(sum) = self.execute_returning(query, table)? else {
+            unreachable!("sum returns a sum");
+        };
+        Ok(sum)
+    }
+
+    /*
+        Like Table::insert_query, run right away, see Transaction::select. False if
+        the key was taken and the transaction skips such inserts.
+    */
+    pub fn insert(&mut self, table: &Arc<Table>, values: &[u64]) -> Result<bool, CrabError> {
+        self.execute_bool(Query::Insert(values.into()), table)
+    }
+
+    /*
+        Like Table::update_query, run right away, see Transaction::select. False if no
+        row has the key.
+    */
+    pub fn update(
+        &mut self,
+        table: &Arc<Table>,
+        key: u64,
+        values: &[Option<u64>],
+    ) -> Result<bool, CrabError> {
+        self.execute_bool(Query::Update(key, values.into()), table)
+    }
+
+    /*
+        Like Table::delete_query, run right away, see Transaction::select. False if no
+        row has the key.
+    */
+    pub fn delete(&mut self, table: &Arc<Table>, key: u64) -> Result<bool, CrabError> {
+        self.execute_bool(Query::Delete(key), table)
+    }
+
+    fn execute_bool(&mut self, query: Query, table: &Arc<Table>) -> Result<bool, CrabError> {
+        let QueryResult::Bool(found) = self.execute_returning(query, table)? else {
+            unreachable!("writes of one row return whether they found it");
+        };
+        Ok(found)
+    }
+
+    /*
+        execute, handing back what the query returned
+    */
+    fn execute_returning(
+        &mut self,
+        query: Query,
+        table: &Arc<Table>,
+    ) -> Result<QueryResult, CrabError> {
+        if !self.execute(query, table) {
+            return Err(CrabError::TransactionAborted {
+                retryable: self.current_status == QueryStatus::AbortedRetryable,
+            });
+        }
+
+        Ok(self
+            .results
+            .last()
+            .cloned()
+            .expect("result of an executed query"))
+    }
+
+    /*
+        Marks where the transaction is now, for Transaction::rollback_to.
+    */
+    pub fn savepoint(&self) -> SavepointId {
+        SavepointId {
+            epoch: self.epoch,
+            queries: self.query_log.len(),
+        }
+    }
+
+    /*
+        Undoes every query run since savepoint and drops any waiting to run, releasing
+        the locks they took. Locks taken before it are kept, including ones later
+        upgraded to exclusive. Panics if the savepoint was rolled back past, or is
+        from before the transaction last committed or aborted.
+    */
+    pub fn rollback_to(&mut self, savepoint: SavepointId) {
+        assert!(
+            savepoint.epoch == self.epoch && savepoint.queries <= self.query_log.len(),
+            "savepoint no longer exists"
+        );
+
+        self.undo_queries(savepoint.queries);
+        self.queries.truncate(savepoint.queries);
+        self.current_status = QueryStatus::Executing;
+    }
+
+    /*
+        Runs the first query that hasn't run yet. A query that lost a lock is
+        undone and run again on its own a few times, keeping the locks taken by
+        the queries before it, and only then fails. False if it aborted, with
+        whatever it did still logged
+    */
+    fn run_next_query(&mut self) -> bool {
+        let position = self.query_log.len();
+
+        for attempt in 0..=QUERY_RETRIES {
+            if attempt > 0 {
+                self.undo_queries(position);
+                self.current_status = QueryStatus::Executing;
+                thread::yield_now();
             }
 
-            // println!(
-            //     "Current writes: {} Current locks: {} ({} acquired) , Thread Id: {:?}",
-            //     self.current_writes,
-            //     self.current_locks,
-            //     self.locks_acquired.len(),
-            //     std::thread::current().id()
-            // );
+            self.run_query(position);
 
             match self.current_status {
-                QueryStatus::AbortedNotRetryable | QueryStatus::AbortedRetryable => {
-                    self.rollback();
-                    return false;
-                }
-                _ => {}
+                QueryStatus::AbortedRetryable => {}
+                QueryStatus::AbortedNotRetryable => break,
+                _ => return true,
             }
         }
 
-        self.commit();
-        true
+        self.last_error = Some(TransactionError {
+            query_index: position,
+            table_name: self.queries[position].1.name().to_string(),
+            kind: self.abort_reason.take().expect("aborted query's reason"),
+        });
+        false
+    }
+
+    fn run_query(&mut self, position: usize) {
+        let query = self.queries[position].clone();
+
+        self.current_locks = 0;
+        self.current_writes = 0;
+        self.current_own_writes = 0;
+
+        let result = match &query.0 {
+            Query::Select(search_val, col_idx, selected) => query
+                .1
+                .select_query(*search_val, *col_idx, selected, Some(self))
+                .map(QueryResult::Records),
+            Query::Sum(start, end, val) => query
+                .1
+                .sum_query(*start, *end, *val, Some(self))
+                .map(QueryResult::Sum),
+            Query::Insert(vals) => query
+                .1
+                .insert_query(vals, Some(self))
+                .map(|_| QueryResult::Bool(self.skipped.last() != Some(&position))),
+            Query::Update(key, vals) => query
+                .1
+                .update_query(*key, vals, Some(self))
+                .map(QueryResult::Bool),
+            Query::Upsert(vals) => query
+                .1
+                .upsert_query(vals, Some(self))
+                .map(|upserted| QueryResult::Bool(upserted == Some(UpsertResult::Inserted))),
+            Query::UpdateRange(start, end, updates) => query
+                .1
+                .update_range(*start, *end, updates, Some(self))
+                .map(|updated| QueryResult::Sum(updated as u64)),
+            Query::Delete(key) => query
+                .1
+                .delete_query(*key, Some(self))
+                .map(QueryResult::Bool),
+        };
+
+        match result {
+            Ok(result) => self.results.push(result),
+            Err(err) => self.set_aborted(AbortKind::QueryFailed(err)),
+        }
+
+        self.query_log.push(ExecutedQuery::new(
+            self.current_locks,
+            self.current_writes,
+            self.current_own_writes,
+        ));
     }
 
-    fn commit(&mut self) {
+    fn release_committed(&mut self) {
         self.write_log.clear();
+        self.own_writes.clear();
+        self.own_write_log.clear();
 
         for idx in (0..self.query_log.len()).rev() {
             let table = Arc::clone(&self.queries[idx].1);
@@ -176,7 +620,10 @@ impl Transaction {
             }
         }
 
+        // Nothing is left to run, and queries executed from here on start afresh
+        self.queries.clear();
         self.current_status = QueryStatus::Idle;
+        self.epoch += 1;
 
         assert!(self.query_log.is_empty());
         assert!(self.write_log.is_empty());
@@ -184,62 +631,127 @@ impl Transaction {
     }
 
     fn rollback(&mut self) {
-        for idx in (0..(self.query_log.len())).rev() {
-            let table = Arc::clone(&self.queries[idx].1);
-            let entry = self.query_log.remove(idx);
+        self.undo_queries(0);
+        self.epoch += 1;
 
+        assert!(self.query_log.is_empty());
+        assert!(self.write_log.is_empty());
+        assert!(self.locks_acquired.is_empty());
+        assert!(self.own_write_log.is_empty());
+    }
+
+    /*
+        Undoes the queries logged after the first keep, newest first, releasing
+        the locks they took
+    */
+    fn undo_queries(&mut self, keep: usize) {
+        while self.query_log.len() > keep {
+            let table = Arc::clone(&self.queries[self.query_log.len() - 1].1);
+            let entry = self.query_log.pop().unwrap();
+
+            // Undone in the table's storage, under the locks the query still holds
+            let data = &table.data;
             for _ in 0..entry.num_muts {
-                let write_entry = self.write_log.remove(self.write_log.len() - 1);
+                let write_entry = self.write_log.pop().unwrap();
 
                 match write_entry {
-                    Mutation::Index(index_entry) => match index_entry {
-                        IndexMutation::Add { rid, value, column } => {
-                            table.index.write().remove_index(column, value, rid)
-                        }
-                        IndexMutation::Remove {
-                            rid,
-                            old_value,
-                            column,
-                        } => table.index.write().update_index(column, old_value, rid),
-                    },
-                    Mutation::Record(write_entry) => {
-                        table
-                            .get_page(write_entry.modified_entry)
-                            .get_column(
-                                table.get_bufferpool().lock().borrow_mut(),
-                                write_entry.modified_column,
-                            )
-                            .write_slot(
-                                write_entry.modified_entry.slot(),
-                                write_entry.original_value,
-                            );
-                    }
+                    Mutation::Index(index_entry) => data.undo_index_write(index_entry),
+                    Mutation::Record(write_entry) => data.undo_write(
+                        write_entry.modified_column,
+                        write_entry.modified_entry,
+                        write_entry.original_value,
+                    ),
+                    Mutation::InsertedRecord(rid) => data.undo_insert(rid),
+                    Mutation::DeletedRecord { base, chain } => data.undo_delete(base, &chain),
                 }
             }
 
+            for _ in 0..entry.num_own_writes {
+                let undo = self.own_write_log.pop().unwrap();
+                let own_writes = self.own_writes.get_mut(&undo.table).unwrap();
+
+                match undo.previous {
+                    Some(values) => own_writes.insert(undo.rid, values),
+                    None => own_writes.remove(&undo.rid),
+                };
+            }
+
             for _ in 0..entry.num_locks {
-                let lock = self.locks_acquired.remove(self.locks_acquired.len() - 1);
+                let lock = self.locks_acquired.pop().unwrap();
                 table.get_lock_manager().unlock(&lock);
             }
         }
 
-        assert!(self.query_log.is_empty());
-        assert!(self.write_log.is_empty());
-        assert!(self.locks_acquired.is_empty());
+        self.results.truncate(keep);
+        self.skipped.retain(|position| *position < keep);
     }
 
-    pub fn set_aborted(&mut self, retry: bool) {
-        if retry {
-            self.current_status = QueryStatus::AbortedRetryable;
+    pub fn set_aborted(&mut self, reason: AbortKind) {
+        self.current_status = if reason.is_retryable() {
+            QueryStatus::AbortedRetryable
         } else {
-            self.current_status = QueryStatus::AbortedNotRetryable;
-        }
+            QueryStatus::AbortedNotRetryable
+        };
+        self.abort_reason = Some(reason);
     }
 
     pub fn get_status(&self) -> QueryStatus {
         self.current_status
     }
 
+    /*
+        Which query last aborted and why, kept until another one does.
+    */
+    pub fn last_error(&self) -> Option<&TransactionError> {
+        self.last_error.as_ref()
+    }
+
+    /*
+        Positions of the queries that were skipped under ConstraintPolicy::Skip
+    */
+    pub fn skipped_queries(&self) -> &[usize] {
+        &self.skipped
+    }
+
+    /*
+        What each query returned, in the order they were added, once Transaction::run
+        has committed. Empty while the transaction hasn't run or after it aborted.
+    */
+    pub fn results(&self) -> &[QueryResult] {
+        &self.results
+    }
+
+    pub fn constraint_violated(&mut self) {
+        match self.on_constraint_violation {
+            ConstraintPolicy::Abort => self.set_aborted(AbortKind::DuplicateKey),
+            // The running query is logged once it returns, so its position is the log length
+            ConstraintPolicy::Skip => self.skipped.push(self.query_log.len()),
+        }
+    }
+
+    /*
+        Reads made with this transaction see what it wrote through these, rather
+        than relying on its writes having already reached the pages
+    */
+    pub(crate) fn record_own_write(&mut self, table: &str, rid: RID, values: Option<Box<[u64]>>) {
+        let previous = self
+            .own_writes
+            .entry(table.to_string())
+            .or_default()
+            .insert(rid, values);
+
+        self.current_own_writes += 1;
+        self.own_write_log.push(OwnWriteUndo {
+            table: table.to_string(),
+            rid,
+            previous,
+        });
+    }
+
+    pub(crate) fn own_writes(&self, table: &str) -> Option<&OwnWrites> {
+        self.own_writes.get(table)
+    }
+
     pub fn log_index_write(&mut self, mutation: IndexMutation) {
         self.current_writes += 1;
         self.write_log.push(Mutation::Index(mutation));
@@ -254,52 +766,94 @@ impl Transaction {
         }));
     }
 
-    fn try_lock(&mut self, locks: &LockManager, rid: RID, lock_type: LockType) -> bool {
-        let lock = self.locks_acquired.iter_mut().find(|x| x.rid == rid);
+    pub(crate) fn log_insert(&mut self, rid: RID) {
+        self.current_writes += 1;
+        self.write_log.push(Mutation::InsertedRecord(rid));
+    }
 
-        if let Some(l) = lock {
-            if lock_type == LockType::Exclusive && l.lock_type == LockType::Shared {
-                return locks.upgrade_shared(l);
-            } else {
-                return true;
-            }
+    pub(crate) fn log_delete(&mut self, base: RID, chain: Vec<RID>) {
+        self.current_writes += 1;
+        self.write_log.push(Mutation::DeletedRecord { base, chain });
+    }
+
+    /*
+        Whether this transaction holds a lock on rid in locks that covers
+        lock_type, an exclusive lock covering a shared one. Every table has its
+        own lock manager, so the same RID in another table is another lock
+    */
+    pub fn has_lock(&self, locks: &LockManager, rid: RID, lock_type: LockType) -> bool {
+        self.locks_acquired.iter().any(|l| {
+            l.manager == locks.id()
+                && l.rid == rid
+                && (l.lock_type == lock_type || l.lock_type == LockType::Exclusive)
+        })
+    }
+
+    /*
+        Waits out younger holders for up to wait_die, see LockManager::lock. A
+        shared lock the transaction already holds is upgraded when it needs an
+        exclusive one, and stays shared if that fails
+    */
+    fn try_lock(
+        &mut self,
+        locks: &LockManager,
+        rid: RID,
+        lock_type: LockType,
+        wait_die: Duration,
+    ) -> Result<(), u64> {
+        if self.has_lock(locks, rid, lock_type) {
+            return Ok(());
         }
 
-        if let Some(handle) = locks.try_lock(rid, lock_type) {
-            self.current_locks += 1;
-            self.locks_acquired.push(handle);
-
-            /*
-                            println!(
-                                "Thread {:?} acquired a {:?} lock on {}, now at {} locks",
-                                std::thread::current().id(),
-                                lock_type,
-                                rid.raw(),
-                                self.locks_acquired.len()
-                            );
-            */
-            true
-        } else {
-            false
+        if let Some(shared) = self
+            .locks_acquired
+            .iter_mut()
+            .find(|l| l.manager == locks.id() && l.rid == rid)
+        {
+            return locks.upgrade(shared, wait_die);
         }
+
+        let handle = locks.lock(rid, lock_type, self.timestamp, wait_die)?;
+        self.current_locks += 1;
+        self.locks_acquired.push(handle);
+        Ok(())
     }
 
+    /*
+        Every query path locks through here, so this is the one place the
+        concurrency policy is applied.
+    */
     pub fn try_lock_with_abort(
         &mut self,
         locks: &LockManager,
         rid: RID,
         lock_type: LockType,
     ) -> bool {
-        if !self.try_lock(locks, rid, lock_type) {
-            // println!(
-            //     "Thread {:?} failed to lock {:?} on RID {:?}",
-            //     std::thread::current().id(),
-            //     lock_type,
-            //     rid
-            // );
-            self.set_aborted(true);
-            return false;
+        let policy = self.concurrency_policy.unwrap_or_else(|| locks.policy());
+        let start = Instant::now();
+
+        let wait_die = match policy {
+            ConcurrencyPolicy::WaitDie => Duration::from_millis(WAIT_DIE_TIMEOUT_MS),
+            _ => Duration::ZERO,
+        };
+
+        loop {
+            if self.try_lock(locks, rid, lock_type, wait_die).is_ok() {
+                return true;
+            }
+
+            // Wait-die has already waited as long as it should
+            let wait = match policy {
+                ConcurrencyPolicy::NoWait | ConcurrencyPolicy::WaitDie => false,
+                ConcurrencyPolicy::WaitWithTimeout(timeout) => start.elapsed() < timeout,
+            };
+
+            if !wait {
+                self.set_aborted(AbortKind::LockConflict(rid));
+                return false;
+            }
+
+            thread::yield_now();
         }
-        true
     }
 }
@@ -0,0 +1,138 @@
+use std::{
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use rustc_hash::FxHashMap;
+
+use crate::transaction::Transaction;
+
+#[derive(Clone, Debug, Default)]
+pub struct WorkerStats {
+    // Transactions handed to this worker by the dispatcher
+    pub transactions: usize,
+    pub queries: usize,
+    pub committed: usize,
+    pub aborted: usize,
+    pub retries: usize,
+    pub elapsed: Duration,
+}
+
+impl WorkerStats {
+    pub fn throughput(&self) -> f64 {
+        self.committed as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/*
+    Shared alternative to one TransactionWorker per thread. Transactions are
+    dispatched to the worker already handling their table and key range, so
+    hot ranges stay on one worker, unless that worker is carrying more than
+    twice the mean load, in which case the least loaded worker gets it.
+*/
+pub struct TransactionScheduler {
+    num_workers: usize,
+    pending: Vec<Transaction>,
+    threads: Vec<JoinHandle<WorkerStats>>,
+    stats: Vec<WorkerStats>,
+}
+
+impl TransactionScheduler {
+    pub fn new(num_workers: usize) -> Self {
+        assert!(num_workers > 0);
+
+        Self {
+            num_workers,
+            pending: Vec::new(),
+            threads: Vec::new(),
+            stats: Vec::new(),
+        }
+    }
+
+    pub fn add_transaction(&mut self, transaction: Transaction) {
+        self.pending.push(transaction);
+    }
+
+    pub fn add_transactions(&mut self, transactions: Vec<Transaction>) {
+        self.pending.extend(transactions);
+    }
+
+    fn dispatch(&mut self) -> Vec<Vec<Transaction>> {
+        let mut queues: Vec<Vec<Transaction>> = (0..self.num_workers).map(|_| Vec::new()).collect();
+        let mut loads = vec![0; self.num_workers];
+        let mut total = 0;
+        let mut homes: FxHashMap<(String, Option<u64>), usize> = FxHashMap::default();
+
+        for transaction in self.pending.drain(..) {
+            let weight = transaction.query_count().max(1);
+            total += weight;
+
+            let least = (0..self.num_workers).min_by_key(|w| loads[*w]).unwrap();
+            let limit = 2 * total / self.num_workers;
+
+            let worker = match transaction.touched().first().and_then(|tag| homes.get(tag)) {
+                Some(&home) if loads[home] + weight <= limit => home,
+                _ => least,
+            };
+
+            for tag in transaction.touched() {
+                homes.entry(tag.clone()).or_insert(worker);
+            }
+
+            loads[worker] += weight;
+            queues[worker].push(transaction);
+        }
+
+        queues
+    }
+
+    fn spawn_worker_thread(mut queue: Vec<Transaction>) -> JoinHandle<WorkerStats> {
+        std::thread::spawn(move || {
+            let start = Instant::now();
+            let mut stats = WorkerStats {
+                transactions: queue.len(),
+                queries: queue.iter().map(Transaction::query_count).sum(),
+                ..Default::default()
+            };
+
+            queue.reverse();
+
+            while let Some(mut transaction) = queue.pop() {
+                match transaction.run() {
+                    Ok(_) => stats.committed += 1,
+                    Err(error) if error.is_retryable() => {
+                        stats.retries += 1;
+                        queue.insert(0, transaction);
+                    }
+                    Err(_) => stats.aborted += 1,
+                }
+            }
+
+            stats.elapsed = start.elapsed();
+            stats
+        })
+    }
+
+    pub fn run(&mut self) {
+        for queue in self.dispatch() {
+            self.threads
+                .push(TransactionScheduler::spawn_worker_thread(queue));
+        }
+    }
+
+    pub fn join(&mut self) {
+        let threads = std::mem::take(&mut self.threads);
+
+        self.stats = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect();
+    }
+
+    /*
+        Per-worker counters from the last completed run
+    */
+    pub fn stats(&self) -> &[WorkerStats] {
+        &self.stats
+    }
+}
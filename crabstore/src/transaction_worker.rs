@@ -5,54 +5,132 @@ use std::{
         atomic::{AtomicBool, AtomicUsize},
         Arc,
     },
+    thread,
+    time::Duration,
 };
 
 use parking_lot::RwLock;
 
-use crate::transaction::{QueryStatus, Transaction};
+use crate::transaction::{QueryResult, Transaction, TransactionError};
+
+/*
+    How often a worker runs a transaction again after it aborts retryably
+*/
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RetryPolicy {
+    // Retries before the transaction counts as failed, None to keep retrying
+    pub max_retries: Option<usize>,
+    // Sleep before the first retry, doubling with every one after it
+    pub backoff: Option<Duration>,
+}
+
+impl RetryPolicy {
+    fn allows(&self, transaction: &Transaction) -> bool {
+        match self.max_retries {
+            Some(max_retries) => transaction.retries() < max_retries,
+            None => true,
+        }
+    }
+
+    fn wait(&self, transaction: &Transaction) {
+        if let Some(backoff) = self.backoff {
+            thread::sleep(backoff.saturating_mul(1 << transaction.retries().min(16)));
+        }
+    }
+}
 
 pub struct TransactionWorker {
     transactions: Arc<RwLock<VecDeque<Transaction>>>,
     thread: Option<std::thread::JoinHandle<()>>,
-    stats: Arc<RwLock<Vec<bool>>>,
+    // Every run of a transaction, with why it aborted if it did
+    stats: Arc<RwLock<Vec<Result<(), TransactionError>>>>,
+    // What each committed transaction returned, in the order they committed
+    results: Arc<RwLock<Vec<Vec<QueryResult>>>>,
+    // Transactions that aborted for good, retries used up or not retryable at all
+    failed: Arc<RwLock<Vec<Transaction>>>,
+    retry_policy: RetryPolicy,
     result: usize,
 }
 
 impl TransactionWorker {
-    fn spawn_worker_thread(
-        queue: &Arc<RwLock<VecDeque<Transaction>>>,
-        stats: &Arc<RwLock<Vec<bool>>>,
-    ) -> std::thread::JoinHandle<()> {
-        let queue = Arc::clone(queue);
-        let stats = Arc::clone(stats);
-
-        std::thread::spawn(move || {
-            let mut queue = queue.write();
-            let mut stats = stats.write();
-
-            while !queue.is_empty() {
-                let mut transaction = queue.pop_front().unwrap();
-                let result = transaction.run();
-                stats.push(result);
-
-                if !result && transaction.get_status() == QueryStatus::AbortedRetryable {
-                    queue.push_back(transaction);
+    fn spawn_worker_thread(&self) -> std::thread::JoinHandle<()> {
+        let queue = Arc::clone(&self.transactions);
+        let stats = Arc::clone(&self.stats);
+        let results = Arc::clone(&self.results);
+        let failed = Arc::clone(&self.failed);
+        let retry_policy = self.retry_policy;
+
+        /*
+            The queue is only locked to take the next transaction or put back one
+            to retry, so workers sharing it run their transactions side by side
+        */
+        std::thread::spawn(move || loop {
+            let Some(mut transaction) = queue.write().pop_front() else {
+                break;
+            };
+
+            let result = transaction.run();
+            let retry = matches!(&result, Err(error) if error.is_retryable())
+                && retry_policy.allows(&transaction);
+
+            match result {
+                Ok(committed) => {
+                    stats.write().push(Ok(()));
+                    results.write().push(committed);
+                    continue;
                 }
+                Err(error) => stats.write().push(Err(error)),
+            }
+
+            if retry {
+                retry_policy.wait(&transaction);
+                queue.write().push_back(transaction);
+            } else {
+                failed.write().push(transaction);
             }
         })
     }
 
     pub fn new() -> Self {
+        TransactionWorker::with_retry_policy(RetryPolicy::default())
+    }
+
+    pub fn with_retry_policy(retry_policy: RetryPolicy) -> Self {
         let transactions = Arc::new(RwLock::new(VecDeque::new()));
 
         Self {
             transactions,
             thread: None,
             stats: Arc::new(RwLock::new(Vec::new())),
+            results: Arc::new(RwLock::new(Vec::new())),
+            failed: Arc::new(RwLock::new(Vec::new())),
+            retry_policy,
             result: 0,
         }
     }
 
+    /*
+        A worker that gives up on a transaction once it has been retried max_retries
+        times, without sleeping in between.
+    */
+    pub fn with_max_retries(max_retries: usize) -> Self {
+        TransactionWorker::with_retry_policy(RetryPolicy {
+            max_retries: Some(max_retries),
+            backoff: None,
+        })
+    }
+
+    /*
+        A worker taking its transactions from the same queue as other, with its own
+        stats and results. Transactions added to either can run on both.
+    */
+    pub fn sharing(other: &TransactionWorker) -> Self {
+        Self {
+            transactions: Arc::clone(&other.transactions),
+            ..Self::with_retry_policy(other.retry_policy)
+        }
+    }
+
     pub fn add_transaction(&mut self, transaction: Transaction) {
         self.transactions.write().push_back(transaction);
     }
@@ -63,10 +141,7 @@ impl TransactionWorker {
 
     pub fn run(&mut self) {
         if self.thread.is_none() {
-            self.thread = Some(TransactionWorker::spawn_worker_thread(
-                &self.transactions,
-                &self.stats,
-            ));
+            self.thread = Some(self.spawn_worker_thread());
         }
     }
 
@@ -79,4 +154,64 @@ impl TransactionWorker {
 
         handle.join().unwrap();
     }
+
+    /*
+        Results of every transaction committed since the last call, in the order they
+        committed, see Transaction::results. Call after TransactionWorker::join.
+    */
+    pub fn take_results(&mut self) -> Vec<Vec<QueryResult>> {
+        std::mem::take(&mut self.results.write())
+    }
+
+    /*
+        Whether each run of a transaction by this worker committed, in the order they
+        finished. A transaction that aborted retryably shows up once per attempt.
+    */
+    pub fn stats(&self) -> Vec<bool> {
+        self.stats.read().iter().map(Result::is_ok).collect()
+    }
+
+    /*
+        Why each run of a transaction by this worker that aborted did, in the order
+        they finished.
+    */
+    pub fn errors(&self) -> Vec<TransactionError> {
+        let stats = self.stats.read();
+        stats
+            .iter()
+            .filter_map(|result| result.clone().err())
+            .collect()
+    }
+
+    /*
+        How many transactions this worker committed.
+    */
+    pub fn committed(&self) -> usize {
+        self.stats
+            .read()
+            .iter()
+            .filter(|result| result.is_ok())
+            .count()
+    }
+
+    /*
+        How many runs of a transaction by this worker aborted, retried ones included.
+    */
+    pub fn aborted(&self) -> usize {
+        self.stats
+            .read()
+            .iter()
+            .filter(|result| result.is_err())
+            .count()
+    }
+
+    /*
+        Transactions this worker gave up on, in the order it did, since the last call:
+        those that used up their retries and those that aborted without being
+        retryable. Each still holds its queries, so it can be run again. Call after
+        TransactionWorker::join.
+    */
+    pub fn failed_transactions(&mut self) -> Vec<Transaction> {
+        std::mem::take(&mut self.failed.write())
+    }
 }
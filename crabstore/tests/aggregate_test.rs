@@ -0,0 +1,53 @@
+use crabcore::{crabstore::CrabStore, table::Table};
+use tempfile::tempdir;
+
+fn aggregates(table: &Table, start: u64, end: u64) -> (u64, Option<u64>, Option<u64>, Option<f64>) {
+    (
+        table.sum_query(start, end, 1, None).unwrap(),
+        table.min_query(start, end, 1, None).unwrap(),
+        table.max_query(start, end, 1, None).unwrap(),
+        table.avg_query(start, end, 1, None).unwrap(),
+    )
+}
+
+#[test]
+fn aggregates_read_latest_values() {
+    let dir = tempdir().unwrap();
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+
+    let table = crabstore.create_table("Grades", 2, 0);
+    for key in 0..10 {
+        table.insert_query(&[key, key + 10], None).unwrap();
+    }
+
+    // The base records still hold 10 and 19, only the tail records have the new values
+    table.update_query(0, &[None, Some(50)], None).unwrap();
+    table.update_query(9, &[None, Some(0)], None).unwrap();
+    table.delete_query(5, None).unwrap();
+
+    let (sum, min, max, avg) = aggregates(&table, 0, 9);
+    assert_eq!(sum, 50 + 11 + 12 + 13 + 14 + 16 + 17 + 18);
+    assert_eq!(min, Some(0));
+    assert_eq!(max, Some(50));
+    assert_eq!(avg, Some(sum as f64 / 9.0));
+
+    assert_eq!(
+        aggregates(&table, 1, 3),
+        (36, Some(11), Some(13), Some(12.0))
+    );
+
+    // A zero is a value like any other, an empty range has none
+    assert_eq!(aggregates(&table, 9, 9), (0, Some(0), Some(0), Some(0.0)));
+    assert_eq!(aggregates(&table, 5, 5), (0, None, None, None));
+    assert_eq!(aggregates(&table, 100, 200), (0, None, None, None));
+
+    // Read from the snapshot instead of the pages, with the same answers
+    let before = aggregates(&table, 0, 9);
+    table.freeze().unwrap();
+    assert_eq!(aggregates(&table, 0, 9), before);
+    assert_eq!(aggregates(&table, 5, 5), (0, None, None, None));
+    table.unfreeze();
+
+    crabstore.close();
+}
@@ -0,0 +1,149 @@
+#![cfg(feature = "async")]
+use crabcore::{
+    async_store::{AsyncCrabStore, AsyncTable},
+    config::CrabConfig,
+    transaction::{Query, Transaction},
+};
+use std::{
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    pin::pin,
+    sync::Arc,
+    task::{Context, Poll, Wake, Waker},
+    thread::{self, Thread},
+};
+use tempfile::tempdir;
+
+const NUM_TASKS: u64 = 8;
+const KEYS_PER_TASK: u64 = 500;
+
+/*
+    Polls a future on the current thread, parking between polls, which is all a
+    runtime worker does with it too
+*/
+struct Unpark(Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut context = Context::from_waker(&waker);
+    let mut future = pin!(future);
+
+    loop {
+        match future.as_mut().poll(&mut context) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+async fn run_task(table: AsyncTable, task: u64) {
+    let keys = task * KEYS_PER_TASK..(task + 1) * KEYS_PER_TASK;
+
+    for key in keys.clone() {
+        table.insert_query(vec![key, task, 0]).await.unwrap();
+    }
+    for key in keys.clone().step_by(2) {
+        assert!(table
+            .update_query(key, vec![None, None, Some(key)])
+            .await
+            .unwrap());
+    }
+
+    for key in keys.clone() {
+        let selected = table.select_query(key, 0, vec![1, 1, 1]).await.unwrap();
+        assert_eq!(
+            selected[0].columns,
+            [key, task, if key % 2 == 0 { key } else { 0 }]
+        );
+    }
+
+    assert_eq!(
+        table.sum_query(keys.start, keys.end - 1, 2).await.unwrap(),
+        keys.step_by(2).sum::<u64>()
+    );
+}
+
+#[test]
+fn concurrent_async_queries() {
+    let dir = tempdir().unwrap();
+    let store = AsyncCrabStore::with_config(
+        dir.path().into(),
+        CrabConfig {
+            async_threads: 4,
+            ..Default::default()
+        },
+    );
+
+    block_on(store.open()).unwrap();
+    let table = block_on(store.create_table("Grades".to_string(), 3, 0));
+
+    // Every task runs on its own thread, all of them waiting on the one pool
+    thread::scope(|scope| {
+        for task in 0..NUM_TASKS {
+            let table = table.clone();
+            scope.spawn(move || block_on(run_task(table, task)));
+        }
+    });
+
+    let mut transaction = Transaction::new();
+    transaction.add_query(Query::Delete(0), table.table());
+    transaction.add_query(
+        Query::Update(1, Box::new([None, Some(9), None])),
+        table.table(),
+    );
+    let (committed, _) = block_on(store.run(transaction));
+    assert!(committed.is_ok());
+
+    block_on(async {
+        assert!(table
+            .select_query(0, 0, vec![1, 1, 1])
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            table.select_query(1, 0, vec![0, 1, 0]).await.unwrap()[0].columns,
+            [9]
+        );
+        store.close().await;
+    });
+
+    // Reopened through the async store, the other tasks' rows are all there
+    let store = AsyncCrabStore::new(dir.path().into());
+    block_on(async {
+        store.open().await.unwrap();
+        let table = store.get_table("Grades".to_string()).await;
+        assert_eq!(
+            table
+                .sum_query(0, NUM_TASKS * KEYS_PER_TASK, 1)
+                .await
+                .unwrap(),
+            (0..NUM_TASKS).map(|task| task * KEYS_PER_TASK).sum::<u64>() + 9
+        );
+        store.close().await;
+    });
+}
+
+#[test]
+fn panics_reach_the_awaiting_task() {
+    let dir = tempdir().unwrap();
+    let store = AsyncCrabStore::new(dir.path().into());
+    block_on(store.open()).unwrap();
+
+    let missing = panic::catch_unwind(AssertUnwindSafe(|| {
+        block_on(store.get_table("Missing".to_string()))
+    }));
+    assert!(missing.is_err());
+
+    // The pool thread that panicked is still there to take the next query
+    let table = block_on(store.create_table("Grades".to_string(), 2, 0));
+    block_on(table.insert_query(vec![1, 2])).unwrap();
+    assert_eq!(block_on(table.sum_query(0, 10, 1)).unwrap(), 2);
+
+    block_on(store.close());
+}
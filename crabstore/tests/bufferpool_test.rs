@@ -0,0 +1,216 @@
+use crabcore::{
+    bufferpool::{BufferPool, BufferPoolStats},
+    disk_manager::DiskManager,
+    error::CrabError,
+    eviction::Eviction,
+};
+use std::{
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+use tempfile::tempdir;
+
+#[test]
+fn full_pool_fails_instead_of_waiting() {
+    let dir = tempdir().unwrap();
+    let disk = Arc::new(DiskManager::new(&dir.path().join("pages.CRAB")).unwrap());
+    let bufferpool = BufferPool::new(disk, 4);
+
+    let mut pinned = (0..4)
+        .map(|page_id| bufferpool.get_page(page_id).unwrap())
+        .collect::<Vec<_>>();
+    pinned[0].write_slot(0, 7);
+    assert!(pinned.iter().all(|frame| frame.pin_count() == 1));
+
+    // Cached pages can still be pinned again, nothing else fits
+    let again = bufferpool.get_page(0).unwrap();
+    assert_eq!(again.pin_count(), 2);
+    assert!(matches!(
+        bufferpool.get_page(4),
+        Err(CrabError::BufferPoolFull)
+    ));
+    assert!(matches!(
+        bufferpool.new_page(),
+        Err(CrabError::BufferPoolFull)
+    ));
+
+    // Page 0 has two guards, both have to go before its frame is free
+    pinned.remove(0);
+    assert!(matches!(
+        bufferpool.get_page(4),
+        Err(CrabError::BufferPoolFull)
+    ));
+    drop(again);
+
+    let fifth = bufferpool.get_page(4).unwrap();
+    assert_eq!(fifth.get_page_id(), 4);
+    assert!(!bufferpool.is_page_mapped(0));
+
+    // The evicted page was written back on the way out
+    drop(pinned);
+    assert_eq!(bufferpool.get_page(0).unwrap().slot(0), 7);
+}
+
+#[test]
+fn pins_are_released_by_every_thread() {
+    let dir = tempdir().unwrap();
+    let disk = Arc::new(DiskManager::new(&dir.path().join("pages.CRAB")).unwrap());
+    let bufferpool = Arc::new(BufferPool::new(disk, 4));
+
+    let readers = (0..4)
+        .map(|thread| {
+            let bufferpool = Arc::clone(&bufferpool);
+            thread::spawn(move || {
+                for page_id in 0..64 {
+                    // Other threads may hold all 4 frames for a moment
+                    if let Ok(frame) = bufferpool.get_page(page_id * 4 + thread) {
+                        let cloned = frame.clone();
+                        assert_eq!(cloned.get_page_id(), page_id * 4 + thread);
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    let frames = (0..4)
+        .map(|page_id| bufferpool.get_page(page_id).unwrap())
+        .collect::<Vec<_>>();
+    assert!(frames.iter().all(|frame| frame.pin_count() == 1));
+}
+
+/*
+    Misses re-reading 4 hot pages of an 8 frame pool after a scan of 64
+    others went through it, the way a merge goes through a range
+*/
+fn hot_page_misses_after_scan(eviction: Eviction) -> usize {
+    let dir = tempdir().unwrap();
+    let disk = Arc::new(DiskManager::new(&dir.path().join("pages.CRAB")).unwrap());
+    let bufferpool = BufferPool::with_policy(disk, 8, eviction.policy(8));
+
+    for _ in 0..2 {
+        for page_id in 0..4 {
+            bufferpool.get_page(page_id).unwrap();
+        }
+    }
+
+    for page_id in 100..164 {
+        bufferpool.get_page_for_scan(page_id).unwrap();
+    }
+
+    bufferpool.reset_stats();
+    for page_id in 0..4 {
+        bufferpool.get_page(page_id).unwrap();
+    }
+    bufferpool.stats().misses
+}
+
+#[test]
+fn lru_k_keeps_hot_pages_through_a_scan() {
+    // The clock hand clears the hot pages' reference bits and comes round again
+    assert_eq!(hot_page_misses_after_scan(Eviction::Clock), 4);
+    assert_eq!(hot_page_misses_after_scan(Eviction::LruK), 0);
+}
+
+#[test]
+fn stats_count_every_path() {
+    let dir = tempdir().unwrap();
+    let disk = Arc::new(DiskManager::new(&dir.path().join("pages.CRAB")).unwrap());
+    let bufferpool = BufferPool::new(disk, 2);
+
+    // First access misses, the second hits
+    let first = bufferpool.get_page(0).unwrap();
+    first.write_slot(0, 1);
+    let again = bufferpool.get_page(0).unwrap();
+    assert_eq!(
+        bufferpool.stats(),
+        BufferPoolStats {
+            requests: 2,
+            hits: 1,
+            misses: 1,
+            pinned: 1,
+            ..BufferPoolStats::default()
+        }
+    );
+    drop((first, again));
+
+    // Page 0 goes out dirty for page 2
+    let new_page = bufferpool.new_page().unwrap();
+    new_page.write_slot(0, 2);
+    drop(new_page);
+    drop(bufferpool.get_page(2).unwrap());
+    let stats = bufferpool.stats();
+    assert_eq!(
+        (stats.misses, stats.evictions, stats.dirty_writes),
+        (2, 1, 1)
+    );
+    assert_eq!(stats.pinned, 0);
+
+    // So does the new page when everything is flushed
+    bufferpool.flush_all();
+    assert_eq!(bufferpool.stats().dirty_writes, 2);
+
+    bufferpool.reset_stats();
+    assert_eq!(bufferpool.stats(), BufferPoolStats::default());
+}
+
+fn wait_until_mapped(bufferpool: &BufferPool, page_id: usize) {
+    let started = Instant::now();
+    while !bufferpool.is_page_mapped(page_id) {
+        assert!(
+            started.elapsed() < Duration::from_secs(5),
+            "page {page_id} never prefetched"
+        );
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn prefetch_reads_ahead_around_pinned_frames() {
+    let dir = tempdir().unwrap();
+    let disk = Arc::new(DiskManager::new(&dir.path().join("pages.CRAB")).unwrap());
+    let bufferpool = Arc::new(BufferPool::new(Arc::clone(&disk), 4));
+
+    for page_id in 0..8 {
+        bufferpool
+            .get_page(page_id)
+            .unwrap()
+            .write_slot(0, page_id as u64);
+    }
+    bufferpool.flush_all();
+    bufferpool.reset_stats();
+
+    // Page 0 is cached already, the others are read for the fetches after
+    let pinned = (0..2)
+        .map(|page_id| bufferpool.get_page(page_id).unwrap())
+        .collect::<Vec<_>>();
+    bufferpool.prefetch(&[0, 2, 3]);
+    wait_until_mapped(&bufferpool, 3);
+
+    for page_id in 2..4 {
+        assert_eq!(
+            bufferpool.get_page(page_id).unwrap().slot(0),
+            page_id as u64
+        );
+    }
+    let stats = bufferpool.stats();
+    assert_eq!((stats.misses, stats.hits, stats.prefetched), (2, 2, 2));
+
+    // Only one frame isn't pinned, 5 takes it from 4, and free pages are never read
+    let pinned = pinned
+        .into_iter()
+        .chain(Some(bufferpool.get_page(2).unwrap()))
+        .collect::<Vec<_>>();
+    disk.free_page(6);
+    bufferpool.prefetch(&[6, 4, 5]);
+    wait_until_mapped(&bufferpool, 5);
+
+    assert!((0..3).all(|page_id| bufferpool.is_page_mapped(page_id)));
+    assert!(!bufferpool.is_page_mapped(4) && !bufferpool.is_page_mapped(6));
+    assert_eq!(bufferpool.stats().prefetched, 4);
+    drop(pinned);
+}
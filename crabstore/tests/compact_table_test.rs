@@ -0,0 +1,221 @@
+use crabcore::{crabstore::CrabStore, error::CrabError, table::Table, transaction::Transaction};
+use std::{fs, path::Path};
+use tempfile::tempdir;
+
+// A few page ranges, nine in ten of which get deleted
+const NUMBER_OF_RECORDS: u64 = 30000;
+
+fn is_kept(key: u64) -> bool {
+    key.is_multiple_of(10)
+}
+
+fn populate(table: &Table) {
+    table.build_index(1);
+    for key in 0..NUMBER_OF_RECORDS {
+        table.insert_query(&[key, key % 7, key], None).unwrap();
+    }
+    // Some rows read from a tail record, which the copy has to follow
+    for key in (0..NUMBER_OF_RECORDS).step_by(3) {
+        table
+            .update_query(key, &[None, None, Some(key * 2)], None)
+            .unwrap();
+    }
+    for key in (0..NUMBER_OF_RECORDS).filter(|key| !is_kept(*key)) {
+        assert!(table.delete_query(key, None).unwrap());
+    }
+}
+
+fn latest(key: u64) -> [u64; 3] {
+    [
+        key,
+        key % 7,
+        if key.is_multiple_of(3) { key * 2 } else { key },
+    ]
+}
+
+fn check_rows(table: &Table) {
+    for key in 0..NUMBER_OF_RECORDS {
+        let selected = table.select_query(key, 0, &[1, 1, 1], None).unwrap();
+        if is_kept(key) {
+            assert_eq!(selected[0].columns, latest(key));
+        } else {
+            assert!(selected.is_empty());
+        }
+    }
+
+    let kept = (0..NUMBER_OF_RECORDS).filter(|key| is_kept(*key));
+    assert_eq!(
+        table.sum_query(0, NUMBER_OF_RECORDS, 2, None).unwrap(),
+        kept.clone().map(|key| latest(key)[2]).sum::<u64>()
+    );
+
+    // Through the index on column 1, which was rebuilt for the new RIDs
+    assert_eq!(table.indexed_columns(), [0, 1]);
+    for value in 0..7 {
+        let mut keys = table
+            .select_query(value, 1, &[1, 0, 0], None)
+            .unwrap()
+            .into_iter()
+            .map(|record| record.columns[0])
+            .collect::<Vec<u64>>();
+        keys.sort_unstable();
+        assert_eq!(
+            keys,
+            kept.clone()
+                .filter(|key| key % 7 == value)
+                .collect::<Vec<u64>>()
+        );
+    }
+}
+
+fn file_size(dir: &Path) -> u64 {
+    fs::metadata(CrabStore::table_filename(dir, "Grades"))
+        .unwrap()
+        .len()
+}
+
+#[test]
+fn compact_table_shrinks_and_keeps_rows() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 3, 0);
+    populate(&table);
+    db.checkpoint();
+    let old_size = file_size(dir.path());
+
+    let report = db.compact_table("Grades").unwrap();
+    assert_eq!(report.rows, NUMBER_OF_RECORDS as usize / 10);
+    assert!(report.new_pages * 5 < report.old_pages, "{report:?}");
+    assert!(file_size(dir.path()) * 5 < old_size);
+    assert!(!CrabStore::compact_dirname(dir.path(), "Grades").exists());
+
+    // The old handle is closed, the store hands out the compacted table
+    assert_eq!(
+        table.select_query(0, 0, &[1, 1, 1], None),
+        Err(CrabError::TableClosed)
+    );
+    let table = db.get_table("Grades");
+    assert_eq!(
+        table.storage_stats().unwrap().live_rows,
+        NUMBER_OF_RECORDS as usize / 10
+    );
+    check_rows(&table);
+
+    // New rows go after the compacted ones
+    table
+        .insert_query(&[NUMBER_OF_RECORDS, 0, 0], None)
+        .unwrap();
+    assert!(table.delete_query(NUMBER_OF_RECORDS, None).unwrap());
+
+    db.close();
+
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+    check_rows(&db.get_table("Grades"));
+    db.close();
+}
+
+#[test]
+fn compact_into_writes_a_loadable_copy() {
+    let dir = tempdir().unwrap();
+    let copy_dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 3, 0);
+    populate(&table);
+
+    let report = table.compact_into(copy_dir.path()).unwrap();
+    assert_eq!(report.rows, NUMBER_OF_RECORDS as usize / 10);
+
+    // The original is untouched and still open
+    check_rows(&table);
+    db.close();
+
+    CrabStore::persist_table_index(
+        &CrabStore::database_filename(copy_dir.path()),
+        vec!["Grades".to_string()],
+    )
+    .unwrap();
+    let mut copy = CrabStore::new(copy_dir.path().into());
+    copy.open().unwrap();
+    check_rows(&copy.get_table("Grades"));
+    copy.close();
+}
+
+#[test]
+fn compaction_waits_for_transactions() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 2, 0);
+    for key in 0..100 {
+        table.insert_query(&[key, key], None).unwrap();
+    }
+
+    // An uncommitted update still holds its row's lock
+    let mut transaction = Transaction::new();
+    table
+        .update_query(5, &[None, Some(0)], Some(&mut transaction))
+        .unwrap();
+
+    assert_eq!(db.compact_table("Grades"), Err(CrabError::TableInUse));
+    assert_eq!(
+        table.compact_into(&dir.path().join("copy")),
+        Err(CrabError::TableInUse)
+    );
+
+    // Nothing was closed or swapped
+    assert_eq!(table.sum_query(0, 99, 1, None).unwrap(), 4950 - 5);
+    db.close();
+}
+
+#[test]
+fn interrupted_compaction_finishes_on_open() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 3, 0);
+    populate(&table);
+    db.close();
+
+    // Staged without the marker, as if the copy crashed part way: thrown away
+    let compact_dir = CrabStore::compact_dirname(dir.path(), "Grades");
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+    db.get_table("Grades").compact_into(&compact_dir).unwrap();
+    db.close();
+
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+    assert!(!compact_dir.exists());
+    assert_eq!(
+        db.get_table("Grades").storage_stats().unwrap().base_pages,
+        NUMBER_OF_RECORDS as usize / 512 + 1
+    );
+    check_rows(&db.get_table("Grades"));
+
+    // Staged with the marker, as if the swap crashed part way: finished
+    db.get_table("Grades").compact_into(&compact_dir).unwrap();
+    db.close();
+    fs::write(compact_dir.join("COMPLETE"), []).unwrap();
+    fs::rename(
+        CrabStore::page_dir_filename(&compact_dir, "Grades"),
+        CrabStore::page_dir_filename(dir.path(), "Grades"),
+    )
+    .unwrap();
+
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+    assert!(!compact_dir.exists());
+    assert_eq!(
+        db.get_table("Grades").storage_stats().unwrap().base_pages,
+        NUMBER_OF_RECORDS as usize / 5120 + 1
+    );
+    check_rows(&db.get_table("Grades"));
+    db.close();
+}
@@ -0,0 +1,144 @@
+use crabcore::{crabstore::CrabStore, table::Table};
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+use tempfile::tempdir;
+
+// Eight base pages of one page range
+const NUMBER_OF_RECORDS: u64 = 4096;
+
+fn is_deleted(key: u64) -> bool {
+    key % 10 == 3
+}
+
+fn deleted_count() -> usize {
+    (0..NUMBER_OF_RECORDS)
+        .filter(|key| is_deleted(*key))
+        .count()
+}
+
+/*
+    Keeps updating the live rows, so every base page gets merged again, until
+    done says so. Returns the value the last round wrote.
+*/
+fn update_until(table: &Table, mut round: u64, done: impl Fn(&Table) -> bool) -> u64 {
+    let start = Instant::now();
+
+    loop {
+        for key in (0..NUMBER_OF_RECORDS).filter(|key| !is_deleted(*key)) {
+            table
+                .update_query(key, &[None, None, Some(round)], None)
+                .unwrap();
+        }
+        thread::sleep(Duration::from_millis(20));
+
+        if done(table) {
+            return round;
+        }
+
+        assert!(
+            start.elapsed() < Duration::from_secs(30),
+            "merges never caught up"
+        );
+        round += 1;
+    }
+}
+
+fn check_live_rows(table: &Table, round: u64) {
+    let live = NUMBER_OF_RECORDS as usize - deleted_count();
+
+    assert_eq!(
+        table.sum_query(0, NUMBER_OF_RECORDS, 2, None).unwrap(),
+        live as u64 * round
+    );
+
+    for key in 0..NUMBER_OF_RECORDS {
+        let selected = table.select_query(key, 0, &[1, 1, 1], None).unwrap();
+        if is_deleted(key) {
+            assert!(selected.is_empty());
+        } else {
+            assert_eq!(selected[0].columns, [key, key % 50, round]);
+        }
+    }
+
+    // The index counts exactly, so this is how many entries are left per value
+    for value in 0..50 {
+        let expected = (0..NUMBER_OF_RECORDS)
+            .filter(|key| key % 50 == value && !is_deleted(*key))
+            .count();
+        assert_eq!(table.explain(1, value).estimated_rows, expected as f64);
+        assert_eq!(
+            table
+                .select_query(value, 1, &[1, 0, 0], None)
+                .unwrap()
+                .len(),
+            expected
+        );
+    }
+}
+
+#[test]
+fn merge_compacts_deleted_rows() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 3, 0);
+    table.build_index(1);
+    for key in 0..NUMBER_OF_RECORDS {
+        table.insert_query(&[key, key % 50, 0], None).unwrap();
+    }
+
+    // Half the deleted rows have a newer version in a tail record
+    for key in (0..NUMBER_OF_RECORDS).filter(|key| key % 20 == 3) {
+        table
+            .update_query(key, &[None, Some(key % 50), Some(7)], None)
+            .unwrap();
+    }
+    for key in (0..NUMBER_OF_RECORDS).filter(|key| is_deleted(*key)) {
+        assert!(table.delete_query(key, None).unwrap());
+    }
+
+    let stats = table.storage_stats().unwrap();
+    assert_eq!(stats.base_pages, 8);
+    assert_eq!(stats.deleted_rows, deleted_count());
+    assert_eq!(
+        stats.live_rows,
+        NUMBER_OF_RECORDS as usize - deleted_count()
+    );
+    assert_eq!(stats.compacted_rows, 0);
+
+    // Every row holding 3 is deleted, and took its index entry with it
+    assert_eq!(table.explain(1, 3).estimated_rows, 0.0);
+
+    let round = update_until(&table, 1, |table| {
+        table.storage_stats().unwrap().compacted_rows == deleted_count()
+    });
+    check_live_rows(&table, round);
+
+    // Later merges find the slots already cleared and don't count them again
+    let compacted = table.merge_stats().compacted_rows;
+    assert_eq!(compacted, deleted_count());
+    let merges = table.merge_stats().merges;
+    let round = update_until(&table, round + 1, |table| {
+        table.merge_stats().merges >= merges + 2
+    });
+    assert_eq!(table.merge_stats().compacted_rows, compacted);
+    assert_eq!(
+        table.storage_stats().unwrap().compacted_rows,
+        deleted_count()
+    );
+
+    db.close();
+
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+    let table = db.get_table("Grades");
+    assert_eq!(
+        table.storage_stats().unwrap().compacted_rows,
+        deleted_count()
+    );
+    check_live_rows(&table, round);
+    db.close();
+}
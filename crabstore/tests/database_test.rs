@@ -1,8 +1,26 @@
 #![feature(test)]
 extern crate test;
-use crabcore::{crabstore::CrabStore, record::Record};
+use crabcore::{
+    bufferpool::{BudgetPolicy, QueryBudget},
+    crabstore::CrabStore,
+    error::CrabError,
+    index::Index,
+    record::Record,
+    rid::RID,
+    table::{AccessPath, QueryContext, ResultOrder, Table},
+};
 use rand::prelude::*;
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::Duration,
+};
 use tempfile::tempdir;
 use test::Bencher;
 
@@ -13,23 +31,23 @@ fn verify() {
     let dir = tempdir().unwrap();
 
     let mut crabstore = CrabStore::new(dir.path().into());
-    crabstore.open();
+    crabstore.open().unwrap();
     let grades = crabstore.create_table("Grades", 4, 0);
 
     for i in 0..num_records {
-        grades.insert_query(&[i, 2, 3, 4], None);
+        grades.insert_query(&[i, 2, 3, 4], None).unwrap();
     }
 
-    let sum = grades.sum_query(0, num_records, 1, None);
+    let sum = grades.sum_query(0, num_records, 1, None).unwrap();
     assert_eq!(sum, 2 * num_records);
-    let sum = grades.sum_query(0, num_records, 2, None);
+    let sum = grades.sum_query(0, num_records, 2, None).unwrap();
     assert_eq!(sum, 3 * num_records);
 
-    let selected = grades.select_query(19999, 0, &[1, 1, 1, 1], None);
+    let selected = grades.select_query(19999, 0, &[1, 1, 1, 1], None).unwrap();
     assert_eq!(selected[0].columns, &[19999, 2, 3, 4]);
 
     for i in 0..num_records {
-        let old_values = &grades.select_query(i, 0, &[1, 1, 1, 1], None)[0].columns;
+        let old_values = &grades.select_query(i, 0, &[1, 1, 1, 1], None).unwrap()[0].columns;
         let mut new_values = old_values
             .iter()
             .map(|x| Some(x + i))
@@ -37,10 +55,10 @@ fn verify() {
 
         new_values[0] = None;
 
-        grades.update_query(i, &new_values, None);
+        grades.update_query(i, &new_values, None).unwrap();
     }
 
-    let selected = grades.select_query(19965, 0, &[1, 1, 1, 1], None);
+    let selected = grades.select_query(19965, 0, &[1, 1, 1, 1], None).unwrap();
     assert_eq!(selected[0].columns, [19965, 19967, 19968, 19969]);
     drop(grades);
 
@@ -48,12 +66,23 @@ fn verify() {
 }
 
 fn regorganize_result(result: Vec<Record>) -> Vec<Vec<u64>> {
-    let mut val = Vec::with_capacity(result.len());
-    for r in result.iter() {
-        val.push(r.columns.clone());
-    }
-    val.sort();
-    val
+    result.into_iter().map(|record| record.columns).collect()
+}
+
+// Ordered by the engine, so the same rows compare equal across merges and index builds
+fn select_by_key(table: &Table, search_value: u64, column_index: usize) -> Vec<Vec<u64>> {
+    regorganize_result(
+        table
+            .select_query_limit(
+                search_value,
+                column_index,
+                &[1, 1, 1, 1, 1],
+                ResultOrder::ByPrimaryKey,
+                None,
+                None,
+            )
+            .unwrap(),
+    )
 }
 
 #[test]
@@ -72,47 +101,42 @@ fn correctness_tester1() {
     let dir = tempdir().unwrap();
 
     let mut crabstore = CrabStore::new(dir.path().into());
-    crabstore.open();
+    crabstore.open().unwrap();
 
     let table = crabstore.create_table("test", 5, 0);
 
     for record in records {
-        table.insert_query(&record, None);
+        table.insert_query(&record, None).unwrap();
     }
 
     table.build_index(2);
-    let result = regorganize_result(table.select_query(1, 2, &[1, 1, 1, 1, 1], None));
-    assert_eq!(result.len(), 4);
-    assert!(result.iter().any(|x| x.eq(&records[0])));
-    assert!(result.iter().any(|x| x.eq(&records[1])));
-    assert!(result.iter().any(|x| x.eq(&records[5])));
-    assert!(result.iter().any(|x| x.eq(&records[7])));
+    let result = select_by_key(&table, 1, 2);
+    assert_eq!(result, [records[0], records[1], records[5], records[7]]);
 
     table.drop_index(2);
-    let result = regorganize_result(table.select_query(3, 2, &[1, 1, 1, 1, 1], None));
-    assert_eq!(result.len(), 1);
-    assert!(result.iter().any(|x| x.eq(&records[2])));
-
-    let result = regorganize_result(table.select_query(1, 2, &[1, 1, 1, 1, 1], None));
-    assert_eq!(result.len(), 4);
-    assert!(result.iter().any(|x| x.eq(&records[0])));
-    assert!(result.iter().any(|x| x.eq(&records[1])));
-    assert!(result.iter().any(|x| x.eq(&records[5])));
-    assert!(result.iter().any(|x| x.eq(&records[7])));
-
-    let result = regorganize_result(table.select_query(10, 2, &[1, 1, 1, 1, 1], None));
+    let result = select_by_key(&table, 3, 2);
+    assert_eq!(result, [records[2]]);
+
+    let result = select_by_key(&table, 1, 2);
+    assert_eq!(result, [records[0], records[1], records[5], records[7]]);
+
+    let result = select_by_key(&table, 10, 2);
     assert_eq!(result.len(), 0);
 
-    table.update_query(8, &[None, Some(2), Some(2), Some(2), Some(2)], None);
-    let result = regorganize_result(table.select_query(8, 2, &[1, 1, 1, 1, 1], None));
+    table
+        .update_query(8, &[None, Some(2), Some(2), Some(2), Some(2)], None)
+        .unwrap();
+    let result = select_by_key(&table, 8, 2);
     assert_eq!(result.len(), 0);
 
-    table.update_query(7, &[Some(8), Some(2), Some(2), Some(2), Some(2)], None);
-    let result = regorganize_result(table.select_query(7, 0, &[1, 1, 1, 1, 1], None));
+    table
+        .update_query(7, &[Some(8), Some(2), Some(2), Some(2), Some(2)], None)
+        .unwrap();
+    let result = select_by_key(&table, 7, 0);
     assert_eq!(result.len(), 0);
 
-    table.delete_query(5, None);
-    let result = regorganize_result(table.select_query(5, 0, &[1, 1, 1, 1, 1], None));
+    table.delete_query(5, None).unwrap();
+    let result = select_by_key(&table, 5, 0);
     assert_eq!(result.len(), 0);
 
     let table2 = crabstore.create_table("test2", 5, 0);
@@ -128,13 +152,12 @@ fn correctness_tester1() {
     ];
 
     for record in records2.iter() {
-        table2.insert_query(record, None);
+        table2.insert_query(record, None).unwrap();
     }
 
-    let result = regorganize_result(table2.select_query(1, 0, &[1, 1, 1, 1, 1], None));
+    let result = select_by_key(&table2, 1, 0);
 
-    assert_eq!(result.len(), 1);
-    assert!(result.iter().any(|x| x.eq(&records2[0])));
+    assert_eq!(result, [records2[0]]);
 }
 
 #[test]
@@ -152,15 +175,15 @@ fn correctness_tester2() {
     let dir = tempdir().unwrap();
 
     let mut crabstore = CrabStore::new(dir.path().into());
-    crabstore.open();
+    crabstore.open().unwrap();
 
     let table = crabstore.create_table("test3", 5, 2);
 
     for record in records.iter() {
-        table.insert_query(record, None);
+        table.insert_query(record, None).unwrap();
     }
 
-    let result = table.sum_query(3, 5, 4, None);
+    let result = table.sum_query(3, 5, 4, None).unwrap();
     assert_eq!(result, 5);
 }
 
@@ -170,9 +193,10 @@ const NUMBER_OF_UPDATES: u64 = 1;
 
 fn durability_tester1(directory: &Path, records: &mut HashMap<u64, Vec<u64>>, keys: &Vec<u64>) {
     let mut crabstore = CrabStore::new(directory.to_path_buf());
-    crabstore.open();
+    crabstore.open().unwrap();
 
     let table = crabstore.create_table("Grades", 5, 0);
+    table.build_index(1);
 
     let mut rand = StdRng::seed_from_u64(3562901);
 
@@ -185,12 +209,12 @@ fn durability_tester1(directory: &Path, records: &mut HashMap<u64, Vec<u64>>, ke
             rand.gen_range(0..20),
             rand.gen_range(0..20),
         ];
-        table.insert_query(&record, None);
+        table.insert_query(&record, None).unwrap();
         records.insert(key, record);
     }
 
     for key in keys.iter() {
-        let record = &table.select_query(*key, 0, &[1, 1, 1, 1, 1], None)[0].columns;
+        let record = &table.select_query(*key, 0, &[1, 1, 1, 1, 1], None).unwrap()[0].columns;
         for (i, column) in record.iter().enumerate() {
             assert_eq!(*column, records.get(key).unwrap()[i]);
         }
@@ -205,8 +229,8 @@ fn durability_tester1(directory: &Path, records: &mut HashMap<u64, Vec<u64>>, ke
                 updated_columns[i] = Some(val);
                 records.get_mut(key).unwrap()[i] = val;
             }
-            table.update_query(*key, &updated_columns, None);
-            let record = &table.select_query(*key, 0, &[1, 1, 1, 1, 1], None)[0];
+            table.update_query(*key, &updated_columns, None).unwrap();
+            let record = &table.select_query(*key, 0, &[1, 1, 1, 1, 1], None).unwrap()[0];
             for (i, val) in record.columns.iter().enumerate() {
                 assert_eq!(*val, records.get(key).unwrap()[i]);
             }
@@ -230,17 +254,39 @@ fn durability_tester1(directory: &Path, records: &mut HashMap<u64, Vec<u64>>, ke
 
 fn durability_tester2(directory: &Path, records: &mut HashMap<u64, Vec<u64>>, keys: &Vec<u64>) {
     let mut crabstore = CrabStore::new(directory.to_path_buf());
-    crabstore.open();
+    crabstore.open().unwrap();
 
     let table = crabstore.get_table("Grades");
 
     for key in keys.iter() {
-        let record = &table.select_query(*key, 0, &[1, 1, 1, 1, 1], None)[0].columns;
+        let record = &table.select_query(*key, 0, &[1, 1, 1, 1, 1], None).unwrap()[0].columns;
         for (i, column) in record.iter().enumerate() {
             assert_eq!(*column, records.get(key).unwrap()[i]);
         }
     }
 
+    // The secondary index came back from its file, with the updated values
+    for value in 0..20 {
+        assert_eq!(table.explain(1, value).access, AccessPath::Index);
+
+        let mut found: Vec<u64> = table
+            .select_query(value, 1, &[1, 0, 0, 0, 0], None)
+            .unwrap()
+            .iter()
+            .map(|record| record.columns[0])
+            .collect();
+        found.sort();
+
+        let mut expected: Vec<u64> = records
+            .iter()
+            .filter(|(_, record)| record[1] == value)
+            .map(|(key, _)| *key)
+            .collect();
+        expected.sort();
+
+        assert_eq!(found, expected);
+    }
+
     crabstore.close();
 }
 
@@ -282,3 +328,933 @@ fn durability_tester() {
     durability_tester1(dir.path(), &mut records, &keys);
     durability_tester2(dir.path(), &mut records, &keys);
 }
+
+#[test]
+fn close_while_selecting() {
+    let num_records = 1000;
+
+    let dir = tempdir().unwrap();
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("Closing", 2, 0);
+
+    for i in 0..num_records {
+        table.insert_query(&[i, i * 2], None).unwrap();
+    }
+
+    let selector = {
+        let table = Arc::clone(&table);
+        thread::spawn(move || {
+            let mut key = 0;
+            loop {
+                match table.select_query(key, 0, &[1, 1], None) {
+                    Ok(selected) => assert_eq!(selected[0].columns, [key, key * 2]),
+                    Err(err) => return err,
+                }
+                key = (key + 1) % num_records;
+            }
+        })
+    };
+
+    thread::sleep(Duration::from_millis(50));
+    crabstore.close();
+
+    assert_eq!(selector.join().unwrap(), CrabError::TableClosed);
+    assert!(table.is_closed());
+    assert_eq!(
+        table.insert_query(&[num_records, 0], None),
+        Err(CrabError::TableClosed)
+    );
+}
+
+#[test]
+fn select_fetches_projected_frames_once_per_page() {
+    let num_records = 1024;
+    let num_pages = 2;
+
+    let dir = tempdir().unwrap();
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("Projection", 10, 0);
+
+    for i in 0..num_records {
+        table
+            .insert_query(&[i, 7, i, i, i, i, i, i, i, i], None)
+            .unwrap();
+    }
+
+    table.build_index(1);
+
+    let bufferpool = table.get_bufferpool();
+    let requests = |included_columns: &[usize]| {
+        bufferpool.reset_stats();
+        let selected = table.select_query(7, 1, included_columns, None).unwrap();
+        assert_eq!(selected.len(), num_records as usize);
+        bufferpool.stats().requests
+    };
+
+    let unprojected = requests(&[0; 10]);
+    let projected = requests(&[1, 1, 0, 1, 0, 0, 0, 0, 1, 0]);
+    let everything = requests(&[1; 10]);
+
+    // The searched column is always read back to re-check the match
+    assert_eq!(projected - unprojected, 3 * num_pages);
+    assert_eq!(everything - unprojected, 9 * num_pages);
+
+    crabstore.close();
+}
+
+#[test]
+fn indexes_survive_reopen() {
+    let num_records = 1000;
+
+    let dir = tempdir().unwrap();
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("Indexed", 5, 0);
+
+    for i in 0..num_records {
+        table.insert_query(&[i, 0, 0, i * 10, 0], None).unwrap();
+    }
+
+    table.build_index(3);
+    assert_eq!(table.indexed_columns(), [0, 3]);
+    crabstore.close();
+
+    // Lose the index data, the header still declares which columns were indexed
+    fs::remove_file(CrabStore::index_filename(dir.path(), "Indexed")).unwrap();
+
+    crabstore.open().unwrap();
+    let table = crabstore.get_table("Indexed");
+    assert_eq!(table.indexed_columns(), [0, 3]);
+
+    let bufferpool = table.get_bufferpool();
+    bufferpool.reset_stats();
+
+    let selected = table.select_query(5000, 3, &[1, 0, 0, 1, 0], None).unwrap();
+    assert_eq!(selected[0].columns, [500, 5000]);
+
+    // A scan would have touched a frame for every record
+    assert!(bufferpool.stats().requests < 10);
+
+    crabstore.close();
+}
+
+#[test]
+fn create_index_then_range() {
+    let dir = tempdir().unwrap();
+    let mut index = Index::new(0, 3, &dir.path().join("index"));
+    assert_eq!(index.range_from_index(1, ..), None);
+
+    index.create_index(1);
+    for (rid, value) in [(0, 7), (1, 3), (2, 12), (3, 7), (4, 10)] {
+        index.update_index(1, value, RID::from(rid));
+    }
+
+    let rids: Vec<u64> = index
+        .range_from_index(1, 5..=10)
+        .unwrap()
+        .iter()
+        .map(|rid| rid.raw())
+        .collect();
+    assert_eq!(rids, [0, 3, 4]);
+
+    // Creating it again starts it over
+    index.create_index(1);
+    assert_eq!(index.range_from_index(1, ..), Some(Vec::new()));
+    assert_eq!(index.entry_count(1), Some(0));
+}
+
+#[test]
+fn building_an_index_twice_rebuilds_it() {
+    let dir = tempdir().unwrap();
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("Rebuilt", 2, 0);
+
+    for i in 0..100 {
+        table.insert_query(&[i, i % 10], None).unwrap();
+    }
+
+    table.build_index(1);
+    table.update_query(3, &[None, Some(4)], None).unwrap();
+    table.build_index(1);
+
+    let selected = table.select_query(3, 1, &[1, 0], None).unwrap();
+    assert_eq!(selected.len(), 9);
+
+    let selected = table.select_query(4, 1, &[1, 0], None).unwrap();
+    assert_eq!(selected.len(), 11);
+
+    crabstore.close();
+}
+
+#[test]
+fn rid_zero_round_trip() {
+    let dir = tempdir().unwrap();
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("Zero", 3, 0);
+
+    // All zero values on RID 0 look exactly like an unwritten slot did
+    table.insert_query(&[0, 0, 0], None).unwrap();
+    table.insert_query(&[1, 10, 100], None).unwrap();
+
+    let by_scan = table.select_query(0, 1, &[1, 1, 1], None).unwrap();
+    assert_eq!(regorganize_result(by_scan), [[0, 0, 0]]);
+
+    assert!(table.update_query(0, &[None, Some(7), None], None).unwrap());
+    let selected = table.select_query(0, 0, &[1, 1, 1], None).unwrap();
+    assert_eq!(regorganize_result(selected), [[0, 7, 0]]);
+    assert!(table
+        .select_query(0, 1, &[1, 1, 1], None)
+        .unwrap()
+        .is_empty());
+
+    let tails = &table.dump_range(0).unwrap().tail_pages;
+    assert_eq!(tails.len(), 1);
+    assert_eq!(tails[0].records.len(), 1);
+    assert_eq!(tails[0].records[0].base_rid.raw(), 0);
+
+    assert!(table.delete_query(0, None).unwrap());
+    assert!(table
+        .select_query(0, 0, &[1, 1, 1], None)
+        .unwrap()
+        .is_empty());
+    assert!(table
+        .select_query(7, 1, &[1, 1, 1], None)
+        .unwrap()
+        .is_empty());
+    assert_eq!(table.sum_query(0, 1, 2, None).unwrap(), 100);
+
+    table.insert_query(&[0, 1, 2], None).unwrap();
+    crabstore.close();
+
+    crabstore.open().unwrap();
+    let table = crabstore.get_table("Zero");
+
+    let selected = table.select_query(0, 0, &[1, 1, 1], None).unwrap();
+    assert_eq!(regorganize_result(selected), [[0, 1, 2]]);
+    assert_eq!(table.sum_query(0, 1, 2, None).unwrap(), 102);
+
+    crabstore.close();
+}
+
+#[test]
+fn readers_never_see_torn_updates() {
+    let num_records = 64;
+    let num_rounds = 200;
+
+    let dir = tempdir().unwrap();
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("Torn", 3, 0);
+
+    for key in 0..num_records {
+        table.insert_query(&[key, key, key * 2], None).unwrap();
+    }
+
+    table.build_index(1);
+
+    let done = Arc::new(AtomicBool::new(false));
+
+    let readers = (0..4)
+        .map(|seed| {
+            let table = Arc::clone(&table);
+            let done = Arc::clone(&done);
+            thread::spawn(move || {
+                let mut rand = StdRng::seed_from_u64(seed);
+                while !done.load(Ordering::Relaxed) {
+                    let key = rand.gen_range(0..num_records);
+                    for record in table.select_query(key, 0, &[1, 1, 1], None).unwrap() {
+                        assert_eq!(record.columns[0], key);
+                        assert_eq!(record.columns[2], record.columns[1] * 2);
+                    }
+
+                    let value = rand.gen_range(0..num_records * num_rounds);
+                    for record in table.select_query(value, 1, &[1, 1, 1], None).unwrap() {
+                        assert_eq!(record.columns[1], value);
+                        assert_eq!(record.columns[2], value * 2);
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for round in 1..num_rounds {
+        for key in 0..num_records {
+            let value = round * num_records + key;
+            table
+                .update_query(key, &[None, Some(value), Some(value * 2)], None)
+                .unwrap();
+        }
+    }
+
+    done.store(true, Ordering::Relaxed);
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    for key in 0..num_records {
+        let value = (num_rounds - 1) * num_records + key;
+        let selected = table.select_query(value, 1, &[1, 1, 1], None).unwrap();
+        assert_eq!(regorganize_result(selected), [[key, value, value * 2]]);
+        assert!(
+            table
+                .select_query(key, 1, &[1, 1, 1], None)
+                .unwrap()
+                .is_empty()
+                || key == value
+        );
+    }
+
+    crabstore.close();
+}
+
+#[test]
+fn readers_never_see_torn_inserts() {
+    let num_records = 4096;
+
+    let dir = tempdir().unwrap();
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("TornInsert", 4, 0);
+
+    let done = Arc::new(AtomicBool::new(false));
+
+    let readers = (0..4)
+        .map(|seed| {
+            let table = Arc::clone(&table);
+            let done = Arc::clone(&done);
+            thread::spawn(move || {
+                let mut rand = StdRng::seed_from_u64(seed);
+                while !done.load(Ordering::Relaxed) {
+                    let key = rand.gen_range(0..num_records);
+                    for record in table.select_query(key, 0, &[1, 1, 1, 1], None).unwrap() {
+                        assert_eq!(record.columns, [key, key * 2, key * 3, key + 7]);
+                    }
+
+                    // Unindexed column, so every visible slot is scanned
+                    let tag = rand.gen_range(0..16);
+                    for record in table.select_query(tag, 1, &[1, 1, 1, 1], None).unwrap() {
+                        let key = record.columns[0];
+                        assert_eq!(record.columns, [key, tag, key * 3, key + 7]);
+                        assert_eq!(key * 2, tag);
+                    }
+
+                    let start = rand.gen_range(0..num_records);
+                    let sum = table.sum_query(start, start + 8, 3, None).unwrap();
+                    let expected = (start..=start + 8)
+                        .filter(|key| *key < num_records)
+                        .map(|key| key + 7)
+                        .sum::<u64>();
+                    assert!(sum <= expected);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for key in 0..num_records {
+        table
+            .insert_query(&[key, key * 2, key * 3, key + 7], None)
+            .unwrap();
+    }
+
+    done.store(true, Ordering::Relaxed);
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    for key in 0..num_records {
+        let selected = table.select_query(key, 0, &[1, 1, 1, 1], None).unwrap();
+        assert_eq!(
+            regorganize_result(selected),
+            [[key, key * 2, key * 3, key + 7]]
+        );
+    }
+
+    crabstore.close();
+}
+
+#[test]
+fn query_budget_limits_scans() {
+    let num_records = 5000;
+
+    let dir = tempdir().unwrap();
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("Budget", 3, 0);
+
+    for i in 0..num_records {
+        table.insert_query(&[i, i % 10, i], None).unwrap();
+    }
+
+    let budget = |policy| QueryContext {
+        budget: Some(QueryBudget {
+            max_fetches: 64,
+            policy,
+        }),
+    };
+
+    // Column 1 has no index, so this reads every page of the table
+    fn full_scan(table: &Table) -> Result<Vec<Record>, CrabError> {
+        table.select_query(3, 1, &[1, 1, 1], None)
+    }
+
+    let strict = budget(BudgetPolicy::Fail);
+    assert!(matches!(
+        table.with_context(&strict, full_scan),
+        Err(CrabError::BudgetExceeded {
+            max_fetches: 64,
+            ..
+        })
+    ));
+
+    let point = table
+        .with_context(&strict, |t| t.select_query(1234, 0, &[1, 1, 1], None))
+        .unwrap();
+    assert_eq!(regorganize_result(point), [[1234, 4, 1234]]);
+
+    let unbudgeted = full_scan(&table).unwrap();
+    assert_eq!(unbudgeted.len(), (num_records / 10) as usize);
+    assert_eq!(
+        table
+            .with_context(&QueryContext::default(), full_scan)
+            .unwrap(),
+        unbudgeted
+    );
+    assert_eq!(
+        table
+            .with_context(&budget(BudgetPolicy::Degrade), full_scan)
+            .unwrap(),
+        unbudgeted
+    );
+
+    crabstore.close();
+}
+
+#[test]
+fn select_rids_then_materialize_matches_select() {
+    let num_records = 2000;
+
+    let dir = tempdir().unwrap();
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("TwoLevel", 4, 0);
+
+    for i in 0..num_records {
+        table.insert_query(&[i, i % 7, i * 3, i % 2], None).unwrap();
+    }
+
+    for i in (0..num_records).step_by(5) {
+        table
+            .update_query(i, &[None, Some(i % 3), None, Some(9)], None)
+            .unwrap();
+    }
+
+    for i in (0..num_records).step_by(11) {
+        table.delete_query(i, None).unwrap();
+    }
+
+    let check = |table: &Table| {
+        for (value, column) in [(3, 1), (1, 1), (9, 3), (600, 0), (num_records, 0)] {
+            for mask in [[1, 1, 1, 1], [0, 1, 0, 1], [0, 0, 0, 0]] {
+                let rids = table.select_rids(value, column).unwrap();
+                let two_level = table.materialize(&rids, &mask).unwrap();
+                let selected = table.select_query(value, column, &mask, None).unwrap();
+                assert_eq!(two_level, selected, "{value} in column {column}");
+            }
+        }
+    };
+
+    check(&table);
+
+    table.freeze().unwrap();
+    check(&table);
+    table.unfreeze();
+
+    crabstore.close();
+}
+
+#[test]
+fn deletes_leave_every_index() {
+    let dir = tempdir().unwrap();
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+
+    let table = crabstore.create_table("Deleted", 3, 0);
+    table.build_index(1);
+    for key in 0..1000 {
+        table.insert_query(&[key, key % 10, key * 2], None).unwrap();
+    }
+    // Deleted rows are dropped under their latest values, not the inserted ones
+    for key in (0..1000).step_by(4) {
+        table
+            .update_query(key, &[None, Some(10 + key % 10), None], None)
+            .unwrap();
+    }
+    for key in (0..1000).step_by(2) {
+        assert!(table.delete_query(key, None).unwrap());
+    }
+
+    let check = |index: &Index| {
+        assert_eq!(index.entry_count(0), Some(500));
+        assert_eq!(index.entry_count(1), Some(500));
+        assert_eq!(index.entry_count(2), None);
+        for (value, rid) in index.entries(1).unwrap() {
+            assert_eq!(value % 2, 1, "{rid:?}");
+        }
+    };
+
+    for value in (0..20).step_by(2) {
+        assert!(table
+            .select_query(value, 1, &[1, 1, 1], None)
+            .unwrap()
+            .is_empty());
+    }
+
+    crabstore.close();
+    check(&Index::load(&CrabStore::table_files(dir.path(), "Deleted").id_file, 3).unwrap());
+
+    // Nothing stale left over for opening to rebuild the key index over
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.get_table("Deleted");
+    assert!(table.ensure_primary_index().is_none());
+
+    for key in 0..1000 {
+        let selected = index_backed_select(&table, key).unwrap();
+        assert_eq!(selected.len() as u64, key % 2);
+    }
+
+    crabstore.close();
+}
+
+#[test]
+fn delete_returns_latest_version() {
+    let dir = tempdir().unwrap();
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+
+    let table = crabstore.create_table("Returning", 3, 0);
+    for key in 0..100 {
+        table.insert_query(&[key, key % 10, key * 2], None).unwrap();
+    }
+    for key in (0..100).step_by(3) {
+        table
+            .update_query(key, &[None, None, Some(key * 5)], None)
+            .unwrap();
+    }
+
+    for key in (0..100).step_by(2) {
+        let before = table.select_query(key, 0, &[1, 1, 1], None).unwrap();
+        let deleted = table.delete_returning_query(key, None).unwrap().unwrap();
+
+        assert_eq!(deleted, before[0]);
+        assert!(table
+            .select_query(key, 0, &[1, 1, 1], None)
+            .unwrap()
+            .is_empty());
+    }
+
+    // Gone already, or never there
+    assert!(table.delete_returning_query(0, None).unwrap().is_none());
+    assert!(table.delete_returning_query(100, None).unwrap().is_none());
+
+    crabstore.close();
+}
+
+#[test]
+fn batch_insert_reports_duplicates() {
+    let dir = tempdir().unwrap();
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+
+    let table = crabstore.create_table("Batch", 3, 0);
+    table.build_index(1);
+    table.insert_query(&[5, 0, 0], None).unwrap();
+
+    // Spills over several pages, and a page range, from an unaligned first RID
+    let mut rows = (0..9000)
+        .map(|key| vec![key, key % 10, key * 2])
+        .collect::<Vec<_>>();
+    rows.push(vec![7, 1, 1]);
+    let inserted = table.insert_many_query(&rows).unwrap();
+
+    let refused = inserted
+        .iter()
+        .enumerate()
+        .filter(|(_, inserted)| !**inserted)
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+    assert_eq!(refused, [5, 9000]);
+
+    assert_eq!(
+        regorganize_result(table.select_query(5, 0, &[1, 1, 1], None).unwrap()),
+        [[5, 0, 0]]
+    );
+    for key in [0, 7, 511, 512, 8191, 8192, 8999] {
+        let selected = index_backed_select(&table, key).unwrap();
+        assert_eq!(regorganize_result(selected), [[key, key % 10, key * 2]]);
+    }
+    assert_eq!(
+        table.select_query(3, 1, &[1, 0, 0], None).unwrap().len(),
+        900
+    );
+    // Key 5 kept the row it had before
+    assert_eq!(table.sum_query(0, 8999, 2, None).unwrap(), 8999 * 9000 - 10);
+
+    // Single inserts pick up where the batch left off
+    table.insert_query(&[9000, 0, 0], None).unwrap();
+    assert_eq!(table.storage_stats().unwrap().live_rows, 9001);
+    assert_eq!(table.insert_many_query(&[]).unwrap(), []);
+
+    crabstore.close();
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.get_table("Batch");
+    assert_eq!(table.sum_query(0, 9000, 0, None).unwrap(), 9000 * 9001 / 2);
+
+    crabstore.close();
+}
+
+#[test]
+fn rid_queries_skip_key_lookup() {
+    let dir = tempdir().unwrap();
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+
+    let table = crabstore.create_table("ByRid", 3, 0);
+    for key in 0..100 {
+        table.insert_query(&[key, key % 10, key * 2], None).unwrap();
+    }
+
+    let rid = table.select_rids(42, 0).unwrap()[0];
+    assert!(table
+        .update_by_rid(rid, &[None, Some(7), None], None)
+        .unwrap());
+
+    let by_rid = table.select_by_rid(rid, &[1, 1, 1]).unwrap().unwrap();
+    assert_eq!(by_rid.columns, [42, 7, 84]);
+    assert_eq!(
+        by_rid,
+        table.select_query(42, 0, &[1, 1, 1], None).unwrap()[0]
+    );
+
+    // Key constraints hold as for update_query
+    assert!(!table
+        .update_by_rid(rid, &[Some(41), None, None], None)
+        .unwrap());
+
+    // Tail RIDs aren't rows
+    let tail = table.get_latest(rid);
+    assert!(tail.is_tail());
+    assert!(table.select_by_rid(tail, &[1, 1, 1]).unwrap().is_none());
+    assert!(!table
+        .update_by_rid(tail, &[None, Some(8), None], None)
+        .unwrap());
+
+    // A stale RID stays dead, even once the key is back under a new one
+    table.delete_query(42, None).unwrap();
+    table.insert_query(&[42, 0, 0], None).unwrap();
+
+    assert!(table.select_by_rid(rid, &[1, 1, 1]).unwrap().is_none());
+    assert!(!table
+        .update_by_rid(rid, &[None, Some(9), None], None)
+        .unwrap());
+    assert_eq!(
+        regorganize_result(table.select_query(42, 0, &[1, 1, 1], None).unwrap()),
+        [[42, 0, 0]]
+    );
+    assert!(table
+        .select_by_rid(1000.into(), &[1, 1, 1])
+        .unwrap()
+        .is_none());
+
+    crabstore.close();
+}
+
+fn reopen_without_key_index(dir: &Path, num_records: u64) -> CrabStore {
+    let mut crabstore = CrabStore::new(dir.into());
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("Keyless", 3, 0);
+
+    for i in 0..num_records {
+        table.insert_query(&[i, i % 10, i * 2], None).unwrap();
+    }
+
+    table.drop_index(0);
+    crabstore.close();
+
+    let mut crabstore = CrabStore::new(dir.into());
+    crabstore.open().unwrap();
+    crabstore
+}
+
+// Few enough fetches that only an index lookup fits
+fn index_backed_select(table: &Table, key: u64) -> Result<Vec<Record>, CrabError> {
+    let context = QueryContext {
+        budget: Some(QueryBudget {
+            max_fetches: 64,
+            policy: BudgetPolicy::Fail,
+        }),
+    };
+
+    table.with_context(&context, |t| t.select_query(key, 0, &[1, 1, 1], None))
+}
+
+#[test]
+fn cleared_key_index_rebuilt_on_open() {
+    let num_records = 5000;
+    let dir = tempdir().unwrap();
+
+    let mut crabstore = reopen_without_key_index(dir.path(), num_records);
+    let table = crabstore.get_table("Keyless");
+
+    assert_eq!(table.indexed_columns(), [0]);
+    assert!(table.ensure_primary_index().is_none());
+
+    for key in [0, 1234, num_records - 1] {
+        let selected = index_backed_select(&table, key).unwrap();
+        assert_eq!(regorganize_result(selected), [[key, key % 10, key * 2]]);
+    }
+
+    crabstore.close();
+}
+
+#[test]
+fn large_key_index_rebuilt_in_background() {
+    let num_records = 80_000;
+    let dir = tempdir().unwrap();
+
+    let mut crabstore = reopen_without_key_index(dir.path(), num_records);
+    let table = crabstore.get_table("Keyless");
+    let mut rand = StdRng::seed_from_u64(3562901);
+
+    // Point selects are answered by scanning until the rebuild catches up
+    loop {
+        let key = rand.gen_range(0..num_records);
+        let selected = table.select_query(key, 0, &[1, 1, 1], None).unwrap();
+        assert_eq!(regorganize_result(selected), [[key, key % 10, key * 2]]);
+
+        if index_backed_select(&table, key).is_ok() {
+            break;
+        }
+
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    // Inserts made during the rebuild went into the index too
+    table.insert_query(&[num_records, 0, 0], None).unwrap();
+    table.quiesce(Duration::from_secs(30)).unwrap();
+    assert!(table.ensure_primary_index().is_none());
+
+    for key in [0, 40_000, num_records] {
+        assert_eq!(index_backed_select(&table, key).unwrap().len(), 1);
+    }
+
+    crabstore.close();
+}
+
+#[test]
+fn keyless_scan_skips_deleted_rows() {
+    let dir = tempdir().unwrap();
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+
+    let table = crabstore.create_table("Scanned", 3, 0);
+    for key in 0..100 {
+        table.insert_query(&[key, key % 10, key * 2], None).unwrap();
+    }
+    table.delete_query(5, None).unwrap();
+    table.drop_index(0);
+
+    // Every lookup below scans past the deleted row, a stalled scan never answers
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn({
+        let table = table.clone();
+        move || {
+            let missing = table.update_query(1000, &[None, Some(1), None], None);
+            let present = table.update_query(50, &[None, Some(1), None], None);
+            table.build_index(0);
+            sender.send((missing, present)).unwrap();
+        }
+    });
+
+    let (missing, present) = receiver.recv_timeout(Duration::from_secs(30)).unwrap();
+    assert!(!missing.unwrap());
+    assert!(present.unwrap());
+
+    assert!(table
+        .select_query(5, 0, &[1, 1, 1], None)
+        .unwrap()
+        .is_empty());
+    let selected = index_backed_select(&table, 50).unwrap();
+    assert_eq!(regorganize_result(selected), [[50, 1, 100]]);
+
+    crabstore.close();
+}
+
+/*
+    Inserters race to allocate new page ranges while updaters allocate tail
+    pages in older ones, all of it contending on the page directory
+*/
+#[bench]
+fn concurrent_insert_bench(b: &mut Bencher) {
+    let num_threads = 4;
+    let per_thread = 8 * 8192;
+
+    b.iter(|| {
+        let dir = tempdir().unwrap();
+
+        let mut crabstore = CrabStore::new(dir.path().into());
+        crabstore.open().unwrap();
+        let table = crabstore.create_table("Ranges", 3, 0);
+
+        let writers = (0..num_threads)
+            .map(|thread| {
+                let table = Arc::clone(&table);
+                thread::spawn(move || {
+                    for i in 0..per_thread {
+                        let key = i * num_threads + thread;
+                        table.insert_query(&[key, i, i], None).unwrap();
+
+                        if i % 8 == 0 && key >= 8192 {
+                            table
+                                .update_query(key - 8192, &[None, Some(i), None], None)
+                                .unwrap();
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        crabstore.close();
+    });
+}
+
+/*
+    Point selects from several threads over a table small enough to stay
+    cached, so they only contend on the bufferpool. With the same work per
+    thread, an iteration should take about as long with 4 threads as with 1
+*/
+fn concurrent_selects(b: &mut Bencher, num_threads: u64) {
+    let rows = 8192;
+    let per_thread = 20_000;
+
+    let dir = tempdir().unwrap();
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("Reads", 3, 0);
+    for key in 0..rows {
+        table.insert_query(&[key, key, key], None).unwrap();
+    }
+
+    b.iter(|| {
+        let readers = (0..num_threads)
+            .map(|thread| {
+                let table = Arc::clone(&table);
+                thread::spawn(move || {
+                    let mut rng = StdRng::seed_from_u64(thread);
+                    for _ in 0..per_thread {
+                        let key = rng.gen_range(0..rows);
+                        table.select_query(key, 0, &[1, 1, 1], None).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for reader in readers {
+            reader.join().unwrap();
+        }
+    });
+
+    crabstore.close();
+}
+
+#[bench]
+fn select_bench_1_thread(b: &mut Bencher) {
+    concurrent_selects(b, 1);
+}
+
+#[bench]
+fn select_bench_4_threads(b: &mut Bencher) {
+    concurrent_selects(b, 4);
+}
+
+const BULK_ROWS: u64 = 100_000;
+
+#[bench]
+fn single_insert_bench(b: &mut Bencher) {
+    b.iter(|| {
+        let dir = tempdir().unwrap();
+
+        let mut crabstore = CrabStore::new(dir.path().into());
+        crabstore.open().unwrap();
+        let table = crabstore.create_table("Bulk", 3, 0);
+
+        for key in 0..BULK_ROWS {
+            table.insert_query(&[key, key % 10, key], None).unwrap();
+        }
+
+        crabstore.close();
+    });
+}
+
+#[bench]
+fn batch_insert_bench(b: &mut Bencher) {
+    let rows = (0..BULK_ROWS)
+        .map(|key| vec![key, key % 10, key])
+        .collect::<Vec<_>>();
+
+    b.iter(|| {
+        let dir = tempdir().unwrap();
+
+        let mut crabstore = CrabStore::new(dir.path().into());
+        crabstore.open().unwrap();
+        let table = crabstore.create_table("Bulk", 3, 0);
+
+        for batch in rows.chunks(8192) {
+            table.insert_many_query(batch).unwrap();
+        }
+
+        crabstore.close();
+    });
+}
+
+#[bench]
+fn close_dirty_pages_bench(b: &mut Bencher) {
+    // 256 base pages of 32 columns plus metadata, about 10k column pages to write back
+    let columns = 32;
+    let rows = (0..512 * 256)
+        .map(|key| vec![key; columns])
+        .collect::<Vec<_>>();
+
+    b.iter(|| {
+        let dir = tempdir().unwrap();
+
+        let mut crabstore = CrabStore::new(dir.path().into());
+        crabstore.open().unwrap();
+        let table = crabstore.create_table("Wide", columns, 0);
+
+        for batch in rows.chunks(8192) {
+            table.insert_many_query(batch).unwrap();
+        }
+
+        crabstore.close();
+    });
+}
@@ -0,0 +1,184 @@
+use crabcore::{
+    crabstore::CrabStore,
+    merge::MergeStats,
+    table::{AccessPath, Table, WriteStats},
+};
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+use tempfile::tempdir;
+
+fn wait_for_merge(table: &Table) -> MergeStats {
+    let start = Instant::now();
+
+    while table.merge_stats().merges == 0 {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "no merge ran after filling the tails"
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    table.merge_stats()
+}
+
+#[test]
+fn explain_follows_indexes() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 3, 0);
+    for key in 0..1000 {
+        table.insert_query(&[key, key % 10, key], None).unwrap();
+    }
+
+    let plan = table.explain(0, 5);
+    assert_eq!((plan.access, plan.estimated_rows), (AccessPath::Index, 1.0));
+    assert_eq!(table.explain(1, 5).access, AccessPath::Scan);
+
+    table.build_index(1);
+    let plan = table.explain(1, 5);
+    assert_eq!(
+        (plan.access, plan.estimated_rows),
+        (AccessPath::Index, 100.0)
+    );
+
+    table.freeze().unwrap();
+    assert_eq!(table.access_path(1), AccessPath::Frozen);
+    table.unfreeze();
+
+    db.close();
+}
+
+#[test]
+fn slow_queries_keep_the_most_recent() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 2, 0);
+    for key in 0..10 {
+        table.insert_query(&[key, key], None).unwrap();
+    }
+
+    // Nothing this small takes the default threshold
+    assert!(table.slow_queries().is_empty());
+
+    table.set_slow_query_threshold(Duration::ZERO);
+    for key in 0..1000 {
+        table.select_query(key % 10, 0, &[1, 1], None).unwrap();
+    }
+    table.sum_query(0, 9, 1, None).unwrap();
+
+    let slow = table.slow_queries();
+    assert!(!slow.is_empty() && slow.len() < 1000);
+    assert_eq!(slow.last().unwrap().query, "sum_query");
+    assert!(slow[..slow.len() - 1]
+        .iter()
+        .all(|query| query.query == "select_query"));
+
+    table.reset_stats();
+    assert!(table.slow_queries().is_empty());
+
+    db.close();
+}
+
+#[test]
+fn merge_and_bufferpool_stats() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 3, 0);
+    for key in 0..100 {
+        table.insert_query(&[key, 0, 0], None).unwrap();
+    }
+    assert_eq!(table.merge_stats(), MergeStats::default());
+
+    // Enough versions of the same rows to fill several tail pages of the range
+    for round in 0..50 {
+        for key in 0..100 {
+            table
+                .update_query(key, &[None, Some(round), None], None)
+                .unwrap();
+        }
+    }
+
+    // Each merge takes only the newest of a row's tail records
+    let merged = wait_for_merge(&table);
+    assert!(merged.tail_records > 0 && merged.tail_records <= 100 * merged.merges);
+    assert!(merged.base_pages > 0);
+
+    assert!(table.bufferpool_stats().requests > 0);
+
+    table.reset_stats();
+    assert_eq!(table.merge_stats(), MergeStats::default());
+    assert_eq!(table.bufferpool_stats().requests, 0);
+
+    db.close();
+}
+
+#[test]
+fn chain_histogram_and_write_amplification() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 3, 0);
+    for key in 0..100 {
+        table.insert_query(&[key, 0, 0], None).unwrap();
+    }
+    assert_eq!(table.chain_histogram().unwrap(), [(0, 100)]);
+
+    // Every column but the page header, 5 metadata, 3 values and the timestamp less 1
+    let inserted = table.write_stats();
+    assert_eq!(inserted.rows_modified, 100);
+    assert_eq!(inserted.amplification(), 8.0);
+
+    for round in 0..3 {
+        for key in 0..100 {
+            table
+                .update_query(key, &[None, Some(round), None], None)
+                .unwrap();
+        }
+    }
+    assert_eq!(table.chain_histogram().unwrap(), [(3, 100)]);
+
+    // Tail records write the same slots, plus the base record's indirection
+    let updated = table.write_stats();
+    assert_eq!(updated.rows_modified, 400);
+    assert_eq!(updated.slots_written, 100 * 8 + 300 * 9);
+
+    table.delete_query(0, None).unwrap();
+    assert_eq!(table.chain_histogram().unwrap(), [(3, 99)]);
+    assert_eq!(table.write_stats().slots_written, updated.slots_written + 4);
+
+    for round in 0..50 {
+        for key in 1..100 {
+            table
+                .update_query(key, &[None, Some(round), None], None)
+                .unwrap();
+        }
+    }
+    let merged = wait_for_merge(&table);
+
+    // Only the tail records written since the merge started are left in the chains
+    let histogram = table.chain_histogram().unwrap();
+    assert_eq!(histogram.iter().map(|(_, rows)| rows).sum::<usize>(), 99);
+    assert!(histogram.last().unwrap().0 < 53, "{histogram:?}");
+
+    // Another merge may have finished since
+    assert!(merged.slots_written > 0);
+    assert!(
+        table.write_stats().slots_written
+            >= updated.slots_written + 4 + 50 * 99 * 9 + merged.slots_written
+    );
+
+    table.reset_stats();
+    assert_eq!(table.write_stats(), WriteStats::default());
+    assert_eq!(table.write_stats().amplification(), 0.0);
+
+    db.close();
+}
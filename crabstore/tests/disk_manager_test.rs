@@ -0,0 +1,100 @@
+use crabcore::disk_manager::DiskManager;
+use std::fs;
+use tempfile::tempdir;
+
+const PAGE_SIZE: usize = 4096;
+
+#[test]
+fn pages_read_back_as_written() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("pages.CRAB");
+    let disk = DiskManager::new(&path).unwrap();
+
+    let mut written = [0; PAGE_SIZE];
+    for (i, byte) in written.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    assert_eq!(disk.write_page(2, &written).unwrap(), PAGE_SIZE);
+    disk.flush().unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().len(), 3 * PAGE_SIZE as u64);
+
+    let mut page = [0xff; PAGE_SIZE];
+    assert_eq!(disk.read_page(2, &mut page).unwrap(), PAGE_SIZE);
+    assert_eq!(page, written);
+
+    // The gap before it reads as zeros, and so does a reader sharing the file
+    assert_eq!(disk.read_page(1, &mut page).unwrap(), PAGE_SIZE);
+    assert_eq!(page, [0; PAGE_SIZE]);
+
+    let reader = DiskManager::open_read_only(&path).unwrap();
+    assert_eq!(reader.read_page(2, &mut page).unwrap(), PAGE_SIZE);
+    assert_eq!(page, written);
+    assert!(reader.write_page(0, &written).is_err());
+}
+
+#[test]
+fn reads_past_the_end_are_zeroed() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("pages.CRAB");
+    let disk = DiskManager::new(&path).unwrap();
+
+    disk.write_page(0, &[7; PAGE_SIZE]).unwrap();
+    disk.write_page(1, &[9; PAGE_SIZE]).unwrap();
+    disk.flush().unwrap();
+
+    // As a crash part way through extending the file would leave it
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_len((PAGE_SIZE + PAGE_SIZE / 2) as u64)
+        .unwrap();
+
+    let mut page = [0xff; PAGE_SIZE];
+    assert_eq!(disk.read_page(1, &mut page).unwrap(), PAGE_SIZE / 2);
+    assert!(page[..PAGE_SIZE / 2].iter().all(|byte| *byte == 9));
+    assert!(page[PAGE_SIZE / 2..].iter().all(|byte| *byte == 0));
+
+    let mut page = [0xff; PAGE_SIZE];
+    assert_eq!(disk.read_page(5, &mut page).unwrap(), 0);
+    assert_eq!(page, [0; PAGE_SIZE]);
+}
+
+#[test]
+fn file_grows_in_extents() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("pages.CRAB");
+    let disk = DiskManager::with_extent_size(&path, 16 * PAGE_SIZE).unwrap();
+    let file_len = || fs::metadata(&path).unwrap().len() as usize;
+
+    // Page 0 is the header's, so pages 1 to 20 take a second extent
+    assert_eq!(disk.reserve_page(), 1);
+    assert_eq!(file_len(), 16 * PAGE_SIZE);
+    assert_eq!(disk.reserve_range(14), 2);
+    assert_eq!(file_len(), 16 * PAGE_SIZE);
+    assert_eq!(disk.reserve_range(5), 16);
+    assert_eq!(file_len(), 32 * PAGE_SIZE);
+    assert_eq!(disk.allocated_pages(), 32);
+
+    // Allocated but never written, the page reads as zeros from the file itself
+    disk.write_page(16, &[3; PAGE_SIZE]).unwrap();
+    let mut page = [0xff; PAGE_SIZE];
+    assert_eq!(disk.read_page(20, &mut page).unwrap(), PAGE_SIZE);
+    assert_eq!(page, [0; PAGE_SIZE]);
+
+    // Trimmed back to the reserved pages, written or not
+    disk.trim().unwrap();
+    assert_eq!(file_len(), 21 * PAGE_SIZE);
+    assert_eq!(disk.read_page(16, &mut page).unwrap(), PAGE_SIZE);
+    assert_eq!(page, [3; PAGE_SIZE]);
+
+    // A file already longer than the next extent is never cut short
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_len(100 * PAGE_SIZE as u64)
+        .unwrap();
+    disk.reserve_range(20);
+    assert_eq!(file_len(), 100 * PAGE_SIZE);
+}
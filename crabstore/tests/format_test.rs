@@ -0,0 +1,219 @@
+use crabcore::{
+    config::CrabConfig, crabstore::CrabStore, error::CrabError, format, schema::ColumnType,
+};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use tempfile::tempdir;
+
+/*
+    Written by format::write_fixture at that FORMAT_VERSION, on a little-endian
+    machine, allocating base pages one at a time so they stay small. Never
+    regenerate these; a new format version gets a new fixture directory next
+    to them.
+*/
+fn fixture_dir(version: u32) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join(format!("tests/fixtures/v{version}"))
+}
+
+fn copy_dir(from: &Path, to: &Path) {
+    for entry in fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        fs::copy(entry.path(), to.join(entry.file_name())).unwrap();
+    }
+}
+
+fn check_fixture(dir: &Path, version: u32) {
+    let mut crabstore = CrabStore::new(dir.into());
+    crabstore.open().unwrap();
+    let table = crabstore.get_table("Fixture");
+
+    assert_eq!(table.columns(), 3);
+
+    // Unique indexes came with version 11
+    match version {
+        ..=10 => {
+            assert_eq!(table.indexed_columns(), [0, 1]);
+            assert!(table.unique_columns().is_empty());
+        }
+        _ => {
+            assert_eq!(table.indexed_columns(), [0, 1, 2]);
+            assert_eq!(table.unique_columns(), [2]);
+        }
+    }
+
+    // Names came with version 4, older tables stay without them
+    if version >= 4 {
+        assert_eq!(table.column_names(), ["key", "grade", "score"]);
+        assert_eq!(table.column_index("grade"), Some(1));
+    } else {
+        assert!(table.column_names().is_empty());
+    }
+
+    let score_type = match version {
+        ..=4 => ColumnType::Unsigned,
+        _ => ColumnType::Signed,
+    };
+    assert_eq!(
+        table.column_types(),
+        [ColumnType::Unsigned, ColumnType::Unsigned, score_type]
+    );
+
+    // NULLs came with version 6
+    assert_eq!(table.is_nullable(), version >= 6);
+    if version >= 6 {
+        assert_eq!(
+            table.select_query(3, 0, &[1, 1, 1], None).unwrap()[0].values(),
+            [Some(3), None, Some(9)]
+        );
+        assert_eq!(
+            table.select_query(600, 0, &[0, 1, 1], None).unwrap()[0].values(),
+            [Some(1), None]
+        );
+    }
+
+    // Timestamps came with version 7. The fixture's last writes are key 600's insert, then 42's update
+    assert_eq!(table.has_timestamps(), version >= 7);
+    if version >= 7 {
+        let now = table.current_timestamp();
+        let as_of = |key, timestamp| {
+            table
+                .select_as_of_query(key, 0, &[1, 1, 1], timestamp)
+                .unwrap()
+                .into_iter()
+                .map(|record| (record.columns, record.timestamp))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(as_of(42, now), [(vec![42, 100, 126], now)]);
+        assert_eq!(as_of(42, now - 1), [(vec![42, 0, 126], 43)]);
+        assert_eq!(as_of(600, now - 1), [(vec![600, 1, 0], now - 1)]);
+        assert!(as_of(600, now - 2).is_empty());
+    } else {
+        assert_eq!(
+            table.select_as_of_query(42, 0, &[1, 1, 1], u64::MAX),
+            Err(CrabError::NoTimestamps)
+        );
+    }
+
+    let select = |key| {
+        table
+            .select_query(key, 0, &[1, 1, 1], None)
+            .unwrap()
+            .into_iter()
+            .map(|record| record.columns)
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(select(10), [[10, 3, 31]]);
+    assert_eq!(select(11), [[11, 4, 33]]);
+    assert_eq!(select(42), [[42, 100, 126]]);
+    assert_eq!(select(598), [[598, 3, 1794]]);
+    assert!(select(599).is_empty());
+
+    let by_secondary = table.select_query(100, 1, &[1, 0, 0], None).unwrap();
+    assert_eq!(by_secondary.len(), 1);
+    assert_eq!(by_secondary[0].columns, [42]);
+
+    // Keys 0 and 5 were bumped by one
+    assert_eq!(table.sum_query(0, 9, 2, None).unwrap(), 3 * 45 + 2);
+
+    // Key 42 holds this score
+    if version >= 11 {
+        table.insert_query(&[700, 0, 126], None).unwrap();
+        assert!(select(700).is_empty());
+    }
+
+    // Composite indexes came with version 12
+    match version {
+        ..=11 => assert!(table.composite_indexes().is_empty()),
+        _ => assert_eq!(table.composite_indexes(), [[1, 2]]),
+    }
+    let select_multi = |filters: &[(usize, u64)]| {
+        table
+            .select_multi_query(filters, &[1, 0, 0], None)
+            .unwrap()
+            .into_iter()
+            .map(|record| record.columns[0])
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(select_multi(&[(1, 4), (2, 33)]), [11]);
+    assert_eq!(select_multi(&[(1, 100)]), [42]);
+
+    crabstore.close();
+}
+
+#[test]
+fn fixture_opens_with_known_results() {
+    for version in 1..=format::FORMAT_VERSION {
+        let dir = tempdir().unwrap();
+        copy_dir(&fixture_dir(version), dir.path());
+
+        check_fixture(dir.path(), version);
+
+        // Rewritten in the current format, and still the same database
+        check_fixture(dir.path(), version);
+    }
+}
+
+/*
+    The table file as a big-endian machine would have written it before version 2,
+    every slot of every page but the header byte-swapped
+*/
+fn swap_table_pages(path: &Path) {
+    let mut bytes = fs::read(path).unwrap();
+
+    for slot in bytes[4096..].chunks_exact_mut(8) {
+        slot.reverse();
+    }
+
+    fs::write(path, bytes).unwrap();
+}
+
+#[test]
+fn big_endian_pages_are_converted() {
+    let dir = tempdir().unwrap();
+    copy_dir(&fixture_dir(1), dir.path());
+    swap_table_pages(&dir.path().join("Fixture_db.CRAB"));
+
+    // A reader can't rewrite the pages
+    let mut reader = CrabStore::with_config(
+        dir.path().into(),
+        CrabConfig {
+            read_only: true,
+            ..Default::default()
+        },
+    );
+    assert!(matches!(reader.open(), Err(CrabError::CorruptFile { .. })));
+
+    check_fixture(dir.path(), 1);
+
+    // Converted in place the first time, so nothing is swapped back on the next open
+    check_fixture(dir.path(), 1);
+}
+
+#[test]
+fn fresh_fixture_matches_known_results() {
+    let dir = tempdir().unwrap();
+    format::write_fixture(dir.path());
+
+    check_fixture(dir.path(), format::FORMAT_VERSION);
+}
+
+/*
+    Run with --ignored when a new format version needs its own fixture
+*/
+#[test]
+#[ignore]
+fn write_new_fixture() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join(format!("tests/fixtures/v{}", format::FORMAT_VERSION));
+
+    assert!(
+        !dir.exists(),
+        "{dir:?} already exists, fixtures are never rewritten"
+    );
+    fs::create_dir_all(&dir).unwrap();
+    format::write_fixture(&dir);
+}
@@ -0,0 +1,163 @@
+#![feature(test)]
+extern crate test;
+use crabcore::{crabstore::CrabStore, error::CrabError, table::Table, transaction::Transaction};
+use rand::prelude::*;
+use std::sync::Arc;
+use tempfile::tempdir;
+use test::Bencher;
+
+const NUMBER_OF_RECORDS: u64 = 10000;
+
+fn populate(table: &Table) {
+    for i in 0..NUMBER_OF_RECORDS {
+        table.insert_query(&[i, i % 10, i * 2], None).unwrap();
+    }
+
+    for i in (0..NUMBER_OF_RECORDS).step_by(3) {
+        table
+            .update_query(i, &[None, None, Some(i * 3)], None)
+            .unwrap();
+    }
+
+    for i in (0..NUMBER_OF_RECORDS).step_by(7) {
+        table.delete_query(i, None).unwrap();
+    }
+}
+
+fn snapshot(table: &Table) -> (Vec<Vec<u64>>, Vec<u64>, Vec<usize>) {
+    let rows = (0..NUMBER_OF_RECORDS)
+        .flat_map(|key| table.select_query(key, 0, &[1, 1, 1], None).unwrap())
+        .map(|record| record.columns)
+        .collect();
+
+    let sums = (0..3)
+        .map(|column| {
+            table
+                .sum_query(100, NUMBER_OF_RECORDS - 100, column, None)
+                .unwrap()
+        })
+        .collect();
+
+    // Column 1 is not indexed, so this goes through the scan path
+    let matches = (0..10)
+        .map(|value| {
+            table
+                .select_query(value, 1, &[1, 0, 0], None)
+                .unwrap()
+                .len()
+        })
+        .collect();
+
+    (rows, sums, matches)
+}
+
+#[test]
+fn freeze_round_trip() {
+    let dir = tempdir().unwrap();
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("Frozen", 3, 0);
+
+    populate(&table);
+    let before = snapshot(&table);
+
+    table.freeze().unwrap();
+    assert!(table.is_frozen());
+    assert_eq!(snapshot(&table), before);
+
+    assert_eq!(
+        table.insert_query(&[NUMBER_OF_RECORDS, 0, 0], None),
+        Err(CrabError::TableFrozen)
+    );
+    assert_eq!(
+        table.update_query(1, &[None, Some(5), None], None),
+        Err(CrabError::TableFrozen)
+    );
+    assert_eq!(table.delete_query(1, None), Err(CrabError::TableFrozen));
+    assert_eq!(snapshot(&table), before);
+
+    table.unfreeze();
+    assert!(!table.is_frozen());
+    assert_eq!(snapshot(&table), before);
+
+    table
+        .insert_query(&[NUMBER_OF_RECORDS, 0, 0], None)
+        .unwrap();
+    table.update_query(1, &[None, Some(5), None], None).unwrap();
+    table.delete_query(2, None).unwrap();
+    let after = snapshot(&table);
+    assert_ne!(after, before);
+
+    // Freezing again must pick up the writes made while unfrozen
+    table.freeze().unwrap();
+    assert_eq!(snapshot(&table), after);
+    table.unfreeze();
+
+    crabstore.close();
+}
+
+#[test]
+fn freeze_refuses_open_transactions() {
+    let dir = tempdir().unwrap();
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("Frozen", 3, 0);
+
+    populate(&table);
+    let before = snapshot(&table);
+
+    // Its write isn't committed yet, a snapshot taken now would keep it after the abort
+    let mut transaction = Transaction::begin();
+    assert!(transaction
+        .update(&table, 1, &[None, Some(5), None])
+        .unwrap());
+
+    assert_eq!(table.freeze(), Err(CrabError::TableInUse));
+    assert!(!table.is_frozen());
+
+    transaction.abort();
+
+    table.freeze().unwrap();
+    assert_eq!(snapshot(&table), before);
+    table.unfreeze();
+
+    crabstore.close();
+}
+
+fn bench_point_selects(b: &mut Bencher, freeze: bool) {
+    let dir = tempdir().unwrap();
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table: Arc<Table> = crabstore.create_table("Bench", 3, 0);
+
+    populate(&table);
+
+    if freeze {
+        table.freeze().unwrap();
+    }
+
+    let mut rand = StdRng::seed_from_u64(3562901);
+    let keys = (0..NUMBER_OF_RECORDS).choose_multiple(&mut rand, 1000);
+
+    b.iter(|| {
+        for key in keys.iter() {
+            test::black_box(table.select_query(*key, 0, &[1, 1, 1], None).unwrap());
+        }
+        test::black_box(table.sum_query(0, NUMBER_OF_RECORDS, 2, None).unwrap());
+    });
+
+    crabstore.close();
+}
+
+#[bench]
+fn select_bench(b: &mut Bencher) {
+    bench_point_selects(b, false);
+}
+
+#[bench]
+fn frozen_select_bench(b: &mut Bencher) {
+    bench_point_selects(b, true);
+}
@@ -0,0 +1,82 @@
+use crabcore::{config::CrabConfig, crabstore::CrabStore};
+use std::{path::Path, time::Duration};
+use tempfile::tempdir;
+
+const ROWS: u64 = 4096;
+
+fn open(dir: &Path, config: CrabConfig) -> CrabStore {
+    let mut db = CrabStore::with_config(dir.into(), config);
+    db.open().unwrap();
+    db
+}
+
+#[test]
+fn tables_keep_their_own_geometry() {
+    let small_dir = tempdir().unwrap();
+    let large_dir = tempdir().unwrap();
+
+    // 1024 rows per page range here, merged once a single tail page fills
+    let mut small = open(
+        small_dir.path(),
+        CrabConfig {
+            bufferpool_frames: 32,
+            page_range_pages: 2,
+            merge_threshold: 1,
+            ..CrabConfig::default()
+        },
+    );
+    let mut large = open(
+        large_dir.path(),
+        CrabConfig {
+            bufferpool_frames: 512,
+            page_range_pages: 32,
+            ..CrabConfig::default()
+        },
+    );
+
+    let tables = [
+        small.create_table("Grades", 3, 0),
+        large.create_table("Grades", 3, 0),
+    ];
+    for table in &tables {
+        for key in 0..ROWS {
+            table.insert_query(&[key, key % 7, 1], None).unwrap();
+        }
+        // Fills the first tail page of the small table's first range, and then some
+        for key in 0..600 {
+            table
+                .update_query(key, &[None, None, Some(2)], None)
+                .unwrap();
+        }
+    }
+    small.quiesce_all(Duration::from_secs(10)).unwrap();
+    large.quiesce_all(Duration::from_secs(10)).unwrap();
+
+    assert_eq!(tables[0].get_bufferpool().size(), 32);
+    assert_eq!(tables[1].get_bufferpool().size(), 512);
+    assert_eq!(tables[0].merge_stats().merges, 1);
+    assert_eq!(tables[1].merge_stats().merges, 0);
+    for table in &tables {
+        assert_eq!(table.sum_query(0, ROWS - 1, 2, None).unwrap(), ROWS + 600);
+    }
+
+    drop(tables);
+    small.close();
+    large.close();
+
+    // The page ranges are the table's for good, the bufferpool is whatever it's opened with
+    for (dir, range_pages) in [(small_dir.path(), 2), (large_dir.path(), 32)] {
+        let mut db = open(dir, CrabConfig::default());
+        let table = db.get_table("Grades");
+
+        assert_eq!(table.page_range_pages(), range_pages);
+        assert_eq!(table.get_bufferpool().size(), 256);
+        assert_eq!(table.sum_query(0, ROWS - 1, 2, None).unwrap(), ROWS + 600);
+
+        table
+            .update_query(ROWS - 1, &[None, None, Some(3)], None)
+            .unwrap();
+        assert_eq!(table.sum_query(0, ROWS - 1, 2, None).unwrap(), ROWS + 602);
+        db.close();
+    }
+}
@@ -0,0 +1,696 @@
+use crabcore::{
+    bufferpool::{BudgetPolicy, QueryBudget},
+    crabstore::CrabStore,
+    error::CrabError,
+    index::{Index, RecordChange},
+    rid::RID,
+    schema::ColumnType,
+    table::{QueryContext, Table, UpdateOp},
+    transaction::{QueryStatus, Transaction},
+};
+use rand::prelude::*;
+use std::{sync::Barrier, thread};
+use tempfile::tempdir;
+
+fn rids(index: &Index, column: usize, value: u64) -> Vec<u64> {
+    let mut rids: Vec<u64> = index
+        .get_from_index(column, value)
+        .unwrap()
+        .iter()
+        .map(|rid| rid.raw())
+        .collect();
+    rids.sort();
+    rids
+}
+
+#[test]
+fn apply_follows_each_change() {
+    let dir = tempdir().unwrap();
+    let mut index = Index::new(0, 3, &dir.path().join("index"));
+    index.create_index(2);
+
+    index.apply(&RecordChange::Insert {
+        rid: RID::from(0),
+        values: &[10, 1, 5],
+        nulls: 0,
+    });
+    // NULLs aren't indexed
+    index.apply(&RecordChange::Insert {
+        rid: RID::from(1),
+        values: &[11, 1, 0],
+        nulls: 0b100,
+    });
+    assert_eq!(rids(&index, 0, 10), [0]);
+    assert_eq!(index.get_from_index(1, 1), None);
+    assert_eq!(rids(&index, 2, 5), [0]);
+    assert_eq!(index.entry_count(2), Some(1));
+
+    // Column 2 isn't in the schema encoding, so its new value isn't looked at
+    index.apply(&RecordChange::Update {
+        rid: RID::from(0),
+        schema_encoding: 0b010,
+        old: &[10, 1, 5],
+        old_nulls: 0,
+        new: &[10, 2, 6],
+        new_nulls: 0,
+    });
+    assert_eq!(rids(&index, 2, 5), [0]);
+
+    index.apply(&RecordChange::Update {
+        rid: RID::from(1),
+        schema_encoding: 0b100,
+        old: &[11, 1, 0],
+        old_nulls: 0b100,
+        new: &[11, 1, 5],
+        new_nulls: 0,
+    });
+    assert_eq!(rids(&index, 2, 5), [0, 1]);
+
+    index.apply(&RecordChange::Update {
+        rid: RID::from(0),
+        schema_encoding: 0b100,
+        old: &[10, 1, 5],
+        old_nulls: 0,
+        new: &[10, 1, 0],
+        new_nulls: 0b100,
+    });
+    assert_eq!(rids(&index, 2, 5), [1]);
+    assert_eq!(index.entry_count(2), Some(1));
+
+    index.apply(&RecordChange::Delete {
+        rid: RID::from(1),
+        values: &[11, 1, 5],
+        nulls: 0,
+    });
+    assert!(rids(&index, 0, 11).is_empty());
+    assert!(rids(&index, 2, 5).is_empty());
+    assert_eq!(index.entry_count(0), Some(1));
+    assert_eq!(index.entry_count(2), Some(0));
+}
+
+/*
+    What every index of the table should hold, worked out from its rows. Rows
+    are told apart by key, a scan returns the RIDs of their latest versions
+*/
+fn scanned_entries(table: &Table, column: usize) -> Vec<(u64, u64)> {
+    let mut entries: Vec<(u64, u64)> = table
+        .scan()
+        .map(|record| record.unwrap())
+        .filter_map(|record| record.value(column).map(|value| (value, record.columns[0])))
+        .collect();
+    entries.sort();
+    entries
+}
+
+fn index_entries(table: &Table, index: &Index, column: usize) -> Vec<(u64, u64)> {
+    let mut entries: Vec<(u64, u64)> = index
+        .entries(column)
+        .unwrap()
+        .map(|(value, rid)| {
+            // An entry left behind for a deleted row can't match any key
+            let key = table
+                .select_by_rid(rid, &[1, 0, 0, 0])
+                .unwrap()
+                .map_or(u64::MAX, |record| record.columns[0]);
+            (value, key)
+        })
+        .collect();
+    entries.sort();
+    entries
+}
+
+fn random_value(rand: &mut StdRng) -> Option<u64> {
+    match rand.gen_range(0..5) {
+        0 => None,
+        _ => Some(rand.gen_range(0..6)),
+    }
+}
+
+#[test]
+fn random_writes_keep_indexes_exact() {
+    let dir = tempdir().unwrap();
+    let mut rand = StdRng::seed_from_u64(4021);
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore
+        .create_table_nullable("Random", vec![ColumnType::Unsigned; 4], Vec::new(), 0)
+        .unwrap();
+    table.build_index(1);
+    table.build_index(3);
+
+    let mut keys: Vec<u64> = Vec::new();
+    let mut next_key = 0;
+
+    for round in 0..6 {
+        for _ in 0..500 {
+            let key = keys.choose(&mut rand).copied();
+
+            match (rand.gen_range(0..6), key) {
+                (0, _) | (_, None) => {
+                    let values = [
+                        Some(next_key),
+                        random_value(&mut rand),
+                        random_value(&mut rand),
+                        random_value(&mut rand),
+                    ];
+                    table.insert_query_nullable(&values, None).unwrap();
+                    keys.push(next_key);
+                    next_key += 1;
+                }
+                (1 | 2, Some(key)) => {
+                    let values: Vec<Option<Option<u64>>> = (0..4)
+                        .map(|column| match column {
+                            0 => None,
+                            _ => rand.gen_bool(0.5).then(|| random_value(&mut rand)),
+                        })
+                        .collect();
+                    assert!(table.update_query_nullable(key, &values, None).unwrap());
+                }
+                (3, Some(key)) => {
+                    assert!(table.delete_query(key, None).unwrap());
+                    keys.retain(|kept| *kept != key);
+                }
+                (4, Some(key)) => {
+                    let low = key.saturating_sub(3);
+                    let updates = [
+                        None,
+                        Some(UpdateOp::Add(1)),
+                        None,
+                        Some(UpdateOp::Set(rand.gen_range(0..6))),
+                    ];
+                    table.update_range(low, key + 3, &updates, None).unwrap();
+                }
+                (_, Some(key)) => {
+                    // Rolled back as often as not, which must undo the index writes too
+                    let mut transaction = Transaction::begin();
+                    let commit = rand.gen_bool(0.5);
+
+                    match rand.gen_range(0..3) {
+                        0 => {
+                            let values = [None, Some(rand.gen_range(0..6)), None, Some(0)];
+                            assert!(transaction.update(&table, key, &values).unwrap());
+                        }
+                        1 => {
+                            assert!(transaction.delete(&table, key).unwrap());
+                            if commit {
+                                keys.retain(|kept| *kept != key);
+                            }
+                        }
+                        _ => {
+                            let values = [next_key, rand.gen_range(0..6), 0, 1];
+                            assert!(transaction.insert(&table, &values).unwrap());
+                            if commit {
+                                keys.push(next_key);
+                            }
+                            next_key += 1;
+                        }
+                    }
+
+                    match commit {
+                        true => {
+                            transaction.commit().unwrap();
+                        }
+                        false => transaction.abort(),
+                    }
+                }
+            }
+        }
+
+        table.checkpoint().unwrap();
+        let index = Index::load(&CrabStore::table_files(dir.path(), "Random").id_file, 4).unwrap();
+
+        for column in [0, 1, 3] {
+            assert_eq!(
+                index_entries(&table, &index, column),
+                scanned_entries(&table, column),
+                "column {column} after round {round}"
+            );
+        }
+        assert_eq!(index.entry_count(0), Some(keys.len()));
+    }
+
+    crabstore.close();
+}
+
+fn rows_holding(table: &Table, column: usize, value: u64) -> Vec<Vec<u64>> {
+    let mut rows: Vec<Vec<u64>> = table
+        .select_query(value, column, &[1, 1, 1], None)
+        .unwrap()
+        .into_iter()
+        .map(|record| record.columns)
+        .collect();
+    rows.sort();
+    rows
+}
+
+#[test]
+fn concurrent_duplicates_insert_once() {
+    let dir = tempdir().unwrap();
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("Unique", 3, 0);
+    table.build_unique_index(1).unwrap();
+
+    const THREADS: u64 = 8;
+
+    // Every thread inserts its own key with the round's value at the same moment
+    for round in 0..50 {
+        let barrier = Barrier::new(THREADS as usize);
+
+        thread::scope(|scope| {
+            for thread in 0..THREADS {
+                let table = &table;
+                let barrier = &barrier;
+                scope.spawn(move || {
+                    barrier.wait();
+                    table
+                        .insert_query(&[round * THREADS + thread, round, thread], None)
+                        .unwrap();
+                });
+            }
+        });
+
+        assert_eq!(rows_holding(&table, 1, round).len(), 1, "round {round}");
+    }
+
+    assert_eq!(table.scan().count(), 50);
+    crabstore.close();
+}
+
+#[test]
+fn colliding_update_changes_nothing() {
+    let dir = tempdir().unwrap();
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("Unique", 3, 0);
+    table.build_unique_index(1).unwrap();
+
+    table.insert_query(&[1, 10, 100], None).unwrap();
+    table.insert_query(&[2, 20, 200], None).unwrap();
+
+    assert!(!table
+        .update_query(2, &[None, Some(10), Some(201)], None)
+        .unwrap());
+    assert_eq!(rows_holding(&table, 0, 1), [[1, 10, 100]]);
+    assert_eq!(rows_holding(&table, 0, 2), [[2, 20, 200]]);
+
+    // A row may keep its own value
+    assert!(table
+        .update_query(1, &[None, Some(10), Some(101)], None)
+        .unwrap());
+    assert_eq!(rows_holding(&table, 1, 10), [[1, 10, 101]]);
+
+    let mut transaction = Transaction::begin();
+    assert!(transaction
+        .update(&table, 1, &[None, None, Some(102)])
+        .unwrap());
+    assert!(transaction
+        .update(&table, 2, &[None, Some(10), None])
+        .is_err());
+    assert_eq!(transaction.get_status(), QueryStatus::AbortedNotRetryable);
+    transaction.abort();
+    assert_eq!(rows_holding(&table, 0, 1), [[1, 10, 101]]);
+    assert_eq!(rows_holding(&table, 0, 2), [[2, 20, 200]]);
+
+    // Every row would end up with the same value
+    assert_eq!(
+        table
+            .update_range(1, 2, &[None, Some(UpdateOp::Set(30)), None], None)
+            .unwrap(),
+        0
+    );
+    assert!(rows_holding(&table, 1, 30).is_empty());
+
+    crabstore.close();
+}
+
+#[test]
+fn deleted_rows_free_their_values() {
+    let dir = tempdir().unwrap();
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("Unique", 3, 0);
+    table.build_unique_index(1).unwrap();
+
+    table.insert_query(&[1, 10, 100], None).unwrap();
+    table.insert_query(&[2, 10, 200], None).unwrap();
+    assert_eq!(rows_holding(&table, 1, 10), [[1, 10, 100]]);
+
+    assert!(table.delete_query(1, None).unwrap());
+    table.insert_query(&[2, 10, 200], None).unwrap();
+    assert_eq!(rows_holding(&table, 1, 10), [[2, 10, 200]]);
+
+    // Moving a row off a value frees it too
+    assert!(table
+        .update_query(2, &[None, Some(11), None], None)
+        .unwrap());
+    table.insert_query(&[3, 10, 300], None).unwrap();
+    assert_eq!(rows_holding(&table, 1, 10), [[3, 10, 300]]);
+
+    crabstore.close();
+}
+
+#[test]
+fn unique_index_needs_distinct_values() {
+    let dir = tempdir().unwrap();
+
+    {
+        let mut crabstore = CrabStore::new(dir.path().into());
+        crabstore.open().unwrap();
+        let table = crabstore.create_table("Unique", 3, 0);
+
+        table.insert_query(&[1, 10, 100], None).unwrap();
+        table.insert_query(&[2, 20, 100], None).unwrap();
+
+        assert_eq!(
+            table.build_unique_index(2),
+            Err(CrabError::DuplicateValue {
+                column: 2,
+                value: 100
+            })
+        );
+        assert_eq!(table.indexed_columns(), [0]);
+
+        table.build_unique_index(1).unwrap();
+        assert_eq!(table.unique_columns(), [1]);
+
+        crabstore.close();
+    }
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.get_table("Unique");
+    assert_eq!(table.unique_columns(), [1]);
+
+    table.insert_query(&[3, 20, 300], None).unwrap();
+    assert!(rows_holding(&table, 0, 3).is_empty());
+
+    crabstore.close();
+}
+
+fn saved_index(table: &Table, dir: &std::path::Path) -> Index {
+    table.checkpoint().unwrap();
+    Index::load(&CrabStore::table_files(dir, "Composite").id_file, 4).unwrap()
+}
+
+fn keys_matching(table: &Table, filters: &[(usize, u64)]) -> Vec<u64> {
+    let mut keys: Vec<u64> = table
+        .select_multi_query(filters, &[1, 0, 0, 0], None)
+        .unwrap()
+        .into_iter()
+        .map(|record| record.columns[0])
+        .collect();
+    keys.sort();
+    keys
+}
+
+#[test]
+fn composite_prefix_lookups() {
+    let dir = tempdir().unwrap();
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("Composite", 4, 0);
+
+    // Inserted before and after the index is built
+    for key in 0..50 {
+        table
+            .insert_query(&[key, key % 5, key % 3, key], None)
+            .unwrap();
+    }
+    table.build_composite_index(&[1, 2]);
+    for key in 50..100 {
+        table
+            .insert_query(&[key, key % 5, key % 3, key], None)
+            .unwrap();
+    }
+
+    let index = saved_index(&table, dir.path());
+    assert_eq!(index.composite_entry_count(&[1, 2]), Some(100));
+    assert_eq!(index.find_composite(&[(2, 1)]), None);
+
+    let expected =
+        |matches: &dyn Fn(u64) -> bool| (0..100).filter(|key| matches(*key)).collect::<Vec<_>>();
+
+    assert_eq!(
+        keys_matching(&table, &[(1, 2), (2, 1)]),
+        expected(&|key| key % 5 == 2 && key % 3 == 1)
+    );
+    // Filters come in any order, and a prefix of the index's columns is enough
+    assert_eq!(
+        keys_matching(&table, &[(2, 1), (1, 2)]),
+        expected(&|key| key % 5 == 2 && key % 3 == 1)
+    );
+    assert_eq!(
+        keys_matching(&table, &[(1, 4)]),
+        expected(&|key| key % 5 == 4)
+    );
+    assert_eq!(keys_matching(&table, &[(1, 4), (3, 19)]), [19]);
+
+    // Without a composite index to start from, single-column indexes are intersected
+    assert_eq!(keys_matching(&table, &[(2, 0), (0, 33)]), [33]);
+    table.build_index(3);
+    assert_eq!(keys_matching(&table, &[(3, 71), (2, 2), (1, 1)]), [71]);
+    assert!(keys_matching(&table, &[(2, 0), (3, 71)]).is_empty());
+
+    crabstore.close();
+}
+
+#[test]
+fn composite_follows_partial_updates() {
+    let dir = tempdir().unwrap();
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("Composite", 4, 0);
+    table.build_composite_index(&[1, 2]);
+
+    table.insert_query(&[1, 10, 20, 0], None).unwrap();
+    table.insert_query(&[2, 10, 21, 0], None).unwrap();
+
+    // Each update changes one column of the key, the other keeps its value
+    assert!(table
+        .update_query(1, &[None, None, Some(21), None], None)
+        .unwrap());
+    assert_eq!(keys_matching(&table, &[(1, 10), (2, 21)]), [1, 2]);
+    assert!(keys_matching(&table, &[(1, 10), (2, 20)]).is_empty());
+
+    assert!(table
+        .update_query(2, &[None, Some(11), None, None], None)
+        .unwrap());
+    assert_eq!(keys_matching(&table, &[(1, 11), (2, 21)]), [2]);
+    assert_eq!(keys_matching(&table, &[(1, 10)]), [1]);
+
+    // An update leaving the index's columns alone leaves its entries alone
+    assert!(table
+        .update_query(2, &[None, None, None, Some(5)], None)
+        .unwrap());
+    assert_eq!(
+        saved_index(&table, dir.path()).composite_entry_count(&[1, 2]),
+        Some(2)
+    );
+
+    // Rolled back, the entries are back where they were
+    let mut transaction = Transaction::begin();
+    assert!(transaction
+        .update(&table, 1, &[None, Some(12), None, None])
+        .unwrap());
+    assert_eq!(keys_matching(&table, &[(1, 12)]), [1]);
+    transaction.abort();
+    assert_eq!(keys_matching(&table, &[(1, 10), (2, 21)]), [1]);
+    assert!(keys_matching(&table, &[(1, 12)]).is_empty());
+
+    assert_eq!(
+        table
+            .update_range(1, 2, &[None, None, Some(UpdateOp::Add(1)), None], None)
+            .unwrap(),
+        2
+    );
+    assert_eq!(keys_matching(&table, &[(1, 10), (2, 22)]), [1]);
+    assert_eq!(keys_matching(&table, &[(1, 11), (2, 22)]), [2]);
+
+    assert!(table.delete_query(1, None).unwrap());
+    assert!(keys_matching(&table, &[(1, 10)]).is_empty());
+
+    let index = saved_index(&table, dir.path());
+    assert_eq!(index.composite_entry_count(&[1, 2]), Some(1));
+    assert_eq!(index.composite_indexes(), [[1, 2]]);
+    assert_eq!(
+        index.find_composite(&[(1, 11), (2, 22)]),
+        Some(vec![RID::from(1)])
+    );
+
+    crabstore.close();
+}
+
+#[test]
+fn index_only_sums_match_page_walks() {
+    let dir = tempdir().unwrap();
+    let mut rand = StdRng::seed_from_u64(557);
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore
+        .create_table_nullable(
+            "Sums",
+            vec![
+                ColumnType::Unsigned,
+                ColumnType::Unsigned,
+                ColumnType::Signed,
+                ColumnType::Unsigned,
+            ],
+            Vec::new(),
+            0,
+        )
+        .unwrap();
+    table.build_index(1);
+    table.build_index(2);
+
+    let signed = |rand: &mut StdRng| Some(rand.gen_range(-1000i64..1000) as u64);
+
+    let mut keys: Vec<u64> = (0..5000).collect();
+    for key in keys.iter().copied() {
+        let values = [
+            Some(key),
+            random_value(&mut rand),
+            signed(&mut rand),
+            Some(key),
+        ];
+        table.insert_query_nullable(&values, None).unwrap();
+    }
+
+    // Deleted and updated rows, and writes rolled back, are what would leave entries behind
+    for _ in 0..2000 {
+        let key = *keys.choose(&mut rand).unwrap();
+
+        match rand.gen_range(0..5) {
+            0 => {
+                assert!(table.delete_query(key, None).unwrap());
+                keys.retain(|kept| *kept != key);
+            }
+            1 => {
+                let values = [None, Some(random_value(&mut rand)), None, None];
+                assert!(table.update_query_nullable(key, &values, None).unwrap());
+            }
+            2 => {
+                let values = [None, None, Some(signed(&mut rand)), None];
+                assert!(table.update_query_nullable(key, &values, None).unwrap());
+            }
+            3 => {
+                let updates = [None, Some(UpdateOp::Add(1)), Some(UpdateOp::Set(7)), None];
+                table.update_range(key, key + 20, &updates, None).unwrap();
+            }
+            _ => {
+                let mut transaction = Transaction::begin();
+                assert!(transaction.delete(&table, key).unwrap());
+                assert!(transaction
+                    .update(&table, key + 1, &[None, Some(3), Some(3), None])
+                    .is_ok());
+                transaction.abort();
+            }
+        }
+    }
+
+    // Whether or not the index answers it, the sum is the one read from the rows
+    let page_walk = |low: u64, high: u64, column: usize| {
+        let mut transaction = Transaction::begin();
+        let sum = transaction.sum(&table, low, high, column).unwrap();
+        transaction.commit().unwrap();
+        sum
+    };
+
+    for _ in 0..40 {
+        let low = rand.gen_range(0..5000);
+        let high = low + rand.gen_range(0..5000);
+
+        for column in 0..4 {
+            assert_eq!(
+                table.sum_query(low, high, column, None).unwrap(),
+                page_walk(low, high, column),
+                "column {column} over {low}..={high}"
+            );
+        }
+    }
+
+    // Summed from the indexes alone, the query reads no page at all
+    let no_pages = QueryContext {
+        budget: Some(QueryBudget {
+            max_fetches: 0,
+            policy: BudgetPolicy::Fail,
+        }),
+    };
+    for column in [0, 1, 2] {
+        assert_eq!(
+            table.with_context(&no_pages, |t| t.sum_query(0, 4999, column, None)),
+            Ok(page_walk(0, 4999, column))
+        );
+    }
+    assert!(table
+        .with_context(&no_pages, |t| t.sum_query(0, 4999, 3, None))
+        .is_err());
+
+    crabstore.close();
+}
+
+#[test]
+fn cardinality_follows_updates() {
+    let dir = tempdir().unwrap();
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore
+        .create_table_nullable("Stats", vec![ColumnType::Unsigned; 3], Vec::new(), 0)
+        .unwrap();
+    table.build_index(1);
+
+    for key in 0..10 {
+        table
+            .insert_query_nullable(&[Some(key), Some(key % 4), Some(key)], None)
+            .unwrap();
+    }
+    assert_eq!(table.cardinality(0), Some(10));
+    assert_eq!(table.cardinality(1), Some(4));
+    assert_eq!(table.index_len(1), Some(10));
+
+    let located = |value| {
+        let mut rids: Vec<u64> = table.locate(1, value).iter().map(|rid| rid.raw()).collect();
+        rids.sort();
+        rids
+    };
+    assert_eq!(located(3), [3, 7]);
+    assert_eq!(table.locate_range(2, 3, 1).len(), 4);
+
+    // Moving both rows off 3 takes the value out, moving one onto 9 adds one
+    assert!(table.update_query(3, &[None, Some(0), None], None).unwrap());
+    assert!(table.update_query(7, &[None, Some(9), None], None).unwrap());
+    assert!(located(3).is_empty());
+    assert_eq!(located(9), [7]);
+    assert_eq!(table.cardinality(1), Some(4));
+    assert_eq!(table.index_len(1), Some(10));
+
+    // NULLs and deleted rows have no entries
+    assert!(table
+        .update_query_nullable(7, &[None, Some(None), None], None)
+        .unwrap());
+    assert!(table.delete_query(1, None).unwrap());
+    assert_eq!(table.cardinality(1), Some(3));
+    assert_eq!(table.index_len(1), Some(8));
+
+    // A rolled back update puts the value back
+    let mut transaction = Transaction::begin();
+    assert!(transaction
+        .update(&table, 5, &[None, Some(7), None])
+        .unwrap());
+    assert_eq!(table.cardinality(1), Some(4));
+    transaction.abort();
+    assert_eq!(table.cardinality(1), Some(3));
+    assert_eq!(located(1), [5, 9]);
+
+    // Unindexed columns, and columns the table doesn't have, have nothing to report
+    assert!(table.locate(2, 4).is_empty());
+    assert!(table.locate_range(0, 9, 2).is_empty());
+    assert_eq!(table.cardinality(2), None);
+    assert_eq!(table.index_len(5), None);
+    assert!(table.locate(5, 0).is_empty());
+    assert!(table.locate_range(3, 2, 1).is_empty());
+
+    crabstore.close();
+}
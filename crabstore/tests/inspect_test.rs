@@ -0,0 +1,110 @@
+use crabcore::{crabstore::CrabStore, rid::RID};
+use tempfile::tempdir;
+
+// Metadata columns come first, the RID column second and the values after all five
+const RID_COLUMN: usize = 1;
+const FIRST_VALUE_COLUMN: usize = 5;
+
+#[test]
+fn inspect_base_and_tail_records() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 3, 0);
+    for key in 0..10 {
+        table.insert_query(&[key, key * 10, 0], None).unwrap();
+    }
+    table
+        .update_query(3, &[None, Some(99), None], None)
+        .unwrap();
+
+    let base = table.inspect_page(0, 4).unwrap();
+    assert!(!base.is_tail);
+    assert_eq!(base.columns.len(), table.total_columns());
+    // Never merged, so the TPS is still what new pages start with
+    assert_eq!(base.header, u64::MAX);
+    assert_eq!(base.slots[RID_COLUMN], [0, 1, 2, 3]);
+    assert_eq!(base.slots[FIRST_VALUE_COLUMN + 1], [0, 10, 20, 30]);
+
+    // Pages are only ever dumped up to the limit
+    assert!(table.inspect_page(0, 0).unwrap().slots.is_empty());
+    assert_eq!(
+        table.inspect_page(0, usize::MAX).unwrap().slots[0].len(),
+        64
+    );
+
+    let row = table.inspect_rid(RID::from(3)).unwrap();
+    assert_eq!((row.page, row.slot, row.stored_rid), (0, 3, 3));
+
+    let tail = table.inspect_rid(RID::from(row.indirection)).unwrap();
+    assert!(tail.rid.is_tail());
+    assert_eq!(tail.base_rid, 3);
+    assert_eq!(tail.indirection, 3);
+    assert_eq!(tail.schema_encoding, 0b010);
+
+    let tail_page = table.inspect_page(tail.page, 0).unwrap();
+    assert!(tail_page.is_tail);
+
+    table.delete_query(5, None).unwrap();
+    let deleted = table.inspect_rid(RID::from(5)).unwrap();
+    assert_eq!((deleted.stored_rid, deleted.base_rid), (u64::MAX, 5));
+
+    assert!(table.inspect_page(1 << 20, 0).is_none());
+
+    db.close();
+}
+
+#[test]
+fn partial_updates_keep_their_own_schema_encoding() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 3, 0);
+    for key in 0..10 {
+        table
+            .insert_query(&[key, key * 10, key * 100], None)
+            .unwrap();
+    }
+
+    // Base slots 7 and 2 hold other rows than the first tail slots, so a write to the wrong one shows
+    table
+        .update_query(7, &[None, Some(71), None], None)
+        .unwrap();
+    table
+        .update_query(7, &[None, None, Some(702)], None)
+        .unwrap();
+    table
+        .update_query(2, &[None, None, Some(202)], None)
+        .unwrap();
+
+    let newest = table.inspect_rid(RID::from(7)).unwrap().indirection;
+    let newest = table.inspect_rid(RID::from(newest)).unwrap();
+    let oldest = table.inspect_rid(RID::from(newest.indirection)).unwrap();
+    assert_eq!((newest.base_rid, newest.schema_encoding), (7, 0b100));
+    assert_eq!((oldest.base_rid, oldest.schema_encoding), (7, 0b010));
+    assert_eq!(oldest.indirection, 7);
+
+    let other = table.inspect_rid(RID::from(2)).unwrap().indirection;
+    let other = table.inspect_rid(RID::from(other)).unwrap();
+    assert_eq!((other.base_rid, other.schema_encoding), (2, 0b100));
+
+    let version = |key, relative_version| {
+        table
+            .select_version_query(key, 0, &[1, 1, 1], relative_version)
+            .unwrap()[0]
+            .columns
+            .clone()
+    };
+    assert_eq!(version(7, 0), [7, 71, 702]);
+    assert_eq!(version(7, -1), [7, 71, 700]);
+    assert_eq!(version(7, -2), [7, 70, 700]);
+    assert_eq!(version(2, -1), [2, 20, 200]);
+
+    // Rows no update touched still read as inserted
+    assert_eq!(version(0, 0), [0, 0, 0]);
+    assert_eq!(version(1, 0), [1, 10, 100]);
+
+    db.close();
+}
@@ -0,0 +1,96 @@
+use crabcore::{crabstore::CrabStore, error::CrabError, table::Table};
+use rand::prelude::*;
+use tempfile::tempdir;
+
+const ROWS: u64 = 50_000;
+
+// Every row holds 7 in its last column, so selecting 7 there selects everything
+fn select_all_sorted(table: &Table) -> Vec<Vec<u64>> {
+    let mut rows: Vec<Vec<u64>> = table
+        .select_query(7, 2, &[1, 1, 1], None)
+        .unwrap()
+        .into_iter()
+        .map(|record| record.columns)
+        .collect();
+    rows.sort();
+    rows
+}
+
+fn check_ordered(table: &Table) {
+    let expected = select_all_sorted(table);
+
+    let keys: Vec<u64> = table.iter_keys().unwrap().collect();
+    assert_eq!(keys, expected.iter().map(|row| row[0]).collect::<Vec<_>>());
+
+    let rows: Vec<Vec<u64>> = table
+        .iter_records_ordered(&[1, 1, 1])
+        .unwrap()
+        .map(|record| record.unwrap().columns)
+        .collect();
+    assert_eq!(rows, expected);
+}
+
+#[test]
+fn iterate_in_key_order() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 3, 0);
+
+    let mut keys: Vec<u64> = (0..ROWS).map(|key| key * 3).collect();
+    keys.shuffle(&mut StdRng::seed_from_u64(484));
+    for key in keys.iter() {
+        table.insert_query(&[*key, key % 100, 7], None).unwrap();
+    }
+    for key in keys.iter().step_by(10) {
+        table
+            .update_query(*key, &[None, Some(key + 1), None], None)
+            .unwrap();
+    }
+    for key in keys.iter().skip(5).step_by(10) {
+        table.delete_query(*key, None).unwrap();
+    }
+
+    assert_eq!(select_all_sorted(&table).len(), ROWS as usize * 9 / 10);
+    check_ordered(&table);
+
+    // Without the key index, the keys come from a sorted scan
+    table.drop_index(0);
+    check_ordered(&table);
+    table.build_index(0);
+
+    // Deleted after the iterator took its snapshot
+    let mut iter = table.iter_keys().unwrap();
+    let mut records = table.iter_records_ordered(&[1, 0, 0]).unwrap();
+    table.delete_query(0, None).unwrap();
+    table.delete_query(3, None).unwrap();
+    assert_eq!(iter.next(), Some(6));
+    assert_eq!(records.next().unwrap().unwrap().columns, [6]);
+
+    drop((iter, records));
+    db.close();
+}
+
+#[test]
+fn ordered_records_stop_once_closed() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 3, 0);
+    for key in 0..2000 {
+        table.insert_query(&[key, 0, 7], None).unwrap();
+    }
+
+    let mut records = table.iter_records_ordered(&[1, 0, 0]).unwrap();
+    assert_eq!(records.next().unwrap().unwrap().columns, [0]);
+
+    db.close();
+
+    // The rest of the first batch was already read
+    let rest: Vec<_> = records.collect();
+    assert!(rest[..511].iter().all(Result::is_ok));
+    assert!(matches!(rest[511], Err(CrabError::TableClosed)));
+    assert_eq!(rest.len(), 512);
+}
@@ -0,0 +1,119 @@
+use crabcore::{
+    crabstore::CrabStore, error::CrabError, index::Index, rid::RID, transaction::Transaction,
+};
+use tempfile::tempdir;
+
+const NUMBER_OF_RECORDS: u64 = 20000;
+
+#[test]
+fn index_range_rev_orders_descending() {
+    let dir = tempdir().unwrap();
+    let mut index = Index::new(0, 2, &dir.path().join("index"));
+    index.create_index(1);
+
+    for (rid, value) in [(0, 5), (1, 9), (2, 5), (3, 1), (4, 9)] {
+        index.update_index(1, value, RID::from(rid));
+    }
+
+    let rids: Vec<u64> = index
+        .range_rev(1, ..)
+        .unwrap()
+        .map(|rid| rid.raw())
+        .collect();
+    // Highest value first, newest first within a value
+    assert_eq!(rids, [4, 1, 2, 0, 3]);
+
+    let rids: Vec<u64> = index
+        .range_rev(1, 2..9)
+        .unwrap()
+        .map(|rid| rid.raw())
+        .collect();
+    assert_eq!(rids, [2, 0]);
+
+    assert!(index.range_rev(0, ..).unwrap().next().is_none());
+}
+
+#[test]
+fn last_n_by_returns_highest_first() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 3, 0);
+    for key in 0..NUMBER_OF_RECORDS {
+        table
+            .insert_query(&[key, key * 7 % NUMBER_OF_RECORDS, key % 3], None)
+            .unwrap();
+    }
+
+    assert_eq!(
+        table.last_n_by(1, 3, &[1, 1, 1], None),
+        Err(CrabError::NotIndexed(1))
+    );
+
+    table.build_index(1);
+    let top: Vec<u64> = table
+        .last_n_by(1, 3, &[0, 1, 0], None)
+        .unwrap()
+        .into_iter()
+        .map(|record| record.columns[0])
+        .collect();
+    assert_eq!(top, [19999, 19998, 19997]);
+
+    // The highest row moves down, the next one is deleted
+    let highest = table.select_query(19999, 1, &[1, 0, 0], None).unwrap()[0].columns[0];
+    let second = table.select_query(19998, 1, &[1, 0, 0], None).unwrap()[0].columns[0];
+    table
+        .update_query(highest, &[None, Some(5), None], None)
+        .unwrap();
+    table.delete_query(second, None).unwrap();
+
+    let top: Vec<u64> = table
+        .last_n_by(1, 2, &[0, 1, 0], None)
+        .unwrap()
+        .into_iter()
+        .map(|record| record.columns[0])
+        .collect();
+    assert_eq!(top, [19997, 19996]);
+
+    // The primary key is always indexed
+    assert_eq!(
+        table.last_n_by(0, 1, &[1, 1, 1], None).unwrap()[0].columns,
+        [19999, 19999 * 7 % NUMBER_OF_RECORDS, 19999 % 3]
+    );
+
+    db.close();
+}
+
+#[test]
+fn last_n_by_stops_early() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 2, 0);
+    for key in 0..NUMBER_OF_RECORDS {
+        table.insert_query(&[key, key], None).unwrap();
+    }
+    table.build_index(1);
+
+    table.reset_stats();
+    assert_eq!(table.last_n_by(1, 5, &[1, 1], None).unwrap().len(), 5);
+    let top_requests = table.bufferpool_stats().requests;
+
+    // Outside a transaction the sum would be read off the index, under one it reads every row
+    table.reset_stats();
+    let mut transaction = Transaction::begin();
+    transaction.sum(&table, 0, NUMBER_OF_RECORDS, 1).unwrap();
+    transaction.commit().unwrap();
+    let scan_requests = table.bufferpool_stats().requests;
+
+    // Only the rows returned are checked and read, not the whole index
+    assert!(
+        top_requests <= 20,
+        "{top_requests} page requests for 5 rows"
+    );
+    assert!(top_requests * 100 < scan_requests);
+
+    db.close();
+}
@@ -0,0 +1,145 @@
+#![feature(test)]
+extern crate test;
+use crabcore::{
+    lock_manager::{LockManager, LockType},
+    rid::RID,
+};
+use std::{
+    sync::Barrier,
+    thread,
+    time::{Duration, Instant},
+};
+use test::Bencher;
+
+const NUM_THREADS: u64 = 8;
+const LOCKS_PER_THREAD: u64 = 4096;
+
+#[test]
+fn locks_conflict_per_record() {
+    let locks = LockManager::new();
+
+    // Neighbouring and far apart RIDs, so some share a shard and some don't
+    for rid in [0, 1, 63, 64, 4096, 1 << 40] {
+        let rid = RID::from(rid);
+
+        let mut shared = locks.try_lock(rid, LockType::Shared, 1).unwrap();
+        let other = locks.try_lock(rid, LockType::Shared, 2).unwrap();
+        assert_eq!(locks.try_lock(rid, LockType::Exclusive, 3).err(), Some(1));
+        assert_eq!(locks.upgrade_shared(&mut shared), Err(2));
+        assert_eq!(shared.lock_type, LockType::Shared);
+
+        locks.unlock(&other);
+        locks.upgrade_shared(&mut shared).unwrap();
+        assert_eq!(locks.try_lock(rid, LockType::Shared, 0).err(), Some(1));
+        assert!(locks.is_locked(rid));
+
+        locks.unlock(&shared);
+        assert!(!locks.is_locked(rid));
+        locks.unlock(&locks.try_lock(rid, LockType::Exclusive, 3).unwrap());
+    }
+}
+
+/*
+    Under wait-die an older transaction sleeps until the younger holder unlocks,
+    rather than until its timeout, and a younger one dies straight away
+*/
+#[test]
+fn wait_die_wakes_older_on_unlock() {
+    let locks = LockManager::new();
+    let rid = RID::from(7);
+    let timeout = Duration::from_secs(30);
+
+    let held = locks.try_lock(rid, LockType::Exclusive, 5).unwrap();
+    assert_eq!(locks.lock(rid, LockType::Shared, 9, timeout).err(), Some(5));
+
+    let started = Instant::now();
+    thread::scope(|s| {
+        let waiter = s.spawn(|| locks.lock(rid, LockType::Exclusive, 1, timeout));
+
+        thread::sleep(Duration::from_millis(50));
+        locks.unlock(&held);
+
+        locks.unlock(&waiter.join().unwrap().unwrap());
+    });
+
+    assert!(started.elapsed() < timeout);
+    assert!(locks.is_empty());
+}
+
+/*
+    Two readers of one record try to upgrade at the same time. Neither can while
+    the other reads, and the one that failed still holds its shared lock
+*/
+#[test]
+fn concurrent_upgrades_of_one_record() {
+    let locks = LockManager::new();
+    let rid = RID::from(7);
+    let barrier = Barrier::new(2);
+
+    thread::scope(|scope| {
+        for owner in [1, 2] {
+            let (locks, barrier) = (&locks, &barrier);
+
+            scope.spawn(move || {
+                for _ in 0..1000 {
+                    let mut shared = locks.try_lock(rid, LockType::Shared, owner).unwrap();
+                    barrier.wait();
+
+                    let upgraded = locks.upgrade_shared(&mut shared);
+                    barrier.wait();
+                    assert_eq!(upgraded, Err(3 - owner));
+                    assert_eq!(shared.lock_type, LockType::Shared);
+                    assert_eq!(locks.try_lock(rid, LockType::Exclusive, 0).err(), Some(1));
+                    barrier.wait();
+
+                    if owner == 2 {
+                        locks.unlock(&shared);
+                    }
+                    barrier.wait();
+
+                    if owner == 1 {
+                        locks.upgrade_shared(&mut shared).unwrap();
+                        locks.unlock(&shared);
+                    }
+                    barrier.wait();
+                }
+            });
+        }
+    });
+
+    assert!(locks.is_empty());
+}
+
+/*
+    Every thread locks its own RIDs, so none of them should ever fail
+*/
+fn lock_disjoint(locks: &LockManager) {
+    thread::scope(|scope| {
+        for t in 0..NUM_THREADS {
+            scope.spawn(move || {
+                let rids = (t * LOCKS_PER_THREAD..(t + 1) * LOCKS_PER_THREAD).map(RID::from);
+                let handles = rids
+                    .map(|rid| locks.try_lock(rid, LockType::Exclusive, t).unwrap())
+                    .collect::<Vec<_>>();
+
+                for handle in handles.iter() {
+                    locks.unlock(handle);
+                }
+            });
+        }
+    });
+}
+
+#[test]
+fn disjoint_locks_never_conflict() {
+    let locks = LockManager::new();
+    lock_disjoint(&locks);
+
+    assert!((0..NUM_THREADS * LOCKS_PER_THREAD).all(|rid| !locks.is_locked(rid.into())));
+}
+
+#[bench]
+fn lock_disjoint_bench(b: &mut Bencher) {
+    let locks = LockManager::new();
+    b.iter(|| lock_disjoint(&locks));
+}
@@ -1,8 +1,25 @@
 #![feature(test)]
 extern crate test;
-use crabcore::crabstore::CrabStore;
+use crabcore::{
+    config::CrabConfig,
+    crabstore::CrabStore,
+    error::CrabError,
+    merge::{MergeConfig, MergeReport},
+    range_dump::TailRecord,
+    table::Table,
+    transaction::{Query, Transaction},
+};
 use rand::prelude::*;
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
 use tempfile::tempdir;
 use test::Bencher;
 
@@ -12,7 +29,7 @@ fn merge_test() {
     let mut rand = StdRng::from_entropy();
 
     let mut crabstore = CrabStore::new(dir.path().into());
-    crabstore.open();
+    crabstore.open().unwrap();
 
     let table = crabstore.create_table("merge", 5, 0);
     let update_nums = [2, 4, 8, 16];
@@ -21,16 +38,18 @@ fn merge_test() {
     let select_repeat = 200;
 
     for i in 0..records_num {
-        table.insert_query(
-            &[
-                i,
-                (i + 100) % records_num,
-                (i + 200) % records_num,
-                (i + 300) % records_num,
-                (i + 400) % records_num,
-            ],
-            None,
-        );
+        table
+            .insert_query(
+                &[
+                    i,
+                    (i + 100) % records_num,
+                    (i + 200) % records_num,
+                    (i + 300) % records_num,
+                    (i + 400) % records_num,
+                ],
+                None,
+            )
+            .unwrap();
     }
 
     for index in 0..update_nums.len() {
@@ -49,7 +68,7 @@ fn merge_test() {
                     update_record[4 - idx] = None;
                 }
 
-                table.update_query(i, &update_record, None);
+                table.update_query(i, &update_record, None).unwrap();
             }
         }
         let keys = (0..records_num).choose_multiple(&mut rand, sample_count);
@@ -57,12 +76,872 @@ fn merge_test() {
         while time < select_repeat {
             time += 1;
             for key in keys.iter() {
-                table.select_query(*key, 0, &[1, 1, 1, 1, 1], None);
+                table.select_query(*key, 0, &[1, 1, 1, 1, 1], None).unwrap();
             }
         }
     }
 }
 
+#[test]
+fn tail_records_follow_updates() {
+    let dir = tempdir().unwrap();
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("tails", 3, 0);
+
+    for i in 0..10 {
+        table.insert_query(&[i, i * 10, i * 100], None).unwrap();
+    }
+
+    table
+        .update_query(3, &[None, Some(31), None], None)
+        .unwrap();
+    table
+        .update_query(3, &[None, None, Some(301)], None)
+        .unwrap();
+    table
+        .update_query(7, &[None, Some(71), Some(701)], None)
+        .unwrap();
+
+    let dump = table.dump_range(0).unwrap();
+
+    assert_eq!(dump.merged_until, 0);
+    assert_eq!(dump.tps.len(), 16);
+    assert_eq!(dump.tail_pages.len(), 1);
+
+    // Only range 0 has rows
+    assert!(table.dump_range(1).is_none());
+
+    let records: Vec<&TailRecord> = dump.tail_pages[0].records.iter().collect();
+    let summary: Vec<(u64, u64, Vec<u64>)> = records
+        .iter()
+        .map(|r| (r.base_rid.raw(), r.schema_encoding, r.columns.clone()))
+        .collect();
+
+    assert_eq!(
+        summary,
+        [
+            (3, 0b010, vec![3, 31, 300]),
+            (3, 0b100, vec![3, 31, 301]),
+            (7, 0b110, vec![7, 71, 701]),
+        ]
+    );
+
+    // Tail RIDs are handed out downwards
+    assert!(records.windows(2).all(|w| w[0].tid > w[1].tid));
+    assert!(records.iter().all(|r| r.tid.is_tail()));
+
+    crabstore.close();
+}
+
+#[test]
+fn merge_consumes_full_tail_pages() {
+    let dir = tempdir().unwrap();
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("merged", 2, 0);
+
+    for i in 0..16 {
+        table.insert_query(&[i, 0], None).unwrap();
+    }
+
+    // Enough tail pages in range 0 for the merge thread to pick it up
+    let updates = 512 * 5;
+    for i in 0..updates {
+        table.update_query(i % 16, &[None, Some(i)], None).unwrap();
+    }
+
+    table.quiesce(Duration::from_secs(10)).unwrap();
+    let dump = table.dump_range(0).unwrap();
+
+    assert_ne!(dump.merged_until, 0, "merge never ran");
+    assert_eq!(
+        dump.tail_pages
+            .iter()
+            .map(|page| page.records.len())
+            .sum::<usize>(),
+        updates as usize
+    );
+
+    // The merged tail page is part of the lineage
+    assert!(dump
+        .tail_pages
+        .iter()
+        .any(|page| page.page == dump.merged_until));
+
+    let last = &dump.tail_pages.last().unwrap().records;
+    for key in 0..16 {
+        let newest = last.iter().rev().find(|r| r.base_rid.raw() == key).unwrap();
+        let selected = table.select_query(key, 0, &[1, 1], None).unwrap();
+        assert_eq!(selected[0].columns, newest.columns);
+    }
+
+    crabstore.close();
+}
+
+#[test]
+fn merge_waits_out_uncommitted_writes() {
+    let dir = tempdir().unwrap();
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("pending", 2, 0);
+
+    for i in 0..16 {
+        table.insert_query(&[i, 0], None).unwrap();
+    }
+
+    let mut transaction = Transaction::new();
+    let start = transaction.savepoint();
+    assert!(transaction.execute(Query::Update(0, Box::new([None, Some(999)])), &table));
+
+    let updates = 512 * 5;
+    for i in 0..updates {
+        table
+            .update_query(1 + i % 15, &[None, Some(i)], None)
+            .unwrap();
+    }
+
+    // Row 0 could still be rolled back, so nothing of its range is merged
+    table.quiesce(Duration::from_secs(10)).unwrap();
+    assert_eq!(table.merge_stats().merges, 0);
+
+    transaction.rollback_to(start);
+    assert!(transaction.run().is_ok());
+
+    // The next merge goes ahead, without the rolled back version
+    for i in 0..updates {
+        table
+            .update_query(1 + i % 15, &[None, Some(i)], None)
+            .unwrap();
+    }
+
+    table.quiesce(Duration::from_secs(10)).unwrap();
+    assert_ne!(table.merge_stats().merges, 0, "merge never ran");
+    assert_eq!(
+        table.select_query(0, 0, &[1, 1], None).unwrap()[0].columns,
+        [0, 0]
+    );
+    assert_eq!(
+        table.select_original(0, &[1, 1]).unwrap()[0].columns,
+        [0, 0]
+    );
+
+    crabstore.close();
+}
+
+#[test]
+fn originals_survive_until_merge() {
+    let dir = tempdir().unwrap();
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("originals", 2, 0);
+
+    for i in 0..16 {
+        table.insert_query(&[i, i * 10], None).unwrap();
+    }
+
+    for i in 0..16 {
+        table
+            .update_query(i, &[None, Some(1000 + i)], None)
+            .unwrap();
+    }
+
+    for key in 0..16 {
+        let original = table.select_original(key, &[1, 1]).unwrap();
+        assert_eq!(original[0].columns, [key, key * 10]);
+
+        let latest = table.select_query(key, 0, &[1, 1], None).unwrap();
+        assert_eq!(latest[0].columns, [key, 1000 + key]);
+    }
+
+    assert_eq!(table.sum_original(4, 7, 1).unwrap(), 40 + 50 + 60 + 70);
+    assert!(table.select_original(16, &[1, 1]).unwrap().is_empty());
+
+    // Past the merge threshold, after which base pages hold merged values
+    let updates = 512 * 5;
+    for i in 0..updates {
+        table.update_query(i % 16, &[None, Some(i)], None).unwrap();
+    }
+
+    table.quiesce(Duration::from_secs(10)).unwrap();
+
+    assert_ne!(table.merge_stats().merges, 0, "merge never ran");
+    assert_ne!(table.dump_range(0).unwrap().merged_until, 0);
+
+    for key in 0..16 {
+        let merged = &table.select_original(key, &[1, 1]).unwrap()[0].columns;
+        assert_eq!(merged[0], key);
+        assert!(merged[1] < updates && merged[1] % 16 == key, "{merged:?}");
+    }
+
+    crabstore.close();
+}
+
+#[test]
+fn versions_follow_tail_chain_across_merges() {
+    let dir = tempdir().unwrap();
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("versions", 3, 0);
+
+    for i in 0..16 {
+        table.insert_query(&[i, i * 10, 0], None).unwrap();
+    }
+
+    let version = |key, relative_version| {
+        table
+            .select_version_query(key, 0, &[0, 1, 1], relative_version)
+            .unwrap()[0]
+            .columns
+            .clone()
+    };
+
+    // Unchanged columns carry over from the version before
+    table
+        .update_query(3, &[None, Some(31), None], None)
+        .unwrap();
+    table.update_query(3, &[None, None, Some(1)], None).unwrap();
+    table
+        .update_query(3, &[None, Some(32), None], None)
+        .unwrap();
+
+    assert_eq!(version(3, 0), [32, 1]);
+    assert_eq!(version(3, 1), [32, 1]);
+    assert_eq!(version(3, -1), [31, 1]);
+    assert_eq!(version(3, -2), [31, 0]);
+    assert_eq!(version(3, -3), [30, 0]);
+    assert_eq!(version(3, -100), [30, 0]);
+    assert_eq!(version(4, -1), [40, 0]);
+    assert!(table
+        .select_version_query(16, 0, &[1, 1, 1], -1)
+        .unwrap()
+        .is_empty());
+
+    // Past the merge threshold, after which the base records hold merged values
+    let updates = 512 * 5;
+    for i in 0..updates {
+        table
+            .update_query(i % 16, &[None, None, Some(i)], None)
+            .unwrap();
+    }
+    table.quiesce(Duration::from_secs(10)).unwrap();
+    assert_ne!(table.merge_stats().merges, 0, "merge never ran");
+
+    // Each key got one update in every 16, the TPS doesn't cut the chain short
+    for key in 0..16 {
+        let last = updates - 16 + key;
+        assert_eq!(version(key, 0)[1], last);
+        assert_eq!(version(key, -1)[1], last - 16);
+        assert_eq!(version(key, -10)[1], last - 160);
+    }
+    assert_eq!(version(3, -160), [32, 1]);
+    assert_eq!(version(3, -162), [31, 0]);
+
+    // Out of updates, the base record reads as of the merge
+    assert_eq!(
+        version(3, -1000),
+        table.select_original(3, &[0, 1, 1]).unwrap()[0].columns
+    );
+
+    crabstore.close();
+}
+
+#[test]
+fn version_sums_follow_tail_chain_across_merges() {
+    let dir = tempdir().unwrap();
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("version_sums", 3, 0);
+
+    for i in 0..16 {
+        table.insert_query(&[i, i * 10, 0], None).unwrap();
+    }
+
+    // Two updates to column 1 with one to column 2 in between, for keys 0..4
+    for i in 0..4 {
+        table
+            .update_query(i, &[None, Some(100), None], None)
+            .unwrap();
+        table.update_query(i, &[None, None, Some(1)], None).unwrap();
+        table
+            .update_query(i, &[None, Some(200), None], None)
+            .unwrap();
+    }
+
+    let sum = |column, relative_version| {
+        table
+            .sum_version_query(0, 7, column, relative_version)
+            .unwrap()
+    };
+    let untouched = 40 + 50 + 60 + 70;
+
+    assert_eq!(sum(1, 0), 4 * 200 + untouched);
+    assert_eq!(sum(1, 0), table.sum_query(0, 7, 1, None).unwrap());
+    assert_eq!(sum(1, -1), 4 * 100 + untouched);
+    assert_eq!(sum(1, -2), 4 * 100 + untouched);
+    assert_eq!(sum(1, -3), 60 + untouched);
+    assert_eq!(sum(2, 0), 4);
+    assert_eq!(sum(2, -1), 4);
+    assert_eq!(sum(2, -2), 0);
+
+    // Past the merge threshold, all on key 0 so the other chains stay short
+    let updates: i64 = 512 * 5;
+    for i in 0..updates {
+        table
+            .update_query(0, &[None, None, Some(i as u64)], None)
+            .unwrap();
+    }
+    table.quiesce(Duration::from_secs(10)).unwrap();
+    assert_ne!(table.merge_stats().merges, 0, "merge never ran");
+
+    assert_eq!(sum(2, 0), updates as u64 - 1 + 3);
+    assert_eq!(sum(2, -1), updates as u64 - 2 + 3);
+
+    // Keys 1..4 are out of updates and read their merged base records
+    let merged = 3 * 200 + untouched;
+    assert_eq!(sum(1, -updates), 200 + merged);
+    assert_eq!(sum(1, -updates - 1), 100 + merged);
+    assert_eq!(sum(1, -updates - 2), 100 + merged);
+    assert_eq!(sum(2, -updates - 1), 1 + 3);
+    assert_eq!(sum(2, -updates - 2), 3);
+
+    crabstore.close();
+}
+
+#[test]
+fn superseded_pages_are_invalidated() {
+    let dir = tempdir().unwrap();
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("superseded", 2, 0);
+
+    for i in 0..16 {
+        table.insert_query(&[i, i * 10], None).unwrap();
+    }
+    let original = table.inspect_page(0, 0).unwrap().columns;
+
+    for i in 0..512 * 5 {
+        table.update_query(i % 16, &[None, Some(i)], None).unwrap();
+    }
+
+    table.quiesce(Duration::from_secs(10)).unwrap();
+    assert_ne!(table.merge_stats().merges, 0, "merge never ran");
+
+    // Indirection, RID and base RID are shared with the copy, the rest is replaced
+    let merged = table.inspect_page(0, 0).unwrap().columns;
+    assert_eq!(merged[..3], original[..3]);
+
+    let superseded = &original[3..];
+    let bufferpool = table.get_bufferpool();
+    for page_id in superseded {
+        assert!(!merged.contains(page_id));
+        assert!(!bufferpool.is_page_mapped(*page_id));
+    }
+    // Every merge dropped the pages it replaced, which were still cached
+    assert_eq!(
+        bufferpool.stats().discarded,
+        table.merge_stats().merges * superseded.len()
+    );
+    drop(bufferpool);
+
+    crabstore.close();
+
+    // Nothing was evicted before the merge, so the originals were never written at all
+    let file = std::fs::read(CrabStore::table_files(dir.path(), "superseded").db_file).unwrap();
+    for page_id in superseded {
+        let start = (page_id * 4096).min(file.len());
+        let end = (start + 4096).min(file.len());
+        assert!(
+            file[start..end].iter().all(|byte| *byte == 0),
+            "page {page_id}"
+        );
+    }
+}
+
+#[test]
+fn superseded_pages_are_reused() {
+    let dir = tempdir().unwrap();
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("reused", 5, 0);
+
+    let records_num = 2000;
+    for i in 0..records_num {
+        table.insert_query(&[i, i, i, i, i], None).unwrap();
+    }
+
+    // Tail pages pile up for good, everything else is the base pages and the free list
+    let columns = table.inspect_page(0, 0).unwrap().columns.len();
+    let rounds = 30;
+    let mut other_pages = Vec::new();
+    for round in 0..rounds {
+        for i in 0..records_num {
+            table
+                .update_query(i, &[None, Some(round), Some(i + round), None, None], None)
+                .unwrap();
+        }
+
+        // Pages a merge freed are reused once a checkpoint no longer references them
+        table.quiesce(Duration::from_secs(10)).unwrap();
+        table.checkpoint().unwrap();
+
+        let tail_pages = table.dump_range(0).unwrap().tail_pages.len();
+        let stats = table.storage_stats().unwrap();
+        other_pages.push(stats.file_pages - tail_pages * columns);
+    }
+    assert!(
+        table.merge_stats().merges >= rounds as usize / 2,
+        "merges didn't keep up"
+    );
+
+    // Without reuse every merge would add a copy of each base page it touched
+    let stats = table.storage_stats().unwrap();
+    let copies = stats.base_pages * (columns - 3);
+    let half = rounds as usize / 2;
+    assert!(
+        other_pages[rounds as usize - 1] - other_pages[half] < copies,
+        "{other_pages:?}"
+    );
+
+    crabstore.close();
+
+    // The free list survives reopening, and reused pages hold what was written to them
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.get_table("reused");
+    assert_eq!(table.storage_stats().unwrap().free_pages, stats.free_pages);
+
+    for i in 0..records_num {
+        table
+            .update_query(i, &[None, None, None, Some(i * 2), None], None)
+            .unwrap();
+    }
+    table.quiesce(Duration::from_secs(10)).unwrap();
+    for i in (0..records_num).step_by(97) {
+        let record = table.select_query(i, 0, &[1, 1, 1, 1, 1], None).unwrap();
+        assert_eq!(record[0].columns, [i, rounds - 1, i + rounds - 1, i * 2, i]);
+    }
+
+    crabstore.close();
+}
+
+#[test]
+fn quiesce_returns_while_writers_keep_going() {
+    let dir = tempdir().unwrap();
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("busy", 2, 0);
+
+    for i in 0..16 {
+        table.insert_query(&[i, 0], None).unwrap();
+    }
+    // Enough for a merge to be queued before anyone quiesces
+    for i in 0..512 * 5 {
+        table.update_query(i % 16, &[None, Some(i)], None).unwrap();
+    }
+
+    let stop = AtomicBool::new(false);
+    thread::scope(|s| {
+        s.spawn(|| {
+            let mut i = 512 * 5;
+            while !stop.load(Ordering::Relaxed) {
+                table.update_query(i % 16, &[None, Some(i)], None).unwrap();
+                i += 1;
+            }
+        });
+
+        // Merges keep being queued behind each call, which only waits for the ones before it
+        let quiescers = (0..4)
+            .map(|_| s.spawn(|| table.quiesce(Duration::from_secs(30))))
+            .collect::<Vec<_>>();
+        for quiescer in quiescers {
+            quiescer.join().unwrap().unwrap();
+            assert_ne!(table.merge_stats().merges, 0);
+        }
+
+        stop.store(true, Ordering::Relaxed);
+    });
+
+    crabstore.quiesce_all(Duration::from_secs(10)).unwrap();
+
+    crabstore.close();
+    assert!(table.quiesce(Duration::ZERO).is_ok());
+}
+
+/*
+    The latest value of every row, None once deleted
+*/
+fn assert_rows(table: &Table, rows: &[Option<u64>]) {
+    for (key, value) in rows.iter().enumerate() {
+        let key = key as u64;
+        let selected = table.select_query(key, 0, &[1, 1], None).unwrap();
+        match value {
+            Some(value) => assert_eq!(selected[0].columns, [key, *value]),
+            None => assert!(selected.is_empty(), "deleted row {key} came back"),
+        }
+    }
+
+    let live = rows.iter().flatten().sum::<u64>();
+    let last_key = rows.len() as u64 - 1;
+    assert_eq!(table.sum_query(0, last_key, 1, None).unwrap(), live);
+}
+
+#[test]
+fn merges_keep_deleted_rows_deleted() {
+    let dir = tempdir().unwrap();
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("deleting", 2, 0);
+
+    let mut rows = vec![Some(0); 64];
+    for key in 0..64 {
+        table.insert_query(&[key, 0], None).unwrap();
+    }
+
+    // A few deletes per filled tail page, between the updates of the rest
+    let mut i = 0;
+    for round in 0..12 {
+        for _ in 0..512 {
+            let key = i % 64;
+            i += 1;
+            if rows[key as usize].is_some() {
+                table.update_query(key, &[None, Some(i)], None).unwrap();
+                rows[key as usize] = Some(i);
+            }
+
+            if i % 149 == 0 {
+                let key = (i / 149 * 7 + round) % 64;
+                let deleted = table.delete_query(key, None).unwrap();
+                assert_eq!(deleted, rows[key as usize].take().is_some());
+            }
+        }
+    }
+
+    table.quiesce(Duration::from_secs(10)).unwrap();
+    assert!(table.merge_stats().merges >= 2, "merges didn't run");
+    assert!(rows.iter().filter(|row| row.is_none()).count() >= 8);
+    assert_rows(&table, &rows);
+
+    crabstore.close();
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.get_table("deleting");
+    assert_rows(&table, &rows);
+
+    crabstore.close();
+}
+
+/*
+    Rounds of 4 tail pages of updates to keys 0..16, each merged and checkpointed
+*/
+fn update_rounds(table: &Table, rounds: std::ops::Range<u64>) {
+    for round in rounds {
+        for i in 0..512 * 4 {
+            let value = round * 512 * 4 + i;
+            table
+                .update_query(value % 16, &[None, Some(value)], None)
+                .unwrap();
+        }
+
+        table.quiesce(Duration::from_secs(10)).unwrap();
+        table.checkpoint().unwrap();
+    }
+}
+
+#[test]
+fn merged_tail_pages_are_reclaimed() {
+    let open = |dir: &Path, retained_tail_pages| {
+        let mut crabstore = CrabStore::with_config(
+            dir.into(),
+            CrabConfig {
+                retained_tail_pages,
+                ..CrabConfig::default()
+            },
+        );
+        crabstore.open().unwrap();
+        crabstore
+    };
+
+    let kept_dir = tempdir().unwrap();
+    let reclaimed_dir = tempdir().unwrap();
+    let mut kept = open(kept_dir.path(), None);
+    let mut reclaimed = open(reclaimed_dir.path(), Some(1));
+
+    let tables = [
+        kept.create_table("tails", 2, 0),
+        reclaimed.create_table("tails", 2, 0),
+    ];
+    for table in &tables {
+        for key in 0..16 {
+            table.insert_query(&[key, 0], None).unwrap();
+        }
+        update_rounds(table, 0..8);
+    }
+    let [kept_table, table] = &tables;
+
+    let kept_dump = kept_table.dump_range(0).unwrap();
+    let dump = table.dump_range(0).unwrap();
+    assert!(kept_dump.reclaimed_from.is_none());
+    assert!(dump.reclaimed_from.is_some());
+    assert!(
+        dump.tail_pages.len() * 4 < kept_dump.tail_pages.len(),
+        "{dump}"
+    );
+    assert_eq!(
+        table.merge_stats().reclaimed_tail_pages,
+        kept_dump.tail_pages.len() - dump.tail_pages.len()
+    );
+
+    // Reclaimed pages are reused, the other table file keeps growing instead
+    let file_pages = table.storage_stats().unwrap().file_pages;
+    assert!(file_pages < kept_table.storage_stats().unwrap().file_pages);
+
+    let versions = |table: &Table, relative_version| {
+        (0..16)
+            .map(|key| {
+                table
+                    .select_version_query(key, 0, &[0, 1], relative_version)
+                    .map(|records| records[0].columns[0])
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // Recent versions are in the pages retained or not merged yet, older ones are gone
+    assert_eq!(versions(table, 0), versions(kept_table, 0));
+    assert_eq!(versions(table, -2), versions(kept_table, -2));
+    assert!(versions(kept_table, -600).iter().all(Result::is_ok));
+    assert!(versions(table, -600)
+        .iter()
+        .all(|version| *version == Err(CrabError::VersionUnavailable)));
+    assert_eq!(
+        table.sum_version_query(0, 15, 1, -600),
+        Err(CrabError::VersionUnavailable)
+    );
+
+    let latest = versions(table, 0);
+    let tail_pages = dump.tail_pages.len();
+    kept.close();
+    reclaimed.close();
+
+    // Where reclaiming left off survives reopening, and so do the rows
+    let mut reclaimed = open(reclaimed_dir.path(), Some(1));
+    let table = reclaimed.get_table("tails");
+    assert_eq!(
+        table.dump_range(0).unwrap().reclaimed_from,
+        dump.reclaimed_from
+    );
+    assert_eq!(versions(&table, 0), latest);
+    assert!(versions(&table, -600)
+        .iter()
+        .all(|version| *version == Err(CrabError::VersionUnavailable)));
+
+    // Deleting stops at the reclaimed pages, and merging and reclaiming carry on
+    assert!(table.delete_query(3, None).unwrap());
+    update_rounds(&table, 8..12);
+    assert_eq!(table.storage_stats().unwrap().file_pages, file_pages);
+    assert!(table.select_query(3, 0, &[1, 1], None).unwrap().is_empty());
+    assert!(table.dump_range(0).unwrap().tail_pages.len() <= tail_pages);
+    assert_eq!(
+        table.select_query(4, 0, &[1, 1], None).unwrap()[0].columns,
+        [4, 12 * 512 * 4 - 12]
+    );
+
+    reclaimed.close();
+}
+
+#[test]
+fn manual_merges_wait_for_force_merge() {
+    let dir = tempdir().unwrap();
+
+    let mut crabstore = CrabStore::with_config(
+        dir.path().into(),
+        CrabConfig {
+            manual_merge: true,
+            ..CrabConfig::default()
+        },
+    );
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("manual", 2, 0);
+    assert!(!table.merge_config().enabled);
+
+    for i in 0..16 {
+        table.insert_query(&[i, 0], None).unwrap();
+    }
+    for i in 0..512 * 5 {
+        table.update_query(i % 16, &[None, Some(i)], None).unwrap();
+    }
+
+    table.quiesce(Duration::from_secs(10)).unwrap();
+    assert_eq!(table.merge_stats().merges, 0);
+
+    // The first 4 tail pages, the 5th is still the range's current one
+    assert_eq!(table.force_merge_all().unwrap(), 1);
+    assert_eq!(table.merge_stats().merges, 1);
+
+    for key in 0..16 {
+        let merged = table.select_original(key, &[1, 1]).unwrap();
+        assert_eq!(merged[0].columns, [key, 2032 + key]);
+
+        let latest = table.select_query(key, 0, &[1, 1], None).unwrap();
+        assert_eq!(latest[0].columns, [key, 2544 + key]);
+
+        let previous = table.select_version_query(key, 0, &[1, 1], -1).unwrap();
+        assert_eq!(previous[0].columns, [key, 2528 + key]);
+    }
+
+    // Nothing filled up since
+    assert_eq!(table.force_merge_all().unwrap(), 0);
+    assert!(!table.force_merge(1).unwrap());
+
+    table.set_merge_config(MergeConfig::default());
+    assert_eq!(table.merge_config(), MergeConfig::default());
+
+    crabstore.close();
+}
+
+#[test]
+fn merge_reports_count_distinct_rows() {
+    let dir = tempdir().unwrap();
+
+    let mut crabstore = CrabStore::with_config(
+        dir.path().into(),
+        CrabConfig {
+            manual_merge: true,
+            ..CrabConfig::default()
+        },
+    );
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("reports", 2, 0);
+
+    let reports = Arc::new(Mutex::new(Vec::<MergeReport>::new()));
+    let hook_reports = Arc::clone(&reports);
+    table.on_merge_complete(Box::new(move |report| {
+        hook_reports.lock().unwrap().push(*report)
+    }));
+
+    for i in 0..100 {
+        table.insert_query(&[i, 0], None).unwrap();
+    }
+
+    // Tail pages 1 and 2 update 37 rows, one more update starts page 3
+    for i in 0..512 * 2 {
+        table.update_query(i % 37, &[None, Some(i)], None).unwrap();
+    }
+    table.update_query(99, &[None, Some(0)], None).unwrap();
+
+    assert!(table.force_merge(0).unwrap());
+    assert_eq!(reports.lock().unwrap().len(), 1);
+    assert_eq!(reports.lock().unwrap()[0].tail_records, 37);
+    assert_eq!(table.merge_stats().tail_records, 37);
+
+    // Page 3 only updates row 99, page 4 rows 50 to 59
+    for i in 1..512 {
+        table.update_query(99, &[None, Some(i)], None).unwrap();
+    }
+    for i in 0..512 {
+        table
+            .update_query(50 + i % 10, &[None, Some(i)], None)
+            .unwrap();
+    }
+    table.update_query(98, &[None, Some(0)], None).unwrap();
+
+    assert!(table.force_merge(0).unwrap());
+    let reports = reports.lock().unwrap().clone();
+    assert_eq!(reports.len(), 2);
+    assert_eq!(reports[1].range_id, 0);
+    assert_eq!(reports[1].tail_records, 11);
+
+    let stats = table.merge_stats();
+    assert_eq!(stats.merges, 2);
+    assert_eq!(stats.tail_records, 48);
+    assert_eq!(
+        stats.base_pages,
+        reports
+            .iter()
+            .map(|report| report.base_pages)
+            .sum::<usize>()
+    );
+    assert_eq!(
+        stats.total_time,
+        reports.iter().map(|report| report.time).sum::<Duration>()
+    );
+
+    crabstore.close();
+}
+
+#[test]
+fn tables_share_merge_threads() {
+    let dir = tempdir().unwrap();
+
+    let mut crabstore = CrabStore::with_config(
+        dir.path().into(),
+        CrabConfig {
+            merge_threads: 2,
+            ..CrabConfig::default()
+        },
+    );
+    crabstore.open().unwrap();
+    let tables: Vec<_> = (0..6)
+        .map(|table| crabstore.create_table(&format!("shared{table}"), 2, 0))
+        .collect();
+
+    // However many tables are open
+    assert_eq!(crabstore.merge_scheduler().running_threads(), 2);
+
+    thread::scope(|scope| {
+        for table in &tables {
+            scope.spawn(move || {
+                for i in 0..16 {
+                    table.insert_query(&[i, 0], None).unwrap();
+                }
+                for i in 0..512 * 9 {
+                    table.update_query(i % 16, &[None, Some(i)], None).unwrap();
+                }
+            });
+        }
+    });
+
+    for table in &tables {
+        table.quiesce(Duration::from_secs(30)).unwrap();
+
+        // 8 filled tail pages, merged 4 at a time
+        assert_eq!(table.merge_stats().merges, 2);
+        assert_eq!(table.merge_status(), None);
+
+        for key in 0..16 {
+            let merged = table.select_original(key, &[1, 1]).unwrap();
+            assert_eq!(merged[0].columns, [key, 4080 + key]);
+        }
+    }
+
+    // Its merges stop, the other tables' carry on
+    assert!(crabstore.drop_table("shared0"));
+    for i in 0..512 * 4 {
+        tables[0]
+            .update_query(i % 16, &[None, Some(i)], None)
+            .unwrap();
+        tables[1]
+            .update_query(i % 16, &[None, Some(i)], None)
+            .unwrap();
+    }
+    tables[1].quiesce(Duration::from_secs(30)).unwrap();
+    assert_eq!(tables[0].merge_stats().merges, 2);
+    assert_eq!(tables[1].merge_stats().merges, 3);
+
+    crabstore.close();
+}
+
 /*
 #[bench]
 fn merge_bench(b: &mut Bencher) {
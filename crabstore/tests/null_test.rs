@@ -0,0 +1,176 @@
+use crabcore::{
+    crabstore::CrabStore,
+    error::CrabError,
+    schema::ColumnType,
+    table::{Table, UpdateOp},
+};
+use std::time::Duration;
+use tempfile::tempdir;
+
+// Every tenth row has a NULL grade
+fn grade(key: u64) -> Option<u64> {
+    match key % 10 {
+        0 => None,
+        _ => Some(key % 3),
+    }
+}
+
+fn keys_where(table: &Table, column_index: usize, value: u64) -> Vec<u64> {
+    let mut keys = table
+        .select_query(value, column_index, &[1, 0, 0], None)
+        .unwrap()
+        .into_iter()
+        .map(|record| record.columns[0])
+        .collect::<Vec<_>>();
+    keys.sort_unstable();
+    keys
+}
+
+fn values(table: &Table, key: u64) -> Vec<Option<u64>> {
+    table.select_query(key, 0, &[1, 1, 1], None).unwrap()[0].values()
+}
+
+fn check_lookups(table: &Table) {
+    // A NULL reads as 0, but never matches it
+    let zeros = (0..100)
+        .filter(|key| grade(*key) == Some(0))
+        .collect::<Vec<_>>();
+    assert_eq!(keys_where(table, 1, 0), zeros);
+
+    assert_eq!(values(table, 10), [Some(10), None, Some(10)]);
+    assert_eq!(values(table, 11), [Some(11), Some(2), Some(11)]);
+
+    let grades = (0..100).filter_map(grade).collect::<Vec<_>>();
+    assert_eq!(
+        table.sum_query(0, 99, 1, None),
+        Ok(grades.iter().sum::<u64>())
+    );
+    assert_eq!(
+        table.avg_query(0, 99, 1, None),
+        Ok(Some(
+            grades.iter().sum::<u64>() as f64 / grades.len() as f64
+        ))
+    );
+    assert_eq!(table.max_query(10, 10, 1, None), Ok(None));
+    assert_eq!(
+        table
+            .select_range_query(0, 0, 1, &[1, 0, 0], None, None)
+            .unwrap()
+            .len(),
+        zeros.len()
+    );
+}
+
+#[test]
+fn nulls_are_skipped_by_lookups_and_aggregates() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db
+        .create_table_nullable("Grades", vec![ColumnType::Unsigned; 3], Vec::new(), 0)
+        .unwrap();
+    assert!(table.is_nullable());
+
+    for key in 0..100 {
+        table
+            .insert_query_nullable(&[Some(key), grade(key), Some(key)], None)
+            .unwrap();
+    }
+
+    // Scanned, then through an index that has no entries for the NULLs
+    check_lookups(&table);
+    table.build_index(1);
+    check_lookups(&table);
+
+    assert_eq!(
+        table.insert_query_nullable(&[None, Some(1), Some(1)], None),
+        Err(CrabError::NotNullable(0))
+    );
+    assert_eq!(
+        table.update_query_nullable(1, &[Some(None), None, None], None),
+        Err(CrabError::NotNullable(0))
+    );
+
+    // Setting a NULL takes the row out of the index, and a value puts it back
+    assert!(table
+        .update_query_nullable(3, &[None, Some(None), None], None)
+        .unwrap());
+    assert_eq!(values(&table, 3), [Some(3), None, Some(3)]);
+    assert!(!keys_where(&table, 1, 0).contains(&3));
+    assert!(table.update_query(3, &[None, Some(0), None], None).unwrap());
+    assert!(keys_where(&table, 1, 0).contains(&3));
+
+    // NULL plus anything is still NULL, a set value isn't NULL any more
+    assert_eq!(
+        table
+            .update_range(10, 20, &[None, Some(UpdateOp::Add(1)), None], None)
+            .unwrap(),
+        11
+    );
+    assert_eq!(values(&table, 10), [Some(10), None, Some(10)]);
+    assert_eq!(values(&table, 11), [Some(11), Some(3), Some(11)]);
+    assert_eq!(
+        table
+            .update_range(20, 20, &[None, Some(UpdateOp::Set(0)), None], None)
+            .unwrap(),
+        1
+    );
+    assert!(keys_where(&table, 1, 0).contains(&20));
+
+    // Tail records that only touch the last column carry the NULL along, into the merged pages too
+    for i in 0..512 * 5 {
+        table
+            .update_query(30 + i % 16, &[None, None, Some(i)], None)
+            .unwrap();
+    }
+    table.quiesce(Duration::from_secs(10)).unwrap();
+    assert_ne!(table.merge_stats().merges, 0, "merge never ran");
+    assert_eq!(
+        table.select_original(40, &[0, 1, 0]).unwrap()[0].values(),
+        [None]
+    );
+    assert_eq!(values(&table, 40)[1], None);
+
+    db.close();
+
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+    assert_eq!(
+        values(&db.get_table("Grades"), 10),
+        [Some(10), None, Some(10)]
+    );
+
+    db.compact_table("Grades").unwrap();
+    let table = db.get_table("Grades");
+    assert!(table.is_nullable());
+    assert_eq!(values(&table, 40)[1], None);
+    assert_eq!(values(&table, 3), [Some(3), Some(0), Some(3)]);
+
+    db.close();
+}
+
+#[test]
+fn nulls_need_a_nullable_table() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 3, 0);
+    assert!(!table.is_nullable());
+
+    assert_eq!(
+        table.insert_query_nullable(&[Some(1), None, Some(1)], None),
+        Err(CrabError::NotNullable(1))
+    );
+    table
+        .insert_query_nullable(&[Some(1), Some(0), Some(1)], None)
+        .unwrap();
+    assert_eq!(values(&table, 1), [Some(1), Some(0), Some(1)]);
+    assert_eq!(
+        table.update_query_nullable(1, &[None, Some(None), None], None),
+        Err(CrabError::NotNullable(1))
+    );
+
+    db.close();
+}
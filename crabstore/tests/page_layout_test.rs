@@ -0,0 +1,115 @@
+use crabcore::{config::CrabConfig, crabstore::CrabStore, format::PageLayout, rid::RID};
+use std::{path::Path, time::Instant};
+use tempfile::tempdir;
+
+fn open(dir: &Path, page_layout: PageLayout) -> CrabStore {
+    let mut db = CrabStore::with_config(
+        dir.into(),
+        CrabConfig {
+            page_layout,
+            ..CrabConfig::default()
+        },
+    );
+    db.open().unwrap();
+    db
+}
+
+// Consecutive columns of a page a whole page range apart
+fn is_columnar(columns: &[usize]) -> bool {
+    columns.windows(2).all(|pair| pair[1] == pair[0] + 16)
+}
+
+#[test]
+fn columnar_pages_keep_their_layout() {
+    let dir = tempdir().unwrap();
+    let mut db = open(dir.path(), PageLayout::Columnar);
+
+    let table = db.create_table("Grades", 3, 0);
+    for key in 0..2000 {
+        table.insert_query(&[key, key % 10, 0], None).unwrap();
+    }
+
+    let first = table.inspect_page(0, 0).unwrap().columns;
+    let second = table.inspect_page(1, 0).unwrap().columns;
+    assert!(is_columnar(&first));
+    assert!(first.iter().zip(&second).all(|(a, b)| *b == a + 1));
+
+    // Past the merge threshold of range 0
+    for round in 0..6 {
+        for key in 0..512 {
+            table
+                .update_query(key, &[None, None, Some(round)], None)
+                .unwrap();
+        }
+    }
+
+    let tail = table.inspect_rid(RID::from(0)).unwrap().indirection;
+    let tail_page = table.inspect_rid(RID::from(tail)).unwrap().page;
+    assert!(is_columnar(
+        &table.inspect_page(tail_page, 0).unwrap().columns
+    ));
+
+    let start = Instant::now();
+    while table.merge_stats().merges == 0 && start.elapsed().as_secs() < 10 {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    assert_ne!(table.merge_stats().merges, 0, "merge never ran");
+
+    // Only the copied columns move, and they stay a column per run
+    let merged = table.inspect_page(0, 0).unwrap().columns;
+    assert_eq!(merged[..3], first[..3]);
+    assert!(is_columnar(&merged[3..]));
+
+    db.close();
+
+    // The header decides, not the config of whoever opens it
+    let mut db = open(dir.path(), PageLayout::Interleaved);
+    let table = db.get_table("Grades");
+    for key in 2000..9000 {
+        table.insert_query(&[key, key % 10, 0], None).unwrap();
+    }
+    assert!(is_columnar(&table.inspect_page(16, 0).unwrap().columns));
+
+    assert_eq!(
+        table.select_query(3, 0, &[1, 1, 1], None).unwrap()[0].columns,
+        [3, 3, 5]
+    );
+    assert_eq!(
+        table.select_query(8999, 0, &[1, 1, 1], None).unwrap()[0].columns,
+        [8999, 9, 0]
+    );
+
+    db.close();
+}
+
+/*
+    Cold scan of two columns of a million rows, once per layout. Run with
+    cargo test --release --test page_layout_test -- --ignored --nocapture
+*/
+#[test]
+#[ignore]
+fn cold_two_column_scan() {
+    for layout in [PageLayout::Interleaved, PageLayout::Columnar] {
+        let dir = tempdir().unwrap();
+        let mut db = open(dir.path(), layout);
+
+        let table = db.create_table("Wide", 8, 0);
+        for key in 0..1_000_000 {
+            table
+                .insert_query(&[key, key % 1000, 0, 0, 0, 0, 0, 0], None)
+                .unwrap();
+        }
+        db.close();
+
+        let mut db = open(dir.path(), layout);
+        let table = db.get_table("Wide");
+
+        let start = Instant::now();
+        let rows = table
+            .select_query(7, 1, &[1, 1, 0, 0, 0, 0, 0, 0], None)
+            .unwrap();
+        println!("{layout:?}: {} rows in {:?}", rows.len(), start.elapsed());
+
+        db.close();
+    }
+}
@@ -0,0 +1,171 @@
+use crabcore::{config::CrabConfig, crabstore::CrabStore, error::CrabError};
+use std::{path::Path, sync::mpsc::channel, thread};
+use tempfile::tempdir;
+
+const BATCHES: u64 = 5;
+const BATCH_SIZE: u64 = 2000;
+
+fn read_only(dir: &Path) -> CrabStore {
+    let mut reader = CrabStore::with_config(
+        dir.into(),
+        CrabConfig {
+            read_only: true,
+            ..Default::default()
+        },
+    );
+    reader.open().unwrap();
+    reader
+}
+
+#[test]
+fn refresh_sees_each_checkpoint() {
+    let dir = tempdir().unwrap();
+
+    let mut writer = CrabStore::new(dir.path().into());
+    writer.open().unwrap();
+    writer.create_table("Grades", 2, 0);
+    writer.checkpoint();
+
+    let mut reader = read_only(dir.path());
+    let grades = reader.get_table("Grades");
+    assert_eq!(grades.sum_query(0, u64::MAX, 1, None).unwrap(), 0);
+
+    let (checkpointed_send, checkpointed) = channel();
+    let (verified_send, verified) = channel();
+
+    let writer_thread = thread::spawn(move || {
+        let table = writer.get_table("Grades");
+
+        for batch in 0..BATCHES {
+            // Appended while the reader is still checking the previous checkpoint
+            for key in batch * BATCH_SIZE..(batch + 1) * BATCH_SIZE {
+                table.insert_query(&[key, 1], None).unwrap();
+            }
+
+            if batch > 0 {
+                verified.recv().unwrap();
+            }
+
+            table.update_query(batch, &[None, Some(2)], None).unwrap();
+
+            writer.checkpoint();
+            checkpointed_send.send(batch).unwrap();
+        }
+
+        verified.recv().unwrap();
+        writer.close();
+    });
+
+    for batch in checkpointed.iter() {
+        reader.refresh().unwrap();
+
+        // The handle from before the first refresh keeps up too
+        let rows = (batch + 1) * BATCH_SIZE;
+        assert_eq!(
+            grades.sum_query(0, u64::MAX, 1, None).unwrap(),
+            rows + batch + 1
+        );
+
+        assert_eq!(
+            grades.select_query(batch, 0, &[1, 1], None).unwrap()[0].columns,
+            [batch, 2]
+        );
+        assert_eq!(
+            grades.select_query(rows - 1, 0, &[1, 1], None).unwrap()[0].columns,
+            [rows - 1, 1]
+        );
+        assert!(grades
+            .select_query(rows, 0, &[1, 1], None)
+            .unwrap()
+            .is_empty());
+
+        verified_send.send(()).unwrap();
+    }
+
+    writer_thread.join().unwrap();
+
+    // After the writer's close the reader sees the final state as well
+    reader.refresh().unwrap();
+    assert_eq!(
+        grades.sum_query(0, u64::MAX, 1, None).unwrap(),
+        BATCHES * BATCH_SIZE + BATCHES
+    );
+
+    reader.close();
+}
+
+#[test]
+fn refresh_picks_up_new_tables() {
+    let dir = tempdir().unwrap();
+
+    let mut writer = CrabStore::new(dir.path().into());
+    writer.open().unwrap();
+    writer.create_table("Grades", 2, 0);
+    writer.checkpoint();
+
+    let mut reader = read_only(dir.path());
+
+    let courses = writer.create_table("Courses", 3, 0);
+    courses.insert_query(&[7, 8, 9], None).unwrap();
+
+    // Not checkpointed yet
+    reader.refresh().unwrap();
+    assert_eq!(
+        reader
+            .get_table("Grades")
+            .sum_query(0, u64::MAX, 1, None)
+            .unwrap(),
+        0
+    );
+
+    writer.checkpoint();
+    reader.refresh().unwrap();
+
+    let courses = reader.get_table("Courses");
+    assert_eq!(
+        courses.select_query(7, 0, &[1, 1, 1], None).unwrap()[0].columns,
+        [7, 8, 9]
+    );
+
+    writer.close();
+    reader.close();
+}
+
+#[test]
+fn read_only_rejects_writes() {
+    let dir = tempdir().unwrap();
+
+    let mut writer = CrabStore::new(dir.path().into());
+    writer.open().unwrap();
+    writer
+        .create_table("Grades", 2, 0)
+        .insert_query(&[1, 2], None)
+        .unwrap();
+    writer.checkpoint();
+
+    let mut reader = read_only(dir.path());
+    let grades = reader.get_table("Grades");
+
+    assert_eq!(grades.insert_query(&[3, 4], None), Err(CrabError::ReadOnly));
+    assert_eq!(
+        grades.update_query(1, &[None, Some(5)], None),
+        Err(CrabError::ReadOnly)
+    );
+    assert_eq!(grades.delete_query(1, None), Err(CrabError::ReadOnly));
+
+    // Closing the reader leaves the writer's files alone
+    reader.close();
+    writer.close();
+
+    let mut reopened = CrabStore::new(dir.path().into());
+    reopened.open().unwrap();
+    assert_eq!(
+        reopened
+            .get_table("Grades")
+            .select_query(1, 0, &[1, 1], None)
+            .unwrap()[0]
+            .columns,
+        [1, 2]
+    );
+    reopened.close();
+}
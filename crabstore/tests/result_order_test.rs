@@ -0,0 +1,244 @@
+use crabcore::{
+    crabstore::CrabStore,
+    table::{ResultOrder, Table},
+};
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+use tempfile::tempdir;
+
+const NUMBER_OF_RECORDS: u64 = 64;
+
+/*
+    Keys of the rows holding 1 in column 1, in the order the select returned them
+*/
+fn selected_keys(table: &Table, order: ResultOrder, limit: Option<usize>) -> Vec<u64> {
+    table
+        .select_query_limit(1, 1, &[1, 0, 0], order, limit, None)
+        .unwrap()
+        .into_iter()
+        .map(|record| record.columns[0])
+        .collect()
+}
+
+#[test]
+fn ordered_selects_survive_a_merge() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 3, 0);
+    // Inserted highest key first, so RID order is the reverse of key order
+    for key in (0..NUMBER_OF_RECORDS).rev() {
+        table.insert_query(&[key, key % 2, 0], None).unwrap();
+    }
+    table.build_index(1);
+
+    let odd_keys = (1..NUMBER_OF_RECORDS).step_by(2).collect::<Vec<u64>>();
+    let by_key = selected_keys(&table, ResultOrder::ByPrimaryKey, None);
+    let by_rid = selected_keys(&table, ResultOrder::ByRid, None);
+    assert_eq!(by_key, odd_keys);
+    assert_eq!(by_rid, odd_keys.iter().rev().copied().collect::<Vec<u64>>());
+
+    // The first matches in the order, not the first found
+    assert_eq!(
+        selected_keys(&table, ResultOrder::ByPrimaryKey, Some(3)),
+        [1, 3, 5]
+    );
+
+    // Past the merge threshold
+    for i in 0..512 * 5 {
+        table
+            .update_query(i % NUMBER_OF_RECORDS, &[None, None, Some(i)], None)
+            .unwrap();
+    }
+
+    let start = Instant::now();
+    while table.merge_stats().merges == 0 && start.elapsed() < Duration::from_secs(10) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_ne!(table.merge_stats().merges, 0, "merge never ran");
+
+    assert_eq!(
+        selected_keys(&table, ResultOrder::ByPrimaryKey, None),
+        by_key
+    );
+    assert_eq!(selected_keys(&table, ResultOrder::ByRid, None), by_rid);
+
+    // Also after the index is rebuilt, or dropped for a scan
+    table.drop_index(1);
+    assert_eq!(
+        selected_keys(&table, ResultOrder::ByPrimaryKey, None),
+        by_key
+    );
+    table.build_index(1);
+    assert_eq!(
+        selected_keys(&table, ResultOrder::ByPrimaryKey, None),
+        by_key
+    );
+
+    db.close();
+}
+
+#[test]
+fn ordered_select_where() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 3, 0);
+    for key in (0..NUMBER_OF_RECORDS).rev() {
+        table.insert_query(&[key, key % 2, key % 3], None).unwrap();
+    }
+
+    let selected = table
+        .select_where(
+            &[(1, 0), (2, 0)],
+            &[1, 0, 0],
+            ResultOrder::ByPrimaryKey,
+            None,
+        )
+        .unwrap();
+    assert_eq!(
+        selected
+            .iter()
+            .map(|record| record.columns[0])
+            .collect::<Vec<u64>>(),
+        (0..NUMBER_OF_RECORDS).step_by(6).collect::<Vec<u64>>()
+    );
+
+    // Sorted by key even when the key column isn't projected
+    let selected = table
+        .select_query_limit(0, 2, &[0, 0, 1], ResultOrder::ByPrimaryKey, Some(2), None)
+        .unwrap();
+    assert_eq!(selected.len(), 2);
+    assert!(selected.iter().all(|record| record.columns == [0]));
+    assert!(selected[0].rid > selected[1].rid);
+
+    db.close();
+}
+
+#[test]
+fn range_select_sorted_by_column() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Ranged", 3, 0);
+    let mut model = (0..10_000)
+        .map(|key| Some([key, key * 7919 % 1000, key]))
+        .collect::<Vec<_>>();
+    for row in model.iter().flatten() {
+        table.insert_query(row, None).unwrap();
+    }
+
+    // Moves a third of the rows to another value, in and out of the range
+    for key in (0..10_000).step_by(3) {
+        let row = model[key as usize].as_mut().unwrap();
+        row[1] = (row[1] + 150) % 1000;
+        table
+            .update_query(key, &[None, Some(row[1]), None], None)
+            .unwrap();
+    }
+    let deleted = model
+        .iter()
+        .flatten()
+        .find(|row| (200..=300).contains(&row[1]))
+        .unwrap()[0];
+    table.delete_query(deleted, None).unwrap();
+    model[deleted as usize] = None;
+
+    let mut expected = model
+        .iter()
+        .flatten()
+        .filter(|row| (200..=300).contains(&row[1]))
+        .map(|row| row.to_vec())
+        .collect::<Vec<_>>();
+    expected.sort_unstable_by_key(|row| (row[1], row[0]));
+    assert!(expected.windows(2).any(|w| w[0][1] == w[1][1]));
+
+    let check = |table: &Table| {
+        let selected = table
+            .select_range_query(200, 300, 1, &[1, 1, 1], None, None)
+            .unwrap()
+            .into_iter()
+            .map(|record| record.columns)
+            .collect::<Vec<_>>();
+        assert_eq!(selected, expected);
+
+        // Sorted by the filter column even when it isn't returned
+        let keys = table
+            .select_range_query(200, 300, 1, &[1, 0, 0], None, None)
+            .unwrap()
+            .into_iter()
+            .map(|record| record.columns)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            expected.iter().map(|row| vec![row[0]]).collect::<Vec<_>>()
+        );
+
+        let by_key = table
+            .select_range_query(deleted - 2, deleted + 2, 0, &[0, 0, 1], None, None)
+            .unwrap()
+            .into_iter()
+            .map(|record| record.columns[0])
+            .collect::<Vec<_>>();
+        assert_eq!(by_key, [deleted - 2, deleted - 1, deleted + 1, deleted + 2]);
+
+        // A limit keeps the first rows in the same order, splitting equal values by key
+        for limit in [0, 1, 25, expected.len(), expected.len() + 1] {
+            let limited = table
+                .select_range_query(200, 300, 1, &[1, 1, 1], Some(limit), None)
+                .unwrap()
+                .into_iter()
+                .map(|record| record.columns)
+                .collect::<Vec<_>>();
+            assert_eq!(limited, expected[..limit.min(expected.len())]);
+        }
+    };
+
+    check(&table);
+    table.build_index(1);
+    check(&table);
+    table.freeze().unwrap();
+    check(&table);
+    table.unfreeze();
+    table.drop_index(1);
+    table.freeze().unwrap();
+    check(&table);
+    table.unfreeze();
+
+    db.close();
+}
+
+#[test]
+fn range_limit_stops_index_lookup_early() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Ranged", 3, 0);
+    table.build_index(1);
+    for key in 0..8192 {
+        table.insert_query(&[key, key / 2, key], None).unwrap();
+    }
+
+    let select_requests = |limit| {
+        table.reset_stats();
+        let selected = table
+            .select_range_query(0, u64::MAX, 1, &[1, 1, 1], limit, None)
+            .unwrap();
+        (selected.len(), table.bufferpool_stats().requests)
+    };
+
+    let (all, all_requests) = select_requests(None);
+    assert_eq!(all, 8192);
+
+    let (limited, limited_requests) = select_requests(Some(9));
+    assert_eq!(limited, 9);
+    assert!(limited_requests * 100 < all_requests);
+
+    db.close();
+}
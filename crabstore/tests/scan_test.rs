@@ -0,0 +1,137 @@
+use crabcore::{
+    config::CrabConfig, crabstore::CrabStore, error::CrabError, eviction::Eviction, record::Record,
+};
+use std::thread;
+use tempfile::tempdir;
+
+#[test]
+fn scan_matches_queries() {
+    let num_rows = 50_000;
+
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 3, 0);
+    for key in 0..num_rows {
+        table.insert_query(&[key, key % 7, 1], None).unwrap();
+    }
+
+    // Some rows read from their tail records, some are gone
+    for key in (0..num_rows).step_by(3) {
+        table
+            .update_query(key, &[None, None, Some(2)], None)
+            .unwrap();
+    }
+    for key in (0..num_rows).step_by(10) {
+        table.delete_query(key, None).unwrap();
+    }
+
+    let records = table.scan().collect::<Result<Vec<Record>, _>>().unwrap();
+    let keys = records
+        .iter()
+        .map(|record| record.columns[0])
+        .collect::<Vec<_>>();
+
+    // RID order is insertion order here, so also key order
+    assert_eq!(keys, table.iter_keys().unwrap().collect::<Vec<_>>());
+    for column in 1..3 {
+        assert_eq!(
+            records
+                .iter()
+                .map(|record| record.columns[column])
+                .sum::<u64>(),
+            table.sum_query(0, num_rows, column, None).unwrap()
+        );
+    }
+    assert_eq!(
+        records
+            .iter()
+            .filter(|record| record.columns[1] == 3)
+            .count(),
+        table.select_query(3, 1, &[1, 0, 0], None).unwrap().len()
+    );
+
+    db.close();
+}
+
+#[test]
+fn scan_ignores_later_inserts() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 2, 0);
+    for key in 0..2000 {
+        table.insert_query(&[key, key], None).unwrap();
+    }
+
+    let scan = table.scan();
+    let scanned = thread::scope(|s| {
+        let inserts = s.spawn(|| {
+            for key in 2000..4000 {
+                table.insert_query(&[key, key], None).unwrap();
+            }
+        });
+
+        let scanned = scan
+            .map(|record| record.unwrap().columns[0])
+            .collect::<Vec<_>>();
+        inserts.join().unwrap();
+        scanned
+    });
+
+    assert_eq!(scanned, (0..2000).collect::<Vec<_>>());
+    assert_eq!(table.scan().count(), 4000);
+
+    // The rest of the scan fails once the table is closed
+    let mut scan = table.scan();
+    assert!(scan.next().unwrap().is_ok());
+    db.close();
+    assert!(scan.any(|record| record == Err(CrabError::TableClosed)));
+}
+
+/*
+    Misses re-reading a few rows from the first two base pages, each time
+    after a full scan of a table much larger than the bufferpool
+*/
+fn hot_misses_between_scans(eviction: Eviction) -> usize {
+    let num_rows = 512 * 16 * 6;
+
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::with_config(
+        dir.path().into(),
+        CrabConfig {
+            eviction,
+            ..CrabConfig::default()
+        },
+    );
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 3, 0);
+    for key in 0..num_rows {
+        table.insert_query(&[key, key, key], None).unwrap();
+    }
+
+    let mut misses = 0;
+    for round in 0..5 {
+        table.reset_stats();
+        for key in (0..1024).step_by(64) {
+            table.select_query(key, 0, &[1, 1, 1], None).unwrap();
+        }
+        if round > 0 {
+            misses += table.bufferpool_stats().misses;
+        }
+
+        assert_eq!(table.scan().count() as u64, num_rows);
+    }
+
+    db.close();
+    misses
+}
+
+#[test]
+fn lru_k_keeps_hot_pages_through_scans() {
+    assert!(hot_misses_between_scans(Eviction::Clock) > 0);
+    assert_eq!(hot_misses_between_scans(Eviction::LruK), 0);
+}
@@ -0,0 +1,101 @@
+use crabcore::{crabstore::CrabStore, error::CrabError};
+use tempfile::tempdir;
+
+fn names(names: &[&str]) -> Vec<String> {
+    names.iter().map(|name| name.to_string()).collect()
+}
+
+#[test]
+fn named_columns_survive_reopen_and_compaction() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db
+        .create_table_named("Grades", names(&["student", "grade", "score"]), 0)
+        .unwrap();
+    assert_eq!(table.columns(), 3);
+    assert_eq!(table.column_index("score"), Some(2));
+    assert_eq!(table.column_index("Score"), None);
+
+    for key in 0..100 {
+        table.insert_query(&[key, key % 5, key * 2], None).unwrap();
+    }
+
+    assert!(table
+        .update_query_named(7, &[("score", 1000)], None)
+        .unwrap());
+    let records = table
+        .select_query_named(7, "student", &["score", "student"], None)
+        .unwrap();
+    assert_eq!(records[0].columns, [7, 1000]);
+    assert_eq!(
+        table
+            .select_query_named(3, "grade", &["student"], None)
+            .unwrap()
+            .len(),
+        20
+    );
+
+    assert_eq!(
+        table.select_query_named(7, "name", &["score"], None),
+        Err(CrabError::UnknownColumn("name".into()))
+    );
+    assert_eq!(
+        table.update_query_named(7, &[("score", 1), ("rank", 1)], None),
+        Err(CrabError::UnknownColumn("rank".into()))
+    );
+    // Nothing is written when a name is wrong
+    assert_eq!(
+        table.select_query(7, 0, &[0, 0, 1], None).unwrap()[0].columns,
+        [1000]
+    );
+
+    db.close();
+
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+    assert_eq!(
+        db.get_table("Grades").column_names(),
+        ["student", "grade", "score"]
+    );
+
+    db.compact_table("Grades").unwrap();
+    let table = db.get_table("Grades");
+    assert_eq!(table.column_index("grade"), Some(1));
+    assert_eq!(
+        table
+            .select_query_named(7, "student", &["score"], None)
+            .unwrap()[0]
+            .columns,
+        [1000]
+    );
+
+    // Tables created without names still have none
+    let unnamed = db.create_table("Unnamed", 2, 0);
+    assert!(unnamed.column_names().is_empty());
+    assert_eq!(unnamed.column_index("0"), None);
+
+    db.close();
+}
+
+#[test]
+fn invalid_column_names_rejected() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let invalid = |db: &mut CrabStore, column_names: Vec<String>, key_index| {
+        matches!(
+            db.create_table_named("Grades", column_names, key_index),
+            Err(CrabError::InvalidSchema(_))
+        )
+    };
+
+    assert!(invalid(&mut db, names(&["id", "grade", "id"]), 0));
+    assert!(invalid(&mut db, names(&[]), 0));
+    assert!(invalid(&mut db, names(&["id", "grade"]), 2));
+    assert!(invalid(&mut db, vec!["x".repeat(4096)], 0));
+
+    db.close();
+}
@@ -0,0 +1,203 @@
+use crabcore::{
+    crabstore::CrabStore,
+    table::{ResultOrder, Table},
+    transaction::Transaction,
+};
+use tempfile::tempdir;
+
+// Every row holds one of two values in column 1, like a boolean flag
+const NUMBER_OF_RECORDS: u64 = 8192;
+
+fn populate(table: &Table) {
+    for key in 0..NUMBER_OF_RECORDS {
+        table.insert_query(&[key, key % 2, key], None).unwrap();
+    }
+}
+
+/*
+    Bufferpool requests a select on column 1 makes, with and without a limit
+*/
+fn select_requests(table: &Table, limit: Option<usize>) -> (usize, usize) {
+    table.reset_stats();
+    let selected = table
+        .select_query_limit(1, 1, &[1, 1, 1], ResultOrder::Unordered, limit, None)
+        .unwrap();
+    (selected.len(), table.bufferpool_stats().requests)
+}
+
+#[test]
+fn limit_stops_scan_early() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 3, 0);
+    populate(&table);
+
+    let (all, all_requests) = select_requests(&table, None);
+    assert_eq!(all, NUMBER_OF_RECORDS as usize / 2);
+
+    let (limited, limited_requests) = select_requests(&table, Some(10));
+    assert_eq!(limited, 10);
+    assert!(limited_requests * 100 < all_requests);
+
+    let selected = table
+        .select_query_limit(1, 1, &[1, 0, 1], ResultOrder::Unordered, Some(3), None)
+        .unwrap();
+    assert_eq!(
+        selected
+            .iter()
+            .map(|record| record.columns.clone())
+            .collect::<Vec<_>>(),
+        [[1, 1], [3, 3], [5, 5]]
+    );
+
+    // Fewer matches than the limit are all returned
+    assert_eq!(
+        table
+            .select_query_limit(5, 0, &[1, 1, 1], ResultOrder::Unordered, Some(10), None)
+            .unwrap()
+            .len(),
+        1
+    );
+    assert!(table
+        .select_query_limit(1, 1, &[1, 1, 1], ResultOrder::Unordered, Some(0), None)
+        .unwrap()
+        .is_empty());
+
+    db.close();
+}
+
+#[test]
+fn limit_stops_index_lookup_early() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 3, 0);
+    table.build_index(1);
+    populate(&table);
+    // Rows updated away from 1 are still in the index under it
+    for key in (1..NUMBER_OF_RECORDS).step_by(4) {
+        table
+            .update_query(key, &[None, Some(2), None], None)
+            .unwrap();
+    }
+
+    let (all, all_requests) = select_requests(&table, None);
+    assert_eq!(all, NUMBER_OF_RECORDS as usize / 4);
+
+    let (limited, limited_requests) = select_requests(&table, Some(10));
+    assert_eq!(limited, 10);
+    assert!(limited_requests * 100 < all_requests);
+
+    table.freeze().unwrap();
+    assert_eq!(select_requests(&table, Some(10)).0, 10);
+    table.unfreeze();
+
+    db.close();
+}
+
+#[test]
+fn limit_only_locks_returned_rows() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 3, 0);
+    populate(&table);
+
+    let mut transaction = Transaction::new();
+    let selected = table
+        .select_query_limit(
+            1,
+            1,
+            &[1, 0, 0],
+            ResultOrder::Unordered,
+            Some(2),
+            Some(&mut transaction),
+        )
+        .unwrap();
+    assert_eq!(selected.len(), 2);
+
+    // Only the rows returned were locked, the ones past the limit never were
+    assert!(table
+        .get_lock_manager()
+        .is_locked(table.select_rids(1, 0).unwrap()[0]));
+    assert!(!table
+        .get_lock_manager()
+        .is_locked(table.select_rids(5, 0).unwrap()[0]));
+
+    db.close();
+}
+
+#[test]
+fn count_bounded_stops_at_bound() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 3, 0);
+    populate(&table);
+
+    table.reset_stats();
+    assert_eq!(table.select_count_bounded(1, 1, 101).unwrap(), 101);
+    let bounded_requests = table.bufferpool_stats().requests;
+
+    table.reset_stats();
+    assert_eq!(
+        table
+            .select_count_bounded(1, 1, NUMBER_OF_RECORDS as usize)
+            .unwrap(),
+        NUMBER_OF_RECORDS as usize / 2
+    );
+    assert!(bounded_requests * 10 < table.bufferpool_stats().requests);
+
+    assert_eq!(table.select_count_bounded(7, 0, 5).unwrap(), 1);
+    assert_eq!(table.select_count_bounded(2, 1, 5).unwrap(), 0);
+
+    db.close();
+}
+
+#[test]
+fn count_matches_select() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 3, 0);
+    populate(&table);
+    for key in (0..NUMBER_OF_RECORDS).step_by(3) {
+        table.delete_query(key, None).unwrap();
+    }
+    for key in (1..NUMBER_OF_RECORDS).step_by(7) {
+        table
+            .update_query(key, &[None, Some(2), None], None)
+            .unwrap();
+    }
+
+    let check = |table: &Table| {
+        for (value, column) in [(0, 1), (1, 1), (2, 1), (3, 1), (5, 0), (6, 0), (5, 2)] {
+            table.reset_stats();
+            let selected = table.select_query(value, column, &[1, 1, 1], None).unwrap();
+            let select_requests = table.bufferpool_stats().requests;
+
+            table.reset_stats();
+            assert_eq!(
+                table.count_query(value, column).unwrap(),
+                selected.len(),
+                "{value} in column {column}"
+            );
+            if !selected.is_empty() {
+                assert!(table.bufferpool_stats().requests < select_requests);
+            }
+        }
+    };
+
+    check(&table);
+    table.build_index(1);
+    table.build_index(2);
+    check(&table);
+
+    db.close();
+}
@@ -0,0 +1,164 @@
+use crabcore::{crabstore::CrabStore, error::CrabError, schema::ColumnType, table::Table};
+use tempfile::tempdir;
+
+fn signed(value: i64) -> u64 {
+    value as u64
+}
+
+fn range_keys(table: &Table, start: i64, end: i64, column_index: usize) -> Vec<i64> {
+    table
+        .select_range_query(
+            signed(start),
+            signed(end),
+            column_index,
+            &[1, 0],
+            None,
+            None,
+        )
+        .unwrap()
+        .into_iter()
+        .map(|record| record.columns[0] as i64)
+        .collect()
+}
+
+fn check_ranges(table: &Table) {
+    // Through the key index, and negative keys below zero instead of above every positive one
+    assert_eq!(
+        range_keys(table, -10, 10, 0),
+        (-10..=10).collect::<Vec<_>>()
+    );
+    assert_eq!(range_keys(table, -3, -1, 0), [-3, -2, -1]);
+    assert!(range_keys(table, 10, -10, 0).is_empty());
+
+    // Column 1 holds the negated key
+    assert_eq!(range_keys(table, -2, 1, 1), [2, 1, 0, -1]);
+
+    assert_eq!(
+        table
+            .iter_keys()
+            .unwrap()
+            .map(|key| key as i64)
+            .collect::<Vec<_>>(),
+        (-20..=20).collect::<Vec<_>>()
+    );
+
+    assert_eq!(table.sum_query(signed(-20), signed(-1), 1, None), Ok(210));
+    assert_eq!(table.sum_query(signed(-10), signed(5), 1, None), Ok(40));
+    assert_eq!(
+        table.sum_query(signed(1), signed(20), 1, None),
+        Ok(signed(-210))
+    );
+    assert_eq!(
+        table.min_query(signed(-5), signed(5), 1, None),
+        Ok(Some(signed(-5)))
+    );
+    assert_eq!(
+        table.max_query(signed(-5), signed(5), 1, None),
+        Ok(Some(signed(5)))
+    );
+    assert_eq!(
+        table.avg_query(signed(-20), signed(0), 1, None),
+        Ok(Some(10.0))
+    );
+}
+
+#[test]
+fn signed_columns_order_below_zero() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db
+        .create_table_typed(
+            "Signed",
+            vec![ColumnType::Signed, ColumnType::Signed],
+            Vec::new(),
+            0,
+        )
+        .unwrap();
+    assert_eq!(table.column_types(), [ColumnType::Signed; 2]);
+
+    for key in -20..=20i64 {
+        table
+            .insert_query(&[signed(key), signed(-key)], None)
+            .unwrap();
+    }
+    assert_eq!(
+        table.select_query(signed(-7), 0, &[1, 1], None).unwrap()[0].columns,
+        [signed(-7), 7]
+    );
+
+    // Column 1 scanned, then through its index
+    check_ranges(&table);
+    table.build_index(1);
+    check_ranges(&table);
+
+    table.freeze().unwrap();
+    check_ranges(&table);
+    table.unfreeze();
+
+    db.close();
+
+    // The index file holds the values, and is keyed as signed again on open
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+    let table = db.get_table("Signed");
+    assert_eq!(table.column_types(), [ColumnType::Signed; 2]);
+    check_ranges(&table);
+
+    db.compact_table("Signed").unwrap();
+    check_ranges(&db.get_table("Signed"));
+
+    db.close();
+}
+
+#[test]
+fn sums_that_overflow_fail() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db
+        .create_table_typed(
+            "Sums",
+            vec![
+                ColumnType::Unsigned,
+                ColumnType::Unsigned,
+                ColumnType::Signed,
+            ],
+            Vec::new(),
+            0,
+        )
+        .unwrap();
+
+    table
+        .insert_query(&[0, u64::MAX, signed(i64::MAX)], None)
+        .unwrap();
+    table.insert_query(&[1, 1, 1], None).unwrap();
+    table.insert_query(&[2, 0, signed(i64::MIN)], None).unwrap();
+    table.insert_query(&[3, 0, signed(-1)], None).unwrap();
+
+    // Bits that read as -1 in a signed column are u64::MAX in an unsigned one
+    assert_eq!(table.sum_query(0, 0, 1, None), Ok(u64::MAX));
+    assert_eq!(
+        table.sum_query(0, 1, 1, None),
+        Err(CrabError::SumOverflow(1))
+    );
+
+    assert_eq!(
+        table.sum_query(0, 1, 2, None),
+        Err(CrabError::SumOverflow(2))
+    );
+    assert_eq!(
+        table.sum_query(2, 3, 2, None),
+        Err(CrabError::SumOverflow(2))
+    );
+    // Overflows on the way, but the sum itself fits
+    assert_eq!(table.sum_query(0, 3, 2, None), Ok(signed(-1)));
+    assert_eq!(table.sum_query(1, 3, 2, None), Ok(signed(i64::MIN)));
+
+    assert_eq!(table.sum_original(0, 1, 2), Err(CrabError::SumOverflow(2)));
+    assert_eq!(table.sum_version_query(0, 3, 2, 0), Ok(signed(-1)));
+
+    db.close();
+}
@@ -0,0 +1,178 @@
+use crabcore::{
+    crabstore::CrabStore,
+    table::{ResultOrder, Table},
+};
+use std::{sync::Arc, thread};
+use tempfile::tempdir;
+
+const NUMBER_OF_RECORDS: u64 = 20000;
+
+fn assert_close(estimate: u64, actual: u64) {
+    let error = (estimate as f64 - actual as f64).abs() / actual as f64;
+    assert!(
+        error < 0.05,
+        "estimated {estimate} distinct values, {actual} actual"
+    );
+}
+
+fn assert_loaded_stats(table: &Table) {
+    let stats = table.column_stats();
+
+    assert_close(stats[0].distinct, NUMBER_OF_RECORDS);
+    assert_close(stats[1].distinct, 10);
+    assert_close(stats[2].distinct, 1000);
+
+    assert_eq!(
+        (stats[0].min, stats[0].max),
+        (Some(0), Some(NUMBER_OF_RECORDS - 1))
+    );
+    assert_eq!((stats[1].min, stats[1].max), (Some(0), Some(9)));
+    assert_eq!((stats[2].min, stats[2].max), (Some(5), Some(1004)));
+    assert!(stats.iter().all(|column| column.null_fraction == 0.0));
+}
+
+#[test]
+fn stats_track_loaded_data() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 3, 0);
+    assert!(table
+        .column_stats()
+        .iter()
+        .all(|column| column.distinct == 0 && column.min.is_none() && column.max.is_none()));
+
+    for key in 0..NUMBER_OF_RECORDS {
+        table
+            .insert_query(&[key, key % 10, key % 1000 + 5], None)
+            .unwrap();
+    }
+
+    // Kept up by the inserts, and recomputing changes nothing
+    assert_loaded_stats(&table);
+    let incremental = table.column_stats();
+    table.analyze().unwrap();
+    assert_eq!(table.column_stats(), incremental);
+
+    db.close();
+
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+    assert_eq!(db.get_table("Grades").column_stats(), incremental);
+    db.close();
+}
+
+#[test]
+fn analyze_drops_overwritten_values() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 3, 0);
+    for key in 0..1000 {
+        table.insert_query(&[key, key, 0], None).unwrap();
+    }
+
+    for key in 0..1000 {
+        table
+            .update_query(key, &[None, Some(key % 4 + 100), None], None)
+            .unwrap();
+    }
+
+    // Writes only ever add values
+    let stats = table.column_stats();
+    assert!(stats[1].distinct > 900);
+    assert_eq!((stats[1].min, stats[1].max), (Some(0), Some(999)));
+
+    table.analyze().unwrap();
+
+    let stats = table.column_stats();
+    assert_eq!(stats[1].distinct, 4);
+    assert_eq!((stats[1].min, stats[1].max), (Some(100), Some(103)));
+
+    db.close();
+}
+
+#[test]
+fn predicates_ordered_by_selectivity() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 3, 0);
+    for key in 0..2000 {
+        table.insert_query(&[key, key % 2, key], None).unwrap();
+    }
+
+    // Column 2 is unique, column 1 only splits the rows in half
+    assert_eq!(table.plan_predicates(&[(1, 1), (2, 5)]), [(2, 5), (1, 1)]);
+    assert_eq!(
+        table
+            .select_where(&[(1, 1), (2, 5)], &[1, 1, 1], ResultOrder::Unordered, None)
+            .unwrap()
+            .into_iter()
+            .map(|record| record.columns)
+            .collect::<Vec<_>>(),
+        [[5, 1, 5]]
+    );
+    assert!(table
+        .select_where(&[(1, 0), (2, 5)], &[1, 1, 1], ResultOrder::Unordered, None)
+        .unwrap()
+        .is_empty());
+
+    // The key index counts exactly, one row at most
+    assert_eq!(table.plan_predicates(&[(1, 1), (0, 7)]), [(0, 7), (1, 1)]);
+
+    for key in 0..2000 {
+        table
+            .update_query(key, &[None, Some(key), Some(key % 2)], None)
+            .unwrap();
+    }
+    table.analyze().unwrap();
+
+    // Now column 1 is unique and column 2 isn't
+    assert_eq!(table.plan_predicates(&[(1, 5), (2, 1)]), [(1, 5), (2, 1)]);
+    assert_eq!(
+        table
+            .select_where(&[(2, 1), (1, 5)], &[1, 0, 0], ResultOrder::Unordered, None)
+            .unwrap()
+            .into_iter()
+            .map(|record| record.columns)
+            .collect::<Vec<_>>(),
+        [[5]]
+    );
+
+    db.close();
+}
+
+#[test]
+fn analyze_runs_alongside_writes() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 2, 0);
+    for key in 0..NUMBER_OF_RECORDS {
+        table.insert_query(&[key, key % 10], None).unwrap();
+    }
+
+    let writer = {
+        let table = Arc::clone(&table);
+        thread::spawn(move || {
+            for key in NUMBER_OF_RECORDS..2 * NUMBER_OF_RECORDS {
+                table.insert_query(&[key, key % 10], None).unwrap();
+            }
+        })
+    };
+
+    table.analyze().unwrap();
+    writer.join().unwrap();
+
+    // Whatever the analyze saw, every row written before it started is counted
+    let stats = table.column_stats();
+    assert!(stats[0].distinct as f64 > NUMBER_OF_RECORDS as f64 * 0.95);
+    assert_eq!(stats[1].distinct, 10);
+
+    db.close();
+}
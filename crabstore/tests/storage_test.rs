@@ -0,0 +1,286 @@
+use crabcore::{config::CrabConfig, crabstore::CrabStore, error::CrabError};
+use std::{fs, thread, time::Duration};
+use tempfile::tempdir;
+
+#[test]
+fn deleted_directory_fails_queries() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 3, 0);
+    for key in 0..100 {
+        table.insert_query(&[key, key * 10, 0], None).unwrap();
+    }
+    table.checkpoint().unwrap();
+
+    fs::remove_dir_all(dir.path()).unwrap();
+
+    // The table file is still open, but the directory files can't be replaced
+    assert!(matches!(
+        table.checkpoint(),
+        Err(CrabError::StorageUnavailable(_))
+    ));
+
+    // From another thread too, every query fails from now on instead of reading garbage
+    let reader = thread::spawn({
+        let table = table.clone();
+        move || table.select_query(5, 0, &[1, 1, 1], None)
+    });
+    assert!(matches!(
+        reader.join().unwrap(),
+        Err(CrabError::StorageUnavailable(_))
+    ));
+    assert!(matches!(
+        table.insert_query(&[100, 0, 0], None),
+        Err(CrabError::StorageUnavailable(_))
+    ));
+
+    assert!(matches!(
+        table.persist(),
+        Err(CrabError::StorageUnavailable(_))
+    ));
+    assert!(matches!(
+        table.select_query(5, 0, &[1, 1, 1], None),
+        Err(CrabError::TableClosed)
+    ));
+
+    // Closing reports the failures rather than panicking
+    db.close();
+}
+
+#[test]
+fn table_files_grow_in_extents() {
+    let extent_size = 64 * 4096;
+
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::with_config(
+        dir.path().into(),
+        CrabConfig {
+            extent_size,
+            ..CrabConfig::default()
+        },
+    );
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 3, 0);
+    let file_len = || {
+        fs::metadata(CrabStore::table_filename(dir.path(), "Grades"))
+            .unwrap()
+            .len() as usize
+    };
+
+    let mut lengths = Vec::new();
+    for key in 0..512 * 40 {
+        table.insert_query(&[key, key, key], None).unwrap();
+        if key % 512 == 0 {
+            lengths.push(file_len());
+        }
+    }
+    lengths.dedup();
+
+    // A few steps of whole extents, each ahead of the pages reserved so far
+    assert!(lengths.len() > 1 && lengths.len() < 10, "{lengths:?}");
+    assert!(
+        lengths.iter().all(|len| len % extent_size == 0),
+        "{lengths:?}"
+    );
+    let file_pages = table.storage_stats().unwrap().file_pages;
+    assert!(file_len() >= file_pages * 4096);
+
+    // Closing gives back the part never reserved
+    db.close();
+    assert_eq!(file_len(), file_pages * 4096);
+
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+    let table = db.get_table("Grades");
+    assert_eq!(
+        table
+            .select_query(512 * 40 - 1, 0, &[1, 1, 1], None)
+            .unwrap()[0]
+            .columns,
+        [512 * 40 - 1; 3]
+    );
+    db.close();
+}
+
+#[test]
+fn dirty_pages_are_written_in_the_background() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::with_config(
+        dir.path().into(),
+        CrabConfig {
+            flush_interval: Duration::from_secs(3600),
+            ..CrabConfig::default()
+        },
+    );
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 3, 0);
+    for key in 0..1000 {
+        table.insert_query(&[key, key * 10, 0], None).unwrap();
+    }
+    let dirty_count = || table.get_bufferpool().dirty_count();
+    assert!(dirty_count() > 0);
+
+    // Changing the interval wakes the flusher up, it doesn't wait out the hour
+    table.set_flush_interval(Duration::from_millis(10));
+    let start = std::time::Instant::now();
+    while dirty_count() > 0 {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(5));
+    }
+
+    // The written pages stay cached
+    let misses = table.bufferpool_stats().misses;
+    assert_eq!(
+        table.select_query(999, 0, &[1, 1, 1], None).unwrap()[0].columns,
+        [999, 9990, 0]
+    );
+    assert_eq!(table.bufferpool_stats().misses, misses);
+
+    table
+        .update_query(999, &[None, Some(1), None], None)
+        .unwrap();
+    db.close();
+
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+    let table = db.get_table("Grades");
+    assert_eq!(
+        table.select_query(999, 0, &[1, 1, 1], None).unwrap()[0].columns,
+        [999, 1, 0]
+    );
+    db.close();
+}
+
+fn file_pages(base_page_batch: usize, rows: u64) -> usize {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::with_config(
+        dir.path().into(),
+        CrabConfig {
+            base_page_batch,
+            ..CrabConfig::default()
+        },
+    );
+    db.open().unwrap();
+
+    let table = db.create_table("Wide", 30, 0);
+    for key in 0..rows {
+        table.insert_query(&[key; 30], None).unwrap();
+    }
+    let pages = table.storage_stats().unwrap().file_pages;
+
+    db.close();
+    pages
+}
+
+#[test]
+fn base_pages_allocated_in_batches() {
+    // Five metadata columns and the timestamps, and page 0 of the file is the header
+    let columns = 36;
+
+    assert_eq!(file_pages(0, 10), 1 + 16 * columns);
+    assert_eq!(file_pages(1, 10), 1 + columns);
+    assert_eq!(file_pages(4, 513), 1 + 4 * columns);
+    assert_eq!(file_pages(4, 512 * 4 + 1), 1 + 8 * columns);
+}
+
+#[test]
+fn batch_not_dividing_range_allocates_whole_ranges() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::with_config(
+        dir.path().into(),
+        CrabConfig {
+            page_range_pages: 6,
+            ..CrabConfig::default()
+        },
+    );
+    db.open().unwrap();
+    let table = db.create_table("Grades", 3, 0);
+    table.insert_query(&[0, 0, 0], None).unwrap();
+    db.close();
+
+    // The table keeps its ranges of 6 pages, which batches of 4 don't divide
+    let mut db = CrabStore::with_config(
+        dir.path().into(),
+        CrabConfig {
+            base_page_batch: 4,
+            ..CrabConfig::default()
+        },
+    );
+    db.open().unwrap();
+
+    let table = db.get_table("Grades");
+    assert_eq!(table.page_range_pages(), 6);
+    for key in 1..512 * 7 {
+        table.insert_query(&[key, key * 2, 0], None).unwrap();
+    }
+    // Five metadata columns and the timestamps, and page 0 of the file is the header
+    assert_eq!(table.storage_stats().unwrap().file_pages, 1 + 12 * 9);
+    assert_eq!(
+        table.select_query(512 * 6, 0, &[1, 1, 1], None).unwrap()[0].columns,
+        [512 * 6, 512 * 12, 0]
+    );
+    db.close();
+}
+
+#[test]
+fn concurrent_first_inserts_allocate_once() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::with_config(
+        dir.path().into(),
+        CrabConfig {
+            base_page_batch: 1,
+            ..CrabConfig::default()
+        },
+    );
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 3, 0);
+    let threads = 8;
+    let rows_per_thread = 2000;
+
+    // Every thread keeps running into pages of the same ranges nobody has allocated yet
+    thread::scope(|s| {
+        for thread in 0..threads {
+            let table = &table;
+            s.spawn(move || {
+                for i in 0..rows_per_thread {
+                    let key = i * threads + thread;
+                    table.insert_query(&[key, key * 2, 0], None).unwrap();
+                }
+            });
+        }
+    });
+
+    for key in 0..threads * rows_per_thread {
+        let rows = table.select_query(key, 0, &[1, 1, 1], None).unwrap();
+        assert_eq!(rows[0].columns, [key, key * 2, 0]);
+    }
+
+    // Each page was allocated exactly once
+    let rows = (threads * rows_per_thread) as usize;
+    let stats = table.storage_stats().unwrap();
+    assert_eq!(stats.base_pages, rows.div_ceil(512));
+    assert_eq!(stats.file_pages, 1 + stats.base_pages * 9);
+
+    // Merging a range whose later pages were never allocated
+    let updates = 512 * 5;
+    for i in 0..updates {
+        table
+            .update_query(i % 16, &[None, None, Some(i)], None)
+            .unwrap();
+    }
+
+    table.quiesce(Duration::from_secs(10)).unwrap();
+    assert_ne!(table.merge_stats().merges, 0, "merge never ran");
+
+    for key in 0..16 {
+        let rows = table.select_query(key, 0, &[1, 1, 1], None).unwrap();
+        assert_eq!(rows[0].columns, [key, key * 2, updates - 16 + key]);
+    }
+
+    db.close();
+}
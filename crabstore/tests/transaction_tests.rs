@@ -2,12 +2,26 @@
 extern crate test;
 use core::num;
 use crabcore::{
+    config::CrabConfig,
     crabstore::CrabStore,
-    transaction::{Query, Transaction},
-    transaction_worker::TransactionWorker,
+    error::CrabError,
+    lock_manager::{ConcurrencyPolicy, LockType},
+    rid::RID,
+    table::{ResultOrder, Table, UpdateOp},
+    transaction::{
+        AbortKind, ConstraintPolicy, Query, QueryResult, QueryStatus, Transaction, TransactionError,
+    },
+    transaction_scheduler::TransactionScheduler,
+    transaction_worker::{RetryPolicy, TransactionWorker},
 };
 use rand::prelude::*;
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, Barrier},
+    thread,
+    time::Duration,
+};
 use tempfile::tempdir;
 use test::Bencher;
 
@@ -26,7 +40,7 @@ const NUM_THREADS: u64 = 4;
 fn transaction_test2(dir: &Path) {
     let mut rand = StdRng::seed_from_u64(3562901);
     let mut crabstore = CrabStore::new(dir.into());
-    crabstore.open();
+    crabstore.open().unwrap();
 
     let grades = crabstore.get_table("Grades");
     let mut records: HashMap<u64, Vec<u64>> = HashMap::new();
@@ -52,7 +66,10 @@ fn transaction_test2(dir: &Path) {
     }
 
     for key in keys.iter() {
-        let record = &grades.select_query(*key, 0, &[1, 1, 1, 1, 1], None)[0].columns;
+        let record = &grades
+            .select_query(*key, 0, &[1, 1, 1, 1, 1], None)
+            .unwrap()[0]
+            .columns;
 
         for (i, col) in record.iter().enumerate() {
             assert_eq!(*col, records.get(key).unwrap()[i]);
@@ -100,7 +117,10 @@ fn transaction_test2(dir: &Path) {
     let mut score = keys.len();
 
     for key in keys.iter() {
-        let record = &grades.select_query(*key, 0, &[1, 1, 1, 1, 1], None)[0].columns;
+        let record = &grades
+            .select_query(*key, 0, &[1, 1, 1, 1, 1], None)
+            .unwrap()[0]
+            .columns;
 
         for (i, col) in record.iter().enumerate() {
             if *col != records.get(key).unwrap()[i] {
@@ -176,7 +196,7 @@ fn transaction_test1(dir: &Path) {
     }
 
     for key in keys {
-        let record = &grades.select_query(key, 0, &[1, 1, 1, 1, 1], None)[0].columns;
+        let record = &grades.select_query(key, 0, &[1, 1, 1, 1, 1], None).unwrap()[0].columns;
 
         for (i, col) in record.iter().enumerate() {
             assert_eq!(*col, records.get(&key).unwrap()[i]);
@@ -185,3 +205,1233 @@ fn transaction_test1(dir: &Path) {
 
     crabstore.close();
 }
+
+fn insert_with_duplicate(dir: &Path, policy: ConstraintPolicy) -> (bool, Transaction, Vec<u64>) {
+    let mut crabstore = CrabStore::new(dir.into());
+    crabstore.open().unwrap();
+
+    let grades = crabstore.create_table("Grades", 2, 0);
+    grades.insert_query(&[3, 30], None).unwrap();
+
+    let mut transaction = Transaction::with_constraint_policy(policy);
+    for key in [1, 2, 3, 4, 5] {
+        transaction.add_query(Query::Insert(Box::new([key, key * 100])), &grades);
+    }
+
+    let committed = transaction.run().is_ok();
+
+    let present = (1..=5)
+        .filter(|key| {
+            let selected = grades.select_query(*key, 0, &[1, 1], None).unwrap();
+            !selected.is_empty()
+        })
+        .collect();
+
+    crabstore.close();
+
+    (committed, transaction, present)
+}
+
+#[test]
+fn duplicate_insert_aborts_transaction() {
+    let dir = tempdir().unwrap();
+    let (committed, transaction, present) =
+        insert_with_duplicate(dir.path(), ConstraintPolicy::Abort);
+
+    assert!(!committed);
+    assert_eq!(transaction.get_status(), QueryStatus::AbortedNotRetryable);
+    assert_eq!(
+        transaction.last_error(),
+        Some(&TransactionError {
+            query_index: 2,
+            table_name: "Grades".to_string(),
+            kind: AbortKind::DuplicateKey,
+        })
+    );
+    assert!(transaction.skipped_queries().is_empty());
+    assert_eq!(present, [3]);
+}
+
+#[test]
+fn duplicate_insert_skipped_in_transaction() {
+    let dir = tempdir().unwrap();
+    let (committed, transaction, present) =
+        insert_with_duplicate(dir.path(), ConstraintPolicy::Skip);
+
+    assert!(committed);
+    assert_eq!(transaction.get_status(), QueryStatus::Idle);
+    assert_eq!(transaction.skipped_queries(), [2]);
+    assert_eq!(
+        transaction.results(),
+        [true, true, false, true, true].map(QueryResult::Bool)
+    );
+    assert_eq!(present, [1, 2, 3, 4, 5]);
+}
+
+#[test]
+fn concurrent_inserts_of_one_key() {
+    let num_keys = 500;
+    let num_threads = 8;
+
+    let dir = tempdir().unwrap();
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("Grades", 2, 0);
+
+    for key in 0..num_keys * 2 {
+        let barrier = Barrier::new(num_threads);
+
+        let committed: usize = thread::scope(|s| {
+            let inserts = (0..num_threads as u64)
+                .map(|thread| {
+                    let (table, barrier) = (&table, &barrier);
+
+                    s.spawn(move || {
+                        barrier.wait();
+
+                        // The first half of the keys go through transactions
+                        if key >= num_keys {
+                            table.insert_query(&[key, thread], None).unwrap();
+                            return 0;
+                        }
+
+                        let mut transaction = Transaction::new();
+                        transaction.add_query(Query::Insert(Box::new([key, thread])), table);
+
+                        loop {
+                            if transaction.run().is_ok() {
+                                return 1;
+                            }
+                            if transaction.get_status() != QueryStatus::AbortedRetryable {
+                                return 0;
+                            }
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            inserts.into_iter().map(|t| t.join().unwrap()).sum()
+        });
+
+        if key < num_keys {
+            assert_eq!(committed, 1, "key {key}");
+        }
+        assert_eq!(table.select_query(key, 0, &[1, 1], None).unwrap().len(), 1);
+    }
+
+    // Every live base record is counted once, whatever its chain length
+    let rows: usize = table
+        .chain_histogram()
+        .unwrap()
+        .iter()
+        .map(|(_, rows)| rows)
+        .sum();
+    assert_eq!(rows as u64, num_keys * 2);
+
+    crabstore.close();
+}
+
+#[test]
+fn concurrent_writers_never_duplicate_keys() {
+    let num_keys = 1000;
+    let num_threads = 8;
+
+    let dir = tempdir().unwrap();
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("Grades", 2, 0);
+
+    let count = |key| table.select_query(key, 0, &[1, 1], None).unwrap().len();
+
+    // Every thread inserts every key, odd threads through transactions
+    thread::scope(|s| {
+        for thread in 0..num_threads {
+            let table = &table;
+
+            s.spawn(move || {
+                for key in 0..num_keys {
+                    if thread % 2 == 0 {
+                        table.insert_query(&[key, thread], None).unwrap();
+                        continue;
+                    }
+
+                    let mut transaction = Transaction::new();
+                    transaction.add_query(Query::Insert(Box::new([key, thread])), table);
+                    while transaction.run().is_err()
+                        && transaction.get_status() == QueryStatus::AbortedRetryable
+                    {
+                    }
+                }
+            });
+        }
+    });
+
+    assert!((0..num_keys).all(|key| count(key) == 1));
+    assert_eq!(table.iter_keys().unwrap().count() as u64, num_keys);
+
+    // Each thread moves its own rows onto the same new keys, which only one can get
+    let moved: usize = thread::scope(|s| {
+        let updates = (0..num_threads)
+            .map(|thread| {
+                let table = &table;
+
+                s.spawn(move || {
+                    (0..num_keys / num_threads)
+                        .filter(|i| {
+                            let key = thread * (num_keys / num_threads) + i;
+                            table
+                                .update_query(key, &[Some(num_keys + i), None], None)
+                                .unwrap()
+                        })
+                        .count()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        updates.into_iter().map(|t| t.join().unwrap()).sum()
+    });
+
+    assert_eq!(moved as u64, num_keys / num_threads);
+    assert!((num_keys..num_keys + num_keys / num_threads).all(|key| count(key) == 1));
+    assert_eq!(table.iter_keys().unwrap().count() as u64, num_keys);
+
+    crabstore.close();
+}
+
+#[test]
+fn transactional_sum_skips_rows_deleted_while_waiting() {
+    let dir = tempdir().unwrap();
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("Grades", 2, 0);
+
+    for key in 0..100 {
+        table.insert_query(&[key, 1], None).unwrap();
+    }
+
+    // Stands in for a transaction deleting key 3: it holds the row until it commits
+    let deleted = table.select_rids(3, 0).unwrap()[0];
+    let lock_manager = table.get_lock_manager();
+    let holder = lock_manager
+        .try_lock(deleted, LockType::Exclusive, Transaction::new().timestamp())
+        .unwrap();
+
+    let sum = thread::spawn({
+        let table = table.clone();
+        move || {
+            let mut transaction = Transaction::new();
+            transaction.set_concurrency_policy(ConcurrencyPolicy::WaitWithTimeout(
+                Duration::from_secs(10),
+            ));
+            let sum = table.sum_query(0, 99, 1, Some(&mut transaction)).unwrap();
+            (sum, transaction.get_status())
+        }
+    });
+
+    // Long enough for the sum to have found the row and be waiting on its lock
+    thread::sleep(Duration::from_millis(200));
+    table.delete_query(3, None).unwrap();
+    lock_manager.unlock(&holder);
+
+    assert_eq!(sum.join().unwrap(), (99, QueryStatus::Idle));
+
+    crabstore.close();
+}
+
+#[test]
+fn scheduler_balances_skewed_load() {
+    let dir = tempdir().unwrap();
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("Skewed", 3, 0);
+
+    for key in 0..NUMBER_OF_RECORDS {
+        table.insert_query(&[key, 0, 0], None).unwrap();
+    }
+
+    let mut rand = StdRng::seed_from_u64(3562901);
+    let mut scheduler = TransactionScheduler::new(NUM_THREADS as usize);
+    let mut expected = HashMap::new();
+
+    for i in 0..200 {
+        // Most transactions hammer the first few keys
+        let key = if i % 5 == 0 {
+            rand.gen_range(0..NUMBER_OF_RECORDS)
+        } else {
+            rand.gen_range(0..16)
+        };
+
+        let mut transaction = Transaction::new();
+        transaction.add_query(Query::Update(key, Box::new([None, Some(i), None])), &table);
+        *expected.entry(key).or_insert(0) += 1;
+        transaction.add_query(Query::Select(key, 0, Box::new([1, 1, 1])), &table);
+        scheduler.add_transaction(transaction);
+    }
+
+    scheduler.run();
+    scheduler.join();
+
+    let stats = scheduler.stats();
+    assert_eq!(stats.len(), NUM_THREADS as usize);
+    assert_eq!(stats.iter().map(|s| s.committed).sum::<usize>(), 200);
+
+    let mean = 200 / NUM_THREADS as usize;
+    for worker in stats {
+        assert!(worker.transactions <= 2 * mean, "{stats:?}");
+    }
+
+    for key in expected.keys() {
+        assert_eq!(
+            table.select_query(*key, 0, &[1, 1, 1], None).unwrap().len(),
+            1
+        );
+    }
+
+    crabstore.close();
+}
+
+fn contended_updates(table_policy: ConcurrencyPolicy, override_policy: Option<ConcurrencyPolicy>) {
+    let num_groups = 8;
+    let num_transactions = 400;
+
+    let dir = tempdir().unwrap();
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("Contended", 3, 0);
+    table.set_concurrency_policy(table_policy);
+
+    for key in 0..num_groups * 4 {
+        table.insert_query(&[key, 0, 0], None).unwrap();
+    }
+
+    let mut queues: Vec<Vec<Transaction>> = (0..NUM_THREADS).map(|_| Vec::new()).collect();
+
+    for i in 1..=num_transactions {
+        let group = i % num_groups;
+        let mut keys: Vec<u64> = (group * 4..group * 4 + 4).collect();
+
+        // Opposite lock orders so waiting policies have deadlocks to get out of
+        if i % 2 == 1 {
+            keys.reverse();
+        }
+
+        let mut transaction = Transaction::new();
+        if let Some(policy) = override_policy {
+            transaction.set_concurrency_policy(policy);
+        }
+
+        for key in keys {
+            transaction.add_query(Query::Select(key, 0, Box::new([1, 1, 1])), &table);
+            transaction.add_query(
+                Query::Update(key, Box::new([None, Some(i), Some(i)])),
+                &table,
+            );
+        }
+
+        queues[(i % NUM_THREADS) as usize].push(transaction);
+    }
+
+    let workers = queues
+        .into_iter()
+        .map(|mut queue| {
+            thread::spawn(move || {
+                let mut committed = 0;
+                while let Some(mut transaction) = queue.pop() {
+                    if transaction.run().is_ok() {
+                        committed += 1;
+                    } else {
+                        assert_eq!(transaction.get_status(), QueryStatus::AbortedRetryable);
+                        queue.insert(0, transaction);
+                    }
+                }
+                committed
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let committed: u64 = workers.into_iter().map(|w| w.join().unwrap()).sum();
+    assert_eq!(committed, num_transactions);
+
+    // Each transaction wrote its whole group, so the last one to commit owns all of it
+    for group in 0..num_groups {
+        let rows: Vec<Vec<u64>> = (group * 4..group * 4 + 4)
+            .map(|key| {
+                table.select_query(key, 0, &[1, 1, 1], None).unwrap()[0]
+                    .columns
+                    .clone()
+            })
+            .collect();
+
+        let winner = rows[0][1];
+        assert_ne!(winner, 0);
+        assert_eq!(winner % num_groups, group);
+
+        for row in rows {
+            assert_eq!(
+                row[1..],
+                [winner, winner],
+                "{table_policy:?} {override_policy:?}"
+            );
+        }
+    }
+
+    crabstore.close();
+}
+
+#[test]
+fn no_lost_updates_without_waiting() {
+    contended_updates(ConcurrencyPolicy::NoWait, None);
+}
+
+#[test]
+fn no_lost_updates_waiting_with_timeout() {
+    contended_updates(
+        ConcurrencyPolicy::WaitWithTimeout(Duration::from_millis(1)),
+        None,
+    );
+}
+
+#[test]
+fn no_lost_updates_with_wait_die() {
+    contended_updates(ConcurrencyPolicy::WaitDie, None);
+}
+
+/*
+    Every transaction reads then increments the same few rows, half of them in the
+    opposite order, so shared locks keep running into upgrades and each other
+*/
+#[test]
+fn wait_die_commits_everything_under_heavy_contention() {
+    let num_keys = 4;
+    let per_thread = 100;
+
+    let dir = tempdir().unwrap();
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("Hot", 3, 0);
+    table.set_concurrency_policy(ConcurrencyPolicy::WaitDie);
+
+    for key in 0..num_keys {
+        table.insert_query(&[key, 0, 0], None).unwrap();
+    }
+
+    thread::scope(|s| {
+        for thread in 0..NUM_THREADS {
+            let table = &table;
+
+            s.spawn(move || {
+                for _ in 0..per_thread {
+                    let mut keys = (0..num_keys).collect::<Vec<_>>();
+                    if thread % 2 == 1 {
+                        keys.reverse();
+                    }
+
+                    let mut transaction = Transaction::new();
+                    for key in keys {
+                        transaction.add_query(Query::Select(key, 0, Box::new([0, 1, 0])), table);
+                        transaction.add_query(
+                            Query::UpdateRange(
+                                key,
+                                key,
+                                Box::new([None, Some(UpdateOp::Add(1)), None]),
+                            ),
+                            table,
+                        );
+                    }
+
+                    while transaction.run().is_err() {
+                        assert_eq!(transaction.get_status(), QueryStatus::AbortedRetryable);
+                    }
+                }
+            });
+        }
+    });
+
+    for key in 0..num_keys {
+        assert_eq!(
+            table.select_query(key, 0, &[0, 1, 0], None).unwrap()[0].columns,
+            [NUM_THREADS * per_thread]
+        );
+    }
+    assert!(table.get_lock_manager().is_idle());
+
+    crabstore.close();
+}
+
+#[test]
+fn transaction_policy_overrides_table() {
+    contended_updates(ConcurrencyPolicy::NoWait, Some(ConcurrencyPolicy::WaitDie));
+}
+
+/*
+    Two threads read a counter and write it back plus one, so every write needs
+    the shared lock taken by its read upgraded
+*/
+#[test]
+fn read_then_write_loses_no_updates() {
+    let increments = 200;
+
+    let dir = tempdir().unwrap();
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("Counter", 2, 0);
+    table.insert_query(&[0, 0], None).unwrap();
+
+    thread::scope(|s| {
+        for _ in 0..2 {
+            let table = &table;
+
+            s.spawn(move || {
+                for _ in 0..increments {
+                    loop {
+                        let mut transaction = Transaction::new();
+                        let start = transaction.savepoint();
+
+                        if transaction.execute(Query::Select(0, 0, Box::new([0, 1])), table) {
+                            let value = match &transaction.results()[0] {
+                                QueryResult::Records(records) => records[0].columns[0],
+                                result => panic!("select returned {result:?}"),
+                            };
+                            let update = Query::Update(0, Box::new([None, Some(value + 1)]));
+
+                            if transaction.execute(update, table) {
+                                assert!(transaction.run().is_ok());
+                                break;
+                            }
+                        }
+
+                        // Let go of the read lock and start over
+                        assert_eq!(transaction.get_status(), QueryStatus::AbortedRetryable);
+                        transaction.rollback_to(start);
+                        assert!(transaction.run().is_ok());
+                    }
+                }
+            });
+        }
+    });
+
+    assert_eq!(
+        table.select_query(0, 0, &[0, 1], None).unwrap()[0].columns,
+        [2 * increments]
+    );
+    assert!(table.get_lock_manager().is_empty());
+
+    crabstore.close();
+}
+
+/*
+    Every table has its own lock manager, so a row of one table holding the same
+    RID as a row of another shares no lock with it
+*/
+#[test]
+fn same_rid_in_two_tables_locks_each() {
+    let dir = tempdir().unwrap();
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let a = crabstore.create_table("A", 2, 0);
+    let b = crabstore.create_table("B", 2, 0);
+    a.insert_query(&[1, 0], None).unwrap();
+    b.insert_query(&[2, 0], None).unwrap();
+
+    let rid = RID::from(0);
+
+    for first in [
+        Query::Select(1, 0, Box::new([1, 1])),
+        Query::Update(1, Box::new([None, Some(10)])),
+    ] {
+        let mut transaction = Transaction::new();
+        assert!(transaction.execute(first, &a));
+        assert!(transaction.execute(Query::Update(2, Box::new([None, Some(20)])), &b));
+
+        assert!(a.get_lock_manager().is_locked(rid));
+        assert!(b.get_lock_manager().is_locked_exclusive(rid));
+        assert!(transaction.has_lock(&b.get_lock_manager(), rid, LockType::Exclusive));
+
+        transaction.abort();
+        assert!(a.get_lock_manager().is_empty());
+        assert!(b.get_lock_manager().is_empty());
+    }
+
+    crabstore.close();
+}
+
+#[test]
+fn lock_table_empties_once_transactions_finish() {
+    let dir = tempdir().unwrap();
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table = crabstore.create_table("Grades", 3, 0);
+    table.set_concurrency_policy(ConcurrencyPolicy::WaitDie);
+
+    for key in 0..NUMBER_OF_RECORDS {
+        table.insert_query(&[key, 0, 0], None).unwrap();
+    }
+
+    // One entry per record while it is locked
+    let mut transaction = Transaction::new();
+    for key in 0..50 {
+        assert!(transaction.execute(Query::Select(key, 0, Box::new([1, 1, 1])), &table));
+    }
+    assert_eq!(table.get_lock_manager().len(), 50);
+    assert!(transaction.run().is_ok());
+    assert!(table.get_lock_manager().is_empty());
+
+    let mut rand = StdRng::seed_from_u64(3562901);
+    let mut workers = (0..NUM_THREADS)
+        .map(|_| TransactionWorker::new())
+        .collect::<Vec<_>>();
+
+    for i in 0..NUMBER_OF_TRANSACTIONS * 4 {
+        let mut transaction = Transaction::new();
+        for _ in 0..NUMBER_OF_OPERATIONS_PER_RECORD {
+            let key = rand.gen_range(0..NUMBER_OF_RECORDS);
+            transaction.add_query(Query::Select(key, 0, Box::new([1, 1, 1])), &table);
+            transaction.add_query(Query::Update(key, Box::new([None, Some(i), None])), &table);
+        }
+        workers[(i % NUM_THREADS) as usize].add_transaction(transaction);
+    }
+
+    for worker in workers.iter_mut() {
+        worker.run();
+    }
+    for worker in workers.iter_mut() {
+        worker.join();
+    }
+
+    assert!(table.get_lock_manager().is_empty());
+
+    crabstore.close();
+}
+
+/*
+    Workers draining one queue each take transactions from it as they finish the
+    last, so all of them get a share of the work
+*/
+#[test]
+fn workers_sharing_a_queue_split_the_work() {
+    let num_transactions = 1000;
+
+    let dir = tempdir().unwrap();
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+
+    let table = crabstore.create_table("Grades", 3, 0);
+    for key in 0..NUMBER_OF_RECORDS {
+        table.insert_query(&[key, 0, 0], None).unwrap();
+    }
+
+    let mut workers = vec![TransactionWorker::new()];
+    for _ in 1..NUM_THREADS {
+        workers.push(TransactionWorker::sharing(&workers[0]));
+    }
+
+    let mut rand = StdRng::seed_from_u64(7);
+    for i in 0..num_transactions {
+        let mut transaction = Transaction::new();
+        for _ in 0..NUMBER_OF_OPERATIONS_PER_RECORD {
+            let key = rand.gen_range(0..NUMBER_OF_RECORDS);
+            transaction.add_query(Query::Update(key, Box::new([None, Some(i), None])), &table);
+        }
+        // Any of them would do, they all add to the one queue
+        workers[0].add_transaction(transaction);
+    }
+
+    for worker in workers.iter_mut() {
+        worker.run();
+    }
+    for worker in workers.iter_mut() {
+        worker.join();
+    }
+
+    let mut committed = 0;
+    for worker in workers.iter_mut() {
+        assert_eq!(worker.stats().len(), worker.committed() + worker.aborted());
+        assert_eq!(worker.take_results().len(), worker.committed());
+        committed += worker.committed();
+    }
+    assert_eq!(committed, num_transactions as usize);
+    assert!(
+        workers
+            .iter()
+            .filter(|worker| worker.committed() > 0)
+            .count()
+            > 1
+    );
+    assert!(table.get_lock_manager().is_empty());
+
+    crabstore.close();
+}
+
+/*
+    Transactions that can never get their lock, since an open transaction holds
+    it all along, abort every time they run
+*/
+#[test]
+fn worker_gives_up_after_max_retries() {
+    let dir = tempdir().unwrap();
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+
+    let table = crabstore.create_table("Grades", 2, 0);
+    table.insert_query(&[1, 0], None).unwrap();
+
+    let mut holder = Transaction::new();
+    assert!(holder.execute(Query::Update(1, Box::new([None, Some(10)])), &table));
+
+    let contenders = || {
+        [20, 30]
+            .map(|value| {
+                let mut transaction = Transaction::new();
+                transaction.set_concurrency_policy(ConcurrencyPolicy::NoWait);
+                transaction.add_query(Query::Update(1, Box::new([None, Some(value)])), &table);
+                transaction
+            })
+            .into()
+    };
+
+    let mut worker = TransactionWorker::with_max_retries(3);
+    worker.add_transactions(contenders());
+    worker.run();
+    worker.join();
+
+    // Each ran once and was retried three times, losing the lock of row 1 every time
+    assert_eq!(worker.stats(), [false; 8]);
+    assert_eq!((worker.committed(), worker.aborted()), (0, 8));
+    let locked = table.select_rids(1, 0).unwrap()[0];
+    for error in worker.errors() {
+        assert_eq!(error.query_index, 0);
+        assert_eq!(error.table_name, "Grades");
+        assert_eq!(error.kind, AbortKind::LockConflict(locked));
+        assert!(error.is_retryable());
+    }
+    let failed = worker.failed_transactions();
+    assert_eq!(failed.len(), 2);
+    for transaction in failed.iter() {
+        assert_eq!(transaction.retries(), 3);
+        assert_eq!(transaction.get_status(), QueryStatus::AbortedRetryable);
+    }
+    assert!(worker.failed_transactions().is_empty());
+
+    // Backing off, and running them again once the lock is free
+    let mut worker = TransactionWorker::with_retry_policy(RetryPolicy {
+        max_retries: Some(2),
+        backoff: Some(Duration::from_millis(5)),
+    });
+    worker.add_transactions(contenders());
+    worker.run();
+    worker.join();
+    assert_eq!(worker.aborted(), 6);
+    assert_eq!(worker.failed_transactions().len(), 2);
+
+    assert!(holder.run().is_ok());
+    let mut worker = TransactionWorker::new();
+    worker.add_transactions(failed);
+    worker.run();
+    worker.join();
+    assert_eq!(worker.committed(), 2);
+    assert!(worker.failed_transactions().is_empty());
+    assert!(table.get_lock_manager().is_empty());
+
+    crabstore.close();
+}
+
+/*
+    Everything a rolled back delete could leave behind: latest and original
+    versions, the tail each row points at, both sums, and the rows the index
+    on column 1 finds
+*/
+type DeleteSnapshot = (Vec<Vec<u64>>, Vec<RID>, [u64; 2], Vec<Vec<RID>>);
+
+fn delete_snapshot(table: &Table) -> DeleteSnapshot {
+    let mut rows = Vec::new();
+    let mut latest = Vec::new();
+    for key in 0..100 {
+        rows.extend(
+            table
+                .select_query(key, 0, &[1, 1, 1], None)
+                .unwrap()
+                .into_iter()
+                .chain(table.select_original(key, &[1, 1, 1]).unwrap())
+                .map(|record| record.columns),
+        );
+        latest.extend(
+            table
+                .select_rids(key, 0)
+                .unwrap()
+                .into_iter()
+                .map(|rid| table.get_latest(rid)),
+        );
+    }
+
+    let sums = [
+        table.sum_query(0, 99, 2, None).unwrap(),
+        table.sum_original(0, 99, 2).unwrap(),
+    ];
+
+    let indexed = (0..10)
+        .map(|value| {
+            let mut rids = table.select_rids(value, 1).unwrap();
+            rids.sort_unstable();
+            rids
+        })
+        .collect();
+
+    (rows, latest, sums, indexed)
+}
+
+#[test]
+fn aborted_delete_restores_version_chains() {
+    let dir = tempdir().unwrap();
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+
+    let grades = crabstore.create_table("Grades", 3, 0);
+    grades.build_index(1);
+    for key in 0..100 {
+        grades.insert_query(&[key, key % 10, key], None).unwrap();
+    }
+    // Chains of a few tails each for delete to walk
+    for round in 1..4 {
+        for key in (0..100).step_by(3) {
+            grades
+                .update_query(
+                    key,
+                    &[None, Some((key + round) % 10), Some(key * round)],
+                    None,
+                )
+                .unwrap();
+        }
+    }
+
+    let before = delete_snapshot(&grades);
+
+    let mut transaction = Transaction::new();
+    for key in (0..100).step_by(5) {
+        transaction.add_query(Query::Delete(key), &grades);
+    }
+    // Key 0 was just deleted, but 1 is still there, so this forces the abort
+    transaction.add_query(Query::Insert(Box::new([1, 0, 0])), &grades);
+    assert!(transaction.run().is_err());
+
+    assert_eq!(delete_snapshot(&grades), before);
+
+    // A replica only follows tails whose RID is intact, so it sees the chains too
+    crabstore.checkpoint();
+    let mut reader = CrabStore::with_config(
+        dir.path().into(),
+        CrabConfig {
+            read_only: true,
+            ..Default::default()
+        },
+    );
+    reader.open().unwrap();
+    assert_eq!(delete_snapshot(&reader.get_table("Grades")), before);
+    reader.close();
+
+    // The restored chains still take new versions
+    grades
+        .update_query(0, &[None, None, Some(1000)], None)
+        .unwrap();
+    assert_eq!(
+        grades.select_query(0, 0, &[1, 1, 1], None).unwrap()[0].columns,
+        [0, 3, 1000]
+    );
+
+    crabstore.close();
+}
+
+#[test]
+fn aborted_inserts_leave_no_rows() {
+    let dir = tempdir().unwrap();
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+
+    let grades = crabstore.create_table("Grades", 3, 0);
+    grades.build_index(1);
+    grades.insert_query(&[3, 30, 0], None).unwrap();
+
+    // Some of the new rows are updated or deleted again before the duplicate aborts it all
+    let mut transaction = Transaction::new();
+    for key in 10..20 {
+        transaction.add_query(Query::Insert(Box::new([key, key % 2, key])), &grades);
+    }
+    transaction.add_query(Query::Update(11, Box::new([None, Some(7), None])), &grades);
+    transaction.add_query(Query::Delete(12), &grades);
+    transaction.add_query(Query::Insert(Box::new([3, 1, 0])), &grades);
+
+    assert!(transaction.run().is_err());
+    assert_eq!(transaction.get_status(), QueryStatus::AbortedNotRetryable);
+
+    for key in 10..20 {
+        assert!(grades
+            .select_query(key, 0, &[1, 1, 1], None)
+            .unwrap()
+            .is_empty());
+    }
+    for value in [0, 1, 7] {
+        assert!(grades.select_rids(value, 1).unwrap().is_empty());
+    }
+    assert_eq!(grades.scan().count(), 1);
+    assert_eq!(grades.sum_query(0, 100, 2, None).unwrap(), 0);
+
+    for key in 10..20 {
+        grades.insert_query(&[key, key % 2, key], None).unwrap();
+    }
+    assert_eq!(grades.select_rids(1, 1).unwrap().len(), 5);
+    assert_eq!(
+        grades.select_query(12, 0, &[1, 1, 1], None).unwrap()[0].columns,
+        [12, 0, 12]
+    );
+
+    crabstore.close();
+}
+
+/*
+    Moves amount from one balance to another if the first has enough, logging
+    the transfer under id. Whatever it decides, a transaction that fails
+    partway leaves nothing behind
+*/
+fn transfer(table: &Arc<Table>, id: u64, from: u64, to: u64, amount: u64) -> bool {
+    let mut transaction = Transaction::begin();
+
+    let balance = transaction.select(table, from, 0, &[0, 1]).unwrap()[0].columns[0];
+    if balance < amount {
+        transaction.abort();
+        return false;
+    }
+
+    let target = transaction.select(table, to, 0, &[0, 1]).unwrap()[0].columns[0];
+    assert!(transaction
+        .update(table, from, &[None, Some(balance - amount)])
+        .unwrap());
+    assert!(transaction
+        .update(table, to, &[None, Some(target + amount)])
+        .unwrap());
+
+    // A repeated id is a duplicate key, which aborts the whole transfer
+    match transaction.insert(table, &[id, amount]) {
+        Ok(_) => transaction.commit().is_ok(),
+        Err(CrabError::TransactionAborted { retryable: false }) => {
+            transaction.abort();
+            false
+        }
+        Err(err) => panic!("transfer failed with {err}"),
+    }
+}
+
+#[test]
+fn interactive_transactions_decide_on_their_reads() {
+    let dir = tempdir().unwrap();
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+
+    let table = crabstore.create_table("Accounts", 2, 0);
+    table.insert_query(&[1, 100], None).unwrap();
+    table.insert_query(&[2, 0], None).unwrap();
+
+    let balances = |table: &Table| {
+        [1, 2].map(|key| table.select_query(key, 0, &[0, 1], None).unwrap()[0].columns[0])
+    };
+
+    assert!(transfer(&table, 1000, 1, 2, 60));
+    assert_eq!(balances(&table), [40, 60]);
+
+    // Not enough left, so nothing is written
+    assert!(!transfer(&table, 1001, 1, 2, 60));
+    // Both updates ran before the duplicate id aborted the transfer
+    assert!(!transfer(&table, 1000, 2, 1, 10));
+    assert_eq!(balances(&table), [40, 60]);
+    assert_eq!(
+        table.select_query(1000, 0, &[0, 1], None).unwrap()[0].columns,
+        [60]
+    );
+    assert!(table.get_lock_manager().is_empty());
+
+    // A query that loses its lock is undone on its own, and the rest stays until the abort
+    let mut holder = Transaction::begin();
+    assert_eq!(holder.update(&table, 2, &[None, Some(70)]), Ok(true));
+
+    let mut transaction = Transaction::begin();
+    transaction.set_concurrency_policy(ConcurrencyPolicy::NoWait);
+    assert_eq!(transaction.update(&table, 1, &[None, Some(0)]), Ok(true));
+    assert_eq!(
+        transaction.select(&table, 2, 0, &[0, 1]),
+        Err(CrabError::TransactionAborted { retryable: true })
+    );
+    assert_eq!(transaction.sum(&table, 1, 1, 1), Ok(0));
+    transaction.abort();
+    assert_eq!(transaction.get_status(), QueryStatus::AbortedNotRetryable);
+
+    assert!(holder.commit().is_ok());
+    assert_eq!(table.sum_query(1, 2, 1, None), Ok(110));
+    assert_eq!(transaction.delete(&table, 2), Ok(true));
+    assert_eq!(transaction.delete(&table, 2), Ok(false));
+    assert!(transaction.commit().is_ok());
+    assert_eq!(table.sum_query(1, 2, 1, None), Ok(40));
+    assert!(table.get_lock_manager().is_empty());
+
+    crabstore.close();
+}
+
+/*
+    Reads made with a transaction that hasn't committed yet, after its own writes
+*/
+#[test]
+fn transaction_reads_its_own_writes() {
+    let dir = tempdir().unwrap();
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+
+    let grades = crabstore.create_table("Grades", 3, 0);
+    grades.build_index(1);
+    for key in 0..10 {
+        grades.insert_query(&[key, key % 2, key], None).unwrap();
+    }
+
+    let mut transaction = Transaction::new();
+
+    // Insert then select, by key and through the index on column 1
+    grades
+        .insert_query(&[20, 7, 200], Some(&mut transaction))
+        .unwrap();
+    assert_eq!(
+        grades
+            .select_query(20, 0, &[1, 1, 1], Some(&mut transaction))
+            .unwrap()[0]
+            .columns,
+        [20, 7, 200]
+    );
+    assert_eq!(
+        grades
+            .select_query(7, 1, &[1, 0, 1], Some(&mut transaction))
+            .unwrap()[0]
+            .columns,
+        [20, 200]
+    );
+
+    // Update then sum, over a range that includes the inserted row
+    grades
+        .update_query(3, &[None, Some(7), Some(30)], Some(&mut transaction))
+        .unwrap();
+    assert_eq!(
+        grades.sum_query(0, 20, 2, Some(&mut transaction)).unwrap(),
+        45 - 3 + 30 + 200
+    );
+    assert_eq!(
+        grades
+            .select_where(
+                &[(1, 7), (2, 30)],
+                &[1, 0, 0],
+                ResultOrder::Unordered,
+                Some(&mut transaction)
+            )
+            .unwrap()[0]
+            .columns,
+        [3]
+    );
+    assert_eq!(
+        grades
+            .select_query(1, 1, &[1, 0, 0], Some(&mut transaction))
+            .unwrap()
+            .len(),
+        4
+    );
+
+    // Delete then select, both the updated row and the inserted one
+    grades.delete_query(3, Some(&mut transaction)).unwrap();
+    grades.delete_query(20, Some(&mut transaction)).unwrap();
+    assert!(grades
+        .select_query(3, 0, &[1, 1, 1], Some(&mut transaction))
+        .unwrap()
+        .is_empty());
+    assert!(grades
+        .select_query(7, 1, &[1, 1, 1], Some(&mut transaction))
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        grades.sum_query(0, 20, 2, Some(&mut transaction)).unwrap(),
+        45 - 3
+    );
+
+    // Updates made by update_range are seen the same way
+    grades
+        .update_range(
+            0,
+            4,
+            &[None, None, Some(UpdateOp::Add(100))],
+            Some(&mut transaction),
+        )
+        .unwrap();
+    assert_eq!(
+        grades.sum_query(0, 4, 2, Some(&mut transaction)).unwrap(),
+        1 + 2 + 4 + 400
+    );
+
+    crabstore.close();
+}
+
+#[test]
+fn transaction_results_match_committed_state() {
+    let dir = tempdir().unwrap();
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+
+    let grades = crabstore.create_table("Grades", 3, 0);
+    for key in 0..10 {
+        grades.insert_query(&[key, key, 0], None).unwrap();
+    }
+
+    let mut transaction = Transaction::new();
+    transaction.add_query(Query::Insert(Box::new([10, 10, 0])), &grades);
+    transaction.add_query(Query::Update(3, Box::new([None, Some(30), None])), &grades);
+    transaction.add_query(Query::Update(11, Box::new([None, Some(1), None])), &grades);
+    transaction.add_query(
+        Query::UpdateRange(0, 4, Box::new([None, None, Some(UpdateOp::Add(1))])),
+        &grades,
+    );
+    transaction.add_query(Query::Delete(9), &grades);
+    transaction.add_query(Query::Upsert(Box::new([12, 12, 0])), &grades);
+    transaction.add_query(Query::Select(3, 0, Box::new([1, 1, 1])), &grades);
+    transaction.add_query(Query::Select(9, 0, Box::new([1, 1, 1])), &grades);
+    transaction.add_query(Query::Sum(0, 12, 1), &grades);
+
+    assert!(transaction.results().is_empty());
+    assert!(transaction.run().is_ok());
+
+    let select = |key| grades.select_query(key, 0, &[1, 1, 1], None).unwrap();
+    assert_eq!(
+        transaction.results(),
+        [
+            QueryResult::Bool(true),
+            QueryResult::Bool(true),
+            QueryResult::Bool(false),
+            QueryResult::Sum(5),
+            QueryResult::Bool(true),
+            QueryResult::Bool(true),
+            QueryResult::Records(select(3)),
+            QueryResult::Records(select(9)),
+            QueryResult::Sum(grades.sum_query(0, 12, 1, None).unwrap()),
+        ]
+    );
+    assert_eq!(select(3)[0].columns, [3, 30, 1]);
+    assert!(select(9).is_empty());
+
+    // An aborted transaction has nothing to show
+    let mut transaction = Transaction::new();
+    transaction.add_query(Query::Select(3, 0, Box::new([1, 1, 1])), &grades);
+    transaction.add_query(Query::Insert(Box::new([3, 0, 0])), &grades);
+
+    assert!(transaction.run().is_err());
+    assert!(transaction.results().is_empty());
+
+    // Workers keep what their committed transactions read
+    let mut worker = TransactionWorker::new();
+    for key in 0..4 {
+        let mut transaction = Transaction::new();
+        transaction.add_query(
+            Query::Update(key, Box::new([None, Some(key * 2), None])),
+            &grades,
+        );
+        transaction.add_query(Query::Select(key, 0, Box::new([1, 1, 0])), &grades);
+        worker.add_transaction(transaction);
+    }
+    worker.run();
+    worker.join();
+
+    let results = worker.take_results();
+    assert_eq!(results.len(), 4);
+    for (key, results) in (0..4).zip(results) {
+        match &results[1] {
+            QueryResult::Records(records) => assert_eq!(records[0].columns, [key, key * 2]),
+            result => panic!("select returned {result:?}"),
+        }
+    }
+    assert!(worker.take_results().is_empty());
+
+    crabstore.close();
+}
+
+#[test]
+fn rollback_to_savepoint_keeps_earlier_updates() {
+    let dir = tempdir().unwrap();
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+
+    let grades = crabstore.create_table("Grades", 3, 0);
+    for key in 0..10 {
+        grades.insert_query(&[key, 0, 0], None).unwrap();
+    }
+
+    let mut transaction = Transaction::new();
+    let mut savepoint = transaction.savepoint();
+    for key in 0..10 {
+        if key == 5 {
+            savepoint = transaction.savepoint();
+        }
+        assert!(transaction.execute(Query::Update(key, Box::new([None, Some(1), None])), &grades));
+    }
+
+    let select = |transaction: &mut Transaction, key| {
+        assert!(transaction.execute(Query::Select(key, 0, Box::new([1, 1, 1])), &grades));
+        match transaction.results().last() {
+            Some(QueryResult::Records(records)) => records[0].columns.clone(),
+            result => panic!("select returned {result:?}"),
+        }
+    };
+    assert_eq!(select(&mut transaction, 7), [7, 1, 0]);
+
+    // Locks taken after the savepoint are released, and reads see the rows as they were there
+    transaction.rollback_to(savepoint);
+    assert_eq!(transaction.results().len(), 5);
+
+    let mut other = Transaction::new();
+    other.set_concurrency_policy(ConcurrencyPolicy::NoWait);
+    other.add_query(Query::Update(8, Box::new([None, None, Some(8)])), &grades);
+    assert!(other.run().is_ok());
+    other.add_query(Query::Update(2, Box::new([None, None, Some(2)])), &grades);
+    assert!(other.run().is_err());
+    assert_eq!(other.get_status(), QueryStatus::AbortedRetryable);
+
+    assert_eq!(select(&mut transaction, 7), [7, 0, 0]);
+    assert_eq!(select(&mut transaction, 4), [4, 1, 0]);
+
+    // A failed query is undone on its own, and the transaction carries on
+    assert!(!transaction.execute(Query::Insert(Box::new([3, 0, 0])), &grades));
+    assert_eq!(transaction.get_status(), QueryStatus::AbortedNotRetryable);
+    assert!(transaction.run().is_ok());
+    assert_eq!(transaction.get_status(), QueryStatus::Idle);
+
+    for key in 0..10 {
+        let updated = (key < 5) as u64;
+        let moved = if key == 8 { 8 } else { 0 };
+        assert_eq!(
+            grades.select_query(key, 0, &[1, 1, 1], None).unwrap()[0].columns,
+            [key, updated, moved]
+        );
+    }
+    assert_eq!(grades.sum_query(0, 9, 1, None), Ok(5));
+    assert_eq!(
+        grades.select_version_query(8, 0, &[0, 1, 0], -1).unwrap()[0].columns,
+        [0]
+    );
+
+    crabstore.close();
+}
+
+#[test]
+#[should_panic(expected = "savepoint no longer exists")]
+fn savepoints_end_with_their_transaction() {
+    let dir = tempdir().unwrap();
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+
+    let grades = crabstore.create_table("Grades", 3, 0);
+    grades.insert_query(&[1, 0, 0], None).unwrap();
+
+    let mut transaction = Transaction::new();
+    let savepoint = transaction.savepoint();
+    assert!(transaction.execute(Query::Update(1, Box::new([None, Some(1), None])), &grades));
+    assert!(transaction.run().is_ok());
+
+    transaction.rollback_to(savepoint);
+}
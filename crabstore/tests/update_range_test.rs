@@ -0,0 +1,234 @@
+#![feature(test)]
+extern crate test;
+use crabcore::{
+    crabstore::CrabStore,
+    table::{Table, UpdateOp},
+    transaction::{Query, Transaction},
+};
+use std::sync::Arc;
+use tempfile::tempdir;
+use test::Bencher;
+
+// Enough rows to span a few page ranges and fill their tails
+const NUMBER_OF_RECORDS: u64 = 20000;
+
+fn populate(table: &Table) {
+    for key in 0..NUMBER_OF_RECORDS {
+        table.insert_query(&[key, key % 100, 50], None).unwrap();
+    }
+}
+
+fn rows(table: &Table) -> Vec<Vec<u64>> {
+    (0..NUMBER_OF_RECORDS)
+        .map(|key| {
+            table.select_query(key, 0, &[1, 1, 1], None).unwrap()[0]
+                .columns
+                .clone()
+        })
+        .collect()
+}
+
+#[test]
+fn update_range_matches_update_loop() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let ranged = db.create_table("Ranged", 3, 0);
+    let looped = db.create_table("Looped", 3, 0);
+    ranged.build_index(2);
+    looped.build_index(2);
+
+    for table in [&ranged, &looped] {
+        populate(table);
+
+        // Some rows already have a tail record the Add has to start from
+        for key in (0..NUMBER_OF_RECORDS).step_by(7) {
+            table
+                .update_query(key, &[None, None, Some(key % 13)], None)
+                .unwrap();
+        }
+    }
+
+    assert_eq!(
+        ranged
+            .update_range(
+                1000,
+                15000,
+                &[None, Some(UpdateOp::Set(7)), Some(UpdateOp::Add(2))],
+                None
+            )
+            .unwrap(),
+        14001
+    );
+    assert_eq!(
+        ranged
+            .update_range(10000, 12000, &[None, None, Some(UpdateOp::Add(1))], None)
+            .unwrap(),
+        2001
+    );
+
+    for key in 1000..=15000 {
+        let latest = looped.select_query(key, 0, &[0, 0, 1], None).unwrap()[0].columns[0];
+        looped
+            .update_query(key, &[None, Some(7), Some(latest + 2)], None)
+            .unwrap();
+    }
+    for key in 10000..=12000 {
+        let latest = looped.select_query(key, 0, &[0, 0, 1], None).unwrap()[0].columns[0];
+        looped
+            .update_query(key, &[None, None, Some(latest + 1)], None)
+            .unwrap();
+    }
+
+    assert_eq!(rows(&ranged), rows(&looped));
+
+    // The index on column 2 agrees with the values as well
+    for value in [2, 3, 8, 15, 52, 53] {
+        let mut ranged_keys: Vec<u64> = ranged
+            .select_query(value, 2, &[1, 0, 0], None)
+            .unwrap()
+            .into_iter()
+            .map(|record| record.columns[0])
+            .collect();
+        let mut looped_keys: Vec<u64> = looped
+            .select_query(value, 2, &[1, 0, 0], None)
+            .unwrap()
+            .into_iter()
+            .map(|record| record.columns[0])
+            .collect();
+        ranged_keys.sort_unstable();
+        looped_keys.sort_unstable();
+        assert_eq!(ranged_keys, looped_keys);
+    }
+
+    db.close();
+
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+    assert_eq!(rows(&db.get_table("Ranged")), rows(&db.get_table("Looped")));
+    db.close();
+}
+
+#[test]
+fn update_range_rolls_back_with_transaction() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 3, 0);
+    table.build_index(1);
+    for key in 0..100 {
+        table.insert_query(&[key, 10, key], None).unwrap();
+    }
+
+    let mut transaction = Transaction::new();
+    transaction.add_query(
+        Query::UpdateRange(20, 29, Box::new([None, Some(UpdateOp::Add(5)), None])),
+        &table,
+    );
+    // Key 0 already exists, so the whole transaction is undone
+    transaction.add_query(Query::Insert(Box::new([0, 0, 0])), &table);
+    assert!(transaction.run().is_err());
+
+    assert_eq!(
+        table.select_query(10, 1, &[1, 0, 0], None).unwrap().len(),
+        100
+    );
+    assert!(table
+        .select_query(15, 1, &[1, 0, 0], None)
+        .unwrap()
+        .is_empty());
+    assert_eq!(table.sum_query(0, 99, 1, None).unwrap(), 1000);
+
+    let mut transaction = Transaction::new();
+    transaction.add_query(
+        Query::UpdateRange(20, 29, Box::new([None, Some(UpdateOp::Add(5)), None])),
+        &table,
+    );
+    assert!(transaction.run().is_ok());
+
+    assert_eq!(
+        table.select_query(15, 1, &[1, 0, 0], None).unwrap().len(),
+        10
+    );
+    assert_eq!(table.sum_query(0, 99, 1, None).unwrap(), 1050);
+
+    db.close();
+}
+
+#[test]
+fn update_range_leaves_keys_alone() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 2, 0);
+    for key in 0..10 {
+        table.insert_query(&[key, 0], None).unwrap();
+    }
+
+    assert_eq!(
+        table
+            .update_range(0, 9, &[Some(UpdateOp::Add(100)), None], None)
+            .unwrap(),
+        0
+    );
+    assert_eq!(table.sum_query(0, 9, 0, None).unwrap(), 45);
+
+    // Keys moved out of the range by an earlier update aren't in it anymore
+    table.update_query(3, &[Some(50), None], None).unwrap();
+    assert_eq!(
+        table
+            .update_range(0, 9, &[None, Some(UpdateOp::Set(1))], None)
+            .unwrap(),
+        9
+    );
+    assert_eq!(
+        table.select_query(50, 0, &[1, 1], None).unwrap()[0].columns,
+        [50, 0]
+    );
+
+    db.close();
+}
+
+fn bench_curve(b: &mut Bencher, ranged: bool) {
+    let dir = tempdir().unwrap();
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open().unwrap();
+    let table: Arc<Table> = crabstore.create_table("Bench", 3, 0);
+
+    populate(&table);
+
+    b.iter(|| {
+        if ranged {
+            test::black_box(
+                table
+                    .update_range(0, 4999, &[None, None, Some(UpdateOp::Add(2))], None)
+                    .unwrap(),
+            );
+        } else {
+            for key in 0..5000 {
+                let latest = table.select_query(key, 0, &[0, 0, 1], None).unwrap()[0].columns[0];
+                test::black_box(
+                    table
+                        .update_query(key, &[None, None, Some(latest + 2)], None)
+                        .unwrap(),
+                );
+            }
+        }
+    });
+
+    crabstore.close();
+}
+
+#[bench]
+fn update_range_bench(b: &mut Bencher) {
+    bench_curve(b, true);
+}
+
+#[bench]
+fn update_loop_bench(b: &mut Bencher) {
+    bench_curve(b, false);
+}
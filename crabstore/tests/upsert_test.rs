@@ -0,0 +1,154 @@
+use crabcore::{
+    crabstore::CrabStore,
+    table::{Table, UpsertResult},
+    transaction::{Query, QueryStatus, Transaction},
+};
+use std::thread;
+use tempfile::tempdir;
+
+fn row(table: &Table, key: u64) -> Option<Vec<u64>> {
+    let mut records = table.select_query(key, 0, &[1, 1, 1], None).unwrap();
+    assert!(records.len() <= 1, "key {key} has {} rows", records.len());
+    records.pop().map(|record| record.columns)
+}
+
+#[test]
+fn upsert_inserts_updates_and_resurrects() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Latest", 3, 0);
+    table.build_index(1);
+
+    assert_eq!(
+        table.upsert_query(&[1, 10, 100], None),
+        Ok(Some(UpsertResult::Inserted))
+    );
+    assert_eq!(
+        table.upsert_query(&[1, 11, 101], None),
+        Ok(Some(UpsertResult::Updated))
+    );
+    assert_eq!(row(&table, 1), Some(vec![1, 11, 101]));
+
+    // The update went through the index like any other
+    assert!(table
+        .select_query(10, 1, &[1, 0, 0], None)
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        table.select_query(11, 1, &[1, 0, 0], None).unwrap().len(),
+        1
+    );
+    assert_eq!(
+        table.select_version_query(1, 0, &[0, 1, 0], -1).unwrap()[0].columns,
+        [10]
+    );
+
+    // A deleted row comes back as a new one, without the old one's history
+    let old_rid = table.select_rids(1, 0).unwrap();
+    assert!(table.delete_query(1, None).unwrap());
+    assert_eq!(
+        table.upsert_query(&[1, 12, 102], None),
+        Ok(Some(UpsertResult::Inserted))
+    );
+    assert_eq!(row(&table, 1), Some(vec![1, 12, 102]));
+    assert_ne!(table.select_rids(1, 0).unwrap(), old_rid);
+    assert_eq!(
+        table.select_version_query(1, 0, &[0, 1, 0], -1).unwrap()[0].columns,
+        [12]
+    );
+
+    db.close();
+
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+    assert_eq!(row(&db.get_table("Latest"), 1), Some(vec![1, 12, 102]));
+    db.close();
+}
+
+#[test]
+fn aborted_upserts_roll_back() {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Latest", 3, 0);
+    table.insert_query(&[1, 10, 100], None).unwrap();
+    table.insert_query(&[3, 30, 300], None).unwrap();
+    table.delete_query(3, None).unwrap();
+
+    // The duplicate insert at the end aborts the whole transaction
+    let mut transaction = Transaction::new();
+    transaction.add_query(Query::Upsert(Box::new([1, 11, 101])), &table);
+    transaction.add_query(Query::Upsert(Box::new([2, 20, 200])), &table);
+    transaction.add_query(Query::Upsert(Box::new([3, 31, 301])), &table);
+    transaction.add_query(Query::Insert(Box::new([1, 0, 0])), &table);
+
+    assert!(transaction.run().is_err());
+    assert_eq!(transaction.get_status(), QueryStatus::AbortedNotRetryable);
+    assert_eq!(row(&table, 1), Some(vec![1, 10, 100]));
+    assert_eq!(row(&table, 2), None);
+    assert_eq!(row(&table, 3), None);
+
+    let mut transaction = Transaction::new();
+    transaction.add_query(Query::Upsert(Box::new([1, 11, 101])), &table);
+    transaction.add_query(Query::Upsert(Box::new([3, 31, 301])), &table);
+
+    assert!(transaction.run().is_ok());
+    assert_eq!(row(&table, 1), Some(vec![1, 11, 101]));
+    assert_eq!(row(&table, 3), Some(vec![3, 31, 301]));
+
+    db.close();
+}
+
+#[test]
+fn concurrent_upserts_of_one_key() {
+    let num_threads = 8;
+    let num_keys = 200;
+
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+    let table = db.create_table("Latest", 3, 0);
+
+    // Whichever upsert reaches a key first inserts it, and the rest update that row
+    let inserted: usize = thread::scope(|s| {
+        let upserts = (0..num_threads)
+            .map(|thread| {
+                let table = &table;
+
+                s.spawn(move || {
+                    (0..num_keys)
+                        .filter(|key| {
+                            let values = [*key, thread, thread];
+
+                            if thread % 2 == 0 {
+                                return table.upsert_query(&values, None).unwrap()
+                                    == Some(UpsertResult::Inserted);
+                            }
+
+                            let mut transaction = Transaction::new();
+                            transaction.add_query(Query::Upsert(Box::new(values)), table);
+                            while transaction.run().is_err() {
+                                assert_eq!(transaction.get_status(), QueryStatus::AbortedRetryable);
+                            }
+                            false
+                        })
+                        .count()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        upserts.into_iter().map(|t| t.join().unwrap()).sum()
+    });
+
+    assert!(inserted <= num_keys as usize);
+    for key in 0..num_keys {
+        let values = row(&table, key).unwrap();
+        assert_eq!(values[1], values[2]);
+    }
+    assert_eq!(table.iter_keys().unwrap().count() as u64, num_keys);
+
+    db.close();
+}
@@ -0,0 +1,109 @@
+use crabcore::{crabstore::CrabStore, table::Table};
+use tempfile::tempdir;
+
+const NUMBER_OF_RECORDS: u64 = 1000;
+
+fn keys_where(table: &Table, column_index: usize, value: u64) -> Vec<u64> {
+    let mut keys = table
+        .select_query(value, column_index, &[1, 0, 0], None)
+        .unwrap()
+        .into_iter()
+        .map(|record| record.columns[0])
+        .collect::<Vec<_>>();
+    keys.sort_unstable();
+    keys
+}
+
+fn write_where(indexed: bool) {
+    let dir = tempdir().unwrap();
+    let mut db = CrabStore::new(dir.path().into());
+    db.open().unwrap();
+
+    let table = db.create_table("Grades", 3, 0);
+    if indexed {
+        table.build_index(1);
+    }
+    for key in 0..NUMBER_OF_RECORDS {
+        table.insert_query(&[key, key % 10, key], None).unwrap();
+    }
+
+    // Matches go by the latest version, so 5 no longer holds 5 and 6 now does
+    table
+        .update_query(5, &[None, Some(10), None], None)
+        .unwrap();
+    table.update_query(6, &[None, Some(5), None], None).unwrap();
+
+    let fives = (0..NUMBER_OF_RECORDS)
+        .filter(|key| (key % 10 == 5 && *key != 5) || *key == 6)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        table
+            .update_where_query(1, 5, &[None, None, Some(0)], None)
+            .unwrap(),
+        100
+    );
+    for key in fives.iter() {
+        assert_eq!(
+            table.select_query(*key, 0, &[1, 1, 1], None).unwrap()[0].columns,
+            [*key, 5, 0]
+        );
+    }
+    assert_eq!(
+        table.select_query(5, 0, &[1, 1, 1], None).unwrap()[0].columns,
+        [5, 10, 5]
+    );
+
+    // Every match would end up with the same key
+    assert_eq!(
+        table
+            .update_where_query(1, 5, &[Some(5000), None, None], None)
+            .unwrap(),
+        0
+    );
+
+    // Moves rows under another value, which the index has to follow
+    assert_eq!(
+        table
+            .update_where_query(1, 5, &[None, Some(50), None], None)
+            .unwrap(),
+        100
+    );
+    assert!(keys_where(&table, 1, 5).is_empty());
+    assert_eq!(keys_where(&table, 1, 50), fives);
+
+    assert_eq!(table.delete_where_query(1, 50, None).unwrap(), 100);
+    assert!(keys_where(&table, 1, 50).is_empty());
+    for key in fives.iter() {
+        assert!(table
+            .select_query(*key, 0, &[1, 1, 1], None)
+            .unwrap()
+            .is_empty());
+    }
+
+    // Deleted rows are gone from the key index too, so their keys can come back
+    assert_eq!(table.delete_where_query(1, 50, None).unwrap(), 0);
+    table.insert_query(&[15, 50, 0], None).unwrap();
+    assert_eq!(keys_where(&table, 1, 50), [15]);
+
+    assert_eq!(table.delete_where_query(2, 7, None).unwrap(), 1);
+    assert!(table
+        .select_query(7, 0, &[1, 1, 1], None)
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        table.storage_stats().unwrap().live_rows,
+        NUMBER_OF_RECORDS as usize - 100 + 1 - 1
+    );
+
+    db.close();
+}
+
+#[test]
+fn write_where_scans_unindexed_column() {
+    write_where(false);
+}
+
+#[test]
+fn write_where_uses_index() {
+    write_where(true);
+}
@@ -1,10 +1,10 @@
-use std::{path::PathBuf, str::FromStr, sync::Arc};
+use std::{path::PathBuf, str::FromStr, sync::Arc, time::Duration};
 
-use crabcore::crabstore::CrabStore;
+use crabcore::{crabstore::CrabStore, schema::ColumnType};
 use parking_lot::Mutex;
-use pyo3::prelude::*;
+use pyo3::{exceptions::PyValueError, prelude::*, types::PyDict};
 
-use super::tablepy::TablePy;
+use super::{crab_err, tablepy::TablePy};
 
 #[derive(Clone)]
 #[pyclass]
@@ -27,6 +27,55 @@ impl CrabStorePy {
         Python::with_gil(|py| Py::new(py, TablePy(table))).unwrap()
     }
 
+    pub fn create_table_named(
+        &mut self,
+        name: String,
+        column_names: Vec<String>,
+        key_index: usize,
+    ) -> PyResult<Py<TablePy>> {
+        let table = self
+            .0
+            .lock()
+            .create_table_named(&name, column_names, key_index)
+            .map_err(crab_err)?;
+        Python::with_gil(|py| Py::new(py, TablePy(table)))
+    }
+
+    /*
+        Each column type is "signed" or "unsigned". A nullable table's columns
+        but the key take None
+    */
+    #[pyo3(signature = (name, column_types, key_index, column_names=None, nullable=false))]
+    pub fn create_table_typed(
+        &mut self,
+        name: String,
+        column_types: Vec<String>,
+        key_index: usize,
+        column_names: Option<Vec<String>>,
+        nullable: bool,
+    ) -> PyResult<Py<TablePy>> {
+        let column_names = column_names.unwrap_or_default();
+        let column_types = column_types
+            .iter()
+            .map(|column_type| match column_type.as_str() {
+                "signed" => Ok(ColumnType::Signed),
+                "unsigned" => Ok(ColumnType::Unsigned),
+                _ => Err(PyValueError::new_err(format!(
+                    "Unknown column type {column_type:?}, expected signed or unsigned"
+                ))),
+            })
+            .collect::<PyResult<Vec<ColumnType>>>()?;
+
+        let mut store = self.0.lock();
+        let table = match nullable {
+            true => store.create_table_nullable(&name, column_types, column_names, key_index),
+            false => store.create_table_typed(&name, column_types, column_names, key_index),
+        }
+        .map_err(crab_err)?;
+        drop(store);
+        Python::with_gil(|py| Py::new(py, TablePy(table)))
+    }
+
     pub fn drop_table(&mut self, name: String) {
         self.0.lock().drop_table(&name);
     }
@@ -36,10 +85,40 @@ impl CrabStorePy {
         Python::with_gil(|py| Py::new(py, TablePy(table))).unwrap()
     }
 
-    pub fn open(&mut self, path: String) {
+    #[pyo3(signature = (path, read_only=false))]
+    pub fn open(&mut self, path: String, read_only: bool) -> PyResult<()> {
         let mut crabstore = self.0.lock();
         crabstore.directory = PathBuf::from_str(&path).unwrap();
-        crabstore.open();
+        crabstore.config.read_only = read_only;
+        crabstore.open().map_err(crab_err)
+    }
+
+    pub fn checkpoint(&self) {
+        self.0.lock().checkpoint();
+    }
+
+    #[pyo3(signature = (timeout_ms=60_000))]
+    pub fn quiesce_all(&self, py: Python<'_>, timeout_ms: u64) -> PyResult<()> {
+        let crabstore = self.0.lock();
+        py.allow_threads(|| crabstore.quiesce_all(Duration::from_millis(timeout_ms)))
+            .map_err(crab_err)
+    }
+
+    pub fn refresh(&mut self) -> PyResult<()> {
+        self.0.lock().refresh().map_err(crab_err)
+    }
+
+    /*
+        Rewrites the table without its deleted rows, tables fetched before have to be fetched again
+    */
+    pub fn compact_table(&mut self, py: Python<'_>, name: String) -> PyResult<Py<PyDict>> {
+        let report = self.0.lock().compact_table(&name).map_err(crab_err)?;
+
+        let dict = PyDict::new(py);
+        dict.set_item("rows", report.rows)?;
+        dict.set_item("old_pages", report.old_pages)?;
+        dict.set_item("new_pages", report.new_pages)?;
+        Ok(dict.into())
     }
 
     pub fn close(&mut self) {
@@ -1,5 +1,6 @@
+use crabcore::{error::CrabError, schema::ColumnType};
 use crabstorepy::CrabStorePy;
-use pyo3::prelude::*;
+use pyo3::{exceptions::PyRuntimeError, prelude::*};
 use recordpy::RecordPy;
 use tablepy::TablePy;
 
@@ -7,6 +8,36 @@ pub mod crabstorepy;
 pub mod recordpy;
 pub mod tablepy;
 
+pub(crate) fn crab_err(err: CrabError) -> PyErr {
+    PyRuntimeError::new_err(err.to_string())
+}
+
+/*
+    A column value from Python. Ints below zero, meant for signed columns, are
+    passed on as their two's complement bits like the table stores them
+*/
+#[derive(Clone, Copy, Debug)]
+pub struct Value(pub u64);
+
+impl<'a> FromPyObject<'a> for Value {
+    fn extract(value: &'a PyAny) -> PyResult<Self> {
+        match value.extract::<u64>() {
+            Ok(value) => Ok(Value(value)),
+            Err(_) => Ok(Value(value.extract::<i64>()? as u64)),
+        }
+    }
+}
+
+/*
+    A column value back as a Python int, negative where a signed column's is
+*/
+pub(crate) fn value_py(py: Python<'_>, column_type: ColumnType, value: u64) -> PyObject {
+    match column_type {
+        ColumnType::Signed => (value as i64).into_py(py),
+        ColumnType::Unsigned => value.into_py(py),
+    }
+}
+
 #[pymodule]
 pub fn crabstore(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_class::<RecordPy>()?;
@@ -1,28 +1,50 @@
-use crabcore::record::Record;
+use crabcore::{record::Record, schema::ColumnType};
 use pyo3::{prelude::*, types::PyList};
 
+use super::value_py;
+
 #[derive(Clone, Debug)]
 #[pyclass(subclass, get_all)]
 pub struct RecordPy {
     pub rid: u64,
     pub columns: Py<PyList>,
+    pub timestamp: u64,
 }
 
 impl RecordPy {
     pub fn from(record: &Record, py: Python) -> Py<Self> {
+        RecordPy::with_types(record, &[], py)
+    }
+
+    /*
+        column_types lines up with the record's columns, missing ones are unsigned.
+        NULLs come out as None
+    */
+    pub fn with_types(record: &Record, column_types: &[ColumnType], py: Python) -> Py<Self> {
         let result_cols = PyList::empty(py);
-        for c in record.columns.iter() {
-            result_cols.append(c).unwrap();
+        for (i, c) in record.values().into_iter().enumerate() {
+            let column_type = column_types.get(i).copied().unwrap_or_default();
+            let value = match c {
+                Some(c) => value_py(py, column_type, c),
+                None => py.None(),
+            };
+            result_cols.append(value).unwrap();
         }
-        Py::new(py, RecordPy::new(record.rid, result_cols.into())).unwrap()
+        let record = RecordPy::new(record.rid, result_cols.into(), record.timestamp);
+        Py::new(py, record).unwrap()
     }
 }
 
 #[pymethods]
 impl RecordPy {
     #[new]
-    pub fn new(rid: u64, columns: Py<PyList>) -> Self {
-        RecordPy { rid, columns }
+    #[pyo3(signature = (rid, columns, timestamp=0))]
+    pub fn new(rid: u64, columns: Py<PyList>, timestamp: u64) -> Self {
+        RecordPy {
+            rid,
+            columns,
+            timestamp,
+        }
     }
 
     pub fn __str__(&self) -> String {
@@ -30,7 +52,7 @@ impl RecordPy {
 
         Python::with_gil(|py| {
             for c in self.columns.as_ref(py).iter() {
-                p.push_str(&c.extract::<u64>().unwrap().to_string());
+                p.push_str(&c.to_string());
                 p.push(',');
             }
         });
@@ -1,52 +1,132 @@
-use std::{path::Path, sync::Arc};
+use std::{sync::Arc, time::Duration};
 
-use crabcore::table::Table;
+use crabcore::{
+    config::CrabConfig,
+    crabstore::TableFiles,
+    error::CrabError,
+    key_order::OrderedRecords,
+    lock_manager::ConcurrencyPolicy,
+    rid::RID,
+    scan::TableScan,
+    schema::ColumnType,
+    table::{AccessPath, ResultOrder, Table, UpdateOp, UpsertResult},
+};
 use pyo3::{
+    exceptions::PyValueError,
     prelude::*,
-    types::{PyList, PyTuple},
+    types::{PyDict, PyList, PyTuple},
 };
 
-use super::recordpy::RecordPy;
+use super::{crab_err, recordpy::RecordPy, value_py, Value};
 
 #[pyclass]
 pub struct TablePy(pub Arc<Table>);
 
+/*
+    Generator over a table's rows in primary key order, see TablePy::iter_rows
+*/
+#[pyclass]
+pub struct RowIterPy(OrderedRecords<Arc<Table>>, Vec<ColumnType>);
+
+#[pymethods]
+impl RowIterPy {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(mut slf: PyRefMut<'_, Self>, py: Python<'_>) -> PyResult<Option<Py<RecordPy>>> {
+        let RowIterPy(records, column_types) = &mut *slf;
+
+        match py.allow_threads(|| records.next()) {
+            Some(record) => Ok(Some(RecordPy::with_types(
+                &record.map_err(crab_err)?,
+                column_types,
+                py,
+            ))),
+            None => Ok(None),
+        }
+    }
+}
+
+/*
+    Generator over a table's rows in RID order, see TablePy::scan
+*/
+#[pyclass]
+pub struct ScanIterPy(TableScan<Arc<Table>>, Vec<ColumnType>);
+
+#[pymethods]
+impl ScanIterPy {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(mut slf: PyRefMut<'_, Self>, py: Python<'_>) -> PyResult<Option<Py<RecordPy>>> {
+        let ScanIterPy(records, column_types) = &mut *slf;
+
+        match py.allow_threads(|| records.next()) {
+            Some(record) => Ok(Some(RecordPy::with_types(
+                &record.map_err(crab_err)?,
+                column_types,
+                py,
+            ))),
+            None => Ok(None),
+        }
+    }
+}
+
 impl TablePy {
-    pub fn new(
-        name: String,
-        num_columns: usize,
-        key_index: usize,
-        db_file: &Path,
-        pd_file: &Path,
-        id_file: &Path,
-        rd_file: &Path,
-    ) -> Self {
-        Self(Arc::new(Table::new(
-            name,
-            num_columns,
-            key_index,
-            db_file,
-            pd_file,
-            id_file,
-            rd_file,
-        )))
-    }
-
-    pub fn load(
-        name: &str,
-        db_file: &Path,
-        pd_file: &Path,
-        id_file: &Path,
-        rd_file: &Path,
-    ) -> Self {
-        Self(Arc::new(Table::load(
-            name, db_file, pd_file, id_file, rd_file,
-        )))
+    pub fn new(name: String, num_columns: usize, key_index: usize, files: &TableFiles) -> Self {
+        let table = Arc::new(Table::new(name, num_columns, key_index, files));
+        table.ensure_primary_index();
+        Self(table)
+    }
+
+    pub fn load(name: &str, files: &TableFiles, config: &CrabConfig) -> PyResult<Self> {
+        let table = Arc::new(Table::load(name, files, config).map_err(crab_err)?);
+        table.ensure_primary_index();
+        Ok(Self(table))
+    }
+
+    /*
+        A column argument given either as its index or as its name
+    */
+    fn column_arg(&self, column: &PyAny) -> PyResult<usize> {
+        if let Ok(column_index) = column.extract::<usize>() {
+            return Ok(column_index);
+        }
+
+        let name = column.extract::<&str>()?;
+        self.0
+            .column_index(name)
+            .ok_or_else(|| PyValueError::new_err(format!("No column named {name:?}")))
+    }
+
+    /*
+        Types of the included columns, in the order records hold them
+    */
+    fn included_types(&self, included_columns: &[usize]) -> Vec<ColumnType> {
+        self.0
+            .column_types()
+            .into_iter()
+            .zip(included_columns)
+            .filter(|(_, included)| **included != 0)
+            .map(|(column_type, _)| column_type)
+            .collect()
+    }
+
+    fn column_value_py(&self, py: Python<'_>, column_index: usize, value: u64) -> PyObject {
+        let column_type = self.0.column_types().get(column_index).copied();
+        value_py(py, column_type.unwrap_or_default(), value)
     }
 }
 
 #[pymethods]
 impl TablePy {
+    #[getter]
+    fn column_names(&self) -> Vec<String> {
+        self.0.column_names().to_vec()
+    }
+
     #[getter]
     fn num_columns(&self) -> usize {
         self.0.columns()
@@ -55,82 +135,847 @@ impl TablePy {
     pub fn sum(
         &self,
         py: Python<'_>,
-        start_range: u64,
-        end_range: u64,
+        start_range: Value,
+        end_range: Value,
         column_index: usize,
-    ) -> u64 {
-        py.allow_threads(move || self.0.sum_query(start_range, end_range, column_index, None))
+    ) -> PyResult<PyObject> {
+        let sum = py
+            .allow_threads(move || {
+                self.0
+                    .sum_query(start_range.0, end_range.0, column_index, None)
+            })
+            .map_err(crab_err)?;
+
+        Ok(self.column_value_py(py, column_index, sum))
     }
 
-    pub fn select(
+    pub fn min(
         &self,
         py: Python<'_>,
-        search_value: u64,
+        start_range: Value,
+        end_range: Value,
         column_index: usize,
+    ) -> PyResult<Option<PyObject>> {
+        let value = py
+            .allow_threads(move || {
+                self.0
+                    .min_query(start_range.0, end_range.0, column_index, None)
+            })
+            .map_err(crab_err)?;
+
+        Ok(value.map(|value| self.column_value_py(py, column_index, value)))
+    }
+
+    pub fn max(
+        &self,
+        py: Python<'_>,
+        start_range: Value,
+        end_range: Value,
+        column_index: usize,
+    ) -> PyResult<Option<PyObject>> {
+        let value = py
+            .allow_threads(move || {
+                self.0
+                    .max_query(start_range.0, end_range.0, column_index, None)
+            })
+            .map_err(crab_err)?;
+
+        Ok(value.map(|value| self.column_value_py(py, column_index, value)))
+    }
+
+    pub fn avg(
+        &self,
+        py: Python<'_>,
+        start_range: Value,
+        end_range: Value,
+        column_index: usize,
+    ) -> PyResult<Option<f64>> {
+        py.allow_threads(move || {
+            self.0
+                .avg_query(start_range.0, end_range.0, column_index, None)
+        })
+        .map_err(crab_err)
+    }
+
+    #[pyo3(signature = (search_value, column, columns, limit=None))]
+    pub fn select(
+        &self,
+        py: Python<'_>,
+        search_value: Value,
+        column: &PyAny,
         columns: &PyList,
-    ) -> Py<PyList> {
+        limit: Option<usize>,
+    ) -> PyResult<Py<PyList>> {
+        let column_index = self.column_arg(column)?;
         if column_index >= self.0.columns() {
-            return Python::with_gil(|py| -> Py<PyList> { PyList::empty(py).into() });
+            return Ok(PyList::empty(py).into());
+        }
+
+        if limit.is_none() {
+            let rids = self.select_rids(py, search_value, column_index)?;
+            return self.materialize(py, rids, columns);
         }
 
         let included_columns: Vec<usize> = columns
             .iter()
-            .enumerate()
-            .filter(|(_i, x)| x.extract::<u64>().unwrap() != 0)
-            .map(|(i, _x)| i)
-            .collect();
+            .map(|x| x.extract::<usize>())
+            .collect::<PyResult<_>>()?;
+
+        let results = py
+            .allow_threads(|| {
+                self.0.select_query_limit(
+                    search_value.0,
+                    column_index,
+                    &included_columns,
+                    ResultOrder::Unordered,
+                    limit,
+                    None,
+                )
+            })
+            .map_err(crab_err)?;
+
+        let column_types = self.included_types(&included_columns);
+        let selected_records = PyList::empty(py);
+        for result in results {
+            selected_records.append(RecordPy::with_types(&result, &column_types, py))?;
+        }
+
+        Ok(selected_records.into())
+    }
+
+    // filters is a list of (column, value) pairs, every one of which a row must hold
+    pub fn select_multi(
+        &self,
+        py: Python<'_>,
+        filters: &PyList,
+        columns: &PyList,
+    ) -> PyResult<Py<PyList>> {
+        let filters: Vec<(usize, u64)> = filters
+            .iter()
+            .map(|filter| {
+                let (column, value): (&PyAny, Value) = filter.extract()?;
+                Ok((self.column_arg(column)?, value.0))
+            })
+            .collect::<PyResult<_>>()?;
+        if filters
+            .iter()
+            .any(|(column, _)| *column >= self.0.columns())
+        {
+            return Ok(PyList::empty(py).into());
+        }
+
+        let included_columns: Vec<usize> = columns
+            .iter()
+            .map(|x| x.extract::<usize>())
+            .collect::<PyResult<_>>()?;
+
+        let results = py
+            .allow_threads(|| self.0.select_multi_query(&filters, &included_columns, None))
+            .map_err(crab_err)?;
 
-        let mut results = vec![];
+        let column_types = self.included_types(&included_columns);
+        let selected_records = PyList::empty(py);
+        for result in results {
+            selected_records.append(RecordPy::with_types(&result, &column_types, py))?;
+        }
+
+        Ok(selected_records.into())
+    }
+
+    pub fn select_version(
+        &self,
+        py: Python<'_>,
+        search_value: Value,
+        column_index: usize,
+        columns: &PyList,
+        relative_version: i64,
+    ) -> PyResult<Py<PyList>> {
+        let included_columns: Vec<usize> = columns
+            .iter()
+            .map(|x| x.extract::<usize>())
+            .collect::<PyResult<_>>()?;
+
+        let results = py
+            .allow_threads(|| {
+                self.0.select_version_query(
+                    search_value.0,
+                    column_index,
+                    &included_columns,
+                    relative_version,
+                )
+            })
+            .map_err(crab_err)?;
+
+        let column_types = self.included_types(&included_columns);
+        let selected_records = PyList::empty(py);
+        for result in results {
+            selected_records.append(RecordPy::with_types(&result, &column_types, py))?;
+        }
+
+        Ok(selected_records.into())
+    }
+
+    pub fn select_as_of(
+        &self,
+        py: Python<'_>,
+        search_value: Value,
+        column_index: usize,
+        columns: &PyList,
+        timestamp: u64,
+    ) -> PyResult<Py<PyList>> {
+        let included_columns: Vec<usize> = columns
+            .iter()
+            .map(|x| x.extract::<usize>())
+            .collect::<PyResult<_>>()?;
+
+        let results = py
+            .allow_threads(|| {
+                self.0.select_as_of_query(
+                    search_value.0,
+                    column_index,
+                    &included_columns,
+                    timestamp,
+                )
+            })
+            .map_err(crab_err)?;
+
+        let column_types = self.included_types(&included_columns);
+        let selected_records = PyList::empty(py);
+        for result in results {
+            selected_records.append(RecordPy::with_types(&result, &column_types, py))?;
+        }
+
+        Ok(selected_records.into())
+    }
+
+    pub fn current_timestamp(&self) -> u64 {
+        self.0.current_timestamp()
+    }
+
+    pub fn sum_version(
+        &self,
+        py: Python<'_>,
+        start_range: Value,
+        end_range: Value,
+        column_index: usize,
+        relative_version: i64,
+    ) -> PyResult<PyObject> {
+        let sum = py
+            .allow_threads(move || {
+                self.0
+                    .sum_version_query(start_range.0, end_range.0, column_index, relative_version)
+            })
+            .map_err(crab_err)?;
+
+        Ok(self.column_value_py(py, column_index, sum))
+    }
+
+    pub fn count(
+        &self,
+        py: Python<'_>,
+        search_value: Value,
+        column_index: usize,
+    ) -> PyResult<usize> {
+        py.allow_threads(|| self.0.count_query(search_value.0, column_index))
+            .map_err(crab_err)
+    }
+
+    pub fn select_count_bounded(
+        &self,
+        py: Python<'_>,
+        search_value: Value,
+        column_index: usize,
+        bound: usize,
+    ) -> PyResult<usize> {
         py.allow_threads(|| {
-            results = self
-                .0
-                .select_query(search_value, column_index, &included_columns, None);
-        });
-
-        Python::with_gil(|py| -> Py<PyList> {
-            let selected_records: Py<PyList> = PyList::empty(py).into();
-            for result in results {
-                selected_records
-                    .as_ref(py)
-                    .append(RecordPy::from(&result, py))
-                    .expect("Failed to append to python list");
-            }
-            selected_records
+            self.0
+                .select_count_bounded(search_value.0, column_index, bound)
         })
+        .map_err(crab_err)
+    }
+
+    pub fn select_rids(
+        &self,
+        py: Python<'_>,
+        search_value: Value,
+        column_index: usize,
+    ) -> PyResult<Vec<u64>> {
+        let rids = py
+            .allow_threads(|| self.0.select_rids(search_value.0, column_index))
+            .map_err(crab_err)?;
+
+        Ok(rids.iter().map(RID::raw).collect())
+    }
+
+    #[pyo3(signature = (start, end, column_index, columns, limit=None))]
+    pub fn select_range(
+        &self,
+        py: Python<'_>,
+        start: Value,
+        end: Value,
+        column_index: usize,
+        columns: &PyList,
+        limit: Option<usize>,
+    ) -> PyResult<Py<PyList>> {
+        let included_columns: Vec<usize> = columns
+            .iter()
+            .map(|x| x.extract::<usize>())
+            .collect::<PyResult<_>>()?;
+
+        let results = py
+            .allow_threads(|| {
+                self.0.select_range_query(
+                    start.0,
+                    end.0,
+                    column_index,
+                    &included_columns,
+                    limit,
+                    None,
+                )
+            })
+            .map_err(crab_err)?;
+
+        let column_types = self.included_types(&included_columns);
+        let selected_records = PyList::empty(py);
+        for result in results {
+            selected_records.append(RecordPy::with_types(&result, &column_types, py))?;
+        }
+
+        Ok(selected_records.into())
+    }
+
+    pub fn select_by_rid(
+        &self,
+        py: Python<'_>,
+        rid: u64,
+        columns: &PyList,
+    ) -> PyResult<Option<Py<RecordPy>>> {
+        let included_columns: Vec<usize> = columns
+            .iter()
+            .map(|x| x.extract::<usize>())
+            .collect::<PyResult<_>>()?;
+
+        let result = py
+            .allow_threads(|| self.0.select_by_rid(RID::from(rid), &included_columns))
+            .map_err(crab_err)?;
+
+        let column_types = self.included_types(&included_columns);
+        Ok(result.map(|record| RecordPy::with_types(&record, &column_types, py)))
     }
 
-    pub fn update(&self, py: Python<'_>, key: u64, values: &PyTuple) -> bool {
+    pub fn materialize(
+        &self,
+        py: Python<'_>,
+        rids: Vec<u64>,
+        columns: &PyList,
+    ) -> PyResult<Py<PyList>> {
+        let rids: Vec<RID> = rids.into_iter().map(RID::from).collect();
+        let included_columns: Vec<usize> = columns
+            .iter()
+            .map(|x| x.extract::<usize>())
+            .collect::<PyResult<_>>()?;
+
+        let results = py
+            .allow_threads(|| self.0.materialize(&rids, &included_columns))
+            .map_err(crab_err)?;
+
+        let column_types = self.included_types(&included_columns);
+        let selected_records = PyList::empty(py);
+        for result in results {
+            selected_records.append(RecordPy::with_types(&result, &column_types, py))?;
+        }
+
+        Ok(selected_records.into())
+    }
+
+    pub fn select_where(
+        &self,
+        py: Python<'_>,
+        predicates: Vec<(usize, Value)>,
+        columns: &PyList,
+    ) -> PyResult<Py<PyList>> {
+        let predicates: Vec<(usize, u64)> = predicates
+            .into_iter()
+            .map(|(column, value)| (column, value.0))
+            .collect();
+        let included_columns: Vec<usize> = columns
+            .iter()
+            .map(|x| x.extract::<usize>())
+            .collect::<PyResult<_>>()?;
+
+        let results = py
+            .allow_threads(|| {
+                self.0
+                    .select_where(&predicates, &included_columns, ResultOrder::Unordered, None)
+            })
+            .map_err(crab_err)?;
+
+        let column_types = self.included_types(&included_columns);
+        let selected_records = PyList::empty(py);
+        for result in results {
+            selected_records.append(RecordPy::with_types(&result, &column_types, py))?;
+        }
+
+        Ok(selected_records.into())
+    }
+
+    pub fn last_n_by(
+        &self,
+        py: Python<'_>,
+        column_index: usize,
+        n: usize,
+        columns: &PyList,
+    ) -> PyResult<Py<PyList>> {
+        let included_columns: Vec<usize> = columns
+            .iter()
+            .map(|x| x.extract::<usize>())
+            .collect::<PyResult<_>>()?;
+
+        let results = py
+            .allow_threads(|| self.0.last_n_by(column_index, n, &included_columns, None))
+            .map_err(crab_err)?;
+
+        let column_types = self.included_types(&included_columns);
+        let selected_records = PyList::empty(py);
+        for result in results {
+            selected_records.append(RecordPy::with_types(&result, &column_types, py))?;
+        }
+
+        Ok(selected_records.into())
+    }
+
+    /*
+        Rows in primary key order, read from the table a batch at a time as the
+        generator is consumed rather than all at once
+    */
+    pub fn iter_rows(&self, py: Python<'_>, columns: &PyList) -> PyResult<RowIterPy> {
+        let included_columns: Vec<usize> = columns
+            .iter()
+            .map(|x| x.extract::<usize>())
+            .collect::<PyResult<_>>()?;
+
+        let column_types = self.included_types(&included_columns);
+        let table = Arc::clone(&self.0);
+        py.allow_threads(|| OrderedRecords::new(table, &included_columns))
+            .map(|records| RowIterPy(records, column_types))
+            .map_err(crab_err)
+    }
+
+    /*
+        Every row in RID order, read a page at a time as the generator is consumed
+    */
+    pub fn scan(&self) -> ScanIterPy {
+        let column_types = self.included_types(&vec![1; self.0.columns()]);
+        ScanIterPy(TableScan::new(Arc::clone(&self.0)), column_types)
+    }
+
+    pub fn analyze(&self, py: Python<'_>) -> PyResult<()> {
+        py.allow_threads(|| self.0.analyze()).map_err(crab_err)
+    }
+
+    /*
+        (distinct, min, max, null_fraction) per column
+    */
+    pub fn column_stats(&self) -> Vec<(u64, Option<u64>, Option<u64>, f64)> {
+        self.0
+            .column_stats()
+            .into_iter()
+            .map(|stats| (stats.distinct, stats.min, stats.max, stats.null_fraction))
+            .collect()
+    }
+
+    pub fn explain(&self, py: Python<'_>, column: usize, value: Value) -> PyResult<Py<PyDict>> {
+        let plan = self.0.explain(column, value.0);
+
+        let dict = PyDict::new(py);
+        dict.set_item("access", access_name(plan.access))?;
+        dict.set_item("estimated_rows", plan.estimated_rows)?;
+        Ok(dict.into())
+    }
+
+    /*
+        Snapshot of the statistics, plans and counters the table keeps. Every
+        part is copied out under its own short lock, so the parts can be a few
+        queries apart from each other.
+    */
+    pub fn diagnostics(&self, py: Python<'_>) -> PyResult<Py<PyDict>> {
+        let columns = PyList::empty(py);
+        for (column, stats) in self.0.column_stats().into_iter().enumerate() {
+            let dict = PyDict::new(py);
+            dict.set_item("distinct", stats.distinct)?;
+            dict.set_item("min", stats.min)?;
+            dict.set_item("max", stats.max)?;
+            dict.set_item("null_fraction", stats.null_fraction)?;
+            dict.set_item("access", access_name(self.0.access_path(column)))?;
+            columns.append(dict)?;
+        }
+
+        let bufferpool_stats = self.0.bufferpool_stats();
+        let bufferpool = PyDict::new(py);
+        bufferpool.set_item("requests", bufferpool_stats.requests)?;
+        bufferpool.set_item("hits", bufferpool_stats.hits)?;
+        bufferpool.set_item("misses", bufferpool_stats.misses)?;
+        bufferpool.set_item("evictions", bufferpool_stats.evictions)?;
+        bufferpool.set_item("dirty_writes", bufferpool_stats.dirty_writes)?;
+        bufferpool.set_item("pinned", bufferpool_stats.pinned)?;
+        bufferpool.set_item("prefetched", bufferpool_stats.prefetched)?;
+        bufferpool.set_item("discarded", bufferpool_stats.discarded)?;
+
+        let merge_stats = self.0.merge_stats();
+        let merge = PyDict::new(py);
+        merge.set_item("merges", merge_stats.merges)?;
+        merge.set_item("tail_records", merge_stats.tail_records)?;
+        merge.set_item("base_pages", merge_stats.base_pages)?;
+        merge.set_item("compacted_rows", merge_stats.compacted_rows)?;
+        merge.set_item("slots_written", merge_stats.slots_written)?;
+        merge.set_item("total_ms", merge_stats.total_time.as_secs_f64() * 1000.0)?;
+        merge.set_item("reclaimed_tail_pages", merge_stats.reclaimed_tail_pages)?;
+
+        let storage_stats = self.0.storage_stats().map_err(crab_err)?;
+        let storage = PyDict::new(py);
+        storage.set_item("base_pages", storage_stats.base_pages)?;
+        storage.set_item("live_rows", storage_stats.live_rows)?;
+        storage.set_item("deleted_rows", storage_stats.deleted_rows)?;
+        storage.set_item("compacted_rows", storage_stats.compacted_rows)?;
+        storage.set_item("file_pages", storage_stats.file_pages)?;
+        storage.set_item("free_pages", storage_stats.free_pages)?;
+
+        let write_stats = self.0.write_stats();
+        let writes = PyDict::new(py);
+        writes.set_item("rows_modified", write_stats.rows_modified)?;
+        writes.set_item("slots_written", write_stats.slots_written)?;
+        writes.set_item("amplification", write_stats.amplification())?;
+
+        let chain_histogram = self.0.chain_histogram().map_err(crab_err)?;
+
+        let slow_queries = PyList::empty(py);
+        for query in self.0.slow_queries() {
+            let dict = PyDict::new(py);
+            dict.set_item("query", query.query)?;
+            dict.set_item("ms", query.duration.as_secs_f64() * 1000.0)?;
+            slow_queries.append(dict)?;
+        }
+
+        let dict = PyDict::new(py);
+        dict.set_item("columns", columns)?;
+        dict.set_item("bufferpool", bufferpool)?;
+        dict.set_item("merge", merge)?;
+        dict.set_item("storage", storage)?;
+        dict.set_item("writes", writes)?;
+        dict.set_item("chain_histogram", chain_histogram)?;
+        dict.set_item("slow_queries", slow_queries)?;
+        Ok(dict.into())
+    }
+
+    pub fn reset_stats(&self) {
+        self.0.reset_stats();
+    }
+
+    pub fn set_slow_query_threshold(&self, threshold_ms: f64) {
+        self.0
+            .set_slow_query_threshold(Duration::from_secs_f64(threshold_ms / 1000.0));
+    }
+
+    pub fn update(&self, py: Python<'_>, key: Value, values: &PyTuple) -> PyResult<bool> {
         let vals: Vec<Option<u64>> = values
             .iter()
-            .map(|val| val.extract::<Option<u64>>().unwrap())
+            .map(|val| val.extract::<Option<Value>>().unwrap().map(|val| val.0))
             .collect::<Vec<Option<u64>>>();
 
-        py.allow_threads(move || self.0.update_query(key, &vals, None))
+        py.allow_threads(move || self.0.update_query(key.0, &vals, None))
+            .map_err(crab_err)
+    }
+
+    pub fn update_by_rid(&self, py: Python<'_>, rid: u64, values: &PyTuple) -> PyResult<bool> {
+        let vals: Vec<Option<u64>> = values
+            .iter()
+            .map(|val| Ok(val.extract::<Option<Value>>()?.map(|val| val.0)))
+            .collect::<PyResult<_>>()?;
+
+        py.allow_threads(move || self.0.update_by_rid(RID::from(rid), &vals, None))
+            .map_err(crab_err)
     }
 
-    pub fn delete(&self, py: Python<'_>, key: u64) -> bool {
-        py.allow_threads(move || self.0.delete_query(key, None))
+    /*
+        Each column is None, ("set", value) or ("add", delta)
+    */
+    pub fn update_range(
+        &self,
+        py: Python<'_>,
+        start_range: Value,
+        end_range: Value,
+        updates: &PyTuple,
+    ) -> PyResult<usize> {
+        let ops = updates
+            .iter()
+            .map(|op| match op.extract::<Option<(&str, Value)>>()? {
+                None => Ok(None),
+                Some(("set", value)) => Ok(Some(UpdateOp::Set(value.0))),
+                // A negative delta wraps around to the subtraction
+                Some(("add", delta)) => Ok(Some(UpdateOp::Add(delta.0))),
+                Some((op, _)) => Err(PyValueError::new_err(format!(
+                    "Unknown update {op:?}, expected set or add"
+                ))),
+            })
+            .collect::<PyResult<Vec<Option<UpdateOp>>>>()?;
+
+        py.allow_threads(move || self.0.update_range(start_range.0, end_range.0, &ops, None))
+            .map_err(crab_err)
+    }
+
+    pub fn delete(&self, py: Python<'_>, key: Value) -> PyResult<bool> {
+        py.allow_threads(move || self.0.delete_query(key.0, None))
+            .map_err(crab_err)
+    }
+
+    pub fn delete_where(
+        &self,
+        py: Python<'_>,
+        column_index: usize,
+        value: Value,
+    ) -> PyResult<usize> {
+        py.allow_threads(move || self.0.delete_where_query(column_index, value.0, None))
+            .map_err(crab_err)
+    }
+
+    pub fn update_where(
+        &self,
+        py: Python<'_>,
+        column_index: usize,
+        value: Value,
+        values: &PyTuple,
+    ) -> PyResult<usize> {
+        let vals: Vec<Option<u64>> = values
+            .iter()
+            .map(|val| Ok(val.extract::<Option<Value>>()?.map(|val| val.0)))
+            .collect::<PyResult<_>>()?;
+
+        py.allow_threads(move || {
+            self.0
+                .update_where_query(column_index, value.0, &vals, None)
+        })
+        .map_err(crab_err)
     }
 
     #[pyo3(signature = (*values))]
-    pub fn insert(&self, py: Python<'_>, values: &PyTuple) {
+    /*
+        None inserts a NULL, which only a nullable table takes
+    */
+    pub fn insert(&self, py: Python<'_>, values: &PyTuple) -> PyResult<()> {
         let vals = values
             .iter()
-            .map(|v| v.extract::<u64>().unwrap())
-            .collect::<Vec<u64>>();
+            .map(|v| Ok(v.extract::<Option<Value>>()?.map(|v| v.0)))
+            .collect::<PyResult<Vec<Option<u64>>>>()?;
+
+        py.allow_threads(move || self.0.insert_query_nullable(&vals, None))
+            .map_err(crab_err)
+    }
+
+    #[pyo3(signature = (*values))]
+    /*
+        Returns "inserted" or "updated"
+    */
+    pub fn upsert(&self, py: Python<'_>, values: Vec<Value>) -> PyResult<&'static str> {
+        let vals: Vec<u64> = values.into_iter().map(|v| v.0).collect();
 
-        py.allow_threads(move || self.0.insert_query(&vals, None));
+        match py.allow_threads(move || self.0.upsert_query(&vals, None)) {
+            Ok(Some(UpsertResult::Inserted)) => Ok("inserted"),
+            Ok(_) => Ok("updated"),
+            Err(err) => Err(crab_err(err)),
+        }
+    }
+
+    /*
+        One list of values per row, returns whether each row went in
+    */
+    pub fn insert_many(&self, py: Python<'_>, rows: Vec<Vec<Value>>) -> PyResult<Vec<bool>> {
+        let rows: Vec<Vec<u64>> = rows
+            .into_iter()
+            .map(|row| row.into_iter().map(|value| value.0).collect())
+            .collect();
+
+        py.allow_threads(move || self.0.insert_many_query(&rows))
+            .map_err(crab_err)
     }
 
     pub fn build_index(&self, column_num: usize) {
         self.0.build_index(column_num);
     }
 
+    // Raises if rows already share a value in the column
+    pub fn build_unique_index(&self, column_num: usize) -> PyResult<()> {
+        self.0.build_unique_index(column_num).map_err(crab_err)
+    }
+
+    pub fn unique_columns(&self) -> Vec<usize> {
+        self.0.unique_columns()
+    }
+
     pub fn drop_index(&self, column_num: usize) {
         self.0.drop_index(column_num);
     }
 
-    pub fn persist(&self) {
-        self.0.persist();
+    pub fn build_composite_index(&self, columns: Vec<usize>) {
+        self.0.build_composite_index(&columns);
+    }
+
+    pub fn drop_composite_index(&self, columns: Vec<usize>) {
+        self.0.drop_composite_index(&columns);
+    }
+
+    pub fn composite_indexes(&self) -> Vec<Vec<usize>> {
+        self.0.composite_indexes()
+    }
+
+    pub fn indexed_columns(&self) -> Vec<usize> {
+        self.0.indexed_columns()
+    }
+
+    pub fn locate(&self, column_index: usize, value: Value) -> Vec<u64> {
+        self.0
+            .locate(column_index, value.0)
+            .iter()
+            .map(RID::raw)
+            .collect()
+    }
+
+    pub fn locate_range(&self, begin: Value, end: Value, column_index: usize) -> Vec<u64> {
+        self.0
+            .locate_range(begin.0, end.0, column_index)
+            .iter()
+            .map(RID::raw)
+            .collect()
+    }
+
+    // None if the column isn't indexed
+    pub fn cardinality(&self, column_index: usize) -> Option<usize> {
+        self.0.cardinality(column_index)
+    }
+
+    pub fn index_len(&self, column_index: usize) -> Option<usize> {
+        self.0.index_len(column_index)
+    }
+
+    #[getter]
+    pub fn concurrency_policy(&self) -> (&'static str, Option<u64>) {
+        match self.0.concurrency_policy() {
+            ConcurrencyPolicy::NoWait => ("no_wait", None),
+            ConcurrencyPolicy::WaitWithTimeout(timeout) => {
+                ("wait", Some(timeout.as_millis() as u64))
+            }
+            ConcurrencyPolicy::WaitDie => ("wait_die", None),
+        }
+    }
+
+    #[pyo3(signature = (policy, timeout_ms=None))]
+    pub fn set_concurrency_policy(&self, policy: &str, timeout_ms: Option<u64>) -> PyResult<()> {
+        let policy = match (policy, timeout_ms) {
+            ("no_wait", None) => ConcurrencyPolicy::NoWait,
+            ("wait", Some(timeout_ms)) => {
+                ConcurrencyPolicy::WaitWithTimeout(Duration::from_millis(timeout_ms))
+            }
+            ("wait_die", None) => ConcurrencyPolicy::WaitDie,
+            _ => {
+                return Err(PyValueError::new_err(format!(
+                    "Unknown concurrency policy {policy:?} (timeout {timeout_ms:?}), expected \
+                     no_wait, wait with a timeout or wait_die"
+                )))
+            }
+        };
+
+        self.0.set_concurrency_policy(policy);
+        Ok(())
+    }
+
+    pub fn dump_range(&self, py: Python<'_>, range_id: usize) -> PyResult<Py<PyDict>> {
+        let dump = py
+            .allow_threads(|| self.0.dump_range(range_id))
+            .ok_or_else(|| PyValueError::new_err(format!("No page range {range_id}")))?;
+        let records = dump
+            .tail_pages
+            .iter()
+            .flat_map(|page| page.records.iter().map(move |record| (page.page, record)));
+
+        let dict = PyDict::new(py);
+        dict.set_item(
+            "tail_page",
+            records.clone().map(|(page, _)| page).collect::<Vec<_>>(),
+        )?;
+        dict.set_item(
+            "tid",
+            records
+                .clone()
+                .map(|(_, r)| r.tid.raw())
+                .collect::<Vec<_>>(),
+        )?;
+        dict.set_item(
+            "base_rid",
+            records
+                .clone()
+                .map(|(_, r)| r.base_rid.raw())
+                .collect::<Vec<_>>(),
+        )?;
+        dict.set_item(
+            "schema_encoding",
+            records
+                .clone()
+                .map(|(_, r)| r.schema_encoding)
+                .collect::<Vec<_>>(),
+        )?;
+        dict.set_item(
+            "columns",
+            records.map(|(_, r)| r.columns.clone()).collect::<Vec<_>>(),
+        )?;
+        dict.set_item(
+            "base_page",
+            dump.tps.iter().map(|(page, _)| *page).collect::<Vec<_>>(),
+        )?;
+        dict.set_item(
+            "tps",
+            dump.tps.iter().map(|(_, tps)| *tps).collect::<Vec<_>>(),
+        )?;
+        dict.set_item("merged_until", vec![dump.merged_until])?;
+        dict.set_item("reclaimed_from", vec![dump.reclaimed_from])?;
+
+        Ok(dict.into())
+    }
+
+    /*
+        Waits for merges and index builds queued so far and flushes dirty pages,
+        see Table::quiesce
+    */
+    #[pyo3(signature = (timeout_ms=60_000))]
+    pub fn quiesce(&self, py: Python<'_>, timeout_ms: u64) -> PyResult<()> {
+        py.allow_threads(|| self.0.quiesce(Duration::from_millis(timeout_ms)))
+            .map_err(crab_err)
+    }
+
+    pub fn force_merge(&self, py: Python<'_>, range_id: usize) -> PyResult<bool> {
+        py.allow_threads(|| self.0.force_merge(range_id))
+            .map_err(crab_err)
+    }
+
+    pub fn force_merge_all(&self, py: Python<'_>) -> PyResult<usize> {
+        py.allow_threads(|| self.0.force_merge_all())
+            .map_err(crab_err)
+    }
+
+    // Why the last failed merge failed, None if none did
+    pub fn merge_status(&self) -> Option<String> {
+        self.0
+            .merge_status()
+            .map(|err| CrabError::from(err).to_string())
+    }
+
+    pub fn persist(&self) -> PyResult<()> {
+        self.0.persist().map_err(crab_err)
+    }
+}
+
+fn access_name(access: AccessPath) -> &'static str {
+    match access {
+        AccessPath::Index => "index",
+        AccessPath::Scan => "scan",
+        AccessPath::Frozen => "frozen",
     }
 }
@@ -0,0 +1,100 @@
+"""
+Writes the CSV behind the milestone report's index and merge graphs: select
+throughput and page reads on an indexed and an unindexed column, before and
+after the merge thread has folded the updates into the base pages.
+
+    maturin develop --release
+    python crabstorepy/tests/diagnostics_report.py report.csv
+"""
+import csv
+import sys
+import tempfile
+from random import Random
+from time import perf_counter, sleep
+
+from crabstore import CrabStorePy
+
+RECORDS = 10000
+SELECTS = 2000
+# Enough rounds of updates to fill a few tail pages in every range
+UPDATE_ROUNDS = 8
+
+
+def measure(table, phase, merges, access, column, values):
+    table.reset_stats()
+
+    start = perf_counter()
+    for value in values:
+        table.select(value, column, [1, 1, 1, 1, 1])
+    elapsed = perf_counter() - start
+
+    diagnostics = table.diagnostics()
+    assert diagnostics["columns"][column]["access"] == access
+
+    return {
+        "phase": phase,
+        "access": access,
+        "selects": len(values),
+        "ops_per_sec": round(len(values) / elapsed),
+        "page_requests": diagnostics["bufferpool"]["requests"],
+        "page_reads": diagnostics["bufferpool"]["misses"],
+        "merges": merges,
+    }
+
+
+def measure_phase(table, phase, rand):
+    # Column 2 has ten rows per value, so both paths return the same amount
+    values = [rand.randrange(RECORDS // 10) for _ in range(SELECTS)]
+    # Measuring resets the counters, so take the merges up to this phase first
+    merges = table.diagnostics()["merge"]["merges"]
+
+    table.drop_index(2)
+    rows = [measure(table, phase, merges, "scan", 2, values[: SELECTS // 20])]
+
+    table.build_index(2)
+    rows.append(measure(table, phase, merges, "index", 2, values))
+
+    return rows
+
+
+def wait_for_merges(table, timeout=30):
+    start = perf_counter()
+    while table.diagnostics()["merge"]["merges"] == 0:
+        if perf_counter() - start > timeout:
+            raise RuntimeError("merge thread never ran")
+        sleep(0.1)
+
+
+def main():
+    out = open(sys.argv[1], "w", newline="") if len(sys.argv) > 1 else sys.stdout
+    rand = Random(3562901)
+
+    with tempfile.TemporaryDirectory() as directory:
+        db = CrabStorePy()
+        db.open(directory)
+        table = db.create_table("Grades", 5, 0)
+
+        for key in range(RECORDS):
+            table.insert(key, key % 100, key // 10, 0, 0)
+
+        rows = measure_phase(table, "pre_merge", rand)
+
+        for round in range(UPDATE_ROUNDS):
+            for key in range(RECORDS):
+                table.update(key, (None, None, None, round, key))
+        wait_for_merges(table)
+
+        rows += measure_phase(table, "post_merge", rand)
+
+        slow = table.diagnostics()["slow_queries"]
+        print(f"{len(slow)} slow queries logged", file=sys.stderr)
+
+        db.close()
+
+    writer = csv.DictWriter(out, fieldnames=list(rows[0]))
+    writer.writeheader()
+    writer.writerows(rows)
+
+
+if __name__ == "__main__":
+    main()
@@ -13,4 +13,13 @@ class Index:
         self.table.build_index(column_number)
 
     def drop_index(self, column_number):
-        self.table.drop_index(column_number)
\ No newline at end of file
+        self.table.drop_index(column_number)
+
+    def indexed_columns(self):
+        return self.table.indexed_columns()
+
+    def locate(self, column, value):
+        return self.table.locate(column, value)
+
+    def locate_range(self, begin, end, column):
+        return self.table.locate_range(begin, end, column)
\ No newline at end of file